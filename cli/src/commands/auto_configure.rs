@@ -0,0 +1,447 @@
+//! Configuration templates for `provisioner run --auto-configure`. A template lists the AppKeys
+//! to add, the models to bind, the publications and subscriptions to set and the features to
+//! enable on a newly provisioned node. It's loaded from a `.json` file like:
+//!
+//! ```json
+//! {
+//!     "app_keys": [{"net_key_index": 0, "app_key_index": 0, "app_key": "63964771734fbd76e3b40519d1d94a48"}],
+//!     "bindings": [{"element_index": 0, "model_id": 4096, "app_key_index": 0}],
+//!     "publications": [{"element_index": 0, "model_id": 4096, "address": 49152, "app_key_index": 0, "ttl": 5}],
+//!     "subscriptions": [{"element_index": 0, "model_id": 4097, "address": 49153}],
+//!     "features": {"relay": true, "proxy": false}
+//! }
+//! ```
+//!
+//! Every section is optional. The template is turned into a list of [`Step`]s applied in order
+//! (AppKeys first since bindings and publications need them). The steps are checked against the
+//! node's Composition Data (see [`check_steps`]) before any of them is sent so a template that
+//! doesn't fit the node fails instead of being partly applied.
+use crate::{helper, CLIError};
+use bluetooth_mesh::access::ModelIdentifier;
+use bluetooth_mesh::address::Address;
+use bluetooth_mesh::crypto::key::AppKey;
+use bluetooth_mesh::foundation::{CompositionDataPage0, FeatureFlags};
+use bluetooth_mesh::mesh::{
+    AppKeyIndex, CompanyID, ElementIndex, KeyIndex, ModelID, NetKeyIndex, TTL,
+};
+use std::convert::TryFrom;
+use std::fmt;
+
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigTemplate {
+    pub app_keys: Vec<AppKeyTemplate>,
+    pub bindings: Vec<BindingTemplate>,
+    pub publications: Vec<PublicationTemplate>,
+    pub subscriptions: Vec<SubscriptionTemplate>,
+    pub features: FeaturesTemplate,
+}
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AppKeyTemplate {
+    pub net_key_index: u16,
+    pub app_key_index: u16,
+    /// 128-bit hex string.
+    pub app_key: String,
+}
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct BindingTemplate {
+    pub element_index: u8,
+    pub model_id: u16,
+    /// Only set for vendor models.
+    #[serde(default)]
+    pub company_id: Option<u16>,
+    pub app_key_index: u16,
+}
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PublicationTemplate {
+    pub element_index: u8,
+    pub model_id: u16,
+    #[serde(default)]
+    pub company_id: Option<u16>,
+    pub address: u16,
+    pub app_key_index: u16,
+    /// Uses the node's Default TTL if not given.
+    #[serde(default)]
+    pub ttl: Option<u8>,
+}
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SubscriptionTemplate {
+    pub element_index: u8,
+    pub model_id: u16,
+    #[serde(default)]
+    pub company_id: Option<u16>,
+    pub address: u16,
+}
+/// Features left as `None` aren't touched.
+#[derive(serde::Deserialize, Copy, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeaturesTemplate {
+    pub relay: Option<bool>,
+    pub proxy: Option<bool>,
+    pub friend: Option<bool>,
+}
+/// Feature that can be enabled or disabled by a template.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum Feature {
+    Relay,
+    Proxy,
+    Friend,
+}
+impl From<Feature> for FeatureFlags {
+    fn from(feature: Feature) -> Self {
+        match feature {
+            Feature::Relay => FeatureFlags::Relay,
+            Feature::Proxy => FeatureFlags::Proxy,
+            Feature::Friend => FeatureFlags::Friend,
+        }
+    }
+}
+/// One Config Client request to apply to the new node. Element indexes are relative to the
+/// node's primary address.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Step {
+    AddAppKey {
+        net_key_index: NetKeyIndex,
+        app_key_index: AppKeyIndex,
+        app_key: AppKey,
+    },
+    BindAppKey {
+        element_index: ElementIndex,
+        model: ModelIdentifier,
+        app_key_index: AppKeyIndex,
+    },
+    SetPublication {
+        element_index: ElementIndex,
+        model: ModelIdentifier,
+        address: Address,
+        app_key_index: AppKeyIndex,
+        ttl: Option<TTL>,
+    },
+    AddSubscription {
+        element_index: ElementIndex,
+        model: ModelIdentifier,
+        address: Address,
+    },
+    SetFeature(Feature, bool),
+}
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::AddAppKey {
+                net_key_index,
+                app_key_index,
+                ..
+            } => write!(
+                f,
+                "add AppKey {:?} on NetKey {:?}",
+                app_key_index, net_key_index
+            ),
+            Step::BindAppKey {
+                element_index,
+                model,
+                app_key_index,
+            } => write!(
+                f,
+                "bind AppKey {:?} to {:?} on element {}",
+                app_key_index, model, element_index.0
+            ),
+            Step::SetPublication {
+                element_index,
+                model,
+                address,
+                ..
+            } => write!(
+                f,
+                "publish {:?} on element {} to {:?}",
+                model, element_index.0, address
+            ),
+            Step::AddSubscription {
+                element_index,
+                model,
+                address,
+            } => write!(
+                f,
+                "subscribe {:?} on element {} to {:?}",
+                model, element_index.0, address
+            ),
+            Step::SetFeature(feature, enabled) => write!(
+                f,
+                "{} {:?}",
+                if *enabled { "enable" } else { "disable" },
+                feature
+            ),
+        }
+    }
+}
+impl Step {
+    /// Checks that the node with `composition` has the element and model (or supports the
+    /// feature) this step configures. Returns why it doesn't.
+    pub fn check(&self, composition: &CompositionDataPage0) -> Result<(), String> {
+        let (element_index, model) = match self {
+            Step::AddAppKey { .. } => return Ok(()),
+            Step::BindAppKey {
+                element_index,
+                model,
+                ..
+            }
+            | Step::SetPublication {
+                element_index,
+                model,
+                ..
+            }
+            | Step::AddSubscription {
+                element_index,
+                model,
+                ..
+            } => (*element_index, model),
+            Step::SetFeature(feature, _) => {
+                return if composition.features().get((*feature).into()) {
+                    Ok(())
+                } else {
+                    Err(format!("the node doesn't support {:?}", feature))
+                };
+            }
+        };
+        if composition.elements().element(element_index).is_none() {
+            Err(format!("the node has no element {}", element_index.0))
+        } else if composition.model_at(element_index, model).is_none() {
+            Err(format!(
+                "element {} doesn't have {:?}",
+                element_index.0, model
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+/// Checks every step against the Composition Data of the node (see [`Step::check`]). Fails on
+/// the first step the node can't apply instead of skipping it.
+pub fn check_steps(steps: &[Step], composition: &CompositionDataPage0) -> Result<(), CLIError> {
+    for step in steps {
+        step.check(composition).map_err(|reason| {
+            CLIError::OtherMessage(format!("auto-configure step '{}': {}", step, reason))
+        })?;
+    }
+    Ok(())
+}
+fn template_error(msg: String) -> CLIError {
+    CLIError::OtherMessage(format!("bad auto-configure template: {}", msg))
+}
+fn key_index(index: u16) -> Result<KeyIndex, CLIError> {
+    KeyIndex::try_from(index).map_err(|_| template_error(format!("key index {} > 4095", index)))
+}
+fn model(model_id: u16, company_id: Option<u16>) -> ModelIdentifier {
+    match company_id {
+        Some(company_id) => ModelIdentifier::new_vendor(ModelID(model_id), CompanyID(company_id)),
+        None => ModelIdentifier::new_sig(ModelID(model_id)),
+    }
+}
+fn address(address: u16) -> Result<Address, CLIError> {
+    match Address::from(address) {
+        Address::Unassigned => Err(template_error("the unassigned address (0x0000)".to_owned())),
+        a => Ok(a),
+    }
+}
+impl ConfigTemplate {
+    /// Converts the template into the `Step`s to apply, in order. Fails on the first invalid
+    /// key index, key, address or TTL so a bad template is caught before provisioning anything.
+    pub fn steps(&self) -> Result<Vec<Step>, CLIError> {
+        let mut steps = Vec::new();
+        for app_key in &self.app_keys {
+            steps.push(Step::AddAppKey {
+                net_key_index: NetKeyIndex(key_index(app_key.net_key_index)?),
+                app_key_index: AppKeyIndex(key_index(app_key.app_key_index)?),
+                app_key: helper::hex_str_to_bytes::<[u8; 16]>(&app_key.app_key)
+                    .map(AppKey::new_bytes)
+                    .ok_or_else(|| {
+                        template_error(format!(
+                            "'{}' is not a 128-bit hex string",
+                            &app_key.app_key
+                        ))
+                    })?,
+            });
+        }
+        for binding in &self.bindings {
+            steps.push(Step::BindAppKey {
+                element_index: ElementIndex(binding.element_index),
+                model: model(binding.model_id, binding.company_id),
+                app_key_index: AppKeyIndex(key_index(binding.app_key_index)?),
+            });
+        }
+        for publication in &self.publications {
+            steps.push(Step::SetPublication {
+                element_index: ElementIndex(publication.element_index),
+                model: model(publication.model_id, publication.company_id),
+                address: address(publication.address)?,
+                app_key_index: AppKeyIndex(key_index(publication.app_key_index)?),
+                ttl: publication
+                    .ttl
+                    .map(|ttl| {
+                        TTL::try_from(ttl)
+                            .map_err(|_| template_error(format!("`{}` is not a valid TTL", ttl)))
+                    })
+                    .transpose()?,
+            });
+        }
+        for subscription in &self.subscriptions {
+            let address = address(subscription.address)?;
+            if let Address::Unicast(_) = address {
+                return Err(template_error(format!(
+                    "can't subscribe to the unicast address {:?}",
+                    address
+                )));
+            }
+            steps.push(Step::AddSubscription {
+                element_index: ElementIndex(subscription.element_index),
+                model: model(subscription.model_id, subscription.company_id),
+                address,
+            });
+        }
+        let features = [
+            (Feature::Relay, self.features.relay),
+            (Feature::Proxy, self.features.proxy),
+            (Feature::Friend, self.features.friend),
+        ];
+        for &(feature, enabled) in features.iter() {
+            if let Some(enabled) = enabled {
+                steps.push(Step::SetFeature(feature, enabled));
+            }
+        }
+        Ok(steps)
+    }
+}
+/// Loads a `ConfigTemplate` from a `.json` file and converts it into its `Step`s.
+pub fn load_template(path: &str) -> Result<Vec<Step>, CLIError> {
+    let template: ConfigTemplate = serde_json::from_reader(helper::load_file(path, false, false)?)
+        .map_err(CLIError::SerdeJSON)?;
+    template.steps()
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bluetooth_mesh::foundation::element::{ElementComposition, ElementsComposition, Location};
+    use bluetooth_mesh::foundation::{Features, ProductID, VersionID, CRPL};
+
+    const TEMPLATE: &str = r#"{
+        "app_keys": [{"net_key_index": 0, "app_key_index": 1, "app_key": "63964771734fbd76e3b40519d1d94a48"}],
+        "bindings": [{"element_index": 1, "model_id": 4097, "app_key_index": 1}],
+        "publications": [{"element_index": 0, "model_id": 4096, "address": 49152, "app_key_index": 1, "ttl": 5}],
+        "subscriptions": [{"element_index": 1, "model_id": 4097, "address": 49153}],
+        "features": {"relay": true, "proxy": false}
+    }"#;
+
+    fn steps(template: &str) -> Result<Vec<Step>, CLIError> {
+        serde_json::from_str::<ConfigTemplate>(template)
+            .map_err(CLIError::SerdeJSON)?
+            .steps()
+    }
+    /// Node with a Generic OnOff Server on its primary element and a Generic OnOff Client on its
+    /// second one. It only supports the Relay feature.
+    fn composition() -> CompositionDataPage0 {
+        let mut primary = ElementComposition::new_empty(Location::Numbered(0));
+        primary.add_model(ModelIdentifier::new_sig(ModelID(0x1000)));
+        let mut secondary = ElementComposition::new_empty(Location::Numbered(0));
+        secondary.add_model(ModelIdentifier::new_sig(ModelID(0x1001)));
+        let mut features = Features::default();
+        features.set(FeatureFlags::Relay);
+        CompositionDataPage0::new(
+            CompanyID(0x05F1),
+            ProductID(0x0001),
+            VersionID(0x0001),
+            CRPL(32),
+            features,
+            ElementsComposition::new(vec![primary, secondary]),
+        )
+    }
+    #[test]
+    fn test_template_steps() {
+        let app_key_index = AppKeyIndex(KeyIndex::new(1));
+        assert_eq!(
+            steps(TEMPLATE).expect("valid template"),
+            vec![
+                Step::AddAppKey {
+                    net_key_index: NetKeyIndex(KeyIndex::new(0)),
+                    app_key_index,
+                    app_key: AppKey::new_bytes([
+                        0x63, 0x96, 0x47, 0x71, 0x73, 0x4f, 0xbd, 0x76, 0xe3, 0xb4, 0x05, 0x19,
+                        0xd1, 0xd9, 0x4a, 0x48
+                    ]),
+                },
+                Step::BindAppKey {
+                    element_index: ElementIndex(1),
+                    model: ModelIdentifier::new_sig(ModelID(0x1001)),
+                    app_key_index,
+                },
+                Step::SetPublication {
+                    element_index: ElementIndex(0),
+                    model: ModelIdentifier::new_sig(ModelID(0x1000)),
+                    address: Address::from(0xC000),
+                    app_key_index,
+                    ttl: Some(TTL::new(5)),
+                },
+                Step::AddSubscription {
+                    element_index: ElementIndex(1),
+                    model: ModelIdentifier::new_sig(ModelID(0x1001)),
+                    address: Address::from(0xC001),
+                },
+                Step::SetFeature(Feature::Relay, true),
+                Step::SetFeature(Feature::Proxy, false),
+            ]
+        );
+        assert_eq!(steps("{}").expect("every section is optional"), vec![]);
+    }
+    #[test]
+    fn test_bad_template() {
+        assert!(steps(
+            r#"{"bindings": [{"element_index": 0, "model_id": 4096, "app_key_index": 4096}]}"#
+        )
+        .is_err());
+        assert!(steps(
+            r#"{"app_keys": [{"net_key_index": 0, "app_key_index": 0, "app_key": "6396"}]}"#
+        )
+        .is_err());
+        assert!(steps(
+            r#"{"subscriptions": [{"element_index": 0, "model_id": 4096, "address": 5}]}"#
+        )
+        .is_err());
+        assert!(steps(r#"{"publications": [{"element_index": 0, "model_id": 4096, "address": 0, "app_key_index": 0}]}"#).is_err());
+        assert!(steps(r#"{"features": {"low_power": true}}"#).is_err());
+    }
+    #[test]
+    fn test_check_steps() {
+        let composition = composition();
+        let steps = steps(TEMPLATE).expect("valid template");
+        // Disabling the Proxy feature the node doesn't support fails.
+        assert!(check_steps(&steps, &composition).is_err());
+        assert!(check_steps(&steps[..steps.len() - 1], &composition).is_ok());
+        assert_eq!(
+            Step::BindAppKey {
+                element_index: ElementIndex(2),
+                model: ModelIdentifier::new_sig(ModelID(0x1001)),
+                app_key_index: AppKeyIndex(KeyIndex::new(1)),
+            }
+            .check(&composition),
+            Err("the node has no element 2".to_owned())
+        );
+        assert_eq!(
+            Step::AddSubscription {
+                element_index: ElementIndex(0),
+                model: ModelIdentifier::new_sig(ModelID(0x1001)),
+                address: Address::from(0xC001),
+            }
+            .check(&composition),
+            Err(format!(
+                "element 0 doesn't have {:?}",
+                ModelIdentifier::new_sig(ModelID(0x1001))
+            ))
+        );
+        assert_eq!(
+            Step::SetFeature(Feature::Friend, true).check(&composition),
+            Err("the node doesn't support Friend".to_owned())
+        );
+    }
+}
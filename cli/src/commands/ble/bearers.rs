@@ -1 +1,227 @@
+//! Mesh advertising bearer over an HCI adapter. [`run_adapter`] scans the adapter for mesh
+//! advertisements and advertises what [`HciAdvertiser`]s queued in between until every
+//! `HciAdvertiser` is gone.
+use bluetooth_mesh::asyncs::sync::mpsc;
+use bluetooth_mesh::interface::OutputInterface;
+use bluetooth_mesh::provisioning::pb_adv;
+use bluetooth_mesh::stack::bearer::{self, BearerError, IncomingMessage};
+use bluetooth_mesh::stack::bearers::advertiser::{AdvBearer, AdvertisingConnection};
+use btle::le::advertiser::{AdvertisingParameters, AdvertisingType};
+use btle::le::report::ReportInfo;
+use futures_util::future::Either;
+use futures_util::StreamExt;
+use std::future::Future;
+use std::time::Duration;
 
+/// How long each advertisement is left on. Long enough for one advertising event.
+pub const ADVERTISING_WINDOW: Duration = Duration::from_millis(20);
+
+/// Returned by an `HciAdvertiser` once `run_adapter` stopped.
+#[derive(Copy, Clone, Debug)]
+pub struct AdapterStopped;
+impl btle::error::Error for AdapterStopped {}
+/// Returned by `run_adapter` when the adapter stops reporting advertisements.
+#[derive(Copy, Clone, Debug)]
+pub struct ScanEnded;
+impl btle::error::Error for ScanEnded {}
+
+/// `AdvertisingConnection` handing advertisements to `run_adapter`. Clones share the adapter.
+/// Delayed advertisements are waited on by a task so it must be used inside the tokio runtime.
+#[derive(Clone, Debug)]
+pub struct HciAdvertiser(mpsc::UnboundedSender<Box<[u8]>>);
+impl HciAdvertiser {
+    /// Returns the advertiser and the receiver for `run_adapter`. Each advertisement comes out
+    /// of it once it's due.
+    pub fn new() -> (HciAdvertiser, mpsc::UnboundedReceiver<Box<[u8]>>) {
+        let (advertisements_tx, advertisements_rx) = mpsc::unbounded_channel();
+        (HciAdvertiser(advertisements_tx), advertisements_rx)
+    }
+}
+impl AdvertisingConnection for HciAdvertiser {
+    fn advertise(&mut self, adv_data: &[u8], delay: Duration) -> Result<(), BearerError> {
+        let adv_data = Box::<[u8]>::from(adv_data);
+        if delay == Duration::default() {
+            return self
+                .0
+                .send(adv_data)
+                .map_err(|_| BearerError::Other(Box::new(AdapterStopped)));
+        }
+        // Retransmissions due after the adapter stopped are dropped.
+        let advertisements = self.0.clone();
+        tokio::spawn(async move {
+            tokio::time::delay_for(delay).await;
+            let _ = advertisements.send(adv_data);
+        });
+        Ok(())
+    }
+}
+/// Advertises every Network PDU from `outgoing` (a `FullStack::outgoing_bearer`) with `bearer`
+/// until `outgoing` closes or `stop` resolves. PDUs already in `outgoing` by then are still
+/// advertised. Failures are logged and counted. Returns how many PDUs couldn't be advertised.
+pub async fn transmit(
+    mut outgoing: mpsc::Receiver<bearer::OutgoingMessage>,
+    mut bearer: AdvBearer<HciAdvertiser>,
+    stop: impl Future<Output = ()>,
+    logger: slog::Logger,
+) -> usize {
+    let mut failed = 0_usize;
+    let mut send = |bearer::OutgoingMessage::Network(pdu)| {
+        debug!(logger, "net_tx"; "dst" => ?pdu.dst, "pdu" => ?pdu.pdu);
+        if let Err(e) = bearer.send_pdu(&pdu) {
+            warn!(logger, "net_tx_failed"; "error" => ?e);
+            failed += 1;
+        }
+    };
+    futures_util::pin_mut!(stop);
+    loop {
+        let next = outgoing.recv();
+        futures_util::pin_mut!(next);
+        match futures_util::future::select(next, stop.as_mut()).await {
+            Either::Left((Some(msg), _)) => send(msg),
+            Either::Left((None, _)) => return failed,
+            Either::Right(((), _)) => break,
+        }
+    }
+    outgoing.close();
+    while let Some(msg) = outgoing.recv().await {
+        send(msg)
+    }
+    failed
+}
+/// Advertises every PB-ADV PDU from `outgoing` once. The link retransmits them on its own.
+/// Failures are only logged.
+pub async fn transmit_pb_adv(
+    mut outgoing: mpsc::Receiver<pb_adv::PDU>,
+    mut advertiser: HciAdvertiser,
+    logger: slog::Logger,
+) {
+    while let Some(pdu) = outgoing.recv().await {
+        debug!(logger, "pb_adv_tx"; "pdu" => ?pdu);
+        let advertised = match bearer::pb_adv_data(&pdu) {
+            Ok(adv_data) => advertiser
+                .advertise(&adv_data, Duration::default())
+                .map_err(|e| format!("{:?}", e)),
+            Err(e) => Err(format!("{:?}", e)),
+        };
+        if let Err(e) = advertised {
+            warn!(logger, "pb_adv_tx_failed"; "error" => e);
+        }
+    }
+}
+/// Scans `adapter` and sends every mesh advertisement (see `IncomingMessage::from_report_info`)
+/// to `incoming`. Stops once `incoming` closes or every `HciAdvertiser` is gone and everything
+/// they queued was advertised. Fails with `ScanEnded` if the scan ends first.
+///
+/// Each advertisement from `advertisements` is advertised (non-connectable) for
+/// `ADVERTISING_WINDOW` as soon as it comes in. The scan is stopped in the meantime.
+pub async fn run_adapter<A: btle::hci::adapter::Adapter>(
+    adapter: A,
+    mut advertisements: mpsc::UnboundedReceiver<Box<[u8]>>,
+    mut incoming: mpsc::Sender<IncomingMessage>,
+) -> Result<(), Box<dyn btle::error::Error>> {
+    futures_util::pin_mut!(adapter);
+    let adapter = btle::hci::adapters::Adapter::new(adapter);
+    let mut le = adapter.le();
+    le.set_advertising_parameters(AdvertisingParameters {
+        advertising_type: AdvertisingType::AdvNonconnInd,
+        ..AdvertisingParameters::DEFAULT
+    })
+    .await?;
+    loop {
+        // The scan borrows the adapter so it's dropped before advertising.
+        let next = {
+            let reports = le.advertisement_stream::<Box<[ReportInfo]>>().await?;
+            futures_util::pin_mut!(reports);
+            let advertisement = advertisements.recv();
+            futures_util::pin_mut!(advertisement);
+            match futures_util::future::select(reports.next(), advertisement).await {
+                Either::Left((report_info, _)) => Either::Left(report_info),
+                Either::Right((adv_data, _)) => Either::Right(adv_data),
+            }
+        };
+        match next {
+            Either::Left(Some(report_info)) => {
+                if let Some(msg) = IncomingMessage::from_report_info(report_info?) {
+                    if incoming.send(msg).await.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+            Either::Left(None) => return Err(Box::new(ScanEnded)),
+            Either::Right(Some(adv_data)) => {
+                le.set_advertising_data(&adv_data).await?;
+                le.set_advertising_enable(true).await?;
+                tokio::time::delay_for(ADVERTISING_WINDOW).await;
+                le.set_advertising_enable(false).await?;
+            }
+            Either::Right(None) => return Ok(()),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bluetooth_mesh::address::Address;
+    use bluetooth_mesh::mesh::TransmitInterval;
+    use bluetooth_mesh::net;
+    use bluetooth_mesh::stack::bearer::OutgoingEncryptedNetworkPDU;
+
+    #[test]
+    fn test_hci_advertiser() {
+        let mut runtime = crate::helper::tokio_runtime();
+        runtime.block_on(async {
+            let (mut advertiser, mut advertisements) = HciAdvertiser::new();
+            advertiser
+                .advertise(&[0x01], Duration::from_millis(10))
+                .expect("adapter running");
+            advertiser
+                .advertise(&[0x02], Duration::default())
+                .expect("adapter running");
+            // Due in order, not in the order they were queued.
+            assert_eq!(advertisements.recv().await.as_deref(), Some(&[0x02][..]));
+            assert_eq!(advertisements.recv().await.as_deref(), Some(&[0x01][..]));
+            drop(advertisements);
+            assert!(advertiser.advertise(&[0x03], Duration::default()).is_err());
+        });
+    }
+    /// A PDU sent once with every byte set to `byte`.
+    fn outgoing_pdu(byte: u8) -> bearer::OutgoingMessage {
+        bearer::OutgoingMessage::Network(OutgoingEncryptedNetworkPDU {
+            transmit_parameters: TransmitInterval::from(0),
+            pdu: net::OwnedEncryptedPDU::new(&[byte; 20]).expect("valid length"),
+            dst: Address::Unassigned,
+            relayed: false,
+        })
+    }
+    #[test]
+    fn test_transmit_stop() {
+        let mut runtime = crate::helper::tokio_runtime();
+        runtime.block_on(async {
+            let (advertiser, mut advertisements) = HciAdvertiser::new();
+            let (mut outgoing_tx, outgoing_rx) = mpsc::channel(4);
+            for byte in 1..=2 {
+                outgoing_tx
+                    .send(outgoing_pdu(byte))
+                    .await
+                    .expect("transmit running");
+            }
+            let logger = slog::Logger::root(slog::Discard, o!());
+            // `outgoing_tx` is still open but the PDUs queued before the stop still go out.
+            let failed = transmit(
+                outgoing_rx,
+                AdvBearer::new(advertiser),
+                futures_util::future::ready(()),
+                logger,
+            )
+            .await;
+            assert_eq!(failed, 0);
+            for byte in 1..=2 {
+                let adv_data = advertisements.recv().await.expect("advertised");
+                assert_eq!(adv_data[2..], [byte; 20][..]);
+            }
+            // Every `HciAdvertiser` is gone with the bearer.
+            assert!(advertisements.recv().await.is_none());
+            assert!(outgoing_tx.try_send(outgoing_pdu(3)).is_err());
+        });
+    }
+}
@@ -55,7 +55,7 @@ pub fn dump(
     adapter_id: u16,
     pcap_file: Option<&'_ str>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    crate::helper::tokio_runtime().block_on(async move {
+    crate::helper::block_on(async move {
         #[cfg(unix)]
         return dump_bluez(
             std::env::args()
@@ -1,5 +1,6 @@
 use crate::CLIError;
 
+#[cfg(feature = "mesh")]
 pub mod bearers;
 pub mod hci;
 pub mod remote;
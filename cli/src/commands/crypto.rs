@@ -5,7 +5,9 @@ use bluetooth_mesh::crypto::materials::KeyPhase;
 use bluetooth_mesh::mesh::{
     AppKeyIndex, ElementIndex, IVIndex, IVUpdateFlag, KeyIndex, NetKeyIndex, SequenceNumber,
 };
-use std::convert::TryFrom;
+use bluetooth_mesh::stack::{RecvError, StackInternals};
+use bluetooth_mesh::{lower, net};
+use std::convert::{TryFrom, TryInto};
 use std::fmt::Write;
 use std::str::FromStr;
 
@@ -21,6 +23,21 @@ fn is_key_index(index: String) -> Result<(), String> {
     }
 }
 
+fn print_net_pdu(net_key_index: NetKeyIndex, iv_index: IVIndex, header: &net::Header, hex: &[u8]) {
+    println!(
+        "net_index: {} iv_index: {} ivi: {} nid: {} ctl: {} ttl: {} seq: {} src: {} dst: {}",
+        u16::from(net_key_index.0),
+        iv_index.0,
+        header.ivi.0,
+        header.nid,
+        header.ctl.0,
+        u8::from(header.ttl),
+        header.seq.0.value(),
+        u16::from(header.src),
+        u16::from(&header.dst)
+    );
+    println!("{:x}", helper::HexSlice(hex));
+}
 pub fn sub_command() -> clap::App<'static, 'static> {
     clap::SubCommand::with_name("crypto")
         .about("Read/Write crypto information from/to a device_state file")
@@ -118,6 +135,39 @@ pub fn sub_command() -> clap::App<'static, 'static> {
                         ),
                 ),
         )
+        .subcommand(
+            clap::SubCommand::with_name("encrypt-net")
+                .about(
+                    "encrypt a plaintext Network PDU (header || transport PDU) with a local netkey",
+                )
+                .arg(
+                    clap::Arg::with_name("pdu_hex")
+                        .help("plaintext PDU hex. The IVI and NID are replaced by the netkey's")
+                        .required(true)
+                        .value_name("PDU_HEX")
+                        .validator(helper::is_hex_str_validator),
+                )
+                .arg(
+                    clap::Arg::with_name("net_index")
+                        .short("n")
+                        .long("net-key-index")
+                        .help("netkey index to encrypt with")
+                        .value_name("NET_INDEX")
+                        .default_value("0")
+                        .validator(is_key_index),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("decrypt-net")
+                .about("decrypt a Network PDU with the local netkeys")
+                .arg(
+                    clap::Arg::with_name("pdu_hex")
+                        .help("encrypted PDU hex")
+                        .required(true)
+                        .value_name("PDU_HEX")
+                        .validator(helper::is_hex_str_validator),
+                ),
+        )
         .subcommand(
             clap::SubCommand::with_name("iv")
                 .about("set/get IV index and IV update flag")
@@ -296,8 +346,8 @@ pub fn crypto_matches(
             match appkey_matches.subcommand() {
                 ("list", Some(list_matches)) => {
                     let print_aid = list_matches.is_present("aid");
-                    for (index, appkey) in device_state.security_materials().app_key_map.map.iter()
-                    {
+                    for (index, phase) in device_state.security_materials().app_key_map.map.iter() {
+                        let appkey = phase.tx_key();
                         if print_aid {
                             println!(
                                 "net_index: {} app_index: {} aid: {}",
@@ -373,6 +423,98 @@ pub fn crypto_matches(
                 _ => unreachable!("unhandled appkeys subcommand"),
             }
         }
+        ("encrypt-net", Some(encrypt_matches)) => {
+            let device_state = get_device_state()?;
+            let bytes = helper::hex_str_to_vec(
+                encrypt_matches
+                    .value_of("pdu_hex")
+                    .expect("required by clap"),
+            )
+            .expect("validated by clap");
+            let net_index = NetKeyIndex(KeyIndex::new(
+                encrypt_matches
+                    .value_of("net_index")
+                    .expect("default by clap")
+                    .parse()
+                    .expect("validated by clap"),
+            ));
+            if bytes.len() <= net::PDU_HEADER_LEN
+                || bytes.len() > net::PDU_HEADER_LEN + lower::PDU::max_len()
+            {
+                return Err(CLIError::OtherMessage(format!(
+                    "plaintext PDU must be {} to {} bytes long (got {})",
+                    net::PDU_HEADER_LEN + 1,
+                    net::PDU_HEADER_LEN + lower::PDU::max_len(),
+                    bytes.len()
+                )));
+            }
+            let (header, transport_pdu) = bytes.split_at(net::PDU_HEADER_LEN);
+            let header = net::Header::unpack(header.try_into().expect("checked length"))
+                .ok_or_else(|| CLIError::OtherMessage("SRC isn't a unicast address".to_owned()))?;
+            let keys = device_state
+                .security_materials()
+                .net_key_map
+                .get_keys(net_index)
+                .ok_or_else(|| {
+                    CLIError::OtherMessage(format!(
+                        "no netkey exists under index `{}`",
+                        u16::from(net_index.0)
+                    ))
+                })?
+                .tx_key()
+                .network_keys();
+            let iv_index = device_state.tx_iv_index();
+            let encrypted = header
+                .encrypt_transport_pdu(transport_pdu, keys, iv_index)
+                .map_err(|e| {
+                    CLIError::OtherMessage(format!("unable to encrypt network PDU: {:?}", e))
+                })?;
+            debug!(logger, "encrypted_net_pdu"; "net_index" => u16::from(net_index.0));
+            let header = net::Header {
+                ivi: iv_index.ivi(),
+                nid: keys.nid(),
+                ..header
+            };
+            print_net_pdu(net_index, iv_index, &header, encrypted.as_ref());
+        }
+        ("decrypt-net", Some(decrypt_matches)) => {
+            let bytes = helper::hex_str_to_vec(
+                decrypt_matches
+                    .value_of("pdu_hex")
+                    .expect("required by clap"),
+            )
+            .expect("validated by clap");
+            let pdu = net::EncryptedPDU::new(&bytes).ok_or_else(|| {
+                CLIError::OtherMessage(format!(
+                    "{} bytes isn't a valid network PDU length",
+                    bytes.len()
+                ))
+            })?;
+            let internals = StackInternals::new(get_device_state()?);
+            match internals.decrypt_network_pdu(pdu) {
+                Ok((net_index, iv_index, pdu)) => {
+                    debug!(logger, "decrypted_net_pdu"; "net_index" => u16::from(net_index.0));
+                    let mut plaintext = pdu.header().pack().to_vec();
+                    plaintext.extend_from_slice(pdu.decrypted_data().transport_pdu());
+                    print_net_pdu(net_index, iv_index, pdu.header(), &plaintext);
+                }
+                Err(RecvError::UnknownControlOpcode(net_index, iv_index, unknown)) => {
+                    print_net_pdu(net_index, iv_index, &unknown.header, &unknown.header.pack());
+                    println!("unknown control opcode: 0x{:02x}", unknown.opcode);
+                }
+                Err(RecvError::NoMatchingNetKey) => {
+                    return Err(CLIError::OtherMessage(
+                        "no local netkey decrypts the network PDU".to_owned(),
+                    ))
+                }
+                Err(e) => {
+                    return Err(CLIError::OtherMessage(format!(
+                        "unable to decrypt network PDU: {:?}",
+                        e
+                    )))
+                }
+            }
+        }
         ("iv", Some(iv_matches)) => {
             let mut device_state = get_device_state()?;
             let mut should_write = false;
@@ -1,7 +1,13 @@
+#[cfg(feature = "mesh")]
+pub mod auto_configure;
 pub mod ble;
 #[cfg(feature = "mesh")]
 pub mod crypto;
 #[cfg(feature = "mesh")]
+pub mod oob;
+#[cfg(feature = "mesh")]
 pub mod provisioner;
 #[cfg(feature = "mesh")]
+pub mod send;
+#[cfg(feature = "mesh")]
 pub mod state;
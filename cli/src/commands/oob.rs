@@ -0,0 +1,198 @@
+//! OOB provisioning data helpers. Generates and decodes the payload a provisioner scans (usually
+//! from a QR code) to learn a device's UUID and static OOB value.
+use crate::helper::{self, HexSlice};
+use crate::CLIError;
+use bluetooth_mesh::beacon::{Beacon, OOBFlags, OOBInformation, UnprovisionedDeviceBeacon};
+use bluetooth_mesh::uuid::UUID;
+
+/// Prefix every OOB payload starts with.
+pub const PAYLOAD_PREFIX: &str = "MESH:";
+const STATIC_OOB_LEN: usize = 16;
+
+/// Device data carried in a QR/OOB payload.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct OOBPayload {
+    pub uuid: UUID,
+    pub oob_information: OOBInformation,
+    pub static_oob: Option<[u8; STATIC_OOB_LEN]>,
+}
+impl OOBPayload {
+    pub fn new(uuid: UUID, static_oob: Option<[u8; STATIC_OOB_LEN]>) -> OOBPayload {
+        OOBPayload {
+            uuid,
+            oob_information: OOBInformation::default().set(OOBFlags::MachineReadable2DCode),
+            static_oob,
+        }
+    }
+    pub fn beacon(&self) -> UnprovisionedDeviceBeacon {
+        UnprovisionedDeviceBeacon {
+            uuid: self.uuid,
+            oob_information: self.oob_information,
+            uri_hash: None,
+        }
+    }
+    /// Formats the payload as `MESH:UUID=<hex>;OOB=<hex>[;STATIC=<hex>]`.
+    pub fn encode(&self) -> String {
+        let mut out = format!(
+            "{}UUID={:x};OOB={:04x}",
+            PAYLOAD_PREFIX,
+            HexSlice(self.uuid.as_ref()),
+            self.oob_information.0
+        );
+        if let Some(static_oob) = &self.static_oob {
+            out.push_str(&format!(";STATIC={:x}", HexSlice(&static_oob[..])));
+        }
+        out
+    }
+    /// Parses a payload created by `encode`. Field order doesn't matter but `UUID` is required.
+    pub fn decode(payload: &str) -> Result<OOBPayload, String> {
+        let fields = payload
+            .trim()
+            .strip_prefix(PAYLOAD_PREFIX)
+            .ok_or_else(|| format!("payload missing '{}' prefix", PAYLOAD_PREFIX))?;
+        let mut uuid = None;
+        let mut oob_information = OOBInformation::default();
+        let mut static_oob = None;
+        for field in fields.split(';').filter(|f| !f.is_empty()) {
+            let mut parts = field.splitn(2, '=');
+            let (key, value) = match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => (key, value),
+                _ => return Err(format!("malformed field '{}'", field)),
+            };
+            match key {
+                "UUID" => {
+                    uuid =
+                        Some(UUID(helper::hex_str_to_bytes(value).ok_or_else(|| {
+                            format!("'{}' is not a 128-bit hex UUID", value)
+                        })?))
+                }
+                "OOB" => {
+                    oob_information = OOBInformation(
+                        u16::from_str_radix(value, 16)
+                            .map_err(|_| format!("'{}' is not a 16-bit hex OOB field", value))?,
+                    )
+                }
+                "STATIC" => {
+                    static_oob =
+                        Some(helper::hex_str_to_bytes(value).ok_or_else(|| {
+                            format!("'{}' is not a 128-bit hex static OOB", value)
+                        })?)
+                }
+                _ => return Err(format!("unknown field '{}'", key)),
+            }
+        }
+        Ok(OOBPayload {
+            uuid: uuid.ok_or_else(|| "payload missing UUID field".to_owned())?,
+            oob_information,
+            static_oob,
+        })
+    }
+}
+pub fn generate_sub_command() -> clap::App<'static, 'static> {
+    clap::SubCommand::with_name("generate")
+        .about("generate provisioning data")
+        .subcommand(
+            clap::SubCommand::with_name("oob")
+                .about("generate unprovisioned beacon data and a QR/OOB payload")
+                .arg(
+                    clap::Arg::with_name("uuid")
+                        .help("128-bit device UUID hex")
+                        .required(true)
+                        .value_name("UUID_HEX")
+                        .validator(helper::is_128_bit_hex_str_validator),
+                )
+                .arg(
+                    clap::Arg::with_name("static_oob")
+                        .help("128-bit static OOB hex")
+                        .short("s")
+                        .long("static-oob")
+                        .value_name("STATIC_OOB_HEX")
+                        .validator(helper::is_128_bit_hex_str_validator),
+                ),
+        )
+}
+pub fn decode_sub_command() -> clap::App<'static, 'static> {
+    clap::SubCommand::with_name("decode")
+        .about("decode provisioning data")
+        .subcommand(
+            clap::SubCommand::with_name("oob")
+                .about("decode a QR/OOB payload")
+                .arg(
+                    clap::Arg::with_name("payload")
+                        .help("payload in the form `MESH:UUID=<hex>;OOB=<hex>[;STATIC=<hex>]`")
+                        .required(true)
+                        .value_name("PAYLOAD"),
+                ),
+        )
+}
+pub fn generate_matches(
+    parent_logger: &slog::Logger,
+    generate_matches: &clap::ArgMatches,
+) -> Result<(), CLIError> {
+    let logger = parent_logger.new(o!());
+    match generate_matches.subcommand() {
+        ("oob", Some(oob_matches)) => {
+            let uuid = UUID(
+                helper::hex_str_to_bytes(oob_matches.value_of("uuid").expect("required by clap"))
+                    .expect("validated by clap"),
+            );
+            let static_oob = oob_matches
+                .value_of("static_oob")
+                .map(|s| helper::hex_str_to_bytes(s).expect("validated by clap"));
+            let payload = OOBPayload::new(uuid, static_oob);
+            debug!(logger, "generate_oob"; "uuid" => %uuid);
+            print_payload(&payload);
+            println!("payload: {}", payload.encode());
+            Ok(())
+        }
+        ("", None) => Err(CLIError::Clap(clap::Error::with_description(
+            "missing generate subcommand",
+            clap::ErrorKind::ArgumentNotFound,
+        ))),
+        _ => unreachable!("unhandled generate subcommand"),
+    }
+}
+pub fn decode_matches(
+    parent_logger: &slog::Logger,
+    decode_matches: &clap::ArgMatches,
+) -> Result<(), CLIError> {
+    let logger = parent_logger.new(o!());
+    match decode_matches.subcommand() {
+        ("oob", Some(oob_matches)) => {
+            let payload =
+                OOBPayload::decode(oob_matches.value_of("payload").expect("required by clap"))
+                    .map_err(|e| {
+                        CLIError::Clap(clap::Error::with_description(
+                            &format!("error: {}", e),
+                            clap::ErrorKind::InvalidValue,
+                        ))
+                    })?;
+            debug!(logger, "decode_oob"; "uuid" => %payload.uuid);
+            print_payload(&payload);
+            Ok(())
+        }
+        ("", None) => Err(CLIError::Clap(clap::Error::with_description(
+            "missing decode subcommand",
+            clap::ErrorKind::ArgumentNotFound,
+        ))),
+        _ => unreachable!("unhandled decode subcommand"),
+    }
+}
+fn print_payload(payload: &OOBPayload) {
+    let beacon = payload.beacon();
+    let mut beacon_buf = [0_u8; UnprovisionedDeviceBeacon::max_len()];
+    let beacon_len = beacon.byte_len();
+    beacon
+        .pack_into(&mut beacon_buf[..beacon_len])
+        .expect("beacon buffer is max_len");
+    println!("uuid: {}", payload.uuid);
+    println!("oob_information: {:04x}", payload.oob_information.0);
+    match &payload.static_oob {
+        Some(static_oob) => println!("static_oob: {:x}", HexSlice(&static_oob[..])),
+        None => println!("static_oob: none"),
+    }
+    println!(
+        "unprovisioned_beacon: {:x}",
+        HexSlice(&beacon_buf[..beacon_len])
+    );
+}
@@ -1,27 +1,157 @@
-use crate::helper::tokio_runtime;
+use crate::commands::auto_configure;
+use crate::commands::ble::bearers::{self, HciAdvertiser};
+use crate::json_output::{self, Event};
 use crate::CLIError;
+use bluetooth_mesh::address::UnicastAddress;
+use bluetooth_mesh::asyncs::sync::{mpsc, Mutex};
+use bluetooth_mesh::crypto::KeyRefreshPhases;
+use bluetooth_mesh::device_state::{DeviceState, NodeInfo};
+use bluetooth_mesh::foundation::publication::{ModelPublishInfo, PublishPeriod, PublishRetransmit};
+use bluetooth_mesh::foundation::state::{
+    AttentionTimer, FriendState, GATTProxyState, RelayRetransmit, RelayState,
+};
+use bluetooth_mesh::foundation::{ProductID, ProductInfo, VersionID, CRPL};
+use bluetooth_mesh::mesh::{CompanyID, ElementIndex, KeyIndex, NetKeyIndex};
+use bluetooth_mesh::models::config::client::{ConfigClient, ConfigClientError};
+use bluetooth_mesh::models::health::server::HealthServer;
+use bluetooth_mesh::provisioning::bearer::Link;
+use bluetooth_mesh::provisioning::confirmation::AuthValue;
+use bluetooth_mesh::provisioning::pb_adv::LinkID;
+use bluetooth_mesh::provisioning::session::{self, Authentication, ProvisioningParameters};
+use bluetooth_mesh::provisioning::{generic, pb_adv, protocol};
+use bluetooth_mesh::random::Randomizable;
 use bluetooth_mesh::replay;
 use bluetooth_mesh::stack::bearer::IncomingMessage;
-use bluetooth_mesh::stack::full::FullStack;
+use bluetooth_mesh::stack::bearers::advertiser::AdvBearer;
+use bluetooth_mesh::stack::full::{FullStack, FullStackOptions};
+use bluetooth_mesh::stack::messages;
 use bluetooth_mesh::stack::StackInternals;
-use btle::le::report::ReportInfo;
-use futures_util::StreamExt;
+use bluetooth_mesh::uuid::UUID;
+use futures_util::future::Either;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Mesh advertisements buffered between the scanner and the stack.
+const INCOMING_CHANNEL_SIZE: usize = 32;
+/// How long Ctrl-C waits for the queued segmented messages before aborting them.
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(5);
+/// PB-ADV PDUs buffered between the scanner and the provisioning link.
+const PB_ADV_CHANNEL_SIZE: usize = 32;
+/// Access messages buffered for the Config Client while it waits for a status.
+const CONFIG_REPLIES_CHANNEL_SIZE: usize = 8;
+type AccessMessage = messages::IncomingMessage<Box<[u8]>>;
 
 pub fn sub_command() -> clap::App<'static, 'static> {
     clap::SubCommand::with_name("provisioner")
         .about("Provisioner Role for adding Nodes to a network")
         .subcommand(
             clap::SubCommand::with_name("run")
-                .about("join real Bluetooth Mesh network as a provisioner."),
+                .about("join real Bluetooth Mesh network as a provisioner.")
+                .arg(
+                    clap::Arg::with_name("monitor")
+                        .long("monitor")
+                        .help("Log every decrypted network PDU"),
+                )
+                .arg(
+                    clap::Arg::with_name("auto_configure")
+                        .long("auto-configure")
+                        .value_name("TEMPLATE")
+                        .help("Config template .json file to apply to newly provisioned nodes"),
+                )
+                .arg(
+                    clap::Arg::with_name("device_uuid")
+                        .long("device-uuid")
+                        .value_name("UUID")
+                        .help("Provision the unprovisioned device with this UUID (32 hex characters)")
+                        .validator(crate::helper::is_128_bit_hex_str_validator),
+                )
+                .arg(
+                    clap::Arg::with_name("static_oob")
+                        .long("static-oob")
+                        .value_name("AUTH_VALUE")
+                        .help("Authenticate the device with this 128-bit Static OOB value instead of No OOB")
+                        .requires("device_uuid")
+                        .validator(crate::helper::is_128_bit_hex_str_validator),
+                )
+                .arg(
+                    clap::Arg::with_name("net_key_index")
+                        .long("net-key-index")
+                        .value_name("NET_KEY_INDEX")
+                        .help("NetKey given to the device")
+                        .default_value("0")
+                        .requires("device_uuid")
+                        .validator(crate::helper::is_u16_validator),
+                )
+                .arg(
+                    clap::Arg::with_name("replay_flush_interval")
+                        .long("replay-flush-interval")
+                        .value_name("SECONDS")
+                        .help("How often the replay protection cache is saved (it's also saved on exit)")
+                        .default_value("30")
+                        .validator(crate::helper::is_u32_validator),
+                ),
         )
 }
+/// The device `provisioner run --device-uuid` adds to the network.
+#[derive(Copy, Clone, Debug)]
+pub struct DeviceArgs {
+    pub uuid: UUID,
+    pub static_oob: Option<AuthValue>,
+    pub net_key_index: NetKeyIndex,
+}
+impl DeviceArgs {
+    fn from_matches(matches: &clap::ArgMatches) -> Result<Option<DeviceArgs>, CLIError> {
+        let uuid = match matches.value_of("device_uuid") {
+            Some(uuid) => UUID(crate::helper::hex_str_to_bytes(uuid).expect("validated by clap")),
+            None => return Ok(None),
+        };
+        let static_oob = matches
+            .value_of("static_oob")
+            .map(|hex| AuthValue(crate::helper::hex_str_to_bytes(hex).expect("validated by clap")));
+        let index = matches
+            .value_of("net_key_index")
+            .and_then(|index| index.parse::<u16>().ok())
+            .expect("validated by clap");
+        let index = KeyIndex::try_from(index).map_err(|_| {
+            CLIError::OtherMessage(format!("net key index {} is more than 12 bits", index))
+        })?;
+        Ok(Some(DeviceArgs {
+            uuid,
+            static_oob,
+            net_key_index: NetKeyIndex(index),
+        }))
+    }
+}
+fn replay_flush_interval(matches: &clap::ArgMatches) -> Result<Duration, CLIError> {
+    let seconds = matches
+        .value_of("replay_flush_interval")
+        .and_then(|seconds| seconds.parse::<u32>().ok())
+        .expect("validated by clap");
+    if seconds == 0 {
+        return Err(CLIError::OtherMessage(
+            "replay flush interval must be at least 1 second".to_owned(),
+        ));
+    }
+    Ok(Duration::from_secs(seconds.into()))
+}
 pub fn provisioner_matches(
     logger: &slog::Logger,
     device_state_path: &str,
+    json_output: bool,
     matches: &clap::ArgMatches,
 ) -> Result<(), CLIError> {
     match matches.subcommand() {
-        ("run", Some(_matches)) => tokio_runtime().block_on(provision(logger, device_state_path)),
+        ("run", Some(run_matches)) => provision_blocking(
+            logger,
+            device_state_path,
+            json_output,
+            run_matches.is_present("monitor"),
+            run_matches.value_of("adapter"),
+            run_matches.value_of("auto_configure"),
+            DeviceArgs::from_matches(run_matches)?,
+            replay_flush_interval(run_matches)?,
+        ),
         ("", None) => Err(CLIError::Clap(clap::Error::with_description(
             "missing subcommand",
             clap::ErrorKind::ArgumentNotFound,
@@ -30,37 +160,697 @@ pub fn provisioner_matches(
     }
 }
 
-pub async fn provision(_logger: &slog::Logger, device_state_path: &str) -> Result<(), CLIError> {
+/// Blocking version of `provision`. Safe to call from inside an existing tokio runtime (see
+/// `helper::block_on`). Async callers should `.await` `provision` directly instead.
+pub fn provision_blocking(
+    logger: &slog::Logger,
+    device_state_path: &str,
+    json_output: bool,
+    monitor: bool,
+    adapter_id: Option<&str>,
+    auto_configure_path: Option<&str>,
+    device: Option<DeviceArgs>,
+    replay_flush_interval: Duration,
+) -> Result<(), CLIError> {
+    crate::helper::block_on(provision(
+        logger,
+        device_state_path,
+        json_output,
+        monitor,
+        adapter_id,
+        auto_configure_path,
+        device,
+        replay_flush_interval,
+    ))
+}
+/// Runs the provisioner until the adapter's advertisement stream ends. With `json_output`, every
+/// decoded beacon, network PDU, access message and PB-ADV PDU is emitted as a JSON line (see
+/// `json_output`) and the human readable output goes to stderr.
+///
+/// With `device`, a PB-ADV link is opened to that device and it's provisioned. The new node is
+/// saved to the device state file.
+///
+/// The replay protection cache is loaded from its sidecar file (`helper::replay_cache_path`) and
+/// saved back every `replay_flush_interval` and once the stack stops.
+pub async fn provision(
+    logger: &slog::Logger,
+    device_state_path: &str,
+    json_output: bool,
+    monitor: bool,
+    adapter_id: Option<&str>,
+    auto_configure_path: Option<&str>,
+    device: Option<DeviceArgs>,
+    replay_flush_interval: Duration,
+) -> Result<(), CLIError> {
     let dsm = crate::helper::load_device_state(device_state_path)?;
-    let (adapter, adapter_source) = crate::helper::hci_adapter();
-    println!("using hci adapter from '{}'", adapter_source);
-    futures_util::pin_mut!(adapter);
-    let adapter = btle::hci::adapters::Adapter::new(adapter);
-    let mut le = adapter.le();
-    async move {
-        let incoming = le.advertisement_stream::<Box<[ReportInfo]>>().await?;
-        futures_util::pin_mut!(incoming);
-        let internals = StackInternals::new(dsm);
-        let cache = replay::Cache::new();
-        let mut stack = FullStack::new(internals, cache, 5);
-        while let Some(report_info) = incoming.next().await {
-            if let Some(new_msg) = IncomingMessage::from_report_info(report_info?) {
-                dbg!(&new_msg);
-                match new_msg {
-                    IncomingMessage::Network(n) => {
-                        if stack.incoming_bearer.send(n).await.is_err() {
-                            break;
+    let replay_cache = ReplayCacheFile {
+        path: crate::helper::replay_cache_path(device_state_path),
+        flush_interval: replay_flush_interval,
+    };
+    // Check the NetKey and addresses before touching the adapter too.
+    let new_device = match device {
+        Some(device) => Some(NewDevice::new(&dsm, device_state_path, device)?),
+        None => None,
+    };
+    // Load the template before touching the adapter so a bad template fails right away.
+    let auto_configure = match auto_configure_path {
+        Some(path) => {
+            let steps = auto_configure::load_template(path)?;
+            json_output::print_status(
+                json_output,
+                format_args!("auto-configure template '{}':", path),
+            );
+            for step in &steps {
+                json_output::print_status(json_output, format_args!("  {}", step));
+            }
+            Some(steps)
+        }
+        None => None,
+    };
+    match adapter_id {
+        Some(adapter_id) => {
+            let (adapter, adapter_source) = crate::helper::hci_adapter_by_id(adapter_id)?;
+            provision_with_adapter(
+                logger,
+                device_state_path,
+                dsm,
+                json_output,
+                monitor,
+                auto_configure,
+                new_device,
+                replay_cache,
+                adapter,
+                &adapter_source,
+            )
+            .await
+        }
+        None => {
+            let (adapter, adapter_source) = crate::helper::hci_adapter();
+            provision_with_adapter(
+                logger,
+                device_state_path,
+                dsm,
+                json_output,
+                monitor,
+                auto_configure,
+                new_device,
+                replay_cache,
+                adapter,
+                adapter_source,
+            )
+            .await
+        }
+    }
+}
+async fn provision_with_adapter<A: btle::hci::adapter::Adapter>(
+    logger: &slog::Logger,
+    device_state_path: &str,
+    dsm: bluetooth_mesh::device_state::DeviceState,
+    json_output: bool,
+    monitor: bool,
+    auto_configure: Option<Vec<auto_configure::Step>>,
+    new_device: Option<NewDevice>,
+    replay_cache: ReplayCacheFile,
+    adapter: A,
+    adapter_source: &str,
+) -> Result<(), CLIError> {
+    json_output::print_status(
+        json_output,
+        format_args!("using hci adapter from '{}'", adapter_source),
+    );
+    if auto_configure.is_some() && new_device.is_none() {
+        warn!(logger, "auto_configure_unused";
+            "reason" => "no device to provision (`--device-uuid`)");
+    }
+    let cache = replay_cache.load().await?;
+    info!(logger, "replay_cache_loaded"; "path" => &replay_cache.path, "sources" => cache.len());
+    // Reported in our Composition Data. `0xFFFF` is the Company ID for devices without one.
+    let product = ProductInfo {
+        cid: CompanyID(0xFFFF),
+        pid: ProductID(0x0000),
+        vid: VersionID(0x0000),
+        crpl: CRPL(u16::try_from(cache.capacity()).unwrap_or(u16::max_value())),
+    };
+    let mut internals = StackInternals::new(dsm);
+    internals.set_seq_store(Some(Box::new(crate::helper::DeviceStateSeqStore {
+        path: device_state_path.to_owned(),
+        logger: logger.clone(),
+    })));
+    let mut stack = FullStack::with_logger(
+        internals,
+        cache,
+        5,
+        FullStackOptions::default().monitor(monitor || json_output),
+        logger.clone(),
+    );
+    let attention_logger = logger.new(o!("attention" => true));
+    stack.set_health_server(Some(HealthServer::new(
+        product.cid,
+        Box::new(move |event| info!(attention_logger, "attention"; "event" => ?event)),
+    )));
+    let shared_cache = stack.replay_cache.clone();
+    // Dropping `stop_flusher` stops the periodic flushes.
+    let (stop_flusher, stop_rx) = mpsc::channel::<()>(1);
+    let flusher = tokio::spawn(replay_cache.clone().flush_loop(
+        shared_cache.clone(),
+        stop_rx,
+        logger.clone(),
+    ));
+    let (advertiser, advertisements) = HciAdvertiser::new();
+    let (incoming_tx, mut incoming) = mpsc::channel(INCOMING_CHANNEL_SIZE);
+    if let Some(mut monitor_rx) = stack.monitor.take() {
+        let logger = logger.new(o!("monitor" => true));
+        tokio::spawn(async move {
+            while let Some(pdu) = monitor_rx.recv().await {
+                if json_output {
+                    emit(&logger, &Event::from_network_pdu(&pdu));
+                } else {
+                    info!(logger, "net_pdu"; "pdu" => ?pdu);
+                }
+            }
+        });
+    }
+    // Config and Health messages are answered by our servers. The rest are printed and handed
+    // to the Config Client configuring a new node (dropped if it isn't waiting for them).
+    let (_, closed) = bluetooth_mesh::asyncs::sync::mpsc::channel(1);
+    let mut access_rx = std::mem::replace(&mut stack.incoming_access, closed);
+    // Our servers' replies and the Config Client's messages.
+    let (_, closed) = bluetooth_mesh::asyncs::sync::mpsc::channel(1);
+    let outgoing_rx = std::mem::replace(&mut stack.outgoing_bearer, closed);
+    let stack = Arc::new(stack);
+    let dispatch_stack = stack.clone();
+    let dispatch = async move {
+        let stack = dispatch_stack;
+        tokio::spawn(bearers::transmit(
+            outgoing_rx,
+            AdvBearer::new(advertiser.clone()),
+            futures_util::future::pending(),
+            logger.new(o!("net_tx" => true)),
+        ));
+        let (mut replies_tx, replies_rx) = mpsc::channel(CONFIG_REPLIES_CHANNEL_SIZE);
+        let mut incoming_bearer = stack.incoming_bearer.clone();
+        let access_stack = stack.clone();
+        let access_logger = logger.new(o!("access" => true));
+        tokio::spawn(async move {
+            while let Some(msg) = access_rx.recv().await {
+                match access_stack.handle_access_message(&msg, &product).await {
+                    Ok(true) => debug!(access_logger, "access_message_answered"; "src" => ?msg.src),
+                    Ok(false) => {
+                        if json_output {
+                            emit(&access_logger, &Event::from_access_message(&msg))
                         }
+                        let _ = replies_tx.try_send(msg);
+                    }
+                    Err(e) => warn!(access_logger, "access_response_failed";
+                        "src" => ?msg.src, "error" => ?e),
+                }
+            }
+        });
+        let mut pb_adv_tx = new_device.map(|new_device| {
+            new_device.spawn(
+                logger,
+                json_output,
+                stack.clone(),
+                advertiser,
+                replies_rx,
+                auto_configure,
+            )
+        });
+        while let Some(new_msg) = incoming.recv().await {
+            dbg!(&new_msg);
+            match new_msg {
+                IncomingMessage::Network(n) => {
+                    if incoming_bearer.send(n).await.is_err() {
+                        break;
                     }
-                    IncomingMessage::Beacon(b) => todo!("handle beacons {:?}", b),
-                    IncomingMessage::PBAdv(p) => todo!("handle pb_adv {:?}", p),
+                }
+                IncomingMessage::Beacon(b) => {
+                    if json_output {
+                        emit(logger, &Event::from_beacon(&b));
+                    }
+                    stack.feed_beacon(&b).await
+                }
+                IncomingMessage::PBAdv(p) => {
+                    if json_output {
+                        emit(logger, &Event::from_pb_adv(&p));
+                    } else {
+                        report_provisioning_failure(logger, &p)
+                    }
+                    if let Some(pb_adv_tx) = pb_adv_tx.as_mut() {
+                        // Dropping a PDU when the link falls behind is fine (the other end
+                        // retransmits) and nothing reads them once provisioning is done.
+                        let _ = pb_adv_tx.try_send(p.pdu);
+                    }
+                }
+            }
+        }
+    };
+    let run = futures_util::future::join(
+        bearers::run_adapter(adapter, advertisements, incoming_tx),
+        dispatch,
+    );
+    // The adapter keeps running while the queued segmented messages go out.
+    let interrupted = async {
+        let _ = tokio::signal::ctrl_c().await;
+        info!(logger, "interrupted"; "shutdown_deadline" => ?SHUTDOWN_DEADLINE);
+        stack.shutdown(SHUTDOWN_DEADLINE).await
+    };
+    futures_util::pin_mut!(run, interrupted);
+    let result = match futures_util::future::select(run, interrupted).await {
+        Either::Left(((result, ()), _)) => {
+            result.map_err(|e| CLIError::OtherMessage(format!("stack error: {:?}", e)))
+        }
+        Either::Right((shutdown, _)) => {
+            shutdown.map_err(|e| CLIError::OtherMessage(format!("shutdown error: {:?}", e)))
+        }
+    };
+    // Save the cache even if the stack stopped with an error.
+    drop(stop_flusher);
+    // Wait for any flush in progress so it can't overwrite the final one.
+    let _ = flusher.await;
+    replay_cache.flush(&shared_cache).await?;
+    result?;
+    json_output::print_status(json_output, format_args!("provisioner done"));
+    Ok(())
+}
+/// Where the replay protection cache is saved and how often.
+#[derive(Clone, Debug)]
+struct ReplayCacheFile {
+    path: String,
+    flush_interval: Duration,
+}
+impl ReplayCacheFile {
+    async fn load(&self) -> Result<replay::Cache, CLIError> {
+        crate::helper::load_replay_cache(&self.path).await
+    }
+    /// Saves a snapshot of `cache`. The lock is only held while copying it.
+    async fn flush(&self, cache: &Mutex<replay::Cache>) -> Result<(), CLIError> {
+        let snapshot = cache.lock().await.clone();
+        crate::helper::write_replay_cache(&self.path, &snapshot).await
+    }
+    /// Flushes `cache` every `flush_interval` until `stop`'s sender is dropped. Failed writes are
+    /// only logged so a full disk doesn't stop the stack.
+    async fn flush_loop(
+        self,
+        cache: Arc<Mutex<replay::Cache>>,
+        mut stop: mpsc::Receiver<()>,
+        logger: slog::Logger,
+    ) {
+        while tokio::time::timeout(self.flush_interval, stop.recv())
+            .await
+            .is_err()
+        {
+            if let Err(e) = self.flush(&cache).await {
+                warn!(logger, "replay_cache_flush_failed"; "path" => &self.path, "error" => ?e);
+            }
+        }
+    }
+}
+/// A device to provision (from `DeviceArgs`) with everything it'll be given.
+struct NewDevice {
+    uuid: UUID,
+    primary_address: UnicastAddress,
+    parameters: ProvisioningParameters,
+    device_state_path: String,
+}
+impl NewDevice {
+    fn new(dsm: &DeviceState, device_state_path: &str, args: DeviceArgs) -> Result<Self, CLIError> {
+        let index = u16::from(args.net_key_index.0);
+        let net_key = dsm
+            .security_materials()
+            .net_key_map
+            .get_keys(args.net_key_index)
+            .ok_or_else(|| CLIError::OtherMessage(format!("no NetKey with index {}", index)))?;
+        let primary_address = dsm
+            .next_node_address()
+            .ok_or_else(|| CLIError::OtherMessage("no unicast addresses left".to_owned()))?;
+        Ok(NewDevice {
+            uuid: args.uuid,
+            primary_address,
+            parameters: ProvisioningParameters {
+                attention_timer: AttentionTimer::new(0),
+                authentication: args
+                    .static_oob
+                    .map_or(Authentication::NoOOB, Authentication::StaticOOB),
+                net_key: *net_key.tx_key().net_key(),
+                net_key_index: args.net_key_index,
+                key_refresh: (net_key.phase() == KeyRefreshPhases::Second).into(),
+                iv_update: dsm.iv_update_flag(),
+                iv_index: dsm.iv_index(),
+            },
+            device_state_path: device_state_path.to_owned(),
+        })
+    }
+    /// Starts provisioning in the background. PB-ADV PDUs from the scanner go into the returned
+    /// `Sender` and ours are advertised with `advertiser`. Once provisioned, the node is
+    /// configured with `auto_configure` (if given) by a Config Client reading its statuses from
+    /// `replies`.
+    fn spawn(
+        self,
+        logger: &slog::Logger,
+        json_output: bool,
+        stack: Arc<FullStack>,
+        advertiser: HciAdvertiser,
+        replies: mpsc::Receiver<AccessMessage>,
+        auto_configure: Option<Vec<auto_configure::Step>>,
+    ) -> mpsc::Sender<pb_adv::PDU> {
+        let (pb_adv_tx, pb_adv_rx) = mpsc::channel(PB_ADV_CHANNEL_SIZE);
+        let (outgoing_tx, outgoing_rx) = mpsc::channel(PB_ADV_CHANNEL_SIZE);
+        tokio::spawn(bearers::transmit_pb_adv(
+            outgoing_rx,
+            advertiser,
+            logger.new(o!("pb_adv_tx" => true)),
+        ));
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            let node = match self
+                .provision(json_output, &stack, pb_adv_rx, outgoing_tx)
+                .await
+            {
+                Ok(node) => node,
+                Err(e) => {
+                    error!(logger, "provisioning_failed"; "error" => ?e);
+                    return;
+                }
+            };
+            if let Some(steps) = auto_configure {
+                if let Err(e) = configure(json_output, &stack, replies, node, &steps).await {
+                    error!(logger, "auto_configure_failed"; "error" => ?e);
                 }
             }
+        });
+        pb_adv_tx
+    }
+    /// Provisions the device and adds it to the device state file and to `stack` (so its DevKey
+    /// can be used). Returns the new node's primary address and info.
+    async fn provision(
+        self,
+        json_output: bool,
+        stack: &FullStack,
+        incoming: mpsc::Receiver<pb_adv::PDU>,
+        outgoing: mpsc::Sender<pb_adv::PDU>,
+    ) -> Result<(UnicastAddress, NodeInfo), CLIError> {
+        let link_id = LinkID::new(u32::random_secure());
+        json_output::print_status(
+            json_output,
+            format_args!("opening link {:08x} to {}", link_id.value(), self.uuid),
+        );
+        let link = Link::open(link_id, self.uuid, incoming, outgoing)
+            .await
+            .map_err(|e| CLIError::OtherMessage(format!("can't open link: {:?}", e)))?;
+        let provisioned = session::provision(link, self.primary_address, &self.parameters, None)
+            .await
+            .map_err(|e| CLIError::OtherMessage(format!("provisioning failed: {:?}", e)))?;
+        let node = NodeInfo {
+            uuid: self.uuid,
+            element_count: provisioned.element_count,
+            net_key_index: self.parameters.net_key_index,
+            dev_key: provisioned.dev_key,
+        };
+        // The stack only writes the device state when it reserves Sequence Numbers so reload it
+        // and only add the node.
+        let mut dsm = crate::helper::load_device_state(&self.device_state_path)?;
+        dsm.nodes_mut().insert(provisioned.primary_address, node);
+        crate::helper::write_device_state(&self.device_state_path, &dsm)?;
+        stack
+            .internals_with_mut(|internals| {
+                internals
+                    .device_state_mut()
+                    .nodes_mut()
+                    .insert(provisioned.primary_address, node)
+            })
+            .await;
+        json_output::print_status(
+            json_output,
+            format_args!(
+                "provisioned {} as {:#06x} ({} elements)",
+                self.uuid,
+                u16::from(provisioned.primary_address),
+                provisioned.element_count.0
+            ),
+        );
+        Ok((provisioned.primary_address, node))
+    }
+}
+/// Applies every auto-configure step to the newly provisioned `node` with a Config Client. The
+/// steps are checked against the node's Composition Data first (see
+/// [`auto_configure::check_steps`]). Stops at the first step that fails.
+async fn configure(
+    json_output: bool,
+    stack: &FullStack,
+    mut replies: mpsc::Receiver<AccessMessage>,
+    (primary_address, node): (UnicastAddress, NodeInfo),
+    steps: &[auto_configure::Step],
+) -> Result<(), CLIError> {
+    let mut client = ConfigClient::new(stack, &mut replies, node.net_key_index);
+    let element_address = |element_index: ElementIndex| {
+        if element_index.0 >= node.element_count.0 {
+            return Err(CLIError::OtherMessage(format!(
+                "node {:#06x} has no element {}",
+                u16::from(primary_address),
+                element_index.0
+            )));
         }
-        Result::<(), Box<dyn btle::error::Error>>::Ok(())
+        UnicastAddress::try_from(u16::from(primary_address) + u16::from(element_index.0))
+            .map_err(|_| CLIError::OtherMessage("element address out of range".to_owned()))
+    };
+    let composition = client
+        .get_composition_data(primary_address)
+        .await
+        .map_err(|e| {
+            CLIError::OtherMessage(format!("getting the Composition Data failed: {:?}", e))
+        })?;
+    auto_configure::check_steps(steps, &composition)?;
+    for step in steps {
+        json_output::print_status(
+            json_output,
+            format_args!("configuring {:#06x}: {}", u16::from(primary_address), step),
+        );
+        let result = match *step {
+            auto_configure::Step::AddAppKey {
+                net_key_index,
+                app_key_index,
+                app_key,
+            } => {
+                client
+                    .add_app_key(primary_address, net_key_index, app_key_index, app_key)
+                    .await
+            }
+            auto_configure::Step::BindAppKey {
+                element_index,
+                model,
+                app_key_index,
+            } => {
+                client
+                    .bind_app_key(
+                        primary_address,
+                        element_address(element_index)?,
+                        app_key_index,
+                        model,
+                    )
+                    .await
+            }
+            auto_configure::Step::SetPublication {
+                element_index,
+                model,
+                address,
+                app_key_index,
+                ttl,
+            } => {
+                let publication = ModelPublishInfo {
+                    address,
+                    app_key_index,
+                    credential_flag: false,
+                    ttl,
+                    period: PublishPeriod::disabled(),
+                    retransmit: PublishRetransmit::from(0),
+                };
+                client
+                    .set_model_publication(
+                        primary_address,
+                        element_address(element_index)?,
+                        model,
+                        publication,
+                    )
+                    .await
+            }
+            auto_configure::Step::AddSubscription {
+                element_index,
+                model,
+                address,
+            } => {
+                client
+                    .add_subscription(
+                        primary_address,
+                        element_address(element_index)?,
+                        model,
+                        address,
+                    )
+                    .await
+            }
+            auto_configure::Step::SetFeature(feature, enabled) => {
+                match set_feature(&mut client, primary_address, feature, enabled).await {
+                    Ok(true) => Ok(()),
+                    Ok(false) => {
+                        return Err(CLIError::OtherMessage(format!(
+                            "auto-configure step '{}' failed: the node doesn't support {:?}",
+                            step, feature
+                        )))
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+        };
+        result.map_err(|e| {
+            CLIError::OtherMessage(format!("auto-configure step '{}' failed: {:?}", step, e))
+        })?;
     }
-    .await
-    .map_err(|e| CLIError::OtherMessage(format!("stack error: {:?}", e)))?;
-    println!("provisioner done");
+    json_output::print_status(
+        json_output,
+        format_args!("configured {:#06x}", u16::from(primary_address)),
+    );
     Ok(())
 }
+/// Enables or disables `feature` on `node`. Returns `false` if the node reports another state
+/// afterwards (it doesn't support `feature`).
+async fn set_feature(
+    client: &mut ConfigClient<'_>,
+    node: UnicastAddress,
+    feature: auto_configure::Feature,
+    enabled: bool,
+) -> Result<bool, ConfigClientError> {
+    Ok(match feature {
+        auto_configure::Feature::Relay => {
+            let state = if enabled {
+                RelayState::Enabled
+            } else {
+                RelayState::Disabled
+            };
+            client
+                .set_relay(node, state, RelayRetransmit::default())
+                .await?
+                .0
+                == state
+        }
+        auto_configure::Feature::Proxy => {
+            let state = if enabled {
+                GATTProxyState::Enabled
+            } else {
+                GATTProxyState::Disabled
+            };
+            client.set_gatt_proxy(node, state).await? == state
+        }
+        auto_configure::Feature::Friend => {
+            let state = if enabled {
+                FriendState::Enabled
+            } else {
+                FriendState::Disabled
+            };
+            client.set_friend(node, state).await? == state
+        }
+    })
+}
+/// Emits `event` as a JSON line. Write errors (like a closed pipe) are only logged so they don't
+/// stop the stack.
+fn emit(logger: &slog::Logger, event: &Event) {
+    if let Err(e) = json_output::emit(event) {
+        warn!(logger, "json_output_failed"; "error" => ?e);
+    }
+}
+/// Logs the reason of any Provisioning Failed PDU seen on a PB-ADV link.
+fn report_provisioning_failure(logger: &slog::Logger, incoming: &pb_adv::IncomingPDU) {
+    let generic_pdu = &incoming.pdu.generic_pdu;
+    let failed = match (&generic_pdu.control, generic_pdu.payload.as_ref()) {
+        // Failed PDUs are always small enough to fit in the Transaction Start.
+        (generic::Control::TransactionStart(_), Some(payload)) => {
+            match protocol::PDU::unpack_from(payload.as_ref()) {
+                Ok(protocol::PDU::Failed(failed)) => failed,
+                _ => return,
+            }
+        }
+        _ => return,
+    };
+    error!(logger, "provisioning_failed";
+        "link_id" => incoming.pdu.link_id.value(),
+        "code" => u8::from(failed.0),
+        "reason" => %failed.0);
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bluetooth_mesh::crypto::key::NetKey;
+    use bluetooth_mesh::mesh::ElementCount;
+    use bluetooth_mesh::provisioning::protocol::PDU;
+    use bluetooth_mesh::test_util;
+
+    /// Capabilities of a 2 element device without OOB.
+    const CAPABILITIES: [u8; 12] = [
+        0x01, 0x02, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn test_provision_new_device() {
+        let path =
+            std::env::temp_dir().join(format!("mesh_cli_provision_{}.json", std::process::id()));
+        let path = path.to_str().expect("utf-8 temp dir");
+        let mut dsm = DeviceState::new(UnicastAddress::new(0x0001), ElementCount(1));
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        dsm.add_net_key(net_key_index, &NetKey::new_bytes([0x33; 16]))
+            .expect("room for a NetKey");
+        crate::helper::write_device_state(path, &dsm).expect("device state written");
+        let args = DeviceArgs {
+            uuid: UUID([0x01; 16]),
+            static_oob: None,
+            net_key_index,
+        };
+        let new_device = NewDevice::new(&dsm, path, args).expect("NetKey and addresses left");
+        let capabilities = match PDU::unpack_from(&CAPABILITIES[..]) {
+            Ok(PDU::Capabilities(capabilities)) => capabilities,
+            pdu => panic!("expected capabilities, got {:?}", pdu),
+        };
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let mut runtime = crate::helper::tokio_runtime();
+        let (node, device) = runtime.block_on(async {
+            let stack = FullStack::new(StackInternals::new(dsm), replay::Cache::default(), 5);
+            let (advertiser, mut advertisements) = HciAdvertiser::new();
+            let (pb_adv_tx, pb_adv_rx) = mpsc::channel(PB_ADV_CHANNEL_SIZE);
+            let (outgoing_tx, outgoing_rx) = mpsc::channel(PB_ADV_CHANNEL_SIZE);
+            tokio::spawn(bearers::transmit_pb_adv(outgoing_rx, advertiser, logger));
+            // The device hears everything advertised. Its answers go straight to the link.
+            let (mut to_device_tx, mut to_device_rx) = mpsc::channel(PB_ADV_CHANNEL_SIZE);
+            tokio::spawn(async move {
+                while let Some(adv_data) = advertisements.recv().await {
+                    if let Some(IncomingMessage::PBAdv(incoming)) =
+                        IncomingMessage::from_adv_data(&adv_data, None)
+                    {
+                        if to_device_tx.send(incoming.pdu).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            });
+            let device = async move {
+                let open = to_device_rx.recv().await.expect("Link Open advertised");
+                let link = Link::accept(open.link_id, to_device_rx, pb_adv_tx)
+                    .await
+                    .expect("Link Ack sent");
+                test_util::provision_device(link, capabilities, AuthValue::NO_OOB, false).await
+            };
+            futures_util::future::join(
+                new_device.provision(false, &stack, pb_adv_rx, outgoing_tx),
+                device,
+            )
+            .await
+        });
+        let saved = crate::helper::load_device_state(path);
+        std::fs::remove_file(path).expect("device state file exists");
+        let (primary_address, node) = node.expect("device provisioned");
+        let (data, dev_key) = device.expect("provisioning data received");
+        assert_eq!(primary_address, UnicastAddress::new(0x0002));
+        assert_eq!(data.unicast_address, primary_address);
+        assert_eq!(node.element_count, ElementCount(2));
+        assert_eq!(node.dev_key, dev_key);
+        let saved = saved.expect("device state loads");
+        assert_eq!(saved.nodes().get(&primary_address), Some(&node));
+    }
+}
@@ -0,0 +1,379 @@
+use crate::commands::ble::bearers::{self, HciAdvertiser};
+use crate::{helper, CLIError};
+use bluetooth_mesh::access::Opcode;
+use bluetooth_mesh::address::Address;
+use bluetooth_mesh::asyncs::sync::{mpsc, oneshot};
+use bluetooth_mesh::control::{self, ControlMessage};
+use bluetooth_mesh::crypto::aes::MicSize;
+use bluetooth_mesh::foundation::state::NetworkTransmit;
+use bluetooth_mesh::lower;
+use bluetooth_mesh::mesh::{
+    AppKeyIndex, ElementIndex, KeyIndex, TransmitCount, TransmitInterval, TransmitSteps, TTL,
+};
+use bluetooth_mesh::stack::bearer::IncomingMessage;
+use bluetooth_mesh::stack::bearers::advertiser::AdvBearer;
+use bluetooth_mesh::stack::full::{FullStack, FullStackOptions};
+use bluetooth_mesh::stack::messages::{IncomingNetworkPDU, MessageKeys, OutgoingMessage};
+use bluetooth_mesh::stack::segments::SEGMENTS_SEND_TIMEOUT;
+use bluetooth_mesh::stack::{SendError, StackInternals};
+use bluetooth_mesh::upper::AppPayload;
+use std::convert::TryFrom;
+
+/// Mesh advertisements queued between the adapter and the stack.
+const INCOMING_CHANNEL_SIZE: usize = 32;
+
+pub fn sub_command() -> clap::App<'static, 'static> {
+    clap::SubCommand::with_name("send")
+        .about("send an Access message encrypted with a local appkey")
+        .arg(
+            clap::Arg::with_name("dst")
+                .long("dst")
+                .value_name("ADDRESS")
+                .help("destination address (`0x0005` or `5`)")
+                .required(true)
+                .validator(helper::is_address_validator),
+        )
+        .arg(
+            clap::Arg::with_name("app_key_index")
+                .long("app-key-index")
+                .value_name("APP_INDEX")
+                .help("appkey to encrypt the message with")
+                .default_value("0")
+                .validator(helper::is_u16_validator),
+        )
+        .arg(
+            clap::Arg::with_name("ttl")
+                .long("ttl")
+                .value_name("TTL")
+                .help("TTL of the message. Uses the node's default TTL if not given")
+                .validator(helper::is_ttl),
+        )
+        .arg(
+            clap::Arg::with_name("access_pdu")
+                .value_name("ACCESS_PDU_HEX")
+                .help("whole Access PDU (opcode || parameters) hex")
+                .required_unless("opcode")
+                .conflicts_with("opcode")
+                .validator(helper::is_hex_str_validator),
+        )
+        .arg(
+            clap::Arg::with_name("opcode")
+                .long("opcode")
+                .value_name("OPCODE_HEX")
+                .help("1, 2 or 3 byte opcode hex (`8204`)")
+                .validator(helper::is_hex_str_validator),
+        )
+        .arg(
+            clap::Arg::with_name("payload")
+                .long("payload")
+                .value_name("PARAMETERS_HEX")
+                .help("parameters following `--opcode`")
+                .requires("opcode")
+                .validator(helper::is_hex_str_validator),
+        )
+        .arg(
+            clap::Arg::with_name("segment")
+                .long("segment")
+                .help("segment the message even if it fits in one PDU"),
+        )
+        .arg(
+            clap::Arg::with_name("transmit_count")
+                .long("transmit-count")
+                .value_name("COUNT")
+                .help("retransmissions of every PDU. Uses the Network Transmit state if not given")
+                .validator(helper::is_transmit_count),
+        )
+        .arg(
+            clap::Arg::with_name("transmit_steps")
+                .long("transmit-steps")
+                .value_name("STEPS")
+                .help("transmissions (STEPS + 1) * 10ms apart. Uses the Network Transmit state if not given")
+                .validator(helper::is_transmit_steps),
+        )
+}
+/// Everything `send` needs from the command line.
+#[derive(Clone, Debug)]
+pub struct SendArgs {
+    pub dst: Address,
+    pub app_key_index: AppKeyIndex,
+    pub ttl: Option<TTL>,
+    pub access_pdu: Box<[u8]>,
+    pub force_segment: bool,
+    /// Overrides the node's Network Transmit state for the advertising bearer.
+    pub network_transmit: Option<NetworkTransmit>,
+}
+impl SendArgs {
+    fn from_matches(matches: &clap::ArgMatches) -> Result<SendArgs, CLIError> {
+        let dst = helper::parse_address(matches.value_of("dst").expect("required by clap"))
+            .expect("validated by clap");
+        match dst {
+            Address::Unassigned => {
+                return Err(CLIError::OtherMessage(
+                    "can't send to the unassigned address".to_owned(),
+                ))
+            }
+            Address::VirtualHash(_) => {
+                return Err(CLIError::OtherMessage(
+                    "sending to a virtual address needs its full label UUID".to_owned(),
+                ))
+            }
+            _ => (),
+        }
+        let index = matches
+            .value_of("app_key_index")
+            .and_then(|index| index.parse::<u16>().ok())
+            .expect("validated by clap");
+        let index = KeyIndex::try_from(index).map_err(|_| {
+            CLIError::OtherMessage(format!("app key index {} is more than 12 bits", index))
+        })?;
+        let ttl = matches
+            .value_of("ttl")
+            .map(|ttl| TTL::new(ttl.parse().expect("validated by clap")));
+        let network_transmit = match (
+            matches.value_of("transmit_count"),
+            matches.value_of("transmit_steps"),
+        ) {
+            (None, None) => None,
+            (count, steps) => {
+                // The one not given comes from the default Network Transmit.
+                let default = NetworkTransmit::default().0;
+                Some(NetworkTransmit(TransmitInterval::new(
+                    count.map_or(default.count, |count| {
+                        TransmitCount::new(count.parse().expect("validated by clap"))
+                    }),
+                    steps.map_or(default.steps, |steps| {
+                        TransmitSteps::new(steps.parse().expect("validated by clap"))
+                    }),
+                )))
+            }
+        };
+        let hex = |name| {
+            matches
+                .value_of(name)
+                .map(|hex| helper::hex_str_to_vec(hex).expect("validated by clap"))
+        };
+        let access_pdu = match hex("opcode") {
+            Some(opcode) => {
+                let opcode_len = Opcode::unpack_from(&opcode)
+                    .ok()
+                    .map(|o| o.byte_len())
+                    .ok_or_else(|| CLIError::OtherMessage("invalid opcode".to_owned()))?;
+                if opcode_len != opcode.len() {
+                    return Err(CLIError::OtherMessage(format!(
+                        "opcode should be {} bytes long",
+                        opcode_len
+                    )));
+                }
+                let mut pdu = opcode;
+                pdu.extend_from_slice(&hex("payload").unwrap_or_default());
+                pdu
+            }
+            None => {
+                let pdu = hex("access_pdu").expect("required by clap");
+                Opcode::split_from(&pdu).map_err(|_| {
+                    CLIError::OtherMessage(
+                        "access PDU doesn't start with a valid opcode".to_owned(),
+                    )
+                })?;
+                pdu
+            }
+        };
+        Ok(SendArgs {
+            dst,
+            app_key_index: AppKeyIndex(index),
+            ttl,
+            access_pdu: access_pdu.into_boxed_slice(),
+            force_segment: matches.is_present("segment"),
+            network_transmit,
+        })
+    }
+}
+pub fn send_matches(
+    logger: &slog::Logger,
+    device_state_path: &str,
+    matches: &clap::ArgMatches,
+) -> Result<(), CLIError> {
+    let args = SendArgs::from_matches(matches)?;
+    helper::block_on(send(
+        logger,
+        device_state_path,
+        matches.value_of("adapter"),
+        args,
+    ))
+}
+/// Sends `args.access_pdu` through the whole stack (access, upper/lower transport and network
+/// layer) and advertises every Network PDU with the HCI adapter. While sending, the adapter is
+/// scanned so segment acks from a unicast destination reach the stack. Each ack is reported as it
+/// comes in. The message only counts as sent once every transmission of its PDUs was advertised.
+///
+/// Each new block of Sequence Numbers is saved before it's used (see
+/// `helper::DeviceStateSeqStore`). The device state and the replay protection cache are saved
+/// again afterwards.
+pub async fn send(
+    logger: &slog::Logger,
+    device_state_path: &str,
+    adapter_id: Option<&str>,
+    args: SendArgs,
+) -> Result<(), CLIError> {
+    let dsm = helper::load_device_state(device_state_path)?;
+    let cache_path = helper::replay_cache_path(device_state_path);
+    let cache = helper::load_replay_cache(&cache_path).await?;
+    let mut internals = StackInternals::new(dsm);
+    internals.set_seq_store(Some(Box::new(helper::DeviceStateSeqStore {
+        path: device_state_path.to_owned(),
+        logger: logger.clone(),
+    })));
+    let mut stack = FullStack::with_logger(
+        internals,
+        cache,
+        5,
+        FullStackOptions::default().monitor(true),
+        logger.clone(),
+    );
+    let sent = match adapter_id {
+        Some(adapter_id) => {
+            let (adapter, adapter_source) = helper::hci_adapter_by_id(adapter_id)?;
+            send_with_adapter(logger, &mut stack, &args, adapter, &adapter_source).await
+        }
+        None => {
+            let (adapter, adapter_source) = helper::hci_adapter();
+            send_with_adapter(logger, &mut stack, &args, adapter, adapter_source).await
+        }
+    };
+    // Even if the send failed, its Sequence Numbers are used up and the cache might have changed.
+    helper::write_device_state(
+        device_state_path,
+        stack.internals.read().await.device_state(),
+    )?;
+    let cache = stack.replay_cache.lock().await.clone();
+    helper::write_replay_cache(&cache_path, &cache).await?;
+    sent
+}
+async fn send_with_adapter<A: btle::hci::adapter::Adapter>(
+    logger: &slog::Logger,
+    stack: &mut FullStack,
+    args: &SendArgs,
+    adapter: A,
+    adapter_source: &str,
+) -> Result<(), CLIError> {
+    info!(logger, "send"; "adapter" => adapter_source, "dst" => ?args.dst);
+    let src = stack
+        .internals
+        .read()
+        .await
+        .device_state()
+        .element_address(ElementIndex(0))
+        .expect("primary element always exists");
+    if let Some(monitor_rx) = stack.monitor.take() {
+        tokio::spawn(report_acks(monitor_rx, args.dst, Address::Unicast(src)));
+    }
+    // Take the outgoing PDUs so `stack.send_message` can be borrowed while they're transmitted.
+    let (_, closed) = mpsc::channel(1);
+    let outgoing_rx = std::mem::replace(&mut stack.outgoing_bearer, closed);
+    let (advertiser, advertisements) = HciAdvertiser::new();
+    let mut bearer = AdvBearer::new(advertiser);
+    bearer.set_network_transmit(args.network_transmit);
+    let (stop_transmit, stop_rx) = oneshot::channel::<()>();
+    let transmit = bearers::transmit(
+        outgoing_rx,
+        bearer,
+        async move {
+            let _ = stop_rx.await;
+        },
+        logger.new(o!("net_tx" => true)),
+    );
+    let msg = OutgoingMessage {
+        app_payload: AppPayload(args.access_pdu.clone()),
+        mic_size: MicSize::Small,
+        force_segment: args.force_segment,
+        encryption_key: MessageKeys::App(args.app_key_index),
+        iv_index: stack.internals.read().await.device_state().tx_iv_index(),
+        source_element_index: ElementIndex(0),
+        dst: args.dst,
+        ttl: args.ttl,
+    };
+    let acked = msg.should_segment() && args.dst.is_unicast();
+    let (incoming_tx, mut incoming_rx) = mpsc::channel(INCOMING_CHANNEL_SIZE);
+    let mut incoming_bearer = stack.incoming_bearer.clone();
+    // Keeps reading until `run_adapter` stops so it never waits on a full channel.
+    let dispatch = async move {
+        while let Some(msg) = incoming_rx.recv().await {
+            if let IncomingMessage::Network(n) = msg {
+                let _ = incoming_bearer.send(n).await;
+            }
+        }
+    };
+    // The stack sends the segments (and retransmits them) on its own. `delivered` gets how the
+    // transfer ended. Nothing else gets transmitted after that.
+    let sending = async {
+        let result = match stack.queue_message(msg).await {
+            Ok(delivered) => delivered.await.unwrap_or(Err(SendError::ChannelClosed)),
+            Err(e) => Err(e),
+        };
+        let _ = stop_transmit.send(());
+        result
+    };
+    // `run_adapter` stops once `transmit` is done and every advertisement went out.
+    let (result, failed, adapter_result, ()) = futures_util::future::join4(
+        sending,
+        transmit,
+        bearers::run_adapter(adapter, advertisements, incoming_tx),
+        dispatch,
+    )
+    .await;
+    if let Err(e) = adapter_result {
+        return Err(CLIError::OtherMessage(format!(
+            "adapter stopped before every PDU was advertised: {:?}",
+            e
+        )));
+    }
+    if result.is_ok() && failed > 0 {
+        return Err(CLIError::OtherMessage(format!(
+            "{} network PDUs couldn't be advertised",
+            failed
+        )));
+    }
+    match result {
+        Ok(()) if acked => println!("every segment acked by {:?}", args.dst),
+        Ok(()) => println!("sent to {:?}", args.dst),
+        Err(SendError::AckTimeout) => println!(
+            "no ack for every segment from {:?} within {}s",
+            args.dst,
+            SEGMENTS_SEND_TIMEOUT.as_secs()
+        ),
+        Err(SendError::Unacknowledged) => println!(
+            "{:?} stopped acking segments after every retransmission",
+            args.dst
+        ),
+        Err(SendError::Canceled) => println!("{:?} canceled the message", args.dst),
+        Err(e) => println!("send failed: {:?}", e),
+    }
+    Ok(())
+}
+/// Prints every Segment Acknowledgment `dst` sends to `src`.
+async fn report_acks(
+    mut monitor_rx: mpsc::Receiver<IncomingNetworkPDU>,
+    dst: Address,
+    src: Address,
+) {
+    while let Some(incoming) = monitor_rx.recv().await {
+        let header = incoming.pdu.header();
+        if Address::Unicast(header.src) != dst || header.dst != src {
+            continue;
+        }
+        if let lower::PDU::UnsegmentedControl(pdu) = incoming.pdu.payload() {
+            if pdu.opcode() != control::Ack::OPCODE {
+                continue;
+            }
+            if let Ok(ack) = control::Ack::unpack(pdu.data()) {
+                println!(
+                    "ack from {:?}: seq_zero: {} block_ack: {:#010x} ({} segments)",
+                    dst,
+                    u16::from(ack.seq_zero),
+                    ack.block_ack.0,
+                    ack.block_ack.count_ones()
+                );
+            }
+        }
+    }
+}
@@ -1,59 +1,126 @@
 use crate::{helper, CLIError};
 use bluetooth_mesh::address::{Address, UnicastAddress};
+use bluetooth_mesh::crypto::key::AppKey;
 use bluetooth_mesh::device_state;
-use bluetooth_mesh::mesh::ElementCount;
+use bluetooth_mesh::foundation::state::DefaultTTLState;
+use bluetooth_mesh::foundation::FeatureFlags;
+use bluetooth_mesh::mesh::{AppKeyIndex, ElementCount, ElementIndex, KeyIndex, NetKeyIndex};
+use bluetooth_mesh::random::Randomizable;
+use std::convert::TryFrom;
 use std::str::FromStr;
 
+/// Default TTL of new device states (the spec's recommended 7).
+const DEFAULT_TTL: &str = "7";
+fn key_index_arg(name: &'static str, long: &'static str) -> clap::Arg<'static, 'static> {
+    clap::Arg::with_name(name)
+        .long(long)
+        .value_name("KEY_INDEX")
+        .required(true)
+        .validator(helper::is_u16_validator)
+}
+fn app_key_sub_command() -> clap::App<'static, 'static> {
+    clap::SubCommand::with_name("app_key")
+        .about("Manage the application keys")
+        .subcommand(
+            clap::SubCommand::with_name("add")
+                .about("Add an AppKey bound to an existing NetKey")
+                .arg(key_index_arg("net_key_index", "net-key-index"))
+                .arg(key_index_arg("app_key_index", "app-key-index"))
+                .arg(
+                    clap::Arg::with_name("key")
+                        .long("key")
+                        .value_name("APP_KEY")
+                        .help("128-bit AppKey (32 hex characters). Random if not given")
+                        .validator(helper::is_128_bit_hex_str_validator),
+                ),
+        )
+        .subcommand(clap::SubCommand::with_name("list").about("List the AppKeys"))
+        .subcommand(
+            clap::SubCommand::with_name("remove")
+                .about("Remove an AppKey and unbind it from every model")
+                .arg(key_index_arg("app_key_index", "app-key-index")),
+        )
+}
 pub fn sub_command() -> clap::App<'static, 'static> {
-    clap::SubCommand::with_name("state").subcommand(
-        clap::SubCommand::with_name("new")
-            .about("Generate a device state with desired parameters")
-            .arg(
-                clap::Arg::with_name("element_count")
-                    .short("c")
-                    .value_name("ELEMENT_COUNT")
-                    .required(true)
-                    .default_value("1")
-                    .validator(|count| {
-                        if let Ok(c) = usize::from_str(&count) {
-                            match c {
-                                1..=0xFF => Ok(()),
-                                _ => Err(format!(
-                                    "Invalid element count '{}'. Expected in range [1..0xFF]",
-                                    c
-                                )),
+    clap::SubCommand::with_name("state")
+        .subcommand(app_key_sub_command())
+        .subcommand(
+            clap::SubCommand::with_name("set-feature")
+                .about("Enable or disable the Relay, Proxy or Friend feature")
+                .arg(
+                    clap::Arg::with_name("feature")
+                        .value_name("FEATURE")
+                        .required(true)
+                        .possible_values(&["relay", "proxy", "friend"]),
+                )
+                .arg(
+                    clap::Arg::with_name("enabled")
+                        .value_name("ON_OFF")
+                        .required(true)
+                        .possible_values(&["on", "off"]),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("show")
+                .about("Print a summary of the device state (no key material)")
+                .arg(
+                    clap::Arg::with_name("json")
+                        .long("json")
+                        .help("Print the raw device state JSON instead (includes the keys)"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("new")
+                .about("Generate a device state with desired parameters")
+                .arg(
+                    clap::Arg::with_name("element_count")
+                        .short("c")
+                        .value_name("ELEMENT_COUNT")
+                        .required(true)
+                        .default_value("1")
+                        .validator(|count| {
+                            if let Ok(c) = usize::from_str(&count) {
+                                match c {
+                                    1..=0xFF => Ok(()),
+                                    _ => Err(format!(
+                                        "Invalid element count '{}'. Expected in range [1..0xFF]",
+                                        c
+                                    )),
+                                }
+                            } else {
+                                Err(format!("Invalid element count '{}'. Not a number", count))
                             }
-                        } else {
-                            Err(format!("Invalid element count '{}'. Not a number", count))
-                        }
-                    }),
-            )
-            .arg(
-                clap::Arg::with_name("element_address")
-                    .short("a")
-                    .value_name("UNICAST_ADDRESS")
-                    .required(true)
-                    .default_value("1")
-                    .validator(|address| {
-                        let radix = if address.starts_with("0x") { 16 } else { 10 };
-                        if let Ok(a) = u16::from_str_radix(address.trim_start_matches("0x"), radix)
-                        {
-                            match Address::from(a) {
-                                Address::Unicast(_) => Ok(()),
-                                _ => Err(format!("Non-unicast address '{}' given", &address)),
+                        }),
+                )
+                .arg(
+                    clap::Arg::with_name("element_address")
+                        .short("a")
+                        .value_name("UNICAST_ADDRESS")
+                        .required(true)
+                        .default_value("1")
+                        .validator(|address| {
+                            let radix = if address.starts_with("0x") { 16 } else { 10 };
+                            if let Ok(a) =
+                                u16::from_str_radix(address.trim_start_matches("0x"), radix)
+                            {
+                                match Address::from(a) {
+                                    Address::Unicast(_) => Ok(()),
+                                    _ => Err(format!("Non-unicast address '{}' given", &address)),
+                                }
+                            } else {
+                                Err(format!("Non-address '{}' given", &address))
                             }
-                        } else {
-                            Err(format!("Non-address '{}' given", &address))
-                        }
-                    }),
-            )
-            .arg(
-                clap::Arg::with_name("default_ttl")
-                    .short("t")
-                    .value_name("DEFAULT_TTL")
-                    .validator(helper::is_ttl),
-            ),
-    )
+                        }),
+                )
+                .arg(
+                    clap::Arg::with_name("default_ttl")
+                        .short("t")
+                        .value_name("DEFAULT_TTL")
+                        .help("TTL of messages sent without their own TTL")
+                        .default_value(DEFAULT_TTL)
+                        .validator(helper::is_ttl),
+                ),
+        )
 }
 pub fn state_matches(
     parent_logger: &slog::Logger,
@@ -68,14 +135,49 @@ pub fn state_matches(
             ) {
                 (Some(element_count), Some(element_address)) => {
                     let count = ElementCount(element_count.parse().expect("checked by clap"));
-                    let address =
-                        UnicastAddress::new(element_address.parse().expect("checked by clap"));
-                    generate(parent_logger, device_state_path, address, count)
+                    let address = match helper::parse_address(element_address) {
+                        Some(Address::Unicast(address)) => address,
+                        _ => {
+                            return Err(CLIError::OtherMessage(format!(
+                                "primary address '{}' isn't a unicast address",
+                                element_address
+                            )))
+                        }
+                    };
+                    let ttl = new_matches
+                        .value_of("default_ttl")
+                        .expect("default_ttl has a default value")
+                        .parse::<u8>()
+                        .expect("checked by clap");
+                    let default_ttl = DefaultTTLState::try_new(ttl).ok_or_else(|| {
+                        CLIError::OtherMessage(format!(
+                            "default TTL {} isn't allowed (0 or 2-127)",
+                            ttl
+                        ))
+                    })?;
+                    generate(
+                        parent_logger,
+                        device_state_path,
+                        address,
+                        count,
+                        default_ttl,
+                    )
                 }
                 _ => unreachable!("element count and element address should have default values"),
             }
         }
-
+        ("app_key", Some(app_key_matches)) => {
+            app_key_command(parent_logger, device_state_path, app_key_matches)
+        }
+        ("show", Some(show_matches)) => show(device_state_path, show_matches.is_present("json")),
+        ("set-feature", Some(feature_matches)) => set_feature(
+            parent_logger,
+            device_state_path,
+            feature_matches
+                .value_of("feature")
+                .expect("required by clap"),
+            feature_matches.value_of("enabled") == Some("on"),
+        ),
         ("", None) => Err(CLIError::Clap(clap::Error::with_description(
             "missing state subcommand",
             clap::ErrorKind::ArgumentNotFound,
@@ -83,16 +185,160 @@ pub fn state_matches(
         _ => unreachable!("unhandled state subcommand"),
     }
 }
+fn key_index(matches: &clap::ArgMatches, name: &str) -> Result<KeyIndex, CLIError> {
+    let index = matches
+        .value_of(name)
+        .and_then(|index| index.parse::<u16>().ok())
+        .expect("validated by clap");
+    KeyIndex::try_from(index)
+        .map_err(|_| CLIError::OtherMessage(format!("key index {} is more than 12 bits", index)))
+}
+fn app_key_command(
+    parent_logger: &slog::Logger,
+    device_state_path: &str,
+    matches: &clap::ArgMatches,
+) -> Result<(), CLIError> {
+    let logger = parent_logger.new(o!("device_state_path" => device_state_path.to_owned()));
+    let mut device_state = helper::load_device_state(device_state_path)?;
+    match matches.subcommand() {
+        ("add", Some(add_matches)) => {
+            let net_key_index = NetKeyIndex(key_index(add_matches, "net_key_index")?);
+            let app_key_index = AppKeyIndex(key_index(add_matches, "app_key_index")?);
+            let app_key = match add_matches.value_of("key") {
+                Some(hex) => {
+                    AppKey::new_bytes(helper::hex_str_to_bytes(hex).expect("validated by clap"))
+                }
+                None => AppKey::random_secure(),
+            };
+            device_state
+                .add_app_key(net_key_index, app_key_index, app_key)
+                .map_err(|e| CLIError::OtherMessage(format!("can't add app key: {:?}", e)))?;
+            helper::write_device_state(device_state_path, &device_state)?;
+            info!(logger, "app_key_added"; "app_key_index" => u16::from(app_key_index.0),
+                "net_key_index" => u16::from(net_key_index.0));
+            Ok(())
+        }
+        ("list", Some(_)) => {
+            for (index, materials) in device_state.app_keys() {
+                println!(
+                    "{}: {:x} (aid {:?}, net key {})",
+                    u16::from(index.0),
+                    helper::HexSlice(materials.app_key.key().as_ref()),
+                    materials.aid,
+                    u16::from(materials.net_key_index.0)
+                );
+            }
+            Ok(())
+        }
+        ("remove", Some(remove_matches)) => {
+            let app_key_index = AppKeyIndex(key_index(remove_matches, "app_key_index")?);
+            device_state
+                .remove_app_key(app_key_index)
+                .map_err(|e| CLIError::OtherMessage(format!("can't remove app key: {:?}", e)))?;
+            helper::write_device_state(device_state_path, &device_state)?;
+            info!(logger, "app_key_removed"; "app_key_index" => u16::from(app_key_index.0));
+            Ok(())
+        }
+        ("", None) => Err(CLIError::Clap(clap::Error::with_description(
+            "missing app_key subcommand",
+            clap::ErrorKind::ArgumentNotFound,
+        ))),
+        _ => unreachable!("unhandled app_key subcommand"),
+    }
+}
+/// Enables or disables the Relay, Proxy or Friend `feature` (as named by `set-feature`).
+fn set_feature(
+    parent_logger: &slog::Logger,
+    device_state_path: &str,
+    feature: &str,
+    enabled: bool,
+) -> Result<(), CLIError> {
+    let logger = parent_logger.new(o!("device_state_path" => device_state_path.to_owned()));
+    let flag = match feature {
+        "relay" => FeatureFlags::Relay,
+        "proxy" => FeatureFlags::Proxy,
+        "friend" => FeatureFlags::Friend,
+        _ => unreachable!("checked by clap"),
+    };
+    let mut device_state = helper::load_device_state(device_state_path)?;
+    if !device_state.set_feature_enabled(flag, enabled) {
+        return Err(CLIError::OtherMessage(format!(
+            "the {} feature isn't supported by this node",
+            feature
+        )));
+    }
+    helper::write_device_state(device_state_path, &device_state)?;
+    info!(logger, "feature_set"; "feature" => feature, "enabled" => enabled);
+    Ok(())
+}
+fn show(device_state_path: &str, json: bool) -> Result<(), CLIError> {
+    let device_state = helper::load_device_state(device_state_path)?;
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&device_state).map_err(CLIError::SerdeJSON)?
+        );
+        return Ok(());
+    }
+    println!(
+        "primary address: {:#06x}",
+        u16::from(*device_state.unicast_range().start())
+    );
+    println!("element count: {}", device_state.element_count().0);
+    println!("default TTL: {}", device_state.default_ttl());
+    println!("relay: {:?}", device_state.relay_state());
+    println!("proxy: {:?}", device_state.gatt_proxy_state());
+    println!("friend: {:?}", device_state.friend_state());
+    println!("IV index: {}", device_state.iv_index().0);
+    println!("net keys:");
+    for (index, phase) in device_state.security_materials().net_key_map.map.iter() {
+        println!(
+            "  {}: nid {} ({:?})",
+            u16::from(index.0),
+            phase.tx_key().network_keys().nid(),
+            phase.phase()
+        );
+    }
+    println!("app keys:");
+    for (index, materials) in device_state.app_keys() {
+        println!(
+            "  {}: net key {}",
+            u16::from(index.0),
+            u16::from(materials.net_key_index.0)
+        );
+    }
+    println!("sequence number watermarks:");
+    for i in 0..device_state.element_count().0 {
+        println!(
+            "  element {}: {}",
+            i,
+            device_state
+                .seq_counter(ElementIndex(i))
+                .reserved()
+                .0
+                .value()
+        );
+    }
+    Ok(())
+}
 pub fn generate(
     parent_logger: &slog::Logger,
     device_state_path: &str,
     primary_address: UnicastAddress,
     element_count: ElementCount,
+    default_ttl: DefaultTTLState,
 ) -> Result<(), CLIError> {
     let logger = parent_logger.new(o!("device_state_path" => device_state_path.to_owned()));
-    let f = helper::load_file(device_state_path, true, true)?;
-    info!(logger, "found device_state");
-    let device_state = device_state::DeviceState::new(primary_address, element_count);
-    serde_json::to_writer(f, &device_state).map_err(CLIError::SerdeJSON)?;
+    let mut device_state = device_state::DeviceState::try_new(primary_address, element_count)
+        .ok_or_else(|| {
+            CLIError::OtherMessage(format!(
+                "{} elements starting at {:#06x} don't fit in the unicast range (0x0001..=0x7FFF)",
+                element_count.0,
+                u16::from(primary_address)
+            ))
+        })?;
+    device_state.set_default_ttl(default_ttl);
+    helper::write_device_state(device_state_path, &device_state)?;
+    info!(logger, "device_state_generated"; "default_ttl" => u8::from(default_ttl));
     Ok(())
 }
@@ -1,8 +1,9 @@
 use crate::CLIError;
 #[cfg(feature = "mesh")]
-use bluetooth_mesh::{device_state, mesh};
+use bluetooth_mesh::{address, device_state, mesh, replay};
 use std::convert::TryFrom;
 use std::fmt::{Error, Formatter};
+use std::future::Future;
 use std::str::FromStr;
 
 pub struct HexSlice<'a>(pub &'a [u8]);
@@ -34,6 +35,13 @@ pub fn is_hex_str(s: &str) -> bool {
     }
     return true;
 }
+pub fn is_hex_str_validator(input: String) -> Result<(), String> {
+    if !input.is_empty() && is_hex_str(&input) {
+        Ok(())
+    } else {
+        Err(format!("'{}' is not a hex string", &input))
+    }
+}
 pub fn is_128_bit_hex_str_validator(input: String) -> Result<(), String> {
     if input.len() == 32 && is_hex_str(&input) {
         Ok(())
@@ -52,6 +60,38 @@ pub fn is_ttl(input: String) -> Result<(), String> {
         Err(_) => error_msg(),
     }
 }
+/// 3-bit Transmit Count (retransmissions after the first transmission).
+pub fn is_transmit_count(input: String) -> Result<(), String> {
+    match u8::from_str(&input) {
+        Ok(v) if v <= 0b111 => Ok(()),
+        _ => Err(format!("`{}` is not a valid transmit count (0-7)", &input)),
+    }
+}
+/// 5-bit Transmit Interval Steps.
+pub fn is_transmit_steps(input: String) -> Result<(), String> {
+    match u8::from_str(&input) {
+        Ok(v) if v <= 0b1_1111 => Ok(()),
+        _ => Err(format!(
+            "`{}` is not a valid transmit interval steps (0-31)",
+            &input
+        )),
+    }
+}
+/// Parses a 16-bit address, either as hex (`0x0005`) or decimal (`5`).
+#[cfg(feature = "mesh")]
+pub fn parse_address(s: &str) -> Option<address::Address> {
+    let radix = if s.starts_with("0x") { 16 } else { 10 };
+    u16::from_str_radix(s.trim_start_matches("0x"), radix)
+        .ok()
+        .map(address::Address::from)
+}
+#[cfg(feature = "mesh")]
+pub fn is_address_validator(input: String) -> Result<(), String> {
+    match parse_address(&input) {
+        Some(_) => Ok(()),
+        None => Err(format!("'{}' is not a 16-bit address", &input)),
+    }
+}
 pub fn is_u8_validator(input: String) -> Result<(), String> {
     match u8::from_str(&input) {
         Ok(_) => Ok(()),
@@ -95,6 +135,16 @@ pub fn hex_str_to_bytes<T: Default + AsMut<[u8]>>(s: &str) -> Option<T> {
         Some(out)
     }
 }
+/// Like `hex_str_to_bytes` but for any (even) length of hex string.
+pub fn hex_str_to_vec(s: &str) -> Option<Vec<u8>> {
+    if !is_hex_str(s) {
+        return None;
+    }
+    s.as_bytes()
+        .chunks(2)
+        .map(|c| u8::from_str_radix(std::str::from_utf8(c).ok()?, 16).ok())
+        .collect()
+}
 pub fn is_bool_validator(input: String) -> Result<(), String> {
     bool::from_str(&input)
         .ok()
@@ -112,23 +162,165 @@ pub fn load_file(path: &str, writeable: bool, create: bool) -> Result<std::fs::F
 }
 #[cfg(feature = "mesh")]
 pub fn load_device_state(path: &str) -> Result<device_state::DeviceState, CLIError> {
-    serde_json::from_reader(load_file(path, false, false)?).map_err(CLIError::SerdeJSON)
+    use std::io::Read;
+    let mut bytes = Vec::new();
+    load_file(path, false, false)?
+        .read_to_end(&mut bytes)
+        .map_err(|e| CLIError::IOError(path.to_owned(), e))?;
+    crate::state_file::decode(bytes, crate::state_file::state_password().as_deref())
 }
+/// Saves the device state (see `state_file::encode`). Like `write_replay_cache`, it's written to
+/// a temporary file first and renamed over `path` so a crash mid write never loses the keys.
 #[cfg(feature = "mesh")]
 pub fn write_device_state(
     path: &str,
     device_state: &device_state::DeviceState,
 ) -> Result<(), CLIError> {
-    serde_json::to_writer_pretty(load_file(path, true, true)?, device_state)
-        .map_err(CLIError::SerdeJSON)
+    use std::io::Write;
+    let bytes =
+        crate::state_file::encode(device_state, crate::state_file::state_password().as_deref())?;
+    let tmp_path = format!("{}.tmp", path);
+    let mut file = load_file(&tmp_path, true, true)?;
+    file.write_all(&bytes)
+        .and_then(|()| file.sync_all())
+        .map_err(|e| CLIError::IOError(tmp_path.clone(), e))?;
+    std::fs::rename(&tmp_path, path).map_err(|e| CLIError::IOError(path.to_owned(), e))
+}
+/// Writes the device state to `path` every time the stack reserves a new block of Sequence
+/// Numbers so a crash never reuses them.
+#[cfg(feature = "mesh")]
+pub struct DeviceStateSeqStore {
+    pub path: String,
+    pub logger: slog::Logger,
+}
+#[cfg(feature = "mesh")]
+impl bluetooth_mesh::stack::SeqStore for DeviceStateSeqStore {
+    fn save(
+        &self,
+        device_state: &device_state::DeviceState,
+    ) -> Result<(), bluetooth_mesh::stack::SeqStoreError> {
+        write_device_state(&self.path, device_state).map_err(|e| {
+            slog::error!(self.logger, "seq_block_not_saved"; "path" => &self.path, "error" => ?e);
+            bluetooth_mesh::stack::SeqStoreError
+        })
+    }
+}
+/// Sidecar file next to the device state that the replay protection cache is saved in.
+pub fn replay_cache_path(device_state_path: &str) -> String {
+    format!("{}.replay", device_state_path)
 }
+/// Error for a blocking file IO task (see `tokio::task::spawn_blocking`) that panicked.
+#[cfg(feature = "mesh")]
+fn blocking_task_error(e: tokio::task::JoinError) -> CLIError {
+    CLIError::OtherMessage(format!("file IO task failed: {}", e))
+}
+/// Loads the replay protection cache saved by `write_replay_cache`. A missing file (first run)
+/// gives an empty cache. The file is read on tokio's blocking thread pool so it doesn't stall the
+/// stack.
+#[cfg(feature = "mesh")]
+pub async fn load_replay_cache(path: &str) -> Result<replay::Cache, CLIError> {
+    let owned_path = path.to_owned();
+    let read = tokio::task::spawn_blocking(move || std::fs::read(owned_path))
+        .await
+        .map_err(blocking_task_error)?;
+    match read {
+        Ok(bytes) => serde_json::from_slice(&bytes).map_err(CLIError::SerdeJSON),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(replay::Cache::default()),
+        Err(e) => Err(CLIError::IOError(path.to_owned(), e)),
+    }
+}
+/// Saves the replay protection cache (the highest seq and IVI seen per source). It's written to a
+/// temporary file first and renamed over `path` so a crash mid write keeps the old cache. Like
+/// `load_replay_cache`, the file IO runs on tokio's blocking thread pool.
+#[cfg(feature = "mesh")]
+pub async fn write_replay_cache(path: &str, cache: &replay::Cache) -> Result<(), CLIError> {
+    let bytes = serde_json::to_vec(cache).map_err(CLIError::SerdeJSON)?;
+    let tmp_path = format!("{}.tmp", path);
+    let owned_path = path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        std::fs::write(&tmp_path, bytes).and_then(|()| std::fs::rename(&tmp_path, owned_path))
+    })
+    .await
+    .map_err(blocking_task_error)?
+    .map_err(|e| CLIError::IOError(path.to_owned(), e))
+}
+/// Multi-threaded runtime for the CLI commands. It has to be threaded so `block_on` can block
+/// one of its threads.
 pub fn tokio_runtime() -> tokio::runtime::Runtime {
     tokio::runtime::Builder::new()
-        .basic_scheduler()
+        .threaded_scheduler()
         .enable_all()
         .build()
         .expect("can't make async runtime")
 }
+/// Runs `future` to completion from synchronous code. Makes a new runtime (`tokio_runtime`) for
+/// it unless this thread is already in one (the CLI embedded in a bigger async app). Then the
+/// existing runtime keeps driving the IO and timers while this thread blocks in
+/// `block_in_place` instead of panicking with "cannot start a runtime from within a runtime".
+///
+/// # Panics
+/// Panics if called from inside a basic (single threaded) scheduler since it can't block. The
+/// CLI's own runtime (`tokio_runtime`) is threaded.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => {
+            tokio::task::block_in_place(|| handle.enter(|| futures_executor::block_on(future)))
+        }
+        Err(_) => tokio_runtime().block_on(future),
+    }
+}
+/// Parses an HCI adapter id. Takes either the index (`1`) or the Linux style name (`hci1`).
+pub fn parse_adapter_id(id: &str) -> Option<usize> {
+    id.strip_prefix("hci").unwrap_or(id).parse().ok()
+}
+pub fn is_adapter_id_validator(input: String) -> Result<(), String> {
+    match parse_adapter_id(&input) {
+        Some(_) => Ok(()),
+        None => Err(format!(
+            "'{}' is not an HCI adapter id (expected `hciN` or `N`)",
+            &input
+        )),
+    }
+}
+fn usb_error<E: std::fmt::Debug>(id: &str, e: E) -> CLIError {
+    CLIError::OtherMessage(format!("HCI adapter `{}` error: {:?}", id, e))
+}
+/// Error for an adapter `id` that isn't one of the `count` adapters found. Lists the ones that
+/// are there.
+fn adapter_not_found(id: &str, count: usize) -> CLIError {
+    if count == 0 {
+        return CLIError::OtherMessage(format!(
+            "HCI adapter `{}` not found (no Bluetooth adapters detected)",
+            id
+        ));
+    }
+    let available = (0..count)
+        .map(|i| format!("hci{}", i))
+        .collect::<Vec<_>>()
+        .join(", ");
+    CLIError::OtherMessage(format!(
+        "HCI adapter `{}` not found. Available adapters: {}",
+        id, available
+    ))
+}
+/// Opens the HCI adapter named by `id` (see `parse_adapter_id`). `hciN` is the Nth Bluetooth
+/// adapter found. Unlike `hci_adapter`, this never falls back to another adapter.
+pub fn hci_adapter_by_id(id: &str) -> Result<(impl btle::hci::adapter::Adapter, String), CLIError> {
+    let index = parse_adapter_id(id)
+        .ok_or_else(|| CLIError::OtherMessage(format!("invalid HCI adapter id `{}`", id)))?;
+    let manager = btle::hci::usb::manager::Manager::new().map_err(|e| usb_error(id, e))?;
+    let devices = manager.devices().map_err(|e| usb_error(id, e))?;
+    let mut adapters = devices.bluetooth_adapters().collect::<Vec<_>>();
+    if index >= adapters.len() {
+        return Err(adapter_not_found(id, adapters.len()));
+    }
+    let adapter = adapters
+        .swap_remove(index)
+        .map_err(|e| usb_error(id, e))?
+        .open()
+        .map_err(|e| usb_error(id, e))?;
+    Ok((adapter, format!("usb adapter `{}`", id)))
+}
 pub fn hci_adapter() -> (impl btle::hci::adapter::Adapter, &'static str) {
     // TODO: Add Error handling and more adapters
     // This was initially men't just to make prototyping faster but needs must improvement
@@ -146,3 +338,95 @@ pub fn hci_adapter() -> (impl btle::hci::adapter::Adapter, &'static str) {
         "usb",
     )
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_adapter_id() {
+        assert_eq!(parse_adapter_id("hci1"), Some(1));
+        assert_eq!(parse_adapter_id("0"), Some(0));
+        assert_eq!(parse_adapter_id("hci"), None);
+        assert_eq!(parse_adapter_id("hcihci1"), None);
+        assert_eq!(parse_adapter_id("usb0"), None);
+        assert_eq!(parse_adapter_id("-1"), None);
+        assert!(is_adapter_id_validator("hci12".to_owned()).is_ok());
+        assert!(is_adapter_id_validator("hcihci1".to_owned()).is_err());
+    }
+    #[test]
+    fn test_block_on_in_cli_runtime() {
+        let mut runtime = tokio_runtime();
+        let answer = runtime.block_on(async {
+            tokio::spawn(async {
+                block_on(async {
+                    // Timers still need the runtime that's blocked on.
+                    tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+                    42
+                })
+            })
+            .await
+            .expect("task finished")
+        });
+        assert_eq!(answer, 42);
+        // Without a runtime it makes its own.
+        assert_eq!(block_on(async { 42 }), 42);
+    }
+    #[cfg(feature = "mesh")]
+    #[test]
+    fn test_replay_cache_file() {
+        use bluetooth_mesh::address::UnicastAddress;
+        use bluetooth_mesh::mesh::{IVIndex, SequenceNumber, U24};
+        let path =
+            std::env::temp_dir().join(format!("mesh_cli_cache_{}.replay", std::process::id()));
+        let path = path.to_str().expect("utf-8 temp dir");
+        let mut runtime = tokio_runtime();
+        // Nothing saved yet.
+        assert_eq!(
+            runtime
+                .block_on(load_replay_cache(path))
+                .expect("missing file is fine"),
+            replay::Cache::default()
+        );
+        let cache = replay::Cache::with_entries(vec![
+            (
+                UnicastAddress::new(0x0002),
+                IVIndex(1),
+                SequenceNumber(U24::new(10)),
+                None,
+            ),
+            (
+                UnicastAddress::new(0x0100),
+                IVIndex(1),
+                SequenceNumber(U24::new(0x1234)),
+                None,
+            ),
+        ]);
+        runtime
+            .block_on(write_replay_cache(path, &cache))
+            .expect("cache written");
+        assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+        let loaded = runtime.block_on(load_replay_cache(path));
+        std::fs::remove_file(path).expect("cache file exists");
+        assert_eq!(loaded.expect("cache loads"), cache);
+    }
+    #[cfg(feature = "mesh")]
+    #[test]
+    fn test_write_device_state() {
+        use bluetooth_mesh::address::UnicastAddress;
+        use bluetooth_mesh::mesh::ElementCount;
+        let path = std::env::temp_dir().join(format!("mesh_cli_state_{}.json", std::process::id()));
+        let path = path.to_str().expect("utf-8 temp dir");
+        // Overwrites (instead of appending to) what was there and leaves no temporary file.
+        std::fs::write(path, vec![b' '; 4096]).expect("temp dir is writeable");
+        let device_state =
+            device_state::DeviceState::new(UnicastAddress::new(0x0001), ElementCount(2));
+        write_device_state(path, &device_state).expect("device state written");
+        assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+        let loaded = load_device_state(path).expect("device state loads");
+        std::fs::remove_file(path).expect("device state file exists");
+        assert_eq!(
+            serde_json::to_value(&loaded).ok(),
+            serde_json::to_value(&device_state).ok()
+        );
+    }
+}
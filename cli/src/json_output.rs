@@ -0,0 +1,343 @@
+//! `--json-output` mode. Every decoded event is written to stdout as a single JSON object per
+//! line so other tools can consume the stream without parsing the human readable logs (those go
+//! to stderr instead).
+//!
+//! Every line has a `timestamp_ms` (milliseconds since the Unix epoch) and an `event` tag. The
+//! remaining fields depend on the `event`. Addresses, indices and sequence numbers are plain
+//! numbers and raw bytes are lowercase hex strings. Fields are only ever added, never renamed or
+//! removed.
+use crate::helper::HexSlice;
+use crate::CLIError;
+use bluetooth_mesh::access::Opcode;
+use bluetooth_mesh::beacon::BeaconPDU;
+use bluetooth_mesh::lower;
+use bluetooth_mesh::provisioning::{bearer_control, generic, pb_adv, protocol};
+use bluetooth_mesh::stack::bearer::IncomingBeacon;
+use bluetooth_mesh::stack::messages::{IncomingMessage, IncomingNetworkPDU};
+use serde::Serialize;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    UnprovisionedBeacon {
+        uuid: String,
+        oob_information: u16,
+        uri_hash: Option<u32>,
+    },
+    SecureNetworkBeacon {
+        network_id: String,
+        iv_index: u32,
+        key_refresh: bool,
+        iv_update: bool,
+    },
+    /// A decrypted Network PDU (see `FullStack::monitor`).
+    NetworkPdu {
+        net_key_index: u16,
+        iv_index: u32,
+        ctl: bool,
+        ttl: u8,
+        seq: u32,
+        src: u16,
+        dst: u16,
+        /// `unsegmented_access`, `segmented_access`, `unsegmented_control` or
+        /// `segmented_control`.
+        lower_pdu: &'static str,
+    },
+    /// A decrypted Access message for this node.
+    AccessMessage {
+        net_key_index: u16,
+        /// `None` if it was encrypted with a DevKey.
+        app_key_index: Option<u16>,
+        iv_index: u32,
+        ttl: Option<u8>,
+        seq: u32,
+        src: u16,
+        dst: u16,
+        /// `None` if the payload doesn't start with a valid opcode.
+        opcode: Option<String>,
+        parameters: String,
+    },
+    /// A PB-ADV PDU on any provisioning link.
+    Provisioning {
+        link_id: u32,
+        transaction_number: u8,
+        /// `transaction_start`, `transaction_continuation`, `transaction_ack`, `link_open`,
+        /// `link_ack` or `link_close`.
+        generic_pdu: &'static str,
+        /// The Provisioning PDU (`invite`, `capabilities`, ...) if it fits in the Transaction
+        /// Start.
+        provisioning_pdu: Option<&'static str>,
+        /// Set for Provisioning Failed PDUs.
+        error_code: Option<u8>,
+        reason: Option<String>,
+    },
+}
+impl Event {
+    pub fn from_beacon(beacon: &IncomingBeacon) -> Event {
+        match &beacon.beacon {
+            BeaconPDU::Unprovisioned(b) => Event::UnprovisionedBeacon {
+                uuid: b.uuid.to_string(),
+                oob_information: b.oob_information.0,
+                uri_hash: b.uri_hash.map(|hash| hash.0),
+            },
+            BeaconPDU::SecureNetwork(b) => {
+                let flags = u8::from(b.flags);
+                Event::SecureNetworkBeacon {
+                    network_id: format!("{:016x}", b.network_id.0),
+                    iv_index: b.iv_index.0,
+                    key_refresh: flags & 0x01 != 0,
+                    iv_update: flags & 0x02 != 0,
+                }
+            }
+        }
+    }
+    pub fn from_network_pdu(pdu: &IncomingNetworkPDU) -> Event {
+        let header = &pdu.pdu.header;
+        Event::NetworkPdu {
+            net_key_index: u16::from(pdu.net_key_index.0),
+            iv_index: pdu.iv_index.0,
+            ctl: header.ctl.0,
+            ttl: u8::from(header.ttl),
+            seq: header.seq.0.value(),
+            src: u16::from(header.src),
+            dst: u16::from(&header.dst),
+            lower_pdu: match &pdu.pdu.payload {
+                lower::PDU::UnsegmentedAccess(_) => "unsegmented_access",
+                lower::PDU::SegmentedAccess(_) => "segmented_access",
+                lower::PDU::UnsegmentedControl(_) => "unsegmented_control",
+                lower::PDU::SegmentedControl(_) => "segmented_control",
+            },
+        }
+    }
+    pub fn from_access_message<Storage: AsRef<[u8]>>(msg: &IncomingMessage<Storage>) -> Event {
+        let payload = msg.payload.as_ref();
+        let opcode_len = Opcode::unpack_from(&payload[..payload.len().min(Opcode::max_byte_len())])
+            .map(|opcode| opcode.byte_len())
+            .ok();
+        let (opcode, parameters) = payload.split_at(opcode_len.unwrap_or(0));
+        Event::AccessMessage {
+            net_key_index: u16::from(msg.net_key_index.0),
+            app_key_index: msg.app_key_index.map(|index| u16::from(index.0)),
+            iv_index: msg.iv_index.0,
+            ttl: msg.ttl.map(u8::from),
+            seq: msg.seq.0.value(),
+            src: u16::from(msg.src),
+            dst: u16::from(&msg.dst),
+            opcode: opcode_len.map(|_| hex(opcode)),
+            parameters: hex(parameters),
+        }
+    }
+    pub fn from_pb_adv(incoming: &pb_adv::IncomingPDU) -> Event {
+        let generic_pdu = &incoming.pdu.generic_pdu;
+        let protocol_pdu = match (&generic_pdu.control, generic_pdu.payload.as_ref()) {
+            (generic::Control::TransactionStart(_), Some(payload)) => {
+                protocol::PDU::unpack_from(payload.as_ref()).ok()
+            }
+            _ => None,
+        };
+        let failed = match &protocol_pdu {
+            Some(protocol::PDU::Failed(failed)) => Some(failed.0),
+            _ => None,
+        };
+        Event::Provisioning {
+            link_id: incoming.pdu.link_id.value(),
+            transaction_number: incoming.pdu.transaction_number.value(),
+            generic_pdu: match &generic_pdu.control {
+                generic::Control::TransactionStart(_) => "transaction_start",
+                generic::Control::TransactionContinuation(_) => "transaction_continuation",
+                generic::Control::TransactionAcknowledgement(_) => "transaction_ack",
+                generic::Control::BearerControl(bearer_control::PDU::LinkOpen(_)) => "link_open",
+                generic::Control::BearerControl(bearer_control::PDU::LinkAck(_)) => "link_ack",
+                generic::Control::BearerControl(bearer_control::PDU::LinkClose(_)) => "link_close",
+            },
+            provisioning_pdu: protocol_pdu.as_ref().map(protocol_pdu_name),
+            error_code: failed.map(u8::from),
+            reason: failed.map(|code| code.to_string()),
+        }
+    }
+}
+fn protocol_pdu_name(pdu: &protocol::PDU) -> &'static str {
+    match pdu {
+        protocol::PDU::Invite(_) => "invite",
+        protocol::PDU::Capabilities(_) => "capabilities",
+        protocol::PDU::Start(_) => "start",
+        protocol::PDU::PublicKey(_) => "public_key",
+        protocol::PDU::InputComplete(_) => "input_complete",
+        protocol::PDU::Confirm(_) => "confirm",
+        protocol::PDU::Random(_) => "random",
+        protocol::PDU::Data(_) => "data",
+        protocol::PDU::Complete(_) => "complete",
+        protocol::PDU::Failed(_) => "failed",
+    }
+}
+fn hex(bytes: &[u8]) -> String {
+    format!("{:x}", HexSlice(bytes))
+}
+#[derive(Serialize)]
+struct Line<'a> {
+    timestamp_ms: u64,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+/// Writes `event` to stdout as one JSON line.
+pub fn emit(event: &Event) -> Result<(), CLIError> {
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0);
+    let mut line = serde_json::to_vec(&Line {
+        timestamp_ms,
+        event,
+    })
+    .map_err(CLIError::SerdeJSON)?;
+    line.push(b'\n');
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    stdout
+        .write_all(&line)
+        .and_then(|_| stdout.flush())
+        .map_err(|e| CLIError::IOError("stdout".to_owned(), e))
+}
+/// Prints a human readable status line. To stderr in `--json-output` mode so stdout only has
+/// JSON lines.
+pub fn print_status(json_output: bool, status: std::fmt::Arguments) {
+    if json_output {
+        eprintln!("{}", status);
+    } else {
+        println!("{}", status);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Other tools parse these lines so the exact output is pinned for every variant.
+    fn assert_json(event: Event, expected: &str) {
+        assert_eq!(
+            serde_json::to_string(&event).expect("event serializes"),
+            expected
+        );
+    }
+    #[test]
+    fn test_unprovisioned_beacon() {
+        assert_json(
+            Event::UnprovisionedBeacon {
+                uuid: "70cf7c97-32a3-45b6-9149-4810d2e9cbf4".to_owned(),
+                oob_information: 0x0002,
+                uri_hash: Some(0xD97478B3),
+            },
+            r#"{"event":"unprovisioned_beacon","uuid":"70cf7c97-32a3-45b6-9149-4810d2e9cbf4","oob_information":2,"uri_hash":3648288947}"#,
+        );
+        assert_json(
+            Event::UnprovisionedBeacon {
+                uuid: "70cf7c97-32a3-45b6-9149-4810d2e9cbf4".to_owned(),
+                oob_information: 0,
+                uri_hash: None,
+            },
+            r#"{"event":"unprovisioned_beacon","uuid":"70cf7c97-32a3-45b6-9149-4810d2e9cbf4","oob_information":0,"uri_hash":null}"#,
+        );
+    }
+    #[test]
+    fn test_secure_network_beacon() {
+        assert_json(
+            Event::SecureNetworkBeacon {
+                network_id: "3ecaff672f673370".to_owned(),
+                iv_index: 0x12345678,
+                key_refresh: false,
+                iv_update: true,
+            },
+            r#"{"event":"secure_network_beacon","network_id":"3ecaff672f673370","iv_index":305419896,"key_refresh":false,"iv_update":true}"#,
+        );
+    }
+    #[test]
+    fn test_network_pdu() {
+        assert_json(
+            Event::NetworkPdu {
+                net_key_index: 0,
+                iv_index: 0x12345678,
+                ctl: false,
+                ttl: 4,
+                seq: 0x3129AB,
+                src: 0x0003,
+                dst: 0x1201,
+                lower_pdu: "unsegmented_access",
+            },
+            r#"{"event":"network_pdu","net_key_index":0,"iv_index":305419896,"ctl":false,"ttl":4,"seq":3221931,"src":3,"dst":4609,"lower_pdu":"unsegmented_access"}"#,
+        );
+    }
+    #[test]
+    fn test_access_message() {
+        assert_json(
+            Event::AccessMessage {
+                net_key_index: 0,
+                app_key_index: Some(1),
+                iv_index: 0x12345678,
+                ttl: Some(3),
+                seq: 7,
+                src: 0x1201,
+                dst: 0xC105,
+                opcode: Some("8202".to_owned()),
+                parameters: "0100".to_owned(),
+            },
+            r#"{"event":"access_message","net_key_index":0,"app_key_index":1,"iv_index":305419896,"ttl":3,"seq":7,"src":4609,"dst":49413,"opcode":"8202","parameters":"0100"}"#,
+        );
+        assert_json(
+            Event::AccessMessage {
+                net_key_index: 0,
+                app_key_index: None,
+                iv_index: 0,
+                ttl: None,
+                seq: 0,
+                src: 0x0001,
+                dst: 0x0002,
+                opcode: None,
+                parameters: "ff".to_owned(),
+            },
+            r#"{"event":"access_message","net_key_index":0,"app_key_index":null,"iv_index":0,"ttl":null,"seq":0,"src":1,"dst":2,"opcode":null,"parameters":"ff"}"#,
+        );
+    }
+    #[test]
+    fn test_provisioning() {
+        assert_json(
+            Event::Provisioning {
+                link_id: 0x12345678,
+                transaction_number: 0x80,
+                generic_pdu: "transaction_start",
+                provisioning_pdu: Some("failed"),
+                error_code: Some(0x05),
+                reason: Some("Confirmation Failed".to_owned()),
+            },
+            r#"{"event":"provisioning","link_id":305419896,"transaction_number":128,"generic_pdu":"transaction_start","provisioning_pdu":"failed","error_code":5,"reason":"Confirmation Failed"}"#,
+        );
+        assert_json(
+            Event::Provisioning {
+                link_id: 1,
+                transaction_number: 0,
+                generic_pdu: "link_ack",
+                provisioning_pdu: None,
+                error_code: None,
+                reason: None,
+            },
+            r#"{"event":"provisioning","link_id":1,"transaction_number":0,"generic_pdu":"link_ack","provisioning_pdu":null,"error_code":null,"reason":null}"#,
+        );
+    }
+    #[test]
+    fn test_line() {
+        let event = Event::SecureNetworkBeacon {
+            network_id: "3ecaff672f673370".to_owned(),
+            iv_index: 1,
+            key_refresh: true,
+            iv_update: false,
+        };
+        assert_eq!(
+            serde_json::to_string(&Line {
+                timestamp_ms: 1_580_000_000_000,
+                event: &event,
+            })
+            .expect("line serializes"),
+            r#"{"timestamp_ms":1580000000000,"event":"secure_network_beacon","network_id":"3ecaff672f673370","iv_index":1,"key_refresh":true,"iv_update":false}"#
+        );
+    }
+}
@@ -6,12 +6,18 @@ use std::convert::TryFrom;
 
 pub mod commands;
 pub mod helper;
+#[cfg(feature = "mesh")]
+pub mod json_output;
+#[cfg(feature = "mesh")]
+pub mod state_file;
 #[derive(Debug)]
 pub enum CLIError {
     PermissionDenied,
     IOError(String, std::io::Error),
     Clap(clap::Error),
     SerdeJSON(serde_json::Error),
+    /// The device state file couldn't be decrypted (wrong password or corrupted).
+    BadStatePassword,
     OtherMessage(String),
     Other(Box<dyn std::error::Error>),
 }
@@ -21,6 +27,9 @@ fn add_mesh_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
     app.subcommand(commands::state::sub_command())
         .subcommand(commands::provisioner::sub_command())
         .subcommand(commands::crypto::sub_command())
+        .subcommand(commands::send::sub_command())
+        .subcommand(commands::oob::generate_sub_command())
+        .subcommand(commands::oob::decode_sub_command())
 }
 #[cfg(not(feature = "mesh"))]
 fn add_mesh_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
@@ -42,7 +51,7 @@ fn main() {
                     .long("verbose")
                     .multiple(true)
                     .max_values(5)
-                    .help("Set the amount of logging from level 0 up to level 5"),
+                    .help("Increase logging verbosity (-v info, -vv debug, -vvv trace)"),
             )
             .arg(
                 clap::Arg::with_name("device_state")
@@ -50,24 +59,47 @@ fn main() {
                     .long("device_state")
                     .value_name("FILE")
                     .help("Specifies device state .json file"),
+            )
+            .arg(
+                clap::Arg::with_name("adapter")
+                    .long("adapter")
+                    .value_name("ADAPTER_ID")
+                    .global(true)
+                    .help("HCI adapter to use (`hci0`, `hci1`, ...). Auto-selects if not given")
+                    .validator(helper::is_adapter_id_validator),
+            )
+            .arg(
+                clap::Arg::with_name("json_output")
+                    .long("json-output")
+                    .help("Emit decoded events as JSON lines on stdout (logs go to stderr)"),
             ),
     );
 
     let matches = app.get_matches();
 
-    let _log_level = slog::Level::from_usize(
-        1 + usize::try_from(matches.occurrences_of("verbose"))
-            .expect("verbose usize overflow (how??)"),
+    // Warnings and errors by default. Every `-v` lets one more level through, up to trace.
+    let log_level = slog::Level::from_usize(
+        (slog::Level::Warning.as_usize()
+            + usize::try_from(matches.occurrences_of("verbose"))
+                .expect("verbose usize overflow (how??)"))
+        .min(slog::Level::Trace.as_usize()),
     )
-    .expect("verbose limit set too low");
-    let drain = slog_term::PlainSyncDecorator::new(std::io::stdout());
-    let root = slog::Logger::root(slog_term::FullFormat::new(drain).build().fuse(), slog::o!());
-    /*
-    let root = slog::LevelFilter::new(
-        slog::Logger::root(slog_term::FullFormat::new(drain).build().fuse(), slog::o!()),
-        log_level,
-    );
-    */
+    .expect("log level clamped to trace");
+    let json_output = matches.is_present("json_output");
+    // Keep stdout clean for the JSON lines.
+    let root = if json_output {
+        let drain = slog_term::PlainSyncDecorator::new(std::io::stderr());
+        slog::Logger::root(
+            slog::LevelFilter::new(slog_term::FullFormat::new(drain).build(), log_level).fuse(),
+            slog::o!(),
+        )
+    } else {
+        let drain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+        slog::Logger::root(
+            slog::LevelFilter::new(slog_term::FullFormat::new(drain).build(), log_level).fuse(),
+            slog::o!(),
+        )
+    };
     trace!(root, "main");
     let sub_cmd = matches.subcommand().0;
     #[cfg(feature = "mesh")]
@@ -97,8 +129,21 @@ fn main() {
             ("provisioner", Some(prov_matches)) => commands::provisioner::provisioner_matches(
                 &root,
                 get_device_state_path(),
+                json_output,
                 prov_matches,
             )?,
+            #[cfg(feature = "mesh")]
+            ("send", Some(send_matches)) => {
+                commands::send::send_matches(&root, get_device_state_path(), send_matches)?
+            }
+            #[cfg(feature = "mesh")]
+            ("generate", Some(generate_matches)) => {
+                commands::oob::generate_matches(&root, generate_matches)?
+            }
+            #[cfg(feature = "mesh")]
+            ("decode", Some(decode_matches)) => {
+                commands::oob::decode_matches(&root, decode_matches)?
+            }
             ("ble", Some(ble_matches)) => commands::ble::ble_matches(&root, ble_matches)?,
             _ => unreachable!("unhandled sub_command"),
         }
@@ -111,6 +156,9 @@ fn main() {
             }
             CLIError::Clap(error) => eprintln!("{}", &error.message),
             CLIError::SerdeJSON(error) => eprintln!("json error {}", error),
+            CLIError::BadStatePassword => {
+                eprintln!("can't decrypt the device state file (wrong password or corrupted file)")
+            }
             CLIError::PermissionDenied => {
                 eprintln!("permission denied error! (are you running as sudo/admin)?")
             }
@@ -0,0 +1,268 @@
+//! Versioned device state file. The `DeviceState` is saved inside an envelope
+//! (`{"version": N, "state": {...}}`) so files written by older versions of the CLI can be
+//! migrated when the `DeviceState` changes shape instead of failing to deserialize.
+//!
+//! To change the format, bump `DEVICE_STATE_VERSION` and append a function to `MIGRATIONS`
+//! turning the previous version's `state` JSON into the new one.
+//!
+//! With a password in `MESH_STATE_PASSWORD` (not a command line argument, which other users can
+//! see) the JSON is encrypted with AES-256-GCM under a key derived with PBKDF2-HMAC-SHA256.
+//! Encrypted files start with `ENCRYPTED_MAGIC` followed by the salt, the nonce and the
+//! ciphertext (with its tag). Plaintext files are still loaded so encryption can be turned on for
+//! an existing file.
+use crate::CLIError;
+use bluetooth_mesh::device_state::DeviceState;
+use ring::rand::SecureRandom;
+use ring::{aead, pbkdf2, rand};
+use serde::Serialize;
+use serde_json::Value;
+use std::num::NonZeroU32;
+
+/// Version of the `state` this CLI reads and writes.
+pub const DEVICE_STATE_VERSION: u64 = 1;
+/// Oldest version that can still be migrated.
+pub const OLDEST_DEVICE_STATE_VERSION: u64 = 1;
+type Migration = fn(Value) -> Result<Value, CLIError>;
+/// `MIGRATIONS[i]` migrates a `state` from version `OLDEST_DEVICE_STATE_VERSION + i` to the next
+/// version.
+const MIGRATIONS: &[Migration] = &[];
+// Doesn't compile unless there's a migration for every version before `DEVICE_STATE_VERSION`.
+const _: [(); (DEVICE_STATE_VERSION - OLDEST_DEVICE_STATE_VERSION) as usize] =
+    [(); MIGRATIONS.len()];
+
+/// Environment variable the device state password is read from.
+pub const STATE_PASSWORD_ENV: &str = "MESH_STATE_PASSWORD";
+/// First bytes of an encrypted device state file. Plaintext files start with `{`.
+pub const ENCRYPTED_MAGIC: &[u8; 8] = b"BTMESHE1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    version: u64,
+    state: &'a DeviceState,
+}
+/// Error for a device state file this CLI can't read because of its version.
+pub fn version_error(version: u64) -> CLIError {
+    CLIError::OtherMessage(if version < OLDEST_DEVICE_STATE_VERSION {
+        format!(
+            "device state file version {} is too old (oldest supported is {})",
+            version, OLDEST_DEVICE_STATE_VERSION
+        )
+    } else {
+        format!(
+            "device state file version {} is too new (newest supported is {})",
+            version, DEVICE_STATE_VERSION
+        )
+    })
+}
+/// Parses a device state file, migrating it to `DEVICE_STATE_VERSION` if needed. Files from
+/// before the envelope existed (a bare `DeviceState`) are version 1.
+pub fn from_value(value: Value) -> Result<DeviceState, CLIError> {
+    let (version, mut state) = match value {
+        Value::Object(mut object) if object.contains_key("version") => {
+            let version = object
+                .get("version")
+                .and_then(Value::as_u64)
+                .ok_or_else(|| {
+                    CLIError::OtherMessage("device state file version isn't a number".to_owned())
+                })?;
+            let state = object.remove("state").ok_or_else(|| {
+                CLIError::OtherMessage("device state file is missing 'state'".to_owned())
+            })?;
+            (version, state)
+        }
+        bare => (1, bare),
+    };
+    if version < OLDEST_DEVICE_STATE_VERSION || version > DEVICE_STATE_VERSION {
+        return Err(version_error(version));
+    }
+    for migration in &MIGRATIONS[(version - OLDEST_DEVICE_STATE_VERSION) as usize..] {
+        state = migration(state)?;
+    }
+    serde_json::from_value(state).map_err(CLIError::SerdeJSON)
+}
+/// Returns the device state password from `STATE_PASSWORD_ENV` (unset or empty for none).
+pub fn state_password() -> Option<String> {
+    std::env::var(STATE_PASSWORD_ENV)
+        .ok()
+        .filter(|password| !password.is_empty())
+}
+fn aead_key(password: &str, salt: &[u8]) -> aead::LessSafeKey {
+    let mut key = [0_u8; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).expect("nonzero iterations"),
+        salt,
+        password.as_bytes(),
+        &mut key,
+    );
+    aead::LessSafeKey::new(
+        aead::UnboundKey::new(&aead::AES_256_GCM, &key).expect("correct AES-256 key length"),
+    )
+}
+/// Parses the contents of a device state file. Encrypted files need `password`
+/// (`CLIError::BadStatePassword` if it's wrong).
+pub fn decode(mut bytes: Vec<u8>, password: Option<&str>) -> Result<DeviceState, CLIError> {
+    if !bytes.starts_with(ENCRYPTED_MAGIC) {
+        return from_value(serde_json::from_slice(&bytes).map_err(CLIError::SerdeJSON)?);
+    }
+    let password = password.ok_or_else(|| {
+        CLIError::OtherMessage(format!(
+            "device state file is encrypted. Set {} to its password",
+            STATE_PASSWORD_ENV
+        ))
+    })?;
+    let header_len = ENCRYPTED_MAGIC.len() + SALT_LEN + NONCE_LEN;
+    if bytes.len() < header_len + aead::AES_256_GCM.tag_len() {
+        return Err(CLIError::OtherMessage(
+            "encrypted device state file is truncated".to_owned(),
+        ));
+    }
+    let mut ciphertext = bytes.split_off(header_len);
+    let (aad, nonce) = bytes.split_at(ENCRYPTED_MAGIC.len() + SALT_LEN);
+    let plaintext = aead_key(password, &aad[ENCRYPTED_MAGIC.len()..])
+        .open_in_place(
+            aead::Nonce::try_assume_unique_for_key(nonce).expect("correct nonce length"),
+            aead::Aad::from(aad),
+            &mut ciphertext,
+        )
+        .map_err(|_| CLIError::BadStatePassword)?;
+    from_value(serde_json::from_slice(plaintext).map_err(CLIError::SerdeJSON)?)
+}
+/// Serializes `device_state` in a `DEVICE_STATE_VERSION` envelope. Encrypted if `password` is
+/// given (with a fresh salt and nonce every time).
+pub fn encode(device_state: &DeviceState, password: Option<&str>) -> Result<Vec<u8>, CLIError> {
+    let mut json = serde_json::to_vec_pretty(&Envelope {
+        version: DEVICE_STATE_VERSION,
+        state: device_state,
+    })
+    .map_err(CLIError::SerdeJSON)?;
+    let password = match password {
+        Some(password) => password,
+        None => return Ok(json),
+    };
+    let mut salt = [0_u8; SALT_LEN];
+    let mut nonce = [0_u8; NONCE_LEN];
+    let rng = rand::SystemRandom::new();
+    rng.fill(&mut salt)
+        .and_then(|()| rng.fill(&mut nonce))
+        .map_err(|_| CLIError::OtherMessage("can't generate random salt/nonce".to_owned()))?;
+    let mut out = Vec::with_capacity(
+        ENCRYPTED_MAGIC.len() + SALT_LEN + NONCE_LEN + json.len() + aead::AES_256_GCM.tag_len(),
+    );
+    out.extend_from_slice(ENCRYPTED_MAGIC);
+    out.extend_from_slice(&salt);
+    aead_key(password, &salt)
+        .seal_in_place_append_tag(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::from(&out[..]),
+            &mut json,
+        )
+        .map_err(|_| CLIError::OtherMessage("can't encrypt device state".to_owned()))?;
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&json);
+    Ok(out)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bluetooth_mesh::address::UnicastAddress;
+    use bluetooth_mesh::mesh::ElementCount;
+
+    fn device_state() -> DeviceState {
+        DeviceState::new(UnicastAddress::new(0x0001), ElementCount(2))
+    }
+    fn same_state(decoded: Result<DeviceState, CLIError>) -> bool {
+        let decoded = decoded.expect("device state decodes");
+        serde_json::to_value(&decoded).ok() == serde_json::to_value(&device_state()).ok()
+    }
+    fn message(result: Result<DeviceState, CLIError>) -> String {
+        match result {
+            Err(CLIError::OtherMessage(msg)) => msg,
+            other => panic!("expected an error message, got {:?}", other.map(|_| ())),
+        }
+    }
+    #[test]
+    fn test_versions() {
+        let state = serde_json::to_value(&device_state()).expect("device state serializes");
+        assert!(same_state(from_value(serde_json::json!({
+            "version": DEVICE_STATE_VERSION,
+            "state": state.clone(),
+        }))));
+        // Files from before the envelope are version 1.
+        assert!(same_state(from_value(state.clone())));
+        assert!(message(from_value(serde_json::json!({
+            "version": DEVICE_STATE_VERSION + 1,
+            "state": state.clone(),
+        })))
+        .contains("too new"));
+        assert!(message(from_value(serde_json::json!({
+            "version": OLDEST_DEVICE_STATE_VERSION - 1,
+            "state": state,
+        })))
+        .contains("too old"));
+    }
+    #[test]
+    fn test_bad_envelope() {
+        assert!(message(from_value(
+            serde_json::json!({ "version": DEVICE_STATE_VERSION })
+        ))
+        .contains("missing 'state'"));
+        assert!(message(from_value(
+            serde_json::json!({ "version": "1", "state": {} })
+        ))
+        .contains("isn't a number"));
+    }
+    #[test]
+    fn test_encrypted_round_trip() {
+        let encrypted = encode(&device_state(), Some("hunter2")).expect("encrypted");
+        assert!(encrypted.starts_with(ENCRYPTED_MAGIC));
+        // None of the JSON is readable.
+        assert!(!encrypted.windows(7).any(|window| window == b"\"state\""));
+        assert!(same_state(decode(encrypted.clone(), Some("hunter2"))));
+        // Every save uses a fresh salt and nonce.
+        assert_ne!(
+            encode(&device_state(), Some("hunter2")).expect("encrypted"),
+            encrypted
+        );
+    }
+    #[test]
+    fn test_encrypted_bad_password() {
+        let encrypted = encode(&device_state(), Some("hunter2")).expect("encrypted");
+        match decode(encrypted.clone(), Some("hunter3")) {
+            Err(CLIError::BadStatePassword) => (),
+            other => panic!("expected BadStatePassword, got {:?}", other.map(|_| ())),
+        }
+        match decode(encrypted, None) {
+            Err(CLIError::OtherMessage(msg)) => assert!(msg.contains(STATE_PASSWORD_ENV)),
+            other => panic!("expected a missing password, got {:?}", other.map(|_| ())),
+        }
+    }
+    #[test]
+    fn test_encrypted_truncated() {
+        let encrypted = encode(&device_state(), Some("hunter2")).expect("encrypted");
+        // Missing some of the ciphertext fails authentication.
+        match decode(encrypted[..encrypted.len() - 1].to_vec(), Some("hunter2")) {
+            Err(CLIError::BadStatePassword) => (),
+            other => panic!("expected BadStatePassword, got {:?}", other.map(|_| ())),
+        }
+        // Too short to even have the header and tag.
+        let short = ENCRYPTED_MAGIC.len() + SALT_LEN + NONCE_LEN + aead::AES_256_GCM.tag_len() - 1;
+        for len in &[ENCRYPTED_MAGIC.len(), short] {
+            match decode(encrypted[..*len].to_vec(), Some("hunter2")) {
+                Err(CLIError::OtherMessage(msg)) => assert!(msg.contains("truncated")),
+                other => panic!("expected a truncated file, got {:?}", other.map(|_| ())),
+            }
+        }
+    }
+    #[test]
+    fn test_plaintext_still_loads() {
+        let plaintext = encode(&device_state(), None).expect("encoded");
+        assert_eq!(plaintext.first(), Some(&b'{'));
+        assert!(same_state(decode(plaintext.clone(), None)));
+        // Turning encryption on for an existing file.
+        assert!(same_state(decode(plaintext, Some("hunter2"))));
+    }
+}
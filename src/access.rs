@@ -1,7 +1,18 @@
 //! Access Layer between Models and the rest of the stack (Transport, Network, etc). The most
 //! surface layer of the stack.
+//!
+//! An Access payload is an `Opcode` (1, 2 or 3 bytes) followed by the message parameters. See
+//! [`AccessPayload`] (or [`encode`] and [`decode`]) for packing them and encrypting them with an
+//! AppKey or DevKey.
 use crate::bytes::ToFromBytesEndian;
+use crate::crypto::aes::MicSize;
+use crate::crypto::MIC;
 use crate::mesh::{CompanyID, ModelID};
+use crate::upper::{
+    AppPayload, EncryptedAppPayload, SecurityMaterials, ENCRYPTED_APP_PAYLOAD_MAX_LEN,
+};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
 #[cfg_attr(feature = "serde-1", derive(serde::Serialize, serde::Deserialize))]
@@ -94,17 +105,31 @@ impl Opcode {
             let vendor_opcode = VendorOpcode::new(bytes[0] & !0xC0);
             let company_id = CompanyID(u16::from_le_bytes([bytes[1], bytes[2]]));
             Ok(Opcode::Vendor(vendor_opcode, company_id))
-        } else if bytes[0] & 0x80 == 1 {
+        } else if bytes[0] & 0xC0 == 0x80 {
             if bytes.len() < 2 {
                 return Err(OpcodeConversationError(()));
             }
-            Ok(Opcode::SIG(SigOpcode::DoubleOctet(u16::from_le_bytes([
+            Ok(Opcode::SIG(SigOpcode::DoubleOctet(u16::from_be_bytes([
                 bytes[0], bytes[1],
             ]))))
         } else {
             Err(OpcodeConversationError(()))
         }
     }
+    /// Splits an Access payload into its `Opcode` and the parameters that follow it.
+    pub fn split_from(payload: &[u8]) -> Result<(Self, &[u8]), OpcodeConversationError> {
+        let opcode_len = match payload.first() {
+            None => return Err(OpcodeConversationError(())),
+            Some(b) if b & 0x80 == 0 => 1,
+            Some(b) if b & 0xC0 == 0xC0 => 3,
+            Some(_) => 2,
+        };
+        if payload.len() < opcode_len {
+            return Err(OpcodeConversationError(()));
+        }
+        let (opcode, parameters) = payload.split_at(opcode_len);
+        Ok((Self::unpack_from(opcode)?, parameters))
+    }
     pub fn pack_into(&self, buffer: &mut [u8]) -> Result<(), OpcodeConversationError> {
         match *self {
             Opcode::SIG(s) => match s {
@@ -124,7 +149,7 @@ impl Opcode {
                         return Err(OpcodeConversationError(()));
                     }
                     if d & 0xC000 == 0x8000 {
-                        buffer[..2].copy_from_slice(&d.to_le_bytes()[..]);
+                        buffer[..2].copy_from_slice(&d.to_be_bytes()[..]);
                         Ok(())
                     } else {
                         Err(OpcodeConversationError(()))
@@ -211,13 +236,13 @@ impl ModelIdentifier {
                 buf[..CompanyID::byte_len()].copy_from_slice(&company_id.to_bytes_le());
                 &mut buf[CompanyID::byte_len()..]
             }
-        }[..VendorModelID::byte_len()])
+        }[..ModelID::byte_len()])
             .copy_from_slice(&self.model_id.to_bytes_le());
     }
     pub fn unpack_from(buf: &[u8]) -> Option<Self> {
         match buf.len() {
-            4 => Some(Self::new_sig(ModelID::from_bytes_le(buf)?)),
-            8 => Some(Self::new_vendor(
+            2 => Some(Self::new_sig(ModelID::from_bytes_le(buf)?)),
+            4 => Some(Self::new_vendor(
                 ModelID::from_bytes_le(&buf[2..4])?,
                 CompanyID::from_bytes_le(&buf[..2])?,
             )),
@@ -225,3 +250,215 @@ impl ModelIdentifier {
         }
     }
 }
+/// Max length of an Access payload (opcode and parameters). The encrypted payload and its 32-bit
+/// TransMIC have to fit in `ENCRYPTED_APP_PAYLOAD_MAX_LEN`.
+pub const ACCESS_PAYLOAD_MAX_LEN: usize = ENCRYPTED_APP_PAYLOAD_MAX_LEN - MIC::small_size();
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub enum AccessPayloadError {
+    /// The opcode is RFU, badly formatted or the payload is too short to hold it.
+    InvalidOpcode,
+    /// The payload is longer than `ACCESS_PAYLOAD_MAX_LEN`.
+    TooLong,
+    /// The TransMIC didn't match the key and nonce.
+    DecryptFailed,
+}
+impl From<OpcodeConversationError> for AccessPayloadError {
+    fn from(_: OpcodeConversationError) -> Self {
+        AccessPayloadError::InvalidOpcode
+    }
+}
+/// Packs `opcode` followed by `parameters` into an Access payload.
+pub fn encode(opcode: Opcode, parameters: &[u8]) -> Result<Box<[u8]>, AccessPayloadError> {
+    let opcode_len = opcode.byte_len();
+    if opcode_len + parameters.len() > ACCESS_PAYLOAD_MAX_LEN {
+        return Err(AccessPayloadError::TooLong);
+    }
+    let mut out = Vec::with_capacity(opcode_len + parameters.len());
+    out.resize(opcode_len, 0);
+    opcode.pack_into(&mut out[..])?;
+    out.extend_from_slice(parameters);
+    Ok(out.into_boxed_slice())
+}
+/// Splits an Access payload into its `Opcode` and parameters.
+pub fn decode(payload: &[u8]) -> Result<(Opcode, &[u8]), AccessPayloadError> {
+    if payload.len() > ACCESS_PAYLOAD_MAX_LEN {
+        return Err(AccessPayloadError::TooLong);
+    }
+    Ok(Opcode::split_from(payload)?)
+}
+/// Decrypted Access Layer message. It's what the Upper Transport Layer carries (encrypted with
+/// an AppKey or the DevKey) and what models send and receive.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub struct AccessPayload {
+    opcode: Opcode,
+    parameters: Box<[u8]>,
+}
+impl AccessPayload {
+    /// Returns `InvalidOpcode` if `opcode` can't be packed or `TooLong` if the payload wouldn't
+    /// fit in an Upper Transport PDU.
+    pub fn new(opcode: Opcode, parameters: &[u8]) -> Result<Self, AccessPayloadError> {
+        let mut buf = [0_u8; Opcode::max_byte_len()];
+        opcode.pack_into(&mut buf[..])?;
+        if opcode.byte_len() + parameters.len() > ACCESS_PAYLOAD_MAX_LEN {
+            return Err(AccessPayloadError::TooLong);
+        }
+        Ok(Self {
+            opcode,
+            parameters: parameters.into(),
+        })
+    }
+    pub fn opcode(&self) -> Opcode {
+        self.opcode
+    }
+    pub fn parameters(&self) -> &[u8] {
+        self.parameters.as_ref()
+    }
+    /// Length of the packed payload (opcode and parameters).
+    pub fn len(&self) -> usize {
+        self.opcode.byte_len() + self.parameters.len()
+    }
+    /// Always `false`. There's at least an opcode.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+    /// Packs the opcode followed by the parameters.
+    pub fn encode(&self) -> Box<[u8]> {
+        encode(self.opcode, self.parameters()).expect("checked by AccessPayload::new")
+    }
+    pub fn decode(payload: &[u8]) -> Result<Self, AccessPayloadError> {
+        let (opcode, parameters) = decode(payload)?;
+        Ok(Self {
+            opcode,
+            parameters: parameters.into(),
+        })
+    }
+    /// Encrypts the packed payload with `sm`. `SecurityMaterials::App` and
+    /// `SecurityMaterials::VirtualAddress` use the application nonce and an AppKey while
+    /// `SecurityMaterials::Device` uses the device nonce and the DevKey.
+    pub fn encrypt(
+        &self,
+        sm: &SecurityMaterials,
+        mic_size: MicSize,
+    ) -> EncryptedAppPayload<Box<[u8]>> {
+        AppPayload::new(self.encode()).encrypt(sm, mic_size)
+    }
+    /// Decrypts `encrypted` with `sm` (see [`AccessPayload::encrypt`]) and decodes it.
+    pub fn decrypt(
+        encrypted: EncryptedAppPayload<Box<[u8]>>,
+        sm: SecurityMaterials,
+    ) -> Result<Self, AccessPayloadError> {
+        let payload = encrypted
+            .decrypt(sm)
+            .map_err(|_| AccessPayloadError::DecryptFailed)?;
+        Self::decode(payload.payload())
+    }
+}
+impl From<&AccessPayload> for AppPayload<Box<[u8]>> {
+    fn from(payload: &AccessPayload) -> Self {
+        AppPayload::new(payload.encode())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::{Address, UnicastAddress};
+    use crate::crypto::key::{AppKey, DevKey};
+    use crate::crypto::nonce::{AppNonceParts, DeviceNonceParts};
+    use crate::mesh::{IVIndex, SequenceNumber, U24};
+    use crate::random::Randomizable;
+
+    #[test]
+    fn test_opcode_formats() {
+        let single = Opcode::SIG(SigOpcode::SingleOctet(0x04));
+        let double = Opcode::SIG(SigOpcode::DoubleOctet(0x8204));
+        let vendor = Opcode::Vendor(VendorOpcode::new(0x15), CompanyID(0x000A));
+        assert_eq!(&encode(single, &[0xAA]).unwrap()[..], &[0x04, 0xAA][..]);
+        assert_eq!(&encode(double, &[]).unwrap()[..], &[0x82, 0x04][..]);
+        assert_eq!(
+            &encode(vendor, &[0xAA, 0xBB]).unwrap()[..],
+            &[0xD5, 0x0A, 0x00, 0xAA, 0xBB][..]
+        );
+        for &opcode in &[single, double, vendor] {
+            let payload = AccessPayload::new(opcode, &[1, 2, 3]).unwrap();
+            assert_eq!(payload.len(), opcode.byte_len() + 3);
+            assert_eq!(AccessPayload::decode(&payload.encode()), Ok(payload));
+        }
+        assert_eq!(decode(&[0x82, 0x04, 0x01]), Ok((double, &[0x01_u8][..])));
+    }
+    #[test]
+    fn test_invalid_payloads() {
+        // RFU opcode.
+        assert_eq!(
+            decode(&[0x7F, 0x00]),
+            Err(AccessPayloadError::InvalidOpcode)
+        );
+        // Too short for the opcode.
+        assert_eq!(decode(&[]), Err(AccessPayloadError::InvalidOpcode));
+        assert_eq!(decode(&[0x82]), Err(AccessPayloadError::InvalidOpcode));
+        assert_eq!(
+            decode(&[0xC1, 0x00]),
+            Err(AccessPayloadError::InvalidOpcode)
+        );
+        assert_eq!(
+            AccessPayload::new(Opcode::SIG(SigOpcode::DoubleOctet(0x0204)), &[]),
+            Err(AccessPayloadError::InvalidOpcode)
+        );
+        let single = Opcode::SIG(SigOpcode::SingleOctet(0x04));
+        assert!(encode(single, &[0_u8; ACCESS_PAYLOAD_MAX_LEN - 1]).is_ok());
+        assert_eq!(
+            encode(single, &[0_u8; ACCESS_PAYLOAD_MAX_LEN]),
+            Err(AccessPayloadError::TooLong)
+        );
+    }
+    #[test]
+    fn test_encrypt_decrypt() {
+        let payload =
+            AccessPayload::new(Opcode::SIG(SigOpcode::DoubleOctet(0x8204)), &[0x01, 0x00]).unwrap();
+        let seq = SequenceNumber(U24::new(0x0100));
+        let src = UnicastAddress::new(0x0001);
+        let dst = Address::from(0x0002);
+        let iv_index = IVIndex(0);
+
+        let app_key = AppKey::random_secure();
+        let app_nonce = AppNonceParts {
+            aszmic: false,
+            seq,
+            src,
+            dst,
+            iv_index,
+        }
+        .to_nonce();
+        let app_sm = SecurityMaterials::App(app_nonce, &app_key, app_key.aid());
+        let encrypted = payload.encrypt(&app_sm, MicSize::Small);
+        assert_eq!(encrypted.aid(), Some(app_key.aid()));
+        assert_ne!(encrypted.data(), &payload.encode()[..]);
+        assert_eq!(
+            AccessPayload::decrypt(encrypted.clone(), app_sm),
+            Ok(payload.clone())
+        );
+        let other_key = AppKey::random_secure();
+        assert_eq!(
+            AccessPayload::decrypt(
+                encrypted,
+                SecurityMaterials::App(app_nonce, &other_key, other_key.aid())
+            ),
+            Err(AccessPayloadError::DecryptFailed)
+        );
+
+        let dev_key = DevKey::random_secure();
+        let device_sm = SecurityMaterials::Device(
+            DeviceNonceParts {
+                aszmic: true,
+                seq,
+                src,
+                dst,
+                iv_index,
+            }
+            .to_nonce(),
+            &dev_key,
+        );
+        let encrypted = payload.encrypt(&device_sm, MicSize::Big);
+        assert_eq!(encrypted.aid(), None);
+        assert_eq!(AccessPayload::decrypt(encrypted, device_sm), Ok(payload));
+    }
+}
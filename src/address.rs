@@ -64,7 +64,7 @@ impl GroupAddress {
     }
     /// Group address corresponding to all relay nodes.
     pub const fn all_relays() -> GroupAddress {
-        GroupAddress(0xFFE)
+        GroupAddress(0xFFFE)
     }
     /// Group address corresponding to all nodes.
     pub const fn all_nodes() -> GroupAddress {
@@ -238,6 +238,13 @@ impl Address {
             _ => true,
         }
     }
+    /// Returns if the address is the unassigned address (`0x0000`). The unassigned address is
+    /// never a valid source or destination for a message but it is used as a sentinel for "no
+    /// address" (Ex: publishing disabled).
+    #[must_use]
+    pub fn is_unassigned(&self) -> bool {
+        !self.is_assigned()
+    }
     #[must_use]
     pub fn is_unicast(&self) -> bool {
         match self {
@@ -479,3 +486,38 @@ impl ToFromBytesEndian for GroupAddress {
         u16::from_bytes_be(bytes)?.try_into().ok()
     }
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unassigned() {
+        assert!(Address::from(0x0000).is_unassigned());
+        assert!(Address::default().is_unassigned());
+        assert_eq!(Address::Unassigned.value(), 0x0000);
+        assert!(!Address::from(0x0001).is_unassigned());
+        assert!(!Address::from(0xC000).is_unassigned());
+        assert!(!Address::from(0x8001).is_unassigned());
+        assert!(UnicastAddress::try_from(0x0000).is_err());
+    }
+    #[test]
+    fn test_virtual_address() {
+        // Mesh Profile v1.0.1 Sample Data (8.3.22 and 8.3.23).
+        for &(label, hash) in &[
+            ("0073e7e4d8b9440faf8415df4c56c0e1", 0xB529),
+            ("f4a002c7fb1e4ca0a469a021de0db875", 0x9736),
+        ] {
+            let uuid = UUID(UUID::uuid_bytes_from_str(label).unwrap());
+            let address = VirtualAddress::new(&uuid);
+            assert_eq!(address.hash(), VirtualAddressHash::new(hash));
+            assert_eq!(address.uuid(), &uuid);
+            assert_eq!(u16::from(&Address::Virtual(address)), hash);
+            // Only the hash makes it over the air.
+            assert_eq!(
+                Address::from(hash).virtual_hash(),
+                Some(VirtualAddressHash::new(hash))
+            );
+            assert!(!Address::from(hash).is_full_virtual());
+        }
+    }
+}
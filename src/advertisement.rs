@@ -29,8 +29,10 @@ impl<InterSink: InterfaceSink> ScannerSink for ScannerInputSink<InterSink> {
                         encrypted_pdu: pdu,
                         rssi: advertisement.rssi(),
                         dont_relay: false,
+                        from_proxy: false,
                     };
-                    self.0.consume_pdu(&incoming);
+                    // Advertisements are best effort so PDUs the stack doesn't take are dropped.
+                    let _ = self.0.consume_pdu(&incoming);
                 }
             }
             _ => (),
@@ -1,8 +1,10 @@
 //! Bluetooth Mesh Beacon Layer. Currently only supports `SecureNetworkBeacon`s and
 //! `UnprovisionedDeviceBeacon`s.
 use crate::bytes::ToFromBytesEndian;
+use crate::crypto::aes::AESCipher;
+use crate::crypto::key::BeaconKey;
 use crate::crypto::{s1, NetworkID};
-use crate::mesh::IVIndex;
+use crate::mesh::{IVIndex, IVUpdateFlag, KeyRefreshFlag};
 use crate::uuid::UUID;
 use btle::{ConversionError, PackError};
 use core::convert::{TryFrom, TryInto};
@@ -142,6 +144,23 @@ impl From<SecureNetworkFlags> for u8 {
         f.0
     }
 }
+impl SecureNetworkFlags {
+    pub fn new(key_refresh: KeyRefreshFlag, iv_update: IVUpdateFlag) -> SecureNetworkFlags {
+        SecureNetworkFlags(
+            (u8::from(bool::from(key_refresh)) << SecureNetworkFlag::KeyRefresh as u8)
+                | (u8::from(bool::from(iv_update)) << SecureNetworkFlag::IVUpdate as u8),
+        )
+    }
+    pub fn get(self, flag: SecureNetworkFlag) -> bool {
+        self.0 & (1_u8 << flag as u8) != 0
+    }
+    pub fn key_refresh(self) -> KeyRefreshFlag {
+        KeyRefreshFlag(self.get(SecureNetworkFlag::KeyRefresh))
+    }
+    pub fn iv_update(self) -> IVUpdateFlag {
+        IVUpdateFlag(self.get(SecureNetworkFlag::IVUpdate))
+    }
+}
 impl TryFrom<u8> for SecureNetworkFlags {
     type Error = ConversionError;
 
@@ -162,6 +181,32 @@ pub const AUTHENTICATION_VALUE_LEN: usize = 8;
 pub struct AuthenticationValue(pub [u8; AUTHENTICATION_VALUE_LEN]);
 impl AuthenticationValue {
     pub const BYTE_LEN: usize = AUTHENTICATION_VALUE_LEN;
+    /// `AES-CMAC(BeaconKey, Flags || Network ID || IV Index)` truncated to 8 bytes.
+    pub fn calculate(
+        beacon_key: &BeaconKey,
+        flags: SecureNetworkFlags,
+        network_id: NetworkID,
+        iv_index: IVIndex,
+    ) -> AuthenticationValue {
+        let cmac = AESCipher::new(beacon_key.key()).cmac_slice(&[
+            &[flags.0],
+            network_id.0.to_be_bytes().as_ref(),
+            iv_index.to_bytes_be().as_ref(),
+        ]);
+        AuthenticationValue(
+            cmac.as_ref()[..AUTHENTICATION_VALUE_LEN]
+                .try_into()
+                .expect("cmac is 16 bytes"),
+        )
+    }
+}
+/// What an authenticated Secure Network Beacon tells us about its network.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct VerifiedBeacon {
+    pub network_id: NetworkID,
+    pub iv_index: IVIndex,
+    pub key_refresh: KeyRefreshFlag,
+    pub iv_update: IVUpdateFlag,
 }
 #[derive(Copy, Clone, Debug)]
 pub struct SecureNetworkBeacon {
@@ -173,6 +218,38 @@ pub struct SecureNetworkBeacon {
 impl SecureNetworkBeacon {
     pub const BYTE_LEN: usize =
         1 + NetworkID::BYTE_LEN + IVIndex::BYTE_LEN + AuthenticationValue::BYTE_LEN;
+    /// Creates a beacon authenticated with `beacon_key` (the network's `BeaconKey`).
+    pub fn new(
+        flags: SecureNetworkFlags,
+        network_id: NetworkID,
+        iv_index: IVIndex,
+        beacon_key: &BeaconKey,
+    ) -> SecureNetworkBeacon {
+        SecureNetworkBeacon {
+            flags,
+            network_id,
+            iv_index,
+            authentication_value: AuthenticationValue::calculate(
+                beacon_key, flags, network_id, iv_index,
+            ),
+        }
+    }
+    /// Checks the Authentication Value against `beacon_key`. Returns `None` if the beacon
+    /// wasn't sent with that key (or was tampered with).
+    pub fn verify(&self, beacon_key: &BeaconKey) -> Option<VerifiedBeacon> {
+        let expected =
+            AuthenticationValue::calculate(beacon_key, self.flags, self.network_id, self.iv_index);
+        if expected.0 == self.authentication_value.0 {
+            Some(VerifiedBeacon {
+                network_id: self.network_id,
+                iv_index: self.iv_index,
+                key_refresh: self.flags.key_refresh(),
+                iv_update: self.flags.iv_update(),
+            })
+        } else {
+            None
+        }
+    }
     pub fn unpack_from(buf: &[u8]) -> Result<SecureNetworkBeacon, PackError> {
         PackError::expect_length(Self::BYTE_LEN, buf)?;
         let flags = SecureNetworkFlags::try_from(buf[0]).map_err(|_| PackError::bad_index(0))?;
@@ -233,6 +310,13 @@ impl BeaconPDU {
         }
     }
 }
+/// Parses a Secure Network Beacon (without the beacon type octet) and authenticates it with
+/// `beacon_key`. Returns `None` for malformed or unauthenticated beacons.
+pub fn verify_secure_network_beacon(buf: &[u8], beacon_key: &BeaconKey) -> Option<VerifiedBeacon> {
+    SecureNetworkBeacon::unpack_from(buf)
+        .ok()?
+        .verify(beacon_key)
+}
 pub struct PackedBeacon {}
 impl AsRef<[u8]> for PackedBeacon {
     fn as_ref(&self) -> &[u8] {
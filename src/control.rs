@@ -1,8 +1,11 @@
 //! Bluetooth Mesh Control Layer.
 
+use crate::address::{Address, UnicastAddress};
 use crate::bytes::ToFromBytesEndian;
+use crate::foundation::Features;
 use crate::friend;
 use crate::lower::{BlockAck, SeqZero, UnsegmentedControlPDU, SEQ_ZERO_MAX};
+use crate::mesh::{IVIndex, IVUpdateFlag, KeyRefreshFlag, TTL, U24};
 use alloc::vec::Vec;
 use core::convert::{TryFrom, TryInto};
 
@@ -228,11 +231,11 @@ impl ControlMessage for Ack {
 
     fn unpack(buf: &[u8]) -> Result<Self, ControlMessageError> {
         if buf.len() == ACK_SIZE {
-            let seq = u16::from_bytes_le(&buf[..2]).expect("seq_zero is always here");
+            let seq = u16::from_bytes_be(&buf[..2]).expect("seq_zero is always here");
             let seq_zero = SeqZero::new((seq >> 2) & SEQ_ZERO_MAX);
             let obo = seq & 0x8000 != 0;
             let block_ack =
-                BlockAck(u32::from_bytes_le(&buf[2..6]).expect("block_ack is always here"));
+                BlockAck(u32::from_bytes_be(&buf[2..6]).expect("block_ack is always here"));
             Ok(Self {
                 obo,
                 seq_zero,
@@ -255,174 +258,423 @@ impl ControlMessage for Ack {
     }
 }
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
-pub struct FriendPoll(friend::FriendPoll);
+pub struct FriendPoll(pub friend::FriendPoll);
 impl ControlMessage for FriendPoll {
     const OPCODE: ControlOpcode = ControlOpcode::FriendPoll;
 
     fn byte_len(&self) -> usize {
-        unimplemented!()
+        1
     }
 
-    fn unpack(_buf: &[u8]) -> Result<Self, ControlMessageError> {
-        unimplemented!()
+    fn unpack(buf: &[u8]) -> Result<Self, ControlMessageError> {
+        match buf {
+            [b] if b & !0x01 == 0 => Ok(FriendPoll(friend::FriendPoll::new(friend::FSN::new(
+                b & 0x01 != 0,
+            )))),
+            [_] => Err(ControlMessageError::BadBytes),
+            _ => Err(ControlMessageError::BadLength),
+        }
     }
 
-    fn pack(&self, _buf: &mut [u8]) -> Result<(), ControlMessageError> {
-        unimplemented!()
+    fn pack(&self, buf: &mut [u8]) -> Result<(), ControlMessageError> {
+        if buf.is_empty() {
+            Err(ControlMessageError::BufferTooSmall)
+        } else {
+            buf[0] = u8::from(self.0.fsn().value());
+            Ok(())
+        }
     }
 }
+const FRIEND_UPDATE_SIZE: usize = 6;
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
-pub struct FriendUpdate {}
+pub struct FriendUpdate(pub friend::FriendUpdate);
 impl ControlMessage for FriendUpdate {
     const OPCODE: ControlOpcode = ControlOpcode::FriendUpdate;
 
     fn byte_len(&self) -> usize {
-        unimplemented!()
+        FRIEND_UPDATE_SIZE
     }
 
-    fn unpack(_buf: &[u8]) -> Result<Self, ControlMessageError> {
-        unimplemented!()
+    fn unpack(buf: &[u8]) -> Result<Self, ControlMessageError> {
+        if buf.len() != FRIEND_UPDATE_SIZE {
+            return Err(ControlMessageError::BadLength);
+        }
+        let md = match buf[5] {
+            0x00 => false,
+            0x01 => true,
+            _ => return Err(ControlMessageError::BadBytes),
+        };
+        Ok(FriendUpdate(friend::FriendUpdate {
+            key_refresh_flag: KeyRefreshFlag(buf[0] & 0x01 != 0),
+            iv_update_flag: IVUpdateFlag(buf[0] & 0x02 != 0),
+            iv_index: IVIndex::from_bytes_be(&buf[1..5]).expect("iv_index is always here"),
+            md: friend::MD::new(md),
+        }))
     }
 
-    fn pack(&self, _buf: &mut [u8]) -> Result<(), ControlMessageError> {
-        unimplemented!()
+    fn pack(&self, buf: &mut [u8]) -> Result<(), ControlMessageError> {
+        if buf.len() < FRIEND_UPDATE_SIZE {
+            Err(ControlMessageError::BufferTooSmall)
+        } else {
+            buf[0] = u8::from(self.0.key_refresh_flag.0) | (u8::from(self.0.iv_update_flag.0) << 1);
+            buf[1..5].copy_from_slice(&self.0.iv_index.to_bytes_be());
+            buf[5] = u8::from(self.0.md.value());
+            Ok(())
+        }
     }
 }
+const FRIEND_REQUEST_SIZE: usize = 10;
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
-pub struct FriendRequest {}
+pub struct FriendRequest(pub friend::FriendRequest);
 impl ControlMessage for FriendRequest {
     const OPCODE: ControlOpcode = ControlOpcode::FriendRequest;
 
     fn byte_len(&self) -> usize {
-        unimplemented!()
+        FRIEND_REQUEST_SIZE
     }
 
-    fn unpack(_buf: &[u8]) -> Result<Self, ControlMessageError> {
-        unimplemented!()
+    fn unpack(buf: &[u8]) -> Result<Self, ControlMessageError> {
+        if buf.len() != FRIEND_REQUEST_SIZE {
+            return Err(ControlMessageError::BadLength);
+        }
+        let criteria =
+            friend::Criteria::try_from(buf[0]).map_err(|_| ControlMessageError::BadBytes)?;
+        let receive_delay =
+            friend::ReceiveDelay::new_checked(buf[1]).ok_or(ControlMessageError::BadBytes)?;
+        let poll_timeout = friend::PollTimeout::new(
+            U24::from_bytes_be(&buf[2..5]).expect("poll_timeout is always here"),
+        );
+        if !poll_timeout.is_valid_request() {
+            return Err(ControlMessageError::BadBytes);
+        }
+        let previous_address =
+            match u16::from_bytes_be(&buf[5..7]).expect("previous_address is always here") {
+                0 => None,
+                address => Some(
+                    UnicastAddress::try_from(address).map_err(|_| ControlMessageError::BadBytes)?,
+                ),
+            };
+        let num_elements = match buf[7] {
+            0 => return Err(ControlMessageError::BadBytes),
+            n => n,
+        };
+        Ok(FriendRequest(friend::FriendRequest {
+            criteria,
+            receive_delay,
+            poll_timeout,
+            previous_address,
+            num_elements,
+            lpn_counter: friend::LPNCounter::new(
+                u16::from_bytes_be(&buf[8..10]).expect("lpn_counter is always here"),
+            ),
+        }))
     }
 
-    fn pack(&self, _buf: &mut [u8]) -> Result<(), ControlMessageError> {
-        unimplemented!()
+    fn pack(&self, buf: &mut [u8]) -> Result<(), ControlMessageError> {
+        if buf.len() < FRIEND_REQUEST_SIZE {
+            Err(ControlMessageError::BufferTooSmall)
+        } else {
+            buf[0] = u8::from(self.0.criteria);
+            buf[1] = self.0.receive_delay.value();
+            buf[2..5].copy_from_slice(&self.0.poll_timeout.steps().to_bytes_be());
+            buf[5..7].copy_from_slice(&self.0.previous_address.map_or(0, u16::from).to_bytes_be());
+            buf[7] = self.0.num_elements;
+            buf[8..10].copy_from_slice(&self.0.lpn_counter.value().to_bytes_be());
+            Ok(())
+        }
     }
 }
+const FRIEND_OFFER_SIZE: usize = 6;
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
-pub struct FriendOffer {}
+pub struct FriendOffer(pub friend::FriendOffer);
 impl ControlMessage for FriendOffer {
     const OPCODE: ControlOpcode = ControlOpcode::FriendOffer;
 
     fn byte_len(&self) -> usize {
-        unimplemented!()
+        FRIEND_OFFER_SIZE
     }
 
-    fn unpack(_buf: &[u8]) -> Result<Self, ControlMessageError> {
-        unimplemented!()
+    fn unpack(buf: &[u8]) -> Result<Self, ControlMessageError> {
+        if buf.len() != FRIEND_OFFER_SIZE {
+            return Err(ControlMessageError::BadLength);
+        }
+        Ok(FriendOffer(friend::FriendOffer {
+            receive_window: buf[0],
+            queue_size: buf[1],
+            subscription_list_size: buf[2],
+            rssi: buf[3] as i8,
+            friend_counter: friend::FriendCounter::new(
+                u16::from_bytes_be(&buf[4..6]).expect("friend_counter is always here"),
+            ),
+        }))
     }
 
-    fn pack(&self, _buf: &mut [u8]) -> Result<(), ControlMessageError> {
-        unimplemented!()
+    fn pack(&self, buf: &mut [u8]) -> Result<(), ControlMessageError> {
+        if buf.len() < FRIEND_OFFER_SIZE {
+            Err(ControlMessageError::BufferTooSmall)
+        } else {
+            buf[0] = self.0.receive_window;
+            buf[1] = self.0.queue_size;
+            buf[2] = self.0.subscription_list_size;
+            buf[3] = self.0.rssi as u8;
+            buf[4..6].copy_from_slice(&self.0.friend_counter.value().to_bytes_be());
+            Ok(())
+        }
+    }
+}
+const FRIEND_CLEAR_SIZE: usize = 4;
+/// `LPNAddress || LPNCounter` (both big endian) shared by Friend Clear and Friend Clear Confirm.
+fn unpack_friend_clear(
+    buf: &[u8],
+) -> Result<(UnicastAddress, friend::LPNCounter), ControlMessageError> {
+    if buf.len() != FRIEND_CLEAR_SIZE {
+        return Err(ControlMessageError::BadLength);
+    }
+    let lpn_address = UnicastAddress::try_from(
+        u16::from_bytes_be(&buf[0..2]).expect("lpn_address is always here"),
+    )
+    .map_err(|_| ControlMessageError::BadBytes)?;
+    let lpn_counter = friend::LPNCounter::new(
+        u16::from_bytes_be(&buf[2..4]).expect("lpn_counter is always here"),
+    );
+    Ok((lpn_address, lpn_counter))
+}
+fn pack_friend_clear(
+    lpn_address: UnicastAddress,
+    lpn_counter: friend::LPNCounter,
+    buf: &mut [u8],
+) -> Result<(), ControlMessageError> {
+    if buf.len() < FRIEND_CLEAR_SIZE {
+        Err(ControlMessageError::BufferTooSmall)
+    } else {
+        buf[0..2].copy_from_slice(&u16::from(lpn_address).to_bytes_be());
+        buf[2..4].copy_from_slice(&lpn_counter.value().to_bytes_be());
+        Ok(())
     }
 }
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
-pub struct FriendClear {}
+pub struct FriendClear(pub friend::FriendClear);
 impl ControlMessage for FriendClear {
     const OPCODE: ControlOpcode = ControlOpcode::FriendClear;
 
     fn byte_len(&self) -> usize {
-        unimplemented!()
+        FRIEND_CLEAR_SIZE
     }
 
-    fn unpack(_buf: &[u8]) -> Result<Self, ControlMessageError> {
-        unimplemented!()
+    fn unpack(buf: &[u8]) -> Result<Self, ControlMessageError> {
+        let (lpn_address, lpn_counter) = unpack_friend_clear(buf)?;
+        Ok(FriendClear(friend::FriendClear {
+            lpn_address,
+            lpn_counter,
+        }))
     }
 
-    fn pack(&self, _buf: &mut [u8]) -> Result<(), ControlMessageError> {
-        unimplemented!()
+    fn pack(&self, buf: &mut [u8]) -> Result<(), ControlMessageError> {
+        pack_friend_clear(self.0.lpn_address, self.0.lpn_counter, buf)
     }
 }
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
-pub struct FriendClearConfirm {}
+pub struct FriendClearConfirm(pub friend::FriendClearConfirm);
 impl ControlMessage for FriendClearConfirm {
     const OPCODE: ControlOpcode = ControlOpcode::FriendClearConfirm;
 
     fn byte_len(&self) -> usize {
-        unimplemented!()
+        FRIEND_CLEAR_SIZE
     }
 
-    fn unpack(_buf: &[u8]) -> Result<Self, ControlMessageError> {
-        unimplemented!()
+    fn unpack(buf: &[u8]) -> Result<Self, ControlMessageError> {
+        let (lpn_address, lpn_counter) = unpack_friend_clear(buf)?;
+        Ok(FriendClearConfirm(friend::FriendClearConfirm {
+            lpn_address,
+            lpn_counter,
+        }))
     }
 
-    fn pack(&self, _buf: &mut [u8]) -> Result<(), ControlMessageError> {
-        unimplemented!()
+    fn pack(&self, buf: &mut [u8]) -> Result<(), ControlMessageError> {
+        pack_friend_clear(self.0.lpn_address, self.0.lpn_counter, buf)
     }
 }
-#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
-pub struct FriendSubscriptionListAdd {}
+/// `TransactionNumber || AddressList` with big endian addresses.
+fn unpack_subscription_list(
+    buf: &[u8],
+) -> Result<friend::FriendSubscriptionList, ControlMessageError> {
+    let (&transaction_number, addresses) =
+        buf.split_first().ok_or(ControlMessageError::BadLength)?;
+    if addresses.is_empty() || addresses.len() % 2 != 0 {
+        return Err(ControlMessageError::BadLength);
+    }
+    Ok(friend::FriendSubscriptionList {
+        transaction_number,
+        addresses: addresses
+            .chunks_exact(2)
+            .map(|address| Address::from_bytes_be(address).expect("2 byte chunks"))
+            .collect(),
+    })
+}
+fn pack_subscription_list(
+    list: &friend::FriendSubscriptionList,
+    buf: &mut [u8],
+) -> Result<(), ControlMessageError> {
+    if buf.len() < 1 + list.addresses.len() * 2 {
+        Err(ControlMessageError::BufferTooSmall)
+    } else {
+        buf[0] = list.transaction_number;
+        for (address, out) in list.addresses.iter().zip(buf[1..].chunks_exact_mut(2)) {
+            out.copy_from_slice(&address.to_bytes_be());
+        }
+        Ok(())
+    }
+}
+#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct FriendSubscriptionListAdd(pub friend::FriendSubscriptionList);
 impl ControlMessage for FriendSubscriptionListAdd {
     const OPCODE: ControlOpcode = ControlOpcode::FriendSubscriptionListAdd;
 
     fn byte_len(&self) -> usize {
-        unimplemented!()
+        1 + self.0.addresses.len() * 2
     }
 
-    fn unpack(_buf: &[u8]) -> Result<Self, ControlMessageError> {
-        unimplemented!()
+    fn unpack(buf: &[u8]) -> Result<Self, ControlMessageError> {
+        unpack_subscription_list(buf).map(FriendSubscriptionListAdd)
     }
 
-    fn pack(&self, _buf: &mut [u8]) -> Result<(), ControlMessageError> {
-        unimplemented!()
+    fn pack(&self, buf: &mut [u8]) -> Result<(), ControlMessageError> {
+        pack_subscription_list(&self.0, buf)
     }
 }
-#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
-pub struct FriendSubscriptionListRemove {}
+#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct FriendSubscriptionListRemove(pub friend::FriendSubscriptionList);
 impl ControlMessage for FriendSubscriptionListRemove {
     const OPCODE: ControlOpcode = ControlOpcode::FriendSubscriptionListRemove;
 
     fn byte_len(&self) -> usize {
-        unimplemented!()
+        1 + self.0.addresses.len() * 2
     }
 
-    fn unpack(_buf: &[u8]) -> Result<Self, ControlMessageError> {
-        unimplemented!()
+    fn unpack(buf: &[u8]) -> Result<Self, ControlMessageError> {
+        unpack_subscription_list(buf).map(FriendSubscriptionListRemove)
     }
 
-    fn pack(&self, _buf: &mut [u8]) -> Result<(), ControlMessageError> {
-        unimplemented!()
+    fn pack(&self, buf: &mut [u8]) -> Result<(), ControlMessageError> {
+        pack_subscription_list(&self.0, buf)
     }
 }
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
-pub struct FriendSubscriptionListConfirm {}
+pub struct FriendSubscriptionListConfirm(pub friend::FriendSubscriptionListConfirm);
 
 impl ControlMessage for FriendSubscriptionListConfirm {
     const OPCODE: ControlOpcode = ControlOpcode::FriendSubscriptionListConfirm;
 
     fn byte_len(&self) -> usize {
-        unimplemented!()
+        1
     }
 
-    fn unpack(_buf: &[u8]) -> Result<Self, ControlMessageError> {
-        unimplemented!()
+    fn unpack(buf: &[u8]) -> Result<Self, ControlMessageError> {
+        match buf {
+            [transaction_number] => Ok(FriendSubscriptionListConfirm(
+                friend::FriendSubscriptionListConfirm {
+                    transaction_number: *transaction_number,
+                },
+            )),
+            _ => Err(ControlMessageError::BadLength),
+        }
     }
 
-    fn pack(&self, _buf: &mut [u8]) -> Result<(), ControlMessageError> {
-        unimplemented!()
+    fn pack(&self, buf: &mut [u8]) -> Result<(), ControlMessageError> {
+        if buf.is_empty() {
+            Err(ControlMessageError::BufferTooSmall)
+        } else {
+            buf[0] = self.0.transaction_number;
+            Ok(())
+        }
     }
 }
+const HEARTBEAT_SIZE: usize = 3;
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
-pub struct Heartbeat {}
+pub struct Heartbeat {
+    /// TTL the Heartbeat was sent with. Subtracting the received TTL gives the hop count.
+    pub init_ttl: TTL,
+    /// Features the sender currently has enabled.
+    pub features: Features,
+}
 
 impl ControlMessage for Heartbeat {
     const OPCODE: ControlOpcode = ControlOpcode::Heartbeat;
 
     fn byte_len(&self) -> usize {
-        unimplemented!()
+        HEARTBEAT_SIZE
     }
 
-    fn unpack(_buf: &[u8]) -> Result<Self, ControlMessageError> {
-        unimplemented!()
+    fn unpack(buf: &[u8]) -> Result<Self, ControlMessageError> {
+        if buf.len() == HEARTBEAT_SIZE {
+            Ok(Self {
+                init_ttl: TTL::new(buf[0] & 0x7F),
+                features: Features::from_bytes_be(&buf[1..3]).expect("features is always here"),
+            })
+        } else {
+            Err(ControlMessageError::BadLength)
+        }
     }
 
-    fn pack(&self, _buf: &mut [u8]) -> Result<(), ControlMessageError> {
-        unimplemented!()
+    fn pack(&self, buf: &mut [u8]) -> Result<(), ControlMessageError> {
+        if buf.len() < HEARTBEAT_SIZE {
+            Err(ControlMessageError::BufferTooSmall)
+        } else {
+            buf[0] = u8::from(self.init_ttl);
+            buf[1..3].copy_from_slice(&self.features.to_bytes_be());
+            Ok(())
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<M: ControlMessage + PartialEq + core::fmt::Debug>(msg: &M) {
+        let mut buf = alloc::vec![0_u8; msg.byte_len()];
+        msg.pack(&mut buf).expect("buffer is big enough");
+        assert_eq!(&M::unpack(&buf).expect("packed message unpacks"), msg);
+        assert_eq!(
+            msg.pack(&mut buf[..msg.byte_len() - 1]),
+            Err(ControlMessageError::BufferTooSmall)
+        );
+    }
+    #[test]
+    fn test_friend_clear() {
+        let clear = FriendClear(friend::FriendClear {
+            lpn_address: UnicastAddress::new(0x1234),
+            lpn_counter: friend::LPNCounter::new(0xABCD),
+        });
+        let mut buf = [0_u8; FRIEND_CLEAR_SIZE];
+        clear.pack(&mut buf).expect("buffer is big enough");
+        assert_eq!(buf, [0x12, 0x34, 0xAB, 0xCD]);
+        round_trip(&clear);
+        round_trip(&FriendClearConfirm(friend::FriendClearConfirm {
+            lpn_address: UnicastAddress::new(0x0001),
+            lpn_counter: friend::LPNCounter::new(0),
+        }));
+
+        // The LPNAddress must be a unicast address.
+        assert_eq!(
+            FriendClear::unpack(&[0xC0, 0x01, 0x00, 0x00]),
+            Err(ControlMessageError::BadBytes)
+        );
+        assert_eq!(
+            FriendClearConfirm::unpack(&[0x00, 0x01, 0x00]),
+            Err(ControlMessageError::BadLength)
+        );
+    }
+    #[test]
+    fn test_friend_poll() {
+        round_trip(&FriendPoll(friend::FriendPoll::new(friend::FSN::new(
+            false,
+        ))));
+        round_trip(&FriendPoll(friend::FriendPoll::new(friend::FSN::new(true))));
+        // Only the lowest bit (the FSN) may be set.
+        assert_eq!(
+            FriendPoll::unpack(&[0x02]),
+            Err(ControlMessageError::BadBytes)
+        );
+        assert_eq!(FriendPoll::unpack(&[]), Err(ControlMessageError::BadLength));
     }
 }
@@ -165,7 +165,7 @@ impl AESCipher {
         let nonce = nonce.as_ref().into();
         match mic {
             MIC::Big(b) => self
-                .ccm_small_mic_cipher()
+                .ccm_big_mic_cipher()
                 .decrypt_in_place_detached(
                     nonce,
                     associated_data,
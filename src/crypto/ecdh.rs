@@ -0,0 +1,79 @@
+//! P-256 Elliptic Curve Diffie-Hellman for the provisioning key exchange (backed by `ring`).
+//! Public keys are exchanged as the raw X and Y coordinates (`protocol::PublicKey`).
+use crate::crypto::{ECDHSecret, ECDH_SECRET_LEN};
+use crate::provisioning::protocol::{PublicKey, KEY_COMPONENT_LEN};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, ECDH_P256};
+use ring::rand::SystemRandom;
+
+/// SEC1 tag for an uncompressed point (`0x04 || X || Y`).
+const UNCOMPRESSED_POINT_TAG: u8 = 0x04;
+const UNCOMPRESSED_POINT_LEN: usize = 1 + KEY_COMPONENT_LEN * 2;
+
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum ECDHError {
+    /// The system random number generator failed.
+    KeyGeneration,
+    /// The other end's public key isn't a point on the curve.
+    InvalidPublicKey,
+}
+/// Single use P-256 private key. It's consumed by [`PrivateKey::agree`] so every provisioning
+/// gets a new key pair.
+pub struct PrivateKey(EphemeralPrivateKey);
+impl PrivateKey {
+    /// Generates a new key pair.
+    pub fn generate() -> Result<(PrivateKey, PublicKey), ECDHError> {
+        let private_key = EphemeralPrivateKey::generate(&ECDH_P256, &SystemRandom::new())
+            .map_err(|_| ECDHError::KeyGeneration)?;
+        let point = private_key
+            .compute_public_key()
+            .map_err(|_| ECDHError::KeyGeneration)?;
+        let point = point.as_ref();
+        debug_assert_eq!(point.len(), UNCOMPRESSED_POINT_LEN);
+        let mut public_key = PublicKey::default();
+        public_key.x.copy_from_slice(&point[1..=KEY_COMPONENT_LEN]);
+        public_key
+            .y
+            .copy_from_slice(&point[KEY_COMPONENT_LEN + 1..]);
+        Ok((PrivateKey(private_key), public_key))
+    }
+    /// Computes the shared secret with the other end's `public_key`. Fails with
+    /// `InvalidPublicKey` if the key isn't valid.
+    pub fn agree(self, public_key: &PublicKey) -> Result<ECDHSecret, ECDHError> {
+        let mut point = [0_u8; UNCOMPRESSED_POINT_LEN];
+        point[0] = UNCOMPRESSED_POINT_TAG;
+        point[1..=KEY_COMPONENT_LEN].copy_from_slice(&public_key.x[..]);
+        point[KEY_COMPONENT_LEN + 1..].copy_from_slice(&public_key.y[..]);
+        agreement::agree_ephemeral(
+            self.0,
+            &UnparsedPublicKey::new(&ECDH_P256, &point[..]),
+            ECDHError::InvalidPublicKey,
+            |secret| {
+                let mut out = [0_u8; ECDH_SECRET_LEN];
+                out.copy_from_slice(secret);
+                Ok(ECDHSecret::new_bytes(out))
+            },
+        )
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_agreement() {
+        let (provisioner, provisioner_public) = PrivateKey::generate().expect("system random");
+        let (device, device_public) = PrivateKey::generate().expect("system random");
+        assert_ne!(provisioner_public, device_public);
+        assert_eq!(
+            provisioner.agree(&device_public),
+            device.agree(&provisioner_public)
+        );
+    }
+    #[test]
+    fn test_invalid_public_key() {
+        let (private_key, _) = PrivateKey::generate().expect("system random");
+        assert_eq!(
+            private_key.agree(&PublicKey::default()),
+            Err(ECDHError::InvalidPublicKey)
+        );
+    }
+}
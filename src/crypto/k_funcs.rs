@@ -11,8 +11,13 @@ use core::convert::TryInto;
 /// k1 function from Mesh Core v1.0. N==`bytes` and P==`extra`.
 #[must_use]
 pub fn k1(key: &Key, salt: Salt, extra: &[u8]) -> Key {
-    let t = AESCipher::from(salt).cmac(key.as_ref());
-    AESCipher::from(t).cmac(extra)
+    k1_bytes(key.as_ref(), salt, extra)
+}
+/// `k1` with any length `n` (like the 32 byte `ECDHSecret` used during provisioning).
+#[must_use]
+pub fn k1_bytes(n: &[u8], salt: Salt, p: &[u8]) -> Key {
+    let t = AESCipher::from(salt).cmac(n);
+    AESCipher::from(t).cmac(p)
 }
 #[must_use]
 pub fn k2(key: &Key, p: impl AsRef<[u8]>) -> (NID, EncryptionKey, PrivacyKey) {
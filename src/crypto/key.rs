@@ -296,9 +296,10 @@ impl DevKey {
     pub fn from_hex(hex: &str) -> Option<Self> {
         Some(Self::new_bytes(hex_16_to_array(hex)?))
     }
+    /// `k1(ECDHSecret, ProvisioningSalt, "prdk")`
     #[must_use]
     pub fn from_salt_and_secret(salt: ProvisioningSalt, secret: ECDHSecret) -> Self {
-        Self::new(super::k1(&salt.0.as_key(), secret.as_salt(), b"prdk"))
+        Self::new(super::k1_bytes(secret.as_ref(), salt.as_salt(), b"prdk"))
     }
     #[must_use]
     pub fn key(&self) -> Key {
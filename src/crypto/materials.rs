@@ -191,6 +191,49 @@ impl NetKeyMap {
     ) -> Option<KeyPhase<NetworkSecurityMaterials>> {
         self.map.insert(index, KeyPhase::Normal(new_key.into()))
     }
+    /// Starts updating the NetKey under `index` to `new_key` (Key Refresh Phase 1). Messages are
+    /// still sent with the old key but received with either key. Updating again with the same
+    /// `new_key` is allowed.
+    pub fn update(&mut self, index: NetKeyIndex, new_key: &NetKey) -> Result<(), NetKeyError> {
+        let phase = self
+            .map
+            .get_mut(&index)
+            .ok_or(NetKeyError::InvalidNetKeyIndex)?;
+        let new = NetworkSecurityMaterials::from(new_key);
+        match *phase {
+            KeyPhase::Normal(old) => {
+                *phase = KeyPhase::Phase1(KeyPair { new, old });
+                Ok(())
+            }
+            KeyPhase::Phase1(pair) if pair.new == new => Ok(()),
+            _ => Err(NetKeyError::CannotUpdate),
+        }
+    }
+    /// Moves the NetKey under `index` to the Key Refresh `phase` and returns the phase it ends up
+    /// in. `Second` switches transmitting to the new key (only from Phase 1). `Third` (and
+    /// `Normal`) revoke the old key and go back to Normal Operation. `First` is only entered
+    /// through `NetKeyMap::update`. Asking for the current phase doesn't change anything.
+    pub fn set_phase(
+        &mut self,
+        index: NetKeyIndex,
+        phase: KeyRefreshPhases,
+    ) -> Result<KeyRefreshPhases, NetKeyError> {
+        let key_phase = self
+            .map
+            .get_mut(&index)
+            .ok_or(NetKeyError::InvalidNetKeyIndex)?;
+        *key_phase = match (*key_phase, phase) {
+            (KeyPhase::Phase1(pair), KeyRefreshPhases::Second) => KeyPhase::Phase2(pair),
+            (KeyPhase::Phase1(pair), KeyRefreshPhases::Third)
+            | (KeyPhase::Phase1(pair), KeyRefreshPhases::Normal)
+            | (KeyPhase::Phase2(pair), KeyRefreshPhases::Third)
+            | (KeyPhase::Phase2(pair), KeyRefreshPhases::Normal) => KeyPhase::Normal(pair.new),
+            (current, _) if current.phase() == phase => current,
+            (current @ KeyPhase::Normal(_), KeyRefreshPhases::Third) => current,
+            _ => return Err(NetKeyError::CannotSet),
+        };
+        Ok(key_phase.phase())
+    }
 }
 pub struct NIDFilterMap<
     'a,
@@ -227,6 +270,7 @@ impl<'a, I: Iterator<Item = (&'a NetKeyIndex, &'a KeyPhase<NetworkSecurityMateri
         }
     }
 }
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 #[cfg_attr(feature = "serde-1", derive(serde::Serialize, serde::Deserialize))]
 pub struct ApplicationSecurityMaterials {
     pub app_key: AppKey,
@@ -242,9 +286,42 @@ impl ApplicationSecurityMaterials {
         }
     }
 }
+/// Returned when an AppKey can't be added or updated.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum AppKeyError {
+    /// A different AppKey is already stored under the `AppKeyIndex`.
+    KeyIndexAlreadyStored,
+    /// No AppKey is stored under the `AppKeyIndex`.
+    InvalidAppKeyIndex,
+    /// No NetKey is stored under the `NetKeyIndex`.
+    InvalidNetKeyIndex,
+    /// The AppKey is bound to a different NetKey.
+    InvalidBinding,
+    /// The AppKey is already being updated to a different key.
+    CannotUpdate,
+    /// Storing the AppKey would go over `KeyLimits::max_app_keys`.
+    InsufficientResources,
+}
+/// Returned when a NetKey can't be added, updated or moved to another Key Refresh phase.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum NetKeyError {
+    /// A different NetKey is already stored under the `NetKeyIndex`.
+    KeyIndexAlreadyStored,
+    /// No NetKey is stored under the `NetKeyIndex`.
+    InvalidNetKeyIndex,
+    /// The NetKey is already being updated to a different key.
+    CannotUpdate,
+    /// The Key Refresh phase can't move to the asked for phase from the current one.
+    CannotSet,
+    /// Storing the NetKey would go over `KeyLimits::max_net_keys`.
+    InsufficientResources,
+}
+/// AppKeys indexed by `AppKeyIndex`. Like `NetKeyMap`, each AppKey has an old and a new value
+/// during a Key Refresh. Both are tried when decrypting while the `KeyPhase` picks which one is
+/// used for transmitting.
 #[cfg_attr(feature = "serde-1", derive(serde::Serialize, serde::Deserialize))]
 pub struct AppKeyMap {
-    pub map: btree_map::BTreeMap<AppKeyIndex, ApplicationSecurityMaterials>,
+    pub map: btree_map::BTreeMap<AppKeyIndex, KeyPhase<ApplicationSecurityMaterials>>,
 }
 impl AppKeyMap {
     pub fn new() -> Self {
@@ -252,14 +329,23 @@ impl AppKeyMap {
             map: btree_map::BTreeMap::new(),
         }
     }
-
+    /// Returns the `ApplicationSecurityMaterials` used for transmitting under `index`.
     pub fn get_key(&self, index: AppKeyIndex) -> Option<&ApplicationSecurityMaterials> {
+        self.map.get(&index).map(KeyPhase::tx_key)
+    }
+    pub fn get_keys(&self, index: AppKeyIndex) -> Option<&KeyPhase<ApplicationSecurityMaterials>> {
         self.map.get(&index)
     }
-    pub fn get_key_mut(&mut self, index: AppKeyIndex) -> Option<&mut ApplicationSecurityMaterials> {
+    pub fn get_keys_mut(
+        &mut self,
+        index: AppKeyIndex,
+    ) -> Option<&mut KeyPhase<ApplicationSecurityMaterials>> {
         self.map.get_mut(&index)
     }
-    pub fn remove_key(&mut self, index: AppKeyIndex) -> Option<ApplicationSecurityMaterials> {
+    pub fn remove_key(
+        &mut self,
+        index: AppKeyIndex,
+    ) -> Option<KeyPhase<ApplicationSecurityMaterials>> {
         self.map.remove(&index)
     }
     pub fn insert(
@@ -267,27 +353,72 @@ impl AppKeyMap {
         net_key_index: NetKeyIndex,
         app_key_index: AppKeyIndex,
         new_key: AppKey,
-    ) -> Option<ApplicationSecurityMaterials> {
+    ) -> Option<KeyPhase<ApplicationSecurityMaterials>> {
         self.map.insert(
             app_key_index,
-            ApplicationSecurityMaterials::new(new_key, net_key_index),
+            KeyPhase::Normal(ApplicationSecurityMaterials::new(new_key, net_key_index)),
         )
     }
+    /// Starts updating the AppKey under `app_key_index` to `new_key` (Key Refresh Phase 1).
+    /// Messages are still sent with the old key but received with either key. Updating again
+    /// with the same `new_key` is allowed.
+    pub fn update(
+        &mut self,
+        net_key_index: NetKeyIndex,
+        app_key_index: AppKeyIndex,
+        new_key: AppKey,
+    ) -> Result<(), AppKeyError> {
+        let phase = self
+            .map
+            .get_mut(&app_key_index)
+            .ok_or(AppKeyError::InvalidAppKeyIndex)?;
+        if phase.tx_key().net_key_index != net_key_index {
+            return Err(AppKeyError::InvalidBinding);
+        }
+        let new = ApplicationSecurityMaterials::new(new_key, net_key_index);
+        match *phase {
+            KeyPhase::Normal(old) => {
+                *phase = KeyPhase::Phase1(KeyPair { new, old: *old });
+                Ok(())
+            }
+            KeyPhase::Phase1(pair) if pair.new == new => Ok(()),
+            _ => Err(AppKeyError::CannotUpdate),
+        }
+    }
+    /// Moves every AppKey bound to `net_key_index` along with the NetKey's Key Refresh `phase`.
+    /// `Second` switches transmitting to the new keys. `Third` (and `Normal`) revoke the old keys.
+    pub fn set_phase(&mut self, net_key_index: NetKeyIndex, phase: KeyRefreshPhases) {
+        for key_phase in self
+            .map
+            .values_mut()
+            .filter(|p| p.tx_key().net_key_index == net_key_index)
+        {
+            *key_phase = match (*key_phase, phase) {
+                (KeyPhase::Phase1(pair), KeyRefreshPhases::Second) => KeyPhase::Phase2(pair),
+                (KeyPhase::Phase1(pair), KeyRefreshPhases::Third)
+                | (KeyPhase::Phase1(pair), KeyRefreshPhases::Normal)
+                | (KeyPhase::Phase2(pair), KeyRefreshPhases::Third)
+                | (KeyPhase::Phase2(pair), KeyRefreshPhases::Normal) => KeyPhase::Normal(pair.new),
+                (current, _) => current,
+            }
+        }
+    }
     /// Returns all `ApplicationSecurityMaterials` matching `aid_to_match`. Because `AID` is a 6-bit value,
     /// one `AID` can match multiple different application keys. For this reason, this functions returns an
     /// iterator that yields each matching application security materials. Only attempting to decrypt
     /// the Application Payload (and it failing/succeeding) will tell you if the `AID` and
-    /// `ApplicationSecurityMaterials` match.
+    /// `ApplicationSecurityMaterials` match. During a Key Refresh, both the old and new keys are
+    /// yielded.
     pub fn matching_aid(
         &self,
         aid_to_match: AID,
     ) -> impl Iterator<Item = (AppKeyIndex, &'_ ApplicationSecurityMaterials)> {
-        self.map.iter().filter_map(move |(&index, materials)| {
-            if materials.aid == aid_to_match {
-                Some((index, materials))
-            } else {
-                None
-            }
+        self.map.iter().flat_map(move |(&index, phase)| {
+            let (first, second) = phase.rx_keys();
+            core::iter::once(first)
+                .chain(second)
+                .filter(move |materials| materials.aid == aid_to_match)
+                .map(move |materials| (index, materials))
         })
     }
 }
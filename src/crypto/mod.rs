@@ -27,6 +27,8 @@ pub fn hex_16_to_array(hex: &str) -> Option<[u8; 16]> {
 pub mod aes;
 mod aes_ccm;
 mod aes_cmac;
+#[cfg(feature = "full_stack")]
+pub mod ecdh;
 pub mod k_funcs;
 pub mod key;
 pub mod materials;
@@ -219,24 +221,26 @@ impl AsRef<[u8]> for Salt {
 #[cfg_attr(feature = "serde-1", derive(serde::Serialize, serde::Deserialize))]
 pub struct ProvisioningSalt(Salt);
 impl ProvisioningSalt {
+    pub fn new(salt: Salt) -> Self {
+        Self(salt)
+    }
     pub fn as_salt(&self) -> Salt {
         self.0
     }
 }
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialOrd, PartialEq, Ord)]
 #[cfg_attr(feature = "serde-1", derive(serde::Serialize, serde::Deserialize))]
-pub struct ECDHSecret(Salt);
+pub struct ECDHSecret([u8; ECDH_SECRET_LEN]);
+/// P-256 ECDH shared secrets are the 32 byte X coordinate.
+pub const ECDH_SECRET_LEN: usize = 32;
 impl ECDHSecret {
-    pub fn new_bytes(bytes: [u8; SALT_LEN]) -> Self {
-        Self(Salt::new(bytes))
-    }
-    pub fn as_salt(&self) -> Salt {
-        self.0
+    pub fn new_bytes(bytes: [u8; ECDH_SECRET_LEN]) -> Self {
+        Self(bytes)
     }
 }
 impl AsRef<[u8]> for ECDHSecret {
     fn as_ref(&self) -> &[u8] {
-        self.0.as_ref()
+        &self.0[..]
     }
 }
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialOrd, PartialEq, Ord)]
@@ -281,4 +285,4 @@ impl Display for KeyRefreshPhases {
 }
 use crate::bytes::ToFromBytesEndian;
 use core::fmt::{Display, Error, Formatter};
-pub use k_funcs::{k1, k2, k3, k4, s1};
+pub use k_funcs::{k1, k1_bytes, k2, k3, k4, s1};
@@ -1,9 +1,13 @@
 //! Bluetooth Mesh nonces. Based on Mesh Core Spec v1.0.
+//!
+//! Every nonce is 13 bytes: the `NonceType`, one byte depending on the type (CTL and TTL or
+//! ASZMIC), the SEQ, the SRC, the DST (or padding) and the IV Index. Every multi-byte field is
+//! big endian.
 use crate::address::{Address, UnicastAddress};
 use crate::bytes::ToFromBytesEndian;
 use crate::mesh::{IVIndex, SequenceNumber, CTL, TTL};
 
-const NONCE_LEN: usize = 13;
+pub const NONCE_LEN: usize = 13;
 const ZERO_NONCE_BYTES: [u8; NONCE_LEN] = [0_u8; NONCE_LEN];
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialOrd, PartialEq, Ord)]
 pub struct Nonce([u8; NONCE_LEN]);
@@ -12,6 +16,34 @@ impl Nonce {
         Nonce(bytes)
     }
 }
+/// Packs the fields every nonce type shares (all big endian).
+fn nonce_bytes(
+    nonce_type: NonceType,
+    flags: u8,
+    seq: SequenceNumber,
+    src: UnicastAddress,
+    dst: [u8; 2],
+    iv_index: IVIndex,
+) -> [u8; NONCE_LEN] {
+    let seq = seq.to_bytes_be();
+    let src = src.to_bytes_be();
+    let iv = iv_index.to_bytes_be();
+    [
+        nonce_type.as_u8(),
+        flags,
+        seq[0],
+        seq[1],
+        seq[2],
+        src[0],
+        src[1],
+        dst[0],
+        dst[1],
+        iv[0],
+        iv[1],
+        iv[2],
+        iv[3],
+    ]
+}
 impl AsRef<[u8]> for Nonce {
     fn as_ref(&self) -> &[u8] {
         &self.0[..]
@@ -20,8 +52,23 @@ impl AsRef<[u8]> for Nonce {
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialOrd, PartialEq, Ord)]
 pub struct NetworkNonce(Nonce);
 impl NetworkNonce {
-    pub fn new(nonce: Nonce) -> Self {
-        Self(nonce)
+    /// Network nonce for a Network PDU with the given header fields.
+    #[must_use]
+    pub fn new(
+        ctl: CTL,
+        ttl: TTL,
+        seq: SequenceNumber,
+        src: UnicastAddress,
+        iv_index: IVIndex,
+    ) -> Self {
+        Self::new_bytes(nonce_bytes(
+            NonceType::Network,
+            ttl.with_flag(ctl.0),
+            seq,
+            src,
+            [0x00, 0x00],
+            iv_index,
+        ))
     }
     pub fn new_bytes(bytes: [u8; NONCE_LEN]) -> Self {
         Self(Nonce(bytes))
@@ -40,8 +87,24 @@ impl AsRef<Nonce> for NetworkNonce {
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialOrd, PartialEq, Ord)]
 pub struct AppNonce(Nonce);
 impl AppNonce {
-    pub fn new(nonce: Nonce) -> Self {
-        Self(nonce)
+    /// Application nonce for an Access message encrypted with an AppKey. `aszmic` is only set
+    /// for segmented messages with a 64-bit TransMIC.
+    #[must_use]
+    pub fn new(
+        aszmic: bool,
+        seq: SequenceNumber,
+        src: UnicastAddress,
+        dst: Address,
+        iv_index: IVIndex,
+    ) -> Self {
+        Self::new_bytes(nonce_bytes(
+            NonceType::Application,
+            (aszmic as u8) << 7,
+            seq,
+            src,
+            dst.to_bytes_be(),
+            iv_index,
+        ))
     }
     pub fn new_bytes(bytes: [u8; NONCE_LEN]) -> Self {
         Self(Nonce(bytes))
@@ -60,8 +123,24 @@ impl AsRef<Nonce> for AppNonce {
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialOrd, PartialEq, Ord)]
 pub struct DeviceNonce(Nonce);
 impl DeviceNonce {
-    pub fn new(nonce: Nonce) -> Self {
-        Self(nonce)
+    /// Device nonce for an Access message encrypted with a DevKey. Same layout as the
+    /// `AppNonce`.
+    #[must_use]
+    pub fn new(
+        aszmic: bool,
+        seq: SequenceNumber,
+        src: UnicastAddress,
+        dst: Address,
+        iv_index: IVIndex,
+    ) -> Self {
+        Self::new_bytes(nonce_bytes(
+            NonceType::Device,
+            (aszmic as u8) << 7,
+            seq,
+            src,
+            dst.to_bytes_be(),
+            iv_index,
+        ))
     }
     pub fn new_bytes(bytes: [u8; NONCE_LEN]) -> Self {
         Self(Nonce(bytes))
@@ -80,13 +159,28 @@ impl AsRef<Nonce> for DeviceNonce {
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialOrd, PartialEq, Ord)]
 pub struct ProxyNonce(Nonce);
 impl ProxyNonce {
-    pub fn new(nonce: Nonce) -> Self {
-        Self(nonce)
+    /// Proxy nonce for a Proxy Configuration message. Like the `NetworkNonce` but without the
+    /// CTL and TTL.
+    #[must_use]
+    pub fn new(seq: SequenceNumber, src: UnicastAddress, iv_index: IVIndex) -> Self {
+        Self::new_bytes(nonce_bytes(
+            NonceType::Proxy,
+            0x00,
+            seq,
+            src,
+            [0x00, 0x00],
+            iv_index,
+        ))
     }
     pub fn new_bytes(bytes: [u8; NONCE_LEN]) -> Self {
         Self(Nonce(bytes))
     }
 }
+impl AsRef<[u8]> for ProxyNonce {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
 impl AsRef<Nonce> for ProxyNonce {
     fn as_ref(&self) -> &Nonce {
         &self.0
@@ -136,24 +230,7 @@ impl NetworkNonceParts {
     }
     #[must_use]
     pub fn to_nonce(&self) -> NetworkNonce {
-        let seq = self.seq.to_bytes_be();
-        let src = self.src.to_bytes_be();
-        let iv = self.iv_index.to_bytes_be();
-        NetworkNonce::new_bytes([
-            NonceType::Network.as_u8(),
-            self.ttl.with_flag(self.ctl.0),
-            seq[2],
-            seq[1],
-            seq[0],
-            src[1],
-            src[0],
-            0x00,
-            0x00,
-            iv[3],
-            iv[2],
-            iv[1],
-            iv[0],
-        ])
+        NetworkNonce::new(self.ctl, self.ttl, self.seq, self.src, self.iv_index)
     }
 }
 
@@ -168,25 +245,7 @@ pub struct AppNonceParts {
 
 impl AppNonceParts {
     pub fn to_nonce(&self) -> AppNonce {
-        let seq = self.seq.to_bytes_be();
-        let src = self.src.to_bytes_be();
-        let dst = self.dst.to_bytes_be();
-        let iv = self.iv_index.to_bytes_be();
-        AppNonce::new_bytes([
-            NonceType::Application.as_u8(),
-            (self.aszmic as u8) << 7,
-            seq[0],
-            seq[1],
-            seq[2],
-            src[0],
-            src[1],
-            dst[0],
-            dst[1],
-            iv[0],
-            iv[1],
-            iv[2],
-            iv[3],
-        ])
+        AppNonce::new(self.aszmic, self.seq, self.src, self.dst, self.iv_index)
     }
 }
 
@@ -201,54 +260,93 @@ pub struct DeviceNonceParts {
 
 impl DeviceNonceParts {
     pub fn to_nonce(&self) -> DeviceNonce {
-        let seq = self.seq.to_bytes_be();
-        let src = self.src.to_bytes_be();
-        let dst = self.src.to_bytes_be();
-        let iv = self.iv_index.to_bytes_be();
-        DeviceNonce::new_bytes([
-            NonceType::Device.as_u8(),
-            (self.aszmic as u8) << 7,
-            seq[2],
-            seq[1],
-            seq[0],
-            src[1],
-            src[0],
-            dst[1],
-            dst[0],
-            iv[3],
-            iv[2],
-            iv[1],
-            iv[0],
-        ])
+        DeviceNonce::new(self.aszmic, self.seq, self.src, self.dst, self.iv_index)
     }
 }
 
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
 pub struct ProxyNonceParts {
-    seq: SequenceNumber,
-    src: UnicastAddress,
-    iv_index: IVIndex,
+    pub seq: SequenceNumber,
+    pub src: UnicastAddress,
+    pub iv_index: IVIndex,
 }
 
 impl ProxyNonceParts {
     pub fn to_nonce(&self) -> ProxyNonce {
-        let seq = self.seq.to_bytes_be();
-        let src = self.src.to_bytes_be();
-        let iv = self.iv_index.to_bytes_be();
-        ProxyNonce::new_bytes([
-            NonceType::Proxy.as_u8(),
-            0x00,
-            seq[2],
-            seq[1],
-            seq[0],
-            src[1],
-            src[0],
-            0x00,
-            0x00,
-            iv[3],
-            iv[2],
-            iv[1],
-            iv[0],
-        ])
+        ProxyNonce::new(self.seq, self.src, self.iv_index)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::{bytes_str_to_buf, U24};
+
+    fn expected(hex: &str) -> [u8; NONCE_LEN] {
+        bytes_str_to_buf(hex).expect("13 byte hex nonce")
+    }
+    #[test]
+    fn test_network_nonce() {
+        // Message #1 from the Mesh Core v1.0 Sample Data.
+        let nonce = NetworkNonce::new(
+            CTL(true),
+            TTL::new(0),
+            SequenceNumber(U24::new(0x000001)),
+            UnicastAddress::new(0x1201),
+            IVIndex(0x12345678),
+        );
+        assert_eq!(
+            AsRef::<[u8]>::as_ref(&nonce),
+            &expected("00800000011201000012345678")[..]
+        );
+    }
+    #[test]
+    fn test_device_nonce() {
+        // Message #6 from the Mesh Core v1.0 Sample Data.
+        let parts = DeviceNonceParts {
+            aszmic: false,
+            seq: SequenceNumber(U24::new(0x3129AB)),
+            src: UnicastAddress::new(0x0003),
+            dst: Address::from(0x1201),
+            iv_index: IVIndex(0x12345678),
+        };
+        assert_eq!(
+            AsRef::<[u8]>::as_ref(&parts.to_nonce()),
+            &expected("02003129ab0003120112345678")[..]
+        );
+    }
+    #[test]
+    fn test_app_nonce() {
+        // Message #24 from the Mesh Core v1.0 Sample Data (with the virtual address hash).
+        let nonce = AppNonce::new(
+            false,
+            SequenceNumber(U24::new(0x07080B)),
+            UnicastAddress::new(0x1234),
+            Address::from(0xB529),
+            IVIndex(0x12345677),
+        );
+        assert_eq!(
+            AsRef::<[u8]>::as_ref(&nonce),
+            &expected("010007080b1234b52912345677")[..]
+        );
+        let aszmic = AppNonce::new(
+            true,
+            SequenceNumber(U24::new(0x07080B)),
+            UnicastAddress::new(0x1234),
+            Address::from(0xB529),
+            IVIndex(0x12345677),
+        );
+        assert_eq!(AsRef::<[u8]>::as_ref(&aszmic)[1], 0x80);
+    }
+    #[test]
+    fn test_proxy_nonce() {
+        let nonce = ProxyNonce::new(
+            SequenceNumber(U24::new(0x000001)),
+            UnicastAddress::new(0x0001),
+            IVIndex(0x12345678),
+        );
+        assert_eq!(
+            AsRef::<[u8]>::as_ref(&nonce),
+            &expected("03000000010001000012345678")[..]
+        );
     }
 }
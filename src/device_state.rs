@@ -1,33 +1,142 @@
 //! Device State Manager used to storing device state and having an config client control it.
 use crate::access::ModelIdentifier;
-use crate::address::UnicastAddress;
-use crate::crypto::key::DevKey;
-use crate::crypto::materials::{AppKeyMap, NetKeyMap, SecurityMaterials};
+use crate::address::{Address, GroupAddress, UnicastAddress};
+use crate::crypto::key::{AppKey, DevKey, NetKey};
+use crate::crypto::materials::{
+    AppKeyError, AppKeyMap, ApplicationSecurityMaterials, KeyPhase, NetKeyError, NetKeyMap,
+    SecurityMaterials,
+};
+use crate::crypto::KeyRefreshPhases;
 use crate::foundation::publication::ModelPublishInfo;
 use crate::foundation::state::{
-    DefaultTTLState, GATTProxyState, NetworkTransmit, RelayState, SecureNetworkBeaconState,
+    DefaultTTLState, FriendState, GATTProxyState, NetworkTransmit, RelayRetransmit, RelayState,
+    SecureNetworkBeaconState,
 };
+use crate::foundation::{FeatureFlags, Features};
 use crate::mesh::{
-    AppKeyIndex, ElementCount, ElementIndex, IVIndex, IVUpdateFlag, SequenceNumber, IVI, TTL, U24,
+    AppKeyIndex, ElementCount, ElementIndex, IVIndex, IVUpdateFlag, NetKeyIndex, SequenceNumber,
+    IVI, TTL, U24,
 };
 use crate::random::Randomizable;
+use crate::uuid::UUID;
 
 use crate::lower::SegO;
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::vec::Vec;
 use core::convert::TryFrom;
-use core::ops::Range;
+use core::ops::RangeInclusive;
 use core::sync::atomic::Ordering;
 
-#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Default)]
 #[cfg_attr(feature = "serde-1", derive(serde::Serialize, serde::Deserialize))]
 pub struct ModelInfo {
     pub publish: Option<ModelPublishInfo>,
     pub app_key: Vec<AppKeyIndex>,
+    #[cfg_attr(feature = "serde-1", serde(default))]
+    pub subscriptions: BTreeSet<Address>,
+}
+impl ModelInfo {
+    /// Sets the model publication. Publishing to the unassigned address disables publishing so
+    /// `publish` gets cleared instead.
+    pub fn set_publish(&mut self, publish: ModelPublishInfo) {
+        if publish.address.is_unassigned() {
+            self.publish = None;
+        } else {
+            self.publish = Some(publish);
+        }
+    }
+    /// Adds `address` to the subscription list. Returns `false` if `address` is unassigned or a
+    /// unicast address (neither can be subscribed to).
+    pub fn add_subscription(&mut self, address: Address) -> bool {
+        if address.is_unassigned() || address.is_unicast() {
+            return false;
+        }
+        self.subscriptions.insert(address);
+        true
+    }
+    /// Removes `address` from the subscription list. Returns if `address` was subscribed.
+    pub fn remove_subscription(&mut self, address: &Address) -> bool {
+        self.subscriptions.remove(address)
+    }
+    /// Replaces the whole subscription list with `address`. Overwriting with the unassigned
+    /// address just clears the subscription list.
+    pub fn overwrite_subscription(&mut self, address: Address) -> bool {
+        self.subscriptions.clear();
+        if address.is_unassigned() {
+            true
+        } else {
+            self.add_subscription(address)
+        }
+    }
 }
 #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash, Default)]
 #[cfg_attr(feature = "serde-1", derive(serde::Serialize, serde::Deserialize))]
 pub struct Models(BTreeMap<ModelIdentifier, ModelInfo>);
+impl Models {
+    pub fn get(&self, model_identifier: &ModelIdentifier) -> Option<&ModelInfo> {
+        self.0.get(model_identifier)
+    }
+    pub fn get_mut(&mut self, model_identifier: &ModelIdentifier) -> Option<&mut ModelInfo> {
+        self.0.get_mut(model_identifier)
+    }
+    pub fn insert(
+        &mut self,
+        model_identifier: ModelIdentifier,
+        model_info: ModelInfo,
+    ) -> Option<ModelInfo> {
+        self.0.insert(model_identifier, model_info)
+    }
+    pub fn remove(&mut self, model_identifier: &ModelIdentifier) -> Option<ModelInfo> {
+        self.0.remove(model_identifier)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&ModelIdentifier, &ModelInfo)> {
+        self.0.iter()
+    }
+}
+/// A node added to the network by this device (as a provisioner).
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+#[cfg_attr(feature = "serde-1", derive(serde::Serialize, serde::Deserialize))]
+pub struct NodeInfo {
+    pub uuid: UUID,
+    pub element_count: ElementCount,
+    /// The NetKey given to the node in the Provisioning Data.
+    pub net_key_index: NetKeyIndex,
+    pub dev_key: DevKey,
+}
+/// Provisioned nodes by primary address.
+#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash, Default)]
+#[cfg_attr(feature = "serde-1", derive(serde::Serialize, serde::Deserialize))]
+pub struct Nodes(BTreeMap<UnicastAddress, NodeInfo>);
+impl Nodes {
+    pub fn get(&self, primary_address: &UnicastAddress) -> Option<&NodeInfo> {
+        self.0.get(primary_address)
+    }
+    pub fn insert(&mut self, primary_address: UnicastAddress, node: NodeInfo) -> Option<NodeInfo> {
+        self.0.insert(primary_address, node)
+    }
+    pub fn remove(&mut self, primary_address: &UnicastAddress) -> Option<NodeInfo> {
+        self.0.remove(primary_address)
+    }
+    /// Returns the node (and its primary address) that `address` is one of the element addresses
+    /// of.
+    pub fn by_element(&self, address: UnicastAddress) -> Option<(&UnicastAddress, &NodeInfo)> {
+        self.0
+            .range(..=address)
+            .next_back()
+            .filter(|(primary, node)| {
+                u16::from(address) - u16::from(**primary) < u16::from(node.element_count.0)
+            })
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&UnicastAddress, &NodeInfo)> {
+        self.0.iter()
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
 
 #[derive(Default, Debug)]
 #[cfg_attr(feature = "serde-1", derive(serde::Serialize, serde::Deserialize))]
@@ -37,8 +146,36 @@ pub struct ConfigStates {
     pub secure_network_beacon_state: SecureNetworkBeaconState,
     pub default_ttl: DefaultTTLState,
     pub network_transmit: NetworkTransmit,
+    /// How many times (and how far apart) relayed Network PDUs are retransmitted.
+    #[cfg_attr(feature = "serde-1", serde(default))]
+    pub relay_retransmit: RelayRetransmit,
+    #[cfg_attr(feature = "serde-1", serde(default))]
+    pub friend_state: FriendState,
 }
 
+/// Max number of NetKeys and AppKeys a node stores. Adding keys past these limits fails with
+/// `InsufficientResources` instead of growing the key maps forever.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+#[cfg_attr(feature = "serde-1", derive(serde::Serialize, serde::Deserialize))]
+pub struct KeyLimits {
+    pub max_net_keys: usize,
+    pub max_app_keys: usize,
+}
+impl KeyLimits {
+    pub const DEFAULT_MAX_NET_KEYS: usize = 8;
+    pub const DEFAULT_MAX_APP_KEYS: usize = 32;
+    pub fn new(max_net_keys: usize, max_app_keys: usize) -> Self {
+        Self {
+            max_net_keys,
+            max_app_keys,
+        }
+    }
+}
+impl Default for KeyLimits {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_MAX_NET_KEYS, Self::DEFAULT_MAX_APP_KEYS)
+    }
+}
 /// Contains all the persistant Bluetooth Mesh device data. This struct needs to be serialized/saved
 /// somehow when the program shuts down or you will lose all your crypto keys. Normal operations
 /// should use just immutable functions (including increasing SequenceNumbers) but config clients and others will
@@ -54,20 +191,27 @@ pub struct DeviceState {
     config_states: ConfigStates,
 
     security_materials: SecurityMaterials,
+    #[cfg_attr(feature = "serde-1", serde(default))]
+    key_limits: KeyLimits,
+    #[cfg_attr(feature = "serde-1", serde(default))]
+    nodes: Nodes,
 }
 
 impl DeviceState {
     /// Generates a new `DeviceState`. `SecurityMaterials` will be new random keys.
     /// # Panics
-    /// Panics if `element_count == 0 || (primary_address + element_count).is_not_unicast()`
+    /// Panics if `element_count == 0` or the last element's address isn't unicast (see
+    /// [`DeviceState::try_new`]).
     pub fn new(primary_address: UnicastAddress, element_count: ElementCount) -> Self {
-        assert_ne!(element_count.0, 0, "zero element_count given");
-        assert!(
-            UnicastAddress::try_from(u16::from(primary_address) + u16::from(element_count.0))
-                .is_ok(),
-            "primary_address + element_count is non-unicast"
-        );
-        Self {
+        Self::try_new(primary_address, element_count)
+            .expect("zero element_count or elements outside of the unicast range")
+    }
+    /// Generates a new `DeviceState` like [`DeviceState::new`]. Returns `None` if
+    /// `element_count == 0` or the element addresses (`primary_address` to
+    /// `primary_address + element_count - 1`) don't all fit in the unicast range.
+    pub fn try_new(primary_address: UnicastAddress, element_count: ElementCount) -> Option<Self> {
+        Self::last_element_address(primary_address, element_count)?;
+        Some(Self {
             element_count,
             element_address: primary_address,
             seq_counters: core::iter::repeat(SeqCounter::default())
@@ -83,16 +227,26 @@ impl DeviceState {
                 net_key_map: NetKeyMap::new(),
                 app_key_map: AppKeyMap::new(),
             },
-        }
+            key_limits: KeyLimits::default(),
+            nodes: Nodes::default(),
+        })
     }
-    /// Returns the assigned unicast address range.
-    pub fn unicast_range(&self) -> Range<UnicastAddress> {
-        Range {
-            start: self.element_address,
-            end: UnicastAddress::new(
-                u16::from(self.element_address) + u16::from(self.element_count.0),
-            ),
-        }
+    /// Returns the address of the last of `element_count` elements starting at `primary_address`
+    /// or `None` if `element_count == 0` or that address isn't unicast.
+    pub fn last_element_address(
+        primary_address: UnicastAddress,
+        element_count: ElementCount,
+    ) -> Option<UnicastAddress> {
+        let offset = u16::from(element_count.0).checked_sub(1)?;
+        UnicastAddress::try_from(u16::from(primary_address) + offset).ok()
+    }
+    /// Returns the assigned unicast address range (the primary to the last element address).
+    pub fn unicast_range(&self) -> RangeInclusive<UnicastAddress> {
+        RangeInclusive::new(
+            self.element_address,
+            Self::last_element_address(self.element_address, self.element_count)
+                .expect("checked by try_new"),
+        )
     }
     /// Returns the numbers of elements.
     pub fn element_count(&self) -> ElementCount {
@@ -115,23 +269,66 @@ impl DeviceState {
         let range = self.unicast_range();
         if range.contains(&unicast_address) {
             Some(ElementIndex(
-                u8::try_from(u16::from(range.start) - u16::from(unicast_address))
+                u8::try_from(u16::from(unicast_address) - u16::from(*range.start()))
                     .expect("too many elements"),
             ))
         } else {
             None
         }
     }
-    /// IVIndex used for transmitting.
+    /// Returns every address this node responds to. First the element unicast addresses and then
+    /// every group and virtual address any model is subscribed to (each only once).
+    pub fn local_addresses(&self) -> impl Iterator<Item = Address> + '_ {
+        let subscriptions = self
+            .subscribed_addresses()
+            .copied()
+            .collect::<BTreeSet<Address>>();
+        (0..self.element_count.0)
+            .filter_map(move |i| self.element_address(ElementIndex(i)))
+            .map(Address::Unicast)
+            .chain(subscriptions.into_iter())
+    }
+    /// Every model subscription (repeated if several models are subscribed to it).
+    fn subscribed_addresses(&self) -> impl Iterator<Item = &Address> + '_ {
+        self.models
+            .iter()
+            .flat_map(|(_, info)| info.subscriptions.iter())
+    }
+    /// Returns if a message sent to `address` is for this node (see
+    /// [`DeviceState::local_addresses`]). The all-nodes address is always local and a
+    /// `VirtualAddressHash` is local if any subscribed virtual address has that hash. Checked
+    /// against the unicast range and model subscriptions directly so it doesn't allocate.
+    pub fn is_local_address(&self, address: &Address) -> bool {
+        match address {
+            Address::Unassigned => false,
+            Address::Unicast(unicast) => self.unicast_range().contains(unicast),
+            Address::Group(group) if *group == GroupAddress::all_nodes() => true,
+            Address::VirtualHash(hash) => self.subscribed_addresses().any(|local| match local {
+                Address::Virtual(v) => v.hash() == *hash,
+                _ => false,
+            }),
+            _ => self.subscribed_addresses().any(|local| local == address),
+        }
+    }
+    /// IVIndex used for transmitting. While an IV Update is in progress, nodes keep
+    /// transmitting with the previous IVIndex.
     pub fn tx_iv_index(&self) -> IVIndex {
-        self.security_materials.iv_index
+        let iv_index = self.security_materials.iv_index;
+        if bool::from(self.security_materials.iv_update_flag) {
+            iv_index.prev().unwrap_or(iv_index)
+        } else {
+            iv_index
+        }
     }
-    /// IVIndex used for receiving. Will return `None` if no matching `IVIndex` can be found.
-    /// See [`IVIndex::matching_flags`] for more.
+    /// IVIndex used for receiving. It's the current IVIndex if `ivi` matches it or the previous
+    /// one otherwise. Will return `None` if there is no previous `IVIndex`.
     pub fn rx_iv_index(&self, ivi: IVI) -> Option<IVIndex> {
-        self.security_materials
-            .iv_index
-            .matching_flags(ivi, self.security_materials.iv_update_flag)
+        let iv_index = self.security_materials.iv_index;
+        if iv_index.ivi() == ivi {
+            Some(iv_index)
+        } else {
+            iv_index.prev()
+        }
     }
     pub fn iv_index(&self) -> IVIndex {
         self.security_materials.iv_index
@@ -173,15 +370,315 @@ impl DeviceState {
             .get_mut(usize::from(element_index.0))
             .expect("element_index out of bounds")
     }
+    /// Hands out the next Sequence Number for the element at `element_index`. If
+    /// `SeqAllocation::new_block` is set, the `DeviceState` has to be saved before the Sequence
+    /// Number is used. Returns `SeqError::Exhausted` instead of wrapping once the element runs
+    /// out.
+    /// # Panics
+    /// Panics if `element_index >= element_count`.
+    pub fn next_seq(&self, element_index: ElementIndex) -> Result<SeqAllocation, SeqError> {
+        self.next_seqs(element_index, 1)
+    }
+    /// Same as [`DeviceState::next_seq`] but hands out `count` consecutive Sequence Numbers (one
+    /// for each segment of a segmented message).
+    /// # Panics
+    /// Panics if `element_index >= element_count`.
+    pub fn next_seqs(
+        &self,
+        element_index: ElementIndex,
+        count: u32,
+    ) -> Result<SeqAllocation, SeqError> {
+        let (range, new_block) = self
+            .seq_counter(element_index)
+            .allocate(count)
+            .ok_or(SeqError::Exhausted)?;
+        Ok(SeqAllocation {
+            seq: range.start(),
+            count,
+            new_block,
+        })
+    }
+    /// If any element ran out of Sequence Numbers (see [`DeviceState::next_seq`]).
+    pub fn is_seq_exhausted(&self) -> bool {
+        self.seq_counters.iter().any(SeqCounter::is_exhausted)
+    }
+    /// Starts every element's `SeqCounter` over from 0. Only valid once the IV Index used for
+    /// transmitting changes.
+    pub fn reset_seq_counters(&mut self) {
+        for counter in self.seq_counters.iter_mut() {
+            counter.set_seq(SequenceNumber(U24::new(0)));
+        }
+    }
+    pub fn models(&self) -> &Models {
+        &self.models
+    }
+    pub fn models_mut(&mut self) -> &mut Models {
+        &mut self.models
+    }
     pub fn config_states(&self) -> &ConfigStates {
         &self.config_states
     }
     pub fn config_states_mut(&mut self) -> &mut ConfigStates {
         &mut self.config_states
     }
+    /// TTL outgoing messages without their own TTL are sent with.
     pub fn default_ttl(&self) -> TTL {
         TTL::new(self.config_states.default_ttl.into())
     }
+    pub fn default_ttl_state(&self) -> DefaultTTLState {
+        self.config_states.default_ttl
+    }
+    pub fn set_default_ttl(&mut self, default_ttl: DefaultTTLState) {
+        self.config_states.default_ttl = default_ttl
+    }
+    pub fn relay_state(&self) -> RelayState {
+        self.config_states.relay_state
+    }
+    /// How many times (and how far apart) relayed Network PDUs are advertised.
+    pub fn relay_retransmit(&self) -> RelayRetransmit {
+        self.config_states.relay_retransmit
+    }
+    /// Sets the Relay and Relay Retransmit states (like a Config Relay Set). A node without the
+    /// Relay feature (`RelayState::NotSupported`) keeps both states as they are.
+    pub fn set_relay(&mut self, relay_state: RelayState, relay_retransmit: RelayRetransmit) {
+        if self.config_states.relay_state != RelayState::NotSupported {
+            self.config_states.relay_state = relay_state;
+            self.config_states.relay_retransmit = relay_retransmit;
+        }
+    }
+    /// How many times (and how far apart) Network PDUs this node originates are advertised.
+    pub fn network_transmit(&self) -> NetworkTransmit {
+        self.config_states.network_transmit
+    }
+    pub fn set_network_transmit(&mut self, network_transmit: NetworkTransmit) {
+        self.config_states.network_transmit = network_transmit
+    }
+    pub fn gatt_proxy_state(&self) -> GATTProxyState {
+        self.config_states.gatt_proxy_state
+    }
+    /// Sets the GATT Proxy state (like a Config GATT Proxy Set). A node without the Proxy feature
+    /// (`GATTProxyState::NotSupported`) keeps it that way.
+    pub fn set_gatt_proxy(&mut self, gatt_proxy_state: GATTProxyState) {
+        if self.config_states.gatt_proxy_state != GATTProxyState::NotSupported {
+            self.config_states.gatt_proxy_state = gatt_proxy_state;
+        }
+    }
+    pub fn friend_state(&self) -> FriendState {
+        self.config_states.friend_state
+    }
+    /// Sets the Friend state (like a Config Friend Set). A node without the Friend feature
+    /// (`FriendState::NotSupported`) keeps it that way.
+    pub fn set_friend(&mut self, friend_state: FriendState) {
+        if self.config_states.friend_state != FriendState::NotSupported {
+            self.config_states.friend_state = friend_state;
+        }
+    }
+    /// Features this node supports (their states aren't `NotSupported`), as listed in the
+    /// Composition Data. Low Power isn't implemented so it's never supported.
+    pub fn supported_features(&self) -> Features {
+        let mut features = Features::default();
+        if self.config_states.relay_state != RelayState::NotSupported {
+            features.set(FeatureFlags::Relay);
+        }
+        if self.config_states.gatt_proxy_state != GATTProxyState::NotSupported {
+            features.set(FeatureFlags::Proxy);
+        }
+        if self.config_states.friend_state != FriendState::NotSupported {
+            features.set(FeatureFlags::Friend);
+        }
+        features
+    }
+    /// Features currently enabled (sent in Heartbeats).
+    pub fn enabled_features(&self) -> Features {
+        let mut features = Features::default();
+        if self.config_states.relay_state.is_enabled() {
+            features.set(FeatureFlags::Relay);
+        }
+        if self.config_states.gatt_proxy_state.is_enabled() {
+            features.set(FeatureFlags::Proxy);
+        }
+        if self.config_states.friend_state.is_enabled() {
+            features.set(FeatureFlags::Friend);
+        }
+        features
+    }
+    /// Enables or disables the Relay, Proxy or Friend `feature` (see [`DeviceState::set_relay`],
+    /// [`DeviceState::set_gatt_proxy`] and [`DeviceState::set_friend`]). Returns `false` if this
+    /// node doesn't support `feature` (see [`DeviceState::supported_features`]).
+    pub fn set_feature_enabled(&mut self, feature: FeatureFlags, enabled: bool) -> bool {
+        if !self.supported_features().get(feature) {
+            return false;
+        }
+        match (feature, enabled) {
+            (FeatureFlags::Relay, true) => {
+                self.set_relay(RelayState::Enabled, self.relay_retransmit())
+            }
+            (FeatureFlags::Relay, false) => {
+                self.set_relay(RelayState::Disabled, self.relay_retransmit())
+            }
+            (FeatureFlags::Proxy, true) => self.set_gatt_proxy(GATTProxyState::Enabled),
+            (FeatureFlags::Proxy, false) => self.set_gatt_proxy(GATTProxyState::Disabled),
+            (FeatureFlags::Friend, true) => self.set_friend(FriendState::Enabled),
+            (FeatureFlags::Friend, false) => self.set_friend(FriendState::Disabled),
+            (FeatureFlags::LowPower, _) => return false,
+        }
+        true
+    }
+    pub fn key_limits(&self) -> KeyLimits {
+        self.key_limits
+    }
+    /// Sets the `KeyLimits`. Keys already stored past the new limits are kept but no more can
+    /// be added.
+    pub fn set_key_limits(&mut self, key_limits: KeyLimits) {
+        self.key_limits = key_limits;
+    }
+    /// Nodes this device provisioned.
+    pub fn nodes(&self) -> &Nodes {
+        &self.nodes
+    }
+    pub fn nodes_mut(&mut self) -> &mut Nodes {
+        &mut self.nodes
+    }
+    /// DevKey securing Config messages between this node and `address`. That's the DevKey of the
+    /// provisioned node `address` belongs to or this node's own DevKey if it's not a known node.
+    pub fn dev_key_for(&self, address: UnicastAddress) -> &DevKey {
+        self.nodes
+            .by_element(address)
+            .map_or(&self.security_materials.dev_key, |(_, node)| &node.dev_key)
+    }
+    /// Returns the first unicast address after this node's and every provisioned node's
+    /// addresses or `None` if there are none left.
+    pub fn next_node_address(&self) -> Option<UnicastAddress> {
+        let own_end = u16::from(*self.unicast_range().end()) + 1;
+        let end = self
+            .nodes
+            .iter()
+            .map(|(&primary, node)| u16::from(primary) + u16::from(node.element_count.0))
+            .fold(own_end, u16::max);
+        UnicastAddress::try_from(end).ok()
+    }
+    /// Adds `net_key` under `net_key_index`. Adding the same key again is allowed.
+    pub fn add_net_key(
+        &mut self,
+        net_key_index: NetKeyIndex,
+        net_key: &NetKey,
+    ) -> Result<(), NetKeyError> {
+        let max_net_keys = self.key_limits.max_net_keys;
+        let net_key_map = &mut self.security_materials.net_key_map;
+        match net_key_map.get_keys(net_key_index) {
+            Some(current) if current.tx_key().net_key() == net_key => Ok(()),
+            Some(_) => Err(NetKeyError::KeyIndexAlreadyStored),
+            None if net_key_map.map.len() >= max_net_keys => {
+                Err(NetKeyError::InsufficientResources)
+            }
+            None => {
+                net_key_map.insert(net_key_index, net_key);
+                Ok(())
+            }
+        }
+    }
+    /// Starts a Key Refresh of the NetKey under `net_key_index` by installing `net_key` as its
+    /// new key (Phase 1). Both keys are used for receiving. See `NetKeyMap::update`.
+    pub fn update_net_key(
+        &mut self,
+        net_key_index: NetKeyIndex,
+        net_key: &NetKey,
+    ) -> Result<(), NetKeyError> {
+        self.security_materials
+            .net_key_map
+            .update(net_key_index, net_key)
+    }
+    /// Returns the Key Refresh phase of the NetKey under `net_key_index`.
+    pub fn key_refresh_phase(&self, net_key_index: NetKeyIndex) -> Option<KeyRefreshPhases> {
+        self.security_materials
+            .net_key_map
+            .get_keys(net_key_index)
+            .map(KeyPhase::phase)
+    }
+    /// Moves the NetKey under `net_key_index` and every AppKey bound to it to the Key Refresh
+    /// `phase` (see `NetKeyMap::set_phase`). Returns the phase the NetKey ends up in.
+    pub fn set_key_refresh_phase(
+        &mut self,
+        net_key_index: NetKeyIndex,
+        phase: KeyRefreshPhases,
+    ) -> Result<KeyRefreshPhases, NetKeyError> {
+        let new_phase = self
+            .security_materials
+            .net_key_map
+            .set_phase(net_key_index, phase)?;
+        self.security_materials
+            .app_key_map
+            .set_phase(net_key_index, phase);
+        Ok(new_phase)
+    }
+    /// Adds `app_key` under `app_key_index` bound to the NetKey under `net_key_index`. Adding
+    /// the same key again is allowed.
+    pub fn add_app_key(
+        &mut self,
+        net_key_index: NetKeyIndex,
+        app_key_index: AppKeyIndex,
+        app_key: AppKey,
+    ) -> Result<(), AppKeyError> {
+        if self
+            .security_materials
+            .net_key_map
+            .get_keys(net_key_index)
+            .is_none()
+        {
+            return Err(AppKeyError::InvalidNetKeyIndex);
+        }
+        let max_app_keys = self.key_limits.max_app_keys;
+        let app_key_map = &mut self.security_materials.app_key_map;
+        match app_key_map.get_key(app_key_index) {
+            Some(current)
+                if current.app_key == app_key && current.net_key_index == net_key_index =>
+            {
+                Ok(())
+            }
+            Some(_) => Err(AppKeyError::KeyIndexAlreadyStored),
+            None if app_key_map.map.len() >= max_app_keys => {
+                Err(AppKeyError::InsufficientResources)
+            }
+            None => {
+                app_key_map.insert(net_key_index, app_key_index, app_key);
+                Ok(())
+            }
+        }
+    }
+    /// Every AppKey with its index. During a Key Refresh, only the key used for transmitting is
+    /// returned.
+    pub fn app_keys(
+        &self,
+    ) -> impl Iterator<Item = (AppKeyIndex, &'_ ApplicationSecurityMaterials)> + '_ {
+        self.security_materials
+            .app_key_map
+            .map
+            .iter()
+            .map(|(&index, phase)| (index, phase.tx_key()))
+    }
+    /// Removes the AppKey under `app_key_index` and unbinds it from every model. Publications
+    /// using it are cleared. Returns `AppKeyError::InvalidAppKeyIndex` if there's no AppKey under
+    /// `app_key_index`.
+    pub fn remove_app_key(
+        &mut self,
+        app_key_index: AppKeyIndex,
+    ) -> Result<ApplicationSecurityMaterials, AppKeyError> {
+        let removed = self
+            .security_materials
+            .app_key_map
+            .remove_key(app_key_index)
+            .ok_or(AppKeyError::InvalidAppKeyIndex)?;
+        for info in self.models.0.values_mut() {
+            info.app_key.retain(|&index| index != app_key_index);
+            if info
+                .publish
+                .map_or(false, |publish| publish.app_key_index == app_key_index)
+            {
+                info.publish = None;
+            }
+        }
+        Ok(*removed.tx_key())
+    }
 }
 
 #[derive(Default)]
@@ -192,6 +689,7 @@ pub struct DeviceStateBuilder {
     pub models: Option<Models>,
     pub config_states: Option<ConfigStates>,
     pub security_materials: Option<SecurityMaterials>,
+    pub key_limits: Option<KeyLimits>,
 }
 impl DeviceStateBuilder {
     pub fn empty() -> Self {
@@ -205,6 +703,7 @@ impl DeviceStateBuilder {
             models: None,
             config_states: None,
             security_materials: None,
+            key_limits: None,
         }
     }
     pub fn element_count(mut self, element_count: ElementCount) -> Self {
@@ -219,6 +718,11 @@ impl DeviceStateBuilder {
         self.config_states = Some(config_states);
         self
     }
+    /// Defaults to `KeyLimits::default()` if not set.
+    pub fn key_limits(mut self, key_limits: KeyLimits) -> Self {
+        self.key_limits = Some(key_limits);
+        self
+    }
     pub fn finish(self) -> Option<DeviceState> {
         Some(DeviceState {
             element_address: self.element_address?,
@@ -227,6 +731,8 @@ impl DeviceStateBuilder {
             models: self.models?,
             config_states: self.config_states?,
             security_materials: self.security_materials?,
+            key_limits: self.key_limits.unwrap_or_default(),
+            nodes: Nodes::default(),
         })
     }
 }
@@ -279,48 +785,430 @@ impl Iterator for SeqRange {
         }
     }
 }
+/// Number of Sequence Numbers reserved at once by a `SeqCounter`. The `DeviceState` only has to
+/// be saved once per block instead of for every message.
+pub const SEQ_BLOCK_LEN: u32 = 256;
+/// Returned by [`DeviceState::next_seq`] when an element runs out of Sequence Numbers.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum SeqError {
+    /// The 24-bit sequence space is used up. Sequence Numbers never wrap so nothing can be sent
+    /// until an IV Update (see `IVUpdateState::start`) moves the node to the next IV Index,
+    /// which resets the counters.
+    Exhausted,
+}
+/// Sequence Numbers from [`DeviceState::next_seq`] or [`DeviceState::next_seqs`].
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct SeqAllocation {
+    /// The first Sequence Number.
+    pub seq: SequenceNumber,
+    pub count: u32,
+    /// A new block of `SEQ_BLOCK_LEN` Sequence Numbers was reserved for `seq`. The `DeviceState`
+    /// has to be saved before `seq` is used so it's never reused after a restart.
+    pub new_block: bool,
+}
+impl SeqAllocation {
+    pub fn seqs(&self) -> SeqRange {
+        SeqRange(self.seq.0.value()..self.seq.0.value() + self.count)
+    }
+}
+/// Saved form of a `SeqCounter`. Only the reserved watermark is saved so a restart resumes from
+/// it and skips whatever was left in the last block.
+#[cfg(feature = "serde-1")]
+#[derive(Copy, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+struct SeqWatermark(SequenceNumber);
+#[cfg(feature = "serde-1")]
+impl From<SeqWatermark> for SeqCounter {
+    fn from(watermark: SeqWatermark) -> Self {
+        SeqCounter::new(watermark.0)
+    }
+}
+#[cfg(feature = "serde-1")]
+impl From<SeqCounter> for SeqWatermark {
+    fn from(counter: SeqCounter) -> Self {
+        SeqWatermark(counter.reserved())
+    }
+}
 /// Atomic SeqCounter so no PDUs get the same SeqNumber. Sequence Numbers are a finite resource
 /// (only 24-bits) that only get reset every IVIndex update. Also segmented PDUs require sequential
 /// Sequence Number.
+///
+/// Sequence Numbers are reserved `SEQ_BLOCK_LEN` at a time ahead of use. Only the reserved
+/// watermark is saved so Sequence Numbers handed out before a restart are never handed out again.
 #[derive(Default, Debug)]
 #[cfg_attr(feature = "serde-1", derive(serde::Serialize, serde::Deserialize))]
-pub struct SeqCounter(core::sync::atomic::AtomicU32);
+#[cfg_attr(
+    feature = "serde-1",
+    serde(from = "SeqWatermark", into = "SeqWatermark")
+)]
+pub struct SeqCounter {
+    next: core::sync::atomic::AtomicU32,
+    reserved: core::sync::atomic::AtomicU32,
+}
 impl SeqCounter {
     pub fn new(start_seq: SequenceNumber) -> Self {
-        Self(core::sync::atomic::AtomicU32::new(start_seq.0.value()))
+        Self {
+            next: core::sync::atomic::AtomicU32::new(start_seq.0.value()),
+            reserved: core::sync::atomic::AtomicU32::new(start_seq.0.value()),
+        }
+    }
+    /// Allocates `amount` Sequence Numbers and returns them with if a new block had to be reserved
+    /// for them. Returns `None` if the Sequence Numbers would overflow 24-bits.
+    fn allocate(&self, amount: u32) -> Option<(SeqRange, bool)> {
+        let max = U24::max_value().value();
+        let next = self.next.fetch_add(amount, Ordering::SeqCst);
+        let end = next.saturating_add(amount);
+        if next >= max || end > max {
+            // Overflow of Seq Number
+            self.next.store(max, Ordering::SeqCst);
+            return None;
+        }
+        let new_block = end > self.reserved.load(Ordering::SeqCst) && {
+            let watermark = next.saturating_add(SEQ_BLOCK_LEN).max(end).min(max);
+            self.reserved.fetch_max(watermark, Ordering::SeqCst) < end
+        };
+        Some((SeqRange(next..end), new_block))
     }
     /// Allocates a or some SequenceNumbers and increments the internal counter by amount. Allocating
     /// `amount` Sequence Numbers is useful for Segmented Transport PDUs.
-    /// Returns `None` if `SequenceNumber` is at its max or will overflow.
+    /// Returns `None` if `SequenceNumber` is at its max or will overflow. The stack allocates
+    /// through [`DeviceState::next_seqs`] instead, which also reports newly reserved blocks.
     pub fn inc_seq(&self, amount: u32) -> Option<SeqRange> {
-        let next = self
-            .0
-            .fetch_add(amount, core::sync::atomic::Ordering::SeqCst);
-        if next >= U24::max_value().value() {
-            // Overflow of Seq Number
-            self.0.store(
-                U24::max_value().value(),
-                core::sync::atomic::Ordering::SeqCst,
-            );
-            None
-        } else {
-            Some(SeqRange(next..next + amount))
-        }
+        self.allocate(amount).map(|(range, _)| range)
     }
     /// Set the atomic sequence number. This should only really be called when initally setuping up
     /// the `SeqCounter` or reseting it. Setting `SeqCounter` to an older value may cause PDUs to be
     /// dropped by message recipients.
     pub fn set_seq(&mut self, new_seq: SequenceNumber) {
-        *self.0.get_mut() = new_seq.0.value()
+        *self.next.get_mut() = new_seq.0.value();
+        *self.reserved.get_mut() = new_seq.0.value();
+    }
+    /// If every Sequence Number was handed out. Only an IV Update resets the counter.
+    pub fn is_exhausted(&self) -> bool {
+        self.next.load(Ordering::SeqCst) >= U24::max_value().value()
     }
     pub fn check(&self) -> SequenceNumber {
-        SequenceNumber(U24::new(self.0.load(Ordering::SeqCst)))
+        SequenceNumber(U24::new(self.next.load(Ordering::SeqCst)))
+    }
+    /// Every Sequence Number below the watermark may have been handed out. This is what gets
+    /// saved and where the counter resumes from after a restart.
+    pub fn reserved(&self) -> SequenceNumber {
+        SequenceNumber(U24::new(self.reserved.load(Ordering::SeqCst)))
     }
 }
 impl Clone for SeqCounter {
     fn clone(&self) -> Self {
-        SeqCounter(core::sync::atomic::AtomicU32::new(
-            self.0.load(Ordering::SeqCst),
-        ))
+        SeqCounter {
+            next: core::sync::atomic::AtomicU32::new(self.next.load(Ordering::SeqCst)),
+            reserved: core::sync::atomic::AtomicU32::new(self.reserved.load(Ordering::SeqCst)),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::VirtualAddress;
+    use crate::foundation::publication::{PublishPeriod, PublishRetransmit, StepResolution, Steps};
+    use crate::mesh::{KeyIndex, ModelID};
+
+    fn publish_info(address: Address) -> ModelPublishInfo {
+        ModelPublishInfo {
+            address,
+            app_key_index: AppKeyIndex(KeyIndex::new(0)),
+            credential_flag: false,
+            ttl: None,
+            period: PublishPeriod::new(StepResolution::Second1, Steps::new(1)),
+            retransmit: PublishRetransmit::from(0),
+        }
+    }
+    #[test]
+    fn test_unassigned_publish_clears() {
+        let mut info = ModelInfo::default();
+        info.set_publish(publish_info(Address::from(0xC001)));
+        assert_eq!(info.publish.map(|p| p.address), Some(Address::from(0xC001)));
+        info.set_publish(publish_info(Address::Unassigned));
+        assert_eq!(info.publish, None);
+    }
+    #[test]
+    fn test_unassigned_subscription_clears() {
+        let mut info = ModelInfo::default();
+        let group = Address::Group(GroupAddress::new(0xC001));
+        assert!(!info.add_subscription(Address::Unassigned));
+        assert!(info.add_subscription(group));
+        assert!(info.subscriptions.contains(&group));
+        assert!(info.overwrite_subscription(Address::Unassigned));
+        assert!(info.subscriptions.is_empty());
+    }
+    #[test]
+    fn test_element_index() {
+        let device_state = DeviceState::new(UnicastAddress::new(0x0010), ElementCount(3));
+        let index = |address| device_state.element_index(UnicastAddress::new(address));
+        assert_eq!(index(0x000F), None);
+        assert_eq!(index(0x0010), Some(ElementIndex(0)));
+        assert_eq!(index(0x0012), Some(ElementIndex(2)));
+        assert_eq!(index(0x0013), None);
+        assert_eq!(
+            device_state.element_address(ElementIndex(0)),
+            Some(UnicastAddress::new(0x0010))
+        );
+        assert_eq!(
+            device_state.element_address(ElementIndex(2)),
+            Some(UnicastAddress::new(0x0012))
+        );
+        assert_eq!(device_state.element_address(ElementIndex(3)), None);
+        for i in 0..3 {
+            let address = device_state
+                .element_address(ElementIndex(i))
+                .expect("element in range");
+            assert_eq!(device_state.element_index(address), Some(ElementIndex(i)));
+        }
+    }
+    #[test]
+    fn test_try_new_unicast_range() {
+        let last = |primary, count| {
+            DeviceState::last_element_address(UnicastAddress::new(primary), ElementCount(count))
+        };
+        assert_eq!(last(0x0001, 1), Some(UnicastAddress::new(0x0001)));
+        assert_eq!(last(0x7FF0, 16), Some(UnicastAddress::new(0x7FFF)));
+        assert_eq!(last(0x7FF0, 17), None);
+        assert_eq!(last(0x0001, 0), None);
+        let device_state = DeviceState::try_new(UnicastAddress::new(0x7FFE), ElementCount(2))
+            .expect("last element is 0x7FFF");
+        assert_eq!(
+            device_state.unicast_range(),
+            UnicastAddress::new(0x7FFE)..=UnicastAddress::new(0x7FFF)
+        );
+        assert_eq!(device_state.next_node_address(), None);
+        assert!(DeviceState::try_new(UnicastAddress::new(0x7FFE), ElementCount(3)).is_none());
+        assert!(DeviceState::try_new(UnicastAddress::new(0x0001), ElementCount(0)).is_none());
+    }
+    #[test]
+    fn test_local_addresses() {
+        let mut device_state = DeviceState::new(UnicastAddress::new(0x0002), ElementCount(2));
+        let group = Address::Group(GroupAddress::new(0xC001));
+        let virtual_address = VirtualAddress::new(&UUID([0x42; 16]));
+        for (model_id, subscriptions) in &[
+            (0x1000, [group, Address::Virtual(virtual_address)]),
+            (0x1001, [group, Address::Group(GroupAddress::new(0xC002))]),
+        ] {
+            let mut info = ModelInfo::default();
+            for address in subscriptions {
+                assert!(info.add_subscription(*address));
+            }
+            device_state
+                .models_mut()
+                .insert(ModelIdentifier::new_sig(ModelID(*model_id)), info);
+        }
+        let local = device_state.local_addresses().collect::<Vec<_>>();
+        assert_eq!(&local[..2], &[Address::from(0x0002), Address::from(0x0003)]);
+        assert_eq!(local.len(), 5);
+        assert!(local.contains(&Address::Virtual(virtual_address)));
+        assert!(device_state.is_local_address(&group));
+        assert!(device_state.is_local_address(&Address::VirtualHash(virtual_address.hash())));
+        assert!(device_state.is_local_address(&Address::Group(GroupAddress::all_nodes())));
+        assert!(!device_state.is_local_address(&Address::from(0x0004)));
+        assert!(!device_state.is_local_address(&Address::from(0xC003)));
+    }
+    #[test]
+    fn test_app_keys() {
+        let mut device_state = DeviceState::new(UnicastAddress::new(0x0001), ElementCount(1));
+        let net_key_index = NetKeyIndex(KeyIndex::new(1));
+        let app_key_index = AppKeyIndex(KeyIndex::new(2));
+        let app_key = AppKey::new_bytes([0x02; 16]);
+        assert_eq!(
+            device_state.add_app_key(net_key_index, app_key_index, app_key),
+            Err(AppKeyError::InvalidNetKeyIndex)
+        );
+        device_state
+            .add_net_key(net_key_index, &NetKey::new_bytes([0x01; 16]))
+            .unwrap();
+        assert_eq!(
+            device_state.add_app_key(net_key_index, app_key_index, app_key),
+            Ok(())
+        );
+        assert_eq!(
+            device_state.app_keys().collect::<Vec<_>>(),
+            vec![(
+                app_key_index,
+                &ApplicationSecurityMaterials::new(app_key, net_key_index)
+            )]
+        );
+
+        let model = ModelIdentifier::new_sig(ModelID(0x1000));
+        let mut info = ModelInfo::default();
+        info.app_key.push(app_key_index);
+        let mut publish = publish_info(Address::from(0xC001));
+        publish.app_key_index = app_key_index;
+        info.set_publish(publish);
+        device_state.models_mut().insert(model, info);
+
+        assert_eq!(
+            device_state
+                .remove_app_key(app_key_index)
+                .map(|m| m.app_key),
+            Ok(app_key)
+        );
+        assert_eq!(device_state.app_keys().count(), 0);
+        let info = device_state.models().get(&model).unwrap();
+        assert!(info.app_key.is_empty());
+        assert_eq!(info.publish, None);
+        assert_eq!(
+            device_state.remove_app_key(app_key_index),
+            Err(AppKeyError::InvalidAppKeyIndex)
+        );
+    }
+    #[test]
+    fn test_next_seq() {
+        let device_state = DeviceState::new(UnicastAddress::new(0x0001), ElementCount(2));
+        let seq = |seq: u32| SequenceNumber(U24::new(seq));
+        let allocation = |first: u32, new_block: bool| SeqAllocation {
+            seq: seq(first),
+            count: 1,
+            new_block,
+        };
+        let element = ElementIndex(1);
+        // Each block is exactly `SEQ_BLOCK_LEN` long.
+        assert_eq!(device_state.next_seq(element), Ok(allocation(0, true)));
+        for i in 1..SEQ_BLOCK_LEN {
+            assert_eq!(device_state.next_seq(element), Ok(allocation(i, false)));
+        }
+        assert_eq!(
+            device_state.seq_counter(element).reserved(),
+            seq(SEQ_BLOCK_LEN)
+        );
+        assert_eq!(
+            device_state.next_seq(element),
+            Ok(allocation(SEQ_BLOCK_LEN, true))
+        );
+        // Other elements have their own counters.
+        assert_eq!(
+            device_state.next_seq(ElementIndex(0)),
+            Ok(allocation(0, true))
+        );
+        // A restart resumes from the watermark and skips the rest of the block.
+        let reserved = device_state.seq_counter(element).reserved();
+        assert_eq!(reserved, seq(2 * SEQ_BLOCK_LEN));
+        let restarted = SeqCounter::new(reserved);
+        assert_eq!(restarted.inc_seq(1).map(|r| r.start()), Some(reserved));
+    }
+    #[test]
+    fn test_next_seqs_across_blocks() {
+        let device_state = DeviceState::new(UnicastAddress::new(0x0001), ElementCount(1));
+        let element = ElementIndex(0);
+        device_state
+            .next_seqs(element, SEQ_BLOCK_LEN - 2)
+            .expect("fresh counter");
+        // A segmented message running over the end of the block reserves the next one.
+        let allocation = device_state
+            .next_seqs(element, 4)
+            .expect("plenty of Sequence Numbers left");
+        assert!(allocation.new_block);
+        assert_eq!(
+            allocation.seqs().collect::<Vec<_>>(),
+            (SEQ_BLOCK_LEN - 2..SEQ_BLOCK_LEN + 2)
+                .map(|seq| SequenceNumber(U24::new(seq)))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            device_state.seq_counter(element).reserved(),
+            SequenceNumber(U24::new(2 * SEQ_BLOCK_LEN - 2))
+        );
+        // The watermark is what gets saved so a restarted counter never goes back.
+        let restarted = SeqCounter::new(device_state.seq_counter(element).reserved());
+        assert!(restarted.check().0.value() >= allocation.seqs().end().0.value());
+    }
+    #[test]
+    fn test_seq_exhausted() {
+        let mut device_state = DeviceState::new(UnicastAddress::new(0x0001), ElementCount(1));
+        let max = U24::max_value().value();
+        device_state
+            .seq_counter_mut(ElementIndex(0))
+            .set_seq(SequenceNumber(U24::new(max - 3)));
+        // Not enough left for a 4 segment message.
+        assert_eq!(
+            device_state.next_seqs(ElementIndex(0), 4).map(|a| a.seq),
+            Err(SeqError::Exhausted)
+        );
+        assert!(device_state.is_seq_exhausted());
+        device_state
+            .seq_counter_mut(ElementIndex(0))
+            .set_seq(SequenceNumber(U24::new(max - 1)));
+        assert!(!device_state.is_seq_exhausted());
+        assert_eq!(
+            device_state.next_seq(ElementIndex(0)).map(|a| a.seq),
+            Ok(SequenceNumber(U24::new(max - 1)))
+        );
+        // The block is cut short at the end of the sequence space.
+        assert_eq!(
+            device_state.seq_counter(ElementIndex(0)).reserved(),
+            SequenceNumber(U24::new(max))
+        );
+        assert_eq!(
+            device_state.next_seq(ElementIndex(0)),
+            Err(SeqError::Exhausted)
+        );
+        // Never wraps.
+        assert_eq!(
+            device_state.next_seq(ElementIndex(0)),
+            Err(SeqError::Exhausted)
+        );
+        assert!(device_state.is_seq_exhausted());
+        device_state.reset_seq_counters();
+        assert!(!device_state.is_seq_exhausted());
+        assert_eq!(
+            device_state.next_seq(ElementIndex(0)).map(|a| a.seq),
+            Ok(SequenceNumber(U24::new(0)))
+        );
+    }
+    #[test]
+    fn test_next_node_address() {
+        let mut device_state = DeviceState::new(UnicastAddress::new(0x0001), ElementCount(1));
+        assert_eq!(
+            device_state.next_node_address(),
+            Some(UnicastAddress::new(0x0002))
+        );
+        let node = NodeInfo {
+            uuid: UUID([0x42; 16]),
+            element_count: ElementCount(3),
+            net_key_index: NetKeyIndex(KeyIndex::new(0)),
+            dev_key: DevKey::new_bytes([0x01; 16]),
+        };
+        device_state
+            .nodes_mut()
+            .insert(UnicastAddress::new(0x0010), node);
+        device_state
+            .nodes_mut()
+            .insert(UnicastAddress::new(0x0002), node);
+        assert_eq!(
+            device_state.next_node_address(),
+            Some(UnicastAddress::new(0x0013))
+        );
+        assert_eq!(
+            device_state
+                .nodes()
+                .by_element(UnicastAddress::new(0x0012))
+                .map(|(primary, _)| *primary),
+            Some(UnicastAddress::new(0x0010))
+        );
+        assert!(device_state
+            .nodes()
+            .by_element(UnicastAddress::new(0x0013))
+            .is_none());
+        assert!(device_state
+            .nodes()
+            .by_element(UnicastAddress::new(0x0001))
+            .is_none());
+        assert_eq!(
+            device_state.dev_key_for(UnicastAddress::new(0x0003)),
+            &node.dev_key
+        );
+        assert_eq!(
+            device_state.dev_key_for(UnicastAddress::new(0x0005)),
+            &device_state.security_materials().dev_key
+        );
+        device_state
+            .nodes_mut()
+            .insert(UnicastAddress::new(0x7FFD), node);
+        assert_eq!(device_state.next_node_address(), None);
     }
 }
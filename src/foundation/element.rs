@@ -1,11 +1,12 @@
 //use crate::access::ModelIdentifier;
 use crate::access::{ModelIdentifier, SigModelID, VendorModelID};
 
+use crate::address::UnicastAddress;
 use crate::bytes::ToFromBytesEndian;
-use crate::mesh::ModelID;
+use crate::mesh::{ElementIndex, ModelID};
 
 use alloc::vec::Vec;
-use core::convert::TryInto;
+use core::convert::{TryFrom, TryInto};
 
 //use alloc::collections::BTreeMap;
 const MAX_MODELS: usize = 255;
@@ -60,7 +61,7 @@ impl ElementComposition {
                 None
             } else {
                 let mut sig_models = Vec::new();
-                let mut pos = 3;
+                let mut pos = Self::min_byte_len();
                 for _ in 0..num_s {
                     sig_models.push(ModelIdentifier::unpack_from(
                         &buf[pos..pos + ModelIdentifier::sig_byte_len()],
@@ -74,7 +75,6 @@ impl ElementComposition {
                     )?);
                     pos += ModelIdentifier::vendor_byte_len();
                 }
-                debug_assert_eq!(pos, buf.len());
                 Some(Self {
                     location: loc,
                     sig_models,
@@ -89,7 +89,7 @@ impl ElementComposition {
         buf[0..2].copy_from_slice(&self.location.to_bytes_le());
         buf[2] = self.num_s();
         buf[3] = self.num_v();
-        let mut position = 0_usize;
+        let mut position = Self::min_byte_len();
         for model in self.sig_models.iter() {
             // This could be change to a debug_assert.
             assert!(model.is_sig(), "non SIG model in sig_models");
@@ -122,6 +122,38 @@ impl ElementComposition {
             self.vendor_models.push(model)
         }
     }
+    /// Returns `true` if `model` is one of this element's SIG or vendor models.
+    pub fn has_model(&self, model: &ModelIdentifier) -> bool {
+        if model.is_sig() {
+            self.sig_models.contains(model)
+        } else {
+            self.vendor_models.contains(model)
+        }
+    }
+}
+/// Returns the unicast address of the element at `element_index` on a node with `primary` as its
+/// primary address or `None` if it would be past the end of the unicast range.
+pub fn element_address(
+    primary: UnicastAddress,
+    element_index: ElementIndex,
+) -> Option<UnicastAddress> {
+    u16::from(primary)
+        .checked_add(u16::from(element_index.0))
+        .and_then(|address| UnicastAddress::try_from(address).ok())
+}
+/// Where a model lives on a node according to its Composition Data.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct ModelLocation {
+    pub element_index: ElementIndex,
+    pub location: Location,
+    pub model: ModelIdentifier,
+}
+impl ModelLocation {
+    /// Returns the address of the element the model is on (see [`element_address`]). This is
+    /// the address Config messages like Model App Bind target.
+    pub fn element_address(&self, primary: UnicastAddress) -> Option<UnicastAddress> {
+        element_address(primary, self.element_index)
+    }
 }
 
 const LOCATION_LEN: usize = 2;
@@ -237,6 +269,40 @@ impl ToFromBytesEndian for Location {
 #[derive(Clone, Ord, PartialOrd, PartialEq, Debug, Hash, Eq)]
 pub struct ElementsComposition(Vec<ElementComposition>);
 impl ElementsComposition {
+    pub fn new(elements: Vec<ElementComposition>) -> Self {
+        ElementsComposition(elements)
+    }
+    pub fn elements(&self) -> &[ElementComposition] {
+        &self.0
+    }
+    pub fn element(&self, element_index: ElementIndex) -> Option<&ElementComposition> {
+        self.0.get(usize::from(element_index.0))
+    }
+    /// Returns where `model` is if it's on the element at `element_index`.
+    pub fn model_at(
+        &self,
+        element_index: ElementIndex,
+        model: &ModelIdentifier,
+    ) -> Option<ModelLocation> {
+        let element = self.element(element_index)?;
+        if element.has_model(model) {
+            Some(ModelLocation {
+                element_index,
+                location: element.location,
+                model: *model,
+            })
+        } else {
+            None
+        }
+    }
+    /// Returns every element `model` is on.
+    pub fn find_model<'a>(
+        &'a self,
+        model: &'a ModelIdentifier,
+    ) -> impl Iterator<Item = ModelLocation> + 'a {
+        (0..self.0.len())
+            .filter_map(move |index| self.model_at(ElementIndex(u8::try_from(index).ok()?), model))
+    }
     #[must_use]
     pub fn byte_len(&self) -> usize {
         self.0.iter().map(ElementComposition::byte_len).sum()
@@ -264,3 +330,78 @@ impl ElementsComposition {
         Some(ElementsComposition(out))
     }
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::foundation::CompositionDataPage0;
+    use crate::mesh::CompanyID;
+
+    #[test]
+    fn test_model_at() {
+        #[rustfmt::skip]
+        let data = [
+            // CID, PID, VID, CRPL, Features
+            0x0C, 0x00, 0x1A, 0x00, 0x01, 0x00, 0x08, 0x00, 0x03, 0x00,
+            // Element 0: Config Server, Health Server and vendor model 0x002A from 0x003F.
+            0x00, 0x00, 0x02, 0x01, 0x00, 0x00, 0x02, 0x00, 0x3F, 0x00, 0x2A, 0x00,
+            // Element 1: Generic OnOff Server.
+            0x01, 0x00, 0x01, 0x00, 0x00, 0x10,
+            // Element 2: Generic OnOff Server and Generic Level Server.
+            0x02, 0x00, 0x02, 0x00, 0x00, 0x10, 0x02, 0x10,
+        ];
+        let composition =
+            CompositionDataPage0::try_unpack_from(&data[..]).expect("valid composition data");
+        assert_eq!(composition.elements().elements().len(), 3);
+        let on_off = ModelIdentifier::new_sig(ModelID(0x1000));
+        let level = ModelIdentifier::new_sig(ModelID(0x1002));
+        let vendor = ModelIdentifier::new_vendor(ModelID(0x002A), CompanyID(0x003F));
+
+        assert_eq!(
+            composition.model_at(ElementIndex(1), &on_off),
+            Some(ModelLocation {
+                element_index: ElementIndex(1),
+                location: Location::Numbered(1),
+                model: on_off,
+            })
+        );
+        assert!(composition.model_at(ElementIndex(0), &on_off).is_none());
+        assert!(composition.model_at(ElementIndex(1), &level).is_none());
+        assert!(composition.model_at(ElementIndex(3), &on_off).is_none());
+        assert!(composition.model_at(ElementIndex(0), &vendor).is_some());
+        // Same model ID but a SIG model.
+        assert!(composition
+            .model_at(ElementIndex(0), &ModelIdentifier::new_sig(ModelID(0x002A)))
+            .is_none());
+
+        let primary = UnicastAddress::new(0x0100);
+        let level_location = composition
+            .model_at(ElementIndex(2), &level)
+            .expect("level server on element 2");
+        assert_eq!(
+            level_location.element_address(primary),
+            Some(UnicastAddress::new(0x0102))
+        );
+        assert_eq!(
+            composition
+                .elements()
+                .find_model(&on_off)
+                .map(|location| location.element_index)
+                .collect::<Vec<_>>(),
+            [ElementIndex(1), ElementIndex(2)]
+        );
+    }
+    #[test]
+    fn test_element_address() {
+        let primary = UnicastAddress::new(0x0005);
+        assert_eq!(element_address(primary, ElementIndex(0)), Some(primary));
+        assert_eq!(
+            element_address(primary, ElementIndex(3)),
+            Some(UnicastAddress::new(0x0008))
+        );
+        // Past the end of the unicast range.
+        assert_eq!(
+            element_address(UnicastAddress::new(0x7FFE), ElementIndex(2)),
+            None
+        );
+    }
+}
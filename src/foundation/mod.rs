@@ -1,8 +1,12 @@
 //! Foundation Layer. Handles Publication, Config, etc.
 
+use crate::access::ModelIdentifier;
 use crate::bytes::ToFromBytesEndian;
-use crate::foundation::element::ElementsComposition;
-use crate::mesh::CompanyID;
+use crate::device_state::DeviceState;
+use crate::foundation::element::{
+    ElementComposition, ElementsComposition, Location, ModelLocation,
+};
+use crate::mesh::{CompanyID, ElementIndex, ModelID};
 use crate::upper::AppPayload;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
@@ -21,6 +25,23 @@ pub struct StatusCodeConversationError(());
 #[repr(u8)]
 pub enum StatusCode {
     Ok = 0x00,
+    InvalidAddress = 0x01,
+    InvalidModel = 0x02,
+    InvalidAppKeyIndex = 0x03,
+    InvalidNetKeyIndex = 0x04,
+    InsufficientResources = 0x05,
+    KeyIndexAlreadyStored = 0x06,
+    InvalidPublishParameters = 0x07,
+    NotASubscribeModel = 0x08,
+    StorageFailure = 0x09,
+    FeatureNotSupported = 0x0A,
+    CannotUpdate = 0x0B,
+    CannotRemove = 0x0C,
+    CannotBind = 0x0D,
+    TemporarilyUnableToChangeState = 0x0E,
+    CannotSet = 0x0F,
+    UnspecifiedError = 0x10,
+    InvalidBinding = 0x11,
 }
 impl StatusCode {
     pub const fn byte_len() -> usize {
@@ -35,8 +56,28 @@ impl From<StatusCode> for u8 {
 impl TryFrom<u8> for StatusCode {
     type Error = StatusCodeConversationError;
 
-    fn try_from(_value: u8) -> Result<Self, Self::Error> {
-        unimplemented!()
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(StatusCode::Ok),
+            0x01 => Ok(StatusCode::InvalidAddress),
+            0x02 => Ok(StatusCode::InvalidModel),
+            0x03 => Ok(StatusCode::InvalidAppKeyIndex),
+            0x04 => Ok(StatusCode::InvalidNetKeyIndex),
+            0x05 => Ok(StatusCode::InsufficientResources),
+            0x06 => Ok(StatusCode::KeyIndexAlreadyStored),
+            0x07 => Ok(StatusCode::InvalidPublishParameters),
+            0x08 => Ok(StatusCode::NotASubscribeModel),
+            0x09 => Ok(StatusCode::StorageFailure),
+            0x0A => Ok(StatusCode::FeatureNotSupported),
+            0x0B => Ok(StatusCode::CannotUpdate),
+            0x0C => Ok(StatusCode::CannotRemove),
+            0x0D => Ok(StatusCode::CannotBind),
+            0x0E => Ok(StatusCode::TemporarilyUnableToChangeState),
+            0x0F => Ok(StatusCode::CannotSet),
+            0x10 => Ok(StatusCode::UnspecifiedError),
+            0x11 => Ok(StatusCode::InvalidBinding),
+            _ => Err(StatusCodeConversationError(())),
+        }
     }
 }
 #[derive(Ord, PartialOrd, Eq, PartialEq, Copy, Clone, Hash, Debug)]
@@ -112,6 +153,7 @@ impl Crpl {
         2
     }
 }
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
 pub enum FeatureFlags {
     Relay = 0b0001,
     Proxy = 0b0010,
@@ -146,7 +188,7 @@ impl ToFromBytesEndian for Features {
         Some(Features(u16::from_bytes_be(bytes)?))
     }
 }
-#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash, Default)]
 pub struct Features(u16);
 impl Features {
     pub const fn byte_len() -> usize {
@@ -156,12 +198,22 @@ impl Features {
         self.0 |= u16::from(feature)
     }
     pub fn clear(&mut self, feature: FeatureFlags) {
-        self.0 |= !u16::from(feature)
+        self.0 &= !u16::from(feature)
     }
     #[must_use]
     pub fn get(&self, feature: FeatureFlags) -> bool {
         self.0 & u16::from(feature) != 0
     }
+    /// Features set in only one of `self` and `other`.
+    #[must_use]
+    pub fn changed(self, other: Features) -> Features {
+        Features(self.0 ^ other.0)
+    }
+    /// Returns if any feature is set in both `self` and `other`.
+    #[must_use]
+    pub fn intersects(self, other: Features) -> bool {
+        self.0 & other.0 != 0
+    }
 }
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
 pub struct CRPL(pub u16);
@@ -193,6 +245,22 @@ impl ToFromBytesEndian for CRPL {
         Some(CRPL(u16::from_bytes_be(bytes)?))
     }
 }
+/// SIG Model ID of the Config Server every node has on its primary element.
+pub const CONFIG_SERVER_MODEL_ID: ModelID = ModelID(0x0000);
+/// SIG Model ID of the Config Client used by provisioners to configure nodes.
+pub const CONFIG_CLIENT_MODEL_ID: ModelID = ModelID(0x0001);
+/// SIG Model ID of the Health Server every node has on its primary element.
+pub const HEALTH_SERVER_MODEL_ID: ModelID = ModelID(0x0002);
+/// Identifies the node's product in its Composition Data (see
+/// [`CompositionDataPage0::from_device_state`]).
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct ProductInfo {
+    pub cid: CompanyID,
+    pub pid: ProductID,
+    pub vid: VersionID,
+    /// Minimum number of replay protection list entries.
+    pub crpl: CRPL,
+}
 #[derive(Clone, Ord, PartialOrd, PartialEq, Debug, Hash, Eq)]
 pub struct CompositionDataPage0 {
     cid: CompanyID,
@@ -203,6 +271,69 @@ pub struct CompositionDataPage0 {
     elements: ElementsComposition,
 }
 impl CompositionDataPage0 {
+    pub fn new(
+        cid: CompanyID,
+        pid: ProductID,
+        vid: VersionID,
+        crpl: CRPL,
+        features: Features,
+        elements: ElementsComposition,
+    ) -> Self {
+        Self {
+            cid,
+            pid,
+            vid,
+            crpl,
+            features,
+            elements,
+        }
+    }
+    /// Builds page `0` for the node described by `device_state`:
+    /// * The features are the ones the node supports (see [`DeviceState::supported_features`]).
+    /// * The primary element has the Config Server followed by every model in
+    /// `DeviceState::models` (models aren't tied to elements there).
+    /// * Every other element is listed without models.
+    ///
+    /// All the elements have an unknown location.
+    pub fn from_device_state(product: &ProductInfo, device_state: &DeviceState) -> Self {
+        let features = device_state.supported_features();
+        let unknown = Location::Numbered(0);
+        let mut primary = ElementComposition::new_empty(unknown);
+        let config_server = ModelIdentifier::new_sig(CONFIG_SERVER_MODEL_ID);
+        primary.add_model(config_server);
+        for (&model, _) in device_state.models().iter() {
+            if model != config_server {
+                primary.add_model(model);
+            }
+        }
+        let elements = core::iter::once(primary)
+            .chain(
+                (1..device_state.element_count().0).map(|_| ElementComposition::new_empty(unknown)),
+            )
+            .collect::<Vec<_>>();
+        Self::new(
+            product.cid,
+            product.pid,
+            product.vid,
+            product.crpl,
+            features,
+            ElementsComposition::new(elements),
+        )
+    }
+    pub fn features(&self) -> Features {
+        self.features
+    }
+    pub fn elements(&self) -> &ElementsComposition {
+        &self.elements
+    }
+    /// See [`ElementsComposition::model_at`].
+    pub fn model_at(
+        &self,
+        element_index: ElementIndex,
+        model: &ModelIdentifier,
+    ) -> Option<ModelLocation> {
+        self.elements.model_at(element_index, model)
+    }
     pub fn byte_len(&self) -> usize {
         CompanyID::byte_len()
             + ProductID::byte_len()
@@ -218,8 +349,18 @@ impl CompositionDataPage0 {
             + CRPL::byte_len()
             + Features::byte_len()
     }
-    pub fn try_unpack_from(&self, _data: &[u8]) {
-        unimplemented!()
+    pub fn try_unpack_from(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::min_byte_len() {
+            return None;
+        }
+        Some(Self {
+            cid: CompanyID::from_bytes_le(&data[0..2])?,
+            pid: ProductID::from_bytes_le(&data[2..4])?,
+            vid: VersionID::from_bytes_le(&data[4..6])?,
+            crpl: CRPL::from_bytes_le(&data[6..8])?,
+            features: Features::from_bytes_le(&data[8..10])?,
+            elements: ElementsComposition::try_unpack_from(&data[10..])?,
+        })
     }
     pub fn pack_into(&self, buf: &mut [u8]) {
         assert!(buf.len() >= self.byte_len());
@@ -232,7 +373,7 @@ impl CompositionDataPage0 {
         self.elements.pack_into(&mut buf[10..]);
     }
     pub fn as_app_payload(&self) -> AppPayload<Box<[u8]>> {
-        let mut buf = Vec::with_capacity(self.byte_len()).into_boxed_slice();
+        let mut buf = alloc::vec![0_u8; self.byte_len()].into_boxed_slice();
         self.pack_into(buf.as_mut());
         AppPayload::new(buf)
     }
@@ -30,15 +30,15 @@ impl From<StepResolution> for u8 {
     }
 }
 const STEPS_MAX: u8 = 0x3F;
-/// 6-bit Steps for Periods.
+/// 6-bit Steps for Periods. `0` steps disables periodic publishing.
 #[derive(Copy, Clone, Ord, PartialOrd, Debug, Hash, Eq, PartialEq)]
 #[cfg_attr(feature = "serde-1", derive(serde::Serialize, serde::Deserialize))]
 pub struct Steps(u8);
 impl Steps {
     /// # Panics
-    /// Panics if `steps == 0` or `steps > STEPS_MAX`
+    /// Panics if `steps > STEPS_MAX`
     pub fn new(steps: u8) -> Self {
-        assert!(steps != 0 && steps <= STEPS_MAX);
+        assert!(steps <= STEPS_MAX);
         Self(steps)
     }
 }
@@ -58,6 +58,16 @@ impl PublishPeriod {
     pub fn new(resolution: StepResolution, steps: Steps) -> Self {
         Self { resolution, steps }
     }
+    /// No periodic publishing (only publishing on state changes).
+    pub const fn disabled() -> Self {
+        Self {
+            resolution: StepResolution::Milliseconds100,
+            steps: Steps(0),
+        }
+    }
+    pub fn is_disabled(&self) -> bool {
+        self.steps.0 == 0
+    }
     pub fn to_milliseconds(&self) -> u32 {
         self.resolution.to_milliseconds() * u32::from(self.steps.0)
     }
@@ -92,6 +102,16 @@ impl From<PublishPeriod> for time::Duration {
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
 #[cfg_attr(feature = "serde-1", derive(serde::Serialize, serde::Deserialize))]
 pub struct PublishRetransmit(pub TransmitInterval);
+impl PublishRetransmit {
+    /// Times each published message is sent again after the first transmission.
+    pub fn count(&self) -> u8 {
+        self.0.count.into()
+    }
+    /// Time between each retransmission.
+    pub fn interval(&self) -> time::Duration {
+        self.0.steps.to_duration()
+    }
+}
 impl From<u8> for PublishRetransmit {
     fn from(b: u8) -> Self {
         Self(b.into())
@@ -133,10 +153,7 @@ impl ModelPublishInfo {
                 let publish_ttl = if buf[4] == 0xFF {
                     None
                 } else {
-                    if buf[4] & 0x80 != 0 {
-                        return None;
-                    }
-                    Some(TTL::from_masked_u8(buf[4]))
+                    Some(TTL::try_new(buf[4])?)
                 };
                 Some(Self {
                     address,
@@ -154,10 +171,7 @@ impl ModelPublishInfo {
                 let publish_ttl = if buf[18] == 0xFF {
                     None
                 } else {
-                    if buf[0] & 0x80 != 0 {
-                        return None;
-                    }
-                    Some(TTL::from_masked_u8(buf[18]))
+                    Some(TTL::try_new(buf[18])?)
                 };
                 Some(Self {
                     address: Address::Virtual(VirtualAddress::from(&uuid)),
@@ -177,7 +191,7 @@ impl ModelPublishInfo {
             "not enough room for publication"
         );
         let address = u16::from(&self.address);
-        let _pos = match &self.address {
+        let pos = match &self.address {
             Address::Virtual(va) => {
                 buf[..16].copy_from_slice(va.uuid().as_ref());
                 16
@@ -187,6 +201,10 @@ impl ModelPublishInfo {
                 2
             }
         };
-        unimplemented!()
+        let index = u16::from(self.app_key_index.0) | (u16::from(self.credential_flag) << 12);
+        buf[pos..pos + 2].copy_from_slice(&index.to_le_bytes());
+        buf[pos + 2] = self.ttl.map_or(0xFF, u8::from);
+        buf[pos + 3] = self.period.packed();
+        buf[pos + 4] = self.retransmit.into();
     }
 }
@@ -40,6 +40,14 @@ impl RelayState {
 #[derive(Ord, PartialOrd, Eq, PartialEq, Copy, Clone, Hash, Debug)]
 #[cfg_attr(feature = "serde-1", derive(serde::Serialize, serde::Deserialize))]
 pub struct RelayRetransmit(pub TransmitInterval);
+impl Default for RelayRetransmit {
+    fn default() -> Self {
+        RelayRetransmit(TransmitInterval {
+            count: TransmitCount::new(0x2),
+            steps: TransmitSteps::new(1),
+        })
+    }
+}
 #[derive(Ord, PartialOrd, Eq, PartialEq, Copy, Clone, Hash, Debug)]
 #[cfg_attr(feature = "serde-1", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
@@ -93,6 +101,11 @@ impl TryFrom<u8> for GATTProxyState {
         }
     }
 }
+impl GATTProxyState {
+    pub fn is_enabled(self) -> bool {
+        self == GATTProxyState::Enabled
+    }
+}
 impl Default for GATTProxyState {
     fn default() -> GATTProxyState {
         GATTProxyState::Disabled
@@ -150,6 +163,16 @@ impl TryFrom<u8> for FriendState {
         }
     }
 }
+impl FriendState {
+    pub fn is_enabled(self) -> bool {
+        self == FriendState::Enabled
+    }
+}
+impl Default for FriendState {
+    fn default() -> Self {
+        FriendState::Disabled
+    }
+}
 #[derive(Ord, PartialOrd, Eq, PartialEq, Copy, Clone, Hash, Debug)]
 #[cfg_attr(feature = "serde-1", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
@@ -0,0 +1,545 @@
+//! Friend side of a friendship. A Friend answers Friend Requests with Friend Offers and stores
+//! every message for its Low Power nodes in a Friend Queue until the Low Power node polls for it.
+//! The Friend also acks segments sent to a Low Power node on its behalf (see
+//! [`control::Ack::obo`](crate::control::Ack::obo)).
+use crate::address::{Address, UnicastAddress};
+use crate::control;
+use crate::friend::{
+    Criteria, FriendCounter, FriendOffer, FriendRequest, FriendSubscriptionList,
+    FriendSubscriptionListConfirm, ReceiveDelay, FSN,
+};
+use crate::lower::{BlockAck, SeqZero};
+use crate::mesh::{IVIndex, NetKeyIndex, TTL};
+use crate::net;
+use crate::timestamp::{Timestamp, TimestampTrait};
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use core::convert::TryFrom;
+use core::time::Duration;
+
+/// A Low Power node has this long after the Friend Offer to send its first Friend Poll.
+pub const OFFER_TIMEOUT: Duration = Duration::from_secs(1);
+/// Friend Offers are never sent sooner than this after the Friend Request.
+pub const MIN_OFFER_DELAY: Duration = Duration::from_millis(100);
+/// RSSI put in the Friend Offer when the bearer didn't report the Friend Request's RSSI.
+pub const RSSI_UNAVAILABLE: i8 = 127;
+
+/// What a Friend offers each Low Power node.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct FriendConfig {
+    /// Time (in milliseconds) the Friend listens for a Friend Poll.
+    pub receive_window: u8,
+    /// Messages stored for each Low Power node.
+    pub queue_size: u8,
+    /// Addresses each Low Power node can add to its Friend Subscription List.
+    pub subscription_list_size: u8,
+}
+impl Default for FriendConfig {
+    fn default() -> Self {
+        Self {
+            receive_window: 100,
+            queue_size: 16,
+            subscription_list_size: 8,
+        }
+    }
+}
+/// Returns how long to wait before sending `offer` in response to a Friend Request with
+/// `criteria`. Better offers (by [`Criteria::offer_score`]) go out sooner so the Low Power node
+/// hears them first. Never less than `MIN_OFFER_DELAY`.
+pub fn offer_delay(criteria: &Criteria, offer: &FriendOffer) -> Duration {
+    // The score is in half milliseconds.
+    let delay_ms = criteria.offer_score(offer).unwrap_or(0).max(0) / 2;
+    Duration::from_millis(u64::try_from(delay_ms).unwrap_or(0)).max(MIN_OFFER_DELAY)
+}
+/// An entry in a Friend Queue.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum QueueEntry {
+    /// A Friend Update with the current security state. The MD flag is only known when it's
+    /// sent.
+    Update,
+    /// A Network PDU for the Low Power node. It's re-encrypted with the friendship credentials
+    /// when it's sent.
+    PDU {
+        pdu: net::PDU,
+        net_key_index: NetKeyIndex,
+        iv_index: IVIndex,
+    },
+}
+/// Messages stored for a Low Power node. Friend Updates replace any older Friend Update and the
+/// oldest Network PDU is dropped when the queue is full.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct FriendQueue {
+    capacity: usize,
+    entries: VecDeque<QueueEntry>,
+    /// The entry sent in response to the last Friend Poll and that poll's FSN. It's sent again
+    /// if the Low Power node polls with the same FSN.
+    sent: Option<(FSN, QueueEntry)>,
+}
+impl FriendQueue {
+    /// # Panics
+    /// Panics if `capacity == 0`.
+    pub fn new(capacity: usize) -> Self {
+        assert_ne!(capacity, 0, "zero capacity friend queue");
+        Self {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+            sent: None,
+        }
+    }
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    /// Queues `entry`. Returns the entry dropped to make room for it (if any).
+    pub fn push(&mut self, entry: QueueEntry) -> Option<QueueEntry> {
+        if entry == QueueEntry::Update {
+            self.entries.retain(|e| *e != QueueEntry::Update);
+        }
+        let dropped = if self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .position(|e| *e != QueueEntry::Update)
+                .unwrap_or(0);
+            self.entries.remove(oldest)
+        } else {
+            None
+        };
+        self.entries.push_back(entry);
+        dropped
+    }
+    /// Answers a Friend Poll with `fsn`. A repeated FSN gets the last entry again. A new FSN
+    /// means the last entry was received so it gets the next one (a Friend Update if the queue
+    /// is empty). Also returns if there are more entries queued (the MD flag).
+    pub fn poll(&mut self, fsn: FSN) -> (QueueEntry, bool) {
+        let entry = match self.sent {
+            Some((sent_fsn, entry)) if sent_fsn == fsn => entry,
+            _ => self.entries.pop_front().unwrap_or(QueueEntry::Update),
+        };
+        self.sent = Some((fsn, entry));
+        (entry, !self.entries.is_empty())
+    }
+}
+/// A Friend's view of one of its Low Power nodes.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct FriendedLPN {
+    pub lpn_address: UnicastAddress,
+    pub num_elements: u8,
+    pub receive_delay: ReceiveDelay,
+    pub queue: FriendQueue,
+    subscriptions: BTreeSet<u16>,
+    subscription_list_size: usize,
+    /// Segments received of the last segmented message from each source to the Low Power node.
+    segments: BTreeMap<UnicastAddress, (SeqZero, BlockAck)>,
+    /// Set once the Low Power node sent its first Friend Poll.
+    pub established: bool,
+    pub offered_at: Timestamp,
+}
+impl FriendedLPN {
+    /// Returns if `dst` is one of the Low Power node's element addresses or an address on its
+    /// Friend Subscription List.
+    pub fn is_for(&self, dst: &Address) -> bool {
+        match dst {
+            Address::Unicast(unicast) => {
+                let first = u16::from(self.lpn_address);
+                let address = u16::from(*unicast);
+                address >= first
+                    && u32::from(address) < u32::from(first) + u32::from(self.num_elements)
+            }
+            Address::Unassigned => false,
+            _ => self.subscriptions.contains(&u16::from(dst)),
+        }
+    }
+    pub fn subscriptions(&self) -> impl Iterator<Item = Address> + '_ {
+        self.subscriptions
+            .iter()
+            .map(|&address| Address::from(address))
+    }
+    /// Records the segment in `pdu` (if it's a segment for one of the Low Power node's elements)
+    /// and returns the Segment Acknowledgement (with `obo` set) to send back to its source once
+    /// every segment is in.
+    fn ack_segment(&mut self, pdu: &net::PDU) -> Option<control::Ack> {
+        let segmented = pdu.payload.segmented()?;
+        if !pdu.header.dst.is_unicast() {
+            // Segments to groups are never acked.
+            return None;
+        }
+        let header = *segmented.segment_header();
+        let (seq_zero, block_ack) = self
+            .segments
+            .entry(pdu.header.src)
+            .or_insert((header.seq_zero, BlockAck::ZERO));
+        if *seq_zero != header.seq_zero {
+            // A new segmented message from the same source replaces the old one.
+            *seq_zero = header.seq_zero;
+            *block_ack = BlockAck::ZERO;
+        }
+        block_ack.set(u8::from(header.seg_n));
+        if block_ack.all_acked(header.seg_o) {
+            Some(control::Ack {
+                obo: true,
+                seq_zero: header.seq_zero,
+                block_ack: *block_ack,
+            })
+        } else {
+            None
+        }
+    }
+}
+/// The Friend feature. Keeps a Friend Queue (and Friend Subscription List) for each Low Power
+/// node. The friendship credentials and PollTimeout timers are kept in `Friendships`.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct FriendNode {
+    config: FriendConfig,
+    friend_counter: FriendCounter,
+    lpns: BTreeMap<UnicastAddress, FriendedLPN>,
+}
+impl FriendNode {
+    pub fn new(config: FriendConfig) -> Self {
+        Self {
+            config,
+            friend_counter: FriendCounter::new(0),
+            lpns: BTreeMap::new(),
+        }
+    }
+    pub fn config(&self) -> &FriendConfig {
+        &self.config
+    }
+    /// FriendCounter of the next Friend Offer.
+    pub fn friend_counter(&self) -> FriendCounter {
+        self.friend_counter
+    }
+    /// Makes a Friend Offer for `request` from `lpn_address` (received with `rssi`) as of `now`.
+    /// Any Friend Queue the Low Power node already had here is dropped. Returns `None` if the
+    /// Friend Queue is smaller than the Low Power node asked for.
+    pub fn handle_request(
+        &mut self,
+        lpn_address: UnicastAddress,
+        request: &FriendRequest,
+        rssi: Option<i8>,
+        now: Timestamp,
+    ) -> Option<FriendOffer> {
+        let offer = FriendOffer {
+            receive_window: self.config.receive_window,
+            queue_size: self.config.queue_size,
+            subscription_list_size: self.config.subscription_list_size,
+            rssi: rssi.unwrap_or(RSSI_UNAVAILABLE),
+            friend_counter: self.friend_counter,
+        };
+        request.criteria.offer_score(&offer)?;
+        self.friend_counter = FriendCounter::new(self.friend_counter.value().wrapping_add(1));
+        self.lpns.insert(
+            lpn_address,
+            FriendedLPN {
+                lpn_address,
+                num_elements: request.num_elements,
+                receive_delay: request.receive_delay,
+                queue: FriendQueue::new(usize::from(self.config.queue_size.max(1))),
+                subscriptions: BTreeSet::new(),
+                subscription_list_size: usize::from(self.config.subscription_list_size),
+                segments: BTreeMap::new(),
+                established: false,
+                offered_at: now,
+            },
+        );
+        Some(offer)
+    }
+    pub fn lpn(&self, lpn_address: UnicastAddress) -> Option<&FriendedLPN> {
+        self.lpns.get(&lpn_address)
+    }
+    pub fn remove(&mut self, lpn_address: UnicastAddress) -> Option<FriendedLPN> {
+        self.lpns.remove(&lpn_address)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &FriendedLPN> {
+        self.lpns.values()
+    }
+    /// Returns if `dst` is for one of the established Low Power nodes (so PDUs to it are
+    /// queued).
+    pub fn is_friend_of(&self, dst: &Address) -> bool {
+        self.lpns
+            .values()
+            .any(|lpn| lpn.established && lpn.is_for(dst))
+    }
+    /// Queues a Friend Update for every established Low Power node (after the security state
+    /// changed).
+    pub fn queue_update(&mut self) {
+        for lpn in self.lpns.values_mut().filter(|lpn| lpn.established) {
+            lpn.queue.push(QueueEntry::Update);
+        }
+    }
+    /// Queues `pdu` for every established Low Power node it's for (except the one that sent it).
+    /// Returns the Segment Acknowledgement to send on behalf of the Low Power node once every
+    /// segment of a segmented message is in.
+    pub fn queue_pdu(
+        &mut self,
+        pdu: &net::PDU,
+        net_key_index: NetKeyIndex,
+        iv_index: IVIndex,
+    ) -> Option<control::Ack> {
+        let mut ack = None;
+        for lpn in self.lpns.values_mut().filter(|lpn| {
+            lpn.established
+                && lpn.is_for(&pdu.header.dst)
+                && !lpn.is_for(&Address::Unicast(pdu.header.src))
+        }) {
+            let mut pdu = *pdu;
+            // Queued PDUs are relayed to the Low Power node.
+            if pdu.header.ttl.should_relay() {
+                pdu.header.ttl = TTL::new(u8::from(pdu.header.ttl) - 1);
+            }
+            lpn.queue.push(QueueEntry::PDU {
+                pdu,
+                net_key_index,
+                iv_index,
+            });
+            ack = ack.or_else(|| lpn.ack_segment(&pdu));
+        }
+        ack
+    }
+    /// Answers a Friend Poll with `fsn` from `lpn_address`. The first one establishes the
+    /// friendship. Returns the entry to send and the MD flag (see [`FriendQueue::poll`]) or
+    /// `None` if `lpn_address` isn't a Low Power node of this Friend.
+    pub fn handle_poll(
+        &mut self,
+        lpn_address: UnicastAddress,
+        fsn: FSN,
+    ) -> Option<(QueueEntry, bool)> {
+        let lpn = self.lpns.get_mut(&lpn_address)?;
+        lpn.established = true;
+        Some(lpn.queue.poll(fsn))
+    }
+    /// Adds (or removes) the addresses in `list` to the Friend Subscription List of
+    /// `lpn_address`. Addresses past the list size are ignored. Returns the confirmation to send
+    /// back or `None` if `lpn_address` isn't a Low Power node of this Friend.
+    pub fn handle_subscription_list(
+        &mut self,
+        lpn_address: UnicastAddress,
+        list: &FriendSubscriptionList,
+        add: bool,
+    ) -> Option<FriendSubscriptionListConfirm> {
+        let lpn = self.lpns.get_mut(&lpn_address)?;
+        for address in list.addresses.iter().filter(|a| !a.is_unicast()) {
+            let address = u16::from(address);
+            if !add {
+                lpn.subscriptions.remove(&address);
+            } else if lpn.subscriptions.len() < lpn.subscription_list_size {
+                lpn.subscriptions.insert(address);
+            }
+        }
+        Some(FriendSubscriptionListConfirm {
+            transaction_number: list.transaction_number,
+        })
+    }
+    /// Removes the Low Power nodes that didn't poll within `OFFER_TIMEOUT` of their Friend Offer
+    /// as of `now`. Returns their addresses.
+    pub fn expire_offers(&mut self, now: Timestamp) -> alloc::vec::Vec<UnicastAddress> {
+        let expired: alloc::vec::Vec<UnicastAddress> = self
+            .lpns
+            .values()
+            .filter(|lpn| {
+                !lpn.established
+                    && now
+                        .since(lpn.offered_at)
+                        .map_or(false, |elapsed| elapsed > OFFER_TIMEOUT)
+            })
+            .map(|lpn| lpn.lpn_address)
+            .collect();
+        for lpn_address in &expired {
+            self.lpns.remove(lpn_address);
+        }
+        expired
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::friend::{
+        LPNCounter, MinQueueSizeLog, PollTimeout, RSSIFactor, ReceiveWindowFactor,
+    };
+    use crate::lower::{SegN, SegO, SegmentedAccessPDU, UnsegmentedAccessPDU, SZMIC};
+    use crate::mesh::{KeyIndex, SequenceNumber, CTL, NID, U24};
+
+    fn request(min_queue_size_log: MinQueueSizeLog) -> FriendRequest {
+        FriendRequest {
+            criteria: Criteria {
+                rssi_factor: RSSIFactor::Factor1,
+                receive_window_factor: ReceiveWindowFactor::Window1,
+                min_queue_size_log,
+            },
+            receive_delay: ReceiveDelay::new(100),
+            poll_timeout: PollTimeout::MIN,
+            previous_address: None,
+            num_elements: 2,
+            lpn_counter: LPNCounter::new(0),
+        }
+    }
+    fn pdu(seq: u32, dst: Address, payload: crate::lower::PDU) -> net::PDU {
+        net::PDU {
+            header: net::Header {
+                ivi: IVIndex(0).ivi(),
+                nid: NID::new(0),
+                ctl: CTL(false),
+                ttl: TTL::new(5),
+                seq: SequenceNumber(U24::new(seq)),
+                src: UnicastAddress::new(0x0200),
+                dst,
+            },
+            payload,
+        }
+    }
+    fn unsegmented(seq: u32, dst: Address) -> net::PDU {
+        pdu(
+            seq,
+            dst,
+            crate::lower::PDU::UnsegmentedAccess(UnsegmentedAccessPDU::new(None, &[0_u8; 5])),
+        )
+    }
+    fn queued(entry: QueueEntry) -> u32 {
+        match entry {
+            QueueEntry::PDU { pdu, .. } => pdu.header.seq.0.value(),
+            QueueEntry::Update => panic!("not a PDU"),
+        }
+    }
+    #[test]
+    fn test_offer() {
+        let mut friend = FriendNode::new(FriendConfig::default());
+        let lpn = UnicastAddress::new(0x0100);
+        let now = Timestamp::now();
+        // A queue of 16 is too small for 32.
+        assert_eq!(
+            friend.handle_request(lpn, &request(MinQueueSizeLog::N32), Some(-40), now),
+            None
+        );
+        let offer = friend
+            .handle_request(lpn, &request(MinQueueSizeLog::N16), Some(-40), now)
+            .expect("queue big enough");
+        assert_eq!(offer.rssi, -40);
+        assert_eq!(offer.friend_counter, FriendCounter::new(0));
+        assert_eq!(friend.friend_counter(), FriendCounter::new(1));
+        // 100ms ReceiveWindow + 40 (RSSI).
+        assert_eq!(
+            offer_delay(&request(MinQueueSizeLog::N16).criteria, &offer),
+            Duration::from_millis(140)
+        );
+        let mut close = offer;
+        close.receive_window = 10;
+        close.rssi = -10;
+        assert_eq!(
+            offer_delay(&request(MinQueueSizeLog::N16).criteria, &close),
+            MIN_OFFER_DELAY
+        );
+
+        // Nothing is queued until the first Friend Poll.
+        assert!(!friend.is_friend_of(&Address::Unicast(lpn)));
+        assert_eq!(
+            friend.expire_offers(now + OFFER_TIMEOUT),
+            alloc::vec::Vec::new()
+        );
+        assert_eq!(
+            friend.expire_offers(now + OFFER_TIMEOUT + Duration::from_millis(1)),
+            vec![lpn]
+        );
+        assert_eq!(friend.handle_poll(lpn, FSN::new(false)), None);
+    }
+    #[test]
+    fn test_queue() {
+        let mut friend = FriendNode::new(FriendConfig {
+            queue_size: 2,
+            ..FriendConfig::default()
+        });
+        let lpn = UnicastAddress::new(0x0100);
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        friend.handle_request(lpn, &request(MinQueueSizeLog::N2), None, Timestamp::now());
+        // The first poll establishes the friendship and gets a Friend Update.
+        assert_eq!(
+            friend.handle_poll(lpn, FSN::new(false)),
+            Some((QueueEntry::Update, false))
+        );
+        // Both elements of the Low Power node.
+        assert!(friend.is_friend_of(&Address::from(0x0101)));
+        assert!(!friend.is_friend_of(&Address::from(0x0102)));
+
+        for seq in 1..=3 {
+            assert_eq!(
+                friend.queue_pdu(
+                    &unsegmented(seq, Address::from(0x0101)),
+                    net_key_index,
+                    IVIndex(0)
+                ),
+                None
+            );
+        }
+        // Only 2 fit so the oldest was dropped.
+        assert_eq!(friend.lpn(lpn).expect("friended").queue.len(), 2);
+        let (entry, md) = friend.handle_poll(lpn, FSN::new(true)).expect("friended");
+        assert_eq!((queued(entry), md), (2, true));
+        if let QueueEntry::PDU { pdu, .. } = entry {
+            assert_eq!(pdu.header.ttl, TTL::new(4));
+        }
+        // The same FSN gets the same PDU again.
+        let (entry, md) = friend.handle_poll(lpn, FSN::new(true)).expect("friended");
+        assert_eq!((queued(entry), md), (2, true));
+        let (entry, md) = friend.handle_poll(lpn, FSN::new(false)).expect("friended");
+        assert_eq!((queued(entry), md), (3, false));
+        assert_eq!(
+            friend.handle_poll(lpn, FSN::new(true)),
+            Some((QueueEntry::Update, false))
+        );
+
+        // Groups are only queued once they're on the Friend Subscription List.
+        let group = Address::from(0xC001);
+        assert!(!friend.is_friend_of(&group));
+        let list = FriendSubscriptionList {
+            transaction_number: 7,
+            addresses: vec![group, Address::from(0x0003)],
+        };
+        assert_eq!(
+            friend.handle_subscription_list(lpn, &list, true),
+            Some(FriendSubscriptionListConfirm {
+                transaction_number: 7
+            })
+        );
+        assert!(friend.is_friend_of(&group));
+        // Unicast addresses can't be added.
+        assert!(!friend.is_friend_of(&Address::from(0x0003)));
+        friend.handle_subscription_list(lpn, &list, false);
+        assert!(!friend.is_friend_of(&group));
+    }
+    #[test]
+    fn test_obo_ack() {
+        let mut friend = FriendNode::new(FriendConfig::default());
+        let lpn = UnicastAddress::new(0x0100);
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        friend.handle_request(lpn, &request(MinQueueSizeLog::N2), None, Timestamp::now());
+        friend.handle_poll(lpn, FSN::new(false));
+        let segment = |seg_n: u8| {
+            pdu(
+                0x10 + u32::from(seg_n),
+                Address::Unicast(lpn),
+                crate::lower::PDU::SegmentedAccess(SegmentedAccessPDU::new(
+                    None,
+                    SZMIC::from(false),
+                    SeqZero::new(0x10),
+                    SegO::new(1),
+                    SegN::new(seg_n),
+                    &[0_u8; 12],
+                )),
+            )
+        };
+        assert_eq!(
+            friend.queue_pdu(&segment(1), net_key_index, IVIndex(0)),
+            None
+        );
+        let ack = friend
+            .queue_pdu(&segment(0), net_key_index, IVIndex(0))
+            .expect("every segment received");
+        assert!(ack.obo);
+        assert_eq!(ack.seq_zero, SeqZero::new(0x10));
+        assert!(ack.block_ack.all_acked(SegO::new(1)));
+        assert_eq!(friend.lpn(lpn).expect("friended").queue.len(), 2);
+    }
+}
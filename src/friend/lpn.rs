@@ -0,0 +1,413 @@
+//! Low Power node side of a friendship. The Low Power node broadcasts a Friend Request, picks the
+//! best Friend Offer received within the offer window (see [`Criteria::offer_score`]) and polls
+//! the chosen Friend. The friendship is established once the Friend answers the first Friend Poll
+//! with a Friend Update. From then on, the Low Power node has to poll its Friend before the
+//! PollTimeout runs out or the Friend ends the friendship.
+use crate::address::UnicastAddress;
+use crate::friend::{
+    Criteria, FriendOffer, FriendPoll, FriendRequest, FriendUpdate, FriendshipCredentials,
+    LPNCounter, MinQueueSizeLog, PollTimeout, RSSIFactor, ReceiveDelay, ReceiveWindowFactor, FSN,
+};
+use crate::mesh::NetKeyIndex;
+use crate::stack::SendError;
+use crate::timestamp::{Timestamp, TimestampTrait};
+use alloc::vec::Vec;
+use core::time::Duration;
+
+/// Friend Offers are only listened for after this delay (from sending the Friend Request).
+pub const OFFER_DELAY: Duration = Duration::from_millis(100);
+/// How long Friend Offers are collected for (after `OFFER_DELAY`).
+pub const OFFER_WINDOW: Duration = Duration::from_secs(1);
+/// Friend Polls sent without getting a Friend Update back before giving up on a Friend.
+pub const POLL_ATTEMPTS: usize = 3;
+
+/// What a Low Power node asks for when looking for a Friend.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct LPNConfig {
+    pub criteria: Criteria,
+    pub receive_delay: ReceiveDelay,
+    pub poll_timeout: PollTimeout,
+}
+impl Default for LPNConfig {
+    /// Weighs RSSI and ReceiveWindow evenly, wants a Friend Queue of at least 4 messages, a
+    /// 100ms ReceiveDelay and a 10 second PollTimeout.
+    fn default() -> Self {
+        Self {
+            criteria: Criteria {
+                rssi_factor: RSSIFactor::Factor1,
+                receive_window_factor: ReceiveWindowFactor::Window1,
+                min_queue_size_log: MinQueueSizeLog::N4,
+            },
+            receive_delay: ReceiveDelay::new(100),
+            poll_timeout: PollTimeout::from_duration(Duration::from_secs(10)),
+        }
+    }
+}
+/// Returned when a friendship can't be established.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum LPNError {
+    /// No Friend Offer met the criteria within the offer window.
+    NoOffer,
+    /// The chosen Friend never answered the Friend Poll with a Friend Update.
+    NoFriendUpdate,
+    SendError(SendError),
+}
+impl From<SendError> for LPNError {
+    fn from(e: SendError) -> Self {
+        LPNError::SendError(e)
+    }
+}
+/// A Friend Offer and the Friend that sent it.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct Offer {
+    pub friend_address: UnicastAddress,
+    pub offer: FriendOffer,
+}
+/// A Low Power node's view of its friendship with a Friend.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct LPNFriendship {
+    pub credentials: FriendshipCredentials,
+    /// NetKey the friendship credentials are derived from.
+    pub net_key_index: NetKeyIndex,
+    pub poll_timeout: PollTimeout,
+    pub receive_delay: ReceiveDelay,
+    pub offer: FriendOffer,
+    /// Friend Sequence Number of the next Friend Poll.
+    pub fsn: FSN,
+    /// Set once the Friend answered a Friend Poll with a Friend Update.
+    pub established: bool,
+    /// When the last Friend Poll was sent (or the offer was accepted).
+    pub last_poll: Timestamp,
+}
+impl LPNFriendship {
+    pub fn friend_address(&self) -> UnicastAddress {
+        self.credentials.friend_address
+    }
+    /// Returns how long is left to send the next Friend Poll as of `now`. `PollTimeout::ZERO`
+    /// means the Friend already ended the friendship.
+    pub fn remaining_poll_timeout(&self, now: Timestamp) -> PollTimeout {
+        let elapsed = now.since(self.last_poll).unwrap_or_default();
+        self.poll_timeout
+            .as_duration()
+            .checked_sub(elapsed)
+            .map_or(PollTimeout::ZERO, PollTimeout::from_duration)
+    }
+}
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+struct Request {
+    request: FriendRequest,
+    net_key_index: NetKeyIndex,
+    offers: Vec<Offer>,
+}
+/// Friendship state of a Low Power node.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct LowPowerNode {
+    lpn_counter: LPNCounter,
+    request: Option<Request>,
+    friendship: Option<LPNFriendship>,
+    previous_friend: Option<UnicastAddress>,
+}
+impl Default for LowPowerNode {
+    fn default() -> Self {
+        Self {
+            lpn_counter: LPNCounter::new(0),
+            request: None,
+            friendship: None,
+            previous_friend: None,
+        }
+    }
+}
+impl LowPowerNode {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// LPNCounter of the next Friend Request.
+    pub fn lpn_counter(&self) -> LPNCounter {
+        self.lpn_counter
+    }
+    pub fn friendship(&self) -> Option<&LPNFriendship> {
+        self.friendship.as_ref()
+    }
+    /// Returns if the Friend answered a Friend Poll so the friendship is established.
+    pub fn is_established(&self) -> bool {
+        self.friendship
+            .map_or(false, |friendship| friendship.established)
+    }
+    /// Starts looking for a Friend on the NetKey `net_key_index` and returns the Friend Request
+    /// to broadcast (to all friends with a TTL of 0). Any current friendship and offers collected
+    /// for an earlier Friend Request are dropped.
+    pub fn friend_request(
+        &mut self,
+        config: &LPNConfig,
+        num_elements: u8,
+        net_key_index: NetKeyIndex,
+    ) -> FriendRequest {
+        self.clear();
+        let request = FriendRequest {
+            criteria: config.criteria,
+            receive_delay: config.receive_delay,
+            poll_timeout: config.poll_timeout,
+            previous_address: self.previous_friend,
+            num_elements,
+            lpn_counter: self.lpn_counter,
+        };
+        self.lpn_counter = LPNCounter::new(self.lpn_counter.value().wrapping_add(1));
+        self.request = Some(Request {
+            request,
+            net_key_index,
+            offers: Vec::new(),
+        });
+        request
+    }
+    /// Collects the Friend Offer `offer` from `friend_address` received on the NetKey
+    /// `net_key_index`. Returns `false` (and ignores it) if no Friend Request is pending, it came
+    /// in on a different NetKey or its Friend Queue is too small.
+    pub fn handle_offer(
+        &mut self,
+        friend_address: UnicastAddress,
+        net_key_index: NetKeyIndex,
+        offer: FriendOffer,
+    ) -> bool {
+        match &mut self.request {
+            Some(request)
+                if request.net_key_index == net_key_index
+                    && request.request.criteria.offer_score(&offer).is_some() =>
+            {
+                request
+                    .offers
+                    .retain(|o| o.friend_address != friend_address);
+                request.offers.push(Offer {
+                    friend_address,
+                    offer,
+                });
+                true
+            }
+            _ => false,
+        }
+    }
+    /// Returns the offer with the lowest [`Criteria::offer_score`] collected so far. Ties go to
+    /// the offer received first.
+    pub fn best_offer(&self) -> Option<Offer> {
+        let request = self.request.as_ref()?;
+        let criteria = request.request.criteria;
+        request
+            .offers
+            .iter()
+            .min_by_key(|offer| criteria.offer_score(&offer.offer))
+            .copied()
+    }
+    /// Starts a friendship with the Friend of `offer` (usually [`LowPowerNode::best_offer`]) as
+    /// of `now` and ends the pending Friend Request. The friendship credentials are derived
+    /// from `lpn_address` (the primary element), the Friend's address and both counters. The
+    /// friendship is only established once the Friend answers a Friend Poll. Returns `None` if
+    /// no Friend Request is pending.
+    pub fn accept_offer(
+        &mut self,
+        lpn_address: UnicastAddress,
+        offer: &Offer,
+        now: Timestamp,
+    ) -> Option<&LPNFriendship> {
+        let request = self.request.take()?;
+        self.friendship = Some(LPNFriendship {
+            credentials: FriendshipCredentials {
+                lpn_address,
+                friend_address: offer.friend_address,
+                lpn_counter: request.request.lpn_counter,
+                friend_counter: offer.offer.friend_counter,
+            },
+            net_key_index: request.net_key_index,
+            poll_timeout: request.request.poll_timeout,
+            receive_delay: request.request.receive_delay,
+            offer: offer.offer,
+            fsn: FSN::new(false),
+            established: false,
+            last_poll: now,
+        });
+        self.friendship.as_ref()
+    }
+    /// Returns the next Friend Poll for the Friend and restarts the PollTimeout timer as of
+    /// `now`. Returns `None` without a friendship.
+    pub fn friend_poll(&mut self, now: Timestamp) -> Option<FriendPoll> {
+        let friendship = self.friendship.as_mut()?;
+        friendship.last_poll = now;
+        Some(FriendPoll::new(friendship.fsn))
+    }
+    /// Handles a Friend Update from `src`. The first one establishes the friendship. The FSN is
+    /// toggled so the next Friend Poll asks for the next message. Returns `false` if `src`
+    /// isn't the Friend.
+    pub fn handle_update(&mut self, src: UnicastAddress, _update: &FriendUpdate) -> bool {
+        match &mut self.friendship {
+            Some(friendship) if friendship.friend_address() == src => {
+                friendship.established = true;
+                friendship.fsn = FSN::new(!friendship.fsn.value());
+                true
+            }
+            _ => false,
+        }
+    }
+    /// Ends the friendship (and any pending Friend Request). The Friend is sent as the previous
+    /// Friend in the next Friend Request.
+    pub fn clear(&mut self) -> Option<LPNFriendship> {
+        self.request = None;
+        let friendship = self.friendship.take()?;
+        self.previous_friend = Some(friendship.friend_address());
+        Some(friendship)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{self, ControlMessage};
+    use crate::friend::{FriendCounter, MD};
+    use crate::mesh::{IVIndex, IVUpdateFlag, KeyIndex, KeyRefreshFlag};
+
+    fn offer(receive_window: u8, queue_size: u8, rssi: i8, friend_counter: u16) -> FriendOffer {
+        FriendOffer {
+            receive_window,
+            queue_size,
+            subscription_list_size: 8,
+            rssi,
+            friend_counter: FriendCounter::new(friend_counter),
+        }
+    }
+    #[test]
+    fn test_pack_friend_messages() {
+        let request = control::FriendRequest(FriendRequest {
+            criteria: LPNConfig::default().criteria,
+            receive_delay: ReceiveDelay::new(0x64),
+            poll_timeout: PollTimeout::MIN,
+            previous_address: Some(UnicastAddress::new(0x0203)),
+            num_elements: 2,
+            lpn_counter: LPNCounter::new(0x0405),
+        });
+        let unseg = request.try_to_unseg().expect("packs");
+        assert_eq!(
+            unseg.data(),
+            &[0x02, 0x64, 0x00, 0x00, 0x0A, 0x02, 0x03, 0x02, 0x04, 0x05][..]
+        );
+        assert_eq!(control::FriendRequest::try_from_pdu(&unseg), Ok(request));
+        // A prohibited MinQueueSizeLog.
+        assert_eq!(
+            control::FriendRequest::unpack(&[0x00, 0x64, 0x00, 0x00, 0x0A, 0, 0, 1, 0, 0]),
+            Err(control::ControlMessageError::BadBytes)
+        );
+
+        let offer = control::FriendOffer(offer(0xFF, 0x10, -80, 0x0607));
+        let unseg = offer.try_to_unseg().expect("packs");
+        assert_eq!(unseg.data(), &[0xFF, 0x10, 0x08, 0xB0, 0x06, 0x07][..]);
+        assert_eq!(control::FriendOffer::try_from_pdu(&unseg), Ok(offer));
+
+        let update = control::FriendUpdate(FriendUpdate {
+            key_refresh_flag: KeyRefreshFlag(false),
+            iv_update_flag: IVUpdateFlag(true),
+            iv_index: IVIndex(0x1234_5678),
+            md: MD::new(true),
+        });
+        let unseg = update.try_to_unseg().expect("packs");
+        assert_eq!(unseg.data(), &[0x02, 0x12, 0x34, 0x56, 0x78, 0x01][..]);
+        assert_eq!(control::FriendUpdate::try_from_pdu(&unseg), Ok(update));
+    }
+    #[test]
+    fn test_offer_selection() {
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        let mut lpn = LowPowerNode::new();
+        let friend = |address| UnicastAddress::new(address);
+        let mut config = LPNConfig::default();
+        // RSSI weighs 2.5 and the ReceiveWindow 1.
+        config.criteria.rssi_factor = RSSIFactor::Factor4;
+
+        // No offers without a Friend Request.
+        assert!(!lpn.handle_offer(friend(1), net_key_index, offer(50, 16, -50, 0)));
+        let request = lpn.friend_request(&config, 1, net_key_index);
+        assert_eq!(request.lpn_counter, LPNCounter::new(0));
+        assert_eq!(request.previous_address, None);
+        assert_eq!(lpn.lpn_counter(), LPNCounter::new(1));
+
+        // Friend Queue too small.
+        assert!(!lpn.handle_offer(friend(1), net_key_index, offer(10, 2, -20, 0)));
+        // Wrong NetKey.
+        assert!(!lpn.handle_offer(
+            friend(1),
+            NetKeyIndex(KeyIndex::new(1)),
+            offer(10, 16, -20, 0)
+        ));
+        // 2 * 50 + 5 * 80 = 500
+        assert!(lpn.handle_offer(friend(2), net_key_index, offer(50, 16, -80, 0)));
+        // 2 * 150 + 5 * 40 = 500 (a tie goes to the first offer).
+        assert!(lpn.handle_offer(friend(3), net_key_index, offer(150, 4, -40, 0)));
+        assert_eq!(lpn.best_offer().map(|o| o.friend_address), Some(friend(2)));
+        // 2 * 100 + 5 * 50 = 450
+        assert!(lpn.handle_offer(friend(4), net_key_index, offer(100, 32, -50, 0x0101)));
+        let best = lpn.best_offer().expect("offers collected");
+        assert_eq!(best.friend_address, friend(4));
+
+        let lpn_address = UnicastAddress::new(0x0100);
+        let friendship = *lpn
+            .accept_offer(lpn_address, &best, Timestamp::now())
+            .expect("request pending");
+        assert_eq!(
+            friendship.credentials,
+            FriendshipCredentials {
+                lpn_address,
+                friend_address: friend(4),
+                lpn_counter: LPNCounter::new(0),
+                friend_counter: FriendCounter::new(0x0101),
+            }
+        );
+        assert!(!lpn.is_established());
+        // The request is over.
+        assert!(!lpn.handle_offer(friend(5), net_key_index, offer(1, 128, 0, 0)));
+        assert_eq!(lpn.best_offer(), None);
+    }
+    #[test]
+    fn test_poll() {
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        let mut lpn = LowPowerNode::new();
+        let friend = UnicastAddress::new(0x0002);
+        let update = FriendUpdate {
+            key_refresh_flag: KeyRefreshFlag(false),
+            iv_update_flag: IVUpdateFlag(false),
+            iv_index: IVIndex(0),
+            md: MD::new(false),
+        };
+        let start = Timestamp::now();
+        assert_eq!(lpn.friend_poll(start), None);
+
+        let config = LPNConfig::default();
+        lpn.friend_request(&config, 1, net_key_index);
+        lpn.handle_offer(friend, net_key_index, offer(50, 16, -50, 0));
+        let offer = lpn.best_offer().expect("offer collected");
+        lpn.accept_offer(UnicastAddress::new(0x0100), &offer, start);
+
+        let poll = lpn.friend_poll(start).expect("friendship");
+        assert!(!poll.fsn().value());
+        // Updates from anyone else are ignored.
+        assert!(!lpn.handle_update(UnicastAddress::new(0x0003), &update));
+        assert!(!lpn.is_established());
+        // No update so the same FSN is polled again.
+        assert_eq!(lpn.friend_poll(start), Some(poll));
+        assert!(lpn.handle_update(friend, &update));
+        assert!(lpn.is_established());
+        let poll = lpn
+            .friend_poll(start + Duration::from_secs(1))
+            .expect("friendship");
+        assert!(poll.fsn().value());
+
+        let friendship = *lpn.friendship().expect("friendship");
+        assert_eq!(
+            friendship.remaining_poll_timeout(start + Duration::from_secs(1)),
+            config.poll_timeout
+        );
+        assert_eq!(
+            friendship.remaining_poll_timeout(start + Duration::from_secs(11)),
+            PollTimeout::ZERO
+        );
+
+        // The old Friend is sent as the previous address in the next Friend Request.
+        assert_eq!(lpn.clear(), Some(friendship));
+        assert_eq!(lpn.friendship(), None);
+        let request = lpn.friend_request(&config, 1, net_key_index);
+        assert_eq!(request.previous_address, Some(friend));
+        assert_eq!(request.lpn_counter, LPNCounter::new(1));
+    }
+}
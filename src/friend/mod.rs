@@ -0,0 +1,432 @@
+//! Optional Bluetooth Mesh Friends feature.
+#[allow(clippy::module_inception)]
+pub mod friend;
+pub mod lpn;
+
+use crate::address::{Address, UnicastAddress};
+use crate::bytes::ToFromBytesEndian;
+use crate::crypto::k2;
+use crate::crypto::key::NetKey;
+use crate::crypto::materials::NetworkKeys;
+use crate::mesh::{IVIndex, IVUpdateFlag, KeyRefreshFlag, U24};
+use crate::timestamp::{Timestamp, TimestampTrait};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::time::Duration;
+
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct Flags(u8);
+/// Friend Sequence Number. Toggled by the Low Power node for every new Friend Poll.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct FSN(bool);
+impl FSN {
+    pub fn new(fsn: bool) -> FSN {
+        FSN(fsn)
+    }
+    pub fn value(self) -> bool {
+        self.0
+    }
+}
+/// More Data. Set by the Friend when it has more messages queued for the Low Power node.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct MD(bool);
+impl MD {
+    pub fn new(md: bool) -> MD {
+        MD(md)
+    }
+    pub fn value(self) -> bool {
+        self.0
+    }
+}
+/// What a Low Power node asks of its Friend in a Friend Request. Offers with a Friend Queue
+/// smaller than `min_queue_size_log` are ignored and the factors weigh the RSSI and
+/// ReceiveWindow of the rest (see [`Criteria::offer_score`]).
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct Criteria {
+    pub rssi_factor: RSSIFactor,
+    pub receive_window_factor: ReceiveWindowFactor,
+    pub min_queue_size_log: MinQueueSizeLog,
+}
+impl Criteria {
+    /// Returns how well `offer` fits these criteria. Lower is better. `None` if the Friend Queue
+    /// is too small.
+    /// `ReceiveWindowFactor * ReceiveWindow - RSSIFactor * RSSI`
+    pub fn offer_score(&self, offer: &FriendOffer) -> Option<i32> {
+        if u16::from(offer.queue_size) < self.min_queue_size_log.min_queue_size() {
+            None
+        } else {
+            // The factors are in halves so the score is doubled.
+            Some(
+                self.receive_window_factor.halves() * i32::from(offer.receive_window)
+                    - self.rssi_factor.halves() * i32::from(offer.rssi),
+            )
+        }
+    }
+}
+impl From<Criteria> for u8 {
+    fn from(criteria: Criteria) -> Self {
+        ((criteria.rssi_factor as u8) << 5)
+            | ((criteria.receive_window_factor as u8) << 3)
+            | (criteria.min_queue_size_log as u8)
+    }
+}
+impl TryFrom<u8> for Criteria {
+    type Error = ();
+
+    /// Fails if the RFU bit is set or `MinQueueSizeLog` is prohibited.
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        if value & 0x80 != 0 {
+            return Err(());
+        }
+        Ok(Criteria {
+            rssi_factor: RSSIFactor::from_bits(value >> 5),
+            receive_window_factor: ReceiveWindowFactor::from_bits(value >> 3),
+            min_queue_size_log: match MinQueueSizeLog::from_bits(value) {
+                MinQueueSizeLog::Prohibited => return Err(()),
+                log => log,
+            },
+        })
+    }
+}
+/// Time (in milliseconds) the Friend waits after a Friend Poll before responding. The Low Power
+/// node only listens for the response after it.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct ReceiveDelay(u8);
+impl ReceiveDelay {
+    /// Shortest ReceiveDelay (10 milliseconds).
+    pub const MIN: ReceiveDelay = ReceiveDelay(0x0A);
+    /// # Panics
+    /// Panics if `delay_ms < 10`.
+    pub fn new(delay_ms: u8) -> ReceiveDelay {
+        assert!(delay_ms >= Self::MIN.0, "receive delay below 10ms");
+        ReceiveDelay(delay_ms)
+    }
+    pub fn new_checked(delay_ms: u8) -> Option<ReceiveDelay> {
+        if delay_ms >= Self::MIN.0 {
+            Some(ReceiveDelay(delay_ms))
+        } else {
+            None
+        }
+    }
+    pub fn value(self) -> u8 {
+        self.0
+    }
+    pub fn as_duration(self) -> Duration {
+        Duration::from_millis(u64::from(self.0))
+    }
+}
+/// Time (in 100 millisecond steps) a Friend waits for a Friend Poll from the Low Power node
+/// before ending the friendship.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct PollTimeout(U24);
+const POLL_TIMEOUT_STEP_MS: u64 = 100;
+impl PollTimeout {
+    /// No friendship (or the timer already expired).
+    pub const ZERO: PollTimeout = PollTimeout(U24::new_masked(0));
+    /// Smallest PollTimeout a Low Power node can request (1 second).
+    pub const MIN: PollTimeout = PollTimeout(U24::new_masked(0x00_000A));
+    /// Largest PollTimeout a Low Power node can request (just under 96 hours).
+    pub const MAX: PollTimeout = PollTimeout(U24::new_masked(0x34_BBFF));
+    pub fn new(steps: U24) -> PollTimeout {
+        PollTimeout(steps)
+    }
+    pub fn steps(self) -> U24 {
+        self.0
+    }
+    /// Returns if the PollTimeout is in the range a Low Power node may request.
+    pub fn is_valid_request(self) -> bool {
+        self >= Self::MIN && self <= Self::MAX
+    }
+    /// Rounds `duration` down to 100 millisecond steps. Saturates at the 24-bit maximum.
+    pub fn from_duration(duration: Duration) -> PollTimeout {
+        let steps = duration.as_millis() / u128::from(POLL_TIMEOUT_STEP_MS);
+        PollTimeout(U24::new(
+            u32::try_from(steps)
+                .unwrap_or(U24::max_value().value())
+                .min(U24::max_value().value()),
+        ))
+    }
+    pub fn as_duration(self) -> Duration {
+        Duration::from_millis(u64::from(self.0.value()) * POLL_TIMEOUT_STEP_MS)
+    }
+}
+/// Number of Friend Requests the Low Power node has sent.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct LPNCounter(u16);
+impl LPNCounter {
+    pub fn new(counter: u16) -> LPNCounter {
+        LPNCounter(counter)
+    }
+    pub fn value(self) -> u16 {
+        self.0
+    }
+}
+/// Number of Friend Offers the Friend has sent.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct FriendCounter(u16);
+impl FriendCounter {
+    pub fn new(counter: u16) -> FriendCounter {
+        FriendCounter(counter)
+    }
+    pub fn value(self) -> u16 {
+        self.0
+    }
+}
+/// Everything that goes into the friendship security credentials (the NID, EncryptionKey and
+/// PrivacyKey used between a Friend and its Low Power node instead of the managed flooding
+/// ones).
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct FriendshipCredentials {
+    pub lpn_address: UnicastAddress,
+    pub friend_address: UnicastAddress,
+    pub lpn_counter: LPNCounter,
+    pub friend_counter: FriendCounter,
+}
+const FRIENDSHIP_P_LEN: usize = 9;
+impl FriendshipCredentials {
+    /// `k2`'s `P` for the friendship credentials.
+    /// `0x01 || LPNAddress || FriendAddress || LPNCounter || FriendCounter`
+    pub fn p(&self) -> [u8; FRIENDSHIP_P_LEN] {
+        let mut p = [0_u8; FRIENDSHIP_P_LEN];
+        p[0] = 0x01;
+        p[1..3].copy_from_slice(&self.lpn_address.to_bytes_be());
+        p[3..5].copy_from_slice(&self.friend_address.to_bytes_be());
+        p[5..7].copy_from_slice(&self.lpn_counter.value().to_be_bytes());
+        p[7..9].copy_from_slice(&self.friend_counter.value().to_be_bytes());
+        p
+    }
+    /// Derives the friendship `NetworkKeys` from `net_key`. The network nonce is built the same
+    /// way as with the managed flooding credentials, only the keys differ.
+    pub fn network_keys(&self, net_key: &NetKey) -> NetworkKeys {
+        let (nid, encryption, privacy) = k2(net_key.key(), &self.p());
+        NetworkKeys::new(nid, encryption, privacy)
+    }
+}
+/// How much the Friend's RSSI weighs when picking an offer (1, 1.5, 2 or 2.5).
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub enum RSSIFactor {
+    Factor1 = 0b00,
+    Factor2 = 0b01,
+    Factor3 = 0b10,
+    Factor4 = 0b11,
+}
+impl RSSIFactor {
+    /// Uses the lower 2 bits of `bits`.
+    pub fn from_bits(bits: u8) -> RSSIFactor {
+        match bits & 0b11 {
+            0b00 => RSSIFactor::Factor1,
+            0b01 => RSSIFactor::Factor2,
+            0b10 => RSSIFactor::Factor3,
+            _ => RSSIFactor::Factor4,
+        }
+    }
+    /// The factor in halves (`2` is a factor of 1).
+    pub fn halves(self) -> i32 {
+        self as i32 + 2
+    }
+}
+/// How much the Friend's ReceiveWindow weighs when picking an offer (1, 1.5, 2 or 2.5).
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub enum ReceiveWindowFactor {
+    Window1 = 0b00,
+    Window2 = 0b01,
+    Window3 = 0b10,
+    Window4 = 0b11,
+}
+impl ReceiveWindowFactor {
+    /// Uses the lower 2 bits of `bits`.
+    pub fn from_bits(bits: u8) -> ReceiveWindowFactor {
+        match bits & 0b11 {
+            0b00 => ReceiveWindowFactor::Window1,
+            0b01 => ReceiveWindowFactor::Window2,
+            0b10 => ReceiveWindowFactor::Window3,
+            _ => ReceiveWindowFactor::Window4,
+        }
+    }
+    /// The factor in halves (`2` is a factor of 1).
+    pub fn halves(self) -> i32 {
+        self as i32 + 2
+    }
+}
+/// `log2` of the smallest Friend Queue the Low Power node accepts.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub enum MinQueueSizeLog {
+    Prohibited = 0b000,
+    N2 = 0b001,
+    N4 = 0b010,
+    N8 = 0b011,
+    N16 = 0b100,
+    N32 = 0b101,
+    N64 = 0b110,
+    N128 = 0b111,
+}
+impl MinQueueSizeLog {
+    /// Uses the lower 3 bits of `bits`.
+    pub fn from_bits(bits: u8) -> MinQueueSizeLog {
+        match bits & 0b111 {
+            0b000 => MinQueueSizeLog::Prohibited,
+            0b001 => MinQueueSizeLog::N2,
+            0b010 => MinQueueSizeLog::N4,
+            0b011 => MinQueueSizeLog::N8,
+            0b100 => MinQueueSizeLog::N16,
+            0b101 => MinQueueSizeLog::N32,
+            0b110 => MinQueueSizeLog::N64,
+            _ => MinQueueSizeLog::N128,
+        }
+    }
+    /// Smallest Friend Queue (in messages) the Low Power node accepts.
+    pub fn min_queue_size(self) -> u16 {
+        1_u16 << (self as u8)
+    }
+}
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct FriendPoll {
+    fsn: FSN,
+}
+impl FriendPoll {
+    pub fn new(fsn: FSN) -> FriendPoll {
+        FriendPoll { fsn }
+    }
+    pub fn fsn(&self) -> FSN {
+        self.fsn
+    }
+}
+/// Sent by the Friend in response to a Friend Poll (and to finish establishing the friendship).
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct FriendUpdate {
+    pub key_refresh_flag: KeyRefreshFlag,
+    pub iv_update_flag: IVUpdateFlag,
+    pub iv_index: IVIndex,
+    pub md: MD,
+}
+/// Broadcast (to the all-friends address) by a Low Power node looking for a Friend.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct FriendRequest {
+    pub criteria: Criteria,
+    pub receive_delay: ReceiveDelay,
+    pub poll_timeout: PollTimeout,
+    /// The Low Power node's previous Friend (if any) so it can clear the old friendship.
+    pub previous_address: Option<UnicastAddress>,
+    pub num_elements: u8,
+    pub lpn_counter: LPNCounter,
+}
+/// Sent by a Friend able to meet the criteria of a Friend Request.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct FriendOffer {
+    /// Time (in milliseconds) the Friend listens for a Friend Poll.
+    pub receive_window: u8,
+    pub queue_size: u8,
+    pub subscription_list_size: u8,
+    /// RSSI of the Friend Request as the Friend received it.
+    pub rssi: i8,
+    pub friend_counter: FriendCounter,
+}
+/// Sent by a Low Power node to add addresses to (or remove them from) its Friend Subscription
+/// List. The Friend answers with a `FriendSubscriptionListConfirm` with the same
+/// `transaction_number`.
+#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct FriendSubscriptionList {
+    pub transaction_number: u8,
+    pub addresses: Vec<Address>,
+}
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct FriendSubscriptionListConfirm {
+    pub transaction_number: u8,
+}
+/// Sent by a new Friend to the Low Power node's previous Friend to end their friendship. The
+/// previous Friend answers with a `FriendClearConfirm` with the same fields.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct FriendClear {
+    pub lpn_address: UnicastAddress,
+    pub lpn_counter: LPNCounter,
+}
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct FriendClearConfirm {
+    pub lpn_address: UnicastAddress,
+    pub lpn_counter: LPNCounter,
+}
+/// A Friend's view of a friendship with a Low Power node.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Friendship {
+    pub credentials: FriendshipCredentials,
+    pub poll_timeout: PollTimeout,
+    /// When the last Friend Poll (or the Friend Request) was received.
+    pub last_poll: Timestamp,
+}
+impl Friendship {
+    pub fn lpn_address(&self) -> UnicastAddress {
+        self.credentials.lpn_address
+    }
+    /// Returns how long is left on the PollTimeout timer as of `now`. `PollTimeout::ZERO` means
+    /// the timer expired and the friendship is over.
+    pub fn remaining_poll_timeout(&self, now: Timestamp) -> PollTimeout {
+        let elapsed = now.since(self.last_poll).unwrap_or_default();
+        self.poll_timeout
+            .as_duration()
+            .checked_sub(elapsed)
+            .map_or(PollTimeout::ZERO, PollTimeout::from_duration)
+    }
+}
+/// The friendships of a Friend node, keyed by the Low Power node's primary address.
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct Friendships(BTreeMap<UnicastAddress, Friendship>);
+impl Friendships {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Starts (or restarts) a friendship with `credentials.lpn_address` as of `now`.
+    pub fn establish(
+        &mut self,
+        credentials: FriendshipCredentials,
+        poll_timeout: PollTimeout,
+        now: Timestamp,
+    ) {
+        self.0.insert(
+            credentials.lpn_address,
+            Friendship {
+                credentials,
+                poll_timeout,
+                last_poll: now,
+            },
+        );
+    }
+    /// Restarts the PollTimeout timer of `lpn_address` after a Friend Poll. Returns `false` if
+    /// there's no friendship with `lpn_address`.
+    pub fn poll(&mut self, lpn_address: UnicastAddress, now: Timestamp) -> bool {
+        match self.0.get_mut(&lpn_address) {
+            Some(friendship) => {
+                friendship.last_poll = now;
+                true
+            }
+            None => false,
+        }
+    }
+    pub fn remove(&mut self, lpn_address: UnicastAddress) -> Option<Friendship> {
+        self.0.remove(&lpn_address)
+    }
+    pub fn get(&self, lpn_address: UnicastAddress) -> Option<&Friendship> {
+        self.0.get(&lpn_address)
+    }
+    /// Returns the remaining PollTimeout of `lpn_address` as of `now` or `PollTimeout::ZERO` if
+    /// `lpn_address` isn't a friend.
+    pub fn remaining_poll_timeout(
+        &self,
+        lpn_address: UnicastAddress,
+        now: Timestamp,
+    ) -> PollTimeout {
+        self.get(lpn_address)
+            .map_or(PollTimeout::ZERO, |friendship| {
+                friendship.remaining_poll_timeout(now)
+            })
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &Friendship> {
+        self.0.values()
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
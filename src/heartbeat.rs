@@ -0,0 +1,359 @@
+//! Heartbeats (Transport Control messages used to find out how nodes are connected). A node
+//! periodically publishes Heartbeats to a configured destination (see `HeartbeatPublication`)
+//! and counts the Heartbeats it's subscribed to (see `HeartbeatSubscription`). Both are set
+//! through the Config Server.
+use crate::address::{Address, UnicastAddress};
+use crate::control::Heartbeat;
+use crate::foundation::Features;
+use crate::mesh::{KeyIndex, NetKeyIndex, TTL};
+use crate::timestamp::{Timestamp, TimestampTrait};
+use core::time::Duration;
+
+/// Publication count that never runs out (Count Log `0xFF`).
+pub const COUNT_INDEFINITE: u16 = 0xFFFF;
+/// Highest Count Log or Period Log that isn't prohibited (besides the indefinite Count Log).
+pub const MAX_LOG: u8 = 0x11;
+/// Count Log of an indefinite publication count.
+pub const COUNT_LOG_INDEFINITE: u8 = 0xFF;
+/// Min Hops reported before any Heartbeat is received.
+pub const MAX_HOPS: u8 = 0x7F;
+
+/// Decodes a Period Log (`2^(log - 1)` seconds, `0` for `0`). Returns `None` for prohibited
+/// values.
+pub fn period_from_log(log: u8) -> Option<u16> {
+    match log {
+        0 => Some(0),
+        1..=0x10 => Some(1_u16 << (log - 1)),
+        MAX_LOG => Some(0xFFFF),
+        _ => None,
+    }
+}
+/// Decodes a publication Count Log (same as a Period Log but `0x11` is `0xFFFE` and `0xFF` is
+/// `COUNT_INDEFINITE`). Returns `None` for prohibited values.
+pub fn count_from_log(log: u8) -> Option<u16> {
+    match log {
+        MAX_LOG => Some(0xFFFE),
+        COUNT_LOG_INDEFINITE => Some(COUNT_INDEFINITE),
+        _ => period_from_log(log),
+    }
+}
+/// Encodes `value` as a log (`0` for `0`, otherwise the `n` where `2^(n - 1) <= value < 2^n`).
+pub fn to_log(value: u16) -> u8 {
+    (16 - value.leading_zeros()) as u8
+}
+
+/// Heartbeat Publication state. Periodic Heartbeats are sent every `period` until `count` runs
+/// out and an extra Heartbeat is sent whenever one of the `features` is enabled or disabled.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct HeartbeatPublication {
+    /// `Address::Unassigned` disables publishing.
+    pub destination: Address,
+    /// Periodic Heartbeats left to send. `COUNT_INDEFINITE` never runs out.
+    pub count: u16,
+    pub period_log: u8,
+    pub ttl: TTL,
+    /// Features that trigger a Heartbeat when they change.
+    pub features: Features,
+    pub net_key_index: NetKeyIndex,
+    next_periodic: Option<Timestamp>,
+    last_features: Option<Features>,
+}
+impl Default for HeartbeatPublication {
+    fn default() -> Self {
+        Self::new(
+            Address::Unassigned,
+            0,
+            0,
+            TTL::new(0),
+            Features::default(),
+            NetKeyIndex(KeyIndex::new(0)),
+        )
+    }
+}
+impl HeartbeatPublication {
+    /// Creates a publication that sends its first periodic Heartbeat as soon as it's polled.
+    pub fn new(
+        destination: Address,
+        count: u16,
+        period_log: u8,
+        ttl: TTL,
+        features: Features,
+        net_key_index: NetKeyIndex,
+    ) -> Self {
+        Self {
+            destination,
+            count,
+            period_log,
+            ttl,
+            features,
+            net_key_index,
+            next_periodic: None,
+            last_features: None,
+        }
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.destination.is_assigned()
+    }
+    /// Time between periodic Heartbeats or `None` if they're disabled.
+    pub fn period(&self) -> Option<Duration> {
+        match period_from_log(self.period_log) {
+            Some(0) | None => None,
+            Some(secs) => Some(Duration::from_secs(secs.into())),
+        }
+    }
+    /// Count Log of the periodic Heartbeats left to send.
+    pub fn count_log(&self) -> u8 {
+        if self.count == COUNT_INDEFINITE {
+            COUNT_LOG_INDEFINITE
+        } else {
+            to_log(self.count)
+        }
+    }
+    /// Returns the Heartbeat to send at `now` (with `features` being the currently enabled
+    /// features) or `None` if nothing is due. Every periodic Heartbeat uses up one of `count`.
+    /// Heartbeats triggered by a feature change don't.
+    pub fn poll(&mut self, now: Timestamp, features: Features) -> Option<Heartbeat> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let triggered = self.last_features.replace(features).map_or(false, |last| {
+            last.changed(features).intersects(self.features)
+        });
+        let periodic = match self.period() {
+            Some(period) if self.count > 0 => {
+                let due = self
+                    .next_periodic
+                    .map_or(true, |next_periodic| now >= next_periodic);
+                if due {
+                    self.next_periodic = Some(now + period);
+                    if self.count != COUNT_INDEFINITE {
+                        self.count -= 1;
+                    }
+                }
+                due
+            }
+            _ => false,
+        };
+        if triggered || periodic {
+            Some(Heartbeat {
+                init_ttl: self.ttl,
+                features,
+            })
+        } else {
+            None
+        }
+    }
+}
+/// Heartbeat Subscription state. Heartbeats from `source` to `destination` are counted (along
+/// with how many hops they took) until the subscription period ends.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct HeartbeatSubscription {
+    /// `Address::Unassigned` (or `Address::Unicast`).
+    pub source: Address,
+    pub destination: Address,
+    /// Heartbeats received. Stops at `0xFFFF`.
+    pub count: u16,
+    /// `MAX_HOPS` until a Heartbeat is received.
+    pub min_hops: u8,
+    pub max_hops: u8,
+    end: Option<Timestamp>,
+}
+impl Default for HeartbeatSubscription {
+    fn default() -> Self {
+        Self {
+            source: Address::Unassigned,
+            destination: Address::Unassigned,
+            count: 0,
+            min_hops: MAX_HOPS,
+            max_hops: 0,
+            end: None,
+        }
+    }
+}
+impl HeartbeatSubscription {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Subscribes to Heartbeats from `source` to `destination` for `period_log` (see
+    /// `period_from_log`) starting at `now`. An unassigned `source` or `destination` or a
+    /// period of `0` disables the subscription but keeps the last results. Returns `false`
+    /// (and changes nothing) if `period_log` is prohibited.
+    pub fn set(
+        &mut self,
+        source: Address,
+        destination: Address,
+        period_log: u8,
+        now: Timestamp,
+    ) -> bool {
+        let period = match period_from_log(period_log) {
+            Some(period) => period,
+            None => return false,
+        };
+        if source.is_unassigned() || destination.is_unassigned() || period == 0 {
+            self.source = Address::Unassigned;
+            self.destination = Address::Unassigned;
+            self.end = None;
+        } else {
+            *self = Self {
+                source,
+                destination,
+                end: Some(now + Duration::from_secs(period.into())),
+                ..Self::default()
+            };
+        }
+        true
+    }
+    /// Time left in the subscription period at `now`.
+    pub fn remaining(&self, now: Timestamp) -> Duration {
+        self.end.and_then(|end| now.until(end)).unwrap_or_default()
+    }
+    /// Period Log of the time left in the subscription period at `now`.
+    pub fn period_log(&self, now: Timestamp) -> u8 {
+        let secs = self.remaining(now).as_secs();
+        to_log(if secs > 0xFFFF { 0xFFFF } else { secs as u16 })
+    }
+    /// Count Log of the Heartbeats received.
+    pub fn count_log(&self) -> u8 {
+        to_log(self.count)
+    }
+    pub fn is_active(&self, now: Timestamp) -> bool {
+        self.remaining(now) > Duration::from_secs(0)
+    }
+    /// Counts `heartbeat` (received from `src` to `dst` with `ttl` at `now`) if it matches the
+    /// subscription. Returns `false` if it doesn't match, the period is over or its TTL is
+    /// higher than its initial TTL.
+    pub fn handle(
+        &mut self,
+        src: UnicastAddress,
+        dst: &Address,
+        heartbeat: &Heartbeat,
+        ttl: TTL,
+        now: Timestamp,
+    ) -> bool {
+        if self.source != Address::Unicast(src) || self.destination != *dst || !self.is_active(now)
+        {
+            return false;
+        }
+        let hops = match u8::from(heartbeat.init_ttl).checked_sub(u8::from(ttl)) {
+            Some(hops) => hops + 1,
+            None => return false,
+        };
+        self.count = self.count.saturating_add(1);
+        self.min_hops = self.min_hops.min(hops);
+        self.max_hops = self.max_hops.max(hops);
+        true
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::GroupAddress;
+    use crate::foundation::FeatureFlags;
+
+    #[test]
+    fn test_logs() {
+        assert_eq!(period_from_log(0), Some(0));
+        assert_eq!(period_from_log(1), Some(1));
+        assert_eq!(period_from_log(0x10), Some(0x8000));
+        assert_eq!(period_from_log(0x11), Some(0xFFFF));
+        assert_eq!(period_from_log(0x12), None);
+        assert_eq!(period_from_log(0xFF), None);
+        assert_eq!(count_from_log(0x11), Some(0xFFFE));
+        assert_eq!(count_from_log(0xFF), Some(COUNT_INDEFINITE));
+        assert_eq!(to_log(0), 0);
+        assert_eq!(to_log(1), 1);
+        assert_eq!(to_log(3), 2);
+        assert_eq!(to_log(4), 3);
+        assert_eq!(to_log(0xFFFE), 0x10);
+    }
+    #[test]
+    fn test_publication() {
+        let start = Timestamp::now();
+        let second = Duration::from_secs(1);
+        let features = Features::default();
+        let mut publication = HeartbeatPublication::new(
+            Address::Group(GroupAddress::new(0xC000)),
+            2,
+            2,
+            TTL::new(5),
+            features,
+            NetKeyIndex(KeyIndex::new(0)),
+        );
+        assert_eq!(publication.period(), Some(second * 2));
+        let heartbeat = publication
+            .poll(start, features)
+            .expect("first is sent right away");
+        assert_eq!(heartbeat.init_ttl, TTL::new(5));
+        assert_eq!(publication.count, 1);
+        assert!(publication.poll(start + second, features).is_none());
+        assert!(publication.poll(start + second * 2, features).is_some());
+        // Out of periodic Heartbeats.
+        assert_eq!(publication.count, 0);
+        assert!(publication.poll(start + second * 4, features).is_none());
+
+        assert!(HeartbeatPublication::default()
+            .poll(start, features)
+            .is_none());
+    }
+    #[test]
+    fn test_publication_triggered() {
+        let start = Timestamp::now();
+        let mut relay = Features::default();
+        relay.set(FeatureFlags::Relay);
+        let mut proxy = Features::default();
+        proxy.set(FeatureFlags::Proxy);
+        // Periodic Heartbeats disabled.
+        let mut publication = HeartbeatPublication::new(
+            Address::Unicast(UnicastAddress::new(0x0002)),
+            0,
+            0,
+            TTL::new(5),
+            relay,
+            NetKeyIndex(KeyIndex::new(0)),
+        );
+        assert!(publication.poll(start, Features::default()).is_none());
+        // Only changes to the features it publishes for trigger a Heartbeat.
+        assert!(publication.poll(start, proxy).is_none());
+        let heartbeat = publication.poll(start, relay).expect("relay was enabled");
+        assert_eq!(heartbeat.features, relay);
+        assert!(publication.poll(start, relay).is_none());
+        assert!(publication.poll(start, Features::default()).is_some());
+        assert_eq!(publication.count, 0);
+    }
+    #[test]
+    fn test_subscription() {
+        let start = Timestamp::now();
+        let src = UnicastAddress::new(0x0005);
+        let dst = Address::Unicast(UnicastAddress::new(0x0001));
+        let heartbeat = Heartbeat {
+            init_ttl: TTL::new(10),
+            features: Features::default(),
+        };
+        let mut subscription = HeartbeatSubscription::new();
+        assert!(!subscription.handle(src, &dst, &heartbeat, TTL::new(10), start));
+        assert!(!subscription.set(Address::Unicast(src), dst, 0x12, start));
+        // 4 seconds.
+        assert!(subscription.set(Address::Unicast(src), dst, 3, start));
+        assert_eq!(subscription.period_log(start), 3);
+        assert!(subscription.handle(src, &dst, &heartbeat, TTL::new(10), start));
+        assert!(subscription.handle(src, &dst, &heartbeat, TTL::new(7), start));
+        assert!(!subscription.handle(src, &dst, &heartbeat, TTL::new(11), start));
+        let other = UnicastAddress::new(0x0006);
+        assert!(!subscription.handle(other, &dst, &heartbeat, TTL::new(7), start));
+        assert_eq!(subscription.count, 2);
+        assert_eq!(subscription.count_log(), 2);
+        assert_eq!((subscription.min_hops, subscription.max_hops), (1, 4));
+
+        let end = start + Duration::from_secs(4);
+        assert!(!subscription.handle(src, &dst, &heartbeat, TTL::new(7), end));
+        assert_eq!(subscription.period_log(end), 0);
+        // Disabling keeps the results.
+        assert!(subscription.set(Address::Unassigned, dst, 3, end));
+        assert_eq!(subscription.destination, Address::Unassigned);
+        assert_eq!(subscription.count, 2);
+        // Setting it again starts over.
+        assert!(subscription.set(Address::Unicast(src), dst, 1, end));
+        assert_eq!(subscription.count, 0);
+        assert_eq!(subscription.min_hops, MAX_HOPS);
+    }
+}
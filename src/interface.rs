@@ -1,7 +1,30 @@
 //! Network Input/Output Interface and Filter.
-/*
+#[cfg(feature = "full_stack")]
+use crate::asyncs::sync::mpsc;
+use crate::stack::bearer::{BearerError, IncomingEncryptedNetworkPDU, OutgoingEncryptedNetworkPDU};
+use alloc::vec::Vec;
+
+/// Returned when an `InterfaceSink` doesn't take a PDU. The PDU is dropped either way.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub enum InterfaceError {
+    /// The sink is busy (just like a busy radio would be). Later PDUs may still go through.
+    Full,
+    /// The stack behind the sink shut down. No PDU will ever go through again.
+    Closed,
+}
 pub trait InterfaceSink {
-    fn consume_pdu(&mut self, pdu: &IncomingEncryptedNetworkPDU);
+    fn consume_pdu(&mut self, pdu: &IncomingEncryptedNetworkPDU) -> Result<(), InterfaceError>;
+}
+/// Feeds a stack's incoming bearer channel (like `FullStack::incoming_bearer`). Never waits on
+/// the stack so PDUs are dropped if the channel is full or closed.
+#[cfg(feature = "full_stack")]
+impl InterfaceSink for mpsc::Sender<IncomingEncryptedNetworkPDU> {
+    fn consume_pdu(&mut self, pdu: &IncomingEncryptedNetworkPDU) -> Result<(), InterfaceError> {
+        self.try_send(*pdu).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => InterfaceError::Full,
+            mpsc::error::TrySendError::Closed(_) => InterfaceError::Closed,
+        })
+    }
 }
 pub trait InputInterface<Sink: InterfaceSink> {
     fn take_sink(&mut self, sink: Sink);
@@ -39,4 +62,30 @@ impl<'a> OutputInterfaces<'a> {
         Ok(())
     }
 }
-*/
+#[cfg(all(test, feature = "full_stack"))]
+mod tests {
+    use super::*;
+    use crate::net;
+
+    fn incoming_pdu() -> IncomingEncryptedNetworkPDU {
+        IncomingEncryptedNetworkPDU {
+            encrypted_pdu: net::OwnedEncryptedPDU::new(&[0_u8; 20]).expect("valid length"),
+            rssi: None,
+            dont_relay: false,
+            from_proxy: false,
+        }
+    }
+    #[test]
+    fn test_closed_sink() {
+        let (mut sink, mut rx) = mpsc::channel(1);
+        assert_eq!(sink.consume_pdu(&incoming_pdu()), Ok(()));
+        assert_eq!(sink.consume_pdu(&incoming_pdu()), Err(InterfaceError::Full));
+        assert!(rx.try_recv().is_ok());
+        // The stack shutting down doesn't bring the bearer down with it.
+        drop(rx);
+        assert_eq!(
+            sink.consume_pdu(&incoming_pdu()),
+            Err(InterfaceError::Closed)
+        );
+    }
+}
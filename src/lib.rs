@@ -51,7 +51,9 @@ pub mod upper;
 
 pub mod device_state;
 pub mod friend;
+pub mod heartbeat;
 pub mod interface;
+pub mod proxy;
 pub mod relay;
 //pub mod mesh_io;
 //pub mod advertisement;
@@ -65,3 +67,6 @@ pub mod properties;
 
 #[cfg(test)]
 pub mod samples;
+
+#[cfg(any(all(test, feature = "full_stack"), feature = "test-util"))]
+pub mod test_util;
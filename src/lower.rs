@@ -9,6 +9,7 @@ use crate::bytes::ToFromBytesEndian;
 use crate::control::ControlOpcode;
 use crate::crypto::{AID, AKF, MIC};
 use crate::mesh::{IVIndex, SequenceNumber, CTL, U24};
+use core::cmp::Ordering;
 use core::convert::{TryFrom, TryInto};
 
 #[derive(Copy, Clone, Hash, Debug, Ord, PartialOrd, Eq, PartialEq)]
@@ -36,10 +37,23 @@ impl SeqZero {
         assert!(seq_zero <= SEQ_ZERO_MAX);
         SeqZero(seq_zero)
     }
+    /// Reconstructs the full Sequence Number this `SeqZero` was taken from. `seq` is the
+    /// reference, the Sequence Number of any segment of the same message. All of a message's
+    /// segments are sent within 8192 Sequence Numbers of the first one, so the original is the
+    /// largest Sequence Number `<= seq` whose lower 13 bits are `SeqZero`.
+    ///
+    /// If there's no such number (`seq` is too close to 0), the result is above `seq` and
+    /// `SeqAuth::valid_seq(seq)` is false for it.
     pub fn original_seq(&self, seq: SequenceNumber) -> SequenceNumber {
-        SequenceNumber(U24::new(
-            (u32::from(seq.0) & !u32::from(SEQ_ZERO_MAX)) & u32::from(self.0),
-        ))
+        let seq = seq.0.value();
+        let original = (seq & !u32::from(SEQ_ZERO_MAX)) | u32::from(self.0);
+        SequenceNumber(U24::new(if original > seq {
+            original
+                .checked_sub(u32::from(SEQ_ZERO_MAX) + 1)
+                .unwrap_or(original)
+        } else {
+            original
+        }))
     }
 }
 impl From<SequenceNumber> for SeqZero {
@@ -57,8 +71,12 @@ impl From<SeqZero> for u16 {
     }
 }
 
-/// 53-bit Sequence Authentication value.
-#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+/// 53-bit Sequence Authentication value. The IV Index and the Sequence Number of the first
+/// segment of a message (`SeqZero` is its lower 13 bits).
+///
+/// Ordered by `iv_index` first and then `first_seq` (like the 53-bit number itself) so a message
+/// sent after an IV Update is newer even though its Sequence Numbers start over.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub struct SeqAuth {
     pub first_seq: SequenceNumber,
     pub iv_index: IVIndex,
@@ -70,15 +88,36 @@ impl SeqAuth {
             iv_index,
         }
     }
+    /// Reconstructs the `SeqAuth` of a segmented message from its `SeqZero` and the Sequence
+    /// Number `seq` of one of its segments (see `SeqZero::original_seq`).
     pub fn from_seq_zero(seq_zero: SeqZero, seq: SequenceNumber, iv_index: IVIndex) -> Self {
         SeqAuth::new(seq_zero.original_seq(seq), iv_index)
     }
+    /// Returns if a segment with Sequence Number `new_seq` can belong to this message (it's
+    /// within 8192 Sequence Numbers after `first_seq`). The IV Index isn't checked.
     pub fn valid_seq(&self, new_seq: SequenceNumber) -> bool {
-        new_seq >= self.first_seq && (new_seq - self.first_seq) < 8192
+        new_seq >= self.first_seq && (new_seq - self.first_seq) <= u32::from(SEQ_ZERO_MAX)
     }
     pub fn seq_zero(&self) -> SeqZero {
         self.first_seq.into()
     }
+    /// Returns if this message was sent after `other` (a higher `SeqAuth`). Messages from the
+    /// same source with a lower or equal `SeqAuth` are old (replayed or already handled).
+    pub fn is_newer_than(&self, other: &SeqAuth) -> bool {
+        self > other
+    }
+}
+impl Ord for SeqAuth {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.iv_index
+            .cmp(&other.iv_index)
+            .then(self.first_seq.cmp(&other.first_seq))
+    }
+}
+impl PartialOrd for SeqAuth {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
 pub const SEG_MAX: u8 = 0x1F;
@@ -148,11 +187,21 @@ impl BlockAck {
             (self.0 & (1_u32 << u32::from(bit))) != 0
         }
     }
+    /// Sets every bit back to 0.
+    pub fn clear(&mut self) {
+        *self = Self::ZERO;
+    }
     /// Returns if the block ack (up to `seg_o` bits) is all 1s. False if otherwise
     #[must_use]
     pub fn all_acked(self, seg_o: SegO) -> bool {
         self == Self::new_all_acked(seg_o)
     }
+    /// Returns if every segment up to `seg_o` is acked. Unlike `all_acked`, bits past `seg_o`
+    /// are ignored.
+    #[must_use]
+    pub fn is_complete(self, seg_o: SegO) -> bool {
+        self.missing(seg_o).next().is_none()
+    }
     /// Returns the max length of BlockAck in bits (32).
     pub const fn max_len() -> usize {
         32
@@ -168,6 +217,14 @@ impl BlockAck {
         self = BlockAck(self.0 & Self::new_all_acked(seg_o).0);
         u8::from(seg_o) - self.count_ones()
     }
+    /// Returns the indices of the segments (up to `seg_o`) that haven't been acked yet.
+    pub fn missing(self, seg_o: SegO) -> impl Iterator<Item = u8> {
+        (0..=u8::from(seg_o)).filter(move |&seg_n| !self.get(seg_n))
+    }
+    /// Same as `missing` but as `SegN`s.
+    pub fn missing_segments(self, seg_o: SegO) -> impl Iterator<Item = SegN> {
+        self.missing(seg_o).map(SegN::new)
+    }
     pub fn valid_for(self, seg_o: SegO) -> bool {
         self <= Self::new_all_acked(seg_o)
     }
@@ -175,7 +232,7 @@ impl BlockAck {
     /// bits that were 1 that are now 0, it is invalid (`false`).
     pub fn is_new(self, maybe_new: Self) -> bool {
         // maybe_new can only have more new bits set than self.
-        maybe_new > self && ((maybe_new.0 & self.0) == maybe_new.0)
+        maybe_new > self && ((maybe_new.0 & self.0) == self.0)
     }
     pub const fn cancel() -> Self {
         BlockAck::new()
@@ -227,8 +284,8 @@ impl SegmentHeader {
     pub fn pack_into_u24(&self) -> U24 {
         let mut out = 0_u32;
         out |= u32::from(u8::from(self.seg_n));
-        out |= u32::from(u8::from(self.seg_o) << 5);
-        out |= u32::from(u16::from(self.seq_zero) << 10);
+        out |= u32::from(u8::from(self.seg_o)) << 5;
+        out |= u32::from(u16::from(self.seq_zero)) << 10;
         out |= u32::from(self.flag) << 23;
         U24::new(out)
     }
@@ -239,7 +296,7 @@ impl SegmentHeader {
         let seq_high = bytes[0] & 0x7F; //7 upper bits of SeqZero
         let seq_low = (bytes[1] & 0xFC) >> 2; // 6 Lower bits of SeqZero
         let seq_zero = SeqZero::new(u16::from(seq_low) | (u16::from(seq_high) << 6));
-        let seg_o_high = bytes[1] & 0x02;
+        let seg_o_high = bytes[1] & 0x03; // 2 upper bits of SegO
         let seg_n = SegN::new(bytes[2] & SEG_MAX);
         let seg_o_low = (bytes[2] & !SEG_MAX) >> 5;
         let seg_o = SegO::new(seg_o_low | (seg_o_high << 3));
@@ -270,7 +327,8 @@ impl UnsegmentedAccessPDU {
         assert!(data.len() <= UNSEGMENTED_ACCESS_PDU_MAX_LEN);
         assert!(data.len() >= UNSEGMENTED_ACCESS_PDU_MIN_LEN);
         let len = data.len();
-        let buf = [0_u8; UNSEGMENTED_ACCESS_PDU_MAX_LEN];
+        let mut buf = [0_u8; UNSEGMENTED_ACCESS_PDU_MAX_LEN];
+        buf[..len].copy_from_slice(data);
         UnsegmentedAccessPDU {
             aid,
             access_pdu_buf: buf,
@@ -281,6 +339,12 @@ impl UnsegmentedAccessPDU {
     pub const fn max_len() -> usize {
         UNSEGMENTED_ACCESS_PDU_MAX_LEN + 1
     }
+    /// Maximum Upper Transport Access PDU length (including the TransMIC) that fits in one
+    /// Unsegmented Access PDU.
+    #[must_use]
+    pub const fn max_upper_pdu_len() -> usize {
+        UNSEGMENTED_ACCESS_PDU_MAX_LEN
+    }
     #[must_use]
     pub fn upper_pdu_len(&self) -> usize {
         self.access_pdu_len
@@ -323,7 +387,7 @@ impl UnsegmentedAccessPDU {
         } else {
             let akf = AKF::from(bytes[0] & 0x40 != 0);
             let aid = AID::new_masked(bytes[0]);
-            if !bool::from(akf) && u8::from(aid) == 0 {
+            if !bool::from(akf) && u8::from(aid) != 0 {
                 // 0 AKF Flag with a non-zero AID.
                 return None;
             }
@@ -356,7 +420,7 @@ impl SegmentedAccessPDU {
         seg_n: SegN,
         data: &[u8],
     ) -> Self {
-        assert!(data.len() < Self::max_seg_len());
+        assert!(data.len() <= Self::max_seg_len());
         let mut buf = [0_u8; SegmentedAccessPDU::max_seg_len()];
         buf[..data.len()].copy_from_slice(data);
         Self {
@@ -420,7 +484,7 @@ impl SegmentedAccessPDU {
             // AKF is false but AID isn't zero.
             return None;
         }
-        let aid = if akf { None } else { Some(aid) };
+        let aid = if akf { Some(aid) } else { None };
         let packed_header = U24::from_bytes_be(&bytes[1..4]).expect("seq_zero should ways exist");
         let segment_header = SegmentHeader::unpack_from_u24(packed_header);
         Some(SegmentedAccessPDU::new(
@@ -531,7 +595,7 @@ impl SegmentedControlPDU {
     #[must_use]
     pub fn new(opcode: ControlOpcode, header: SegmentHeader, data: &[u8]) -> SegmentedControlPDU {
         assert!(
-            data.len() < MAX_SEGMENTED_CONTROL_PDU_LEN,
+            data.len() <= MAX_SEGMENTED_CONTROL_PDU_LEN,
             "segment overflow ({} > {})",
             data.len(),
             MAX_SEGMENTED_CONTROL_PDU_LEN
@@ -791,3 +855,87 @@ impl From<SegmentedPDU> for PDU {
         (&pdu).into()
     }
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn seq(seq: u32) -> SequenceNumber {
+        SequenceNumber(U24::new(seq))
+    }
+    #[test]
+    fn test_original_seq() {
+        // The reference is the first segment itself.
+        assert_eq!(
+            SeqZero::from(seq(0x12345)).original_seq(seq(0x12345)),
+            seq(0x12345)
+        );
+        // Later segment in the same 8192 block.
+        assert_eq!(
+            SeqZero::new(0x0345).original_seq(seq(0x12350)),
+            seq(0x12345)
+        );
+        // SeqZero's lower bits are higher than the reference's: the first segment was sent in
+        // the previous 8192 block.
+        assert_eq!(
+            SeqZero::new(0x1FFE).original_seq(seq(0x12001)),
+            seq(0x11FFE)
+        );
+        let seq_auth = SeqAuth::from_seq_zero(SeqZero::new(0x1FFE), seq(0x12001), IVIndex(0));
+        assert!(seq_auth.valid_seq(seq(0x12001)));
+        assert!(seq_auth.valid_seq(seq(0x11FFE + 8191)));
+        assert!(!seq_auth.valid_seq(seq(0x11FFE + 8192)));
+        assert!(!seq_auth.valid_seq(seq(0x11FFD)));
+        // No valid original below the reference.
+        let seq_auth = SeqAuth::from_seq_zero(SeqZero::new(0x0100), seq(0x0010), IVIndex(0));
+        assert!(!seq_auth.valid_seq(seq(0x0010)));
+    }
+    #[test]
+    fn test_seq_auth_order() {
+        let old = SeqAuth::new(seq(0x100), IVIndex(1));
+        assert!(SeqAuth::new(seq(0x101), IVIndex(1)).is_newer_than(&old));
+        assert!(!old.is_newer_than(&old));
+        assert!(!SeqAuth::new(seq(0xFF), IVIndex(1)).is_newer_than(&old));
+        // A new IV Index wins even with a lower Sequence Number.
+        assert!(SeqAuth::new(seq(0), IVIndex(2)).is_newer_than(&old));
+        assert!(SeqAuth::new(seq(0xFFFFFF), IVIndex(0)) < old);
+    }
+    #[test]
+    fn test_block_ack_missing() {
+        let seg_o = SegO::new(3);
+        let mut block_ack = BlockAck::ZERO;
+        assert_eq!(
+            block_ack.missing(seg_o).collect::<Vec<_>>(),
+            vec![0, 1, 2, 3]
+        );
+        assert!(!block_ack.is_complete(seg_o));
+        block_ack.set(0);
+        block_ack.set(2);
+        assert_eq!(block_ack.missing(seg_o).collect::<Vec<_>>(), vec![1, 3]);
+        block_ack.set(1);
+        block_ack.set(3);
+        assert_eq!(block_ack.missing(seg_o).next(), None);
+        assert!(block_ack.is_complete(seg_o));
+        assert!(block_ack.all_acked(seg_o));
+        block_ack.clear();
+        assert_eq!(block_ack, BlockAck::ZERO);
+    }
+    #[test]
+    fn test_block_ack_complete_ignores_extra_bits() {
+        let seg_o = SegO::new(1);
+        let block_ack = BlockAck(0b111);
+        assert!(block_ack.is_complete(seg_o));
+        assert!(!block_ack.all_acked(seg_o));
+        assert!(BlockAck(u32::MAX).is_complete(SegO::new(SEG_MAX)));
+    }
+    #[test]
+    fn test_block_ack_cancel() {
+        // A canceled transfer acks nothing so every segment is still missing.
+        let seg_o = SegO::new(2);
+        assert_eq!(
+            BlockAck::cancel().missing(seg_o).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert!(!BlockAck::cancel().is_complete(seg_o));
+    }
+}
@@ -69,17 +69,32 @@ impl From<bool> for IVUpdateFlag {
 pub struct TTL(u8);
 
 const TTL_MASK: u8 = 127;
-const TTL_MAX: u8 = 0xFF;
+const TTL_MAX: u8 = TTL_MASK;
 impl TTL {
+    /// Creates a new 7-bit `TTL`. Valid TTLs are `0..=127`.
+    /// # Panics
+    /// Panics if `v > 127`.
     #[must_use]
     pub fn new(v: u8) -> TTL {
-        assert!(
-            v <= TTL_MASK,
-            "TTL {} is bigger than max TTL {}",
-            v,
-            TTL_MASK
-        );
-        TTL(v)
+        match Self::try_new(v) {
+            Some(ttl) => ttl,
+            None => panic!("TTL {} is bigger than max TTL {}", v, TTL_MAX),
+        }
+    }
+    /// Creates a new 7-bit `TTL` or returns `None` if `v > 127`.
+    #[must_use]
+    pub fn try_new(v: u8) -> Option<TTL> {
+        if v > TTL_MAX {
+            None
+        } else {
+            Some(TTL(v))
+        }
+    }
+    /// Returns if the `TTL` is in the valid 7-bit range. Only deserialized `TTL`s could be
+    /// invalid.
+    #[must_use]
+    pub fn is_valid(self) -> bool {
+        self.0 <= TTL_MAX
     }
     /// Returns u8 with 7 lower bits being TTL and the 1 highest bit being a flag
     #[must_use]
@@ -96,6 +111,8 @@ impl TTL {
     pub const fn from_masked_u8(v: u8) -> TTL {
         TTL(v & TTL_MASK)
     }
+    /// Returns if a PDU with this `TTL` should be relayed. `0` and `1` are never relayed and
+    /// invalid TTLs (`>127`) are treated as non-relayable.
     #[must_use]
     pub fn should_relay(self) -> bool {
         match self.0 {
@@ -110,11 +127,7 @@ impl TryFrom<u8> for TTL {
     type Error = TTLConversationError;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        if value > TTL_MAX {
-            Err(TTLConversationError(()))
-        } else {
-            Ok(TTL(value))
-        }
+        Self::try_new(value).ok_or(TTLConversationError(()))
     }
 }
 impl From<TTL> for u8 {
@@ -540,6 +553,16 @@ impl TransmitInterval {
     pub fn new(count: TransmitCount, steps: TransmitSteps) -> Self {
         Self { count, steps }
     }
+    /// Number of times a PDU is transmitted (the first transmission plus `count` retransmissions).
+    pub fn transmissions(&self) -> u8 {
+        u8::from(self.count) + 1
+    }
+    /// Time between transmissions for the Network Transmit and Relay Retransmit states
+    /// (`(steps + 1) * 10ms`). Publish Retransmit uses 50ms steps instead (see
+    /// [`TransmitSteps::to_duration`]).
+    pub fn network_interval(&self) -> time::Duration {
+        time::Duration::from_millis((u64::from(u8::from(self.steps)) + 1) * 10)
+    }
 }
 impl From<TransmitInterval> for u8 {
     fn from(interval: TransmitInterval) -> Self {
@@ -586,4 +609,20 @@ mod tests {
     fn test_ttl_out_of_range() {
         let _ = TTL::new(128);
     }
+    #[test]
+    fn test_ttl_boundaries() {
+        assert_eq!(TTL::try_new(0).map(u8::from), Some(0));
+        assert_eq!(TTL::try_new(127).map(u8::from), Some(127));
+        assert_eq!(TTL::try_new(128), None);
+        assert_eq!(TTL::try_new(0xFF), None);
+        assert!(TTL::try_from(127_u8).is_ok());
+        assert!(TTL::try_from(128_u8).is_err());
+        assert!(TTL::try_from(0xFF_u8).is_err());
+        assert_eq!(TTL::from_masked_u8(0xFF), TTL::new(127));
+        assert_eq!(TTL::new_with_flag(0xFF), (TTL::new(127), true));
+        // Invalid TTLs can only come from deserializing but they must never be relayed.
+        assert!(!TTL(128).is_valid());
+        assert!(!TTL(128).should_relay());
+        assert!(!TTL(0xFF).should_relay());
+    }
 }
@@ -0,0 +1,667 @@
+//! Config Client for configuring other nodes (usually ones this node provisioned). Every request
+//! is secured with the node's DevKey (see [`DeviceState::dev_key_for`]) and waits for the
+//! matching status from the node (one about the same element, model and keys as the request),
+//! resending the request if none comes back in time.
+//!
+//! [`DeviceState::dev_key_for`]: crate::device_state::DeviceState::dev_key_for
+use crate::access::{ModelIdentifier, Opcode};
+use crate::address::{Address, UnicastAddress};
+use crate::asyncs::sync::mpsc;
+use crate::crypto::aes::MicSize;
+use crate::crypto::key::AppKey;
+use crate::foundation::publication::ModelPublishInfo;
+use crate::foundation::state::{FriendState, GATTProxyState, RelayRetransmit, RelayState};
+use crate::foundation::{CompositionDataPage0, StatusCode};
+use crate::mesh::{AppKeyIndex, ElementIndex, NetKeyIndex};
+use crate::models::config::messages::{
+    app_key_list, composition_data, friend, gatt_proxy, model_app, model_publication,
+    model_subscription, relay,
+};
+use crate::models::PackableMessage;
+use crate::stack::full::FullStack;
+use crate::stack::messages::{IncomingMessage, MessageKeys, OutgoingMessage};
+use crate::stack::SendError;
+use crate::upper::AppPayload;
+use alloc::boxed::Box;
+use core::time::Duration;
+
+/// How long to wait for a status before resending a request.
+pub const DEFAULT_TIMEOUT_SECS: u64 = 5;
+/// How many times a request is resent before giving up.
+pub const DEFAULT_RETRIES: usize = 2;
+
+/// Why a Config Client request failed.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum ConfigClientError {
+    /// The request couldn't be sent (other than its segments not getting acked).
+    Send(SendError),
+    /// No status came back after every retry.
+    Timeout,
+    /// The node answered with an error status.
+    Status(StatusCode),
+    /// The status had the right opcode but couldn't be unpacked.
+    MalformedStatus,
+    /// The request can't be packed (like subscribing to a unicast address or setting a feature
+    /// to `NotSupported`).
+    BadRequest,
+    /// The channel of incoming Access messages closed.
+    ChannelClosed,
+}
+impl From<SendError> for ConfigClientError {
+    fn from(e: SendError) -> Self {
+        ConfigClientError::Send(e)
+    }
+}
+/// Sends Config messages from the primary element of `stack`. Statuses are read from `replies`
+/// which should get the `FullStack::incoming_access` messages (or at least the ones that aren't
+/// handled by this node's own Config Server, see [`FullStack::handle_config_message`]). Messages
+/// that aren't the status being waited for (including stale statuses for another element, model
+/// or key) are dropped.
+pub struct ConfigClient<'a> {
+    stack: &'a FullStack,
+    replies: &'a mut mpsc::Receiver<IncomingMessage<Box<[u8]>>>,
+    net_key_index: NetKeyIndex,
+    timeout: Duration,
+    retries: usize,
+}
+impl<'a> ConfigClient<'a> {
+    pub fn new(
+        stack: &'a FullStack,
+        replies: &'a mut mpsc::Receiver<IncomingMessage<Box<[u8]>>>,
+        net_key_index: NetKeyIndex,
+    ) -> Self {
+        Self {
+            stack,
+            replies,
+            net_key_index,
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            retries: DEFAULT_RETRIES,
+        }
+    }
+    /// How long to wait for each status (default `DEFAULT_TIMEOUT_SECS`).
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    /// How many times to resend a request without a status (default `DEFAULT_RETRIES`).
+    pub fn with_retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+    /// Gets Composition Data page `0` from `node`.
+    pub async fn get_composition_data(
+        &mut self,
+        node: UnicastAddress,
+    ) -> Result<CompositionDataPage0, ConfigClientError> {
+        let status: composition_data::Status = self
+            .request(node, &composition_data::Get(0), |_| true)
+            .await?;
+        Ok(status.page)
+    }
+    /// Adds `app_key` to `node` bound to the NetKey at `net_index`.
+    pub async fn add_app_key(
+        &mut self,
+        node: UnicastAddress,
+        net_index: NetKeyIndex,
+        app_index: AppKeyIndex,
+        app_key: AppKey,
+    ) -> Result<(), ConfigClientError> {
+        let status: app_key_list::Status = self
+            .request(
+                node,
+                &app_key_list::Add {
+                    net_index,
+                    app_index,
+                    app_key,
+                },
+                |status: &app_key_list::Status| {
+                    status.net_index == net_index && status.app_index == app_index
+                },
+            )
+            .await?;
+        check(status.status_code)
+    }
+    /// Binds the AppKey at `app_index` to the model at `element_address` of `node`.
+    pub async fn bind_app_key(
+        &mut self,
+        node: UnicastAddress,
+        element_address: UnicastAddress,
+        app_index: AppKeyIndex,
+        model_identifier: ModelIdentifier,
+    ) -> Result<(), ConfigClientError> {
+        let status: model_app::Status = self
+            .request(
+                node,
+                &model_app::Bind {
+                    element_address,
+                    app_index,
+                    model_identifier,
+                },
+                |status: &model_app::Status| {
+                    status.element_address == element_address
+                        && status.app_index == app_index
+                        && status.model_identifier == model_identifier
+                },
+            )
+            .await?;
+        check(status.status_code)
+    }
+    /// Sets the publication of the model at `element_address` of `node`. A virtual publish
+    /// address needs its full Label UUID.
+    pub async fn set_model_publication(
+        &mut self,
+        node: UnicastAddress,
+        element_address: UnicastAddress,
+        model_identifier: ModelIdentifier,
+        publication: ModelPublishInfo,
+    ) -> Result<(), ConfigClientError> {
+        let matches = |status: &model_publication::Status| {
+            status.element_address == element_address && status.model_identifier == model_identifier
+        };
+        let status: model_publication::Status = match publication.address {
+            Address::Virtual(_) => {
+                self.request(
+                    node,
+                    &model_publication::VirtualSet {
+                        element_address,
+                        publication,
+                        model_identifier,
+                    },
+                    matches,
+                )
+                .await?
+            }
+            Address::VirtualHash(_) => return Err(ConfigClientError::BadRequest),
+            _ => {
+                self.request(
+                    node,
+                    &model_publication::NonVirtualSet {
+                        element_address,
+                        publication,
+                        model_identifier,
+                    },
+                    matches,
+                )
+                .await?
+            }
+        };
+        check(status.status_code)
+    }
+    /// Subscribes the model at `element_address` of `node` to a group or (full) virtual
+    /// `address`.
+    pub async fn add_subscription(
+        &mut self,
+        node: UnicastAddress,
+        element_address: UnicastAddress,
+        model_identifier: ModelIdentifier,
+        address: Address,
+    ) -> Result<(), ConfigClientError> {
+        // The status only has the hash of a virtual address (its `value`).
+        let matches = |status: &model_subscription::Status| {
+            status.element_address == element_address
+                && status.model_identifier == model_identifier
+                && status.address.value() == address.value()
+        };
+        let status: model_subscription::Status = match address {
+            Address::Group(_) => {
+                self.request(
+                    node,
+                    &model_subscription::NonVirtualAdd {
+                        element_address,
+                        address,
+                        model_identifier,
+                    },
+                    matches,
+                )
+                .await?
+            }
+            Address::Virtual(address) => {
+                self.request(
+                    node,
+                    &model_subscription::VirtualAdd {
+                        element_address,
+                        address,
+                        model_identifier,
+                    },
+                    matches,
+                )
+                .await?
+            }
+            _ => return Err(ConfigClientError::BadRequest),
+        };
+        check(status.status_code)
+    }
+    /// Sets the Relay and Relay Retransmit states of `node`. Returns the states the node reports
+    /// afterwards (a node without the Relay feature keeps `RelayState::NotSupported`).
+    pub async fn set_relay(
+        &mut self,
+        node: UnicastAddress,
+        state: RelayState,
+        retransmit: RelayRetransmit,
+    ) -> Result<relay::Status, ConfigClientError> {
+        if state == RelayState::NotSupported {
+            return Err(ConfigClientError::BadRequest);
+        }
+        self.request(node, &relay::Set(state, retransmit), |_| true)
+            .await
+    }
+    /// Sets the GATT Proxy state of `node`. Returns the state the node reports afterwards.
+    pub async fn set_gatt_proxy(
+        &mut self,
+        node: UnicastAddress,
+        state: GATTProxyState,
+    ) -> Result<GATTProxyState, ConfigClientError> {
+        if state == GATTProxyState::NotSupported {
+            return Err(ConfigClientError::BadRequest);
+        }
+        let status: gatt_proxy::Status = self
+            .request(node, &gatt_proxy::Set(state), |_| true)
+            .await?;
+        Ok(status.0)
+    }
+    /// Sets the Friend state of `node`. Returns the state the node reports afterwards.
+    pub async fn set_friend(
+        &mut self,
+        node: UnicastAddress,
+        state: FriendState,
+    ) -> Result<FriendState, ConfigClientError> {
+        if state == FriendState::NotSupported {
+            return Err(ConfigClientError::BadRequest);
+        }
+        let status: friend::Status = self.request(node, &friend::Set(state), |_| true).await?;
+        Ok(status.0)
+    }
+    /// Sends `request` to `node` and waits for a `Status` that `matches` it. The request is sent
+    /// again (with a new Sequence Number) if no status came back within `timeout` or if its
+    /// segments weren't acked.
+    async fn request<Request: PackableMessage, Status: PackableMessage>(
+        &mut self,
+        node: UnicastAddress,
+        request: &Request,
+        matches: impl Fn(&Status) -> bool,
+    ) -> Result<Status, ConfigClientError> {
+        let mut payload = alloc::vec![0_u8; Request::opcode().byte_len() + request.message_size()]
+            .into_boxed_slice();
+        request
+            .pack_with_opcode(&mut payload[..])
+            .map_err(|_| ConfigClientError::BadRequest)?;
+        for _ in 0..=self.retries {
+            let msg = OutgoingMessage {
+                app_payload: AppPayload::new(payload.clone()),
+                mic_size: MicSize::Small,
+                force_segment: false,
+                encryption_key: MessageKeys::Device(self.net_key_index),
+                iv_index: self
+                    .stack
+                    .internals_with(|internals| internals.device_state().tx_iv_index())
+                    .await,
+                source_element_index: ElementIndex(0),
+                dst: Address::Unicast(node),
+                ttl: None,
+            };
+            match self.stack.send_message(msg).await {
+                Ok(()) => (),
+                Err(SendError::AckTimeout) | Err(SendError::Unacknowledged) => continue,
+                Err(e) => return Err(e.into()),
+            }
+            let stack = self.stack;
+            if let Ok(status) = stack
+                .clock()
+                .timeout(self.timeout, self.next_status(node, &matches))
+                .await
+            {
+                return status;
+            }
+        }
+        Err(ConfigClientError::Timeout)
+    }
+    /// Waits for the next `Status` from `node` secured with a DevKey that `matches`.
+    async fn next_status<Status: PackableMessage>(
+        &mut self,
+        node: UnicastAddress,
+        matches: &impl Fn(&Status) -> bool,
+    ) -> Result<Status, ConfigClientError> {
+        loop {
+            let msg = self
+                .replies
+                .recv()
+                .await
+                .ok_or(ConfigClientError::ChannelClosed)?;
+            if msg.src != node || msg.app_key_index.is_some() {
+                continue;
+            }
+            match Opcode::split_from(&msg.payload[..]) {
+                Ok((opcode, parameters)) if opcode == Status::opcode() => {
+                    let status = Status::unpack_from(parameters)
+                        .map_err(|_| ConfigClientError::MalformedStatus)?;
+                    if matches(&status) {
+                        return Ok(status);
+                    }
+                }
+                _ => continue,
+            }
+        }
+    }
+}
+fn check(status_code: StatusCode) -> Result<(), ConfigClientError> {
+    match status_code {
+        StatusCode::Ok => Ok(()),
+        code => Err(ConfigClientError::Status(code)),
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::key::NetKey;
+    use crate::device_state::DeviceState;
+    use crate::foundation::publication::{PublishPeriod, PublishRetransmit, StepResolution, Steps};
+    use crate::foundation::{ProductID, ProductInfo, VersionID, CRPL};
+    use crate::mesh::{
+        CompanyID, ElementCount, KeyIndex, ModelID, TransmitCount, TransmitInterval, TransmitSteps,
+    };
+    use crate::random::Randomizable;
+    use crate::replay;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    const NODE: u16 = 0x0002;
+
+    fn packed<M: PackableMessage>(msg: &M) -> Box<[u8]> {
+        let mut payload =
+            alloc::vec![0_u8; M::opcode().byte_len() + msg.message_size()].into_boxed_slice();
+        msg.pack_with_opcode(&mut payload[..])
+            .ok()
+            .expect("buffer sized for the message");
+        payload
+    }
+    /// Stack whose own Config Server answers the requests (they're looped back). Every message
+    /// it doesn't answer is handed to the returned receiver.
+    fn looped_back_stack() -> (Arc<FullStack>, mpsc::Receiver<IncomingMessage<Box<[u8]>>>) {
+        looped_back_stack_with(|_| Vec::new())
+    }
+    /// Like `looped_back_stack` but requests the Config Server doesn't handle are answered with
+    /// the statuses (packed with their opcodes) `answer` returns for their payload.
+    fn looped_back_stack_with(
+        answer: impl Fn(&[u8]) -> Vec<Box<[u8]>> + Send + 'static,
+    ) -> (Arc<FullStack>, mpsc::Receiver<IncomingMessage<Box<[u8]>>>) {
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        let mut device_state = DeviceState::new(UnicastAddress::new(NODE), ElementCount(2));
+        device_state
+            .security_materials_mut()
+            .net_key_map
+            .insert(net_key_index, &NetKey::random_secure());
+        let mut stack = FullStack::new(
+            crate::stack::StackInternals::new(device_state),
+            replay::Cache::default(),
+            4,
+        );
+        let (_, closed) = mpsc::channel(1);
+        let mut incoming = core::mem::replace(&mut stack.incoming_access, closed);
+        let (mut replies_tx, replies) = mpsc::channel(4);
+        let stack = Arc::new(stack);
+        let server = stack.clone();
+        let product = ProductInfo {
+            cid: CompanyID(0x05F1),
+            pid: ProductID(0x0001),
+            vid: VersionID(0x0002),
+            crpl: CRPL(32),
+        };
+        tokio::spawn(async move {
+            while let Some(msg) = incoming.recv().await {
+                if server
+                    .handle_config_message(&msg, &product)
+                    .await
+                    .expect("status sent")
+                {
+                    continue;
+                }
+                let statuses = answer(&msg.payload[..]);
+                if statuses.is_empty() {
+                    if replies_tx.send(msg).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                let iv_index = server
+                    .internals_with(|internals| internals.device_state().tx_iv_index())
+                    .await;
+                for status in statuses {
+                    server
+                        .send_message(OutgoingMessage {
+                            app_payload: AppPayload::new(status),
+                            mic_size: MicSize::Small,
+                            force_segment: false,
+                            encryption_key: MessageKeys::Device(msg.net_key_index),
+                            iv_index,
+                            source_element_index: ElementIndex(0),
+                            dst: Address::Unicast(msg.src),
+                            ttl: None,
+                        })
+                        .await
+                        .expect("status sent");
+                }
+            }
+        });
+        (stack, replies)
+    }
+    #[tokio::test]
+    async fn test_config_client_requests() {
+        let (stack, mut replies) = looped_back_stack();
+        let node = UnicastAddress::new(NODE);
+        let mut client = ConfigClient::new(&stack, &mut replies, NetKeyIndex(KeyIndex::new(0)));
+
+        let page = client
+            .get_composition_data(node)
+            .await
+            .expect("composition data");
+        assert_eq!(page.elements().elements().len(), 2);
+
+        let app_index = AppKeyIndex(KeyIndex::new(1));
+        let app_key = AppKey::random_secure();
+        assert_eq!(
+            client
+                .add_app_key(node, NetKeyIndex(KeyIndex::new(0)), app_index, app_key)
+                .await,
+            Ok(())
+        );
+        // A different key under the same index is refused by the node.
+        assert_eq!(
+            client
+                .add_app_key(
+                    node,
+                    NetKeyIndex(KeyIndex::new(0)),
+                    app_index,
+                    AppKey::random_secure()
+                )
+                .await,
+            Err(ConfigClientError::Status(StatusCode::KeyIndexAlreadyStored))
+        );
+        let publication = ModelPublishInfo {
+            address: Address::from(0xC001),
+            app_key_index: app_index,
+            credential_flag: false,
+            ttl: None,
+            period: PublishPeriod::new(StepResolution::Second1, Steps::new(1)),
+            retransmit: PublishRetransmit::from(0),
+        };
+        assert_eq!(
+            client
+                .set_model_publication(
+                    node,
+                    node,
+                    ModelIdentifier::new_sig(ModelID(0x1000)),
+                    publication
+                )
+                .await,
+            Err(ConfigClientError::Status(StatusCode::InvalidModel))
+        );
+        assert_eq!(
+            client
+                .add_subscription(
+                    node,
+                    node,
+                    ModelIdentifier::new_sig(ModelID(0x1000)),
+                    Address::from(0x0005)
+                )
+                .await,
+            Err(ConfigClientError::BadRequest)
+        );
+        drop(client);
+        assert!(
+            stack
+                .internals_with(|internals| internals
+                    .device_state()
+                    .security_materials()
+                    .app_key_map
+                    .get_key(app_index)
+                    .is_some())
+                .await
+        );
+    }
+    #[tokio::test]
+    async fn test_config_client_timeout() {
+        let (stack, mut replies) = looped_back_stack();
+        let node = UnicastAddress::new(NODE);
+        // Our Config Server doesn't handle bindings so the request is never answered.
+        let mut client = ConfigClient::new(&stack, &mut replies, NetKeyIndex(KeyIndex::new(0)))
+            .with_timeout(Duration::from_millis(10))
+            .with_retries(1);
+        assert_eq!(
+            client
+                .bind_app_key(
+                    node,
+                    node,
+                    AppKeyIndex(KeyIndex::new(1)),
+                    ModelIdentifier::new_sig(ModelID(0x1000))
+                )
+                .await,
+            Err(ConfigClientError::Timeout)
+        );
+    }
+    #[tokio::test]
+    async fn test_config_client_bind_and_subscribe() {
+        // Every request gets a stale error status (for the other element) before its own.
+        let other_element = UnicastAddress::new(NODE + 1);
+        let (stack, mut replies) = looped_back_stack_with(move |payload| {
+            let (opcode, parameters) = Opcode::split_from(payload).expect("request has an opcode");
+            if opcode == model_app::Bind::opcode() {
+                let bind = model_app::Bind::unpack_from(parameters).expect("bind unpacks");
+                let status = model_app::Status {
+                    status_code: StatusCode::Ok,
+                    element_address: bind.element_address,
+                    app_index: bind.app_index,
+                    model_identifier: bind.model_identifier,
+                };
+                alloc::vec![
+                    packed(&model_app::Status {
+                        status_code: StatusCode::InvalidModel,
+                        element_address: other_element,
+                        ..status
+                    }),
+                    packed(&status),
+                ]
+            } else if opcode == model_subscription::NonVirtualAdd::opcode() {
+                let add = model_subscription::NonVirtualAdd::unpack_from(parameters)
+                    .expect("add unpacks");
+                let status = model_subscription::Status {
+                    status_code: StatusCode::Ok,
+                    element_address: add.element_address,
+                    address: add.address,
+                    model_identifier: add.model_identifier,
+                };
+                alloc::vec![
+                    packed(&model_subscription::Status {
+                        status_code: StatusCode::InsufficientResources,
+                        element_address: other_element,
+                        ..status
+                    }),
+                    packed(&status),
+                ]
+            } else if opcode == model_subscription::VirtualAdd::opcode() {
+                let add =
+                    model_subscription::VirtualAdd::unpack_from(parameters).expect("add unpacks");
+                alloc::vec![packed(&model_subscription::Status {
+                    status_code: StatusCode::Ok,
+                    element_address: add.element_address,
+                    address: Address::Virtual(add.address),
+                    model_identifier: add.model_identifier,
+                })]
+            } else {
+                Vec::new()
+            }
+        });
+        let node = UnicastAddress::new(NODE);
+        let model = ModelIdentifier::new_sig(ModelID(0x1000));
+        let mut client = ConfigClient::new(&stack, &mut replies, NetKeyIndex(KeyIndex::new(0)))
+            .with_timeout(Duration::from_secs(1))
+            .with_retries(0);
+        assert_eq!(
+            client
+                .bind_app_key(node, node, AppKeyIndex(KeyIndex::new(1)), model)
+                .await,
+            Ok(())
+        );
+        assert_eq!(
+            client
+                .add_subscription(node, node, model, Address::from(0xC001))
+                .await,
+            Ok(())
+        );
+        let label = crate::address::VirtualAddress::from(&crate::uuid::UUID([0x42_u8; 16]));
+        assert_eq!(
+            client
+                .add_subscription(node, node, model, Address::Virtual(label))
+                .await,
+            Ok(())
+        );
+    }
+    #[tokio::test]
+    async fn test_config_client_features() {
+        let (stack, mut replies) = looped_back_stack();
+        let node = UnicastAddress::new(NODE);
+        let mut client = ConfigClient::new(&stack, &mut replies, NetKeyIndex(KeyIndex::new(0)));
+        let retransmit = RelayRetransmit(TransmitInterval::new(
+            TransmitCount::new(3),
+            TransmitSteps::new(2),
+        ));
+        assert_eq!(
+            client
+                .set_relay(node, RelayState::Enabled, retransmit)
+                .await,
+            Ok(relay::Status(RelayState::Enabled, retransmit))
+        );
+        assert_eq!(
+            client.set_gatt_proxy(node, GATTProxyState::Enabled).await,
+            Ok(GATTProxyState::Enabled)
+        );
+        assert_eq!(
+            client.set_friend(node, FriendState::Enabled).await,
+            Ok(FriendState::Enabled)
+        );
+        assert_eq!(
+            client.set_friend(node, FriendState::NotSupported).await,
+            Err(ConfigClientError::BadRequest)
+        );
+        drop(client);
+        let states = stack
+            .internals_with(|internals| {
+                let device_state = internals.device_state();
+                (
+                    device_state.relay_state(),
+                    device_state.relay_retransmit(),
+                    device_state.gatt_proxy_state(),
+                    device_state.friend_state(),
+                )
+            })
+            .await;
+        assert_eq!(
+            states,
+            (
+                RelayState::Enabled,
+                retransmit,
+                GATTProxyState::Enabled,
+                FriendState::Enabled
+            )
+        );
+    }
+}
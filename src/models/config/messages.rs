@@ -1,3 +1,7 @@
+use crate::mesh::KeyIndex;
+use crate::models::MessagePackError;
+use core::convert::TryFrom;
+
 pub mod beacon {
     use crate::foundation::state::SecureNetworkBeaconState;
 
@@ -10,14 +14,75 @@ pub mod beacon {
 }
 
 pub mod composition_data {
+    use crate::access::Opcode;
     use crate::foundation::CompositionDataPage0;
+    use crate::models::config::ConfigOpcode;
+    use crate::models::{MessagePackError, PackableMessage};
 
+    /// Requests the Composition Data page `0`. Only page `0` is defined.
     #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
-    pub struct Get(u8);
+    pub struct Get(pub u8);
+    impl PackableMessage for Get {
+        fn opcode() -> Opcode {
+            ConfigOpcode::CompositionDataGet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            1
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.is_empty() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                buffer[0] = self.0;
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() == 1 {
+                Ok(Get(buffer[0]))
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
     #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
     pub struct Status {
-        page_number: u8,
-        page: CompositionDataPage0,
+        pub page_number: u8,
+        pub page: CompositionDataPage0,
+    }
+    impl PackableMessage for Status {
+        fn opcode() -> Opcode {
+            ConfigOpcode::CompositionDataStatus.into()
+        }
+
+        fn message_size(&self) -> usize {
+            1 + self.page.byte_len()
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < self.message_size() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                buffer[0] = self.page_number;
+                self.page.pack_into(&mut buffer[1..]);
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() < 1 + CompositionDataPage0::min_byte_len() {
+                Err(MessagePackError::BadLength)
+            } else {
+                Ok(Status {
+                    page_number: buffer[0],
+                    page: CompositionDataPage0::try_unpack_from(&buffer[1..])
+                        .ok_or(MessagePackError::BadBytes)?,
+                })
+            }
+        }
     }
 }
 pub mod default_ttl {
@@ -207,18 +272,19 @@ pub mod gatt_proxy {
         }
     }
 }
-pub mod relay {
+pub mod friend {
     use crate::access::Opcode;
-    use crate::foundation::state::{RelayRetransmit, RelayState};
+    use crate::foundation::state::FriendState;
     use crate::models::config::ConfigOpcode;
     use crate::models::{MessagePackError, PackableMessage};
     use core::convert::TryInto;
 
     #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
     pub struct Get;
+
     impl PackableMessage for Get {
         fn opcode() -> Opcode {
-            ConfigOpcode::RelayGet.into()
+            ConfigOpcode::FriendGet.into()
         }
 
         fn message_size(&self) -> usize {
@@ -238,75 +304,61 @@ pub mod relay {
         }
     }
     #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
-    pub struct Set(pub RelayState, pub RelayRetransmit);
+    pub struct Set(pub FriendState);
     impl PackableMessage for Set {
         fn opcode() -> Opcode {
-            ConfigOpcode::RelaySet.into()
+            ConfigOpcode::FriendSet.into()
         }
 
         fn message_size(&self) -> usize {
-            2
+            1
         }
 
         fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
-            if buffer.len() < self.message_size() {
+            if buffer.is_empty() {
                 Err(MessagePackError::SmallBuffer)
             } else {
                 buffer[0] = self.0.into();
-                buffer[1] = (self.1).0.into();
                 Ok(())
             }
         }
 
         fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
-            if buffer.len() == 2 {
-                Ok(Set(
-                    buffer[0]
-                        .try_into()
-                        .map_err(|_| MessagePackError::BadBytes)?,
-                    RelayRetransmit(
-                        buffer[1]
-                            .try_into()
-                            .map_err(|_| MessagePackError::BadBytes)?,
-                    ),
-                ))
+            if buffer.len() == 1 {
+                Ok(Set(buffer[0]
+                    .try_into()
+                    .map_err(|_| MessagePackError::BadBytes)?))
             } else {
                 Err(MessagePackError::BadLength)
             }
         }
     }
     #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
-    pub struct Status(pub RelayState, pub RelayRetransmit);
+    pub struct Status(pub FriendState);
     impl PackableMessage for Status {
         fn opcode() -> Opcode {
-            ConfigOpcode::RelayStatus.into()
+            ConfigOpcode::FriendStatus.into()
         }
 
         fn message_size(&self) -> usize {
-            2
+            1
         }
 
         fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
-            if buffer.len() < self.message_size() {
+            if buffer.is_empty() {
                 Err(MessagePackError::SmallBuffer)
             } else {
                 buffer[0] = self.0.into();
-                buffer[1] = (self.1).0.into();
                 Ok(())
             }
         }
 
         fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
-            if buffer.len() == 2 {
+            if buffer.len() == 1 {
                 Ok(Status(
                     buffer[0]
                         .try_into()
                         .map_err(|_| MessagePackError::BadBytes)?,
-                    RelayRetransmit(
-                        buffer[1]
-                            .try_into()
-                            .map_err(|_| MessagePackError::BadBytes)?,
-                    ),
                 ))
             } else {
                 Err(MessagePackError::BadLength)
@@ -314,193 +366,758 @@ pub mod relay {
         }
     }
 }
-pub mod model_publication {
-    use crate::access::{ModelIdentifier, Opcode};
-    use crate::address::{Address, UnicastAddress, ADDRESS_LEN};
+pub mod heartbeat_publication {
+    use super::{pack_key_index, unpack_key_index};
+    use crate::access::Opcode;
+    use crate::address::{Address, ADDRESS_LEN};
     use crate::bytes::ToFromBytesEndian;
-    use crate::foundation::publication::ModelPublishInfo;
-    use crate::foundation::StatusCode;
+    use crate::foundation::{Features, StatusCode};
+    use crate::mesh::{NetKeyIndex, TTL};
     use crate::models::config::ConfigOpcode;
     use crate::models::{MessagePackError, PackableMessage};
     use core::convert::TryInto;
 
-    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
-    pub struct Get {
-        pub element_address: UnicastAddress,
-        pub model_identifier: ModelIdentifier,
-    }
+    const COUNT_LOG_POS: usize = ADDRESS_LEN;
+    const PERIOD_LOG_POS: usize = COUNT_LOG_POS + 1;
+    const TTL_POS: usize = PERIOD_LOG_POS + 1;
+    const FEATURES_POS: usize = TTL_POS + 1;
+    const NET_KEY_INDEX_POS: usize = FEATURES_POS + 2;
+    const SET_LEN: usize = NET_KEY_INDEX_POS + 2;
 
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Get;
     impl PackableMessage for Get {
         fn opcode() -> Opcode {
-            ConfigOpcode::ModelPublicationGet.into()
+            ConfigOpcode::HeartbeatPublicationGet.into()
         }
 
         fn message_size(&self) -> usize {
-            ADDRESS_LEN + self.model_identifier.byte_len()
+            0
         }
 
-        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
-            if buffer.len() < self.message_size() {
-                Err(MessagePackError::SmallBuffer)
-            } else {
-                buffer[0..2].copy_from_slice(&self.element_address.to_bytes_le());
-                self.model_identifier.pack_into(&mut buffer[2..]);
-                Ok(())
-            }
+        fn pack_into(&self, _buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            Ok(())
         }
 
         fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
-            const SIG_LEN: usize = ADDRESS_LEN + ModelIdentifier::vendor_byte_len();
-            const VENDOR_LEN: usize = ADDRESS_LEN + ModelIdentifier::vendor_byte_len();
-            if buffer.len() == SIG_LEN || buffer.len() == VENDOR_LEN {
-                Ok(Get {
-                    element_address: UnicastAddress::from_bytes_le(&buffer[0..2])
-                        .ok_or(MessagePackError::BadBytes)?,
-                    model_identifier: ModelIdentifier::unpack_from(&buffer[2..])
-                        .ok_or(MessagePackError::BadBytes)?,
-                })
+            if buffer.is_empty() {
+                Ok(Get)
             } else {
                 Err(MessagePackError::BadLength)
             }
         }
     }
+    /// Sets where (and how often) Heartbeats are published. See
+    /// [`HeartbeatPublication`](crate::heartbeat::HeartbeatPublication).
     #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
-    pub struct NonVirtualSet {
-        pub element_address: UnicastAddress,
-        pub publication: ModelPublishInfo,
-        pub model_identifier: ModelIdentifier,
+    pub struct Set {
+        pub destination: Address,
+        pub count_log: u8,
+        pub period_log: u8,
+        pub ttl: TTL,
+        pub features: Features,
+        pub net_key_index: NetKeyIndex,
     }
-    impl PackableMessage for NonVirtualSet {
+    impl Set {
+        fn pack(&self, buffer: &mut [u8]) {
+            buffer[..COUNT_LOG_POS].copy_from_slice(&self.destination.to_bytes_le());
+            buffer[COUNT_LOG_POS] = self.count_log;
+            buffer[PERIOD_LOG_POS] = self.period_log;
+            buffer[TTL_POS] = self.ttl.into();
+            buffer[FEATURES_POS..NET_KEY_INDEX_POS].copy_from_slice(&self.features.to_bytes_le());
+            pack_key_index(self.net_key_index.0, &mut buffer[NET_KEY_INDEX_POS..]);
+        }
+        fn unpack(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() != SET_LEN {
+                return Err(MessagePackError::BadLength);
+            }
+            Ok(Set {
+                destination: Address::from_bytes_le(&buffer[..COUNT_LOG_POS])
+                    .ok_or(MessagePackError::BadBytes)?,
+                count_log: buffer[COUNT_LOG_POS],
+                period_log: buffer[PERIOD_LOG_POS],
+                ttl: buffer[TTL_POS]
+                    .try_into()
+                    .map_err(|_| MessagePackError::BadBytes)?,
+                features: Features::from_bytes_le(&buffer[FEATURES_POS..NET_KEY_INDEX_POS])
+                    .ok_or(MessagePackError::BadBytes)?,
+                net_key_index: NetKeyIndex(unpack_key_index(&buffer[NET_KEY_INDEX_POS..])?),
+            })
+        }
+    }
+    impl PackableMessage for Set {
         fn opcode() -> Opcode {
-            ConfigOpcode::ModelPublicationSet.into()
+            ConfigOpcode::HeartbeatPublicationSet.into()
         }
 
         fn message_size(&self) -> usize {
-            ADDRESS_LEN + ModelPublishInfo::NON_VIRTUAL_LEN + self.model_identifier.byte_len()
+            SET_LEN
         }
 
         fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
             if buffer.len() < self.message_size() {
                 Err(MessagePackError::SmallBuffer)
-            } else if self.publication.address.is_virtual() {
-                Err(MessagePackError::BadState)
             } else {
-                buffer[0..ADDRESS_LEN].copy_from_slice(&self.element_address.to_bytes_le());
-                self.publication.pack_into(
-                    &mut buffer[ADDRESS_LEN..ADDRESS_LEN + ModelPublishInfo::NON_VIRTUAL_LEN],
-                );
-                self.model_identifier
-                    .pack_into(&mut buffer[ADDRESS_LEN + ModelPublishInfo::NON_VIRTUAL_LEN..]);
+                self.pack(buffer);
                 Ok(())
             }
         }
 
         fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
-            const SIG_LEN: usize = ADDRESS_LEN
-                + ModelPublishInfo::NON_VIRTUAL_LEN
-                + ModelIdentifier::vendor_byte_len();
-            const VENDOR_LEN: usize = ADDRESS_LEN
-                + ModelPublishInfo::NON_VIRTUAL_LEN
-                + ModelIdentifier::vendor_byte_len();
-            if buffer.len() == SIG_LEN || buffer.len() == VENDOR_LEN {
-                Ok(NonVirtualSet {
-                    element_address: UnicastAddress::from_bytes_le(&buffer[..ADDRESS_LEN])
-                        .ok_or(MessagePackError::BadBytes)?,
-                    publication: ModelPublishInfo::unpack(
-                        &buffer[ADDRESS_LEN..ADDRESS_LEN + ModelPublishInfo::NON_VIRTUAL_LEN],
-                    )
-                    .ok_or(MessagePackError::BadBytes)?,
-                    model_identifier: ModelIdentifier::unpack_from(
-                        &buffer[ModelPublishInfo::NON_VIRTUAL_LEN + ADDRESS_LEN..],
-                    )
-                    .ok_or(MessagePackError::BadBytes)?,
-                })
-            } else {
-                Err(MessagePackError::BadLength)
-            }
+            Set::unpack(buffer)
         }
     }
+    /// The current Heartbeat Publication. `publication.count_log` is the Count Log of the
+    /// periodic Heartbeats left to send.
     #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
-    pub struct VirtualSet {
-        pub element_address: UnicastAddress,
-        pub publication: ModelPublishInfo,
-        pub model_identifier: ModelIdentifier,
+    pub struct Status {
+        pub status_code: StatusCode,
+        pub publication: Set,
     }
-    impl PackableMessage for VirtualSet {
+    impl PackableMessage for Status {
         fn opcode() -> Opcode {
-            ConfigOpcode::ModelPublicationVirtualAddressSet.into()
+            ConfigOpcode::HeartbeatPublicationStatus.into()
         }
 
         fn message_size(&self) -> usize {
-            ADDRESS_LEN + ModelPublishInfo::VIRTUAL_LEN + self.model_identifier.byte_len()
+            1 + SET_LEN
         }
 
         fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
             if buffer.len() < self.message_size() {
                 Err(MessagePackError::SmallBuffer)
-            } else if !self.publication.address.is_full_virtual() {
-                Err(MessagePackError::BadState)
             } else {
-                buffer[0..2].copy_from_slice(&self.element_address.to_bytes_le());
-                self.publication.pack_into(
-                    &mut buffer[ADDRESS_LEN..ADDRESS_LEN + ModelPublishInfo::VIRTUAL_LEN],
-                );
-                self.model_identifier
-                    .pack_into(&mut buffer[ADDRESS_LEN + ModelPublishInfo::VIRTUAL_LEN..]);
+                buffer[0] = self.status_code.into();
+                self.publication.pack(&mut buffer[1..]);
                 Ok(())
             }
         }
 
         fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
-            const SIG_LEN: usize =
-                ADDRESS_LEN + ModelPublishInfo::VIRTUAL_LEN + ModelIdentifier::vendor_byte_len();
-            const VENDOR_LEN: usize =
-                ADDRESS_LEN + ModelPublishInfo::VIRTUAL_LEN + ModelIdentifier::vendor_byte_len();
-            if buffer.len() == SIG_LEN || buffer.len() == VENDOR_LEN {
-                Ok(VirtualSet {
-                    element_address: UnicastAddress::from_bytes_le(&buffer[..ADDRESS_LEN])
-                        .ok_or(MessagePackError::BadBytes)?,
-                    publication: ModelPublishInfo::unpack(
-                        &buffer[ADDRESS_LEN..ADDRESS_LEN + ModelPublishInfo::VIRTUAL_LEN],
-                    )
-                    .ok_or(MessagePackError::BadBytes)?,
-                    model_identifier: ModelIdentifier::unpack_from(
-                        &buffer[ModelPublishInfo::VIRTUAL_LEN + ADDRESS_LEN..],
-                    )
-                    .ok_or(MessagePackError::BadBytes)?,
-                })
+            if buffer.is_empty() {
+                return Err(MessagePackError::BadLength);
+            }
+            Ok(Status {
+                status_code: buffer[0]
+                    .try_into()
+                    .map_err(|_| MessagePackError::BadBytes)?,
+                publication: Set::unpack(&buffer[1..])?,
+            })
+        }
+    }
+}
+pub mod heartbeat_subscription {
+    use crate::access::Opcode;
+    use crate::address::{Address, ADDRESS_LEN};
+    use crate::bytes::ToFromBytesEndian;
+    use crate::foundation::StatusCode;
+    use crate::models::config::ConfigOpcode;
+    use crate::models::{MessagePackError, PackableMessage};
+    use core::convert::TryInto;
+
+    const DESTINATION_POS: usize = ADDRESS_LEN;
+    const PERIOD_LOG_POS: usize = DESTINATION_POS + ADDRESS_LEN;
+    const SET_LEN: usize = PERIOD_LOG_POS + 1;
+    /// Status Code, Set fields, Count Log, Min Hops and Max Hops.
+    const STATUS_LEN: usize = 1 + SET_LEN + 3;
+
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Get;
+    impl PackableMessage for Get {
+        fn opcode() -> Opcode {
+            ConfigOpcode::HeartbeatSubscriptionGet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            0
+        }
+
+        fn pack_into(&self, _buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            Ok(())
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.is_empty() {
+                Ok(Get)
             } else {
                 Err(MessagePackError::BadLength)
             }
         }
     }
+    fn pack(source: Address, destination: Address, period_log: u8, buffer: &mut [u8]) {
+        buffer[..DESTINATION_POS].copy_from_slice(&source.to_bytes_le());
+        buffer[DESTINATION_POS..PERIOD_LOG_POS].copy_from_slice(&destination.to_bytes_le());
+        buffer[PERIOD_LOG_POS] = period_log;
+    }
+    fn unpack(buffer: &[u8]) -> Result<(Address, Address, u8), MessagePackError> {
+        Ok((
+            Address::from_bytes_le(&buffer[..DESTINATION_POS]).ok_or(MessagePackError::BadBytes)?,
+            Address::from_bytes_le(&buffer[DESTINATION_POS..PERIOD_LOG_POS])
+                .ok_or(MessagePackError::BadBytes)?,
+            buffer[PERIOD_LOG_POS],
+        ))
+    }
+    /// Subscribes to the Heartbeats from `source` to `destination` for `period_log`. See
+    /// [`HeartbeatSubscription`](crate::heartbeat::HeartbeatSubscription).
     #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
-    pub struct Status {
-        pub status_code: StatusCode,
-        pub element_address: UnicastAddress,
-        pub publication: ModelPublishInfo,
-        pub model_identifier: ModelIdentifier,
+    pub struct Set {
+        pub source: Address,
+        pub destination: Address,
+        pub period_log: u8,
     }
-    impl PackableMessage for Status {
+    impl PackableMessage for Set {
         fn opcode() -> Opcode {
-            ConfigOpcode::ModelPublicationStatus.into()
+            ConfigOpcode::HeartbeatSubscriptionSet.into()
         }
 
         fn message_size(&self) -> usize {
-            1 + ADDRESS_LEN + ModelPublishInfo::NON_VIRTUAL_LEN + self.model_identifier.byte_len()
+            SET_LEN
         }
 
         fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
             if buffer.len() < self.message_size() {
                 Err(MessagePackError::SmallBuffer)
             } else {
-                // Status Message don't send the full 128-bit UUID, only the hash.
-                let publish_info = if let Some(hash) = self.publication.address.virtual_hash() {
-                    let mut pub_info = self.publication;
-                    pub_info.address = Address::VirtualHash(hash);
-                    pub_info
-                } else {
-                    self.publication
+                pack(self.source, self.destination, self.period_log, buffer);
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() != SET_LEN {
+                return Err(MessagePackError::BadLength);
+            }
+            let (source, destination, period_log) = unpack(buffer)?;
+            Ok(Set {
+                source,
+                destination,
+                period_log,
+            })
+        }
+    }
+    /// The current Heartbeat Subscription. `period_log` is the Period Log of the time left.
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Status {
+        pub status_code: StatusCode,
+        pub source: Address,
+        pub destination: Address,
+        pub period_log: u8,
+        pub count_log: u8,
+        pub min_hops: u8,
+        pub max_hops: u8,
+    }
+    impl PackableMessage for Status {
+        fn opcode() -> Opcode {
+            ConfigOpcode::HeartbeatSubscriptionStatus.into()
+        }
+
+        fn message_size(&self) -> usize {
+            STATUS_LEN
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < self.message_size() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                buffer[0] = self.status_code.into();
+                pack(
+                    self.source,
+                    self.destination,
+                    self.period_log,
+                    &mut buffer[1..],
+                );
+                buffer[1 + SET_LEN] = self.count_log;
+                buffer[2 + SET_LEN] = self.min_hops;
+                buffer[3 + SET_LEN] = self.max_hops;
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() != STATUS_LEN {
+                return Err(MessagePackError::BadLength);
+            }
+            let (source, destination, period_log) = unpack(&buffer[1..])?;
+            Ok(Status {
+                status_code: buffer[0]
+                    .try_into()
+                    .map_err(|_| MessagePackError::BadBytes)?,
+                source,
+                destination,
+                period_log,
+                count_log: buffer[1 + SET_LEN],
+                min_hops: buffer[2 + SET_LEN],
+                max_hops: buffer[3 + SET_LEN],
+            })
+        }
+    }
+}
+pub mod low_power_node_poll_timeout {
+    use crate::access::Opcode;
+    use crate::address::{UnicastAddress, ADDRESS_LEN};
+    use crate::bytes::ToFromBytesEndian;
+    use crate::friend::PollTimeout;
+    use crate::mesh::U24;
+    use crate::models::config::ConfigOpcode;
+    use crate::models::{MessagePackError, PackableMessage};
+
+    const POLL_TIMEOUT_LEN: usize = 3;
+    /// Asks a Friend for the current PollTimeout timer of the Low Power node `lpn_address`.
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Get {
+        pub lpn_address: UnicastAddress,
+    }
+    impl PackableMessage for Get {
+        fn opcode() -> Opcode {
+            ConfigOpcode::LowPowerNodePollTimeoutGet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            ADDRESS_LEN
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < self.message_size() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                buffer[..ADDRESS_LEN].copy_from_slice(&self.lpn_address.to_bytes_le());
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() == ADDRESS_LEN {
+                Ok(Get {
+                    lpn_address: UnicastAddress::from_bytes_le(buffer)
+                        .ok_or(MessagePackError::BadBytes)?,
+                })
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+    /// The current PollTimeout timer of `lpn_address`. `PollTimeout::ZERO` if the node isn't a
+    /// friend of that Low Power node.
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Status {
+        pub lpn_address: UnicastAddress,
+        pub poll_timeout: PollTimeout,
+    }
+    impl PackableMessage for Status {
+        fn opcode() -> Opcode {
+            ConfigOpcode::LowPowerNodePollTimeoutStatus.into()
+        }
+
+        fn message_size(&self) -> usize {
+            ADDRESS_LEN + POLL_TIMEOUT_LEN
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < self.message_size() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                buffer[..ADDRESS_LEN].copy_from_slice(&self.lpn_address.to_bytes_le());
+                buffer[ADDRESS_LEN..ADDRESS_LEN + POLL_TIMEOUT_LEN]
+                    .copy_from_slice(&self.poll_timeout.steps().to_bytes_le());
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() == ADDRESS_LEN + POLL_TIMEOUT_LEN {
+                Ok(Status {
+                    lpn_address: UnicastAddress::from_bytes_le(&buffer[..ADDRESS_LEN])
+                        .ok_or(MessagePackError::BadBytes)?,
+                    poll_timeout: PollTimeout::new(
+                        U24::from_bytes_le(&buffer[ADDRESS_LEN..])
+                            .ok_or(MessagePackError::BadBytes)?,
+                    ),
+                })
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+}
+pub mod relay {
+    use crate::access::Opcode;
+    use crate::foundation::state::{RelayRetransmit, RelayState};
+    use crate::models::config::ConfigOpcode;
+    use crate::models::{MessagePackError, PackableMessage};
+    use core::convert::TryInto;
+
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Get;
+    impl PackableMessage for Get {
+        fn opcode() -> Opcode {
+            ConfigOpcode::RelayGet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            0
+        }
+
+        fn pack_into(&self, _buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            Ok(())
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.is_empty() {
+                Ok(Get)
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Set(pub RelayState, pub RelayRetransmit);
+    impl PackableMessage for Set {
+        fn opcode() -> Opcode {
+            ConfigOpcode::RelaySet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            2
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < self.message_size() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                buffer[0] = self.0.into();
+                buffer[1] = (self.1).0.into();
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() == 2 {
+                Ok(Set(
+                    buffer[0]
+                        .try_into()
+                        .map_err(|_| MessagePackError::BadBytes)?,
+                    RelayRetransmit(
+                        buffer[1]
+                            .try_into()
+                            .map_err(|_| MessagePackError::BadBytes)?,
+                    ),
+                ))
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Status(pub RelayState, pub RelayRetransmit);
+    impl PackableMessage for Status {
+        fn opcode() -> Opcode {
+            ConfigOpcode::RelayStatus.into()
+        }
+
+        fn message_size(&self) -> usize {
+            2
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < self.message_size() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                buffer[0] = self.0.into();
+                buffer[1] = (self.1).0.into();
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() == 2 {
+                Ok(Status(
+                    buffer[0]
+                        .try_into()
+                        .map_err(|_| MessagePackError::BadBytes)?,
+                    RelayRetransmit(
+                        buffer[1]
+                            .try_into()
+                            .map_err(|_| MessagePackError::BadBytes)?,
+                    ),
+                ))
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+}
+pub mod network_transmit {
+    use crate::access::Opcode;
+    use crate::foundation::state::NetworkTransmit;
+    use crate::models::config::ConfigOpcode;
+    use crate::models::{MessagePackError, PackableMessage};
+
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Get;
+    impl PackableMessage for Get {
+        fn opcode() -> Opcode {
+            ConfigOpcode::NetworkTransmitGet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            0
+        }
+
+        fn pack_into(&self, _buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            Ok(())
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.is_empty() {
+                Ok(Get)
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Set(pub NetworkTransmit);
+    impl PackableMessage for Set {
+        fn opcode() -> Opcode {
+            ConfigOpcode::NetworkTransmitSet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            1
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.is_empty() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                buffer[0] = (self.0).0.into();
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() == 1 {
+                Ok(Set(NetworkTransmit(buffer[0].into())))
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Status(pub NetworkTransmit);
+    impl PackableMessage for Status {
+        fn opcode() -> Opcode {
+            ConfigOpcode::NetworkTransmitStatus.into()
+        }
+
+        fn message_size(&self) -> usize {
+            1
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.is_empty() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                buffer[0] = (self.0).0.into();
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() == 1 {
+                Ok(Status(NetworkTransmit(buffer[0].into())))
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+}
+pub mod model_publication {
+    use crate::access::{ModelIdentifier, Opcode};
+    use crate::address::{Address, UnicastAddress, ADDRESS_LEN};
+    use crate::bytes::ToFromBytesEndian;
+    use crate::foundation::publication::ModelPublishInfo;
+    use crate::foundation::StatusCode;
+    use crate::models::config::ConfigOpcode;
+    use crate::models::{MessagePackError, PackableMessage};
+    use core::convert::TryInto;
+
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Get {
+        pub element_address: UnicastAddress,
+        pub model_identifier: ModelIdentifier,
+    }
+
+    impl PackableMessage for Get {
+        fn opcode() -> Opcode {
+            ConfigOpcode::ModelPublicationGet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            ADDRESS_LEN + self.model_identifier.byte_len()
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < self.message_size() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                buffer[0..2].copy_from_slice(&self.element_address.to_bytes_le());
+                self.model_identifier.pack_into(&mut buffer[2..]);
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            const SIG_LEN: usize = ADDRESS_LEN + ModelIdentifier::sig_byte_len();
+            const VENDOR_LEN: usize = ADDRESS_LEN + ModelIdentifier::vendor_byte_len();
+            if buffer.len() == SIG_LEN || buffer.len() == VENDOR_LEN {
+                Ok(Get {
+                    element_address: UnicastAddress::from_bytes_le(&buffer[0..2])
+                        .ok_or(MessagePackError::BadBytes)?,
+                    model_identifier: ModelIdentifier::unpack_from(&buffer[2..])
+                        .ok_or(MessagePackError::BadBytes)?,
+                })
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct NonVirtualSet {
+        pub element_address: UnicastAddress,
+        pub publication: ModelPublishInfo,
+        pub model_identifier: ModelIdentifier,
+    }
+    impl PackableMessage for NonVirtualSet {
+        fn opcode() -> Opcode {
+            ConfigOpcode::ModelPublicationSet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            ADDRESS_LEN + ModelPublishInfo::NON_VIRTUAL_LEN + self.model_identifier.byte_len()
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < self.message_size() {
+                Err(MessagePackError::SmallBuffer)
+            } else if self.publication.address.is_virtual() {
+                Err(MessagePackError::BadState)
+            } else {
+                buffer[0..ADDRESS_LEN].copy_from_slice(&self.element_address.to_bytes_le());
+                self.publication.pack_into(
+                    &mut buffer[ADDRESS_LEN..ADDRESS_LEN + ModelPublishInfo::NON_VIRTUAL_LEN],
+                );
+                self.model_identifier
+                    .pack_into(&mut buffer[ADDRESS_LEN + ModelPublishInfo::NON_VIRTUAL_LEN..]);
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            const SIG_LEN: usize =
+                ADDRESS_LEN + ModelPublishInfo::NON_VIRTUAL_LEN + ModelIdentifier::sig_byte_len();
+            const VENDOR_LEN: usize = ADDRESS_LEN
+                + ModelPublishInfo::NON_VIRTUAL_LEN
+                + ModelIdentifier::vendor_byte_len();
+            if buffer.len() == SIG_LEN || buffer.len() == VENDOR_LEN {
+                Ok(NonVirtualSet {
+                    element_address: UnicastAddress::from_bytes_le(&buffer[..ADDRESS_LEN])
+                        .ok_or(MessagePackError::BadBytes)?,
+                    publication: ModelPublishInfo::unpack(
+                        &buffer[ADDRESS_LEN..ADDRESS_LEN + ModelPublishInfo::NON_VIRTUAL_LEN],
+                    )
+                    .ok_or(MessagePackError::BadBytes)?,
+                    model_identifier: ModelIdentifier::unpack_from(
+                        &buffer[ModelPublishInfo::NON_VIRTUAL_LEN + ADDRESS_LEN..],
+                    )
+                    .ok_or(MessagePackError::BadBytes)?,
+                })
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct VirtualSet {
+        pub element_address: UnicastAddress,
+        pub publication: ModelPublishInfo,
+        pub model_identifier: ModelIdentifier,
+    }
+    impl PackableMessage for VirtualSet {
+        fn opcode() -> Opcode {
+            ConfigOpcode::ModelPublicationVirtualAddressSet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            ADDRESS_LEN + ModelPublishInfo::VIRTUAL_LEN + self.model_identifier.byte_len()
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < self.message_size() {
+                Err(MessagePackError::SmallBuffer)
+            } else if !self.publication.address.is_full_virtual() {
+                Err(MessagePackError::BadState)
+            } else {
+                buffer[0..2].copy_from_slice(&self.element_address.to_bytes_le());
+                self.publication.pack_into(
+                    &mut buffer[ADDRESS_LEN..ADDRESS_LEN + ModelPublishInfo::VIRTUAL_LEN],
+                );
+                self.model_identifier
+                    .pack_into(&mut buffer[ADDRESS_LEN + ModelPublishInfo::VIRTUAL_LEN..]);
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            const SIG_LEN: usize =
+                ADDRESS_LEN + ModelPublishInfo::VIRTUAL_LEN + ModelIdentifier::sig_byte_len();
+            const VENDOR_LEN: usize =
+                ADDRESS_LEN + ModelPublishInfo::VIRTUAL_LEN + ModelIdentifier::vendor_byte_len();
+            if buffer.len() == SIG_LEN || buffer.len() == VENDOR_LEN {
+                Ok(VirtualSet {
+                    element_address: UnicastAddress::from_bytes_le(&buffer[..ADDRESS_LEN])
+                        .ok_or(MessagePackError::BadBytes)?,
+                    publication: ModelPublishInfo::unpack(
+                        &buffer[ADDRESS_LEN..ADDRESS_LEN + ModelPublishInfo::VIRTUAL_LEN],
+                    )
+                    .ok_or(MessagePackError::BadBytes)?,
+                    model_identifier: ModelIdentifier::unpack_from(
+                        &buffer[ModelPublishInfo::VIRTUAL_LEN + ADDRESS_LEN..],
+                    )
+                    .ok_or(MessagePackError::BadBytes)?,
+                })
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Status {
+        pub status_code: StatusCode,
+        pub element_address: UnicastAddress,
+        pub publication: ModelPublishInfo,
+        pub model_identifier: ModelIdentifier,
+    }
+    impl PackableMessage for Status {
+        fn opcode() -> Opcode {
+            ConfigOpcode::ModelPublicationStatus.into()
+        }
+
+        fn message_size(&self) -> usize {
+            1 + ADDRESS_LEN + ModelPublishInfo::NON_VIRTUAL_LEN + self.model_identifier.byte_len()
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < self.message_size() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                // Status Message don't send the full 128-bit UUID, only the hash.
+                let publish_info = if let Some(hash) = self.publication.address.virtual_hash() {
+                    let mut pub_info = self.publication;
+                    pub_info.address = Address::VirtualHash(hash);
+                    pub_info
+                } else {
+                    self.publication
                 };
                 buffer[0] = self.status_code.into();
                 buffer[1..1 + ADDRESS_LEN].copy_from_slice(&self.element_address.to_bytes_le());
@@ -518,25 +1135,143 @@ pub mod model_publication {
             const SIG_LEN: usize = 1
                 + ADDRESS_LEN
                 + ModelPublishInfo::NON_VIRTUAL_LEN
-                + ModelIdentifier::vendor_byte_len();
+                + ModelIdentifier::sig_byte_len();
             const VENDOR_LEN: usize = 1
                 + ADDRESS_LEN
                 + ModelPublishInfo::NON_VIRTUAL_LEN
                 + ModelIdentifier::vendor_byte_len();
             if buffer.len() == SIG_LEN || buffer.len() == VENDOR_LEN {
-                Ok(Status {
-                    status_code: buffer[0]
+                Ok(Status {
+                    status_code: buffer[0]
+                        .try_into()
+                        .map_err(|_| MessagePackError::BadBytes)?,
+                    element_address: UnicastAddress::from_bytes_le(&buffer[1..1 + ADDRESS_LEN])
+                        .ok_or(MessagePackError::BadBytes)?,
+                    publication: ModelPublishInfo::unpack(
+                        &buffer
+                            [1 + ADDRESS_LEN..1 + ADDRESS_LEN + ModelPublishInfo::NON_VIRTUAL_LEN],
+                    )
+                    .ok_or(MessagePackError::BadBytes)?,
+                    model_identifier: ModelIdentifier::unpack_from(
+                        &buffer[1 + ModelPublishInfo::NON_VIRTUAL_LEN + ADDRESS_LEN..],
+                    )
+                    .ok_or(MessagePackError::BadBytes)?,
+                })
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+}
+pub mod model_subscription {
+    use crate::access::{ModelIdentifier, Opcode};
+    use crate::address::{Address, UnicastAddress, VirtualAddress, ADDRESS_LEN};
+    use crate::bytes::ToFromBytesEndian;
+    use crate::foundation::StatusCode;
+    use crate::models::config::ConfigOpcode;
+    use crate::models::{MessagePackError, PackableMessage};
+    use crate::uuid::UUID;
+    use alloc::vec::Vec;
+    use core::convert::TryInto;
+
+    const UUID_LEN: usize = 16;
+
+    /// Subscribes a model to a group address.
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct NonVirtualAdd {
+        pub element_address: UnicastAddress,
+        pub address: Address,
+        pub model_identifier: ModelIdentifier,
+    }
+    impl PackableMessage for NonVirtualAdd {
+        fn opcode() -> Opcode {
+            ConfigOpcode::ModelSubscriptionAdd.into()
+        }
+
+        fn message_size(&self) -> usize {
+            ADDRESS_LEN * 2 + self.model_identifier.byte_len()
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < self.message_size() {
+                Err(MessagePackError::SmallBuffer)
+            } else if !self.address.is_group() {
+                Err(MessagePackError::BadState)
+            } else {
+                buffer[..ADDRESS_LEN].copy_from_slice(&self.element_address.to_bytes_le());
+                buffer[ADDRESS_LEN..ADDRESS_LEN * 2].copy_from_slice(&self.address.to_bytes_le());
+                self.model_identifier
+                    .pack_into(&mut buffer[ADDRESS_LEN * 2..]);
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            const SIG_LEN: usize = ADDRESS_LEN * 2 + ModelIdentifier::sig_byte_len();
+            const VENDOR_LEN: usize = ADDRESS_LEN * 2 + ModelIdentifier::vendor_byte_len();
+            if buffer.len() == SIG_LEN || buffer.len() == VENDOR_LEN {
+                let address = Address::from_bytes_le(&buffer[ADDRESS_LEN..ADDRESS_LEN * 2])
+                    .ok_or(MessagePackError::BadBytes)?;
+                if !address.is_group() {
+                    return Err(MessagePackError::BadBytes);
+                }
+                Ok(NonVirtualAdd {
+                    element_address: UnicastAddress::from_bytes_le(&buffer[..ADDRESS_LEN])
+                        .ok_or(MessagePackError::BadBytes)?,
+                    address,
+                    model_identifier: ModelIdentifier::unpack_from(&buffer[ADDRESS_LEN * 2..])
+                        .ok_or(MessagePackError::BadBytes)?,
+                })
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+
+    /// Subscribes a model to a virtual address (by its full Label UUID).
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct VirtualAdd {
+        pub element_address: UnicastAddress,
+        pub address: VirtualAddress,
+        pub model_identifier: ModelIdentifier,
+    }
+    impl PackableMessage for VirtualAdd {
+        fn opcode() -> Opcode {
+            ConfigOpcode::ModelSubscriptionVirtualAddressAdd.into()
+        }
+
+        fn message_size(&self) -> usize {
+            ADDRESS_LEN + UUID_LEN + self.model_identifier.byte_len()
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < self.message_size() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                buffer[..ADDRESS_LEN].copy_from_slice(&self.element_address.to_bytes_le());
+                buffer[ADDRESS_LEN..ADDRESS_LEN + UUID_LEN]
+                    .copy_from_slice(self.address.uuid().as_ref());
+                self.model_identifier
+                    .pack_into(&mut buffer[ADDRESS_LEN + UUID_LEN..]);
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            const SIG_LEN: usize = ADDRESS_LEN + UUID_LEN + ModelIdentifier::sig_byte_len();
+            const VENDOR_LEN: usize = ADDRESS_LEN + UUID_LEN + ModelIdentifier::vendor_byte_len();
+            if buffer.len() == SIG_LEN || buffer.len() == VENDOR_LEN {
+                let uuid = UUID(
+                    buffer[ADDRESS_LEN..ADDRESS_LEN + UUID_LEN]
                         .try_into()
-                        .map_err(|_| MessagePackError::BadBytes)?,
-                    element_address: UnicastAddress::from_bytes_le(&buffer[1..1 + ADDRESS_LEN])
+                        .expect("length checked above"),
+                );
+                Ok(VirtualAdd {
+                    element_address: UnicastAddress::from_bytes_le(&buffer[..ADDRESS_LEN])
                         .ok_or(MessagePackError::BadBytes)?,
-                    publication: ModelPublishInfo::unpack(
-                        &buffer
-                            [1 + ADDRESS_LEN..1 + ADDRESS_LEN + ModelPublishInfo::NON_VIRTUAL_LEN],
-                    )
-                    .ok_or(MessagePackError::BadBytes)?,
+                    address: VirtualAddress::from(&uuid),
                     model_identifier: ModelIdentifier::unpack_from(
-                        &buffer[1 + ModelPublishInfo::NON_VIRTUAL_LEN + ADDRESS_LEN..],
+                        &buffer[ADDRESS_LEN + UUID_LEN..],
                     )
                     .ok_or(MessagePackError::BadBytes)?,
                 })
@@ -545,26 +1280,6 @@ pub mod model_publication {
             }
         }
     }
-}
-pub mod model_subscription {
-    use crate::access::ModelIdentifier;
-    use crate::address::{Address, UnicastAddress, VirtualAddress};
-    use crate::foundation::StatusCode;
-    use alloc::vec::Vec;
-
-    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
-    pub struct NonVirtualAdd {
-        pub element_address: UnicastAddress,
-        pub address: Address,
-        pub model_identifier: ModelIdentifier,
-    }
-
-    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
-    pub struct VirtualAdd {
-        pub element_address: UnicastAddress,
-        pub address: VirtualAddress,
-        pub model_identifier: ModelIdentifier,
-    }
 
     #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
     pub struct NonVirtualDelete {
@@ -603,6 +1318,50 @@ pub mod model_subscription {
         pub address: Address,
         pub model_identifier: ModelIdentifier,
     }
+    impl PackableMessage for Status {
+        fn opcode() -> Opcode {
+            ConfigOpcode::ModelSubscriptionStatus.into()
+        }
+
+        fn message_size(&self) -> usize {
+            1 + ADDRESS_LEN * 2 + self.model_identifier.byte_len()
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < self.message_size() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                // Like the Publication Status, only the hash of a virtual address is sent.
+                buffer[0] = self.status_code.into();
+                buffer[1..1 + ADDRESS_LEN].copy_from_slice(&self.element_address.to_bytes_le());
+                buffer[1 + ADDRESS_LEN..1 + ADDRESS_LEN * 2]
+                    .copy_from_slice(&self.address.to_bytes_le());
+                self.model_identifier
+                    .pack_into(&mut buffer[1 + ADDRESS_LEN * 2..]);
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            const SIG_LEN: usize = 1 + ADDRESS_LEN * 2 + ModelIdentifier::sig_byte_len();
+            const VENDOR_LEN: usize = 1 + ADDRESS_LEN * 2 + ModelIdentifier::vendor_byte_len();
+            if buffer.len() == SIG_LEN || buffer.len() == VENDOR_LEN {
+                Ok(Status {
+                    status_code: buffer[0]
+                        .try_into()
+                        .map_err(|_| MessagePackError::BadBytes)?,
+                    element_address: UnicastAddress::from_bytes_le(&buffer[1..1 + ADDRESS_LEN])
+                        .ok_or(MessagePackError::BadBytes)?,
+                    address: Address::from_bytes_le(&buffer[1 + ADDRESS_LEN..1 + ADDRESS_LEN * 2])
+                        .ok_or(MessagePackError::BadBytes)?,
+                    model_identifier: ModelIdentifier::unpack_from(&buffer[1 + ADDRESS_LEN * 2..])
+                        .ok_or(MessagePackError::BadBytes)?,
+                })
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
     #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
     pub struct Get {
         pub element_address: UnicastAddress,
@@ -616,17 +1375,201 @@ pub mod model_subscription {
         pub addresses: Vec<Address>,
     }
 }
+pub mod model_app {
+    use super::{pack_key_index, unpack_key_index};
+    use crate::access::{ModelIdentifier, Opcode};
+    use crate::address::{UnicastAddress, ADDRESS_LEN};
+    use crate::bytes::ToFromBytesEndian;
+    use crate::foundation::StatusCode;
+    use crate::mesh::AppKeyIndex;
+    use crate::models::config::ConfigOpcode;
+    use crate::models::{MessagePackError, PackableMessage};
+    use core::convert::TryInto;
+
+    const INDEX_POS: usize = ADDRESS_LEN;
+    const MODEL_POS: usize = INDEX_POS + 2;
+
+    fn pack(
+        element_address: UnicastAddress,
+        app_index: AppKeyIndex,
+        model_identifier: ModelIdentifier,
+        buffer: &mut [u8],
+    ) {
+        buffer[..INDEX_POS].copy_from_slice(&element_address.to_bytes_le());
+        pack_key_index(app_index.0, &mut buffer[INDEX_POS..]);
+        model_identifier.pack_into(&mut buffer[MODEL_POS..]);
+    }
+    fn unpack(
+        buffer: &[u8],
+    ) -> Result<(UnicastAddress, AppKeyIndex, ModelIdentifier), MessagePackError> {
+        if buffer.len() == MODEL_POS + ModelIdentifier::sig_byte_len()
+            || buffer.len() == MODEL_POS + ModelIdentifier::vendor_byte_len()
+        {
+            Ok((
+                UnicastAddress::from_bytes_le(&buffer[..INDEX_POS])
+                    .ok_or(MessagePackError::BadBytes)?,
+                AppKeyIndex(unpack_key_index(&buffer[INDEX_POS..])?),
+                ModelIdentifier::unpack_from(&buffer[MODEL_POS..])
+                    .ok_or(MessagePackError::BadBytes)?,
+            ))
+        } else {
+            Err(MessagePackError::BadLength)
+        }
+    }
+    /// Binds an AppKey to a model.
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Bind {
+        pub element_address: UnicastAddress,
+        pub app_index: AppKeyIndex,
+        pub model_identifier: ModelIdentifier,
+    }
+    impl PackableMessage for Bind {
+        fn opcode() -> Opcode {
+            ConfigOpcode::ModelAppBind.into()
+        }
+
+        fn message_size(&self) -> usize {
+            MODEL_POS + self.model_identifier.byte_len()
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < self.message_size() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                pack(
+                    self.element_address,
+                    self.app_index,
+                    self.model_identifier,
+                    buffer,
+                );
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            let (element_address, app_index, model_identifier) = unpack(buffer)?;
+            Ok(Bind {
+                element_address,
+                app_index,
+                model_identifier,
+            })
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Status {
+        pub status_code: StatusCode,
+        pub element_address: UnicastAddress,
+        pub app_index: AppKeyIndex,
+        pub model_identifier: ModelIdentifier,
+    }
+    impl PackableMessage for Status {
+        fn opcode() -> Opcode {
+            ConfigOpcode::ModelAppStatus.into()
+        }
+
+        fn message_size(&self) -> usize {
+            1 + MODEL_POS + self.model_identifier.byte_len()
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < self.message_size() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                buffer[0] = self.status_code.into();
+                pack(
+                    self.element_address,
+                    self.app_index,
+                    self.model_identifier,
+                    &mut buffer[1..],
+                );
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.is_empty() {
+                return Err(MessagePackError::BadLength);
+            }
+            let status_code = buffer[0]
+                .try_into()
+                .map_err(|_| MessagePackError::BadBytes)?;
+            let (element_address, app_index, model_identifier) = unpack(&buffer[1..])?;
+            Ok(Status {
+                status_code,
+                element_address,
+                app_index,
+                model_identifier,
+            })
+        }
+    }
+}
+/// Packs a single 12-bit key index into 2 bytes (little endian).
+fn pack_key_index(index: KeyIndex, buffer: &mut [u8]) {
+    buffer[..2].copy_from_slice(&u16::from(index).to_le_bytes());
+}
+/// Unpacks a single key index. The upper 4 bits are RFU and must be `0`.
+fn unpack_key_index(buffer: &[u8]) -> Result<KeyIndex, MessagePackError> {
+    KeyIndex::try_from(u16::from_le_bytes([buffer[0], buffer[1]]))
+        .map_err(|_| MessagePackError::BadBytes)
+}
+/// Packs two 12-bit key indexes into 3 bytes (little endian). `first` is in the lower 12 bits.
+fn pack_key_index_pair(first: KeyIndex, second: KeyIndex, buffer: &mut [u8]) {
+    let packed = u32::from(u16::from(first)) | (u32::from(u16::from(second)) << 12);
+    buffer[..3].copy_from_slice(&packed.to_le_bytes()[..3]);
+}
+fn unpack_key_index_pair(buffer: &[u8]) -> (KeyIndex, KeyIndex) {
+    let packed = u32::from_le_bytes([buffer[0], buffer[1], buffer[2], 0]);
+    (
+        KeyIndex::new_masked(packed as u16),
+        KeyIndex::new_masked((packed >> 12) as u16),
+    )
+}
 pub mod net_key_list {
-    use crate::crypto::key::NetKey;
+    use super::{pack_key_index, unpack_key_index};
+    use crate::access::Opcode;
+    use crate::crypto::key::{NetKey, KEY_LEN};
     use crate::foundation::StatusCode;
     use crate::mesh::NetKeyIndex;
+    use crate::models::config::ConfigOpcode;
+    use crate::models::{MessagePackError, PackableMessage};
     use alloc::vec::Vec;
+    use core::convert::TryInto;
 
     #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
     pub struct Add {
         pub index: NetKeyIndex,
         pub key: NetKey,
     }
+    impl PackableMessage for Add {
+        fn opcode() -> Opcode {
+            ConfigOpcode::NetKeyAdd.into()
+        }
+
+        fn message_size(&self) -> usize {
+            2 + KEY_LEN
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < self.message_size() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                pack_key_index(self.index.0, buffer);
+                buffer[2..2 + KEY_LEN].copy_from_slice(self.key.key().as_ref());
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() == 2 + KEY_LEN {
+                Ok(Add {
+                    index: NetKeyIndex(unpack_key_index(buffer)?),
+                    key: NetKey::new_bytes(buffer[2..].try_into().expect("length checked above")),
+                })
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
     #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
     pub struct Update {
         pub index: NetKeyIndex,
@@ -641,6 +1584,38 @@ pub mod net_key_list {
         pub status_code: StatusCode,
         pub index: NetKeyIndex,
     }
+    impl PackableMessage for Status {
+        fn opcode() -> Opcode {
+            ConfigOpcode::NetKeyStatus.into()
+        }
+
+        fn message_size(&self) -> usize {
+            1 + 2
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < self.message_size() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                buffer[0] = self.status_code.into();
+                pack_key_index(self.index.0, &mut buffer[1..]);
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() == 3 {
+                Ok(Status {
+                    status_code: buffer[0]
+                        .try_into()
+                        .map_err(|_| MessagePackError::BadBytes)?,
+                    index: NetKeyIndex(unpack_key_index(&buffer[1..])?),
+                })
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
     #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
     pub struct Get;
     #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
@@ -649,10 +1624,15 @@ pub mod net_key_list {
     }
 }
 pub mod app_key_list {
-    use crate::crypto::key::AppKey;
+    use super::{pack_key_index_pair, unpack_key_index_pair};
+    use crate::access::Opcode;
+    use crate::crypto::key::{AppKey, KEY_LEN};
     use crate::foundation::StatusCode;
     use crate::mesh::{AppKeyIndex, NetKeyIndex};
+    use crate::models::config::ConfigOpcode;
+    use crate::models::{MessagePackError, PackableMessage};
     use alloc::vec::Vec;
+    use core::convert::TryInto;
 
     #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
     pub struct Add {
@@ -660,6 +1640,40 @@ pub mod app_key_list {
         pub app_index: AppKeyIndex,
         pub app_key: AppKey,
     }
+    impl PackableMessage for Add {
+        fn opcode() -> Opcode {
+            ConfigOpcode::AppKeyAdd.into()
+        }
+
+        fn message_size(&self) -> usize {
+            3 + KEY_LEN
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < self.message_size() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                pack_key_index_pair(self.net_index.0, self.app_index.0, buffer);
+                buffer[3..3 + KEY_LEN].copy_from_slice(self.app_key.key().as_ref());
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() == 3 + KEY_LEN {
+                let (net_index, app_index) = unpack_key_index_pair(buffer);
+                Ok(Add {
+                    net_index: NetKeyIndex(net_index),
+                    app_index: AppKeyIndex(app_index),
+                    app_key: AppKey::new_bytes(
+                        buffer[3..].try_into().expect("length checked above"),
+                    ),
+                })
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
     #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
     pub struct Update {
         pub net_index: NetKeyIndex,
@@ -677,6 +1691,40 @@ pub mod app_key_list {
         pub net_index: NetKeyIndex,
         pub app_index: AppKeyIndex,
     }
+    impl PackableMessage for Status {
+        fn opcode() -> Opcode {
+            ConfigOpcode::AppKeyStatus.into()
+        }
+
+        fn message_size(&self) -> usize {
+            1 + 3
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < self.message_size() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                buffer[0] = self.status_code.into();
+                pack_key_index_pair(self.net_index.0, self.app_index.0, &mut buffer[1..]);
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() == 4 {
+                let (net_index, app_index) = unpack_key_index_pair(&buffer[1..]);
+                Ok(Status {
+                    status_code: buffer[0]
+                        .try_into()
+                        .map_err(|_| MessagePackError::BadBytes)?,
+                    net_index: NetKeyIndex(net_index),
+                    app_index: AppKeyIndex(app_index),
+                })
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
     #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
     pub struct Get(NetKeyIndex);
     #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
@@ -686,3 +1734,149 @@ pub mod app_key_list {
         pub indexes: Vec<NetKeyIndex>,
     }
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::ModelIdentifier;
+    use crate::address::{Address, UnicastAddress, VirtualAddress};
+    use crate::foundation::state::{FriendState, GATTProxyState, RelayRetransmit, RelayState};
+    use crate::foundation::StatusCode;
+    use crate::mesh::{
+        AppKeyIndex, CompanyID, ModelID, TransmitCount, TransmitInterval, TransmitSteps,
+    };
+    use crate::models::PackableMessage;
+    use crate::uuid::UUID;
+    use alloc::vec::Vec;
+    use core::fmt::Debug;
+
+    /// Packs `msg`, checks it unpacks to the same message and returns the packed parameters.
+    fn round_trip<M: PackableMessage + PartialEq + Debug>(msg: &M) -> Vec<u8> {
+        let mut buf = alloc::vec![0_u8; msg.message_size()];
+        msg.pack_into(&mut buf[..])
+            .ok()
+            .expect("buffer sized for the message");
+        assert_eq!(M::unpack_from(&buf[..]).ok().as_ref(), Some(msg));
+        assert!(M::unpack_from(&buf[..buf.len() - 1]).is_err());
+        buf
+    }
+    fn vendor_model() -> ModelIdentifier {
+        ModelIdentifier::new_vendor(ModelID(0x0001), CompanyID(0x05F1))
+    }
+    #[test]
+    fn test_model_app_pack() {
+        let bind = model_app::Bind {
+            element_address: UnicastAddress::new(0x0102),
+            app_index: AppKeyIndex(KeyIndex::new(0x345)),
+            model_identifier: ModelIdentifier::new_sig(ModelID(0x1000)),
+        };
+        assert_eq!(
+            round_trip(&bind),
+            alloc::vec![0x02, 0x01, 0x45, 0x03, 0x00, 0x10]
+        );
+        round_trip(&model_app::Bind {
+            model_identifier: vendor_model(),
+            ..bind
+        });
+        let status = model_app::Status {
+            status_code: StatusCode::InvalidAppKeyIndex,
+            element_address: bind.element_address,
+            app_index: bind.app_index,
+            model_identifier: vendor_model(),
+        };
+        assert_eq!(
+            round_trip(&status)[0],
+            u8::from(StatusCode::InvalidAppKeyIndex)
+        );
+        // Key indexes are only 12 bits.
+        assert!(model_app::Bind::unpack_from(&[0x02, 0x01, 0x45, 0x13, 0x00, 0x10]).is_err());
+    }
+    #[test]
+    fn test_model_subscription_pack() {
+        let add = model_subscription::NonVirtualAdd {
+            element_address: UnicastAddress::new(0x0102),
+            address: Address::from(0xC001),
+            model_identifier: ModelIdentifier::new_sig(ModelID(0x1001)),
+        };
+        assert_eq!(
+            round_trip(&add),
+            alloc::vec![0x02, 0x01, 0x01, 0xC0, 0x01, 0x10]
+        );
+        round_trip(&model_subscription::NonVirtualAdd {
+            model_identifier: vendor_model(),
+            ..add
+        });
+        // Only group addresses can be added without their Label UUID.
+        let unicast = model_subscription::NonVirtualAdd {
+            address: Address::from(0x0005),
+            ..add
+        };
+        let mut buf = [0_u8; 6];
+        assert!(unicast.pack_into(&mut buf[..]).is_err());
+        assert!(model_subscription::NonVirtualAdd::unpack_from(&[
+            0x02, 0x01, 0x05, 0x00, 0x01, 0x10
+        ])
+        .is_err());
+
+        let label = VirtualAddress::from(&UUID([0x42_u8; 16]));
+        let virtual_add = model_subscription::VirtualAdd {
+            element_address: add.element_address,
+            address: label,
+            model_identifier: vendor_model(),
+        };
+        let packed = round_trip(&virtual_add);
+        assert_eq!(&packed[2..18], &[0x42_u8; 16][..]);
+
+        // The status only has the virtual address hash.
+        let status = model_subscription::Status {
+            status_code: StatusCode::Ok,
+            element_address: add.element_address,
+            address: Address::Virtual(label),
+            model_identifier: vendor_model(),
+        };
+        let mut buf = alloc::vec![0_u8; status.message_size()];
+        status
+            .pack_into(&mut buf[..])
+            .ok()
+            .expect("buffer sized for the status");
+        let unpacked = model_subscription::Status::unpack_from(&buf[..])
+            .ok()
+            .expect("status unpacks");
+        assert_eq!(unpacked.address.value(), Address::Virtual(label).value());
+        assert_eq!(
+            unpacked,
+            model_subscription::Status {
+                address: unpacked.address,
+                ..status
+            }
+        );
+        round_trip(&model_subscription::Status {
+            address: Address::from(0xC001),
+            ..status
+        });
+    }
+    #[test]
+    fn test_feature_set_pack() {
+        let retransmit = RelayRetransmit(TransmitInterval::new(
+            TransmitCount::new(3),
+            TransmitSteps::new(2),
+        ));
+        assert_eq!(
+            round_trip(&relay::Set(RelayState::Enabled, retransmit)),
+            alloc::vec![0x01, 0x13]
+        );
+        round_trip(&relay::Status(RelayState::NotSupported, retransmit));
+        assert_eq!(
+            round_trip(&gatt_proxy::Set(GATTProxyState::Disabled)),
+            alloc::vec![0x00]
+        );
+        round_trip(&gatt_proxy::Status(GATTProxyState::NotSupported));
+        assert_eq!(
+            round_trip(&friend::Set(FriendState::Enabled)),
+            alloc::vec![0x01]
+        );
+        round_trip(&friend::Status(FriendState::Enabled));
+        // 0x03 and up are prohibited.
+        assert!(friend::Set::unpack_from(&[0x03]).is_err());
+        assert!(relay::Set::unpack_from(&[0x03, 0x13]).is_err());
+    }
+}
@@ -3,7 +3,10 @@ use crate::access::{Opcode, OpcodeConversationError};
 use crate::control::ControlOpcode;
 use core::convert::TryFrom;
 
+#[cfg(feature = "full_stack")]
+pub mod client;
 pub mod messages;
+pub mod server;
 
 pub enum ConfigOpcode {
     AppKeyAdd,
@@ -109,6 +112,7 @@ impl TryFrom<Opcode> for ConfigOpcode {
                     0x01 => Ok(ConfigOpcode::AppKeyUpdate),
                     0x02 => Ok(ConfigOpcode::CompositionDataStatus),
                     0x03 => Ok(ConfigOpcode::ModelPublicationSet),
+                    0x06 => Ok(ConfigOpcode::HeartbeatPublicationStatus),
 
                     _ => Err(OpcodeConversationError(())),
                 },
@@ -0,0 +1,1161 @@
+//! Config Server Model. Every node has one on its primary element. It applies the Config
+//! messages (secured with the node's DevKey) to the node's `DeviceState`.
+use crate::access::{ModelIdentifier, Opcode};
+use crate::address::{Address, UnicastAddress};
+use crate::crypto::aes::MicSize;
+use crate::crypto::materials::{AppKeyError, NetKeyError};
+use crate::device_state::DeviceState;
+use crate::foundation::publication::ModelPublishInfo;
+use crate::foundation::state::{FriendState, GATTProxyState, RelayState};
+use crate::foundation::{CompositionDataPage0, StatusCode};
+use crate::friend::{Friendships, PollTimeout};
+use crate::heartbeat::{self, HeartbeatPublication, HeartbeatSubscription};
+use crate::mesh::{ElementIndex, TTL};
+use crate::models::config::messages::{
+    app_key_list, composition_data, default_ttl, friend, gatt_proxy, heartbeat_publication,
+    heartbeat_subscription, low_power_node_poll_timeout, model_publication, net_key_list,
+    network_transmit, relay,
+};
+use crate::models::config::ConfigOpcode;
+use crate::models::PackableMessage;
+use crate::stack::messages::{IncomingMessage, MessageKeys, OutgoingMessage};
+use crate::timestamp::{Timestamp, TimestampTrait};
+use crate::upper::AppPayload;
+use alloc::boxed::Box;
+use core::convert::TryFrom;
+
+pub struct ConfigServer<'a> {
+    device_state: &'a mut DeviceState,
+    composition_data: Option<&'a CompositionDataPage0>,
+    friendships: Option<&'a Friendships>,
+    heartbeat_publication: Option<&'a mut HeartbeatPublication>,
+    heartbeat_subscription: Option<&'a mut HeartbeatSubscription>,
+}
+impl<'a> ConfigServer<'a> {
+    pub fn new(device_state: &'a mut DeviceState) -> Self {
+        Self {
+            device_state,
+            composition_data: None,
+            friendships: None,
+            heartbeat_publication: None,
+            heartbeat_subscription: None,
+        }
+    }
+    /// Sets the Composition Data page `0` returned for Composition Data Gets. Without it,
+    /// Composition Data Gets aren't responded to.
+    pub fn with_composition_data(mut self, composition_data: &'a CompositionDataPage0) -> Self {
+        self.composition_data = Some(composition_data);
+        self
+    }
+    /// Sets the friendships consulted for Low Power Node PollTimeout Gets (only on Friend
+    /// nodes). Without them, every address reports a PollTimeout of `0`.
+    pub fn with_friendships(mut self, friendships: &'a Friendships) -> Self {
+        self.friendships = Some(friendships);
+        self
+    }
+    /// Sets the Heartbeat states the Heartbeat Publication and Subscription messages get and
+    /// set. Without them, those messages aren't responded to.
+    pub fn with_heartbeat(
+        mut self,
+        publication: &'a mut HeartbeatPublication,
+        subscription: &'a mut HeartbeatSubscription,
+    ) -> Self {
+        self.heartbeat_publication = Some(publication);
+        self.heartbeat_subscription = Some(subscription);
+        self
+    }
+    pub fn device_state(&self) -> &DeviceState {
+        self.device_state
+    }
+    /// Handles a decrypted Config message and returns the Status to respond with. The response
+    /// is secured with the DevKey (AKF=0) on the same NetKey and addressed back to the
+    /// requester's `src`. Returns `None` if `msg` wasn't secured with the DevKey, can't be
+    /// parsed or has no response.
+    pub fn handle_message<Storage: AsRef<[u8]>>(
+        &mut self,
+        msg: &IncomingMessage<Storage>,
+    ) -> Option<OutgoingMessage<Box<[u8]>>> {
+        self.handle_message_at(msg, Timestamp::now())
+    }
+    /// Same as `ConfigServer::handle_message` but received at `now`.
+    pub fn handle_message_at<Storage: AsRef<[u8]>>(
+        &mut self,
+        msg: &IncomingMessage<Storage>,
+        now: Timestamp,
+    ) -> Option<OutgoingMessage<Box<[u8]>>> {
+        // Config messages are only ever secured with the DevKey.
+        if msg.app_key_index.is_some() {
+            return None;
+        }
+        let (opcode, parameters) = Opcode::split_from(msg.payload.as_ref()).ok()?;
+        match ConfigOpcode::try_from(opcode).ok()? {
+            ConfigOpcode::CompositionDataGet => {
+                let get = composition_data::Get::unpack_from(parameters).ok()?;
+                let status = self.handle_composition_data_get(&get)?;
+                Some(self.response(msg, &status))
+            }
+            ConfigOpcode::LowPowerNodePollTimeoutGet => {
+                let get = low_power_node_poll_timeout::Get::unpack_from(parameters).ok()?;
+                let status = self.handle_lpn_poll_timeout_get_at(&get, now);
+                Some(self.response(msg, &status))
+            }
+            ConfigOpcode::NetKeyAdd => {
+                let add = net_key_list::Add::unpack_from(parameters).ok()?;
+                let status = self.handle_net_key_add(&add);
+                Some(self.response(msg, &status))
+            }
+            ConfigOpcode::AppKeyAdd => {
+                let add = app_key_list::Add::unpack_from(parameters).ok()?;
+                let status = self.handle_app_key_add(&add);
+                Some(self.response(msg, &status))
+            }
+            ConfigOpcode::ModelPublicationSet => {
+                let set = model_publication::NonVirtualSet::unpack_from(parameters).ok()?;
+                let status = self.handle_publication_set(&set);
+                Some(self.response(msg, &status))
+            }
+            ConfigOpcode::ModelPublicationVirtualAddressSet => {
+                let set = model_publication::VirtualSet::unpack_from(parameters).ok()?;
+                let status = self.handle_publication_virtual_set(&set);
+                Some(self.response(msg, &status))
+            }
+            ConfigOpcode::HeartbeatPublicationGet => {
+                heartbeat_publication::Get::unpack_from(parameters).ok()?;
+                let status = self.handle_heartbeat_publication_get()?;
+                Some(self.response(msg, &status))
+            }
+            ConfigOpcode::HeartbeatPublicationSet => {
+                let set = heartbeat_publication::Set::unpack_from(parameters).ok()?;
+                let status = self.handle_heartbeat_publication_set(&set)?;
+                Some(self.response(msg, &status))
+            }
+            ConfigOpcode::HeartbeatSubscriptionGet => {
+                heartbeat_subscription::Get::unpack_from(parameters).ok()?;
+                let status = self.handle_heartbeat_subscription_get_at(now)?;
+                Some(self.response(msg, &status))
+            }
+            ConfigOpcode::HeartbeatSubscriptionSet => {
+                let set = heartbeat_subscription::Set::unpack_from(parameters).ok()?;
+                let status = self.handle_heartbeat_subscription_set_at(&set, now)?;
+                Some(self.response(msg, &status))
+            }
+            ConfigOpcode::DefaultTTLGet => {
+                default_ttl::Get::unpack_from(parameters).ok()?;
+                Some(self.response(msg, &self.handle_default_ttl_get()))
+            }
+            ConfigOpcode::DefaultTTLSet => {
+                let set = default_ttl::Set::unpack_from(parameters).ok()?;
+                let status = self.handle_default_ttl_set(&set);
+                Some(self.response(msg, &status))
+            }
+            ConfigOpcode::GATTProxyGet => {
+                gatt_proxy::Get::unpack_from(parameters).ok()?;
+                Some(self.response(msg, &self.handle_gatt_proxy_get()))
+            }
+            ConfigOpcode::GATTProxySet => {
+                let set = gatt_proxy::Set::unpack_from(parameters).ok()?;
+                let status = self.handle_gatt_proxy_set(&set)?;
+                Some(self.response(msg, &status))
+            }
+            ConfigOpcode::FriendGet => {
+                friend::Get::unpack_from(parameters).ok()?;
+                Some(self.response(msg, &self.handle_friend_get()))
+            }
+            ConfigOpcode::FriendSet => {
+                let set = friend::Set::unpack_from(parameters).ok()?;
+                let status = self.handle_friend_set(&set)?;
+                Some(self.response(msg, &status))
+            }
+            ConfigOpcode::RelayGet => {
+                relay::Get::unpack_from(parameters).ok()?;
+                Some(self.response(msg, &self.handle_relay_get()))
+            }
+            ConfigOpcode::RelaySet => {
+                let set = relay::Set::unpack_from(parameters).ok()?;
+                let status = self.handle_relay_set(&set)?;
+                Some(self.response(msg, &status))
+            }
+            ConfigOpcode::NetworkTransmitGet => {
+                network_transmit::Get::unpack_from(parameters).ok()?;
+                Some(self.response(msg, &self.handle_network_transmit_get()))
+            }
+            ConfigOpcode::NetworkTransmitSet => {
+                let set = network_transmit::Set::unpack_from(parameters).ok()?;
+                let status = self.handle_network_transmit_set(&set);
+                Some(self.response(msg, &status))
+            }
+            _ => None,
+        }
+    }
+    /// Packs `status` into an `OutgoingMessage` sent from the primary element back to the
+    /// `src` of `request` with the DevKey.
+    fn response<Storage: AsRef<[u8]>, M: PackableMessage>(
+        &self,
+        request: &IncomingMessage<Storage>,
+        status: &M,
+    ) -> OutgoingMessage<Box<[u8]>> {
+        let mut payload =
+            alloc::vec![0_u8; M::opcode().byte_len() + status.message_size()].into_boxed_slice();
+        status
+            .pack_with_opcode(&mut payload[..])
+            .ok()
+            .expect("buffer sized for the status");
+        OutgoingMessage {
+            app_payload: AppPayload::new(payload),
+            mic_size: MicSize::Small,
+            force_segment: false,
+            encryption_key: MessageKeys::Device(request.net_key_index),
+            iv_index: self.device_state.tx_iv_index(),
+            source_element_index: ElementIndex(0),
+            dst: Address::Unicast(request.src),
+            ttl: None,
+        }
+    }
+    /// Handles a Config Composition Data Get message. Only page `0` exists so it's always
+    /// returned no matter the page asked for. Returns `None` if no Composition Data was given.
+    pub fn handle_composition_data_get(
+        &self,
+        _msg: &composition_data::Get,
+    ) -> Option<composition_data::Status> {
+        self.composition_data.map(|page| composition_data::Status {
+            page_number: 0,
+            page: page.clone(),
+        })
+    }
+    /// Handles a Config NetKey Add message. Fails with `InsufficientResources` once the node
+    /// stores `KeyLimits::max_net_keys` NetKeys.
+    pub fn handle_net_key_add(&mut self, msg: &net_key_list::Add) -> net_key_list::Status {
+        let status_code = match self.device_state.add_net_key(msg.index, &msg.key) {
+            Ok(()) => StatusCode::Ok,
+            Err(NetKeyError::KeyIndexAlreadyStored) => StatusCode::KeyIndexAlreadyStored,
+            Err(NetKeyError::InvalidNetKeyIndex) => StatusCode::InvalidNetKeyIndex,
+            Err(NetKeyError::CannotUpdate) => StatusCode::CannotUpdate,
+            Err(NetKeyError::CannotSet) => StatusCode::CannotSet,
+            Err(NetKeyError::InsufficientResources) => StatusCode::InsufficientResources,
+        };
+        net_key_list::Status {
+            status_code,
+            index: msg.index,
+        }
+    }
+    /// Handles a Config AppKey Add message. Fails with `InsufficientResources` once the node
+    /// stores `KeyLimits::max_app_keys` AppKeys.
+    pub fn handle_app_key_add(&mut self, msg: &app_key_list::Add) -> app_key_list::Status {
+        let status_code =
+            match self
+                .device_state
+                .add_app_key(msg.net_index, msg.app_index, msg.app_key)
+            {
+                Ok(()) => StatusCode::Ok,
+                Err(AppKeyError::KeyIndexAlreadyStored) => StatusCode::KeyIndexAlreadyStored,
+                Err(AppKeyError::InvalidAppKeyIndex) => StatusCode::InvalidAppKeyIndex,
+                Err(AppKeyError::InvalidNetKeyIndex) => StatusCode::InvalidNetKeyIndex,
+                Err(AppKeyError::InvalidBinding) => StatusCode::InvalidBinding,
+                Err(AppKeyError::CannotUpdate) => StatusCode::CannotUpdate,
+                Err(AppKeyError::InsufficientResources) => StatusCode::InsufficientResources,
+            };
+        app_key_list::Status {
+            status_code,
+            net_index: msg.net_index,
+            app_index: msg.app_index,
+        }
+    }
+    /// Handles a Config Low Power Node PollTimeout Get message with the PollTimeout timer as of
+    /// `now`.
+    pub fn handle_lpn_poll_timeout_get_at(
+        &self,
+        msg: &low_power_node_poll_timeout::Get,
+        now: Timestamp,
+    ) -> low_power_node_poll_timeout::Status {
+        low_power_node_poll_timeout::Status {
+            lpn_address: msg.lpn_address,
+            poll_timeout: self.friendships.map_or(PollTimeout::ZERO, |friendships| {
+                friendships.remaining_poll_timeout(msg.lpn_address, now)
+            }),
+        }
+    }
+    /// Handles a Config Low Power Node PollTimeout Get message.
+    pub fn handle_lpn_poll_timeout_get(
+        &self,
+        msg: &low_power_node_poll_timeout::Get,
+    ) -> low_power_node_poll_timeout::Status {
+        self.handle_lpn_poll_timeout_get_at(msg, Timestamp::now())
+    }
+    /// Checks if `publication` can be applied to the model at `element_address`.
+    fn check_publication(
+        &self,
+        element_address: UnicastAddress,
+        model_identifier: &ModelIdentifier,
+        publication: &ModelPublishInfo,
+    ) -> StatusCode {
+        if self.device_state.element_index(element_address).is_none() {
+            StatusCode::InvalidAddress
+        } else if self.device_state.models().get(model_identifier).is_none() {
+            StatusCode::InvalidModel
+        } else if publication.address.is_assigned()
+            && self
+                .device_state
+                .security_materials()
+                .app_key_map
+                .get_key(publication.app_key_index)
+                .is_none()
+        {
+            StatusCode::InvalidAppKeyIndex
+        } else {
+            StatusCode::Ok
+        }
+    }
+    fn set_publication(
+        &mut self,
+        element_address: UnicastAddress,
+        model_identifier: ModelIdentifier,
+        publication: ModelPublishInfo,
+    ) -> model_publication::Status {
+        let status_code = self.check_publication(element_address, &model_identifier, &publication);
+        if status_code == StatusCode::Ok {
+            self.device_state
+                .models_mut()
+                .get_mut(&model_identifier)
+                .expect("model checked above")
+                .set_publish(publication);
+        }
+        model_publication::Status {
+            status_code,
+            element_address,
+            publication,
+            model_identifier,
+        }
+    }
+    /// Handles a Config Model Publication Set message.
+    pub fn handle_publication_set(
+        &mut self,
+        msg: &model_publication::NonVirtualSet,
+    ) -> model_publication::Status {
+        self.set_publication(msg.element_address, msg.model_identifier, msg.publication)
+    }
+    /// Handles a Config Model Publication Virtual Address Set message. The message carries the
+    /// full 128-bit Label UUID so the stored publish address is the full `VirtualAddress`
+    /// (hash + UUID) which is needed to encrypt published messages.
+    pub fn handle_publication_virtual_set(
+        &mut self,
+        msg: &model_publication::VirtualSet,
+    ) -> model_publication::Status {
+        if !msg.publication.address.is_full_virtual() {
+            return model_publication::Status {
+                status_code: StatusCode::InvalidPublishParameters,
+                element_address: msg.element_address,
+                publication: msg.publication,
+                model_identifier: msg.model_identifier,
+            };
+        }
+        self.set_publication(msg.element_address, msg.model_identifier, msg.publication)
+    }
+    fn heartbeat_publication_status(
+        status_code: StatusCode,
+        publication: &HeartbeatPublication,
+    ) -> heartbeat_publication::Status {
+        heartbeat_publication::Status {
+            status_code,
+            publication: heartbeat_publication::Set {
+                destination: publication.destination,
+                count_log: publication.count_log(),
+                period_log: publication.period_log,
+                ttl: publication.ttl,
+                features: publication.features,
+                net_key_index: publication.net_key_index,
+            },
+        }
+    }
+    /// Handles a Config Heartbeat Publication Get message. Returns `None` if no Heartbeat states
+    /// were given.
+    pub fn handle_heartbeat_publication_get(&self) -> Option<heartbeat_publication::Status> {
+        self.heartbeat_publication
+            .as_ref()
+            .map(|publication| Self::heartbeat_publication_status(StatusCode::Ok, publication))
+    }
+    /// Handles a Config Heartbeat Publication Set message. Fails with `InvalidNetKeyIndex` if
+    /// the NetKey isn't stored. An unassigned destination disables publishing (and zeros the
+    /// count, period and TTL). Returns `None` if no Heartbeat states were given or `msg` has
+    /// prohibited values.
+    pub fn handle_heartbeat_publication_set(
+        &mut self,
+        msg: &heartbeat_publication::Set,
+    ) -> Option<heartbeat_publication::Status> {
+        let count = heartbeat::count_from_log(msg.count_log)?;
+        heartbeat::period_from_log(msg.period_log)?;
+        match msg.destination {
+            Address::Unassigned | Address::Unicast(_) | Address::Group(_) => (),
+            Address::Virtual(_) | Address::VirtualHash(_) => return None,
+        }
+        let known_net_key = self
+            .device_state
+            .security_materials()
+            .net_key_map
+            .get_keys(msg.net_key_index)
+            .is_some();
+        let publication = self.heartbeat_publication.as_mut()?;
+        if !known_net_key {
+            return Some(heartbeat_publication::Status {
+                status_code: StatusCode::InvalidNetKeyIndex,
+                publication: *msg,
+            });
+        }
+        **publication = if msg.destination.is_assigned() {
+            HeartbeatPublication::new(
+                msg.destination,
+                count,
+                msg.period_log,
+                msg.ttl,
+                msg.features,
+                msg.net_key_index,
+            )
+        } else {
+            HeartbeatPublication::new(
+                Address::Unassigned,
+                0,
+                0,
+                TTL::new(0),
+                msg.features,
+                msg.net_key_index,
+            )
+        };
+        Some(Self::heartbeat_publication_status(
+            StatusCode::Ok,
+            publication,
+        ))
+    }
+    fn heartbeat_subscription_status(
+        subscription: &HeartbeatSubscription,
+        now: Timestamp,
+    ) -> heartbeat_subscription::Status {
+        heartbeat_subscription::Status {
+            status_code: StatusCode::Ok,
+            source: subscription.source,
+            destination: subscription.destination,
+            period_log: subscription.period_log(now),
+            count_log: subscription.count_log(),
+            min_hops: subscription.min_hops,
+            max_hops: subscription.max_hops,
+        }
+    }
+    /// Handles a Config Heartbeat Subscription Get message with the subscription period as of
+    /// `now`. Returns `None` if no Heartbeat states were given.
+    pub fn handle_heartbeat_subscription_get_at(
+        &self,
+        now: Timestamp,
+    ) -> Option<heartbeat_subscription::Status> {
+        self.heartbeat_subscription
+            .as_ref()
+            .map(|subscription| Self::heartbeat_subscription_status(subscription, now))
+    }
+    /// Handles a Config Heartbeat Subscription Set message received at `now` (see
+    /// [`HeartbeatSubscription::set`]). Returns `None` if no Heartbeat states were given or
+    /// `msg` has prohibited values.
+    pub fn handle_heartbeat_subscription_set_at(
+        &mut self,
+        msg: &heartbeat_subscription::Set,
+        now: Timestamp,
+    ) -> Option<heartbeat_subscription::Status> {
+        match msg.source {
+            Address::Unassigned | Address::Unicast(_) => (),
+            _ => return None,
+        }
+        match msg.destination {
+            Address::Unassigned | Address::Unicast(_) | Address::Group(_) => (),
+            Address::Virtual(_) | Address::VirtualHash(_) => return None,
+        }
+        let subscription = self.heartbeat_subscription.as_mut()?;
+        if !subscription.set(msg.source, msg.destination, msg.period_log, now) {
+            return None;
+        }
+        Some(Self::heartbeat_subscription_status(subscription, now))
+    }
+    /// Handles a Config Default TTL Get message.
+    pub fn handle_default_ttl_get(&self) -> default_ttl::Status {
+        default_ttl::Status(self.device_state.default_ttl_state())
+    }
+    /// Handles a Config Default TTL Set message. Prohibited TTLs (`0x01` and `0x80..=0xFF`)
+    /// never unpack so they aren't responded to.
+    pub fn handle_default_ttl_set(&mut self, msg: &default_ttl::Set) -> default_ttl::Status {
+        self.device_state.set_default_ttl(msg.0);
+        self.handle_default_ttl_get()
+    }
+    /// Handles a Config Relay Get message.
+    pub fn handle_relay_get(&self) -> relay::Status {
+        relay::Status(
+            self.device_state.relay_state(),
+            self.device_state.relay_retransmit(),
+        )
+    }
+    /// Handles a Config Relay Set message (see [`DeviceState::set_relay`]). Nodes without the
+    /// Relay feature respond with their unchanged states. Returns `None` if `msg` sets the
+    /// prohibited `RelayState::NotSupported`.
+    pub fn handle_relay_set(&mut self, msg: &relay::Set) -> Option<relay::Status> {
+        if msg.0 == RelayState::NotSupported {
+            return None;
+        }
+        self.device_state.set_relay(msg.0, msg.1);
+        Some(self.handle_relay_get())
+    }
+    /// Handles a Config GATT Proxy Get message.
+    pub fn handle_gatt_proxy_get(&self) -> gatt_proxy::Status {
+        gatt_proxy::Status(self.device_state.gatt_proxy_state())
+    }
+    /// Handles a Config GATT Proxy Set message (see [`DeviceState::set_gatt_proxy`]). Returns
+    /// `None` if `msg` sets the prohibited `GATTProxyState::NotSupported`.
+    pub fn handle_gatt_proxy_set(&mut self, msg: &gatt_proxy::Set) -> Option<gatt_proxy::Status> {
+        if msg.0 == GATTProxyState::NotSupported {
+            return None;
+        }
+        self.device_state.set_gatt_proxy(msg.0);
+        Some(self.handle_gatt_proxy_get())
+    }
+    /// Handles a Config Friend Get message.
+    pub fn handle_friend_get(&self) -> friend::Status {
+        friend::Status(self.device_state.friend_state())
+    }
+    /// Handles a Config Friend Set message (see [`DeviceState::set_friend`]). Returns `None` if
+    /// `msg` sets the prohibited `FriendState::NotSupported`.
+    pub fn handle_friend_set(&mut self, msg: &friend::Set) -> Option<friend::Status> {
+        if msg.0 == FriendState::NotSupported {
+            return None;
+        }
+        self.device_state.set_friend(msg.0);
+        Some(self.handle_friend_get())
+    }
+    /// Handles a Config Network Transmit Get message.
+    pub fn handle_network_transmit_get(&self) -> network_transmit::Status {
+        network_transmit::Status(self.device_state.network_transmit())
+    }
+    /// Handles a Config Network Transmit Set message.
+    pub fn handle_network_transmit_set(
+        &mut self,
+        msg: &network_transmit::Set,
+    ) -> network_transmit::Status {
+        self.device_state.set_network_transmit(msg.0);
+        self.handle_network_transmit_get()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::{Address, VirtualAddress};
+    use crate::crypto::key::{AppKey, NetKey};
+    use crate::crypto::nonce::DeviceNonceParts;
+    use crate::device_state::{KeyLimits, ModelInfo};
+    use crate::foundation::element::{ElementComposition, ElementsComposition, Location};
+    use crate::foundation::publication::{PublishPeriod, PublishRetransmit, StepResolution, Steps};
+    use crate::foundation::state::{DefaultTTLState, NetworkTransmit, RelayRetransmit};
+    use crate::foundation::{
+        FeatureFlags, Features, ProductID, ProductInfo, VersionID, CONFIG_SERVER_MODEL_ID, CRPL,
+    };
+    use crate::friend::{FriendCounter, FriendshipCredentials, LPNCounter};
+    use crate::mesh::{AppKeyIndex, ElementCount, KeyIndex, ModelID, NetKeyIndex, TTL};
+    use crate::mesh::{CompanyID, IVIndex, SequenceNumber, TransmitInterval, U24};
+    use crate::models::PackableMessage;
+    use crate::random::Randomizable;
+    use crate::stack::StackInternals;
+    use crate::upper::{self, SecurityMaterials};
+    use crate::uuid::UUID;
+    use core::time::Duration;
+
+    fn app_key_index() -> AppKeyIndex {
+        AppKeyIndex(KeyIndex::new(1))
+    }
+    fn model() -> ModelIdentifier {
+        ModelIdentifier::new_sig(ModelID(0x1000))
+    }
+    fn device_state() -> DeviceState {
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        let mut device_state = DeviceState::new(UnicastAddress::new(0x0001), ElementCount(1));
+        let sm = device_state.security_materials_mut();
+        sm.net_key_map
+            .insert(net_key_index, &NetKey::random_secure());
+        sm.app_key_map
+            .insert(net_key_index, app_key_index(), AppKey::random_secure());
+        device_state
+            .models_mut()
+            .insert(model(), ModelInfo::default());
+        device_state
+    }
+    fn virtual_set(
+        uuid: &UUID,
+        model_identifier: ModelIdentifier,
+    ) -> model_publication::VirtualSet {
+        model_publication::VirtualSet {
+            element_address: UnicastAddress::new(0x0001),
+            publication: ModelPublishInfo {
+                address: Address::Virtual(VirtualAddress::new(uuid)),
+                app_key_index: app_key_index(),
+                credential_flag: false,
+                ttl: Some(TTL::new(7)),
+                period: PublishPeriod::new(StepResolution::Second1, Steps::new(5)),
+                retransmit: PublishRetransmit::from(0),
+            },
+            model_identifier,
+        }
+    }
+    #[test]
+    fn test_publication_virtual_set() {
+        let uuid = UUID([0x5A; 16]);
+        let set = virtual_set(&uuid, model());
+        let mut buf = [0_u8; 32];
+        assert!(set.pack_into(&mut buf).is_ok());
+        let set = match model_publication::VirtualSet::unpack_from(&buf[..set.message_size()]) {
+            Ok(set) => set,
+            Err(_) => panic!("unable to unpack virtual set"),
+        };
+        let mut device_state = device_state();
+        let status = ConfigServer::new(&mut device_state).handle_publication_virtual_set(&set);
+        assert_eq!(status.status_code, StatusCode::Ok);
+
+        let internals = StackInternals::new(device_state);
+        let msg = internals
+            .publish_message(ElementIndex(0), &model(), AppPayload([0_u8; 4]))
+            .expect("model should be publishing");
+        let encrypted = match internals.app_encrypt(msg) {
+            Ok(encrypted) => encrypted,
+            Err((e, _)) => panic!("unable to encrypt published message: {:?}", e),
+        };
+        assert_eq!(encrypted.dst, Address::Virtual(VirtualAddress::new(&uuid)));
+        assert_eq!(
+            encrypted.dst.virtual_hash(),
+            Some(VirtualAddress::hash_uuid(&uuid))
+        );
+    }
+    #[test]
+    fn test_key_limits() {
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        let mut device_state = device_state();
+        // `device_state()` already stores 1 NetKey and 1 AppKey.
+        device_state.set_key_limits(KeyLimits::new(2, 2));
+        let mut server = ConfigServer::new(&mut device_state);
+
+        let app_key_add = |index: u16| app_key_list::Add {
+            net_index: net_key_index,
+            app_index: AppKeyIndex(KeyIndex::new(index)),
+            app_key: AppKey::random_secure(),
+        };
+        assert_eq!(
+            server.handle_app_key_add(&app_key_add(2)).status_code,
+            StatusCode::Ok
+        );
+        let status = server.handle_app_key_add(&app_key_add(3));
+        assert_eq!(status.status_code, StatusCode::InsufficientResources);
+        assert_eq!(status.app_index, AppKeyIndex(KeyIndex::new(3)));
+        // Re-adding a stored key still succeeds at the limit.
+        let stored = server
+            .device_state()
+            .security_materials()
+            .app_key_map
+            .get_key(app_key_index())
+            .expect("key added by device_state()")
+            .app_key;
+        let readd = app_key_list::Add {
+            net_index: net_key_index,
+            app_index: app_key_index(),
+            app_key: stored,
+        };
+        assert_eq!(
+            server.handle_app_key_add(&readd).status_code,
+            StatusCode::Ok
+        );
+
+        let net_key_add = |index: u16| net_key_list::Add {
+            index: NetKeyIndex(KeyIndex::new(index)),
+            key: NetKey::random_secure(),
+        };
+        assert_eq!(
+            server.handle_net_key_add(&net_key_add(1)).status_code,
+            StatusCode::Ok
+        );
+        assert_eq!(
+            server.handle_net_key_add(&net_key_add(2)).status_code,
+            StatusCode::InsufficientResources
+        );
+        assert_eq!(
+            server
+                .device_state()
+                .security_materials()
+                .net_key_map
+                .map
+                .len(),
+            2
+        );
+    }
+    #[test]
+    fn test_app_key_add_message() {
+        let add = app_key_list::Add {
+            net_index: NetKeyIndex(KeyIndex::new(0x123)),
+            app_index: AppKeyIndex(KeyIndex::new(0x456)),
+            app_key: AppKey::new_bytes([0xAA; 16]),
+        };
+        let mut buf = [0_u8; 19];
+        add.pack_into(&mut buf[..])
+            .ok()
+            .expect("buffer is big enough");
+        assert_eq!(&buf[..3], &[0x23, 0x61, 0x45]);
+        match app_key_list::Add::unpack_from(&buf[..]) {
+            Ok(unpacked) => assert_eq!(unpacked, add),
+            Err(_) => panic!("AppKey Add should unpack"),
+        }
+    }
+    #[test]
+    fn test_publication_virtual_set_unknown_model() {
+        let set = virtual_set(&UUID([0x5A; 16]), ModelIdentifier::new_sig(ModelID(0x1001)));
+        let mut device_state = device_state();
+        let status = ConfigServer::new(&mut device_state).handle_publication_virtual_set(&set);
+        assert_eq!(status.status_code, StatusCode::InvalidModel);
+    }
+    fn composition_data() -> CompositionDataPage0 {
+        let mut element = ElementComposition::new_empty(Location::Numbered(1));
+        element.add_model(model());
+        CompositionDataPage0::new(
+            CompanyID(0x05F1),
+            ProductID(0x0001),
+            VersionID(0x0002),
+            CRPL(32),
+            Features::default(),
+            ElementsComposition::new(vec![element]),
+        )
+    }
+    #[test]
+    fn test_composition_data_get_reply() {
+        let requester = UnicastAddress::new(0x0005);
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        let get = composition_data::Get(0);
+        let mut payload = [0_u8; 3];
+        get.pack_with_opcode(&mut payload[..])
+            .ok()
+            .expect("buffer is big enough");
+        let request = IncomingMessage {
+            payload: &payload[..],
+            src: requester,
+            dst: Address::Unicast(UnicastAddress::new(0x0001)),
+            seq: SequenceNumber(U24::new(0x20)),
+            iv_index: IVIndex(0),
+            net_key_index,
+            app_key_index: None,
+            ttl: None,
+            rssi: None,
+        };
+
+        let mut device_state = device_state();
+        let composition_data = composition_data();
+        let reply = ConfigServer::new(&mut device_state)
+            .with_composition_data(&composition_data)
+            .handle_message(&request)
+            .expect("composition data get should be answered");
+        assert_eq!(reply.dst, Address::Unicast(requester));
+        match reply.encryption_key {
+            MessageKeys::Device(index) => assert_eq!(index, net_key_index),
+            MessageKeys::App(_) => panic!("config replies use the DevKey"),
+        }
+
+        let internals = StackInternals::new(device_state);
+        let encrypted = match internals.app_encrypt(reply) {
+            Ok(encrypted) => encrypted,
+            Err((e, _)) => panic!("unable to encrypt reply: {:?}", e),
+        };
+        assert_eq!(encrypted.dst, Address::Unicast(requester));
+        let encrypted_payload = match encrypted.upper_pdu {
+            upper::PDU::Access(payload) => payload,
+            upper::PDU::Control(_) => panic!("reply should be an access message"),
+        };
+        // AKF=0
+        assert_eq!(encrypted_payload.aid(), None);
+        let nonce = DeviceNonceParts {
+            aszmic: false,
+            seq: encrypted.seq.start(),
+            src: encrypted.src,
+            dst: encrypted.dst,
+            iv_index: encrypted.iv_index,
+        }
+        .to_nonce();
+        let dev_key = internals.device_state().security_materials().dev_key;
+        let decrypted = encrypted_payload
+            .decrypt(SecurityMaterials::Device(nonce, &dev_key))
+            .ok()
+            .expect("reply should decrypt with the DevKey");
+        let (opcode, parameters) =
+            Opcode::split_from(decrypted.payload()).expect("reply has an opcode");
+        assert_eq!(opcode, composition_data::Status::opcode());
+        match composition_data::Status::unpack_from(parameters) {
+            Ok(status) => {
+                assert_eq!(status.page_number, 0);
+                assert_eq!(status.page, composition_data);
+            }
+            Err(_) => panic!("status should unpack"),
+        }
+    }
+    #[test]
+    fn test_composition_data_from_device_state() {
+        let mut device_state = device_state();
+        device_state.config_states_mut().relay_state = RelayState::NotSupported;
+        let product = ProductInfo {
+            cid: CompanyID(0x05F1),
+            pid: ProductID(0x0001),
+            vid: VersionID(0x0002),
+            crpl: CRPL(32),
+        };
+        let page = CompositionDataPage0::from_device_state(&product, &device_state);
+        assert!(!page.features().get(FeatureFlags::Relay));
+        assert!(page.features().get(FeatureFlags::Proxy));
+        assert_eq!(page.elements().elements().len(), 1);
+        assert_eq!(
+            page.elements().elements()[0].sig_models,
+            vec![ModelIdentifier::new_sig(CONFIG_SERVER_MODEL_ID), model()]
+        );
+        // Only page 0 exists so it's returned for any other page.
+        let status = ConfigServer::new(&mut device_state)
+            .with_composition_data(&page)
+            .handle_composition_data_get(&composition_data::Get(2))
+            .expect("composition data was given");
+        assert_eq!(status.page_number, 0);
+        let mut buf = alloc::vec![0_u8; status.message_size()];
+        status
+            .pack_into(&mut buf[..])
+            .ok()
+            .expect("buffer sized for the status");
+        match composition_data::Status::unpack_from(&buf[..]) {
+            Ok(unpacked) => assert_eq!(unpacked.page, page),
+            Err(_) => panic!("status should unpack"),
+        }
+    }
+    #[test]
+    fn test_app_key_messages_ignored() {
+        let mut payload = [0_u8; 3];
+        composition_data::Get(0)
+            .pack_with_opcode(&mut payload[..])
+            .ok()
+            .expect("buffer is big enough");
+        let request = IncomingMessage {
+            payload: &payload[..],
+            src: UnicastAddress::new(0x0005),
+            dst: Address::Unicast(UnicastAddress::new(0x0001)),
+            seq: SequenceNumber(U24::new(0x20)),
+            iv_index: IVIndex(0),
+            net_key_index: NetKeyIndex(KeyIndex::new(0)),
+            app_key_index: Some(app_key_index()),
+            ttl: None,
+            rssi: None,
+        };
+        let mut device_state = device_state();
+        let composition_data = composition_data();
+        assert!(ConfigServer::new(&mut device_state)
+            .with_composition_data(&composition_data)
+            .handle_message(&request)
+            .is_none());
+    }
+    #[test]
+    fn test_lpn_poll_timeout_get() {
+        let lpn = UnicastAddress::new(0x0010);
+        let established = Timestamp::now();
+        let mut friendships = Friendships::new();
+        // 10 seconds
+        friendships.establish(
+            FriendshipCredentials {
+                lpn_address: lpn,
+                friend_address: UnicastAddress::new(0x0001),
+                lpn_counter: LPNCounter::new(0),
+                friend_counter: FriendCounter::new(0),
+            },
+            PollTimeout::new(U24::new(100)),
+            established,
+        );
+
+        let mut device_state = device_state();
+        let server = ConfigServer::new(&mut device_state).with_friendships(&friendships);
+        let now = established + Duration::from_millis(2_050);
+        let status = server.handle_lpn_poll_timeout_get_at(
+            &low_power_node_poll_timeout::Get { lpn_address: lpn },
+            now,
+        );
+        assert_eq!(status.lpn_address, lpn);
+        assert_eq!(status.poll_timeout, PollTimeout::new(U24::new(79)));
+
+        let unknown = UnicastAddress::new(0x0020);
+        let status = server.handle_lpn_poll_timeout_get_at(
+            &low_power_node_poll_timeout::Get {
+                lpn_address: unknown,
+            },
+            now,
+        );
+        assert_eq!(status.lpn_address, unknown);
+        assert_eq!(status.poll_timeout, PollTimeout::ZERO);
+
+        // Expired timers report 0 too.
+        let status = server.handle_lpn_poll_timeout_get_at(
+            &low_power_node_poll_timeout::Get { lpn_address: lpn },
+            established + Duration::from_secs(11),
+        );
+        assert_eq!(status.poll_timeout, PollTimeout::ZERO);
+
+        let mut buf = [0_u8; 5];
+        status
+            .pack_into(&mut buf[..])
+            .ok()
+            .expect("buffer is big enough");
+        assert_eq!(buf, [0x10, 0x00, 0x00, 0x00, 0x00]);
+    }
+    #[test]
+    fn test_heartbeat_publication_set() {
+        let set = heartbeat_publication::Set {
+            destination: Address::from(0xC000),
+            count_log: 0x03,
+            period_log: 0x02,
+            ttl: TTL::new(5),
+            features: Features::default(),
+            net_key_index: NetKeyIndex(KeyIndex::new(0)),
+        };
+        let mut buf = [0_u8; 9];
+        set.pack_into(&mut buf[..])
+            .ok()
+            .expect("buffer is big enough");
+        assert_eq!(buf, [0x00, 0xC0, 0x03, 0x02, 0x05, 0x00, 0x00, 0x00, 0x00]);
+        match heartbeat_publication::Set::unpack_from(&buf[..]) {
+            Ok(unpacked) => assert_eq!(unpacked, set),
+            Err(_) => panic!("Heartbeat Publication Set should unpack"),
+        }
+
+        let mut device_state = device_state();
+        let mut publication = HeartbeatPublication::default();
+        let mut subscription = HeartbeatSubscription::new();
+        let mut server = ConfigServer::new(&mut device_state)
+            .with_heartbeat(&mut publication, &mut subscription);
+        let status = server
+            .handle_heartbeat_publication_set(&set)
+            .expect("heartbeat states were given");
+        assert_eq!(status.status_code, StatusCode::Ok);
+        assert_eq!(status.publication, set);
+        // Prohibited values aren't responded to.
+        let prohibited = heartbeat_publication::Set {
+            count_log: 0x12,
+            ..set
+        };
+        assert!(server
+            .handle_heartbeat_publication_set(&prohibited)
+            .is_none());
+        let unknown_net_key = heartbeat_publication::Set {
+            net_key_index: NetKeyIndex(KeyIndex::new(1)),
+            ..set
+        };
+        assert_eq!(
+            server
+                .handle_heartbeat_publication_set(&unknown_net_key)
+                .map(|status| status.status_code),
+            Some(StatusCode::InvalidNetKeyIndex)
+        );
+        assert_eq!(
+            server
+                .handle_heartbeat_publication_get()
+                .map(|status| status.publication),
+            Some(set)
+        );
+        assert_eq!(publication.count, 4);
+        assert_eq!(publication.period(), Some(Duration::from_secs(2)));
+
+        // Without the heartbeat states, nothing is responded.
+        assert!(ConfigServer::new(&mut device_state)
+            .handle_heartbeat_publication_get()
+            .is_none());
+    }
+    #[test]
+    fn test_heartbeat_subscription_set() {
+        let now = Timestamp::now();
+        let set = heartbeat_subscription::Set {
+            source: Address::from(0x0005),
+            destination: Address::from(0x0001),
+            period_log: 0x04,
+        };
+        let mut device_state = device_state();
+        let mut publication = HeartbeatPublication::default();
+        let mut subscription = HeartbeatSubscription::new();
+        let mut server = ConfigServer::new(&mut device_state)
+            .with_heartbeat(&mut publication, &mut subscription);
+        let status = server
+            .handle_heartbeat_subscription_set_at(&set, now)
+            .expect("heartbeat states were given");
+        assert_eq!(status.status_code, StatusCode::Ok);
+        assert_eq!(status.period_log, 0x04);
+        assert_eq!(
+            (status.count_log, status.min_hops, status.max_hops),
+            (0, 0x7F, 0)
+        );
+        let group_source = heartbeat_subscription::Set {
+            source: Address::from(0xC000),
+            ..set
+        };
+        assert!(server
+            .handle_heartbeat_subscription_set_at(&group_source, now)
+            .is_none());
+
+        let mut buf = [0_u8; 9];
+        status
+            .pack_into(&mut buf[..])
+            .ok()
+            .expect("buffer is big enough");
+        assert_eq!(buf, [0x00, 0x05, 0x00, 0x01, 0x00, 0x04, 0x00, 0x7F, 0x00]);
+        match heartbeat_subscription::Status::unpack_from(&buf[..]) {
+            Ok(unpacked) => assert_eq!(unpacked, status),
+            Err(_) => panic!("Heartbeat Subscription Status should unpack"),
+        }
+        // Half the period is left.
+        let status = server
+            .handle_heartbeat_subscription_get_at(now + Duration::from_secs(4))
+            .expect("heartbeat states were given");
+        assert_eq!(status.period_log, 0x03);
+        assert_eq!(subscription.source, Address::from(0x0005));
+    }
+    #[test]
+    fn test_relay_set() {
+        let set = relay::Set(
+            RelayState::Enabled,
+            RelayRetransmit(TransmitInterval::from(0x2B)),
+        );
+        let mut payload = [0_u8; 4];
+        set.pack_with_opcode(&mut payload[..])
+            .ok()
+            .expect("buffer is big enough");
+        assert_eq!(payload, [0x80, 0x27, 0x01, 0x2B]);
+        let request = IncomingMessage {
+            payload: &payload[..],
+            src: UnicastAddress::new(0x0005),
+            dst: Address::Unicast(UnicastAddress::new(0x0001)),
+            seq: SequenceNumber(U24::new(0x20)),
+            iv_index: IVIndex(0),
+            net_key_index: NetKeyIndex(KeyIndex::new(0)),
+            app_key_index: None,
+            ttl: None,
+            rssi: None,
+        };
+        let mut device_state = device_state();
+        let reply = ConfigServer::new(&mut device_state)
+            .handle_message(&request)
+            .expect("relay set should be answered");
+        let (opcode, parameters) =
+            Opcode::split_from(reply.app_payload.0.as_ref()).expect("reply has an opcode");
+        assert_eq!(opcode, relay::Status::opcode());
+        assert_eq!(
+            relay::Status::unpack_from(parameters).ok(),
+            Some(relay::Status(set.0, set.1))
+        );
+        assert_eq!(device_state.relay_state(), RelayState::Enabled);
+        assert_eq!(device_state.relay_retransmit(), set.1);
+
+        let mut server = ConfigServer::new(&mut device_state);
+        assert!(server
+            .handle_relay_set(&relay::Set(RelayState::NotSupported, set.1))
+            .is_none());
+        let disable = relay::Set(RelayState::Disabled, RelayRetransmit::default());
+        assert_eq!(
+            server.handle_relay_set(&disable),
+            Some(relay::Status(disable.0, disable.1))
+        );
+        assert_eq!(
+            server.handle_relay_get(),
+            relay::Status(disable.0, disable.1)
+        );
+
+        // Nodes without the Relay feature keep their states.
+        device_state.config_states_mut().relay_state = RelayState::NotSupported;
+        let status = ConfigServer::new(&mut device_state).handle_relay_set(&set);
+        assert_eq!(
+            status,
+            Some(relay::Status(RelayState::NotSupported, disable.1))
+        );
+    }
+    #[test]
+    fn test_feature_sets() {
+        let mut device_state = device_state();
+        assert_eq!(device_state.enabled_features(), Features::default());
+        let mut server = ConfigServer::new(&mut device_state);
+        assert_eq!(
+            server.handle_gatt_proxy_set(&gatt_proxy::Set(GATTProxyState::Enabled)),
+            Some(gatt_proxy::Status(GATTProxyState::Enabled))
+        );
+        assert_eq!(
+            server.handle_friend_set(&friend::Set(FriendState::Enabled)),
+            Some(friend::Status(FriendState::Enabled))
+        );
+        assert!(server
+            .handle_friend_set(&friend::Set(FriendState::NotSupported))
+            .is_none());
+        assert!(server
+            .handle_gatt_proxy_set(&gatt_proxy::Set(GATTProxyState::NotSupported))
+            .is_none());
+        assert_eq!(
+            server.handle_friend_get(),
+            friend::Status(FriendState::Enabled)
+        );
+        let features = device_state.enabled_features();
+        assert!(features.get(FeatureFlags::Proxy));
+        assert!(features.get(FeatureFlags::Friend));
+        assert!(!features.get(FeatureFlags::Relay));
+
+        // Unsupported features stay unsupported.
+        device_state.config_states_mut().friend_state = FriendState::NotSupported;
+        assert_eq!(
+            ConfigServer::new(&mut device_state)
+                .handle_friend_set(&friend::Set(FriendState::Enabled)),
+            Some(friend::Status(FriendState::NotSupported))
+        );
+        assert!(!device_state.set_feature_enabled(FeatureFlags::Friend, true));
+        assert!(!device_state.set_feature_enabled(FeatureFlags::LowPower, true));
+        assert!(device_state.set_feature_enabled(FeatureFlags::Relay, true));
+        assert!(device_state.set_feature_enabled(FeatureFlags::Proxy, false));
+        let features = device_state.enabled_features();
+        assert!(features.get(FeatureFlags::Relay));
+        assert!(!features.get(FeatureFlags::Proxy));
+        assert!(!features.get(FeatureFlags::Friend));
+        let supported = device_state.supported_features();
+        assert!(supported.get(FeatureFlags::Relay));
+        assert!(!supported.get(FeatureFlags::Friend));
+    }
+    #[test]
+    fn test_default_ttl_set() {
+        assert!(default_ttl::Set::unpack_from(&[0x01]).is_err());
+        assert!(default_ttl::Set::unpack_from(&[0x80]).is_err());
+        let set = match default_ttl::Set::unpack_from(&[0x00]) {
+            Ok(set) => set,
+            Err(_) => panic!("a Default TTL of 0 is allowed"),
+        };
+        let mut device_state = device_state();
+        let mut server = ConfigServer::new(&mut device_state);
+        assert_eq!(
+            server.handle_default_ttl_get(),
+            default_ttl::Status(DefaultTTLState::default())
+        );
+        assert_eq!(
+            server.handle_default_ttl_set(&set),
+            default_ttl::Status(DefaultTTLState::new(0))
+        );
+        assert_eq!(device_state.default_ttl(), TTL::new(0));
+    }
+    #[test]
+    fn test_network_transmit_set() {
+        let network_transmit = NetworkTransmit(TransmitInterval::from(0x4A));
+        let mut buf = [0_u8; 1];
+        network_transmit::Set(network_transmit)
+            .pack_into(&mut buf[..])
+            .ok()
+            .expect("buffer is big enough");
+        assert_eq!(buf, [0x4A]);
+        let mut device_state = device_state();
+        let mut server = ConfigServer::new(&mut device_state);
+        assert_eq!(
+            server.handle_network_transmit_get(),
+            network_transmit::Status(NetworkTransmit::default())
+        );
+        assert_eq!(
+            server.handle_network_transmit_set(&network_transmit::Set(network_transmit)),
+            network_transmit::Status(network_transmit)
+        );
+        assert_eq!(device_state.network_transmit(), network_transmit);
+        let internals = StackInternals::new(device_state);
+        assert_eq!(
+            internals.device_state().network_transmit().0,
+            TransmitInterval::from(0x4A)
+        );
+    }
+}
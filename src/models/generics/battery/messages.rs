@@ -0,0 +1,133 @@
+use crate::access::Opcode;
+use crate::bytes::ToFromBytesEndian;
+use crate::mesh::U24;
+use crate::models::generics::battery::{
+    BatteryFlags, BatteryLevel, BatteryOpcode, BatteryState, BatteryTime, BATTERY_TIME_LEN,
+};
+use crate::models::{MessagePackError, PackableMessage};
+use core::convert::TryFrom;
+
+/// Battery Level (1) + Time to Discharge (3) + Time to Charge (3) + Flags (1).
+pub const BATTERY_STATUS_LEN: usize = 1 + BATTERY_TIME_LEN + BATTERY_TIME_LEN + 1;
+
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct Get;
+impl PackableMessage for Get {
+    fn opcode() -> Opcode {
+        BatteryOpcode::BatteryGet.into()
+    }
+
+    fn message_size(&self) -> usize {
+        0
+    }
+
+    fn pack_into(&self, _buffer: &mut [u8]) -> Result<(), MessagePackError> {
+        Ok(())
+    }
+
+    fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+        if buffer.is_empty() {
+            Ok(Get)
+        } else {
+            Err(MessagePackError::BadLength)
+        }
+    }
+}
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct Status(pub BatteryState);
+impl PackableMessage for Status {
+    fn opcode() -> Opcode {
+        BatteryOpcode::BatteryStatus.into()
+    }
+
+    fn message_size(&self) -> usize {
+        BATTERY_STATUS_LEN
+    }
+
+    fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+        if buffer.len() < BATTERY_STATUS_LEN {
+            return Err(MessagePackError::SmallBuffer);
+        }
+        buffer[0] = self.0.level.into();
+        buffer[1..4].copy_from_slice(&self.0.time_to_discharge.value().to_bytes_le());
+        buffer[4..7].copy_from_slice(&self.0.time_to_charge.value().to_bytes_le());
+        buffer[7] = self.0.flags.into();
+        Ok(())
+    }
+
+    fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+        if buffer.len() != BATTERY_STATUS_LEN {
+            return Err(MessagePackError::BadLength);
+        }
+        let time = |bytes: &[u8]| {
+            U24::from_bytes_le(bytes)
+                .map(BatteryTime::new)
+                .ok_or(MessagePackError::BadBytes)
+        };
+        Ok(Status(BatteryState {
+            level: BatteryLevel::try_from(buffer[0]).map_err(|_| MessagePackError::BadBytes)?,
+            time_to_discharge: time(&buffer[1..4])?,
+            time_to_charge: time(&buffer[4..7])?,
+            flags: BatteryFlags::try_from(buffer[7]).map_err(|_| MessagePackError::BadBytes)?,
+        }))
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::generics::battery::{
+        BatteryCharging, BatteryIndicator, BatteryPresence, BatteryServiceability,
+    };
+
+    #[test]
+    fn test_battery_status_pack() {
+        let status = Status(BatteryState {
+            level: BatteryLevel::new(75).expect("valid level"),
+            time_to_discharge: BatteryTime::from_minutes(0x12_3456).expect("fits in 24 bits"),
+            time_to_charge: BatteryTime::UNKNOWN,
+            flags: BatteryFlags {
+                presence: BatteryPresence::PresentRemovable,
+                indicator: BatteryIndicator::Good,
+                charging: BatteryCharging::NotCharging,
+                serviceability: BatteryServiceability::NotRequired,
+            },
+        });
+        let mut buf = [0_u8; BATTERY_STATUS_LEN];
+        status
+            .pack_into(&mut buf[..])
+            .ok()
+            .expect("buffer is big enough");
+        // Flags: 0b01 (not required) | 0b01 (not charging) | 0b10 (good) | 0b01 (removable).
+        assert_eq!(buf, [75, 0x56, 0x34, 0x12, 0xFF, 0xFF, 0xFF, 0b01_01_10_01]);
+        match Status::unpack_from(&buf[..]) {
+            Ok(unpacked) => assert_eq!(unpacked, status),
+            Err(_) => panic!("status should unpack"),
+        }
+    }
+    #[test]
+    fn test_battery_times() {
+        assert_eq!(
+            BatteryTime::from_minutes(0xFF_FFFE).and_then(BatteryTime::minutes),
+            Some(0xFF_FFFE)
+        );
+        // 0xFFFFFF is reserved for unknown.
+        assert_eq!(BatteryTime::from_minutes(0xFF_FFFF), None);
+        assert_eq!(BatteryTime::UNKNOWN.minutes(), None);
+        assert_eq!(BatteryTime::default(), BatteryTime::UNKNOWN);
+    }
+    #[test]
+    fn test_battery_flags() {
+        let unknown = BatteryFlags::default();
+        assert_eq!(u8::from(unknown), 0xFF);
+        assert_eq!(BatteryFlags::try_from(0xFF), Ok(unknown));
+        // Serviceability 0b00 is prohibited.
+        assert!(BatteryFlags::try_from(0x3F).is_err());
+        let charging = BatteryFlags::try_from(0b10_10_01_10).expect("valid flags");
+        assert_eq!(charging.presence, BatteryPresence::PresentNotRemovable);
+        assert_eq!(charging.indicator, BatteryIndicator::Low);
+        assert_eq!(charging.charging, BatteryCharging::Charging);
+        assert_eq!(charging.serviceability, BatteryServiceability::Required);
+        // Levels above 100% (other than unknown) are invalid.
+        assert!(Status::unpack_from(&[101, 0, 0, 0, 0, 0, 0, 0xFF]).is_err());
+    }
+}
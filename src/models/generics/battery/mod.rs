@@ -0,0 +1,217 @@
+//! Generic Battery Model (Generic Battery Server and Generic Battery Client). Reports the state of
+//! a node's battery (level, time to discharge/charge and status flags).
+use crate::access::SigOpcode::DoubleOctet;
+use crate::access::{Opcode, OpcodeConversationError};
+use crate::mesh::U24;
+use core::convert::TryFrom;
+
+pub mod messages;
+pub mod server;
+
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum BatteryOpcode {
+    BatteryGet,
+    BatteryStatus,
+}
+impl TryFrom<Opcode> for BatteryOpcode {
+    type Error = OpcodeConversationError;
+
+    fn try_from(opcode: Opcode) -> Result<Self, Self::Error> {
+        match opcode {
+            Opcode::SIG(DoubleOctet(0x8223)) => Ok(BatteryOpcode::BatteryGet),
+            Opcode::SIG(DoubleOctet(0x8224)) => Ok(BatteryOpcode::BatteryStatus),
+            _ => Err(OpcodeConversationError(())),
+        }
+    }
+}
+impl From<BatteryOpcode> for Opcode {
+    fn from(opcode: BatteryOpcode) -> Self {
+        match opcode {
+            BatteryOpcode::BatteryGet => DoubleOctet(0x8223).into(),
+            BatteryOpcode::BatteryStatus => DoubleOctet(0x8224).into(),
+        }
+    }
+}
+/// Battery charge in percent (`0..=100`). `0xFF` means the level is unknown.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct BatteryLevel(u8);
+pub const BATTERY_LEVEL_MAX: u8 = 100;
+impl BatteryLevel {
+    pub const UNKNOWN: BatteryLevel = BatteryLevel(0xFF);
+    /// Returns `None` if `percent > 100`.
+    pub fn new(percent: u8) -> Option<BatteryLevel> {
+        if percent <= BATTERY_LEVEL_MAX {
+            Some(BatteryLevel(percent))
+        } else {
+            None
+        }
+    }
+    pub fn is_unknown(self) -> bool {
+        self == Self::UNKNOWN
+    }
+    /// Returns the level in percent or `None` if it's unknown.
+    pub fn percent(self) -> Option<u8> {
+        if self.is_unknown() {
+            None
+        } else {
+            Some(self.0)
+        }
+    }
+}
+impl Default for BatteryLevel {
+    fn default() -> Self {
+        Self::UNKNOWN
+    }
+}
+impl From<BatteryLevel> for u8 {
+    fn from(level: BatteryLevel) -> Self {
+        level.0
+    }
+}
+impl TryFrom<u8> for BatteryLevel {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        if value == Self::UNKNOWN.0 {
+            Ok(Self::UNKNOWN)
+        } else {
+            BatteryLevel::new(value).ok_or(())
+        }
+    }
+}
+/// 24-bit count of minutes until the battery is discharged (or charged). `0xFFFFFF` means the
+/// time is unknown.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct BatteryTime(U24);
+pub const BATTERY_TIME_LEN: usize = 3;
+impl BatteryTime {
+    pub const UNKNOWN: BatteryTime = BatteryTime(U24::new_masked(0xFF_FFFF));
+    /// Returns `None` if `minutes` doesn't fit in 24 bits (or is the unknown value).
+    pub fn from_minutes(minutes: u32) -> Option<BatteryTime> {
+        if minutes < Self::UNKNOWN.0.value() {
+            Some(BatteryTime(U24::new(minutes)))
+        } else {
+            None
+        }
+    }
+    pub fn new(value: U24) -> BatteryTime {
+        BatteryTime(value)
+    }
+    pub fn value(self) -> U24 {
+        self.0
+    }
+    pub fn is_unknown(self) -> bool {
+        self == Self::UNKNOWN
+    }
+    /// Returns the time in minutes or `None` if it's unknown.
+    pub fn minutes(self) -> Option<u32> {
+        if self.is_unknown() {
+            None
+        } else {
+            Some(self.0.value())
+        }
+    }
+}
+impl Default for BatteryTime {
+    fn default() -> Self {
+        Self::UNKNOWN
+    }
+}
+/// If a battery is present (and if it can be removed).
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum BatteryPresence {
+    NotPresent = 0b00,
+    PresentRemovable = 0b01,
+    PresentNotRemovable = 0b10,
+    Unknown = 0b11,
+}
+/// How much charge is left in the battery.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum BatteryIndicator {
+    CriticallyLow = 0b00,
+    Low = 0b01,
+    Good = 0b10,
+    Unknown = 0b11,
+}
+/// If the battery can be (and is being) charged.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum BatteryCharging {
+    NotChargeable = 0b00,
+    NotCharging = 0b01,
+    Charging = 0b10,
+    Unknown = 0b11,
+}
+/// If the battery needs to be serviced (`0b00` is prohibited).
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum BatteryServiceability {
+    NotRequired = 0b01,
+    Required = 0b10,
+    Unknown = 0b11,
+}
+/// Generic Battery Flags. Packed into one byte with two bits per field (presence in the lowest
+/// bits, then indicator, charging and serviceability).
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct BatteryFlags {
+    pub presence: BatteryPresence,
+    pub indicator: BatteryIndicator,
+    pub charging: BatteryCharging,
+    pub serviceability: BatteryServiceability,
+}
+impl Default for BatteryFlags {
+    fn default() -> Self {
+        Self {
+            presence: BatteryPresence::Unknown,
+            indicator: BatteryIndicator::Unknown,
+            charging: BatteryCharging::Unknown,
+            serviceability: BatteryServiceability::Unknown,
+        }
+    }
+}
+impl From<BatteryFlags> for u8 {
+    fn from(flags: BatteryFlags) -> Self {
+        (flags.presence as u8)
+            | (flags.indicator as u8) << 2
+            | (flags.charging as u8) << 4
+            | (flags.serviceability as u8) << 6
+    }
+}
+impl TryFrom<u8> for BatteryFlags {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(BatteryFlags {
+            presence: match value & 0b11 {
+                0b00 => BatteryPresence::NotPresent,
+                0b01 => BatteryPresence::PresentRemovable,
+                0b10 => BatteryPresence::PresentNotRemovable,
+                _ => BatteryPresence::Unknown,
+            },
+            indicator: match (value >> 2) & 0b11 {
+                0b00 => BatteryIndicator::CriticallyLow,
+                0b01 => BatteryIndicator::Low,
+                0b10 => BatteryIndicator::Good,
+                _ => BatteryIndicator::Unknown,
+            },
+            charging: match (value >> 4) & 0b11 {
+                0b00 => BatteryCharging::NotChargeable,
+                0b01 => BatteryCharging::NotCharging,
+                0b10 => BatteryCharging::Charging,
+                _ => BatteryCharging::Unknown,
+            },
+            serviceability: match (value >> 6) & 0b11 {
+                0b00 => return Err(()),
+                0b01 => BatteryServiceability::NotRequired,
+                0b10 => BatteryServiceability::Required,
+                _ => BatteryServiceability::Unknown,
+            },
+        })
+    }
+}
+/// Generic Battery State carried by the Generic Battery Status message.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash, Default)]
+pub struct BatteryState {
+    pub level: BatteryLevel,
+    pub time_to_discharge: BatteryTime,
+    pub time_to_charge: BatteryTime,
+    pub flags: BatteryFlags,
+}
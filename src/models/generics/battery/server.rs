@@ -0,0 +1,23 @@
+//! Generic Battery Server model. The battery state is read from the hardware through a callback
+//! every time a Status is needed.
+use crate::models::generics::battery::messages::{Get, Status};
+use crate::models::generics::battery::BatteryState;
+
+/// Generic Battery Server for an element. `read_battery` is called for every Generic Battery Get
+/// (or published Status) to get the current `BatteryState`.
+pub struct BatteryServer<F: FnMut() -> BatteryState> {
+    read_battery: F,
+}
+impl<F: FnMut() -> BatteryState> BatteryServer<F> {
+    pub fn new(read_battery: F) -> Self {
+        Self { read_battery }
+    }
+    /// Returns the Generic Battery Status reflecting the current battery state.
+    pub fn status(&mut self) -> Status {
+        Status((self.read_battery)())
+    }
+    /// Handles a Generic Battery Get and returns the Status to respond with.
+    pub fn handle_get(&mut self, _get: Get) -> Status {
+        self.status()
+    }
+}
@@ -0,0 +1,322 @@
+pub mod global {
+    use crate::access::Opcode;
+    use crate::models::generics::location::{
+        Altitude, GlobalLocation, Latitude, LocationOpcode, Longitude,
+    };
+    use crate::models::{MessagePackError, PackableMessage};
+
+    /// Global Latitude (4) + Global Longitude (4) + Global Altitude (2).
+    pub const GLOBAL_LOCATION_LEN: usize = 4 + 4 + 2;
+
+    fn pack_global_location(
+        location: &GlobalLocation,
+        buffer: &mut [u8],
+    ) -> Result<(), MessagePackError> {
+        if buffer.len() < GLOBAL_LOCATION_LEN {
+            return Err(MessagePackError::SmallBuffer);
+        }
+        buffer[..4].copy_from_slice(&location.latitude.0.to_le_bytes());
+        buffer[4..8].copy_from_slice(&location.longitude.0.to_le_bytes());
+        buffer[8..10].copy_from_slice(&location.altitude.0.to_le_bytes());
+        Ok(())
+    }
+    fn unpack_global_location(buffer: &[u8]) -> Result<GlobalLocation, MessagePackError> {
+        if buffer.len() != GLOBAL_LOCATION_LEN {
+            return Err(MessagePackError::BadLength);
+        }
+        Ok(GlobalLocation {
+            latitude: Latitude(i32::from_le_bytes([
+                buffer[0], buffer[1], buffer[2], buffer[3],
+            ])),
+            longitude: Longitude(i32::from_le_bytes([
+                buffer[4], buffer[5], buffer[6], buffer[7],
+            ])),
+            altitude: Altitude(i16::from_le_bytes([buffer[8], buffer[9]])),
+        })
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Get;
+    impl PackableMessage for Get {
+        fn opcode() -> Opcode {
+            LocationOpcode::GlobalGet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            0
+        }
+
+        fn pack_into(&self, _buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            Ok(())
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.is_empty() {
+                Ok(Get)
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Set(pub GlobalLocation);
+    impl PackableMessage for Set {
+        fn opcode() -> Opcode {
+            LocationOpcode::GlobalSet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            GLOBAL_LOCATION_LEN
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            pack_global_location(&self.0, buffer)
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            Ok(Set(unpack_global_location(buffer)?))
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct SetUnacknowledged(pub GlobalLocation);
+    impl PackableMessage for SetUnacknowledged {
+        fn opcode() -> Opcode {
+            LocationOpcode::GlobalSetUnacknowledged.into()
+        }
+
+        fn message_size(&self) -> usize {
+            GLOBAL_LOCATION_LEN
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            pack_global_location(&self.0, buffer)
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            Ok(SetUnacknowledged(unpack_global_location(buffer)?))
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Status(pub GlobalLocation);
+    impl PackableMessage for Status {
+        fn opcode() -> Opcode {
+            LocationOpcode::GlobalStatus.into()
+        }
+
+        fn message_size(&self) -> usize {
+            GLOBAL_LOCATION_LEN
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            pack_global_location(&self.0, buffer)
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            Ok(Status(unpack_global_location(buffer)?))
+        }
+    }
+}
+pub mod local {
+    use crate::access::Opcode;
+    use crate::models::generics::location::{
+        Altitude, FloorNumber, LocalCoordinate, LocalLocation, LocationOpcode, Uncertainty,
+    };
+    use crate::models::{MessagePackError, PackableMessage};
+
+    /// Local North (2) + Local East (2) + Local Altitude (2) + Floor Number (1) +
+    /// Uncertainty (2).
+    pub const LOCAL_LOCATION_LEN: usize = 2 + 2 + 2 + 1 + 2;
+
+    fn pack_local_location(
+        location: &LocalLocation,
+        buffer: &mut [u8],
+    ) -> Result<(), MessagePackError> {
+        if buffer.len() < LOCAL_LOCATION_LEN {
+            return Err(MessagePackError::SmallBuffer);
+        }
+        buffer[..2].copy_from_slice(&location.north.0.to_le_bytes());
+        buffer[2..4].copy_from_slice(&location.east.0.to_le_bytes());
+        buffer[4..6].copy_from_slice(&location.altitude.0.to_le_bytes());
+        buffer[6] = location.floor.0;
+        buffer[7..9].copy_from_slice(&u16::from(location.uncertainty).to_le_bytes());
+        Ok(())
+    }
+    fn unpack_local_location(buffer: &[u8]) -> Result<LocalLocation, MessagePackError> {
+        if buffer.len() != LOCAL_LOCATION_LEN {
+            return Err(MessagePackError::BadLength);
+        }
+        Ok(LocalLocation {
+            north: LocalCoordinate(i16::from_le_bytes([buffer[0], buffer[1]])),
+            east: LocalCoordinate(i16::from_le_bytes([buffer[2], buffer[3]])),
+            altitude: Altitude(i16::from_le_bytes([buffer[4], buffer[5]])),
+            floor: FloorNumber(buffer[6]),
+            uncertainty: Uncertainty::from(u16::from_le_bytes([buffer[7], buffer[8]])),
+        })
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Get;
+    impl PackableMessage for Get {
+        fn opcode() -> Opcode {
+            LocationOpcode::LocalGet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            0
+        }
+
+        fn pack_into(&self, _buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            Ok(())
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.is_empty() {
+                Ok(Get)
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Set(pub LocalLocation);
+    impl PackableMessage for Set {
+        fn opcode() -> Opcode {
+            LocationOpcode::LocalSet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            LOCAL_LOCATION_LEN
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            pack_local_location(&self.0, buffer)
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            Ok(Set(unpack_local_location(buffer)?))
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct SetUnacknowledged(pub LocalLocation);
+    impl PackableMessage for SetUnacknowledged {
+        fn opcode() -> Opcode {
+            LocationOpcode::LocalSetUnacknowledged.into()
+        }
+
+        fn message_size(&self) -> usize {
+            LOCAL_LOCATION_LEN
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            pack_local_location(&self.0, buffer)
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            Ok(SetUnacknowledged(unpack_local_location(buffer)?))
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Status(pub LocalLocation);
+    impl PackableMessage for Status {
+        fn opcode() -> Opcode {
+            LocationOpcode::LocalStatus.into()
+        }
+
+        fn message_size(&self) -> usize {
+            LOCAL_LOCATION_LEN
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            pack_local_location(&self.0, buffer)
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            Ok(Status(unpack_local_location(buffer)?))
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::generics::location::{
+        Altitude, FloorNumber, GlobalLocation, Latitude, LocalCoordinate, LocalLocation, Longitude,
+        Uncertainty,
+    };
+    use crate::models::PackableMessage;
+
+    #[test]
+    fn test_global_location_pack() {
+        let status = global::Status(GlobalLocation {
+            latitude: Latitude::from_degrees(-90.0).expect("valid latitude"),
+            longitude: Longitude::from_degrees(90.0).expect("valid longitude"),
+            altitude: Altitude::new(-12),
+        });
+        // -90 degrees is -i32::MAX and 90 degrees is half of i32::MAX (rounded down).
+        assert_eq!(status.0.latitude, Latitude(-0x7FFF_FFFF));
+        assert_eq!(status.0.longitude, Longitude(0x3FFF_FFFF));
+        let mut buf = [0_u8; global::GLOBAL_LOCATION_LEN];
+        status
+            .pack_into(&mut buf[..])
+            .ok()
+            .expect("buffer is big enough");
+        assert_eq!(
+            buf,
+            [0x01, 0x00, 0x00, 0x80, 0xFF, 0xFF, 0xFF, 0x3F, 0xF4, 0xFF]
+        );
+        match global::Status::unpack_from(&buf[..]) {
+            Ok(unpacked) => assert_eq!(unpacked, status),
+            Err(_) => panic!("status should unpack"),
+        }
+        assert!(global::Status::unpack_from(&buf[..9]).is_err());
+    }
+    #[test]
+    fn test_global_coordinates() {
+        assert_eq!(Latitude::from_degrees(90.0), Some(Latitude(i32::MAX)));
+        assert_eq!(Latitude::from_degrees(0.0), Some(Latitude(0)));
+        assert_eq!(Latitude::from_degrees(90.5), None);
+        assert_eq!(Longitude::from_degrees(-180.0), Some(Longitude(-i32::MAX)));
+        assert_eq!(Longitude::from_degrees(-180.5), None);
+        // 0x80000000 is reserved for not configured.
+        assert_eq!(Latitude::NOT_CONFIGURED.degrees(), None);
+        assert!(!Longitude(i32::MIN).is_configured());
+        let degrees = Longitude::from_degrees(-122.4194)
+            .and_then(Longitude::degrees)
+            .expect("configured");
+        assert!((degrees + 122.4194).abs() < 1e-6);
+        assert_eq!(GlobalLocation::default().latitude, Latitude::NOT_CONFIGURED);
+    }
+    #[test]
+    fn test_uncertainty() {
+        let uncertainty = Uncertainty::new(true, 0x3, 0xA).expect("valid exponents");
+        assert_eq!(u16::from(uncertainty), 0xA301);
+        assert_eq!(Uncertainty::from(0xA301), uncertainty);
+        // Reserved bits are ignored.
+        assert_eq!(Uncertainty::from(0xA3FF), uncertainty);
+        assert_eq!(uncertainty.update_time().as_secs(), 1);
+        assert_eq!(uncertainty.precision_mm(), 128_000);
+        assert_eq!(Uncertainty::new(false, 0x10, 0), None);
+        assert_eq!(u16::from(Uncertainty::default()), 0);
+    }
+    #[test]
+    fn test_local_location_pack() {
+        let location = LocalLocation {
+            north: LocalCoordinate(-300),
+            east: LocalCoordinate::NOT_CONFIGURED,
+            altitude: Altitude::new(i16::MAX),
+            floor: FloorNumber::new(-1).expect("valid floor"),
+            uncertainty: Uncertainty::new(false, 0x1, 0x2).expect("valid exponents"),
+        };
+        assert_eq!(location.altitude, Altitude::TOO_HIGH);
+        assert_eq!(location.floor.floor(), Some(-1));
+        let mut buf = [0_u8; local::LOCAL_LOCATION_LEN];
+        local::Set(location)
+            .pack_into(&mut buf[..])
+            .ok()
+            .expect("buffer is big enough");
+        assert_eq!(buf, [0xD4, 0xFE, 0x00, 0x80, 0xFE, 0x7F, 19, 0x00, 0x21]);
+        match local::Set::unpack_from(&buf[..]) {
+            Ok(unpacked) => assert_eq!(unpacked.0, location),
+            Err(_) => panic!("set should unpack"),
+        }
+        assert_eq!(FloorNumber::new(232), None);
+        assert_eq!(FloorNumber::GROUND_FLOOR_0.floor(), None);
+    }
+}
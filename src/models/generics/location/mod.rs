@@ -0,0 +1,265 @@
+//! Generic Location Model (Generic Location Server, Generic Location Setup Server and Generic
+//! Location Client). Reports where a node is, either globally (WGS84 coordinates) or locally
+//! (relative to a building's origin, with a floor number).
+use crate::access::SigOpcode::{DoubleOctet, SingleOctet};
+use crate::access::{Opcode, OpcodeConversationError};
+use core::convert::TryFrom;
+use core::time::Duration;
+
+pub mod messages;
+pub mod server;
+
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum LocationOpcode {
+    GlobalGet,
+    GlobalStatus,
+    GlobalSet,
+    GlobalSetUnacknowledged,
+    LocalGet,
+    LocalStatus,
+    LocalSet,
+    LocalSetUnacknowledged,
+}
+impl TryFrom<Opcode> for LocationOpcode {
+    type Error = OpcodeConversationError;
+
+    fn try_from(opcode: Opcode) -> Result<Self, Self::Error> {
+        match opcode {
+            Opcode::SIG(SingleOctet(0x40)) => Ok(LocationOpcode::GlobalStatus),
+            Opcode::SIG(SingleOctet(0x41)) => Ok(LocationOpcode::GlobalSet),
+            Opcode::SIG(SingleOctet(0x42)) => Ok(LocationOpcode::GlobalSetUnacknowledged),
+            Opcode::SIG(DoubleOctet(0x8225)) => Ok(LocationOpcode::GlobalGet),
+            Opcode::SIG(DoubleOctet(0x8226)) => Ok(LocationOpcode::LocalGet),
+            Opcode::SIG(DoubleOctet(0x8227)) => Ok(LocationOpcode::LocalStatus),
+            Opcode::SIG(DoubleOctet(0x8228)) => Ok(LocationOpcode::LocalSet),
+            Opcode::SIG(DoubleOctet(0x8229)) => Ok(LocationOpcode::LocalSetUnacknowledged),
+            _ => Err(OpcodeConversationError(())),
+        }
+    }
+}
+impl From<LocationOpcode> for Opcode {
+    fn from(opcode: LocationOpcode) -> Self {
+        match opcode {
+            LocationOpcode::GlobalStatus => SingleOctet(0x40).into(),
+            LocationOpcode::GlobalSet => SingleOctet(0x41).into(),
+            LocationOpcode::GlobalSetUnacknowledged => SingleOctet(0x42).into(),
+            LocationOpcode::GlobalGet => DoubleOctet(0x8225).into(),
+            LocationOpcode::LocalGet => DoubleOctet(0x8226).into(),
+            LocationOpcode::LocalStatus => DoubleOctet(0x8227).into(),
+            LocationOpcode::LocalSet => DoubleOctet(0x8228).into(),
+            LocationOpcode::LocalSetUnacknowledged => DoubleOctet(0x8229).into(),
+        }
+    }
+}
+/// Global Latitude. Signed 32-bit fixed point where `i32::MAX` is 90 degrees north (and
+/// `-i32::MAX` 90 degrees south). `0x80000000` means the latitude isn't configured.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct Latitude(pub i32);
+impl Latitude {
+    pub const NOT_CONFIGURED: Latitude = Latitude(i32::MIN);
+    /// Returns `None` if `degrees` isn't in `-90.0..=90.0`.
+    pub fn from_degrees(degrees: f64) -> Option<Latitude> {
+        degrees_to_fixed(degrees, 90.0).map(Latitude)
+    }
+    /// Returns the latitude in degrees or `None` if it isn't configured.
+    pub fn degrees(self) -> Option<f64> {
+        fixed_to_degrees(self.0, 90.0)
+    }
+    pub fn is_configured(self) -> bool {
+        self != Self::NOT_CONFIGURED
+    }
+}
+/// Global Longitude. Signed 32-bit fixed point where `i32::MAX` is 180 degrees east (and
+/// `-i32::MAX` 180 degrees west). `0x80000000` means the longitude isn't configured.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct Longitude(pub i32);
+impl Longitude {
+    pub const NOT_CONFIGURED: Longitude = Longitude(i32::MIN);
+    /// Returns `None` if `degrees` isn't in `-180.0..=180.0`.
+    pub fn from_degrees(degrees: f64) -> Option<Longitude> {
+        degrees_to_fixed(degrees, 180.0).map(Longitude)
+    }
+    /// Returns the longitude in degrees or `None` if it isn't configured.
+    pub fn degrees(self) -> Option<f64> {
+        fixed_to_degrees(self.0, 180.0)
+    }
+    pub fn is_configured(self) -> bool {
+        self != Self::NOT_CONFIGURED
+    }
+}
+fn degrees_to_fixed(degrees: f64, max_degrees: f64) -> Option<i32> {
+    if (-max_degrees..=max_degrees).contains(&degrees) {
+        Some((degrees / max_degrees * f64::from(i32::MAX)) as i32)
+    } else {
+        None
+    }
+}
+fn fixed_to_degrees(value: i32, max_degrees: f64) -> Option<f64> {
+    if value == i32::MIN {
+        None
+    } else {
+        Some(f64::from(value) / f64::from(i32::MAX) * max_degrees)
+    }
+}
+/// Global (meters above the WGS84 ellipsoid) or Local (decimeters above the local origin)
+/// Altitude. `0x7FFF` means the altitude isn't configured and `0x7FFE` means it's `0x7FFE` or
+/// higher.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct Altitude(pub i16);
+impl Altitude {
+    pub const NOT_CONFIGURED: Altitude = Altitude(0x7FFF);
+    pub const TOO_HIGH: Altitude = Altitude(0x7FFE);
+    /// Clamps `altitude` to `TOO_HIGH` so it's never mistaken for `NOT_CONFIGURED`.
+    pub fn new(altitude: i16) -> Altitude {
+        Altitude(altitude.min(Self::TOO_HIGH.0))
+    }
+    pub fn is_configured(self) -> bool {
+        self != Self::NOT_CONFIGURED
+    }
+}
+impl Default for Altitude {
+    fn default() -> Self {
+        Self::NOT_CONFIGURED
+    }
+}
+/// Local North or Local East in decimeters from the local origin. `0x8000` means the coordinate
+/// isn't configured.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct LocalCoordinate(pub i16);
+impl LocalCoordinate {
+    pub const NOT_CONFIGURED: LocalCoordinate = LocalCoordinate(i16::MIN);
+    pub fn is_configured(self) -> bool {
+        self != Self::NOT_CONFIGURED
+    }
+}
+impl Default for LocalCoordinate {
+    fn default() -> Self {
+        Self::NOT_CONFIGURED
+    }
+}
+/// Floor Number. `0x00..=0xFB` are floors -20 to 231 (offset by 20). The rest are special
+/// values (see the associated constants).
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct FloorNumber(pub u8);
+pub const FLOOR_NUMBER_MIN: i16 = -20;
+pub const FLOOR_NUMBER_MAX: i16 = 231;
+impl FloorNumber {
+    /// Floor 232 or higher.
+    pub const ABOVE_MAX: FloorNumber = FloorNumber(0xFC);
+    /// The ground floor, numbered 0.
+    pub const GROUND_FLOOR_0: FloorNumber = FloorNumber(0xFD);
+    /// The ground floor, numbered 1.
+    pub const GROUND_FLOOR_1: FloorNumber = FloorNumber(0xFE);
+    pub const NOT_CONFIGURED: FloorNumber = FloorNumber(0xFF);
+    /// Returns `None` if `floor` isn't in `FLOOR_NUMBER_MIN..=FLOOR_NUMBER_MAX`.
+    pub fn new(floor: i16) -> Option<FloorNumber> {
+        if (FLOOR_NUMBER_MIN..=FLOOR_NUMBER_MAX).contains(&floor) {
+            Some(FloorNumber(
+                u8::try_from(floor - FLOOR_NUMBER_MIN).expect("floor is in range"),
+            ))
+        } else {
+            None
+        }
+    }
+    /// Returns the floor or `None` for the special values.
+    pub fn floor(self) -> Option<i16> {
+        if self < Self::ABOVE_MAX {
+            Some(i16::from(self.0) + FLOOR_NUMBER_MIN)
+        } else {
+            None
+        }
+    }
+    pub fn is_configured(self) -> bool {
+        self != Self::NOT_CONFIGURED
+    }
+}
+impl Default for FloorNumber {
+    fn default() -> Self {
+        Self::NOT_CONFIGURED
+    }
+}
+/// Largest Update Time and Precision exponent (4 bits).
+pub const UNCERTAINTY_EXPONENT_MAX: u8 = 0x0F;
+/// Location Uncertainty. Packed into 16 bits with the Stationary flag in bit 0 (bits 1-7 are
+/// reserved), the Update Time in bits 8-11 and the Precision in bits 12-15.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash, Default)]
+pub struct Uncertainty {
+    /// `false` if the node is stationary.
+    pub mobile: bool,
+    update_time: u8,
+    precision: u8,
+}
+impl Uncertainty {
+    /// Returns `None` if `update_time` or `precision` is bigger than `UNCERTAINTY_EXPONENT_MAX`.
+    pub fn new(mobile: bool, update_time: u8, precision: u8) -> Option<Uncertainty> {
+        if update_time <= UNCERTAINTY_EXPONENT_MAX && precision <= UNCERTAINTY_EXPONENT_MAX {
+            Some(Uncertainty {
+                mobile,
+                update_time,
+                precision,
+            })
+        } else {
+            None
+        }
+    }
+    /// Raw Update Time exponent (`x` in `2^(x-3)` seconds).
+    pub fn update_time_exponent(self) -> u8 {
+        self.update_time
+    }
+    /// Raw Precision exponent (`x` in `2^(x-3)` meters).
+    pub fn precision_exponent(self) -> u8 {
+        self.precision
+    }
+    /// Time since the location was last updated (`2^(x-3)` seconds so 125ms to ~68 minutes).
+    pub fn update_time(self) -> Duration {
+        Duration::from_millis(125 << self.update_time)
+    }
+    /// Precision of the location in millimeters (`2^(x-3)` meters so 125mm to ~4km).
+    pub fn precision_mm(self) -> u32 {
+        125 << self.precision
+    }
+}
+impl From<Uncertainty> for u16 {
+    fn from(uncertainty: Uncertainty) -> Self {
+        u16::from(uncertainty.mobile)
+            | u16::from(uncertainty.update_time) << 8
+            | u16::from(uncertainty.precision) << 12
+    }
+}
+impl From<u16> for Uncertainty {
+    /// Ignores the reserved bits.
+    fn from(value: u16) -> Self {
+        Uncertainty {
+            mobile: value & 1 != 0,
+            update_time: ((value >> 8) & 0x0F) as u8,
+            precision: (value >> 12) as u8,
+        }
+    }
+}
+/// Generic Location Global State.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct GlobalLocation {
+    pub latitude: Latitude,
+    pub longitude: Longitude,
+    /// In meters.
+    pub altitude: Altitude,
+}
+impl Default for GlobalLocation {
+    fn default() -> Self {
+        Self {
+            latitude: Latitude::NOT_CONFIGURED,
+            longitude: Longitude::NOT_CONFIGURED,
+            altitude: Altitude::NOT_CONFIGURED,
+        }
+    }
+}
+/// Generic Location Local State.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash, Default)]
+pub struct LocalLocation {
+    pub north: LocalCoordinate,
+    pub east: LocalCoordinate,
+    /// In decimeters.
+    pub altitude: Altitude,
+    pub floor: FloorNumber,
+    pub uncertainty: Uncertainty,
+}
@@ -0,0 +1,77 @@
+//! Generic Location Server and Generic Location Setup Server models. The Server only reports the
+//! location while the Setup Server (on the same element) also lets a Client set it.
+use crate::models::generics::location::messages::{global, local};
+use crate::models::generics::location::{GlobalLocation, LocalLocation};
+
+/// Generic Location Server for an element. Stores the Global and Local location states.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash, Default)]
+pub struct LocationServer {
+    global: GlobalLocation,
+    local: LocalLocation,
+}
+impl LocationServer {
+    pub fn new(global: GlobalLocation, local: LocalLocation) -> Self {
+        Self { global, local }
+    }
+    pub fn global(&self) -> GlobalLocation {
+        self.global
+    }
+    pub fn local(&self) -> LocalLocation {
+        self.local
+    }
+    /// Updates the Global location (from a GPS for example). Doesn't publish anything.
+    pub fn set_global(&mut self, global: GlobalLocation) {
+        self.global = global
+    }
+    /// Updates the Local location. Doesn't publish anything.
+    pub fn set_local(&mut self, local: LocalLocation) {
+        self.local = local
+    }
+    pub fn global_status(&self) -> global::Status {
+        global::Status(self.global)
+    }
+    pub fn local_status(&self) -> local::Status {
+        local::Status(self.local)
+    }
+    /// Handles a Generic Location Global Get and returns the Status to respond with.
+    pub fn handle_global_get(&self, _get: global::Get) -> global::Status {
+        self.global_status()
+    }
+    /// Handles a Generic Location Local Get and returns the Status to respond with.
+    pub fn handle_local_get(&self, _get: local::Get) -> local::Status {
+        self.local_status()
+    }
+}
+/// Generic Location Setup Server for an element. Shares its state with the element's
+/// `LocationServer` (see `server`).
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash, Default)]
+pub struct LocationSetupServer {
+    server: LocationServer,
+}
+impl LocationSetupServer {
+    pub fn new(server: LocationServer) -> Self {
+        Self { server }
+    }
+    pub fn server(&self) -> &LocationServer {
+        &self.server
+    }
+    pub fn server_mut(&mut self) -> &mut LocationServer {
+        &mut self.server
+    }
+    /// Handles a Generic Location Global Set and returns the Status to respond with.
+    pub fn handle_global_set(&mut self, set: global::Set) -> global::Status {
+        self.server.set_global(set.0);
+        self.server.global_status()
+    }
+    pub fn handle_global_set_unacknowledged(&mut self, set: global::SetUnacknowledged) {
+        self.server.set_global(set.0)
+    }
+    /// Handles a Generic Location Local Set and returns the Status to respond with.
+    pub fn handle_local_set(&mut self, set: local::Set) -> local::Status {
+        self.server.set_local(set.0);
+        self.server.local_status()
+    }
+    pub fn handle_local_set_unacknowledged(&mut self, set: local::SetUnacknowledged) {
+        self.server.set_local(set.0)
+    }
+}
@@ -1 +1,3 @@
-
+//! Generic Models. Simple, reusable models (battery, location, etc) shared by many device types.
+pub mod battery;
+pub mod location;
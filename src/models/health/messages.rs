@@ -0,0 +1,473 @@
+use crate::bytes::ToFromBytesEndian;
+use crate::foundation::health::FaultID;
+use crate::mesh::CompanyID;
+use crate::models::MessagePackError;
+use alloc::vec::Vec;
+
+pub mod attention {
+    use crate::access::Opcode;
+    use crate::foundation::state::AttentionTimer;
+    use crate::models::health::HealthOpcode;
+    use crate::models::{MessagePackError, PackableMessage};
+
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Get;
+    impl PackableMessage for Get {
+        fn opcode() -> Opcode {
+            HealthOpcode::AttentionGet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            0
+        }
+
+        fn pack_into(&self, _buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            Ok(())
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.is_empty() {
+                Ok(Get)
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Set(pub AttentionTimer);
+    impl PackableMessage for Set {
+        fn opcode() -> Opcode {
+            HealthOpcode::AttentionSet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            1
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.is_empty() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                buffer[0] = (self.0).0;
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() == 1 {
+                Ok(Set(AttentionTimer::new(buffer[0])))
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct SetUnacknowledged(pub AttentionTimer);
+    impl PackableMessage for SetUnacknowledged {
+        fn opcode() -> Opcode {
+            HealthOpcode::AttentionSetUnacknowledged.into()
+        }
+
+        fn message_size(&self) -> usize {
+            1
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.is_empty() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                buffer[0] = (self.0).0;
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() == 1 {
+                Ok(SetUnacknowledged(AttentionTimer::new(buffer[0])))
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Status(pub AttentionTimer);
+    impl PackableMessage for Status {
+        fn opcode() -> Opcode {
+            HealthOpcode::AttentionStatus.into()
+        }
+
+        fn message_size(&self) -> usize {
+            1
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.is_empty() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                buffer[0] = (self.0).0;
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() == 1 {
+                Ok(Status(AttentionTimer::new(buffer[0])))
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+}
+/// Test ID and Company ID in front of the fault array.
+const FAULTS_POS: usize = 3;
+/// Packs `test_id`, `company_id` and `faults` (one byte each) shared by the Fault Status and the
+/// Current Status.
+fn pack_faults(
+    test_id: u8,
+    company_id: CompanyID,
+    faults: &[FaultID],
+    buffer: &mut [u8],
+) -> Result<(), MessagePackError> {
+    if buffer.len() < FAULTS_POS + faults.len() {
+        return Err(MessagePackError::SmallBuffer);
+    }
+    buffer[0] = test_id;
+    buffer[1..FAULTS_POS].copy_from_slice(&company_id.to_bytes_le());
+    for (byte, &fault) in buffer[FAULTS_POS..].iter_mut().zip(faults) {
+        *byte = fault.into();
+    }
+    Ok(())
+}
+fn unpack_faults(buffer: &[u8]) -> Result<(u8, CompanyID, Vec<FaultID>), MessagePackError> {
+    if buffer.len() < FAULTS_POS {
+        return Err(MessagePackError::BadLength);
+    }
+    Ok((
+        buffer[0],
+        CompanyID::from_bytes_le(&buffer[1..FAULTS_POS]).ok_or(MessagePackError::BadBytes)?,
+        buffer[FAULTS_POS..]
+            .iter()
+            .map(|&fault| FaultID::from(fault))
+            .collect(),
+    ))
+}
+pub mod fault {
+    use super::{pack_faults, unpack_faults, FAULTS_POS};
+    use crate::access::Opcode;
+    use crate::bytes::ToFromBytesEndian;
+    use crate::foundation::health::FaultID;
+    use crate::mesh::CompanyID;
+    use crate::models::health::HealthOpcode;
+    use crate::models::{MessagePackError, PackableMessage};
+    use alloc::vec::Vec;
+
+    fn unpack_company_id(buffer: &[u8]) -> Result<CompanyID, MessagePackError> {
+        if buffer.len() == CompanyID::byte_len() {
+            CompanyID::from_bytes_le(buffer).ok_or(MessagePackError::BadBytes)
+        } else {
+            Err(MessagePackError::BadLength)
+        }
+    }
+    fn pack_company_id(company_id: CompanyID, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+        if buffer.len() < CompanyID::byte_len() {
+            Err(MessagePackError::SmallBuffer)
+        } else {
+            buffer[..CompanyID::byte_len()].copy_from_slice(&company_id.to_bytes_le());
+            Ok(())
+        }
+    }
+    /// Asks for the Registered Fault state of `CompanyID`.
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    pub struct Get(pub CompanyID);
+    impl PackableMessage for Get {
+        fn opcode() -> Opcode {
+            HealthOpcode::FaultGet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            CompanyID::byte_len()
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            pack_company_id(self.0, buffer)
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            Ok(Get(unpack_company_id(buffer)?))
+        }
+    }
+    /// Clears the Registered Fault state of `CompanyID`.
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    pub struct Clear(pub CompanyID);
+    impl PackableMessage for Clear {
+        fn opcode() -> Opcode {
+            HealthOpcode::FaultClear.into()
+        }
+
+        fn message_size(&self) -> usize {
+            CompanyID::byte_len()
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            pack_company_id(self.0, buffer)
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            Ok(Clear(unpack_company_id(buffer)?))
+        }
+    }
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    pub struct ClearUnacknowledged(pub CompanyID);
+    impl PackableMessage for ClearUnacknowledged {
+        fn opcode() -> Opcode {
+            HealthOpcode::FaultClearUnacknowledged.into()
+        }
+
+        fn message_size(&self) -> usize {
+            CompanyID::byte_len()
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            pack_company_id(self.0, buffer)
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            Ok(ClearUnacknowledged(unpack_company_id(buffer)?))
+        }
+    }
+    /// Runs the self-test `test_id` of `company_id`.
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    pub struct Test {
+        pub test_id: u8,
+        pub company_id: CompanyID,
+    }
+    impl Test {
+        fn pack(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < FAULTS_POS {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                buffer[0] = self.test_id;
+                pack_company_id(self.company_id, &mut buffer[1..])
+            }
+        }
+        fn unpack(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() == FAULTS_POS {
+                Ok(Test {
+                    test_id: buffer[0],
+                    company_id: unpack_company_id(&buffer[1..])?,
+                })
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+    impl PackableMessage for Test {
+        fn opcode() -> Opcode {
+            HealthOpcode::FaultTest.into()
+        }
+
+        fn message_size(&self) -> usize {
+            FAULTS_POS
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            self.pack(buffer)
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            Test::unpack(buffer)
+        }
+    }
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    pub struct TestUnacknowledged(pub Test);
+    impl PackableMessage for TestUnacknowledged {
+        fn opcode() -> Opcode {
+            HealthOpcode::FaultTestUnacknowledged.into()
+        }
+
+        fn message_size(&self) -> usize {
+            FAULTS_POS
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            self.0.pack(buffer)
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            Ok(TestUnacknowledged(Test::unpack(buffer)?))
+        }
+    }
+    /// The Registered Fault state of `company_id` and the most recent test it ran.
+    #[derive(Clone, Eq, PartialEq, Debug)]
+    pub struct Status {
+        pub test_id: u8,
+        pub company_id: CompanyID,
+        pub faults: Vec<FaultID>,
+    }
+    impl PackableMessage for Status {
+        fn opcode() -> Opcode {
+            HealthOpcode::FaultStatus.into()
+        }
+
+        fn message_size(&self) -> usize {
+            FAULTS_POS + self.faults.len()
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            pack_faults(self.test_id, self.company_id, &self.faults, buffer)
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            let (test_id, company_id, faults) = unpack_faults(buffer)?;
+            Ok(Status {
+                test_id,
+                company_id,
+                faults,
+            })
+        }
+    }
+}
+pub mod current {
+    use super::{pack_faults, unpack_faults, FAULTS_POS};
+    use crate::access::Opcode;
+    use crate::foundation::health::FaultID;
+    use crate::mesh::CompanyID;
+    use crate::models::health::HealthOpcode;
+    use crate::models::{MessagePackError, PackableMessage};
+    use alloc::vec::Vec;
+
+    /// The Current Fault state of `company_id` (published periodically by the Health Server).
+    #[derive(Clone, Eq, PartialEq, Debug)]
+    pub struct Status {
+        pub test_id: u8,
+        pub company_id: CompanyID,
+        pub faults: Vec<FaultID>,
+    }
+    impl PackableMessage for Status {
+        fn opcode() -> Opcode {
+            HealthOpcode::CurrentStatus.into()
+        }
+
+        fn message_size(&self) -> usize {
+            FAULTS_POS + self.faults.len()
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            pack_faults(self.test_id, self.company_id, &self.faults, buffer)
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            let (test_id, company_id, faults) = unpack_faults(buffer)?;
+            Ok(Status {
+                test_id,
+                company_id,
+                faults,
+            })
+        }
+    }
+}
+pub mod period {
+    use crate::access::Opcode;
+    use crate::models::health::HealthOpcode;
+    use crate::models::{MessagePackError, PackableMessage};
+
+    /// Highest Fast Period Divisor. The publish period is divided by `2^divisor` while there are
+    /// current faults.
+    pub const MAX_FAST_PERIOD_DIVISOR: u8 = 15;
+    fn unpack_divisor(buffer: &[u8]) -> Result<u8, MessagePackError> {
+        match buffer {
+            [divisor] if *divisor <= MAX_FAST_PERIOD_DIVISOR => Ok(*divisor),
+            [_] => Err(MessagePackError::BadBytes),
+            _ => Err(MessagePackError::BadLength),
+        }
+    }
+    fn pack_divisor(divisor: u8, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+        if buffer.is_empty() {
+            Err(MessagePackError::SmallBuffer)
+        } else {
+            buffer[0] = divisor;
+            Ok(())
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Get;
+    impl PackableMessage for Get {
+        fn opcode() -> Opcode {
+            HealthOpcode::PeriodGet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            0
+        }
+
+        fn pack_into(&self, _buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            Ok(())
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.is_empty() {
+                Ok(Get)
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+    /// Sets the Fast Period Divisor.
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Set(pub u8);
+    impl PackableMessage for Set {
+        fn opcode() -> Opcode {
+            HealthOpcode::PeriodSet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            1
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            pack_divisor(self.0, buffer)
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            Ok(Set(unpack_divisor(buffer)?))
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct SetUnacknowledged(pub u8);
+    impl PackableMessage for SetUnacknowledged {
+        fn opcode() -> Opcode {
+            HealthOpcode::PeriodSetUnacknowledged.into()
+        }
+
+        fn message_size(&self) -> usize {
+            1
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            pack_divisor(self.0, buffer)
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            Ok(SetUnacknowledged(unpack_divisor(buffer)?))
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Status(pub u8);
+    impl PackableMessage for Status {
+        fn opcode() -> Opcode {
+            HealthOpcode::PeriodStatus.into()
+        }
+
+        fn message_size(&self) -> usize {
+            1
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            pack_divisor(self.0, buffer)
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            Ok(Status(unpack_divisor(buffer)?))
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Health Models (Health Server and Health Client). Every node has a Health Server on its
+//! primary element.
+use crate::access::SigOpcode::{DoubleOctet, SingleOctet};
+use crate::access::{Opcode, OpcodeConversationError};
+use core::convert::TryFrom;
+
+pub mod messages;
+pub mod server;
+
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum HealthOpcode {
+    AttentionGet,
+    AttentionSet,
+    AttentionSetUnacknowledged,
+    AttentionStatus,
+
+    CurrentStatus,
+
+    FaultClear,
+    FaultClearUnacknowledged,
+    FaultGet,
+    FaultStatus,
+    FaultTest,
+    FaultTestUnacknowledged,
+
+    PeriodGet,
+    PeriodSet,
+    PeriodSetUnacknowledged,
+    PeriodStatus,
+}
+impl TryFrom<Opcode> for HealthOpcode {
+    type Error = OpcodeConversationError;
+
+    fn try_from(opcode: Opcode) -> Result<Self, Self::Error> {
+        match opcode {
+            Opcode::SIG(SingleOctet(0x04)) => Ok(HealthOpcode::CurrentStatus),
+            Opcode::SIG(SingleOctet(0x05)) => Ok(HealthOpcode::FaultStatus),
+            Opcode::SIG(DoubleOctet(d)) => match d {
+                0x8004 => Ok(HealthOpcode::AttentionGet),
+                0x8005 => Ok(HealthOpcode::AttentionSet),
+                0x8006 => Ok(HealthOpcode::AttentionSetUnacknowledged),
+                0x8007 => Ok(HealthOpcode::AttentionStatus),
+                0x802F => Ok(HealthOpcode::FaultClear),
+                0x8030 => Ok(HealthOpcode::FaultClearUnacknowledged),
+                0x8031 => Ok(HealthOpcode::FaultGet),
+                0x8032 => Ok(HealthOpcode::FaultTest),
+                0x8033 => Ok(HealthOpcode::FaultTestUnacknowledged),
+                0x8034 => Ok(HealthOpcode::PeriodGet),
+                0x8035 => Ok(HealthOpcode::PeriodSet),
+                0x8036 => Ok(HealthOpcode::PeriodSetUnacknowledged),
+                0x8037 => Ok(HealthOpcode::PeriodStatus),
+                _ => Err(OpcodeConversationError(())),
+            },
+            _ => Err(OpcodeConversationError(())),
+        }
+    }
+}
+impl From<HealthOpcode> for Opcode {
+    fn from(opcode: HealthOpcode) -> Self {
+        match opcode {
+            HealthOpcode::AttentionGet => DoubleOctet(0x8004).into(),
+            HealthOpcode::AttentionSet => DoubleOctet(0x8005).into(),
+            HealthOpcode::AttentionSetUnacknowledged => DoubleOctet(0x8006).into(),
+            HealthOpcode::AttentionStatus => DoubleOctet(0x8007).into(),
+            HealthOpcode::CurrentStatus => SingleOctet(0x04).into(),
+            HealthOpcode::FaultClear => DoubleOctet(0x802F).into(),
+            HealthOpcode::FaultClearUnacknowledged => DoubleOctet(0x8030).into(),
+            HealthOpcode::FaultGet => DoubleOctet(0x8031).into(),
+            HealthOpcode::FaultStatus => SingleOctet(0x05).into(),
+            HealthOpcode::FaultTest => DoubleOctet(0x8032).into(),
+            HealthOpcode::FaultTestUnacknowledged => DoubleOctet(0x8033).into(),
+            HealthOpcode::PeriodGet => DoubleOctet(0x8034).into(),
+            HealthOpcode::PeriodSet => DoubleOctet(0x8035).into(),
+            HealthOpcode::PeriodSetUnacknowledged => DoubleOctet(0x8036).into(),
+            HealthOpcode::PeriodStatus => DoubleOctet(0x8037).into(),
+        }
+    }
+}
@@ -0,0 +1,539 @@
+//! Health Server model. Keeps the Current and Registered Fault states of each Company ID,
+//! answers the Health Fault, Period and Attention messages and publishes the Current Status
+//! (faster while there are current faults).
+use crate::access::Opcode;
+use crate::address::Address;
+use crate::crypto::aes::MicSize;
+use crate::foundation::health::FaultID;
+use crate::foundation::publication::PublishPeriod;
+use crate::foundation::state::AttentionTimer;
+use crate::mesh::{CompanyID, ElementIndex, IVIndex};
+use crate::models::health::messages::{attention, current, fault, period};
+use crate::models::health::HealthOpcode;
+use crate::models::PackableMessage;
+use crate::stack::messages::{IncomingMessage, MessageKeys, OutgoingMessage};
+use crate::timestamp::{Timestamp, TimestampTrait};
+use crate::upper::AppPayload;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::time::Duration;
+
+/// Passed to the attention callback every time the `AttentionTimer` ticks.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum AttentionEvent {
+    /// The node should keep attracting attention (blink, beep, etc). Holds the seconds remaining.
+    Attention(AttentionTimer),
+    /// The timer reached zero (or was stopped). The node should stop attracting attention.
+    Stop,
+}
+/// Attention Timer for the Health Server. The timer counts down once per second and calls
+/// `callback` every tick so the hardware can indicate attention. Call `tick` once a second
+/// (usually from the stack's maintenance loop).
+pub struct AttentionTimerServer<F: FnMut(AttentionEvent)> {
+    timer: AttentionTimer,
+    callback: F,
+}
+impl<F: FnMut(AttentionEvent)> AttentionTimerServer<F> {
+    pub fn new(callback: F) -> Self {
+        Self {
+            timer: AttentionTimer::default(),
+            callback,
+        }
+    }
+    /// Returns the current `AttentionTimer` (seconds remaining).
+    pub fn get(&self) -> AttentionTimer {
+        self.timer
+    }
+    pub fn is_on(&self) -> bool {
+        self.timer.is_on()
+    }
+    /// Starts (or restarts) the timer with `timer` seconds. Starting with `0` is the same as
+    /// calling `stop`.
+    pub fn start(&mut self, timer: AttentionTimer) {
+        if timer.is_off() {
+            self.stop();
+        } else {
+            self.timer = timer;
+        }
+    }
+    /// Stops the timer. If the timer was running, the callback is told to stop.
+    pub fn stop(&mut self) {
+        if self.timer.is_on() {
+            self.timer = AttentionTimer::default();
+            (self.callback)(AttentionEvent::Stop);
+        }
+    }
+    /// Advances the timer by one second. Does nothing if the timer is off.
+    pub fn tick(&mut self) {
+        if self.timer.is_off() {
+            return;
+        }
+        self.timer = AttentionTimer::new(self.timer.0 - 1);
+        if self.timer.is_off() {
+            (self.callback)(AttentionEvent::Stop);
+        } else {
+            (self.callback)(AttentionEvent::Attention(self.timer));
+        }
+    }
+    /// Returns the Health Attention Status reflecting the current timer.
+    pub fn status(&self) -> attention::Status {
+        attention::Status(self.timer)
+    }
+    /// Handles a Health Attention Set and returns the Status to respond with.
+    pub fn handle_set(&mut self, set: attention::Set) -> attention::Status {
+        self.start(set.0);
+        self.status()
+    }
+    /// Handles a Health Attention Set Unacknowledged. No Status is sent back.
+    pub fn handle_set_unacknowledged(&mut self, set: attention::SetUnacknowledged) {
+        self.start(set.0)
+    }
+    /// Handles a Health Attention Get and returns the Status to respond with.
+    pub fn handle_get(&self, _get: attention::Get) -> attention::Status {
+        self.status()
+    }
+}
+/// The only self-test supported. It's also reported as the most recent test before any Fault
+/// Test.
+pub const STANDARD_TEST_ID: u8 = 0x00;
+/// Attention Timer callback of a `HealthServer` owned by the stack (see
+/// `FullStack::set_health_server`).
+pub type AttentionCallback = Box<dyn FnMut(AttentionEvent) + Send>;
+/// Current and Registered Fault states of one Company ID.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct FaultState {
+    test_id: u8,
+    /// Faults present right now.
+    current: BTreeSet<FaultID>,
+    /// Every fault present since the last Fault Clear.
+    registered: BTreeSet<FaultID>,
+}
+/// Health Server on the primary element. Faults are kept per Company ID (the node's own Company
+/// ID given to `HealthServer::new` plus any added with `HealthServer::add_company`). Call
+/// `tick` once a second to count down the Attention Timer.
+pub struct HealthServer<F: FnMut(AttentionEvent)> {
+    company_id: CompanyID,
+    faults: BTreeMap<u16, FaultState>,
+    fast_period_divisor: u8,
+    attention: AttentionTimerServer<F>,
+    next_publish: Option<Timestamp>,
+}
+impl<F: FnMut(AttentionEvent)> HealthServer<F> {
+    /// Creates a Health Server without any faults for `company_id` (published in the Current
+    /// Status). `attention_callback` is called by the Attention Timer (see
+    /// `AttentionTimerServer`).
+    pub fn new(company_id: CompanyID, attention_callback: F) -> Self {
+        let mut faults = BTreeMap::new();
+        faults.insert(company_id.0, FaultState::default());
+        Self {
+            company_id,
+            faults,
+            fast_period_divisor: 0,
+            attention: AttentionTimerServer::new(attention_callback),
+            next_publish: None,
+        }
+    }
+    /// Keeps fault states for another `company_id` (for vendor specific faults).
+    pub fn add_company(&mut self, company_id: CompanyID) {
+        self.faults.entry(company_id.0).or_default();
+    }
+    /// Sets `fault` as currently present (and registers it) for `company_id`. The next
+    /// `poll_publication` publishes the Current Status right away if it's a new fault. Returns
+    /// `false` if `company_id` is unknown or `fault` is `FaultID::NoFault`.
+    pub fn set_fault(&mut self, company_id: CompanyID, fault: FaultID) -> bool {
+        if fault == FaultID::NoFault {
+            return false;
+        }
+        match self.faults.get_mut(&company_id.0) {
+            Some(state) => {
+                state.registered.insert(fault);
+                if state.current.insert(fault) {
+                    self.next_publish = None;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+    /// Clears `fault` from the current faults of `company_id`. It stays registered until a
+    /// Fault Clear. Returns `false` if `fault` wasn't present.
+    pub fn clear_fault(&mut self, company_id: CompanyID, fault: FaultID) -> bool {
+        self.faults
+            .get_mut(&company_id.0)
+            .map_or(false, |state| state.current.remove(&fault))
+    }
+    /// Faults currently present for `company_id`.
+    pub fn current_faults(&self, company_id: CompanyID) -> Option<Vec<FaultID>> {
+        self.faults
+            .get(&company_id.0)
+            .map(|state| state.current.iter().copied().collect())
+    }
+    /// Faults registered for `company_id` since the last Fault Clear.
+    pub fn registered_faults(&self, company_id: CompanyID) -> Option<Vec<FaultID>> {
+        self.faults
+            .get(&company_id.0)
+            .map(|state| state.registered.iter().copied().collect())
+    }
+    /// Returns if any Company ID has a current fault.
+    pub fn has_current_faults(&self) -> bool {
+        self.faults.values().any(|state| !state.current.is_empty())
+    }
+    pub fn fast_period_divisor(&self) -> u8 {
+        self.fast_period_divisor
+    }
+    pub fn attention(&self) -> &AttentionTimerServer<F> {
+        &self.attention
+    }
+    pub fn attention_mut(&mut self) -> &mut AttentionTimerServer<F> {
+        &mut self.attention
+    }
+    /// Counts the Attention Timer down by one second.
+    pub fn tick(&mut self) {
+        self.attention.tick()
+    }
+    /// Time between Current Status publications for the model's `period`. It's divided by
+    /// `2^fast_period_divisor` while there are current faults. Returns `None` if publishing is
+    /// disabled.
+    pub fn publish_period(&self, period: PublishPeriod) -> Option<Duration> {
+        if period.is_disabled() {
+            None
+        } else {
+            Some(self.fault_publish_period(period.to_duration()))
+        }
+    }
+    /// `period` divided by `2^fast_period_divisor` while there are current faults.
+    pub fn fault_publish_period(&self, period: Duration) -> Duration {
+        if self.has_current_faults() {
+            period / (1_u32 << self.fast_period_divisor)
+        } else {
+            period
+        }
+    }
+    /// Returns the Current Status to publish at `now` (with the model publishing every
+    /// `period`) or `None` if it isn't due yet.
+    pub fn poll_publication(
+        &mut self,
+        now: Timestamp,
+        period: PublishPeriod,
+    ) -> Option<current::Status> {
+        let period = match self.publish_period(period) {
+            Some(period) => period,
+            None => {
+                self.next_publish = None;
+                return None;
+            }
+        };
+        let due = self
+            .next_publish
+            .map_or(true, |next_publish| now >= next_publish);
+        if !due {
+            return None;
+        }
+        self.next_publish = Some(now + period);
+        Some(self.current_status())
+    }
+    /// The Current Status of the node's own Company ID.
+    pub fn current_status(&self) -> current::Status {
+        let state = &self.faults[&self.company_id.0];
+        current::Status {
+            test_id: state.test_id,
+            company_id: self.company_id,
+            faults: state.current.iter().copied().collect(),
+        }
+    }
+    /// The Fault Status of `company_id` or `None` if it's unknown.
+    pub fn fault_status(&self, company_id: CompanyID) -> Option<fault::Status> {
+        self.faults.get(&company_id.0).map(|state| fault::Status {
+            test_id: state.test_id,
+            company_id,
+            faults: state.registered.iter().copied().collect(),
+        })
+    }
+    /// Handles a Health Fault Get. Returns `None` (no response) if the Company ID is unknown.
+    pub fn handle_fault_get(&self, get: fault::Get) -> Option<fault::Status> {
+        self.fault_status(get.0)
+    }
+    /// Clears the registered faults of `company_id`. Returns `false` if it's unknown.
+    fn clear_registered(&mut self, company_id: CompanyID) -> bool {
+        match self.faults.get_mut(&company_id.0) {
+            Some(state) => {
+                state.registered.clear();
+                true
+            }
+            None => false,
+        }
+    }
+    /// Handles a Health Fault Clear and returns the (now empty) Fault Status. Returns `None` if
+    /// the Company ID is unknown.
+    pub fn handle_fault_clear(&mut self, clear: fault::Clear) -> Option<fault::Status> {
+        if self.clear_registered(clear.0) {
+            self.fault_status(clear.0)
+        } else {
+            None
+        }
+    }
+    /// Handles a Health Fault Clear Unacknowledged.
+    pub fn handle_fault_clear_unacknowledged(&mut self, clear: fault::ClearUnacknowledged) {
+        self.clear_registered(clear.0);
+    }
+    /// Handles a Health Fault Test. Only `STANDARD_TEST_ID` is supported and it reports the
+    /// faults already registered. Returns `None` (no response) for other tests or unknown
+    /// Company IDs.
+    pub fn handle_fault_test(&mut self, test: fault::Test) -> Option<fault::Status> {
+        if test.test_id != STANDARD_TEST_ID {
+            return None;
+        }
+        self.faults.get_mut(&test.company_id.0)?.test_id = test.test_id;
+        self.fault_status(test.company_id)
+    }
+    /// Handles a Health Period Set and returns the Status to respond with.
+    pub fn handle_period_set(&mut self, set: period::Set) -> period::Status {
+        self.fast_period_divisor = set.0;
+        period::Status(self.fast_period_divisor)
+    }
+    /// Handles a Health Period Get and returns the Status to respond with.
+    pub fn handle_period_get(&self, _get: period::Get) -> period::Status {
+        period::Status(self.fast_period_divisor)
+    }
+    /// Handles a decrypted Health message and returns the Status to respond with. The response
+    /// is secured with the same AppKey, sent with `tx_iv_index` and addressed back to the
+    /// requester's `src`. Returns `None` if `msg` wasn't secured with an AppKey, can't be
+    /// parsed or has no response (unacknowledged messages).
+    pub fn handle_message<Storage: AsRef<[u8]>>(
+        &mut self,
+        msg: &IncomingMessage<Storage>,
+        tx_iv_index: IVIndex,
+    ) -> Option<OutgoingMessage<Box<[u8]>>> {
+        let app_key_index = msg.app_key_index?;
+        let (opcode, parameters) = Opcode::split_from(msg.payload.as_ref()).ok()?;
+        let payload = match HealthOpcode::try_from(opcode).ok()? {
+            HealthOpcode::FaultGet => {
+                let status = self.handle_fault_get(fault::Get::unpack_from(parameters).ok()?)?;
+                pack_message(&status)
+            }
+            HealthOpcode::FaultClear => {
+                let clear = fault::Clear::unpack_from(parameters).ok()?;
+                pack_message(&self.handle_fault_clear(clear)?)
+            }
+            HealthOpcode::FaultClearUnacknowledged => {
+                let clear = fault::ClearUnacknowledged::unpack_from(parameters).ok()?;
+                self.handle_fault_clear_unacknowledged(clear);
+                return None;
+            }
+            HealthOpcode::FaultTest => {
+                let test = fault::Test::unpack_from(parameters).ok()?;
+                pack_message(&self.handle_fault_test(test)?)
+            }
+            HealthOpcode::FaultTestUnacknowledged => {
+                let test = fault::TestUnacknowledged::unpack_from(parameters).ok()?;
+                self.handle_fault_test(test.0);
+                return None;
+            }
+            HealthOpcode::PeriodGet => {
+                let get = period::Get::unpack_from(parameters).ok()?;
+                pack_message(&self.handle_period_get(get))
+            }
+            HealthOpcode::PeriodSet => {
+                let set = period::Set::unpack_from(parameters).ok()?;
+                pack_message(&self.handle_period_set(set))
+            }
+            HealthOpcode::PeriodSetUnacknowledged => {
+                let set = period::SetUnacknowledged::unpack_from(parameters).ok()?;
+                self.handle_period_set(period::Set(set.0));
+                return None;
+            }
+            HealthOpcode::AttentionGet => {
+                let get = attention::Get::unpack_from(parameters).ok()?;
+                pack_message(&self.attention.handle_get(get))
+            }
+            HealthOpcode::AttentionSet => {
+                let set = attention::Set::unpack_from(parameters).ok()?;
+                pack_message(&self.attention.handle_set(set))
+            }
+            HealthOpcode::AttentionSetUnacknowledged => {
+                let set = attention::SetUnacknowledged::unpack_from(parameters).ok()?;
+                self.attention.handle_set_unacknowledged(set);
+                return None;
+            }
+            HealthOpcode::CurrentStatus
+            | HealthOpcode::FaultStatus
+            | HealthOpcode::PeriodStatus
+            | HealthOpcode::AttentionStatus => return None,
+        };
+        Some(OutgoingMessage {
+            app_payload: AppPayload::new(payload),
+            mic_size: MicSize::Small,
+            force_segment: false,
+            encryption_key: MessageKeys::App(app_key_index),
+            iv_index: tx_iv_index,
+            source_element_index: ElementIndex(0),
+            dst: Address::Unicast(msg.src),
+            ttl: None,
+        })
+    }
+}
+/// Packs `msg` (with its opcode) into an Access payload.
+pub fn pack_message<M: PackableMessage>(msg: &M) -> Box<[u8]> {
+    let mut payload =
+        alloc::vec![0_u8; M::opcode().byte_len() + msg.message_size()].into_boxed_slice();
+    msg.pack_with_opcode(&mut payload[..])
+        .ok()
+        .expect("buffer sized for the message");
+    payload
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::UnicastAddress;
+    use crate::foundation::publication::{StepResolution, Steps};
+    use crate::mesh::{AppKeyIndex, KeyIndex, NetKeyIndex, SequenceNumber, U24};
+    use alloc::vec::Vec;
+
+    const COMPANY_ID: CompanyID = CompanyID(0x05F1);
+
+    fn request<M: PackableMessage>(msg: &M) -> IncomingMessage<Box<[u8]>> {
+        IncomingMessage {
+            payload: pack_message(msg),
+            src: UnicastAddress::new(0x0005),
+            dst: Address::Unicast(UnicastAddress::new(0x0001)),
+            seq: SequenceNumber(U24::new(0x20)),
+            iv_index: IVIndex(0),
+            net_key_index: NetKeyIndex(KeyIndex::new(0)),
+            app_key_index: Some(AppKeyIndex(KeyIndex::new(1))),
+            ttl: None,
+            rssi: None,
+        }
+    }
+    fn fault_status(response: Option<OutgoingMessage<Box<[u8]>>>) -> fault::Status {
+        let response = response.expect("should be answered");
+        assert_eq!(response.dst, Address::from(0x0005));
+        let (opcode, parameters) =
+            Opcode::split_from(&response.app_payload.0[..]).expect("response has an opcode");
+        assert_eq!(opcode, fault::Status::opcode());
+        match fault::Status::unpack_from(parameters) {
+            Ok(status) => status,
+            Err(_) => panic!("fault status should unpack"),
+        }
+    }
+    #[test]
+    fn test_faults() {
+        let mut server = HealthServer::new(COMPANY_ID, |_| ());
+        assert!(server.set_fault(COMPANY_ID, FaultID::OverheatWarning));
+        assert!(server.set_fault(COMPANY_ID, FaultID::BatteryLowWarning));
+        assert!(!server.set_fault(COMPANY_ID, FaultID::NoFault));
+        assert!(!server.set_fault(CompanyID(0x1234), FaultID::OverheatWarning));
+        assert!(server.clear_fault(COMPANY_ID, FaultID::OverheatWarning));
+        assert_eq!(
+            server.current_faults(COMPANY_ID),
+            Some(vec![FaultID::BatteryLowWarning])
+        );
+        assert_eq!(
+            server.current_status().faults,
+            vec![FaultID::BatteryLowWarning]
+        );
+
+        // Cleared faults stay registered until a Fault Clear.
+        let status =
+            fault_status(server.handle_message(&request(&fault::Get(COMPANY_ID)), IVIndex(0)));
+        assert_eq!(status.test_id, STANDARD_TEST_ID);
+        assert_eq!(
+            status.faults,
+            vec![FaultID::BatteryLowWarning, FaultID::OverheatWarning]
+        );
+        assert!(server
+            .handle_message(&request(&fault::Get(CompanyID(0x1234))), IVIndex(0))
+            .is_none());
+        let status =
+            fault_status(server.handle_message(&request(&fault::Clear(COMPANY_ID)), IVIndex(0)));
+        assert!(status.faults.is_empty());
+        // Unsupported tests aren't answered.
+        let test = |test_id| fault::Test {
+            test_id,
+            company_id: COMPANY_ID,
+        };
+        assert!(server
+            .handle_message(&request(&test(0x01)), IVIndex(0))
+            .is_none());
+        fault_status(server.handle_message(&request(&test(STANDARD_TEST_ID)), IVIndex(0)));
+        // Messages secured with the DevKey are ignored.
+        let mut get = request(&fault::Get(COMPANY_ID));
+        get.app_key_index = None;
+        assert!(server.handle_message(&get, IVIndex(0)).is_none());
+    }
+    #[test]
+    fn test_fast_publication() {
+        let start = Timestamp::now();
+        let period = PublishPeriod::new(StepResolution::Second1, Steps::new(8));
+        let mut server = HealthServer::new(COMPANY_ID, |_| ());
+        let response = server.handle_message(&request(&period::Set(2)), IVIndex(0));
+        assert!(response.is_some());
+        assert_eq!(server.fast_period_divisor(), 2);
+        assert_eq!(server.publish_period(period), Some(Duration::from_secs(8)));
+        assert!(server.poll_publication(start, period).is_some());
+        assert!(server
+            .poll_publication(start + Duration::from_secs(7), period)
+            .is_none());
+
+        // A new fault is published right away and then every 8 / 2^2 seconds.
+        let now = start + Duration::from_secs(7);
+        server.set_fault(COMPANY_ID, FaultID::TamperError);
+        assert_eq!(server.publish_period(period), Some(Duration::from_secs(2)));
+        let status = server
+            .poll_publication(now, period)
+            .expect("new fault published");
+        assert_eq!(status.faults, vec![FaultID::TamperError]);
+        assert!(server
+            .poll_publication(now + Duration::from_secs(1), period)
+            .is_none());
+        assert!(server
+            .poll_publication(now + Duration::from_secs(2), period)
+            .is_some());
+        assert!(server
+            .poll_publication(now, PublishPeriod::disabled())
+            .is_none());
+        assert!(period::Set::unpack_from(&[16]).is_err());
+    }
+
+    #[test]
+    fn test_attention_countdown() {
+        let mut events = Vec::new();
+        {
+            let mut server = AttentionTimerServer::new(|e| events.push(e));
+            server.start(AttentionTimer::new(3));
+            assert_eq!(server.status(), attention::Status(AttentionTimer::new(3)));
+            for _ in 0..5 {
+                server.tick();
+            }
+            assert!(!server.is_on());
+            assert_eq!(server.status(), attention::Status(AttentionTimer::new(0)));
+        }
+        assert_eq!(
+            events,
+            [
+                AttentionEvent::Attention(AttentionTimer::new(2)),
+                AttentionEvent::Attention(AttentionTimer::new(1)),
+                AttentionEvent::Stop,
+            ]
+        );
+    }
+    #[test]
+    fn test_attention_stop() {
+        let mut events = Vec::new();
+        {
+            let mut server = AttentionTimerServer::new(|e| events.push(e));
+            server.handle_set(attention::Set(AttentionTimer::new(10)));
+            server.tick();
+            server.handle_set(attention::Set(AttentionTimer::new(0)));
+            server.tick();
+        }
+        assert_eq!(
+            events,
+            [
+                AttentionEvent::Attention(AttentionTimer::new(9)),
+                AttentionEvent::Stop
+            ]
+        );
+    }
+}
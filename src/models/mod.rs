@@ -2,7 +2,9 @@ use crate::access::Opcode;
 
 pub mod config;
 pub mod generics;
+pub mod health;
 pub mod lighting;
+pub mod remote_provisioning;
 pub mod sensors;
 pub mod state;
 pub mod time;
@@ -0,0 +1,552 @@
+pub mod scan {
+    use crate::access::Opcode;
+    use crate::beacon::{OOBInformation, URIHash};
+    use crate::models::remote_provisioning::{
+        RemoteProvisioningOpcode, RemoteProvisioningStatus, ScanningState,
+    };
+    use crate::models::{MessagePackError, PackableMessage};
+    use crate::uuid::UUID;
+    use core::convert::{TryFrom, TryInto};
+
+    pub const UUID_LEN: usize = 16;
+
+    pub(crate) fn unpack_uuid(buffer: &[u8]) -> UUID {
+        UUID(
+            buffer[..UUID_LEN]
+                .try_into()
+                .expect("UUID is always 16 bytes"),
+        )
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Get;
+    impl PackableMessage for Get {
+        fn opcode() -> Opcode {
+            RemoteProvisioningOpcode::ScanGet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            0
+        }
+
+        fn pack_into(&self, _buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            Ok(())
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.is_empty() {
+                Ok(Get)
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+    /// Starts scanning for unprovisioned devices. With a `uuid`, only that device is scanned for.
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Start {
+        /// Max number of devices to report. `0` lets the server pick.
+        pub scanned_items_limit: u8,
+        /// Scan time in seconds. Can't be `0`.
+        pub timeout: u8,
+        pub uuid: Option<UUID>,
+    }
+    impl PackableMessage for Start {
+        fn opcode() -> Opcode {
+            RemoteProvisioningOpcode::ScanStart.into()
+        }
+
+        fn message_size(&self) -> usize {
+            if self.uuid.is_some() {
+                2 + UUID_LEN
+            } else {
+                2
+            }
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if self.timeout == 0 {
+                return Err(MessagePackError::BadState);
+            }
+            if buffer.len() < self.message_size() {
+                return Err(MessagePackError::SmallBuffer);
+            }
+            buffer[0] = self.scanned_items_limit;
+            buffer[1] = self.timeout;
+            if let Some(uuid) = self.uuid {
+                buffer[2..2 + UUID_LEN].copy_from_slice(&uuid.0[..]);
+            }
+            Ok(())
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            let uuid = match buffer.len() {
+                2 => None,
+                l if l == 2 + UUID_LEN => Some(unpack_uuid(&buffer[2..])),
+                _ => return Err(MessagePackError::BadLength),
+            };
+            if buffer[1] == 0 {
+                return Err(MessagePackError::BadBytes);
+            }
+            Ok(Start {
+                scanned_items_limit: buffer[0],
+                timeout: buffer[1],
+                uuid,
+            })
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Stop;
+    impl PackableMessage for Stop {
+        fn opcode() -> Opcode {
+            RemoteProvisioningOpcode::ScanStop.into()
+        }
+
+        fn message_size(&self) -> usize {
+            0
+        }
+
+        fn pack_into(&self, _buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            Ok(())
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.is_empty() {
+                Ok(Stop)
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Status {
+        pub status: RemoteProvisioningStatus,
+        pub scanning_state: ScanningState,
+        pub scanned_items_limit: u8,
+        pub timeout: u8,
+    }
+    impl PackableMessage for Status {
+        fn opcode() -> Opcode {
+            RemoteProvisioningOpcode::ScanStatus.into()
+        }
+
+        fn message_size(&self) -> usize {
+            4
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < 4 {
+                return Err(MessagePackError::SmallBuffer);
+            }
+            buffer[0] = self.status.into();
+            buffer[1] = self.scanning_state.into();
+            buffer[2] = self.scanned_items_limit;
+            buffer[3] = self.timeout;
+            Ok(())
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() != 4 {
+                return Err(MessagePackError::BadLength);
+            }
+            Ok(Status {
+                status: RemoteProvisioningStatus::try_from(buffer[0])
+                    .map_err(|_| MessagePackError::BadBytes)?,
+                scanning_state: ScanningState::try_from(buffer[1])
+                    .map_err(|_| MessagePackError::BadBytes)?,
+                scanned_items_limit: buffer[2],
+                timeout: buffer[3],
+            })
+        }
+    }
+    /// An unprovisioned device found by the server.
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Report {
+        pub rssi: i8,
+        pub uuid: UUID,
+        pub oob_information: OOBInformation,
+        pub uri_hash: Option<URIHash>,
+    }
+    impl Report {
+        pub const fn min_len() -> usize {
+            1 + UUID_LEN + 2
+        }
+        pub const fn max_len() -> usize {
+            Self::min_len() + 4
+        }
+    }
+    impl PackableMessage for Report {
+        fn opcode() -> Opcode {
+            RemoteProvisioningOpcode::ScanReport.into()
+        }
+
+        fn message_size(&self) -> usize {
+            if self.uri_hash.is_some() {
+                Self::max_len()
+            } else {
+                Self::min_len()
+            }
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.len() < self.message_size() {
+                return Err(MessagePackError::SmallBuffer);
+            }
+            buffer[0] = self.rssi.to_le_bytes()[0];
+            buffer[1..1 + UUID_LEN].copy_from_slice(&self.uuid.0[..]);
+            buffer[1 + UUID_LEN..Self::min_len()]
+                .copy_from_slice(&self.oob_information.0.to_le_bytes());
+            if let Some(uri_hash) = self.uri_hash {
+                buffer[Self::min_len()..Self::max_len()].copy_from_slice(&uri_hash.0.to_le_bytes());
+            }
+            Ok(())
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() != Self::min_len() && buffer.len() != Self::max_len() {
+                return Err(MessagePackError::BadLength);
+            }
+            Ok(Report {
+                rssi: i8::from_le_bytes([buffer[0]]),
+                uuid: unpack_uuid(&buffer[1..]),
+                oob_information: OOBInformation(u16::from_le_bytes([
+                    buffer[1 + UUID_LEN],
+                    buffer[2 + UUID_LEN],
+                ])),
+                uri_hash: if buffer.len() == Self::max_len() {
+                    Some(URIHash(u32::from_le_bytes(
+                        buffer[Self::min_len()..]
+                            .try_into()
+                            .expect("URIHash is always 4 bytes"),
+                    )))
+                } else {
+                    None
+                },
+            })
+        }
+    }
+}
+/// Extended scanning asks the server for the advertising data (for example the Complete Local
+/// Name) of an unprovisioned device or of the server itself.
+pub mod extended_scan {
+    use crate::access::Opcode;
+    use crate::beacon::OOBInformation;
+    use crate::models::remote_provisioning::messages::scan::{unpack_uuid, UUID_LEN};
+    use crate::models::remote_provisioning::{RemoteProvisioningOpcode, RemoteProvisioningStatus};
+    use crate::models::{MessagePackError, PackableMessage};
+    use crate::uuid::UUID;
+    use alloc::vec::Vec;
+    use core::convert::TryFrom;
+
+    /// Max number of AD types in an Extended Scan Start.
+    pub const AD_TYPE_FILTER_MAX: usize = 16;
+    /// Max Extended Scan timeout in seconds.
+    pub const TIMEOUT_MAX: u8 = 5;
+    /// AD types that can't be filtered for. The server always reports the complete version
+    /// (Complete List of Service UUIDs or Complete Local Name) instead.
+    const EXCLUDED_AD_TYPES: [u8; 4] = [0x02, 0x04, 0x06, 0x08];
+
+    /// Unprovisioned device to scan and for how long (in seconds, `1..=TIMEOUT_MAX`).
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Target {
+        pub uuid: UUID,
+        pub timeout: u8,
+    }
+    /// Requests the AD structures of `ad_types` from the unprovisioned device `target` or, if
+    /// `None`, from the Remote Provisioning Server itself.
+    #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Start {
+        pub ad_types: Vec<u8>,
+        pub target: Option<Target>,
+    }
+    impl Start {
+        fn is_valid(&self) -> bool {
+            (1..=AD_TYPE_FILTER_MAX).contains(&self.ad_types.len())
+                && self
+                    .ad_types
+                    .iter()
+                    .all(|ad_type| !EXCLUDED_AD_TYPES.contains(ad_type))
+                && self
+                    .target
+                    .map_or(true, |target| (1..=TIMEOUT_MAX).contains(&target.timeout))
+        }
+    }
+    impl PackableMessage for Start {
+        fn opcode() -> Opcode {
+            RemoteProvisioningOpcode::ExtendedScanStart.into()
+        }
+
+        fn message_size(&self) -> usize {
+            1 + self.ad_types.len() + self.target.map_or(0, |_| UUID_LEN + 1)
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if !self.is_valid() {
+                return Err(MessagePackError::BadState);
+            }
+            if buffer.len() < self.message_size() {
+                return Err(MessagePackError::SmallBuffer);
+            }
+            let count = self.ad_types.len();
+            buffer[0] = u8::try_from(count).expect("at most 16 AD types");
+            buffer[1..1 + count].copy_from_slice(&self.ad_types[..]);
+            if let Some(target) = self.target {
+                buffer[1 + count..1 + count + UUID_LEN].copy_from_slice(&target.uuid.0[..]);
+                buffer[1 + count + UUID_LEN] = target.timeout;
+            }
+            Ok(())
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            let count = usize::from(*buffer.first().ok_or(MessagePackError::BadLength)?);
+            if buffer.len() < 1 + count {
+                return Err(MessagePackError::BadLength);
+            }
+            let rest = &buffer[1 + count..];
+            let target = match rest.len() {
+                0 => None,
+                l if l == UUID_LEN + 1 => Some(Target {
+                    uuid: unpack_uuid(rest),
+                    timeout: rest[UUID_LEN],
+                }),
+                _ => return Err(MessagePackError::BadLength),
+            };
+            let start = Start {
+                ad_types: buffer[1..1 + count].to_vec(),
+                target,
+            };
+            if start.is_valid() {
+                Ok(start)
+            } else {
+                Err(MessagePackError::BadBytes)
+            }
+        }
+    }
+    /// Advertising Data structure (without the length byte).
+    #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct AdStructure {
+        pub ad_type: u8,
+        pub data: Vec<u8>,
+    }
+    impl AdStructure {
+        /// Length byte + AD type + data.
+        pub fn byte_len(&self) -> usize {
+            2 + self.data.len()
+        }
+    }
+    /// Advertising data collected for an Extended Scan Start. `oob_information` and
+    /// `adv_structures` are only included if the scan succeeded.
+    #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Report {
+        pub status: RemoteProvisioningStatus,
+        pub uuid: UUID,
+        pub oob_information: Option<OOBInformation>,
+        pub adv_structures: Vec<AdStructure>,
+    }
+    impl PackableMessage for Report {
+        fn opcode() -> Opcode {
+            RemoteProvisioningOpcode::ExtendedScanReport.into()
+        }
+
+        fn message_size(&self) -> usize {
+            1 + UUID_LEN
+                + self.oob_information.map_or(0, |_| 2)
+                + self
+                    .adv_structures
+                    .iter()
+                    .map(AdStructure::byte_len)
+                    .sum::<usize>()
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            // AD structures can only follow the OOB Information.
+            if self.oob_information.is_none() && !self.adv_structures.is_empty() {
+                return Err(MessagePackError::BadState);
+            }
+            if buffer.len() < self.message_size() {
+                return Err(MessagePackError::SmallBuffer);
+            }
+            buffer[0] = self.status.into();
+            buffer[1..1 + UUID_LEN].copy_from_slice(&self.uuid.0[..]);
+            let mut position = 1 + UUID_LEN;
+            if let Some(oob_information) = self.oob_information {
+                buffer[position..position + 2].copy_from_slice(&oob_information.0.to_le_bytes());
+                position += 2;
+            }
+            for ad_structure in &self.adv_structures {
+                // The length byte counts the AD type and the data.
+                buffer[position] = u8::try_from(ad_structure.data.len() + 1)
+                    .map_err(|_| MessagePackError::BadState)?;
+                buffer[position + 1] = ad_structure.ad_type;
+                buffer[position + 2..position + ad_structure.byte_len()]
+                    .copy_from_slice(&ad_structure.data[..]);
+                position += ad_structure.byte_len();
+            }
+            Ok(())
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() < 1 + UUID_LEN || buffer.len() == 1 + UUID_LEN + 1 {
+                return Err(MessagePackError::BadLength);
+            }
+            let status = RemoteProvisioningStatus::try_from(buffer[0])
+                .map_err(|_| MessagePackError::BadBytes)?;
+            let uuid = unpack_uuid(&buffer[1..]);
+            let mut rest = &buffer[1 + UUID_LEN..];
+            let oob_information = if rest.is_empty() {
+                None
+            } else {
+                let oob = OOBInformation(u16::from_le_bytes([rest[0], rest[1]]));
+                rest = &rest[2..];
+                Some(oob)
+            };
+            let mut adv_structures = Vec::new();
+            while let Some(&len) = rest.first() {
+                let len = usize::from(len);
+                if len == 0 || rest.len() < 1 + len {
+                    return Err(MessagePackError::BadBytes);
+                }
+                adv_structures.push(AdStructure {
+                    ad_type: rest[1],
+                    data: rest[2..1 + len].to_vec(),
+                });
+                rest = &rest[1 + len..];
+            }
+            Ok(Report {
+                status,
+                uuid,
+                oob_information,
+                adv_structures,
+            })
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beacon::OOBInformation;
+    use crate::models::remote_provisioning::RemoteProvisioningStatus;
+    use crate::models::PackableMessage;
+    use crate::uuid::UUID;
+    use alloc::vec::Vec;
+
+    const COMPLETE_LOCAL_NAME: u8 = 0x09;
+    const UUID_16: u8 = 0x03;
+
+    fn round_trip<M: PackableMessage + PartialEq + core::fmt::Debug>(msg: &M) -> Vec<u8> {
+        let mut buf = alloc::vec![0_u8; msg.message_size()];
+        msg.pack_into(&mut buf[..])
+            .ok()
+            .expect("buffer is big enough");
+        match M::unpack_from(&buf[..]) {
+            Ok(unpacked) => assert_eq!(&unpacked, msg),
+            Err(_) => panic!("packed message should unpack"),
+        }
+        buf
+    }
+    #[test]
+    fn test_extended_scan_start() {
+        let start = extended_scan::Start {
+            ad_types: alloc::vec![COMPLETE_LOCAL_NAME, UUID_16],
+            target: Some(extended_scan::Target {
+                uuid: UUID([0xAB; 16]),
+                timeout: 3,
+            }),
+        };
+        let buf = round_trip(&start);
+        assert_eq!(buf.len(), 1 + 2 + 16 + 1);
+        assert_eq!(&buf[..3], &[0x02, COMPLETE_LOCAL_NAME, UUID_16]);
+        assert_eq!(buf[19], 3);
+
+        // Without a target, the server reports its own advertising data.
+        let start = extended_scan::Start {
+            ad_types: alloc::vec![COMPLETE_LOCAL_NAME],
+            target: None,
+        };
+        assert_eq!(round_trip(&start), alloc::vec![0x01, COMPLETE_LOCAL_NAME]);
+    }
+    #[test]
+    fn test_extended_scan_start_invalid() {
+        let mut buf = [0_u8; 32];
+        let no_ad_types = extended_scan::Start {
+            ad_types: Vec::new(),
+            target: None,
+        };
+        assert!(no_ad_types.pack_into(&mut buf[..]).is_err());
+        let shortened_name = extended_scan::Start {
+            ad_types: alloc::vec![0x08],
+            target: None,
+        };
+        assert!(shortened_name.pack_into(&mut buf[..]).is_err());
+        let long_timeout = extended_scan::Start {
+            ad_types: alloc::vec![COMPLETE_LOCAL_NAME],
+            target: Some(extended_scan::Target {
+                uuid: UUID::default(),
+                timeout: extended_scan::TIMEOUT_MAX + 1,
+            }),
+        };
+        assert!(long_timeout.pack_into(&mut buf[..]).is_err());
+        // The count says 2 AD types but only 1 follows.
+        assert!(extended_scan::Start::unpack_from(&[0x02, COMPLETE_LOCAL_NAME]).is_err());
+    }
+    #[test]
+    fn test_extended_scan_report() {
+        let report = extended_scan::Report {
+            status: RemoteProvisioningStatus::Success,
+            uuid: UUID([0x11; 16]),
+            oob_information: Some(OOBInformation(0x0102)),
+            adv_structures: alloc::vec![
+                extended_scan::AdStructure {
+                    ad_type: COMPLETE_LOCAL_NAME,
+                    data: b"lamp".to_vec(),
+                },
+                extended_scan::AdStructure {
+                    ad_type: UUID_16,
+                    data: alloc::vec![0x27, 0x18],
+                },
+            ],
+        };
+        let buf = round_trip(&report);
+        assert_eq!(
+            &buf[17..],
+            &[
+                0x02,
+                0x01,
+                0x05,
+                COMPLETE_LOCAL_NAME,
+                b'l',
+                b'a',
+                b'm',
+                b'p',
+                0x03,
+                UUID_16,
+                0x27,
+                0x18
+            ][..]
+        );
+
+        // Failed scans only carry the status and UUID.
+        let failed = extended_scan::Report {
+            status: RemoteProvisioningStatus::LimitedResources,
+            uuid: UUID([0x11; 16]),
+            oob_information: None,
+            adv_structures: Vec::new(),
+        };
+        assert_eq!(round_trip(&failed).len(), 17);
+
+        // An AD structure length running past the end of the message.
+        let mut truncated = buf.clone();
+        truncated.truncate(buf.len() - 1);
+        assert!(extended_scan::Report::unpack_from(&truncated[..]).is_err());
+    }
+    #[test]
+    fn test_scan_report() {
+        let report = scan::Report {
+            rssi: -60,
+            uuid: UUID([0x22; 16]),
+            oob_information: OOBInformation(0x0001),
+            uri_hash: None,
+        };
+        let buf = round_trip(&report);
+        assert_eq!(buf[0], 0xC4);
+        assert_eq!(buf.len(), scan::Report::min_len());
+    }
+}
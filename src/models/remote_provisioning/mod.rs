@@ -0,0 +1,128 @@
+//! Remote Provisioning Models (Remote Provisioning Server and Client). Lets a provisioner find
+//! (and later provision) unprovisioned devices that are only in radio range of another node.
+//! Only the scanning messages are supported so far.
+use crate::access::SigOpcode::DoubleOctet;
+use crate::access::{Opcode, OpcodeConversationError};
+use core::convert::TryFrom;
+
+pub mod messages;
+
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum RemoteProvisioningOpcode {
+    ScanCapabilitiesGet,
+    ScanCapabilitiesStatus,
+    ScanGet,
+    ScanStart,
+    ScanStop,
+    ScanStatus,
+    ScanReport,
+
+    ExtendedScanStart,
+    ExtendedScanReport,
+}
+impl TryFrom<Opcode> for RemoteProvisioningOpcode {
+    type Error = OpcodeConversationError;
+
+    fn try_from(opcode: Opcode) -> Result<Self, Self::Error> {
+        match opcode {
+            Opcode::SIG(DoubleOctet(d)) => match d {
+                0x804F => Ok(RemoteProvisioningOpcode::ScanCapabilitiesGet),
+                0x8050 => Ok(RemoteProvisioningOpcode::ScanCapabilitiesStatus),
+                0x8051 => Ok(RemoteProvisioningOpcode::ScanGet),
+                0x8052 => Ok(RemoteProvisioningOpcode::ScanStart),
+                0x8053 => Ok(RemoteProvisioningOpcode::ScanStop),
+                0x8054 => Ok(RemoteProvisioningOpcode::ScanStatus),
+                0x8055 => Ok(RemoteProvisioningOpcode::ScanReport),
+                0x8056 => Ok(RemoteProvisioningOpcode::ExtendedScanStart),
+                0x8057 => Ok(RemoteProvisioningOpcode::ExtendedScanReport),
+                _ => Err(OpcodeConversationError(())),
+            },
+            _ => Err(OpcodeConversationError(())),
+        }
+    }
+}
+impl From<RemoteProvisioningOpcode> for Opcode {
+    fn from(opcode: RemoteProvisioningOpcode) -> Self {
+        match opcode {
+            RemoteProvisioningOpcode::ScanCapabilitiesGet => DoubleOctet(0x804F).into(),
+            RemoteProvisioningOpcode::ScanCapabilitiesStatus => DoubleOctet(0x8050).into(),
+            RemoteProvisioningOpcode::ScanGet => DoubleOctet(0x8051).into(),
+            RemoteProvisioningOpcode::ScanStart => DoubleOctet(0x8052).into(),
+            RemoteProvisioningOpcode::ScanStop => DoubleOctet(0x8053).into(),
+            RemoteProvisioningOpcode::ScanStatus => DoubleOctet(0x8054).into(),
+            RemoteProvisioningOpcode::ScanReport => DoubleOctet(0x8055).into(),
+            RemoteProvisioningOpcode::ExtendedScanStart => DoubleOctet(0x8056).into(),
+            RemoteProvisioningOpcode::ExtendedScanReport => DoubleOctet(0x8057).into(),
+        }
+    }
+}
+/// Status codes used by the Remote Provisioning Server.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum RemoteProvisioningStatus {
+    Success = 0x00,
+    ScanningCannotStart = 0x01,
+    InvalidState = 0x02,
+    LimitedResources = 0x03,
+    LinkCannotOpen = 0x04,
+    LinkOpenFailed = 0x05,
+    LinkClosedByDevice = 0x06,
+    LinkClosedByServer = 0x07,
+    LinkClosedByClient = 0x08,
+    LinkClosedAsCannotReceivePDU = 0x09,
+    LinkClosedAsCannotSendPDU = 0x0A,
+    LinkClosedAsCannotDeliverPDUReport = 0x0B,
+    LinkClosedAsCannotDeliverPDUOutboundReport = 0x0C,
+}
+impl From<RemoteProvisioningStatus> for u8 {
+    fn from(status: RemoteProvisioningStatus) -> Self {
+        status as u8
+    }
+}
+impl TryFrom<u8> for RemoteProvisioningStatus {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(RemoteProvisioningStatus::Success),
+            0x01 => Ok(RemoteProvisioningStatus::ScanningCannotStart),
+            0x02 => Ok(RemoteProvisioningStatus::InvalidState),
+            0x03 => Ok(RemoteProvisioningStatus::LimitedResources),
+            0x04 => Ok(RemoteProvisioningStatus::LinkCannotOpen),
+            0x05 => Ok(RemoteProvisioningStatus::LinkOpenFailed),
+            0x06 => Ok(RemoteProvisioningStatus::LinkClosedByDevice),
+            0x07 => Ok(RemoteProvisioningStatus::LinkClosedByServer),
+            0x08 => Ok(RemoteProvisioningStatus::LinkClosedByClient),
+            0x09 => Ok(RemoteProvisioningStatus::LinkClosedAsCannotReceivePDU),
+            0x0A => Ok(RemoteProvisioningStatus::LinkClosedAsCannotSendPDU),
+            0x0B => Ok(RemoteProvisioningStatus::LinkClosedAsCannotDeliverPDUReport),
+            0x0C => Ok(RemoteProvisioningStatus::LinkClosedAsCannotDeliverPDUOutboundReport),
+            _ => Err(()),
+        }
+    }
+}
+/// Remote Provisioning Scan state of the server.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum ScanningState {
+    Idle = 0x00,
+    /// Scanning for any unprovisioned device.
+    MultipleDevices = 0x01,
+    /// Scanning for the unprovisioned device with the given UUID.
+    SingleDevice = 0x02,
+}
+impl From<ScanningState> for u8 {
+    fn from(state: ScanningState) -> Self {
+        state as u8
+    }
+}
+impl TryFrom<u8> for ScanningState {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(ScanningState::Idle),
+            0x01 => Ok(ScanningState::MultipleDevices),
+            0x02 => Ok(ScanningState::SingleDevice),
+            _ => Err(()),
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! Time Client model. Reads (and optionally sets) the time of a Time Server.
+use crate::models::time::messages::{role, time};
+use crate::models::time::{TimeRole, TimeState};
+use crate::timestamp::{Timestamp, TimestampTrait};
+
+/// Time Client. Creates the Time Get/Set messages and remembers the last Time Status and Time
+/// Role Status it received.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TimeClient {
+    last_time: Option<(TimeState, Timestamp)>,
+    last_role: Option<TimeRole>,
+}
+impl TimeClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn get(&self) -> time::Get {
+        time::Get
+    }
+    pub fn set(&self, time: TimeState) -> time::Set {
+        time::Set(time)
+    }
+    pub fn role_get(&self) -> role::Get {
+        role::Get
+    }
+    pub fn role_set(&self, role: TimeRole) -> role::Set {
+        role::Set(role)
+    }
+    /// Records a Time Status from a Time Server.
+    pub fn handle_status(&mut self, status: time::Status) {
+        self.last_time = Some((status.0, Timestamp::now()));
+    }
+    /// Records a Time Role Status from a Time Server.
+    pub fn handle_role_status(&mut self, status: role::Status) {
+        self.last_role = Some(status.0);
+    }
+    /// Returns the last `TimeState` received and when it was received.
+    pub fn last_time(&self) -> Option<(TimeState, Timestamp)> {
+        self.last_time
+    }
+    pub fn last_role(&self) -> Option<TimeRole> {
+        self.last_role
+    }
+}
@@ -0,0 +1,291 @@
+pub mod time {
+    use crate::access::Opcode;
+    use crate::models::time::{
+        Subsecond, TAISeconds, TAIUTCDelta, TimeOpcode, TimeState, TimeZoneOffset, Uncertainty,
+        TAI_SECONDS_LEN,
+    };
+    use crate::models::{MessagePackError, PackableMessage};
+    use core::convert::TryInto;
+
+    /// TAI Seconds (5) + Subsecond (1) + Uncertainty (1) + Time Authority and TAI-UTC Delta (2) +
+    /// Time Zone Offset (1).
+    pub const TIME_STATE_LEN: usize = TAI_SECONDS_LEN + 1 + 1 + 2 + 1;
+
+    fn pack_time_state(state: &TimeState, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+        if buffer.len() < TIME_STATE_LEN {
+            return Err(MessagePackError::SmallBuffer);
+        }
+        buffer[..TAI_SECONDS_LEN].copy_from_slice(&state.tai_seconds.to_bytes_le());
+        buffer[5] = state.subsecond.0;
+        buffer[6] = state.uncertainty.0;
+        let authority_delta = u16::from(state.time_authority) | (state.tai_utc_delta.raw() << 1);
+        buffer[7..9].copy_from_slice(&authority_delta.to_le_bytes());
+        buffer[9] = state.time_zone_offset.0;
+        Ok(())
+    }
+    fn unpack_tai_seconds(buffer: &[u8]) -> TAISeconds {
+        TAISeconds::from_bytes_le(
+            buffer[..TAI_SECONDS_LEN]
+                .try_into()
+                .expect("TAI seconds is always 5 bytes"),
+        )
+    }
+    fn unpack_time_state(buffer: &[u8]) -> Result<TimeState, MessagePackError> {
+        if buffer.len() != TIME_STATE_LEN {
+            return Err(MessagePackError::BadLength);
+        }
+        let authority_delta = u16::from_le_bytes([buffer[7], buffer[8]]);
+        Ok(TimeState {
+            tai_seconds: unpack_tai_seconds(buffer),
+            subsecond: Subsecond(buffer[5]),
+            uncertainty: Uncertainty(buffer[6]),
+            time_authority: authority_delta & 1 != 0,
+            tai_utc_delta: TAIUTCDelta::from_raw(authority_delta >> 1)
+                .expect("15 bit delta always fits"),
+            time_zone_offset: TimeZoneOffset(buffer[9]),
+        })
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Get;
+    impl PackableMessage for Get {
+        fn opcode() -> Opcode {
+            TimeOpcode::TimeGet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            0
+        }
+
+        fn pack_into(&self, _buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            Ok(())
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.is_empty() {
+                Ok(Get)
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Set(pub TimeState);
+    impl PackableMessage for Set {
+        fn opcode() -> Opcode {
+            TimeOpcode::TimeSet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            TIME_STATE_LEN
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            pack_time_state(&self.0, buffer)
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            Ok(Set(unpack_time_state(buffer)?))
+        }
+    }
+    /// Time Status. If the TAI Seconds are unknown (`0`), only the TAI Seconds are sent.
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Status(pub TimeState);
+    impl PackableMessage for Status {
+        fn opcode() -> Opcode {
+            TimeOpcode::TimeStatus.into()
+        }
+
+        fn message_size(&self) -> usize {
+            if self.0.is_unknown() {
+                TAI_SECONDS_LEN
+            } else {
+                TIME_STATE_LEN
+            }
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if self.0.is_unknown() {
+                if buffer.len() < TAI_SECONDS_LEN {
+                    return Err(MessagePackError::SmallBuffer);
+                }
+                buffer[..TAI_SECONDS_LEN].copy_from_slice(&[0_u8; TAI_SECONDS_LEN]);
+                Ok(())
+            } else {
+                pack_time_state(&self.0, buffer)
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() == TAI_SECONDS_LEN {
+                let tai_seconds = unpack_tai_seconds(buffer);
+                if tai_seconds.is_unknown() {
+                    Ok(Status(TimeState::default()))
+                } else {
+                    Err(MessagePackError::BadBytes)
+                }
+            } else {
+                let state = unpack_time_state(buffer)?;
+                if state.is_unknown() {
+                    Err(MessagePackError::BadLength)
+                } else {
+                    Ok(Status(state))
+                }
+            }
+        }
+    }
+}
+pub mod role {
+    use crate::access::Opcode;
+    use crate::models::time::{TimeOpcode, TimeRole};
+    use crate::models::{MessagePackError, PackableMessage};
+    use core::convert::TryFrom;
+
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Get;
+    impl PackableMessage for Get {
+        fn opcode() -> Opcode {
+            TimeOpcode::TimeRoleGet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            0
+        }
+
+        fn pack_into(&self, _buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            Ok(())
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.is_empty() {
+                Ok(Get)
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Set(pub TimeRole);
+    impl PackableMessage for Set {
+        fn opcode() -> Opcode {
+            TimeOpcode::TimeRoleSet.into()
+        }
+
+        fn message_size(&self) -> usize {
+            1
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.is_empty() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                buffer[0] = self.0.into();
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() == 1 {
+                Ok(Set(
+                    TimeRole::try_from(buffer[0]).map_err(|_| MessagePackError::BadBytes)?
+                ))
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+    #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+    pub struct Status(pub TimeRole);
+    impl PackableMessage for Status {
+        fn opcode() -> Opcode {
+            TimeOpcode::TimeRoleStatus.into()
+        }
+
+        fn message_size(&self) -> usize {
+            1
+        }
+
+        fn pack_into(&self, buffer: &mut [u8]) -> Result<(), MessagePackError> {
+            if buffer.is_empty() {
+                Err(MessagePackError::SmallBuffer)
+            } else {
+                buffer[0] = self.0.into();
+                Ok(())
+            }
+        }
+
+        fn unpack_from(buffer: &[u8]) -> Result<Self, MessagePackError> {
+            if buffer.len() == 1 {
+                Ok(Status(
+                    TimeRole::try_from(buffer[0]).map_err(|_| MessagePackError::BadBytes)?,
+                ))
+            } else {
+                Err(MessagePackError::BadLength)
+            }
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::time::{
+        Subsecond, TAISeconds, TAIUTCDelta, TimeRole, TimeState, TimeZoneOffset, Uncertainty,
+    };
+    use crate::models::PackableMessage;
+
+    fn time_state() -> TimeState {
+        TimeState {
+            tai_seconds: TAISeconds::new(0x12_3456_789A),
+            subsecond: Subsecond(0x80),
+            uncertainty: Uncertainty(0x05),
+            time_authority: true,
+            tai_utc_delta: TAIUTCDelta::from_seconds(37).expect("valid delta"),
+            time_zone_offset: TimeZoneOffset::from_quarter_hours(-20).expect("valid offset"),
+        }
+    }
+    #[test]
+    fn test_time_status_pack() {
+        let status = time::Status(time_state());
+        let mut buf = [0_u8; time::TIME_STATE_LEN];
+        assert_eq!(status.message_size(), time::TIME_STATE_LEN);
+        status
+            .pack_into(&mut buf[..])
+            .ok()
+            .expect("buffer is big enough");
+        // Delta 37 is 292 (0x124) raw. Shifted left with the authority bit set it's 0x249.
+        assert_eq!(
+            buf,
+            [0x9A, 0x78, 0x56, 0x34, 0x12, 0x80, 0x05, 0x49, 0x02, 0x2C]
+        );
+        match time::Status::unpack_from(&buf[..]) {
+            Ok(unpacked) => assert_eq!(unpacked, status),
+            Err(_) => panic!("status should unpack"),
+        }
+    }
+    #[test]
+    fn test_time_status_unknown() {
+        let status = time::Status(TimeState::default());
+        assert_eq!(status.message_size(), 5);
+        let mut buf = [0xFF_u8; 5];
+        status
+            .pack_into(&mut buf[..])
+            .ok()
+            .expect("buffer is big enough");
+        assert_eq!(buf, [0_u8; 5]);
+        match time::Status::unpack_from(&buf[..]) {
+            Ok(unpacked) => assert!(unpacked.0.is_unknown()),
+            Err(_) => panic!("unknown status should unpack"),
+        }
+        // Known TAI seconds have to come with the rest of the time state.
+        assert!(time::Status::unpack_from(&[1, 0, 0, 0, 0]).is_err());
+    }
+    #[test]
+    fn test_time_role() {
+        let mut buf = [0_u8; 1];
+        role::Set(TimeRole::TimeRelay)
+            .pack_into(&mut buf[..])
+            .ok()
+            .expect("buffer is big enough");
+        assert_eq!(buf, [0x02]);
+        assert!(role::Status::unpack_from(&[0x04]).is_err());
+    }
+}
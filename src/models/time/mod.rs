@@ -1 +1,247 @@
+//! Time Models (Time Server and Time Client). Distributes TAI time across the mesh so nodes can
+//! coordinate time based actions.
+use crate::access::SigOpcode::{DoubleOctet, SingleOctet};
+use crate::access::{Opcode, OpcodeConversationError};
+use core::convert::TryFrom;
 
+pub mod client;
+pub mod messages;
+pub mod server;
+
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum TimeOpcode {
+    TimeGet,
+    TimeSet,
+    TimeStatus,
+
+    TimeRoleGet,
+    TimeRoleSet,
+    TimeRoleStatus,
+
+    TimeZoneGet,
+    TimeZoneSet,
+    TimeZoneStatus,
+
+    TAIUTCDeltaGet,
+    TAIUTCDeltaSet,
+    TAIUTCDeltaStatus,
+}
+impl TryFrom<Opcode> for TimeOpcode {
+    type Error = OpcodeConversationError;
+
+    fn try_from(opcode: Opcode) -> Result<Self, Self::Error> {
+        match opcode {
+            Opcode::SIG(SingleOctet(0x5C)) => Ok(TimeOpcode::TimeSet),
+            Opcode::SIG(SingleOctet(0x5D)) => Ok(TimeOpcode::TimeStatus),
+            Opcode::SIG(DoubleOctet(d)) => match d {
+                0x8237 => Ok(TimeOpcode::TimeGet),
+                0x8238 => Ok(TimeOpcode::TimeRoleGet),
+                0x8239 => Ok(TimeOpcode::TimeRoleSet),
+                0x823A => Ok(TimeOpcode::TimeRoleStatus),
+                0x823B => Ok(TimeOpcode::TimeZoneGet),
+                0x823C => Ok(TimeOpcode::TimeZoneSet),
+                0x823D => Ok(TimeOpcode::TimeZoneStatus),
+                0x823E => Ok(TimeOpcode::TAIUTCDeltaGet),
+                0x823F => Ok(TimeOpcode::TAIUTCDeltaSet),
+                0x8240 => Ok(TimeOpcode::TAIUTCDeltaStatus),
+                _ => Err(OpcodeConversationError(())),
+            },
+            _ => Err(OpcodeConversationError(())),
+        }
+    }
+}
+impl From<TimeOpcode> for Opcode {
+    fn from(opcode: TimeOpcode) -> Self {
+        match opcode {
+            TimeOpcode::TimeGet => DoubleOctet(0x8237).into(),
+            TimeOpcode::TimeSet => SingleOctet(0x5C).into(),
+            TimeOpcode::TimeStatus => SingleOctet(0x5D).into(),
+            TimeOpcode::TimeRoleGet => DoubleOctet(0x8238).into(),
+            TimeOpcode::TimeRoleSet => DoubleOctet(0x8239).into(),
+            TimeOpcode::TimeRoleStatus => DoubleOctet(0x823A).into(),
+            TimeOpcode::TimeZoneGet => DoubleOctet(0x823B).into(),
+            TimeOpcode::TimeZoneSet => DoubleOctet(0x823C).into(),
+            TimeOpcode::TimeZoneStatus => DoubleOctet(0x823D).into(),
+            TimeOpcode::TAIUTCDeltaGet => DoubleOctet(0x823E).into(),
+            TimeOpcode::TAIUTCDeltaSet => DoubleOctet(0x823F).into(),
+            TimeOpcode::TAIUTCDeltaStatus => DoubleOctet(0x8240).into(),
+        }
+    }
+}
+/// 40-bit count of seconds since the TAI epoch (2000-01-01T00:00:00 TAI). `0` means the time is
+/// unknown.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash, Default)]
+pub struct TAISeconds(u64);
+pub const TAI_SECONDS_MAX: u64 = (1_u64 << 40) - 1;
+pub const TAI_SECONDS_LEN: usize = 5;
+impl TAISeconds {
+    /// # Panics
+    /// Panics if `seconds > TAI_SECONDS_MAX`.
+    pub fn new(seconds: u64) -> TAISeconds {
+        assert!(seconds <= TAI_SECONDS_MAX, "TAI seconds is only 40 bits");
+        TAISeconds(seconds)
+    }
+    pub fn new_masked(seconds: u64) -> TAISeconds {
+        TAISeconds(seconds & TAI_SECONDS_MAX)
+    }
+    pub fn value(self) -> u64 {
+        self.0
+    }
+    pub fn is_unknown(self) -> bool {
+        self.0 == 0
+    }
+    pub fn to_bytes_le(self) -> [u8; TAI_SECONDS_LEN] {
+        let b = self.0.to_le_bytes();
+        [b[0], b[1], b[2], b[3], b[4]]
+    }
+    pub fn from_bytes_le(bytes: [u8; TAI_SECONDS_LEN]) -> TAISeconds {
+        TAISeconds(u64::from_le_bytes([
+            bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], 0, 0, 0,
+        ]))
+    }
+}
+/// Fraction of a TAI second in 1/256th steps.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash, Default)]
+pub struct Subsecond(pub u8);
+/// Accumulated time uncertainty in 10 millisecond steps.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash, Default)]
+pub struct Uncertainty(pub u8);
+impl Uncertainty {
+    pub fn milliseconds(self) -> u32 {
+        u32::from(self.0) * 10
+    }
+}
+/// 15-bit difference between TAI and UTC. Encoded with an offset of 255 so it can hold
+/// `-255..=32512` seconds.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct TAIUTCDelta(u16);
+pub const TAI_UTC_DELTA_OFFSET: i16 = 255;
+pub const TAI_UTC_DELTA_MAX: u16 = 0x7FFF;
+impl TAIUTCDelta {
+    pub fn from_raw(raw: u16) -> Option<TAIUTCDelta> {
+        if raw <= TAI_UTC_DELTA_MAX {
+            Some(TAIUTCDelta(raw))
+        } else {
+            None
+        }
+    }
+    pub fn from_seconds(seconds: i16) -> Option<TAIUTCDelta> {
+        seconds
+            .checked_add(TAI_UTC_DELTA_OFFSET)
+            .and_then(|raw| u16::try_from(raw).ok())
+            .and_then(TAIUTCDelta::from_raw)
+    }
+    pub fn raw(self) -> u16 {
+        self.0
+    }
+    pub fn seconds(self) -> i16 {
+        // raw is only 15 bits so it always fits in a i16.
+        self.0 as i16 - TAI_UTC_DELTA_OFFSET
+    }
+}
+impl Default for TAIUTCDelta {
+    fn default() -> Self {
+        TAIUTCDelta(TAI_UTC_DELTA_OFFSET as u16)
+    }
+}
+/// Local time zone offset in 15 minute steps. Encoded with an offset of 64 so it can hold
+/// `-64..=191` steps.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct TimeZoneOffset(pub u8);
+pub const TIME_ZONE_OFFSET_OFFSET: i16 = 64;
+impl TimeZoneOffset {
+    /// Returns `None` if `quarter_hours` is outside `-64..=191`.
+    pub fn from_quarter_hours(quarter_hours: i16) -> Option<TimeZoneOffset> {
+        quarter_hours
+            .checked_add(TIME_ZONE_OFFSET_OFFSET)
+            .and_then(|raw| u8::try_from(raw).ok())
+            .map(TimeZoneOffset)
+    }
+    pub fn quarter_hours(self) -> i16 {
+        i16::from(self.0) - TIME_ZONE_OFFSET_OFFSET
+    }
+    pub fn minutes(self) -> i16 {
+        self.quarter_hours() * 15
+    }
+}
+impl Default for TimeZoneOffset {
+    fn default() -> Self {
+        TimeZoneOffset(TIME_ZONE_OFFSET_OFFSET as u8)
+    }
+}
+/// Time State carried by Time Set and Time Status messages.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash, Default)]
+pub struct TimeState {
+    pub tai_seconds: TAISeconds,
+    pub subsecond: Subsecond,
+    pub uncertainty: Uncertainty,
+    /// If the time comes from a reliable source (GPS, NTP, etc).
+    pub time_authority: bool,
+    pub tai_utc_delta: TAIUTCDelta,
+    pub time_zone_offset: TimeZoneOffset,
+}
+impl TimeState {
+    pub fn is_unknown(&self) -> bool {
+        self.tai_seconds.is_unknown()
+    }
+}
+/// Time Role of a Time Server.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum TimeRole {
+    /// The element doesn't participate in propagating time.
+    None = 0x00,
+    /// The element publishes Time Status and has a reliable time source.
+    TimeAuthority = 0x01,
+    /// The element processes and republishes Time Status messages.
+    TimeRelay = 0x02,
+    /// The element only processes Time Status messages.
+    TimeClient = 0x03,
+}
+impl Default for TimeRole {
+    fn default() -> Self {
+        TimeRole::None
+    }
+}
+impl From<TimeRole> for u8 {
+    fn from(role: TimeRole) -> Self {
+        role as u8
+    }
+}
+impl TryFrom<u8> for TimeRole {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(TimeRole::None),
+            0x01 => Ok(TimeRole::TimeAuthority),
+            0x02 => Ok(TimeRole::TimeRelay),
+            0x03 => Ok(TimeRole::TimeClient),
+            _ => Err(()),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_zone_offset_bounds() {
+        assert_eq!(
+            TimeZoneOffset::from_quarter_hours(-64),
+            Some(TimeZoneOffset(0))
+        );
+        assert_eq!(
+            TimeZoneOffset::from_quarter_hours(191),
+            Some(TimeZoneOffset(255))
+        );
+        assert_eq!(
+            TimeZoneOffset::from_quarter_hours(191).map(TimeZoneOffset::minutes),
+            Some(2865)
+        );
+        assert_eq!(TimeZoneOffset::from_quarter_hours(-65), None);
+        assert_eq!(TimeZoneOffset::from_quarter_hours(192), None);
+        // Adding the offset would overflow an i16.
+        assert_eq!(TimeZoneOffset::from_quarter_hours(i16::MAX), None);
+        assert_eq!(TimeZoneOffset::from_quarter_hours(i16::MIN), None);
+    }
+}
@@ -0,0 +1,137 @@
+//! Time Server model. Keeps a local clock synchronized to the TAI time it was last given.
+use crate::models::time::messages::{role, time};
+use crate::models::time::{Subsecond, TAISeconds, TimeRole, TimeState};
+use crate::timestamp::{Timestamp, TimestampTrait};
+use core::convert::TryFrom;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Time Server for an element. The server remembers the `TimeState` it was last set to and
+/// when. Reading the time adds the local time elapsed since then so the clock keeps running
+/// between Time Sets.
+#[derive(Copy, Clone, Debug)]
+pub struct TimeServer {
+    time: TimeState,
+    set_at: Option<Timestamp>,
+    role: TimeRole,
+}
+impl TimeServer {
+    pub fn new(role: TimeRole) -> Self {
+        Self {
+            time: TimeState::default(),
+            set_at: None,
+            role,
+        }
+    }
+    pub fn role(&self) -> TimeRole {
+        self.role
+    }
+    /// Sets the clock to `time` as of `now`.
+    pub fn set_time_at(&mut self, time: TimeState, now: Timestamp) {
+        self.time = time;
+        self.set_at = if time.is_unknown() { None } else { Some(now) };
+    }
+    pub fn set_time(&mut self, time: TimeState) {
+        self.set_time_at(time, Timestamp::now())
+    }
+    /// Returns the current `TimeState` as of `now`. If the time was never set, the TAI seconds
+    /// will be unknown (`0`).
+    pub fn time_at(&self, now: Timestamp) -> TimeState {
+        let set_at = match self.set_at {
+            Some(set_at) => set_at,
+            None => return self.time,
+        };
+        let elapsed = now.since(set_at).unwrap_or_default();
+        // Work in 1/256th of a second so the subsecond carries into the seconds.
+        let start = (self.time.tai_seconds.value() << 8) | u64::from(self.time.subsecond.0);
+        let elapsed_subseconds =
+            (elapsed.as_secs() << 8) + u64::from(elapsed.subsec_nanos()) * 256 / NANOS_PER_SEC;
+        let current = start + elapsed_subseconds;
+        TimeState {
+            tai_seconds: TAISeconds::new_masked(current >> 8),
+            subsecond: Subsecond(u8::try_from(current & 0xFF).expect("masked to 8 bits")),
+            ..self.time
+        }
+    }
+    pub fn time(&self) -> TimeState {
+        self.time_at(Timestamp::now())
+    }
+    /// Returns the Time Status reflecting the current clock.
+    pub fn status(&self) -> time::Status {
+        time::Status(self.time())
+    }
+    /// Handles a Time Get and returns the Status to respond with.
+    pub fn handle_get(&self, _get: time::Get) -> time::Status {
+        self.status()
+    }
+    /// Handles a Time Set and returns the Status to respond with.
+    pub fn handle_set(&mut self, set: time::Set) -> time::Status {
+        self.set_time(set.0);
+        self.status()
+    }
+    /// Handles a Time Status published by another node. Only Time Relays and Time Clients
+    /// synchronize to it and only if the time is known.
+    pub fn handle_status(&mut self, status: time::Status) {
+        match self.role {
+            TimeRole::TimeRelay | TimeRole::TimeClient if !status.0.is_unknown() => {
+                self.set_time(status.0)
+            }
+            _ => (),
+        }
+    }
+    /// Handles a Time Role Get and returns the Status to respond with.
+    pub fn handle_role_get(&self, _get: role::Get) -> role::Status {
+        role::Status(self.role)
+    }
+    /// Handles a Time Role Set and returns the Status to respond with.
+    pub fn handle_role_set(&mut self, set: role::Set) -> role::Status {
+        self.role = set.0;
+        role::Status(self.role)
+    }
+}
+impl Default for TimeServer {
+    fn default() -> Self {
+        Self::new(TimeRole::default())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::time::{TAIUTCDelta, TimeZoneOffset, Uncertainty};
+    use core::time::Duration;
+
+    fn time_state() -> TimeState {
+        TimeState {
+            tai_seconds: TAISeconds::new(1000),
+            subsecond: Subsecond(0xC0),
+            uncertainty: Uncertainty(1),
+            time_authority: true,
+            tai_utc_delta: TAIUTCDelta::from_seconds(37).expect("valid delta"),
+            time_zone_offset: TimeZoneOffset::default(),
+        }
+    }
+    #[test]
+    fn test_clock_runs() {
+        let mut server = TimeServer::new(TimeRole::TimeAuthority);
+        assert!(server.time().is_unknown());
+        let now = Timestamp::now();
+        server.set_time_at(time_state(), now);
+        assert_eq!(server.time_at(now), time_state());
+        // 0.75 + 1.5 seconds carries over into the seconds.
+        let later = server.time_at(now + Duration::from_millis(1500));
+        assert_eq!(later.tai_seconds, TAISeconds::new(1002));
+        assert_eq!(later.subsecond, Subsecond(0x40));
+        assert_eq!(later.tai_utc_delta, time_state().tai_utc_delta);
+    }
+    #[test]
+    fn test_status_sync() {
+        let mut authority = TimeServer::new(TimeRole::TimeAuthority);
+        authority.handle_status(time::Status(time_state()));
+        assert!(authority.time().is_unknown());
+
+        let mut client = TimeServer::new(TimeRole::None);
+        client.handle_role_set(role::Set(TimeRole::TimeClient));
+        client.handle_status(time::Status(time_state()));
+        assert!(client.time().tai_seconds >= time_state().tai_seconds);
+    }
+}
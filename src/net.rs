@@ -3,13 +3,14 @@
 
 use crate::address::{Address, UnicastAddress, ADDRESS_LEN};
 use crate::bytes::ToFromBytesEndian;
+use crate::control::ControlOpcode;
 use crate::crypto::aes::{AESCipher, MicSize};
 use crate::crypto::key::PrivacyKey;
 use crate::crypto::materials::NetworkKeys;
 use crate::crypto::nonce::{NetworkNonce, NetworkNonceParts};
 use crate::crypto::MIC;
 use crate::lower;
-use crate::mesh::{IVIndex, SequenceNumber, CTL, IVI, NID, TTL};
+use crate::mesh::{IVIndex, SequenceNumber, CTL, IVI, NID, TTL, U24};
 use btle::le::advertisement::{AdType, RawAdStructureBuffer};
 use btle::ConversionError;
 use core::convert::{TryFrom, TryInto};
@@ -22,6 +23,18 @@ pub struct DecryptedData {
     mic: Option<MIC>,
 }
 impl DecryptedData {
+    /// # Panics
+    /// Panics if `transport_pdu.len() > TRANSPORT_PDU_MAX_LEN`.
+    pub fn new(dst: Address, transport_pdu: &[u8]) -> DecryptedData {
+        let mut transport_buf = [0_u8; TRANSPORT_PDU_MAX_LEN];
+        transport_buf[..transport_pdu.len()].copy_from_slice(transport_pdu);
+        DecryptedData {
+            dst,
+            transport_buf,
+            transport_len: transport_pdu.len(),
+            mic: None,
+        }
+    }
     pub fn dst(&self) -> Address {
         self.dst
     }
@@ -57,13 +70,51 @@ impl DecryptedData {
 }
 
 pub enum NetworkDataError {
+    /// The PDU is shorter than the smallest or longer than the largest possible Network PDU
+    /// (see [`EncryptedPDU::min_len`] and [`EncryptedPDU::max_len`]).
+    BadLength,
     InvalidMIC,
     BadIVI,
     BadTransportPDU,
+    /// The PDU authenticated but it's a Control PDU with an opcode this stack doesn't know.
+    UnknownControlOpcode(UnknownControlPDU),
     BadSrc,
     BadDst,
     DifferentNID,
 }
+/// Authenticated Control PDU with an opcode (RFU or newer than this stack) that can't be parsed
+/// into a `lower::PDU`. Only its header is kept so the stack can decide what to do with it.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct UnknownControlPDU {
+    pub header: Header,
+    /// 7-bit Control Opcode.
+    pub opcode: u8,
+    /// Segment header if the PDU is a segment of a Segmented Control message.
+    pub segment_header: Option<lower::SegmentHeader>,
+}
+impl UnknownControlPDU {
+    /// Returns `None` if `transport_pdu` isn't a Control PDU with an unknown opcode (or is too
+    /// short to be one).
+    pub fn from_transport_pdu(header: &Header, transport_pdu: &[u8]) -> Option<UnknownControlPDU> {
+        let first = *transport_pdu.first()?;
+        let opcode = first & 0x7F;
+        if !bool::from(header.ctl) || ControlOpcode::new(opcode).is_some() {
+            return None;
+        }
+        let segment_header = if first & 0x80 != 0 {
+            Some(lower::SegmentHeader::unpack_from_u24(U24::from_bytes_be(
+                transport_pdu.get(1..4)?,
+            )?))
+        } else {
+            None
+        };
+        Some(UnknownControlPDU {
+            header: *header,
+            opcode,
+            segment_header,
+        })
+    }
+}
 
 pub struct OwnedEncryptedData {
     buf: [u8; TRANSPORT_PDU_MAX_LEN + ADDRESS_LEN],
@@ -117,13 +168,14 @@ impl EncryptedData<'_> {
             mic,
         }
     }
+    /// Length of the encrypted DST and TransportPDU plus the MIC.
     #[must_use]
-    pub const fn len(&self) -> usize {
-        self.data.len()
+    pub fn len(&self) -> usize {
+        self.data.len() + self.mic_size()
     }
     #[must_use]
     pub const fn is_empty(&self) -> bool {
-        self.len() == 0
+        self.data.is_empty()
     }
     /// encrypted DST and TransportPDU excluding MIC
     #[must_use]
@@ -132,7 +184,7 @@ impl EncryptedData<'_> {
     }
     #[must_use]
     pub fn data_len(&self) -> usize {
-        self.len() - self.mic_size()
+        self.data.len()
     }
     pub fn mic_size(&self) -> usize {
         self.mic.byte_size()
@@ -141,17 +193,12 @@ impl EncryptedData<'_> {
     pub fn mic(&self) -> MIC {
         self.mic
     }
+    /// The Privacy Random is the first 7 bytes of `EncDST || EncTransportPDU || NetMIC` (the
+    /// same bytes [`PrivacyRandom::from`] takes from an [`EncryptedPDU`]).
     #[must_use]
-    pub fn packed_privacy_random(&self, dst: Address, iv_index: IVIndex) -> PackedPrivacy {
-        let mut privacy_random_buf = [0_u8; PRIVACY_RANDOM_LEN + MIC::max_len()];
-        privacy_random_buf[..ADDRESS_LEN].copy_from_slice(&dst.value().to_le_bytes());
-        privacy_random_buf[ADDRESS_LEN..ADDRESS_LEN + self.data.len()].copy_from_slice(self.data());
-        if self.data.len() < PRIVACY_RANDOM_LEN - ADDRESS_LEN {
-            self.mic.be_pack_into(
-                &mut privacy_random_buf[ADDRESS_LEN + self.data.len()
-                    ..ADDRESS_LEN + self.data().len() + self.mic.byte_size()],
-            );
-        };
+    pub fn packed_privacy_random(&self, iv_index: IVIndex) -> PackedPrivacy {
+        let mut privacy_random_buf = [0_u8; ENCRYPTED_DATA_MAX_LEN + MIC::max_len()];
+        self.pack_into(&mut privacy_random_buf[..]);
         PrivacyRandom(&privacy_random_buf[..PRIVACY_RANDOM_LEN]).pack_with_iv(iv_index)
     }
     #[must_use]
@@ -167,7 +214,7 @@ impl EncryptedData<'_> {
         let mic = self.mic();
         buf[..self.data_len()].copy_from_slice(self.data());
         AESCipher::new(network_keys.encryption_key().key())
-            .ccm_decrypt(nonce.as_ref(), &[], &mut buf[..], mic)
+            .ccm_decrypt(nonce.as_ref(), &[], &mut buf[..self.data_len()], mic)
             .ok()?;
         let mut transport_buf = [0_u8; TRANSPORT_PDU_MAX_LEN];
         let transport_len = self.data_len() - ADDRESS_LEN;
@@ -227,7 +274,7 @@ pub struct Header {
     pub dst: Address,
 }
 // (IVI + NID) (1) + (CTL + TTL) (1) + Seq (3) + Src (2) + Dst (2)
-const PDU_HEADER_LEN: usize = 1 + 1 + 3 + 2 + 2;
+pub const PDU_HEADER_LEN: usize = 1 + 1 + 3 + 2 + 2;
 
 impl Header {
     #[must_use]
@@ -263,10 +310,69 @@ impl Header {
     pub fn obfuscate(&self, pecb: PECB) -> ObfuscatedHeader {
         DeobfuscatedHeader::from(self).obfuscate(pecb)
     }
+    /// Packs the plaintext header (`IVI || NID`, `CTL || TTL`, `SEQ`, `SRC`, `DST`). Useful for
+    /// debugging since it's never sent like this.
+    #[must_use]
+    pub fn pack(&self) -> [u8; PDU_HEADER_LEN] {
+        let mut out = [0_u8; PDU_HEADER_LEN];
+        out[0] = self.nid.with_flag(self.ivi.0);
+        out[1..1 + OBFUSCATED_LEN].copy_from_slice(&self.deobfuscated().pack());
+        out[1 + OBFUSCATED_LEN..].copy_from_slice(&self.dst.to_bytes_be());
+        out
+    }
+    /// Unpacks a plaintext header packed by `Header::pack`. Returns `None` if the SRC isn't a
+    /// unicast address.
+    #[must_use]
+    pub fn unpack(bytes: &[u8; PDU_HEADER_LEN]) -> Option<Header> {
+        let (nid, ivi) = NID::new_with_flag(bytes[0]);
+        let deobfuscated = DeobfuscatedHeader::unpack(
+            bytes[1..1 + OBFUSCATED_LEN]
+                .try_into()
+                .expect("hard coded length"),
+        )?;
+        let dst = Address::from_bytes_be(&bytes[1 + OBFUSCATED_LEN..])?;
+        Some(
+            deobfuscated
+                .private_header(IVI(ivi), nid)
+                .create_header(dst),
+        )
+    }
     #[must_use]
     pub fn deobfuscated(&self) -> DeobfuscatedHeader {
         self.into()
     }
+    /// Encrypts a raw Transport PDU (what a `lower::PDU` packs into) with this header. Unlike
+    /// `PDU::encrypt`, the bytes aren't parsed so PDUs a `lower::PDU` can't hold (like Control
+    /// PDUs with unknown opcodes) can be made. Ignores the IVI and NID.
+    ///
+    /// # Panics
+    /// Panics if `transport_pdu.len() > TRANSPORT_PDU_MAX_LEN`.
+    pub fn encrypt_transport_pdu(
+        &self,
+        transport_pdu: &[u8],
+        net_keys: &NetworkKeys,
+        iv_index: IVIndex,
+    ) -> Result<OwnedEncryptedPDU, PDUEncryptError> {
+        if !self.dst.is_assigned() || (bool::from(self.ctl) && self.dst.is_virtual()) {
+            return Err(PDUEncryptError::BadDst);
+        }
+        let deobfuscated = self.deobfuscated();
+        let encrypted = DecryptedData::new(self.dst, transport_pdu).encrypt(
+            &deobfuscated.nonce(iv_index),
+            net_keys,
+            self.mic_size(),
+        );
+        let pecb = encrypted
+            .data()
+            .packed_privacy_random(iv_index)
+            .encrypt_with(net_keys.privacy_key());
+        Ok(OwnedEncryptedPDU::new_parts(
+            iv_index.ivi(),
+            net_keys.nid(),
+            &deobfuscated.obfuscate(pecb),
+            encrypted.data(),
+        ))
+    }
 }
 impl From<&Header> for DeobfuscatedHeader {
     #[must_use]
@@ -292,8 +398,10 @@ pub struct OwnedEncryptedPDU {
     length: usize,
 }
 impl OwnedEncryptedPDU {
+    /// Returns `None` if `bytes.len() < EncryptedPDU::min_len()` or
+    /// `bytes.len() > EncryptedPDU::max_len()`.
     pub fn new(bytes: &[u8]) -> Option<OwnedEncryptedPDU> {
-        if bytes.len() <= ENCRYPTED_DATA_MAX_LEN && bytes.len() >= ENCRYPTED_DATA_MIN_LEN {
+        if bytes.len() <= MAX_ENCRYPTED_PDU_LEN && bytes.len() >= MIN_ENCRYPTED_PDU_LEN {
             let mut buf = [0_u8; ENCRYPTED_PDU_MAX_SIZE];
             buf[..bytes.len()].copy_from_slice(bytes);
             Some(Self {
@@ -305,9 +413,9 @@ impl OwnedEncryptedPDU {
         }
     }
     /// # Panics
-    /// Panics if `length < ENCRYPTED_PDU_MIN_LEN || length > ENCRYPTED_PDU_MAX_LEN`.
+    /// Panics if `length < MIN_ENCRYPTED_PDU_LEN || length > MAX_ENCRYPTED_PDU_LEN`.
     pub fn new_zeroed(length: usize) -> Self {
-        assert!(length <= ENCRYPTED_DATA_MAX_LEN && length > ENCRYPTED_DATA_MIN_LEN);
+        assert!(length <= MAX_ENCRYPTED_PDU_LEN && length >= MIN_ENCRYPTED_PDU_LEN);
         OwnedEncryptedPDU {
             pdu_buffer: [0_u8; ENCRYPTED_PDU_MAX_SIZE],
             length,
@@ -325,9 +433,6 @@ impl OwnedEncryptedPDU {
         buf[0] = nid.with_flag(ivi.into());
         obfuscated.pack_into(&mut buf[1..1 + ObfuscatedHeader::len()]);
         encrypted_data.pack_into(&mut buf[1 + ObfuscatedHeader::len()..]);
-        encrypted_data
-            .mic
-            .be_pack_into(&mut buf[1 + ObfuscatedHeader::len() + encrypted_data.data_len()..]);
         out
     }
 
@@ -348,7 +453,12 @@ impl TryFrom<RawAdStructureBuffer<&[u8]>> for OwnedEncryptedPDU {
         }
     }
 }
-const MIN_ENCRYPTED_PDU_LEN: usize = PDU_HEADER_LEN + MIC::small_size();
+/// Header + the smallest Transport PDU (1 byte) + a 32-bit NetMIC (Access).
+const MIN_ENCRYPTED_PDU_LEN: usize = PDU_HEADER_LEN + TRANSPORT_PDU_MIN_LEN + MIC::small_size();
+/// Header + the smallest Transport PDU (1 byte) + a 64-bit NetMIC (Control).
+const MIN_ENCRYPTED_CONTROL_PDU_LEN: usize =
+    PDU_HEADER_LEN + TRANSPORT_PDU_MIN_LEN + MIC::big_size();
+/// Largest Network PDU that fits in an advertising bearer Mesh Message AD structure.
 const MAX_ENCRYPTED_PDU_LEN: usize = ENCRYPTED_PDU_MAX_SIZE;
 
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug)]
@@ -372,6 +482,21 @@ impl<'a> EncryptedPDU<'a> {
     pub const fn len(&self) -> usize {
         self.data.len()
     }
+    /// Smallest possible Network PDU (an Access PDU with a 1 byte Transport PDU).
+    #[must_use]
+    pub const fn min_len() -> usize {
+        MIN_ENCRYPTED_PDU_LEN
+    }
+    /// Largest possible Network PDU on the advertising bearer.
+    #[must_use]
+    pub const fn max_len() -> usize {
+        MAX_ENCRYPTED_PDU_LEN
+    }
+    /// Returns if the PDU's length is between `min_len()` and `max_len()`.
+    #[must_use]
+    pub fn is_valid_len(&self) -> bool {
+        self.len() >= Self::min_len() && self.len() <= Self::max_len()
+    }
     #[must_use]
     pub fn data(&self) -> &[u8] {
         &self.data
@@ -392,6 +517,10 @@ impl<'a> EncryptedPDU<'a> {
         keys: &NetworkKeys,
         iv_index: IVIndex,
     ) -> Result<PDU, NetworkDataError> {
+        // Check the length before slicing anything.
+        if !self.is_valid_len() {
+            return Err(NetworkDataError::BadLength);
+        }
         if keys.nid() != self.nid() {
             return Err(NetworkDataError::InvalidMIC);
         }
@@ -407,6 +536,10 @@ impl<'a> EncryptedPDU<'a> {
             .ok_or(NetworkDataError::BadSrc)?;
         let nonce = deobfuscated.nonce(iv_index);
         let private_header = deobfuscated.private_header(self.ivi(), self.nid());
+        // Control PDUs have a 64-bit NetMIC so they need a few more bytes.
+        if bool::from(private_header.ctl()) && self.len() < MIN_ENCRYPTED_CONTROL_PDU_LEN {
+            return Err(NetworkDataError::BadLength);
+        }
         let encrypted_data = self.encrypted_data(private_header.ctl());
         let decrypted_data = encrypted_data
             .try_decrypt(keys, &nonce)
@@ -415,10 +548,17 @@ impl<'a> EncryptedPDU<'a> {
             return Err(NetworkDataError::BadDst);
         }
         let header = private_header.create_header(decrypted_data.dst);
-        let payload = decrypted_data
-            .as_lower_pdu(header.ctl)
-            .ok_or(NetworkDataError::BadTransportPDU)?;
-        Ok(PDU::new(&header, &payload))
+        match decrypted_data.as_lower_pdu(header.ctl) {
+            Some(payload) => Ok(PDU::new(&header, &payload)),
+            None => Err(UnknownControlPDU::from_transport_pdu(
+                &header,
+                decrypted_data.transport_pdu(),
+            )
+            .map_or(
+                NetworkDataError::BadTransportPDU,
+                NetworkDataError::UnknownControlOpcode,
+            )),
+        }
     }
     /// Returns the `ObfuscatedHeader`.
     #[must_use]
@@ -440,7 +580,7 @@ impl<'a> EncryptedPDU<'a> {
     pub fn encrypted_data(&self, ctl: CTL) -> EncryptedData {
         let mic = self.mic(ctl);
         EncryptedData::new(
-            &self.data[OBFUSCATED_LEN..self.data.len() - mic.byte_size()],
+            &self.data[1 + OBFUSCATED_LEN..self.data.len() - mic.byte_size()],
             mic,
         )
     }
@@ -517,28 +657,14 @@ impl PDU {
         net_keys: &NetworkKeys,
         iv_index: IVIndex,
     ) -> Result<OwnedEncryptedPDU, PDUEncryptError> {
-        if !self.header.dst.is_assigned()
-            || (self.payload.is_control() && self.header.dst.is_virtual())
-        {
+        if self.payload.is_control() && self.header.dst.is_virtual() {
             Err(PDUEncryptError::BadDst)
         } else {
-            let deobfuscated = self.header.deobfuscated();
-            let unencrypted = self.decrypted_data();
-            let encrypted = unencrypted.encrypt(
-                &deobfuscated.nonce(iv_index),
+            self.header.encrypt_transport_pdu(
+                self.decrypted_data().transport_pdu(),
                 net_keys,
-                self.header.mic_size(),
-            );
-            let pecb = encrypted
-                .data()
-                .packed_privacy_random(self.header.dst, iv_index)
-                .encrypt_with(net_keys.privacy_key());
-            Ok(OwnedEncryptedPDU::new_parts(
-                iv_index.ivi(),
-                net_keys.nid(),
-                &deobfuscated.obfuscate(pecb),
-                encrypted.data(),
-            ))
+                iv_index,
+            )
         }
     }
 }
@@ -643,11 +769,11 @@ impl DeobfuscatedHeader {
         let src = self.src.to_bytes_be();
         [
             self.ttl.with_flag(self.ctl.0),
-            seq[2],
-            seq[1],
             seq[0],
-            src[1],
+            seq[1],
+            seq[2],
             src[0],
+            src[1],
         ]
     }
     pub fn unpack(bytes: &[u8; OBFUSCATED_LEN]) -> Option<DeobfuscatedHeader> {
@@ -740,7 +866,15 @@ impl From<PackedPrivacy> for [u8; PACKED_PRIVACY_LEN] {
 }
 #[cfg(test)]
 mod tests {
-    use super::Header;
+    use super::{
+        EncryptedPDU, Header, NetworkDataError, OwnedEncryptedPDU, MAX_ENCRYPTED_PDU_LEN,
+        MIN_ENCRYPTED_PDU_LEN,
+    };
+    use crate::address::UnicastAddress;
+    use crate::crypto::key::NetKey;
+    use crate::crypto::materials::NetworkKeys;
+    use crate::mesh::{IVIndex, SequenceNumber, CTL, IVI, NID, TTL, U24};
+    use crate::random::Randomizable;
 
     /*
     /// Generates a random Network PDU Header. Helpful for testing.
@@ -763,6 +897,42 @@ mod tests {
     }
     #[test]
     fn test_random_headers_to_from_bytes() {
-        for _i in 0..10 {}
+        for _i in 0..10 {
+            let header = Header {
+                ivi: IVI(bool::random_secure()),
+                nid: NID::from_masked_u8(u8::random_secure()),
+                ctl: CTL(bool::random_secure()),
+                ttl: TTL::from_masked_u8(u8::random_secure()),
+                seq: SequenceNumber(U24::new_masked(u32::random_secure())),
+                src: UnicastAddress::from_mask_u16(u16::random_secure() | 1),
+                dst: u16::random_secure().into(),
+            };
+            assert_eq!(Header::unpack(&header.pack()), Some(header));
+        }
+    }
+    #[test]
+    fn test_encrypted_pdu_len_bounds() {
+        let too_short = [0_u8; MIN_ENCRYPTED_PDU_LEN - 1];
+        let too_long = [0_u8; MAX_ENCRYPTED_PDU_LEN + 1];
+        assert!(EncryptedPDU::new(&too_short[..]).is_none());
+        assert!(EncryptedPDU::new(&too_long[..]).is_none());
+        assert!(OwnedEncryptedPDU::new(&too_short[..]).is_none());
+        assert!(OwnedEncryptedPDU::new(&too_long[..]).is_none());
+        assert!(EncryptedPDU::new(&too_long[..MIN_ENCRYPTED_PDU_LEN]).is_some());
+        assert!(EncryptedPDU::new(&too_long[..MAX_ENCRYPTED_PDU_LEN]).is_some());
+    }
+    #[test]
+    fn test_try_decrypt_bad_length() {
+        let keys = NetworkKeys::from(&NetKey::random_secure());
+        let too_short = [0_u8; MIN_ENCRYPTED_PDU_LEN - 1];
+        let too_long = [0_u8; MAX_ENCRYPTED_PDU_LEN + 1];
+        for &data in [&too_short[..], &too_long[..]].iter() {
+            // Bypass `EncryptedPDU::new` to make sure `try_decrypt` checks by itself.
+            let pdu = EncryptedPDU { data };
+            match pdu.try_decrypt(&keys, IVIndex(0)) {
+                Err(NetworkDataError::BadLength) => (),
+                _ => panic!("expected BadLength for {} bytes", data.len()),
+            }
+        }
     }
 }
@@ -1 +1,461 @@
+//! PB-ADV link layer. A [`Link`] opens (or accepts) a PB-ADV link and then sends and receives
+//! whole Provisioning PDUs. It takes care of the transaction numbers, splitting PDUs into
+//! Transaction Start/Continuation PDUs, Transaction Acks and retransmissions.
+//!
+//! A `Link` reads PB-ADV PDUs from an `mpsc::Receiver` (PDUs for other links are ignored so
+//! every PB-ADV PDU from the bearer can be forwarded) and writes the ones it sends to an
+//! `mpsc::Sender` for the advertising bearer.
+use crate::asyncs::sync::mpsc;
+use crate::provisioning::bearer_control::{self, CloseReason, LinkAck, LinkClose, LinkOpen};
+use crate::provisioning::generic::{
+    self, Control, SegmentGenerator, TransactionAcknowledgmentPDU, TransactionReassembler,
+};
+use crate::provisioning::pb_adv::{self, LinkID, TransactionNumber};
+use crate::provisioning::protocol;
+use crate::stack::clock::{SharedClock, SystemClock};
+use crate::timestamp::{Timestamp, TimestampTrait};
+use crate::uuid::UUID;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::time::Duration;
 
+/// How long the provisioner keeps sending Link Opens without a Link Ack.
+pub const LINK_ESTABLISHMENT_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a transaction is retransmitted without a Transaction Ack before the link is closed.
+pub const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait for a Link Ack or Transaction Ack before sending again.
+pub const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(500);
+/// How many times a Link Close is sent. Nothing acks it so it's sent a few times in a row.
+pub const LINK_CLOSE_TRANSMISSIONS: usize = 3;
+
+/// Returned when a `Link` can't be opened or stops working.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum LinkError {
+    /// The incoming or outgoing PB-ADV channel closed.
+    ChannelClosed,
+    /// No Link Ack or Transaction Ack in time. A Link Close with `CloseReason::Timeout` was sent
+    /// for timed out transactions.
+    Timeout,
+    /// The other end closed the link.
+    Closed(CloseReason),
+    /// The Provisioning PDU is empty or longer than `generic::TRANSACTION_MAX_LEN`.
+    BadLength,
+}
+/// An open PB-ADV link. See the module docs for more.
+pub struct Link {
+    link_id: LinkID,
+    incoming: mpsc::Receiver<pb_adv::PDU>,
+    outgoing: mpsc::Sender<pb_adv::PDU>,
+    /// Transaction number of the next transaction we send. Provisioners use 0x00-0x7F and
+    /// devices 0x80-0xFF.
+    next_transaction: TransactionNumber,
+    /// The last transaction received from the other end. Retransmissions of it are acked again
+    /// but not delivered again.
+    last_rx_transaction: Option<TransactionNumber>,
+    reassembly: Option<(TransactionNumber, TransactionReassembler)>,
+    /// Provisioning PDUs received while waiting on a Transaction Ack.
+    received: VecDeque<Box<[u8]>>,
+    /// Times the retransmissions and timeouts.
+    clock: SharedClock,
+}
+impl Link {
+    fn new(
+        link_id: LinkID,
+        incoming: mpsc::Receiver<pb_adv::PDU>,
+        outgoing: mpsc::Sender<pb_adv::PDU>,
+        next_transaction: TransactionNumber,
+        clock: SharedClock,
+    ) -> Self {
+        Self {
+            link_id,
+            incoming,
+            outgoing,
+            next_transaction,
+            last_rx_transaction: None,
+            reassembly: None,
+            received: VecDeque::new(),
+            clock,
+        }
+    }
+    /// Opens a link to the unprovisioned device `device_uuid` as the provisioner. Link Opens are
+    /// retransmitted until the device sends a Link Ack or `LINK_ESTABLISHMENT_TIMEOUT` passes.
+    pub async fn open(
+        link_id: LinkID,
+        device_uuid: UUID,
+        incoming: mpsc::Receiver<pb_adv::PDU>,
+        outgoing: mpsc::Sender<pb_adv::PDU>,
+    ) -> Result<Link, LinkError> {
+        Self::open_with_clock(
+            link_id,
+            device_uuid,
+            incoming,
+            outgoing,
+            SystemClock::shared(),
+        )
+        .await
+    }
+    /// Same as `Link::open` but the link is timed with `clock` instead of the system clock.
+    pub async fn open_with_clock(
+        link_id: LinkID,
+        device_uuid: UUID,
+        incoming: mpsc::Receiver<pb_adv::PDU>,
+        outgoing: mpsc::Sender<pb_adv::PDU>,
+        clock: SharedClock,
+    ) -> Result<Link, LinkError> {
+        let mut link = Self::new(
+            link_id,
+            incoming,
+            outgoing,
+            TransactionNumber::new_provisioner(),
+            clock,
+        );
+        let deadline = link.clock.now() + LINK_ESTABLISHMENT_TIMEOUT;
+        loop {
+            link.send_bearer_control(bearer_control::PDU::LinkOpen(LinkOpen::new(device_uuid)))
+                .await?;
+            let retransmit_at = (link.clock.now() + RETRANSMIT_INTERVAL).min(deadline);
+            while let Some(pdu) = link.next_pdu(retransmit_at).await? {
+                match pdu.generic_pdu.control {
+                    Control::BearerControl(bearer_control::PDU::LinkAck(_)) => return Ok(link),
+                    Control::BearerControl(bearer_control::PDU::LinkClose(close)) => {
+                        return Err(LinkError::Closed(close.0))
+                    }
+                    _ => (),
+                }
+            }
+            if link.clock.now() >= deadline {
+                return Err(LinkError::Timeout);
+            }
+        }
+    }
+    /// Accepts a link opened by a provisioner as the device being provisioned (see
+    /// `link::PBAdvLink::handle_link_open` for which Link Opens to accept) and sends the Link
+    /// Ack. Retransmitted Link Opens are acked again by the `Link`.
+    pub async fn accept(
+        link_id: LinkID,
+        incoming: mpsc::Receiver<pb_adv::PDU>,
+        outgoing: mpsc::Sender<pb_adv::PDU>,
+    ) -> Result<Link, LinkError> {
+        Self::accept_with_clock(link_id, incoming, outgoing, SystemClock::shared()).await
+    }
+    /// Same as `Link::accept` but the link is timed with `clock` instead of the system clock.
+    pub async fn accept_with_clock(
+        link_id: LinkID,
+        incoming: mpsc::Receiver<pb_adv::PDU>,
+        outgoing: mpsc::Sender<pb_adv::PDU>,
+        clock: SharedClock,
+    ) -> Result<Link, LinkError> {
+        let mut link = Self::new(
+            link_id,
+            incoming,
+            outgoing,
+            TransactionNumber::new_provisionee(),
+            clock,
+        );
+        link.send_bearer_control(bearer_control::PDU::LinkAck(LinkAck()))
+            .await?;
+        Ok(link)
+    }
+    pub fn link_id(&self) -> LinkID {
+        self.link_id
+    }
+    pub fn clock(&self) -> &SharedClock {
+        &self.clock
+    }
+    /// Sends `pdu` in one transaction (see [`Link::send_bytes`]).
+    pub async fn send(&mut self, pdu: &protocol::PDU) -> Result<(), LinkError> {
+        let mut buf = alloc::vec![0_u8; pdu.byte_len()];
+        pdu.pack_into(&mut buf)
+            .expect("buffer is the length of the PDU");
+        self.send_bytes(&buf).await
+    }
+    /// Sends a packed Provisioning PDU in one transaction. Every segment is retransmitted each
+    /// `RETRANSMIT_INTERVAL` until the other end acks the transaction. If it isn't acked within
+    /// `TRANSACTION_TIMEOUT`, the link is closed with `CloseReason::Timeout`.
+    ///
+    /// Provisioning PDUs received in the meantime are kept for [`Link::recv`].
+    pub async fn send_bytes(&mut self, data: &[u8]) -> Result<(), LinkError> {
+        let transaction_number = self.next_transaction;
+        let segments = SegmentGenerator::new(data)
+            .ok_or(LinkError::BadLength)?
+            .map(|generic_pdu| self.pdu(transaction_number, generic_pdu))
+            .collect::<Vec<_>>();
+        let deadline = self.clock.now() + TRANSACTION_TIMEOUT;
+        while self.clock.now() < deadline {
+            for &segment in &segments {
+                self.send_pdu(segment).await?;
+            }
+            let retransmit_at = (self.clock.now() + RETRANSMIT_INTERVAL).min(deadline);
+            while let Some(pdu) = self.next_pdu(retransmit_at).await? {
+                if self.handle_pdu(pdu).await? == Some(transaction_number) {
+                    self.next_transaction.increment();
+                    return Ok(());
+                }
+            }
+        }
+        self.send_close(CloseReason::Timeout).await?;
+        Err(LinkError::Timeout)
+    }
+    /// Waits for the next Provisioning PDU from the other end (still packed, see
+    /// `protocol::PDU::unpack_from`). Every transaction is acked once reassembled. There is no
+    /// timeout so wrap it in one if needed.
+    pub async fn recv(&mut self) -> Result<Box<[u8]>, LinkError> {
+        loop {
+            if let Some(received) = self.received.pop_front() {
+                return Ok(received);
+            }
+            let pdu = self.incoming.recv().await.ok_or(LinkError::ChannelClosed)?;
+            self.handle_pdu(pdu).await?;
+        }
+    }
+    /// Closes the link with `reason`.
+    pub async fn close(mut self, reason: CloseReason) -> Result<(), LinkError> {
+        self.send_close(reason).await
+    }
+    async fn send_close(&mut self, reason: CloseReason) -> Result<(), LinkError> {
+        for _ in 0..LINK_CLOSE_TRANSMISSIONS {
+            self.send_bearer_control(bearer_control::PDU::LinkClose(LinkClose::new(reason)))
+                .await?;
+        }
+        Ok(())
+    }
+    /// Returns the next PDU for this link or `None` once `until` passes.
+    async fn next_pdu(&mut self, until: Timestamp) -> Result<Option<pb_adv::PDU>, LinkError> {
+        loop {
+            let wait = self.clock.now().until(until).unwrap_or_default();
+            match self.clock.timeout(wait, self.incoming.recv()).await {
+                Ok(Some(pdu)) if pdu.link_id == self.link_id => return Ok(Some(pdu)),
+                Ok(Some(_)) => (),
+                Ok(None) => return Err(LinkError::ChannelClosed),
+                Err(_) => return Ok(None),
+            }
+        }
+    }
+    /// Handles a PDU from the other end. Returns the transaction number if it's a Transaction
+    /// Ack.
+    async fn handle_pdu(
+        &mut self,
+        pdu: pb_adv::PDU,
+    ) -> Result<Option<TransactionNumber>, LinkError> {
+        if pdu.link_id != self.link_id {
+            return Ok(None);
+        }
+        let transaction_number = pdu.transaction_number;
+        let payload = pdu
+            .generic_pdu
+            .payload
+            .as_ref()
+            .map_or(&[][..], AsRef::as_ref);
+        match pdu.generic_pdu.control {
+            Control::TransactionAcknowledgement(_) => return Ok(Some(transaction_number)),
+            Control::BearerControl(bearer_control::PDU::LinkClose(close)) => {
+                return Err(LinkError::Closed(close.0))
+            }
+            Control::BearerControl(bearer_control::PDU::LinkOpen(_)) => {
+                // Our Link Ack got lost.
+                if self.next_transaction.is_provisionee() {
+                    self.send_bearer_control(bearer_control::PDU::LinkAck(LinkAck()))
+                        .await?;
+                }
+            }
+            Control::BearerControl(bearer_control::PDU::LinkAck(_)) => (),
+            Control::TransactionStart(start) => {
+                if self.last_rx_transaction == Some(transaction_number) {
+                    // Our Transaction Ack got lost.
+                    return self.send_ack(transaction_number).await.map(|_| None);
+                }
+                let in_progress = self
+                    .reassembly
+                    .as_ref()
+                    .map_or(false, |(number, _)| *number == transaction_number);
+                if !in_progress {
+                    // Malformed transactions are dropped. The sender times out.
+                    self.reassembly = TransactionReassembler::new(&start, payload)
+                        .ok()
+                        .map(|reassembler| (transaction_number, reassembler));
+                }
+            }
+            Control::TransactionContinuation(continuation) => {
+                if let Some((number, reassembler)) = self.reassembly.as_mut() {
+                    if *number == transaction_number {
+                        // A bad segment is dropped. It'll be retransmitted.
+                        let _ = reassembler.add_continuation(&continuation, payload);
+                    }
+                }
+            }
+        }
+        let complete = self
+            .reassembly
+            .as_ref()
+            .map_or(false, |(_, reassembler)| reassembler.is_complete());
+        if complete {
+            let (number, reassembler) = self.reassembly.take().expect("checked above");
+            // A bad FCS drops the whole transaction so it's retransmitted.
+            if let Ok(data) = reassembler.finish() {
+                self.last_rx_transaction = Some(number);
+                self.received.push_back(data.into_boxed_slice());
+                self.send_ack(number).await?;
+            }
+        }
+        Ok(None)
+    }
+    fn pdu(
+        &self,
+        transaction_number: TransactionNumber,
+        generic_pdu: generic::PDU<pb_adv::GenericPayload>,
+    ) -> pb_adv::PDU {
+        pb_adv::PDU {
+            link_id: self.link_id,
+            transaction_number,
+            generic_pdu,
+        }
+    }
+    async fn send_pdu(&mut self, pdu: pb_adv::PDU) -> Result<(), LinkError> {
+        self.outgoing
+            .send(pdu)
+            .await
+            .ok()
+            .ok_or(LinkError::ChannelClosed)
+    }
+    async fn send_ack(&mut self, transaction_number: TransactionNumber) -> Result<(), LinkError> {
+        self.send_pdu(self.pdu(
+            transaction_number,
+            generic::PDU {
+                control: Control::TransactionAcknowledgement(TransactionAcknowledgmentPDU::new()),
+                payload: None,
+            },
+        ))
+        .await
+    }
+    /// Bearer Control PDUs always use transaction number 0.
+    async fn send_bearer_control(&mut self, pdu: bearer_control::PDU) -> Result<(), LinkError> {
+        self.send_pdu(self.pdu(
+            TransactionNumber::new(0),
+            generic::PDU {
+                control: Control::BearerControl(pdu),
+                payload: None,
+            },
+        ))
+        .await
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asyncs::task;
+    use crate::foundation::state::AttentionTimer;
+    use crate::provisioning::link::{LinkOpenResponse, PBAdvLink};
+    use crate::provisioning::protocol::{Invite, PublicKey};
+    use crate::test_util::ManualClock;
+
+    const LINK_ID: LinkID = LinkID::new(0x1234_5678);
+    const UUID_BYTES: [u8; 16] = [0x01; 16];
+
+    /// Opens a link between a provisioner and a device. Returns the provisioner's and the
+    /// device's `Link`.
+    async fn links() -> (Link, Link) {
+        let (to_device_tx, mut to_device_rx) = mpsc::channel(32);
+        let (to_provisioner_tx, to_provisioner_rx) = mpsc::channel(32);
+        let open = task::spawn(Link::open(
+            LINK_ID,
+            UUID(UUID_BYTES),
+            to_provisioner_rx,
+            to_device_tx,
+        ));
+        let link_open = to_device_rx.recv().await.expect("Link Open sent");
+        let mut device = PBAdvLink::new(UUID(UUID_BYTES));
+        assert_eq!(
+            device.handle_pb_adv_pdu(&link_open),
+            Some(LinkOpenResponse::Opened)
+        );
+        let device = Link::accept(LINK_ID, to_device_rx, to_provisioner_tx)
+            .await
+            .expect("channels open");
+        let provisioner = open
+            .await
+            .expect("open task panicked")
+            .expect("Link Ack sent");
+        (provisioner, device)
+    }
+    #[tokio::test]
+    async fn test_link_transactions() {
+        let (mut provisioner, mut device) = links().await;
+        let invite = protocol::PDU::Invite(Invite(AttentionTimer::new(5)));
+        let (sent, received) = tokio::join!(provisioner.send(&invite), device.recv());
+        assert_eq!(sent, Ok(()));
+        assert_eq!(
+            protocol::PDU::unpack_from(&received.expect("link open")),
+            Ok(invite)
+        );
+        // The Public Key needs a Transaction Start and 2 Transaction Continuations.
+        let public_key = protocol::PDU::PublicKey(PublicKey {
+            x: [0x02; 32],
+            y: [0x03; 32],
+        });
+        let (sent, received) = tokio::join!(device.send(&public_key), provisioner.recv());
+        assert_eq!(sent, Ok(()));
+        assert_eq!(
+            protocol::PDU::unpack_from(&received.expect("link open")),
+            Ok(public_key)
+        );
+        assert_eq!(provisioner.next_transaction, TransactionNumber::new(0x01));
+        assert_eq!(device.next_transaction, TransactionNumber::new(0x81));
+
+        device
+            .close(CloseReason::Success)
+            .await
+            .expect("channel open");
+        assert_eq!(
+            provisioner.recv().await,
+            Err(LinkError::Closed(CloseReason::Success))
+        );
+    }
+    #[tokio::test]
+    async fn test_transaction_timeout() {
+        let clock = ManualClock::new();
+        let (to_device_tx, mut to_device_rx) = mpsc::channel(128);
+        let (_to_provisioner_tx, to_provisioner_rx) = mpsc::channel(1);
+        let mut link = Link::new(
+            LINK_ID,
+            to_provisioner_rx,
+            to_device_tx,
+            TransactionNumber::new_provisioner(),
+            clock.shared(),
+        );
+        let send = task::spawn(async move { link.send_bytes(&[0x00, 0x05]).await });
+        let start = to_device_rx
+            .recv()
+            .await
+            .expect("Transaction Start sent")
+            .generic_pdu
+            .control;
+        let mut sent = || {
+            let mut controls = Vec::new();
+            while let Ok(pdu) = to_device_rx.try_recv() {
+                controls.push(pdu.generic_pdu.control);
+            }
+            controls
+        };
+        assert!(sent().is_empty());
+        // Nobody acks so the Transaction Start is sent again every `RETRANSMIT_INTERVAL`.
+        clock.advance(RETRANSMIT_INTERVAL).await;
+        assert_eq!(sent(), vec![start]);
+
+        clock.advance(TRANSACTION_TIMEOUT).await;
+        assert_eq!(
+            send.await.expect("send task panicked"),
+            Err(LinkError::Timeout)
+        );
+        let close = Control::BearerControl(bearer_control::PDU::LinkClose(LinkClose::new(
+            CloseReason::Timeout,
+        )));
+        assert_eq!(
+            sent()
+                .into_iter()
+                .filter(|control| *control == close)
+                .count(),
+            LINK_CLOSE_TRANSMISSIONS
+        );
+    }
+}
@@ -204,12 +204,16 @@ impl PDU {
                 Opcode::LinkClose
             }
         };
-        buf[0] = opcode.into();
+        buf[0] = opcode.with_gpcf(GPCF::BearerControl);
         Ok(())
     }
     pub fn unpack_from(buf: &[u8]) -> Result<Self, PackError> {
         PackError::atleast_length(1, buf)?;
-        match Opcode::try_from(buf[0]).map_err(|_| PackError::BadOpcode)? {
+        let opcode = match Opcode::from_with_gpcf(buf[0]) {
+            (Some(opcode), GPCF::BearerControl) => opcode,
+            _ => return Err(PackError::BadOpcode),
+        };
+        match opcode {
             Opcode::LinkOpen => Ok(PDU::LinkOpen(LinkOpen::unpack_from(&buf[1..])?)),
             Opcode::LinkAck => Ok(PDU::LinkAck(LinkAck::unpack_from(&buf[1..])?)),
             Opcode::LinkClose => Ok(PDU::LinkClose(LinkClose::unpack_from(&buf[1..])?)),
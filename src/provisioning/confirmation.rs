@@ -1,4 +1,8 @@
-use crate::crypto::{s1, Salt};
+//! Confirmation values exchanged during the Provisioning Protocol. Both ends prove they know the
+//! AuthValue (and the ECDH secret) with `AES-CMAC(ConfirmationKey, Random || AuthValue)`.
+use crate::crypto::aes::AESCipher;
+use crate::crypto::key::Key;
+use crate::crypto::{k1_bytes, s1, ECDHSecret, ProvisioningSalt, Salt};
 use crate::provisioning::protocol;
 use crate::provisioning::protocol::{ProtocolPDU, ProtocolPDUError};
 
@@ -15,7 +19,59 @@ const START_POS: usize = CAPABILITIES_POS + protocol::Capabilities::BYTE_LEN;
 const PROV_KEY_POS: usize = START_POS + protocol::Start::BYTE_LEN;
 const DEVICE_KEY_POS: usize = PROV_KEY_POS + protocol::PublicKey::BYTE_LEN;
 
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
 pub struct ConfirmationSalt(pub Salt);
+impl ConfirmationSalt {
+    /// `s1(ConfirmationSalt || RandomProvisioner || RandomDevice)`
+    pub fn provisioning_salt(
+        &self,
+        provisioner_random: &protocol::Random,
+        device_random: &protocol::Random,
+    ) -> ProvisioningSalt {
+        let mut buf = [0_u8; 16 + protocol::RANDOM_LEN * 2];
+        buf[..16].copy_from_slice(self.0.as_ref());
+        buf[16..16 + protocol::RANDOM_LEN].copy_from_slice(&provisioner_random.0[..]);
+        buf[16 + protocol::RANDOM_LEN..].copy_from_slice(&device_random.0[..]);
+        ProvisioningSalt::new(s1(&buf[..]))
+    }
+}
+pub const AUTH_VALUE_LEN: usize = 16;
+/// AuthValue from the OOB authentication. All zeros for No OOB, the 16 byte value for Static
+/// OOB and the number (see [`AuthValue::from_number`]) for Output and Input OOB.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash, Default)]
+pub struct AuthValue(pub [u8; AUTH_VALUE_LEN]);
+impl AuthValue {
+    pub const NO_OOB: AuthValue = AuthValue([0_u8; AUTH_VALUE_LEN]);
+    /// The AuthValue of a number output or input (a count of blinks, pushes, etc or a numeric
+    /// value). It's stored big endian in the last bytes.
+    pub fn from_number(number: u32) -> AuthValue {
+        let mut value = [0_u8; AUTH_VALUE_LEN];
+        value[AUTH_VALUE_LEN - 4..].copy_from_slice(&number.to_be_bytes());
+        AuthValue(value)
+    }
+}
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct ConfirmationKey(pub Key);
+impl ConfirmationKey {
+    /// `k1(ECDHSecret, ConfirmationSalt, "prck")`
+    pub fn new(secret: &ECDHSecret, salt: &ConfirmationSalt) -> Self {
+        Self(k1_bytes(secret.as_ref(), salt.0, b"prck"))
+    }
+    /// `AES-CMAC(ConfirmationKey, Random || AuthValue)`
+    pub fn confirmation(
+        &self,
+        random: &protocol::Random,
+        auth_value: &AuthValue,
+    ) -> protocol::Confirmation {
+        let mut out = protocol::Confirmation::default();
+        out.0.copy_from_slice(
+            AESCipher::new(self.0)
+                .cmac_slice(&[&random.0[..], &auth_value.0[..]])
+                .as_ref(),
+        );
+        out
+    }
+}
 impl Inputs {
     pub fn is_ready(&self) -> bool {
         self.device_public_key.is_some()
@@ -59,8 +115,56 @@ pub const INPUT_LEN: usize = protocol::Invite::BYTE_LEN
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::crypto::hex_16_to_array;
     #[test]
     pub fn test_input_len() {
         assert_eq!(DEVICE_KEY_POS + protocol::PublicKey::BYTE_LEN, INPUT_LEN)
     }
+    /// Mesh Profile v1.0.1 Sample Data (8.7 Provisioning).
+    fn sample_secret() -> ECDHSecret {
+        let mut secret = [0_u8; 32];
+        secret[..16].copy_from_slice(&hex_16_to_array("ab85843a2f6d883f62e5684b38e30733").unwrap());
+        secret[16..].copy_from_slice(&hex_16_to_array("5fe6e1945ecd19604105c6f23221eb69").unwrap());
+        ECDHSecret::new_bytes(secret)
+    }
+    fn sample_confirmation_salt() -> ConfirmationSalt {
+        ConfirmationSalt(Salt::from_hex("5faabe187337c71cc6c973369dcaa79a").unwrap())
+    }
+    fn sample_provisioner_random() -> protocol::Random {
+        protocol::Random(hex_16_to_array("8b19ac31d58b124c946209b5db1021b9").unwrap())
+    }
+    fn sample_device_random() -> protocol::Random {
+        protocol::Random(hex_16_to_array("55a2a2bca04cd32ff6f346bd0a0c1a3a").unwrap())
+    }
+    #[test]
+    pub fn test_confirmation() {
+        let key = ConfirmationKey::new(&sample_secret(), &sample_confirmation_salt());
+        assert_eq!(
+            key.0,
+            Key::from_hex("e31fe046c68ec339c425fc6629f0336f").unwrap()
+        );
+        assert_eq!(
+            key.confirmation(&sample_provisioner_random(), &AuthValue::NO_OOB),
+            protocol::Confirmation(hex_16_to_array("b38a114dfdca1fe153bd2c1e0dc46ac2").unwrap())
+        );
+        assert_eq!(
+            key.confirmation(&sample_device_random(), &AuthValue::NO_OOB),
+            protocol::Confirmation(hex_16_to_array("eeba521c196b52cc2e37aa40329f554e").unwrap())
+        );
+    }
+    #[test]
+    pub fn test_auth_value_from_number() {
+        let mut expected = [0_u8; AUTH_VALUE_LEN];
+        expected[12..].copy_from_slice(&[0x00, 0x01, 0xE2, 0x40]);
+        assert_eq!(AuthValue::from_number(123_456), AuthValue(expected));
+    }
+    #[test]
+    pub fn test_provisioning_salt() {
+        assert_eq!(
+            sample_confirmation_salt()
+                .provisioning_salt(&sample_provisioner_random(), &sample_device_random())
+                .as_salt(),
+            Salt::from_hex("a21c7d45f201cf9489a2fb57145015b4").unwrap()
+        );
+    }
 }
@@ -0,0 +1,206 @@
+//! Provisioning Data (the NetKey, IV Index and unicast address given to the device) and the
+//! session key and nonce it's encrypted with.
+use crate::address::UnicastAddress;
+use crate::crypto::aes::{AESCipher, MicSize};
+use crate::crypto::key::{Key, NetKey, KEY_LEN};
+use crate::crypto::nonce::{Nonce, NONCE_LEN};
+use crate::crypto::{k1_bytes, ECDHSecret, ProvisioningSalt};
+use crate::mesh::{IVIndex, IVUpdateFlag, KeyIndex, KeyRefreshFlag, NetKeyIndex};
+use crate::provisioning::protocol::{
+    EncryptedProvisioningData, ErrorCode, ENCRYPTED_PROVISIONING_DATA_LEN,
+};
+use core::convert::{TryFrom, TryInto};
+
+pub const PROVISIONING_DATA_LEN: usize = ENCRYPTED_PROVISIONING_DATA_LEN;
+const KEY_INDEX_POS: usize = KEY_LEN;
+const FLAGS_POS: usize = KEY_INDEX_POS + 2;
+const IV_INDEX_POS: usize = FLAGS_POS + 1;
+const ADDRESS_POS: usize = IV_INDEX_POS + IVIndex::BYTE_LEN;
+const KEY_REFRESH_FLAG: u8 = 0x01;
+const IV_UPDATE_FLAG: u8 = 0x02;
+
+/// Everything the device needs to join the network.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct ProvisioningData {
+    pub net_key: NetKey,
+    pub net_key_index: NetKeyIndex,
+    pub key_refresh: KeyRefreshFlag,
+    pub iv_update: IVUpdateFlag,
+    pub iv_index: IVIndex,
+    /// Primary address of the device.
+    pub unicast_address: UnicastAddress,
+}
+impl ProvisioningData {
+    pub fn pack(&self) -> [u8; PROVISIONING_DATA_LEN] {
+        let mut out = [0_u8; PROVISIONING_DATA_LEN];
+        out[..KEY_INDEX_POS].copy_from_slice(self.net_key.key().as_ref());
+        out[KEY_INDEX_POS..FLAGS_POS]
+            .copy_from_slice(&u16::from(self.net_key_index.0).to_be_bytes());
+        out[FLAGS_POS] = if self.key_refresh.0 {
+            KEY_REFRESH_FLAG
+        } else {
+            0
+        } | if self.iv_update.0 { IV_UPDATE_FLAG } else { 0 };
+        out[IV_INDEX_POS..ADDRESS_POS].copy_from_slice(&self.iv_index.0.to_be_bytes());
+        out[ADDRESS_POS..].copy_from_slice(&u16::from(self.unicast_address).to_be_bytes());
+        out
+    }
+    /// Returns `ErrorCode::InvalidFormat` for a bad key index, unknown flags or a non-unicast
+    /// address.
+    pub fn unpack(buf: &[u8; PROVISIONING_DATA_LEN]) -> Result<Self, ErrorCode> {
+        let net_key =
+            NetKey::new_bytes(buf[..KEY_INDEX_POS].try_into().expect("hard coded length"));
+        let net_key_index = KeyIndex::try_from(u16::from_be_bytes([
+            buf[KEY_INDEX_POS],
+            buf[KEY_INDEX_POS + 1],
+        ]))
+        .map_err(|_| ErrorCode::InvalidFormat)?;
+        let flags = buf[FLAGS_POS];
+        if flags & !(KEY_REFRESH_FLAG | IV_UPDATE_FLAG) != 0 {
+            return Err(ErrorCode::InvalidFormat);
+        }
+        let iv_index = u32::from_be_bytes(
+            buf[IV_INDEX_POS..ADDRESS_POS]
+                .try_into()
+                .expect("hard coded length"),
+        );
+        let unicast_address =
+            UnicastAddress::try_from(u16::from_be_bytes([buf[ADDRESS_POS], buf[ADDRESS_POS + 1]]))
+                .map_err(|_| ErrorCode::InvalidFormat)?;
+        Ok(Self {
+            net_key,
+            net_key_index: NetKeyIndex(net_key_index),
+            key_refresh: KeyRefreshFlag(flags & KEY_REFRESH_FLAG != 0),
+            iv_update: IVUpdateFlag(flags & IV_UPDATE_FLAG != 0),
+            iv_index: IVIndex(iv_index),
+            unicast_address,
+        })
+    }
+    pub fn encrypt(&self, session_keys: &SessionKeys) -> EncryptedProvisioningData {
+        let mut data = self.pack();
+        let mic = AESCipher::new(session_keys.key).ccm_encrypt(
+            &session_keys.nonce,
+            b"",
+            &mut data[..],
+            MicSize::Big,
+        );
+        EncryptedProvisioningData::new(data, mic)
+    }
+}
+/// Session key and nonce for encrypting the Provisioning Data.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct SessionKeys {
+    key: Key,
+    nonce: Nonce,
+}
+impl SessionKeys {
+    /// `SessionKey = k1(ECDHSecret, ProvisioningSalt, "prsk")` and `SessionNonce` is the last 13
+    /// bytes of `k1(ECDHSecret, ProvisioningSalt, "prsn")`.
+    pub fn new(secret: &ECDHSecret, salt: &ProvisioningSalt) -> Self {
+        let key = k1_bytes(secret.as_ref(), salt.as_salt(), b"prsk");
+        let nonce = k1_bytes(secret.as_ref(), salt.as_salt(), b"prsn");
+        Self {
+            key,
+            nonce: Nonce::new(
+                nonce.as_ref()[KEY_LEN - NONCE_LEN..]
+                    .try_into()
+                    .expect("hard coded length"),
+            ),
+        }
+    }
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+    pub fn nonce(&self) -> &Nonce {
+        &self.nonce
+    }
+    /// Decrypts the Provisioning Data (the device side). Returns `ErrorCode::DecryptionFailed` if
+    /// the MIC doesn't match.
+    pub fn decrypt(
+        &self,
+        encrypted: &EncryptedProvisioningData,
+    ) -> Result<ProvisioningData, ErrorCode> {
+        let mut data = *encrypted.data();
+        AESCipher::new(self.key)
+            .ccm_decrypt(&self.nonce, b"", &mut data[..], encrypted.mic())
+            .map_err(|_| ErrorCode::DecryptionFailed)?;
+        ProvisioningData::unpack(&data)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::key::DevKey;
+    use crate::crypto::{hex_16_to_array, Salt, MIC};
+
+    /// Mesh Profile v1.0.1 Sample Data (8.7 Provisioning).
+    fn sample_secret() -> ECDHSecret {
+        let mut secret = [0_u8; 32];
+        secret[..16].copy_from_slice(&hex_16_to_array("ab85843a2f6d883f62e5684b38e30733").unwrap());
+        secret[16..].copy_from_slice(&hex_16_to_array("5fe6e1945ecd19604105c6f23221eb69").unwrap());
+        ECDHSecret::new_bytes(secret)
+    }
+    fn sample_salt() -> ProvisioningSalt {
+        ProvisioningSalt::new(Salt::from_hex("a21c7d45f201cf9489a2fb57145015b4").unwrap())
+    }
+    fn sample_data() -> ProvisioningData {
+        ProvisioningData {
+            net_key: NetKey::from_hex("efb2255e6422d330088e09bb015ed707").unwrap(),
+            net_key_index: NetKeyIndex(KeyIndex::new(0x0567)),
+            key_refresh: KeyRefreshFlag(false),
+            iv_update: IVUpdateFlag(false),
+            iv_index: IVIndex(0x0102_0304),
+            unicast_address: UnicastAddress::new(0x0B0C),
+        }
+    }
+    #[test]
+    fn test_session_keys() {
+        let keys = SessionKeys::new(&sample_secret(), &sample_salt());
+        assert_eq!(
+            keys.key(),
+            &Key::from_hex("c80253af86b33dfa450bbdb2a191fea3").unwrap()
+        );
+        assert_eq!(
+            keys.nonce(),
+            &Nonce::new([
+                0xda, 0x7d, 0xdb, 0xe7, 0x8b, 0x5f, 0x62, 0xb8, 0x1d, 0x68, 0x47, 0x48, 0x7e
+            ])
+        );
+        assert_eq!(
+            DevKey::from_salt_and_secret(sample_salt(), sample_secret()),
+            DevKey::from_hex("0520adad5e0142aa3e325087b4ec16d8").unwrap()
+        );
+    }
+    #[test]
+    fn test_encrypt_provisioning_data() {
+        let keys = SessionKeys::new(&sample_secret(), &sample_salt());
+        let encrypted = sample_data().encrypt(&keys);
+        assert_eq!(
+            &encrypted.data()[..16],
+            &hex_16_to_array("d0bd7f4a89a2ff6222af59a90a60ad58").unwrap()[..]
+        );
+        assert_eq!(
+            &encrypted.data()[16..],
+            &[0xac, 0xfe, 0x31, 0x23, 0x35, 0x6f, 0x5c, 0xec, 0x29][..]
+        );
+        assert_eq!(encrypted.mic(), MIC::Big(0x73e0_ec50_783b_10c7));
+        assert_eq!(keys.decrypt(&encrypted), Ok(sample_data()));
+
+        let tampered = EncryptedProvisioningData::new(*encrypted.data(), MIC::Big(0));
+        assert_eq!(keys.decrypt(&tampered), Err(ErrorCode::DecryptionFailed));
+    }
+    #[test]
+    fn test_pack_flags() {
+        let mut data = sample_data();
+        data.iv_update = IVUpdateFlag(true);
+        let packed = data.pack();
+        assert_eq!(packed[FLAGS_POS], IV_UPDATE_FLAG);
+        assert_eq!(ProvisioningData::unpack(&packed), Ok(data));
+        let mut bad = packed;
+        bad[FLAGS_POS] = 0x04;
+        assert_eq!(
+            ProvisioningData::unpack(&bad),
+            Err(ErrorCode::InvalidFormat)
+        );
+    }
+}
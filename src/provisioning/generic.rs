@@ -1,6 +1,7 @@
 use super::bearer_control;
 
-use btle::bytes::Storage;
+use alloc::vec::Vec;
+use btle::bytes::{StaticBuf, Storage};
 use btle::PackError;
 use core::convert::TryFrom;
 use std::convert::TryInto;
@@ -14,12 +15,23 @@ impl SegmentIndex {
     /// # Panics
     /// Panics if `index` is greater than 6 bits (`index` > `SEGMENT_INDEX_MAX`).
     pub fn new(index: u8) -> SegmentIndex {
-        assert!(index < SEGMENT_INDEX_MAX);
+        assert!(index <= SEGMENT_INDEX_MAX);
         Self(index)
     }
+    pub fn value(self) -> u8 {
+        self.0
+    }
 }
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
 pub struct FCS(u8);
+impl FCS {
+    pub fn new(fcs: u8) -> FCS {
+        FCS(fcs)
+    }
+    pub fn value(self) -> u8 {
+        self.0
+    }
+}
 
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
 pub struct MTU(u8);
@@ -125,7 +137,7 @@ pub fn fcs_calc(data: &[u8]) -> FCS {
     for &b in data {
         fcs = FCS_TABLE[usize::from(fcs ^ b)]
     }
-    FCS(fcs)
+    FCS(0xFF - fcs)
 }
 pub fn fcs_check(fcs: FCS, data: &[u8]) -> bool {
     let mut fcs_check = 0xFF;
@@ -155,6 +167,16 @@ impl TransactionStartPDU {
             fcs,
         }
     }
+    /// Index of the last segment (the number of Transaction Continuations).
+    pub fn seg_n(&self) -> SegmentIndex {
+        self.seg_n
+    }
+    pub fn total_length(&self) -> u16 {
+        self.total_length
+    }
+    pub fn fcs(&self) -> FCS {
+        self.fcs
+    }
     /// Calculates fcs and total length on the `data`. Uses `max_mtu` to calculate `seg_n`.
     /// The returned PDU !DOES NOT! have any data attached to it. Data is contained in the
     /// `Payload` field of `PDU`.
@@ -256,6 +278,15 @@ impl Control {
 }
 pub const GENERIC_PDU_MAX_LEN: usize = 24;
 pub const PAYLOAD_MAX_LEN: usize = 64;
+/// Most payload bytes a Transaction Start PDU can carry.
+pub const START_PAYLOAD_MAX_LEN: usize = GENERIC_PDU_MAX_LEN - TransactionStartPDU::BYTE_LEN;
+/// Most payload bytes a Transaction Continuation PDU can carry.
+pub const CONTINUATION_PAYLOAD_MAX_LEN: usize =
+    GENERIC_PDU_MAX_LEN - TransactionContinuationPDU::BYTE_LEN;
+/// Longest Provisioning PDU that fits in one transaction (a Transaction Start and
+/// `SEGMENT_INDEX_MAX` Transaction Continuations).
+pub const TRANSACTION_MAX_LEN: usize =
+    START_PAYLOAD_MAX_LEN + SEGMENT_INDEX_MAX as usize * CONTINUATION_PAYLOAD_MAX_LEN;
 #[derive(Copy, Clone)]
 pub struct PDU<Buf> {
     pub control: Control,
@@ -330,7 +361,249 @@ impl<T: AsRef<[u8]>> core::fmt::Debug for PDU<T> {
             .finish()
     }
 }
+/// Where segment `seg_i` of a transaction starts in the Provisioning PDU. Every segment except
+/// the last one is as large as it can be.
+fn segment_offset(seg_i: u8) -> usize {
+    match seg_i {
+        0 => 0,
+        i => START_PAYLOAD_MAX_LEN + usize::from(i - 1) * CONTINUATION_PAYLOAD_MAX_LEN,
+    }
+}
+/// Returns the data of segment `seg_i` out of a Provisioning PDU of `total_len` bytes.
+fn segment_range(seg_i: u8, total_len: usize) -> core::ops::Range<usize> {
+    let start = segment_offset(seg_i);
+    let max_len = if seg_i == 0 {
+        START_PAYLOAD_MAX_LEN
+    } else {
+        CONTINUATION_PAYLOAD_MAX_LEN
+    };
+    start..total_len.min(start + max_len)
+}
+/// Splits a Provisioning PDU into a Transaction Start PDU followed by as many Transaction
+/// Continuation PDUs as needed.
 pub struct SegmentGenerator<'a> {
     data: &'a [u8],
     fcs: FCS,
+    seg_n: SegmentIndex,
+    next_seg: u8,
+}
+impl<'a> SegmentGenerator<'a> {
+    /// Returns `None` if `data` is empty or longer than `TRANSACTION_MAX_LEN`.
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        if data.is_empty() || data.len() > TRANSACTION_MAX_LEN {
+            return None;
+        }
+        let continuation_len = data.len().saturating_sub(START_PAYLOAD_MAX_LEN);
+        let seg_n =
+            (continuation_len + CONTINUATION_PAYLOAD_MAX_LEN - 1) / CONTINUATION_PAYLOAD_MAX_LEN;
+        Some(Self {
+            data,
+            fcs: fcs_calc(data),
+            seg_n: SegmentIndex::new(u8::try_from(seg_n).expect("length checked above")),
+            next_seg: 0,
+        })
+    }
+    pub fn seg_n(&self) -> SegmentIndex {
+        self.seg_n
+    }
+    pub fn fcs(&self) -> FCS {
+        self.fcs
+    }
+}
+impl<'a> Iterator for SegmentGenerator<'a> {
+    type Item = PDU<StaticBuf<u8, [u8; GENERIC_PDU_MAX_LEN]>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let seg_i = self.next_seg;
+        if seg_i > self.seg_n.0 {
+            return None;
+        }
+        self.next_seg += 1;
+        let control = if seg_i == 0 {
+            Control::TransactionStart(TransactionStartPDU::new(
+                self.seg_n,
+                u16::try_from(self.data.len()).expect("length checked in new"),
+                self.fcs,
+            ))
+        } else {
+            Control::TransactionContinuation(TransactionContinuationPDU::new(SegmentIndex::new(
+                seg_i,
+            )))
+        };
+        Some(PDU {
+            control,
+            payload: Some(StaticBuf::from_slice(
+                &self.data[segment_range(seg_i, self.data.len())],
+            )),
+        })
+    }
+}
+/// Returned when a segment doesn't fit the transaction being reassembled.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum TransactionError {
+    /// The Transaction Start is longer than `TRANSACTION_MAX_LEN`, needs more segments than its
+    /// `seg_n` or is empty.
+    BadTotalLength,
+    /// The Transaction Continuation's index is higher than `seg_n`.
+    BadSegmentIndex,
+    /// The segment's payload isn't the length its index calls for.
+    BadSegmentLength,
+    /// Not every segment has been received yet.
+    Incomplete,
+    /// The reassembled data doesn't match the FCS in the Transaction Start.
+    BadFCS,
+}
+/// Reassembles a transaction from its Transaction Start and Transaction Continuation PDUs.
+/// Continuations received before the Transaction Start have to be dropped (the sender
+/// retransmits the whole transaction until it's acked).
+#[derive(Clone, Debug)]
+pub struct TransactionReassembler {
+    data: Vec<u8>,
+    start: TransactionStartPDU,
+    /// One bit per received segment.
+    received: u64,
+}
+impl TransactionReassembler {
+    pub fn new(start: &TransactionStartPDU, payload: &[u8]) -> Result<Self, TransactionError> {
+        let total_len = usize::from(start.total_length);
+        // The last segment can't be empty and the segments have to be enough for `total_len`.
+        if total_len > TRANSACTION_MAX_LEN
+            || segment_offset(start.seg_n.0) >= total_len
+            || segment_offset(start.seg_n.0 + 1) < total_len
+        {
+            return Err(TransactionError::BadTotalLength);
+        }
+        let mut reassembler = Self {
+            data: alloc::vec![0_u8; total_len],
+            start: *start,
+            received: 0,
+        };
+        reassembler.insert(0, payload)?;
+        Ok(reassembler)
+    }
+    pub fn start(&self) -> &TransactionStartPDU {
+        &self.start
+    }
+    fn insert(&mut self, seg_i: u8, payload: &[u8]) -> Result<(), TransactionError> {
+        if seg_i > self.start.seg_n.0 {
+            return Err(TransactionError::BadSegmentIndex);
+        }
+        let range = segment_range(seg_i, self.data.len());
+        if range.len() != payload.len() {
+            return Err(TransactionError::BadSegmentLength);
+        }
+        self.data[range].copy_from_slice(payload);
+        self.received |= 1_u64 << seg_i;
+        Ok(())
+    }
+    /// Adds a Transaction Continuation's payload. Duplicates just overwrite the segment.
+    pub fn add_continuation(
+        &mut self,
+        continuation: &TransactionContinuationPDU,
+        payload: &[u8],
+    ) -> Result<(), TransactionError> {
+        self.insert(continuation.seg_i.0, payload)
+    }
+    pub fn is_complete(&self) -> bool {
+        let all = u64::max_value() >> (SEGMENT_INDEX_MAX - self.start.seg_n.0);
+        self.received == all
+    }
+    /// Returns the reassembled Provisioning PDU if every segment was received and the FCS
+    /// matches.
+    pub fn finish(self) -> Result<Vec<u8>, TransactionError> {
+        if !self.is_complete() {
+            return Err(TransactionError::Incomplete);
+        }
+        if !fcs_check(self.start.fcs, &self.data) {
+            return Err(TransactionError::BadFCS);
+        }
+        Ok(self.data)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fcs() {
+        // Provisioning Invite PDU from the Mesh Profile sample data.
+        let invite = [0x00, 0x00];
+        assert_eq!(fcs_calc(&invite), FCS(0x14));
+        assert!(fcs_check(FCS(0x14), &invite));
+        assert!(!fcs_check(FCS(0x15), &invite));
+    }
+    #[test]
+    fn test_segment_round_trip() {
+        for &len in &[1_usize, START_PAYLOAD_MAX_LEN, 21, 65, TRANSACTION_MAX_LEN] {
+            let data = (0..len).map(|i| i as u8).collect::<Vec<u8>>();
+            let mut segments = SegmentGenerator::new(&data).expect("valid length");
+            let first = segments.next().expect("always a Transaction Start");
+            let start = match first.control {
+                Control::TransactionStart(start) => start,
+                _ => panic!("first segment must be a Transaction Start"),
+            };
+            let mut reassembler = TransactionReassembler::new(
+                &start,
+                first.payload.as_ref().expect("payload").as_ref(),
+            )
+            .expect("valid Transaction Start");
+            // Deliver the continuations backwards.
+            let continuations = segments.collect::<Vec<_>>();
+            assert_eq!(continuations.len(), usize::from(start.seg_n().value()));
+            for pdu in continuations.iter().rev() {
+                assert!(!reassembler.is_complete());
+                assert!(pdu.byte_len() <= GENERIC_PDU_MAX_LEN);
+                match pdu.control {
+                    Control::TransactionContinuation(continuation) => reassembler
+                        .add_continuation(
+                            &continuation,
+                            pdu.payload.as_ref().expect("payload").as_ref(),
+                        )
+                        .expect("valid continuation"),
+                    _ => panic!("only the first segment is a Transaction Start"),
+                }
+            }
+            assert_eq!(reassembler.finish(), Ok(data));
+        }
+        assert!(SegmentGenerator::new(&[]).is_none());
+        assert!(SegmentGenerator::new(&[0_u8; TRANSACTION_MAX_LEN + 1]).is_none());
+    }
+    #[test]
+    fn test_bad_transaction() {
+        let data = [0x01_u8; 30];
+        let start = TransactionStartPDU::new(SegmentIndex::new(1), 30, fcs_calc(&data));
+        let mut reassembler =
+            TransactionReassembler::new(&start, &data[..START_PAYLOAD_MAX_LEN]).expect("valid");
+        assert_eq!(
+            reassembler.add_continuation(
+                &TransactionContinuationPDU::new(SegmentIndex::new(2)),
+                &data[START_PAYLOAD_MAX_LEN..]
+            ),
+            Err(TransactionError::BadSegmentIndex)
+        );
+        assert_eq!(
+            reassembler.add_continuation(
+                &TransactionContinuationPDU::new(SegmentIndex::new(1)),
+                &data[START_PAYLOAD_MAX_LEN + 1..]
+            ),
+            Err(TransactionError::BadSegmentLength)
+        );
+        assert_eq!(
+            reassembler.clone().finish(),
+            Err(TransactionError::Incomplete)
+        );
+        reassembler
+            .add_continuation(
+                &TransactionContinuationPDU::new(SegmentIndex::new(1)),
+                &[0x02_u8; 10],
+            )
+            .expect("valid continuation");
+        assert_eq!(reassembler.finish(), Err(TransactionError::BadFCS));
+        // 30 bytes can't fit in a lone Transaction Start.
+        let lone = TransactionStartPDU::new(SegmentIndex::new(0), 30, fcs_calc(&data));
+        assert_eq!(
+            TransactionReassembler::new(&lone, &data[..START_PAYLOAD_MAX_LEN]).err(),
+            Some(TransactionError::BadTotalLength)
+        );
+    }
 }
@@ -1,6 +1,8 @@
+use crate::provisioning::bearer_control::{self, LinkOpen};
+use crate::provisioning::generic;
 use crate::provisioning::pb_adv;
 use crate::provisioning::pb_adv::{LinkID, TransactionNumber};
-use alloc::collections::BTreeSet;
+use crate::uuid::UUID;
 use core::sync::atomic::Ordering;
 #[derive(Debug)]
 pub struct AtomicTransactionNumber(core::sync::atomic::AtomicU8);
@@ -36,27 +38,128 @@ impl Ord for AtomicTransactionNumber {
         self.get().cmp(&other.get())
     }
 }
-pub struct Links {
-    links: BTreeSet<Link>,
+/// What to do after a Link Open.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum LinkOpenResponse {
+    /// The link was opened. Send a Link Ack.
+    Opened,
+    /// Retransmitted Link Open for the open link. Send a Link Ack again but leave the link (and
+    /// any provisioning in progress) alone.
+    AlreadyOpen,
+    /// Not for this link (another device's UUID or another link ID while our link is open).
+    /// Don't respond.
+    Ignored,
 }
-impl Links {
-    pub fn handle_pb_adv_pdu(&mut self, _pdu: &pb_adv::PDU) {
-        unimplemented!()
-    }
-}
-pub enum LinkError {
-    Closed,
+/// One PB-ADV link as seen by the device being provisioned. Tracks the device UUID (to know
+/// which Link Opens are for us) and the Link ID of the open link (if any) so retransmitted
+/// Link Opens don't reset a provisioning in progress.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct PBAdvLink {
+    device_uuid: UUID,
+    link_id: Option<LinkID>,
 }
-#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Debug)]
-pub struct Link {
-    link_id: LinkID,
-    my_transaction_number: AtomicTransactionNumber,
-    other_transaction_number: Option<AtomicTransactionNumber>,
-}
-impl Link {
-    pub fn handle_pb_adv_pdu(&self, pdu: &pb_adv::PDU) {
-        if pdu.link_id != self.link_id {
-            return;
+impl PBAdvLink {
+    pub fn new(device_uuid: UUID) -> Self {
+        Self {
+            device_uuid,
+            link_id: None,
+        }
+    }
+    pub fn device_uuid(&self) -> &UUID {
+        &self.device_uuid
+    }
+    /// Returns the Link ID of the open link or `None` if the link is closed.
+    pub fn link_id(&self) -> Option<LinkID> {
+        self.link_id
+    }
+    pub fn is_open(&self) -> bool {
+        self.link_id.is_some()
+    }
+    /// Handles a Link Open for `link_id`. Only a Link Open with our UUID opens the link and,
+    /// once open, only the same Link ID is acked again.
+    pub fn handle_link_open(&mut self, link_id: LinkID, link_open: &LinkOpen) -> LinkOpenResponse {
+        if *link_open.uuid() != self.device_uuid {
+            return LinkOpenResponse::Ignored;
         }
+        match self.link_id {
+            None => {
+                self.link_id = Some(link_id);
+                LinkOpenResponse::Opened
+            }
+            Some(open_id) if open_id == link_id => LinkOpenResponse::AlreadyOpen,
+            Some(_) => LinkOpenResponse::Ignored,
+        }
+    }
+    /// Closes the link if `link_id` is the open link. Returns if the link was closed.
+    pub fn handle_link_close(&mut self, link_id: LinkID) -> bool {
+        if self.link_id == Some(link_id) {
+            self.link_id = None;
+            true
+        } else {
+            false
+        }
+    }
+    /// Handles the Link Open in `pdu` (if it has one). Returns `None` for any other PDU.
+    pub fn handle_pb_adv_pdu(&mut self, pdu: &pb_adv::PDU) -> Option<LinkOpenResponse> {
+        match &pdu.generic_pdu.control {
+            generic::Control::BearerControl(bearer_control::PDU::LinkOpen(link_open)) => {
+                Some(self.handle_link_open(pdu.link_id, link_open))
+            }
+            _ => None,
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_link_open() {
+        let uuid = UUID([0x01; 16]);
+        let mut link = PBAdvLink::new(uuid);
+        let link_id = LinkID::new(0x1234_5678);
+        assert_eq!(
+            link.handle_link_open(link_id, &LinkOpen::new(uuid)),
+            LinkOpenResponse::Opened
+        );
+        // A retransmission is acked again without reopening the link.
+        assert_eq!(
+            link.handle_link_open(link_id, &LinkOpen::new(uuid)),
+            LinkOpenResponse::AlreadyOpen
+        );
+        assert_eq!(link.link_id(), Some(link_id));
+    }
+    #[test]
+    fn test_conflicting_link_open() {
+        let uuid = UUID([0x01; 16]);
+        let mut link = PBAdvLink::new(uuid);
+        let link_id = LinkID::new(0x1234_5678);
+        // Link Opens for other devices never open the link.
+        assert_eq!(
+            link.handle_link_open(link_id, &LinkOpen::new(UUID([0x02; 16]))),
+            LinkOpenResponse::Ignored
+        );
+        assert!(!link.is_open());
+
+        link.handle_link_open(link_id, &LinkOpen::new(uuid));
+        // Another device's UUID while the link is open.
+        assert_eq!(
+            link.handle_link_open(LinkID::new(0x0BAD), &LinkOpen::new(UUID([0x02; 16]))),
+            LinkOpenResponse::Ignored
+        );
+        // Our UUID but another provisioner's Link ID.
+        assert_eq!(
+            link.handle_link_open(LinkID::new(0x0BAD), &LinkOpen::new(uuid)),
+            LinkOpenResponse::Ignored
+        );
+        assert_eq!(link.link_id(), Some(link_id));
+
+        // Once closed, a new link can be opened.
+        assert!(!link.handle_link_close(LinkID::new(0x0BAD)));
+        assert!(link.handle_link_close(link_id));
+        assert_eq!(
+            link.handle_link_open(LinkID::new(0x0BAD), &LinkOpen::new(uuid)),
+            LinkOpenResponse::Opened
+        );
     }
 }
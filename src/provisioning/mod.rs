@@ -2,12 +2,16 @@
 //! Provisioning is Big Endian.
 
 pub mod beacons;
+#[cfg(feature = "full_stack")]
 pub mod bearer;
 pub mod bearer_control;
 pub mod confirmation;
+pub mod data;
 pub mod generic;
 pub mod link;
 pub mod pb_adv;
 pub mod pb_gatt;
 pub mod protocol;
 pub mod provisioner;
+#[cfg(feature = "full_stack")]
+pub mod session;
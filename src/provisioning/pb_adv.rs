@@ -10,7 +10,7 @@ pub struct LinkID(u32);
 
 impl LinkID {
     pub const BYTE_LEN: usize = 4;
-    pub fn new(link_id: u32) -> LinkID {
+    pub const fn new(link_id: u32) -> LinkID {
         LinkID(link_id)
     }
     pub fn value(self) -> u32 {
@@ -74,11 +74,13 @@ impl From<TransactionNumber> for u8 {
         num.0
     }
 }
+/// Buffer for the payload of a Generic Provisioning PDU in a PB-ADV PDU.
+pub type GenericPayload = StaticBuf<u8, [u8; GENERIC_PDU_MAX_LEN]>;
 #[derive(Copy, Clone, Debug)]
 pub struct PDU {
     pub link_id: LinkID,
     pub transaction_number: TransactionNumber,
-    pub generic_pdu: generic::PDU<StaticBuf<u8, [u8; GENERIC_PDU_MAX_LEN]>>,
+    pub generic_pdu: generic::PDU<GenericPayload>,
 }
 impl PDU {
     pub const HEADER_BYTE_LEN: usize = LinkID::BYTE_LEN + TransactionNumber::BYTE_LEN;
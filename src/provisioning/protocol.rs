@@ -90,6 +90,37 @@ impl PDU {
             }
         }
     }
+    /// Length of the PDU with its leading `Opcode` byte.
+    pub fn byte_len(&self) -> usize {
+        1 + match self {
+            PDU::Invite(_) => Invite::BYTE_LEN,
+            PDU::Capabilities(_) => Capabilities::BYTE_LEN,
+            PDU::Start(_) => Start::BYTE_LEN,
+            PDU::PublicKey(_) => PublicKey::BYTE_LEN,
+            PDU::InputComplete(_) => InputComplete::BYTE_LEN,
+            PDU::Confirm(_) => Confirmation::BYTE_LEN,
+            PDU::Random(_) => Random::BYTE_LEN,
+            PDU::Data(_) => EncryptedProvisioningData::BYTE_LEN,
+            PDU::Complete(_) => Complete::BYTE_LEN,
+            PDU::Failed(_) => Failed::BYTE_LEN,
+        }
+    }
+    /// Packs the PDU with its leading `Opcode` byte (the counterpart of `PDU::unpack_from`).
+    pub fn pack_into(&self, buf: &mut [u8]) -> Result<(), ProtocolPDUError> {
+        if buf.len() != self.byte_len() {
+            return Err(ProtocolPDUError::BadLength);
+        }
+        let opcode = self.pack(&mut buf[1..])?;
+        buf[0] = opcode.into();
+        Ok(())
+    }
+    /// Unpacks a PDU with its leading `Opcode` byte.
+    pub fn unpack_from(buf: &[u8]) -> Result<PDU, ProtocolPDUError> {
+        match buf.split_first() {
+            Some((&opcode, rest)) => PDU::unpack(Opcode::try_from(opcode)?, rest),
+            None => Err(ProtocolPDUError::BadLength),
+        }
+    }
     pub fn unpack(opcode: Opcode, buf: &[u8]) -> Result<PDU, ProtocolPDUError> {
         match opcode {
             Opcode::Invite => Ok(PDU::Invite(Invite::unpack(buf)?)),
@@ -298,6 +329,9 @@ impl OutputOOBOptions {
     pub const fn is_zero(self) -> bool {
         self.0 == 0
     }
+    pub fn supports(self, action: OutputOOBAction) -> bool {
+        self.0 & (1_u16 << u8::from(action)) != 0
+    }
 }
 #[derive(Copy, Clone, PartialOrd, PartialEq, Ord, Eq, Debug, Hash)]
 pub struct OOBSize(u8);
@@ -394,6 +428,9 @@ impl InputOOBOptions {
     pub const fn is_zero(self) -> bool {
         self.0 == 0
     }
+    pub fn supports(self, action: InputOOBAction) -> bool {
+        self.0 & (1_u16 << u8::from(action)) != 0
+    }
 }
 #[derive(Copy, Clone, PartialOrd, PartialEq, Ord, Eq, Debug, Hash)]
 pub struct Capabilities {
@@ -406,6 +443,34 @@ pub struct Capabilities {
     input_oob_size: Option<OOBSize>,
     input_oob_action: InputOOBOptions,
 }
+impl Capabilities {
+    pub fn num_elements(&self) -> ElementCount {
+        self.num_elements
+    }
+    pub fn algorithms(&self) -> Algorithms {
+        self.algorithms
+    }
+    pub fn pub_key_option(&self) -> PublicKeyOption {
+        self.pub_key_option
+    }
+    pub fn static_oob_option(&self) -> StaticOOBOption {
+        self.static_oob_option
+    }
+    /// Largest value the device can output (`None` if it doesn't support Output OOB).
+    pub fn output_oob_size(&self) -> Option<OOBSize> {
+        self.output_oob_size
+    }
+    pub fn output_oob_action(&self) -> OutputOOBOptions {
+        self.output_oob_action
+    }
+    /// Largest value the device can input (`None` if it doesn't support Input OOB).
+    pub fn input_oob_size(&self) -> Option<OOBSize> {
+        self.input_oob_size
+    }
+    pub fn input_oob_action(&self) -> InputOOBOptions {
+        self.input_oob_action
+    }
+}
 impl ProtocolPDU for Capabilities {
     const OPCODE: Opcode = Opcode::Capabilities;
 
@@ -486,6 +551,20 @@ pub struct EncryptedProvisioningData {
     data: [u8; ENCRYPTED_PROVISIONING_DATA_LEN],
     mic: MIC,
 }
+impl EncryptedProvisioningData {
+    /// # Panics
+    /// Panics if `mic` isn't a big (64-bit) MIC.
+    pub fn new(data: [u8; ENCRYPTED_PROVISIONING_DATA_LEN], mic: MIC) -> Self {
+        assert!(mic.is_big(), "provisioning data uses a 64-bit MIC");
+        Self { data, mic }
+    }
+    pub fn data(&self) -> &[u8; ENCRYPTED_PROVISIONING_DATA_LEN] {
+        &self.data
+    }
+    pub fn mic(&self) -> MIC {
+        self.mic
+    }
+}
 impl ProtocolPDU for EncryptedProvisioningData {
     const OPCODE: Opcode = Opcode::Data;
 
@@ -524,6 +603,28 @@ pub struct Start {
     public_key_type: PublicKeyType,
     auth_method: AuthenticationMethod,
 }
+impl Start {
+    pub fn new(
+        algorithm: AlgorithmsFlags,
+        public_key_type: PublicKeyType,
+        auth_method: AuthenticationMethod,
+    ) -> Self {
+        Self {
+            algorithm,
+            public_key_type,
+            auth_method,
+        }
+    }
+    pub fn algorithm(&self) -> AlgorithmsFlags {
+        self.algorithm
+    }
+    pub fn public_key_type(&self) -> PublicKeyType {
+        self.public_key_type
+    }
+    pub fn auth_method(&self) -> AuthenticationMethod {
+        self.auth_method
+    }
+}
 impl ProtocolPDU for Start {
     const OPCODE: Opcode = Opcode::Start;
 
@@ -634,6 +735,30 @@ impl TryFrom<u8> for ErrorCode {
         }
     }
 }
+impl From<ProtocolPDUError> for ErrorCode {
+    /// The `ErrorCode` to fail the link with if an incoming PDU can't be unpacked.
+    fn from(e: ProtocolPDUError) -> Self {
+        match e {
+            ProtocolPDUError::BadOpcode => ErrorCode::InvalidPDU,
+            ProtocolPDUError::BadBytes | ProtocolPDUError::BadLength => ErrorCode::InvalidFormat,
+            ProtocolPDUError::BadState => ErrorCode::UnexpectedError,
+        }
+    }
+}
+impl core::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            ErrorCode::InvalidPDU => "invalid PDU",
+            ErrorCode::InvalidFormat => "invalid format",
+            ErrorCode::UnexpectedPDU => "unexpected PDU",
+            ErrorCode::ConfirmationFailed => "confirmation failed",
+            ErrorCode::OutOfResources => "out of resources",
+            ErrorCode::DecryptionFailed => "decryption failed",
+            ErrorCode::UnexpectedError => "unexpected error",
+            ErrorCode::CannotAssignAddress => "cannot assign addresses",
+        })
+    }
+}
 #[derive(Copy, Clone, PartialOrd, PartialEq, Ord, Eq, Debug, Hash)]
 pub struct Complete();
 impl ProtocolPDU for Complete {
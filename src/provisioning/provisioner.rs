@@ -1 +1,573 @@
+//! Provisioner side of the Provisioning Protocol. [`Provisioner`] tracks which PDU the device
+//! should send next and fails the link with the matching `ErrorCode` as soon as something goes
+//! wrong. Sending the PDUs (and the crypto behind them) is left to the caller (`session::provision`
+//! does both over a PB-ADV link).
+//! [`ProvisioningLinks`] runs several of them at once (one per PB-ADV link).
+use crate::address::UnicastAddress;
+use crate::mesh::ElementCount;
+use crate::provisioning::pb_adv::LinkID;
+use crate::provisioning::protocol::{self, ErrorCode, PDU};
+use alloc::collections::BTreeMap;
+use core::convert::TryFrom;
+use subtle::ConstantTimeEq;
 
+/// Where a [`Provisioner`] is in the Provisioning Protocol. Each waiting state is named after
+/// the PDU expected from the device.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum State {
+    /// Invite sent. Waiting for the device's Capabilities.
+    WaitingCapabilities,
+    /// Start and our Public Key sent. Waiting for the device's Public Key.
+    WaitingPublicKey,
+    /// Waiting for the device to finish Input OOB.
+    WaitingInputComplete,
+    /// Our Confirmation sent. Waiting for the device's Confirmation.
+    WaitingConfirmation,
+    /// Our Random sent. Waiting for the device's Random.
+    WaitingRandom,
+    /// Device Random received. Waiting for the caller to [`Provisioner::check_confirmation`].
+    CheckingConfirmation(protocol::Random),
+    /// Provisioning Data sent. Waiting for Complete.
+    WaitingComplete,
+    /// Terminal. The device is provisioned.
+    Complete,
+    /// Terminal. We failed the link and a Provisioning Failed PDU with this code must be sent
+    /// before closing it.
+    Failed(ErrorCode),
+    /// Terminal. The device failed the link. Nothing is sent back.
+    RemoteFailed(ErrorCode),
+}
+impl State {
+    pub fn is_terminal(&self) -> bool {
+        match self {
+            State::Complete | State::Failed(_) | State::RemoteFailed(_) => true,
+            _ => false,
+        }
+    }
+    /// Returns the `ErrorCode` if provisioning failed on either side.
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        match self {
+            State::Failed(code) | State::RemoteFailed(code) => Some(*code),
+            _ => None,
+        }
+    }
+}
+/// Provisioning Protocol state machine for the provisioner.
+#[derive(Copy, Clone, Debug)]
+pub struct Provisioner {
+    state: State,
+    primary_address: UnicastAddress,
+    element_count: Option<ElementCount>,
+    input_oob: bool,
+    device_confirmation: Option<protocol::Confirmation>,
+}
+impl Provisioner {
+    /// Starts a new provisioning session after an Invite has been sent. `primary_address` is
+    /// the first unicast address the device would get.
+    pub fn new(primary_address: UnicastAddress) -> Self {
+        Self {
+            state: State::WaitingCapabilities,
+            primary_address,
+            element_count: None,
+            input_oob: false,
+            device_confirmation: None,
+        }
+    }
+    /// Set if the Start PDU picked Input OOB authentication so an Input Complete is expected
+    /// before the Confirmations.
+    pub fn with_input_oob(mut self, input_oob: bool) -> Self {
+        self.input_oob = input_oob;
+        self
+    }
+    pub fn state(&self) -> State {
+        self.state
+    }
+    /// Element count from the device's Capabilities.
+    pub fn element_count(&self) -> Option<ElementCount> {
+        self.element_count
+    }
+    /// First unicast address the device gets.
+    pub fn primary_address(&self) -> UnicastAddress {
+        self.primary_address
+    }
+    /// Changes the address the device gets. The device's Capabilities are checked against it so
+    /// it should be set before they're handled.
+    pub fn assign_primary_address(&mut self, primary_address: UnicastAddress) {
+        self.primary_address = primary_address;
+    }
+    /// Returns the Provisioning Failed PDU to send if we failed the link.
+    pub fn failed_pdu(&self) -> Option<protocol::Failed> {
+        match self.state {
+            State::Failed(code) => Some(protocol::Failed(code)),
+            _ => None,
+        }
+    }
+    /// Fails the link with `code` (for example `OutOfResources` if the caller can't store the
+    /// new node). Does nothing if already in a terminal state.
+    pub fn fail(&mut self, code: ErrorCode) -> ErrorCode {
+        if !self.state.is_terminal() {
+            self.state = State::Failed(code);
+        }
+        code
+    }
+    /// Handles a raw Provisioning PDU (including the opcode byte) from the device. Malformed PDUs
+    /// fail the link with `InvalidPDU` or `InvalidFormat`.
+    pub fn handle_pdu_bytes(&mut self, buf: &[u8]) -> Result<State, ErrorCode> {
+        if let Some(code) = self.terminal_error() {
+            return Err(code);
+        }
+        match PDU::unpack_from(buf) {
+            Ok(pdu) => self.handle_pdu(&pdu),
+            Err(e) => Err(self.fail(e.into())),
+        }
+    }
+    /// Handles a Provisioning PDU from the device. Any PDU that isn't expected in the current
+    /// state fails the link with `UnexpectedPDU`.
+    pub fn handle_pdu(&mut self, pdu: &PDU) -> Result<State, ErrorCode> {
+        if let Some(code) = self.terminal_error() {
+            return Err(code);
+        }
+        self.state = match (self.state, pdu) {
+            (_, PDU::Failed(failed)) => {
+                self.state = State::RemoteFailed(failed.0);
+                return Err(failed.0);
+            }
+            (State::WaitingCapabilities, PDU::Capabilities(capabilities)) => {
+                let count = capabilities.num_elements();
+                if !self.can_assign(count) {
+                    return Err(self.fail(ErrorCode::CannotAssignAddress));
+                }
+                self.element_count = Some(count);
+                State::WaitingPublicKey
+            }
+            (State::WaitingPublicKey, PDU::PublicKey(_)) => {
+                if self.input_oob {
+                    State::WaitingInputComplete
+                } else {
+                    State::WaitingConfirmation
+                }
+            }
+            (State::WaitingInputComplete, PDU::InputComplete(_)) => State::WaitingConfirmation,
+            (State::WaitingConfirmation, PDU::Confirm(confirmation)) => {
+                self.device_confirmation = Some(*confirmation);
+                State::WaitingRandom
+            }
+            (State::WaitingRandom, PDU::Random(random)) => State::CheckingConfirmation(*random),
+            (State::WaitingComplete, PDU::Complete(_)) => State::Complete,
+            _ => return Err(self.fail(ErrorCode::UnexpectedPDU)),
+        };
+        Ok(self.state)
+    }
+    /// Compares the device's Confirmation to `expected` (computed by the caller from the device
+    /// Random in `State::CheckingConfirmation`). A mismatch fails the link with
+    /// `ConfirmationFailed`.
+    pub fn check_confirmation(
+        &mut self,
+        expected: &protocol::Confirmation,
+    ) -> Result<State, ErrorCode> {
+        if let Some(code) = self.terminal_error() {
+            return Err(code);
+        }
+        match (self.state, self.device_confirmation) {
+            (State::CheckingConfirmation(_), Some(confirmation)) => {
+                if bool::from(confirmation.0[..].ct_eq(&expected.0[..])) {
+                    self.state = State::WaitingComplete;
+                    Ok(self.state)
+                } else {
+                    Err(self.fail(ErrorCode::ConfirmationFailed))
+                }
+            }
+            _ => Err(self.fail(ErrorCode::UnexpectedError)),
+        }
+    }
+    fn terminal_error(&self) -> Option<ErrorCode> {
+        match self.state {
+            State::Complete => Some(ErrorCode::UnexpectedPDU),
+            state => state.error_code(),
+        }
+    }
+    fn can_assign(&self, count: ElementCount) -> bool {
+        u16::from(self.primary_address)
+            .checked_add(u16::from(count.0))
+            .and_then(|end| UnicastAddress::try_from(end - 1).ok())
+            .is_some()
+    }
+}
+/// Hands out consecutive unicast address ranges (one per provisioned device) so concurrent
+/// provisionings never overlap. Addresses given to failed provisionings aren't reused.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct AddressAllocator {
+    next: Option<UnicastAddress>,
+}
+impl AddressAllocator {
+    pub fn new(first_address: UnicastAddress) -> Self {
+        Self {
+            next: Some(first_address),
+        }
+    }
+    /// Returns the next address to hand out or `None` if every unicast address is used.
+    pub fn next_address(&self) -> Option<UnicastAddress> {
+        self.next
+    }
+    /// Reserves `count` consecutive addresses and returns the first one. Returns `None` (and
+    /// reserves nothing) if they don't fit in the unicast range or `count` is 0.
+    pub fn allocate(&mut self, count: ElementCount) -> Option<UnicastAddress> {
+        let primary = self.next?;
+        if count.0 == 0 {
+            return None;
+        }
+        let end = u16::from(primary).checked_add(u16::from(count.0))?;
+        UnicastAddress::try_from(end - 1).ok()?;
+        self.next = UnicastAddress::try_from(end).ok();
+        Some(primary)
+    }
+}
+/// Default number of devices provisioned at the same time. Every link shares the radio so more
+/// links mostly means slower links.
+pub const DEFAULT_MAX_LINKS: usize = 4;
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum LinksError {
+    /// Already provisioning `max_links` devices.
+    TooManyLinks,
+    /// The Link ID is already used by another provisioning.
+    LinkIDInUse,
+    /// Every unicast address is already assigned so the device couldn't get one.
+    NoAddressLeft,
+}
+/// Concurrent provisionings over PB-ADV. Each link (Link ID) runs its own [`Provisioner`] and
+/// gets its addresses from the shared [`AddressAllocator`] once its device's Capabilities
+/// (and so its element count) are known.
+#[derive(Clone, Debug)]
+pub struct ProvisioningLinks {
+    links: BTreeMap<LinkID, Provisioner>,
+    allocator: AddressAllocator,
+    max_links: usize,
+}
+impl ProvisioningLinks {
+    /// # Panics
+    /// Panics if `max_links == 0`.
+    pub fn new(allocator: AddressAllocator, max_links: usize) -> Self {
+        assert_ne!(max_links, 0, "zero max_links");
+        Self {
+            links: BTreeMap::new(),
+            allocator,
+            max_links,
+        }
+    }
+    pub fn allocator(&self) -> &AddressAllocator {
+        &self.allocator
+    }
+    pub fn max_links(&self) -> usize {
+        self.max_links
+    }
+    /// Returns the number of links (finished or not) currently held.
+    pub fn len(&self) -> usize {
+        self.links.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.links.is_empty()
+    }
+    /// Starts provisioning over `link_id` after its Invite has been sent. Fails with
+    /// `NoAddressLeft` if the allocator has no unicast address left to hand out.
+    pub fn open(&mut self, link_id: LinkID, input_oob: bool) -> Result<(), LinksError> {
+        if self.links.contains_key(&link_id) {
+            return Err(LinksError::LinkIDInUse);
+        }
+        if self.links.len() >= self.max_links {
+            return Err(LinksError::TooManyLinks);
+        }
+        // The real address is assigned when the Capabilities come in.
+        let tentative = self
+            .allocator
+            .next_address()
+            .ok_or(LinksError::NoAddressLeft)?;
+        self.links.insert(
+            link_id,
+            Provisioner::new(tentative).with_input_oob(input_oob),
+        );
+        Ok(())
+    }
+    pub fn get(&self, link_id: LinkID) -> Option<&Provisioner> {
+        self.links.get(&link_id)
+    }
+    /// For calls like [`Provisioner::check_confirmation`].
+    pub fn get_mut(&mut self, link_id: LinkID) -> Option<&mut Provisioner> {
+        self.links.get_mut(&link_id)
+    }
+    /// Handles a Provisioning PDU from the device on `link_id`. Returns `None` if the link isn't
+    /// ours. The Capabilities reserve the device's addresses and fail the link with
+    /// `CannotAssignAddress` if there aren't enough left.
+    pub fn handle_pdu(&mut self, link_id: LinkID, pdu: &PDU) -> Option<Result<State, ErrorCode>> {
+        let provisioner = self.links.get_mut(&link_id)?;
+        if let (State::WaitingCapabilities, PDU::Capabilities(capabilities)) =
+            (provisioner.state(), pdu)
+        {
+            match self.allocator.allocate(capabilities.num_elements()) {
+                Some(primary) => provisioner.assign_primary_address(primary),
+                None => return Some(Err(provisioner.fail(ErrorCode::CannotAssignAddress))),
+            }
+        }
+        Some(provisioner.handle_pdu(pdu))
+    }
+    /// Removes the link (once it's closed) and returns its `Provisioner`.
+    pub fn close(&mut self, link_id: LinkID) -> Option<Provisioner> {
+        self.links.remove(&link_id)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provisioning::protocol::{Complete, Confirmation, PublicKey, Random};
+
+    const CAPABILITIES_OPCODE: u8 = 0x01;
+
+    fn capabilities(num_elements: u8) -> PDU {
+        PDU::unpack_from(&[
+            CAPABILITIES_OPCODE,
+            num_elements,
+            0x00,
+            0x01,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+        ])
+        .expect("valid capabilities")
+    }
+    fn public_key() -> PDU {
+        PDU::PublicKey(PublicKey {
+            x: [0x11; 32],
+            y: [0x22; 32],
+        })
+    }
+    /// Runs a provisioner up to `State::CheckingConfirmation` with a device Confirmation of `0xAA`s.
+    fn checking_confirmation() -> Provisioner {
+        let mut provisioner = Provisioner::new(UnicastAddress::new(0x0010));
+        provisioner
+            .handle_pdu(&capabilities(2))
+            .expect("capabilities");
+        provisioner.handle_pdu(&public_key()).expect("public key");
+        provisioner
+            .handle_pdu(&PDU::Confirm(Confirmation([0xAA; 16])))
+            .expect("confirmation");
+        provisioner
+            .handle_pdu(&PDU::Random(Random([0xBB; 16])))
+            .expect("random");
+        provisioner
+    }
+    #[test]
+    fn test_success() {
+        let mut provisioner = checking_confirmation();
+        assert_eq!(
+            provisioner.state(),
+            State::CheckingConfirmation(Random([0xBB; 16]))
+        );
+        assert_eq!(
+            provisioner.check_confirmation(&Confirmation([0xAA; 16])),
+            Ok(State::WaitingComplete)
+        );
+        assert_eq!(
+            provisioner.handle_pdu(&PDU::Complete(Complete())),
+            Ok(State::Complete)
+        );
+        assert_eq!(provisioner.element_count(), Some(ElementCount(2)));
+        assert_eq!(provisioner.failed_pdu(), None);
+    }
+    #[test]
+    fn test_invalid_pdu() {
+        let mut provisioner = Provisioner::new(UnicastAddress::new(0x0010));
+        assert_eq!(
+            provisioner.handle_pdu_bytes(&[0x0A]),
+            Err(ErrorCode::InvalidPDU)
+        );
+        assert_eq!(
+            provisioner.failed_pdu(),
+            Some(protocol::Failed(ErrorCode::InvalidPDU))
+        );
+    }
+    #[test]
+    fn test_invalid_format() {
+        let mut provisioner = Provisioner::new(UnicastAddress::new(0x0010));
+        assert_eq!(
+            provisioner.handle_pdu_bytes(&[CAPABILITIES_OPCODE, 0x01]),
+            Err(ErrorCode::InvalidFormat)
+        );
+        assert_eq!(provisioner.state(), State::Failed(ErrorCode::InvalidFormat));
+    }
+    #[test]
+    fn test_unexpected_pdu() {
+        let mut provisioner = Provisioner::new(UnicastAddress::new(0x0010));
+        assert_eq!(
+            provisioner.handle_pdu(&public_key()),
+            Err(ErrorCode::UnexpectedPDU)
+        );
+        // Failed is terminal.
+        assert_eq!(
+            provisioner.handle_pdu(&capabilities(1)),
+            Err(ErrorCode::UnexpectedPDU)
+        );
+        assert_eq!(provisioner.state(), State::Failed(ErrorCode::UnexpectedPDU));
+    }
+    #[test]
+    fn test_confirmation_failed() {
+        let mut provisioner = checking_confirmation();
+        assert_eq!(
+            provisioner.check_confirmation(&Confirmation([0xAB; 16])),
+            Err(ErrorCode::ConfirmationFailed)
+        );
+        assert_eq!(
+            provisioner.failed_pdu(),
+            Some(protocol::Failed(ErrorCode::ConfirmationFailed))
+        );
+    }
+    #[test]
+    fn test_out_of_resources() {
+        let mut provisioner = Provisioner::new(UnicastAddress::new(0x0010));
+        provisioner
+            .handle_pdu(&capabilities(1))
+            .expect("capabilities");
+        provisioner.fail(ErrorCode::OutOfResources);
+        assert_eq!(
+            provisioner.failed_pdu(),
+            Some(protocol::Failed(ErrorCode::OutOfResources))
+        );
+    }
+    #[test]
+    fn test_decryption_failed_remote() {
+        let mut provisioner = checking_confirmation();
+        provisioner
+            .check_confirmation(&Confirmation([0xAA; 16]))
+            .expect("matching confirmation");
+        assert_eq!(
+            provisioner.handle_pdu(&PDU::Failed(protocol::Failed(ErrorCode::DecryptionFailed))),
+            Err(ErrorCode::DecryptionFailed)
+        );
+        assert_eq!(
+            provisioner.state(),
+            State::RemoteFailed(ErrorCode::DecryptionFailed)
+        );
+        // The device failed the link so we don't send anything back.
+        assert_eq!(provisioner.failed_pdu(), None);
+    }
+    #[test]
+    fn test_unexpected_error() {
+        let mut provisioner = Provisioner::new(UnicastAddress::new(0x0010));
+        assert_eq!(
+            provisioner.check_confirmation(&Confirmation([0xAA; 16])),
+            Err(ErrorCode::UnexpectedError)
+        );
+    }
+    #[test]
+    fn test_cannot_assign_address() {
+        let mut provisioner = Provisioner::new(UnicastAddress::new(0x7FFE));
+        assert_eq!(
+            provisioner.handle_pdu(&capabilities(3)),
+            Err(ErrorCode::CannotAssignAddress)
+        );
+        let mut provisioner = Provisioner::new(UnicastAddress::new(0x7FFE));
+        assert_eq!(
+            provisioner.handle_pdu(&capabilities(2)),
+            Ok(State::WaitingPublicKey)
+        );
+    }
+    #[test]
+    fn test_input_oob() {
+        let mut provisioner = Provisioner::new(UnicastAddress::new(0x0010)).with_input_oob(true);
+        provisioner
+            .handle_pdu(&capabilities(1))
+            .expect("capabilities");
+        assert_eq!(
+            provisioner.handle_pdu(&public_key()),
+            Ok(State::WaitingInputComplete)
+        );
+        assert_eq!(
+            provisioner.handle_pdu(&PDU::Confirm(Confirmation([0xAA; 16]))),
+            Err(ErrorCode::UnexpectedPDU)
+        );
+    }
+    #[test]
+    fn test_concurrent_links() {
+        let mut links =
+            ProvisioningLinks::new(AddressAllocator::new(UnicastAddress::new(0x0010)), 2);
+        let a = LinkID::new(0xA);
+        let b = LinkID::new(0xB);
+        links.open(a, false).expect("first link");
+        links.open(b, false).expect("second link");
+        assert_eq!(links.open(b, false), Err(LinksError::LinkIDInUse));
+        assert_eq!(
+            links.open(LinkID::new(0xC), false),
+            Err(LinksError::TooManyLinks)
+        );
+        assert_eq!(links.handle_pdu(LinkID::new(0xC), &capabilities(1)), None);
+
+        // B's Capabilities come in first so it gets the first range.
+        assert_eq!(
+            links.handle_pdu(b, &capabilities(3)),
+            Some(Ok(State::WaitingPublicKey))
+        );
+        assert_eq!(
+            links.handle_pdu(a, &capabilities(2)),
+            Some(Ok(State::WaitingPublicKey))
+        );
+        for &link_id in [a, b].iter() {
+            links.handle_pdu(link_id, &public_key());
+        }
+        for &link_id in [b, a].iter() {
+            links.handle_pdu(link_id, &PDU::Confirm(Confirmation([0xAA; 16])));
+            links.handle_pdu(link_id, &PDU::Random(Random([0xBB; 16])));
+        }
+        for &link_id in [a, b].iter() {
+            links
+                .get_mut(link_id)
+                .expect("open link")
+                .check_confirmation(&Confirmation([0xAA; 16]))
+                .expect("matching confirmation");
+            assert_eq!(
+                links.handle_pdu(link_id, &PDU::Complete(Complete())),
+                Some(Ok(State::Complete))
+            );
+        }
+        let a = links.close(a).expect("open link");
+        let b = links.close(b).expect("open link");
+        assert_eq!(b.primary_address(), UnicastAddress::new(0x0010));
+        assert_eq!(a.primary_address(), UnicastAddress::new(0x0013));
+        assert_eq!(
+            links.allocator().next_address(),
+            Some(UnicastAddress::new(0x0015))
+        );
+        assert!(links.is_empty());
+    }
+    #[test]
+    fn test_allocator_exhausted() {
+        let mut links = ProvisioningLinks::new(
+            AddressAllocator::new(UnicastAddress::new(0x7FFE)),
+            DEFAULT_MAX_LINKS,
+        );
+        let a = LinkID::new(0xA);
+        let b = LinkID::new(0xB);
+        links.open(a, false).expect("first link");
+        links.open(b, false).expect("second link");
+        assert_eq!(
+            links.handle_pdu(a, &capabilities(2)),
+            Some(Ok(State::WaitingPublicKey))
+        );
+        // Every unicast address is used.
+        assert_eq!(links.allocator().next_address(), None);
+        assert_eq!(
+            links.open(LinkID::new(0xC), false),
+            Err(LinksError::NoAddressLeft)
+        );
+        assert_eq!(
+            links.handle_pdu(b, &capabilities(1)),
+            Some(Err(ErrorCode::CannotAssignAddress))
+        );
+        assert_eq!(
+            links.get(b).and_then(Provisioner::failed_pdu),
+            Some(protocol::Failed(ErrorCode::CannotAssignAddress))
+        );
+    }
+}
@@ -0,0 +1,588 @@
+//! Provisioner side of the Provisioning Protocol over an open PB-ADV [`Link`]. [`provision`]
+//! sends the Invite, Start and our Public Key, checks the device's Confirmation and sends the
+//! encrypted Provisioning Data. The [`Provisioner`] state machine decides which PDU is allowed
+//! next and which `ErrorCode` to fail with.
+use crate::address::UnicastAddress;
+use crate::asyncs::sync::oneshot;
+use crate::crypto::ecdh::PrivateKey;
+use crate::crypto::key::{DevKey, NetKey};
+use crate::foundation::state::AttentionTimer;
+use crate::mesh::{ElementCount, IVIndex, IVUpdateFlag, KeyRefreshFlag, NetKeyIndex};
+use crate::provisioning::bearer::{Link, LinkError};
+use crate::provisioning::bearer_control::CloseReason;
+use crate::provisioning::confirmation::{self, AuthValue, ConfirmationKey};
+use crate::provisioning::data::{ProvisioningData, SessionKeys};
+use crate::provisioning::protocol::{
+    self, AlgorithmsFlags, AuthenticationMethod, Capabilities, ErrorCode, InputOOBAction, OOBSize,
+    OutputOOBAction, PublicKeyType, StaticOOBOption, PDU,
+};
+use crate::provisioning::provisioner::{Provisioner, State};
+use crate::random::Randomizable;
+use core::time::Duration;
+
+/// How long to wait for the device's next Provisioning PDU.
+pub const PROTOCOL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How the device proves it's the device we want to provision.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum Authentication {
+    NoOOB,
+    /// The device must support Static OOB and know this AuthValue.
+    StaticOOB(AuthValue),
+    /// The device outputs a value up to `OOBSize` digits long with the action. The user enters
+    /// it and it's sent to the `output_oob` given to `provision`.
+    OutputOOB(OutputOOBAction, OOBSize),
+    /// The user inputs this AuthValue (shown to them by the caller) on the device with the
+    /// action. It must fit in `OOBSize` digits.
+    InputOOB(InputOOBAction, OOBSize, AuthValue),
+}
+impl Authentication {
+    /// Returns `None` for Output OOB. Only the user knows it once the device output it.
+    pub fn auth_value(&self) -> Option<AuthValue> {
+        match self {
+            Authentication::NoOOB => Some(AuthValue::NO_OOB),
+            Authentication::StaticOOB(auth_value) | Authentication::InputOOB(_, _, auth_value) => {
+                Some(*auth_value)
+            }
+            Authentication::OutputOOB(_, _) => None,
+        }
+    }
+    /// Returns the `AuthenticationMethod` for the Start PDU or `None` if the device's
+    /// `capabilities` don't support it.
+    pub fn method(&self, capabilities: &Capabilities) -> Option<AuthenticationMethod> {
+        let fits =
+            |size: OOBSize, max_size: Option<OOBSize>| max_size.map_or(false, |max| size <= max);
+        match *self {
+            Authentication::NoOOB => Some(AuthenticationMethod::NoOOB),
+            Authentication::StaticOOB(_)
+                if capabilities.static_oob_option() == StaticOOBOption::StaticOOBAvailable =>
+            {
+                Some(AuthenticationMethod::StaticOOB)
+            }
+            Authentication::OutputOOB(action, size)
+                if capabilities.output_oob_action().supports(action)
+                    && fits(size, capabilities.output_oob_size()) =>
+            {
+                Some(AuthenticationMethod::OutputOOB(action, size))
+            }
+            Authentication::InputOOB(action, size, _)
+                if capabilities.input_oob_action().supports(action)
+                    && fits(size, capabilities.input_oob_size()) =>
+            {
+                Some(AuthenticationMethod::InputOOB(action, size))
+            }
+            _ => None,
+        }
+    }
+}
+/// What to give the device.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct ProvisioningParameters {
+    pub attention_timer: AttentionTimer,
+    pub authentication: Authentication,
+    pub net_key: NetKey,
+    pub net_key_index: NetKeyIndex,
+    pub key_refresh: KeyRefreshFlag,
+    pub iv_update: IVUpdateFlag,
+    pub iv_index: IVIndex,
+}
+/// A successfully provisioned device.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct ProvisionedDevice {
+    pub primary_address: UnicastAddress,
+    pub element_count: ElementCount,
+    pub dev_key: DevKey,
+}
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum SessionError {
+    Link(LinkError),
+    /// The device didn't send its next Provisioning PDU within `PROTOCOL_TIMEOUT`.
+    Timeout,
+    /// The device doesn't support the requested `Authentication`. A Provisioning Failed PDU
+    /// with `ErrorCode::UnexpectedError` was sent.
+    AuthenticationUnsupported,
+    /// Output OOB was picked but no `output_oob` was given or it was dropped without a value.
+    NoOutputOOB,
+    /// We failed the session (and sent a Provisioning Failed PDU with this code).
+    Failed(ErrorCode),
+    /// The device sent a Provisioning Failed PDU.
+    RemoteFailed(ErrorCode),
+}
+impl From<LinkError> for SessionError {
+    fn from(e: LinkError) -> Self {
+        SessionError::Link(e)
+    }
+}
+/// Provisions the device at the other end of `link` and gives it the addresses starting at
+/// `primary_address`. The link is closed when done (with `CloseReason::Success` only if the
+/// device sent Provisioning Complete). With `Authentication::OutputOOB`, the value the user read
+/// off the device is awaited on `output_oob`.
+pub async fn provision(
+    mut link: Link,
+    primary_address: UnicastAddress,
+    parameters: &ProvisioningParameters,
+    output_oob: Option<oneshot::Receiver<AuthValue>>,
+) -> Result<ProvisionedDevice, SessionError> {
+    let result = Session {
+        link: &mut link,
+        provisioner: Provisioner::new(primary_address),
+    }
+    .run(parameters, output_oob)
+    .await;
+    let reason = match &result {
+        Ok(_) => CloseReason::Success,
+        Err(SessionError::Link(_)) => return result,
+        Err(SessionError::Timeout) => CloseReason::Timeout,
+        Err(_) => CloseReason::Fail,
+    };
+    // The session is over either way so a broken link doesn't change the result.
+    let _ = link.close(reason).await;
+    result
+}
+struct Session<'a> {
+    link: &'a mut Link,
+    provisioner: Provisioner,
+}
+impl Session<'_> {
+    async fn run(
+        &mut self,
+        parameters: &ProvisioningParameters,
+        output_oob: Option<oneshot::Receiver<AuthValue>>,
+    ) -> Result<ProvisionedDevice, SessionError> {
+        let mut inputs = confirmation::Inputs {
+            invite: None,
+            capabilities: None,
+            start: None,
+            provisioner_public_key: None,
+            device_public_key: None,
+        };
+        let invite = protocol::Invite(parameters.attention_timer);
+        inputs.invite = Some(invite);
+        self.link.send(&PDU::Invite(invite)).await?;
+
+        let capabilities = match self.recv().await? {
+            PDU::Capabilities(capabilities) => capabilities,
+            _ => return Err(self.fail(ErrorCode::UnexpectedPDU).await),
+        };
+        inputs.capabilities = Some(capabilities);
+        let auth_method = match parameters.authentication.method(&capabilities) {
+            Some(auth_method) => auth_method,
+            None => {
+                return Err(match self.fail(ErrorCode::UnexpectedError).await {
+                    SessionError::Link(e) => SessionError::Link(e),
+                    _ => SessionError::AuthenticationUnsupported,
+                })
+            }
+        };
+        if let AuthenticationMethod::InputOOB(_, _) = auth_method {
+            self.provisioner = self.provisioner.with_input_oob(true);
+        }
+        let start = protocol::Start::new(
+            AlgorithmsFlags::FIPSP256,
+            PublicKeyType::NotAvailable,
+            auth_method,
+        );
+        inputs.start = Some(start);
+        self.link.send(&PDU::Start(start)).await?;
+
+        let (private_key, public_key) = match PrivateKey::generate() {
+            Ok(pair) => pair,
+            Err(_) => return Err(self.fail(ErrorCode::UnexpectedError).await),
+        };
+        inputs.provisioner_public_key = Some(public_key);
+        self.link.send(&PDU::PublicKey(public_key)).await?;
+        let device_public_key = match self.recv().await? {
+            PDU::PublicKey(device_public_key) => device_public_key,
+            _ => return Err(self.fail(ErrorCode::UnexpectedPDU).await),
+        };
+        inputs.device_public_key = Some(device_public_key);
+        let secret = match private_key.agree(&device_public_key) {
+            Ok(secret) => secret,
+            Err(_) => return Err(self.fail(ErrorCode::InvalidFormat).await),
+        };
+        let confirmation_salt = match inputs.salt() {
+            Ok(salt) => salt,
+            Err(_) => return Err(self.fail(ErrorCode::UnexpectedError).await),
+        };
+
+        let auth_value = match parameters.authentication.auth_value() {
+            Some(auth_value) => auth_value,
+            None => self.output_oob(output_oob).await?,
+        };
+        if let AuthenticationMethod::InputOOB(_, _) = auth_method {
+            // `recv` only lets Input Complete through here.
+            self.recv().await?;
+        }
+        let confirmation_key = ConfirmationKey::new(&secret, &confirmation_salt);
+        let random = protocol::Random(Randomizable::random_secure());
+        let confirmation = confirmation_key.confirmation(&random, &auth_value);
+        self.link.send(&PDU::Confirm(confirmation)).await?;
+        match self.recv().await? {
+            // A device echoing our Confirmation back could be reflecting it without knowing the
+            // AuthValue.
+            PDU::Confirm(device_confirmation) if device_confirmation == confirmation => {
+                return Err(self.fail(ErrorCode::ConfirmationFailed).await)
+            }
+            PDU::Confirm(_) => (),
+            _ => return Err(self.fail(ErrorCode::UnexpectedPDU).await),
+        }
+        self.link.send(&PDU::Random(random)).await?;
+        let device_random = match self.recv().await? {
+            PDU::Random(device_random) => device_random,
+            _ => return Err(self.fail(ErrorCode::UnexpectedPDU).await),
+        };
+        let expected = confirmation_key.confirmation(&device_random, &auth_value);
+        if let Err(code) = self.provisioner.check_confirmation(&expected) {
+            return Err(self.failed(code).await);
+        }
+
+        let provisioning_salt = confirmation_salt.provisioning_salt(&random, &device_random);
+        let session_keys = SessionKeys::new(&secret, &provisioning_salt);
+        let data = ProvisioningData {
+            net_key: parameters.net_key,
+            net_key_index: parameters.net_key_index,
+            key_refresh: parameters.key_refresh,
+            iv_update: parameters.iv_update,
+            iv_index: parameters.iv_index,
+            unicast_address: self.provisioner.primary_address(),
+        };
+        self.link
+            .send(&PDU::Data(data.encrypt(&session_keys)))
+            .await?;
+        match self.recv().await? {
+            PDU::Complete(_) => (),
+            _ => return Err(self.fail(ErrorCode::UnexpectedPDU).await),
+        }
+        Ok(ProvisionedDevice {
+            primary_address: self.provisioner.primary_address(),
+            element_count: self
+                .provisioner
+                .element_count()
+                .expect("Capabilities were received"),
+            dev_key: DevKey::from_salt_and_secret(provisioning_salt, secret),
+        })
+    }
+    /// Waits for the user to enter the value the device output.
+    async fn output_oob(
+        &mut self,
+        output_oob: Option<oneshot::Receiver<AuthValue>>,
+    ) -> Result<AuthValue, SessionError> {
+        let output_oob = output_oob.ok_or(SessionError::NoOutputOOB)?;
+        let clock = self.link.clock().clone();
+        clock
+            .timeout(PROTOCOL_TIMEOUT, output_oob)
+            .await
+            .map_err(|_| SessionError::Timeout)?
+            .map_err(|_| SessionError::NoOutputOOB)
+    }
+    /// Receives the next Provisioning PDU and runs it through the `Provisioner`. Anything it
+    /// doesn't expect fails the session.
+    async fn recv(&mut self) -> Result<PDU, SessionError> {
+        let clock = self.link.clock().clone();
+        let buf = clock
+            .timeout(PROTOCOL_TIMEOUT, self.link.recv())
+            .await
+            .map_err(|_| SessionError::Timeout)??;
+        let pdu = match PDU::unpack_from(&buf) {
+            Ok(pdu) => pdu,
+            Err(e) => return Err(self.fail(e.into()).await),
+        };
+        match self.provisioner.handle_pdu(&pdu) {
+            Ok(_) => Ok(pdu),
+            Err(code) => Err(self.failed(code).await),
+        }
+    }
+    async fn fail(&mut self, code: ErrorCode) -> SessionError {
+        let code = self.provisioner.fail(code);
+        self.failed(code).await
+    }
+    /// Sends the Provisioning Failed PDU if we failed the session.
+    async fn failed(&mut self, code: ErrorCode) -> SessionError {
+        match self.provisioner.state() {
+            State::Failed(code) => match self.link.send(&PDU::Failed(protocol::Failed(code))).await
+            {
+                Ok(()) => SessionError::Failed(code),
+                Err(e) => e.into(),
+            },
+            State::RemoteFailed(code) => SessionError::RemoteFailed(code),
+            _ => SessionError::Failed(code),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asyncs::{sync::mpsc, task};
+    use crate::crypto::key::Key;
+    use crate::mesh::KeyIndex;
+    use crate::provisioning::pb_adv::LinkID;
+    use crate::test_util;
+    use crate::uuid::UUID;
+
+    const LINK_ID: LinkID = LinkID::new(0x0BAD_CAFE);
+    const STATIC_OOB: AuthValue = AuthValue([0x5A; 16]);
+    /// Capabilities of a 2 element device with Static OOB, Output OOB (Blink or Output Numeric)
+    /// and Input OOB (Input Number) of up to 4 digits.
+    const CAPABILITIES: [u8; 12] = [
+        0x01, 0x02, 0x00, 0x01, 0x00, 0x01, 0x04, 0x00, 0x09, 0x04, 0x00, 0x04,
+    ];
+
+    async fn links() -> (Link, Link) {
+        let (to_device_tx, mut to_device_rx) = mpsc::channel(32);
+        let (to_provisioner_tx, to_provisioner_rx) = mpsc::channel(32);
+        let open = task::spawn(Link::open(
+            LINK_ID,
+            UUID([0x01; 16]),
+            to_provisioner_rx,
+            to_device_tx,
+        ));
+        to_device_rx.recv().await.expect("Link Open sent");
+        let device = Link::accept(LINK_ID, to_device_rx, to_provisioner_tx)
+            .await
+            .expect("channels open");
+        let provisioner = open
+            .await
+            .expect("open task panicked")
+            .expect("Link Ack sent");
+        (provisioner, device)
+    }
+    fn parameters(authentication: Authentication) -> ProvisioningParameters {
+        ProvisioningParameters {
+            attention_timer: AttentionTimer::new(0),
+            authentication,
+            net_key: NetKey::new(Key::new([0x33; 16])),
+            net_key_index: NetKeyIndex(KeyIndex::new(0x123)),
+            key_refresh: KeyRefreshFlag(false),
+            iv_update: IVUpdateFlag(true),
+            iv_index: IVIndex(0x1234_5678),
+        }
+    }
+    async fn recv(link: &mut Link) -> PDU {
+        PDU::unpack_from(&link.recv().await.expect("link open")).expect("valid PDU")
+    }
+    fn capabilities() -> Capabilities {
+        match PDU::unpack_from(&CAPABILITIES[..]).expect("valid capabilities") {
+            PDU::Capabilities(capabilities) => capabilities,
+            pdu => panic!("expected capabilities, got {:?}", pdu),
+        }
+    }
+    /// The device side of the Provisioning Protocol (see `test_util::provision_device`) with
+    /// `CAPABILITIES`.
+    async fn device(
+        link: Link,
+        auth_value: AuthValue,
+        reflect: bool,
+    ) -> Result<(ProvisioningData, DevKey), ErrorCode> {
+        test_util::provision_device(link, capabilities(), auth_value, reflect).await
+    }
+    #[tokio::test]
+    async fn test_provision_no_oob() {
+        let (provisioner, device_link) = links().await;
+        let parameters = parameters(Authentication::NoOOB);
+        let (provisioned, device) = tokio::join!(
+            provision(provisioner, UnicastAddress::new(0x0100), &parameters, None),
+            device(device_link, AuthValue::NO_OOB, false)
+        );
+        let provisioned = provisioned.expect("device provisioned");
+        let (data, dev_key) = device.expect("provisioning data received");
+        assert_eq!(provisioned.primary_address, UnicastAddress::new(0x0100));
+        assert_eq!(provisioned.element_count, ElementCount(2));
+        assert_eq!(provisioned.dev_key, dev_key);
+        assert_eq!(data.unicast_address, UnicastAddress::new(0x0100));
+        assert_eq!(data.net_key, parameters.net_key);
+        assert_eq!(data.net_key_index, parameters.net_key_index);
+        assert_eq!(data.iv_index, parameters.iv_index);
+        assert_eq!(data.iv_update, parameters.iv_update);
+    }
+    #[tokio::test]
+    async fn test_provision_static_oob() {
+        let (provisioner, device_link) = links().await;
+        let parameters = parameters(Authentication::StaticOOB(STATIC_OOB));
+        let (provisioned, device) = tokio::join!(
+            provision(provisioner, UnicastAddress::new(0x0100), &parameters, None),
+            device(device_link, STATIC_OOB, false)
+        );
+        assert_eq!(
+            provisioned.expect("device provisioned").dev_key,
+            device.expect("provisioning data received").1
+        );
+    }
+    #[tokio::test]
+    async fn test_wrong_static_oob() {
+        let (provisioner, device_link) = links().await;
+        let parameters = parameters(Authentication::StaticOOB(STATIC_OOB));
+        let (provisioned, device) = tokio::join!(
+            provision(provisioner, UnicastAddress::new(0x0100), &parameters, None),
+            device(device_link, AuthValue([0xA5; 16]), false)
+        );
+        // The device checks our Confirmation first.
+        assert_eq!(
+            provisioned,
+            Err(SessionError::RemoteFailed(ErrorCode::ConfirmationFailed))
+        );
+        assert_eq!(device.map(|_| ()), Err(ErrorCode::ConfirmationFailed));
+    }
+    #[tokio::test]
+    async fn test_reflected_confirmation() {
+        let (provisioner, device_link) = links().await;
+        let parameters = parameters(Authentication::NoOOB);
+        let (provisioned, device) = tokio::join!(
+            provision(provisioner, UnicastAddress::new(0x0100), &parameters, None),
+            device(device_link, AuthValue::NO_OOB, true)
+        );
+        assert_eq!(
+            provisioned,
+            Err(SessionError::Failed(ErrorCode::ConfirmationFailed))
+        );
+        assert_eq!(device.map(|_| ()), Err(ErrorCode::ConfirmationFailed));
+    }
+    #[tokio::test]
+    async fn test_provision_output_oob() {
+        let (provisioner, device_link) = links().await;
+        let parameters = parameters(Authentication::OutputOOB(
+            OutputOOBAction::OutputNumeric,
+            OOBSize::new(4),
+        ));
+        // The device shows 1234 and the user enters it.
+        let (output_oob_tx, output_oob_rx) = oneshot::channel();
+        output_oob_tx
+            .send(AuthValue::from_number(1234))
+            .expect("receiver open");
+        let (provisioned, device) = tokio::join!(
+            provision(
+                provisioner,
+                UnicastAddress::new(0x0100),
+                &parameters,
+                Some(output_oob_rx)
+            ),
+            device(device_link, AuthValue::from_number(1234), false)
+        );
+        assert_eq!(
+            provisioned.expect("device provisioned").dev_key,
+            device.expect("provisioning data received").1
+        );
+    }
+    #[tokio::test]
+    async fn test_wrong_output_oob() {
+        let (provisioner, device_link) = links().await;
+        let parameters = parameters(Authentication::OutputOOB(
+            OutputOOBAction::Blink,
+            OOBSize::new(1),
+        ));
+        // The device blinked 3 times but the user counted 2.
+        let (output_oob_tx, output_oob_rx) = oneshot::channel();
+        output_oob_tx
+            .send(AuthValue::from_number(2))
+            .expect("receiver open");
+        let (provisioned, device) = tokio::join!(
+            provision(
+                provisioner,
+                UnicastAddress::new(0x0100),
+                &parameters,
+                Some(output_oob_rx)
+            ),
+            device(device_link, AuthValue::from_number(3), false)
+        );
+        assert_eq!(
+            provisioned,
+            Err(SessionError::RemoteFailed(ErrorCode::ConfirmationFailed))
+        );
+        assert_eq!(device.map(|_| ()), Err(ErrorCode::ConfirmationFailed));
+    }
+    #[tokio::test]
+    async fn test_provision_input_oob() {
+        let (provisioner, device_link) = links().await;
+        let parameters = parameters(Authentication::InputOOB(
+            InputOOBAction::InputNumber,
+            OOBSize::new(4),
+            AuthValue::from_number(5678),
+        ));
+        let (provisioned, device) = tokio::join!(
+            provision(provisioner, UnicastAddress::new(0x0100), &parameters, None),
+            device(device_link, AuthValue::from_number(5678), false)
+        );
+        assert_eq!(
+            provisioned.expect("device provisioned").dev_key,
+            device.expect("provisioning data received").1
+        );
+    }
+    #[tokio::test]
+    async fn test_wrong_input_oob() {
+        let (provisioner, device_link) = links().await;
+        let parameters = parameters(Authentication::InputOOB(
+            InputOOBAction::InputNumber,
+            OOBSize::new(4),
+            AuthValue::from_number(5678),
+        ));
+        let (provisioned, device) = tokio::join!(
+            provision(provisioner, UnicastAddress::new(0x0100), &parameters, None),
+            device(device_link, AuthValue::from_number(8765), false)
+        );
+        assert_eq!(
+            provisioned,
+            Err(SessionError::RemoteFailed(ErrorCode::ConfirmationFailed))
+        );
+        assert_eq!(device.map(|_| ()), Err(ErrorCode::ConfirmationFailed));
+    }
+    #[tokio::test]
+    async fn test_authentication_unsupported() {
+        let (provisioner, mut device_link) = links().await;
+        let parameters = parameters(Authentication::OutputOOB(
+            OutputOOBAction::Beep,
+            OOBSize::new(1),
+        ));
+        let device = async {
+            match recv(&mut device_link).await {
+                PDU::Invite(_) => (),
+                pdu => panic!("expected invite, got {:?}", pdu),
+            }
+            device_link
+                .send(&PDU::Capabilities(capabilities()))
+                .await
+                .expect("link open");
+            // The device is told why instead of waiting for a Start PDU.
+            recv(&mut device_link).await
+        };
+        let (provisioned, failed) = tokio::join!(
+            provision(provisioner, UnicastAddress::new(0x0100), &parameters, None),
+            device
+        );
+        assert_eq!(provisioned, Err(SessionError::AuthenticationUnsupported));
+        match failed {
+            PDU::Failed(failed) => assert_eq!(failed.0, ErrorCode::UnexpectedError),
+            pdu => panic!("expected failed, got {:?}", pdu),
+        }
+    }
+    #[test]
+    fn test_authentication_method() {
+        let capabilities = capabilities();
+        let output = |action, size| Authentication::OutputOOB(action, OOBSize::new(size));
+        let input = |action, size| {
+            Authentication::InputOOB(action, OOBSize::new(size), AuthValue::from_number(1))
+        };
+        assert_eq!(
+            Authentication::StaticOOB(STATIC_OOB).method(&capabilities),
+            Some(AuthenticationMethod::StaticOOB)
+        );
+        assert_eq!(
+            output(OutputOOBAction::Blink, 4).method(&capabilities),
+            Some(AuthenticationMethod::OutputOOB(
+                OutputOOBAction::Blink,
+                OOBSize::new(4)
+            ))
+        );
+        assert_eq!(output(OutputOOBAction::Beep, 1).method(&capabilities), None);
+        assert_eq!(
+            output(OutputOOBAction::OutputNumeric, 5).method(&capabilities),
+            None
+        );
+        assert_eq!(
+            input(InputOOBAction::InputNumber, 4).method(&capabilities),
+            Some(AuthenticationMethod::InputOOB(
+                InputOOBAction::InputNumber,
+                OOBSize::new(4)
+            ))
+        );
+        assert_eq!(input(InputOOBAction::Push, 1).method(&capabilities), None);
+    }
+}
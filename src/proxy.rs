@@ -0,0 +1,352 @@
+//! Proxy filter of a connection between a Proxy Server (this node) and a Proxy Client over the
+//! GATT bearer. The Proxy Client sets the filter with Proxy Configuration messages and only
+//! Network PDUs to a destination passing the filter are sent to it. Each connection starts with
+//! an empty white list (nothing is forwarded until the client adds addresses).
+//!
+//! Proxy Configuration messages are Network PDUs (encrypted with the Proxy Nonce) carrying an
+//! opcode and parameters. Only the decrypted opcode and parameters are handled here.
+use crate::address::Address;
+use crate::bytes::ToFromBytesEndian;
+use crate::stack::bearer::OutgoingEncryptedNetworkPDU;
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum ProxyConfigError {
+    BufferTooSmall,
+    BadBytes,
+    BadLength,
+    BadOpcode,
+}
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum ProxyConfigOpcode {
+    SetFilterType = 0x00,
+    AddAddresses = 0x01,
+    RemoveAddresses = 0x02,
+    FilterStatus = 0x03,
+}
+impl From<ProxyConfigOpcode> for u8 {
+    fn from(opcode: ProxyConfigOpcode) -> Self {
+        opcode as u8
+    }
+}
+impl TryFrom<u8> for ProxyConfigOpcode {
+    type Error = ProxyConfigError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(ProxyConfigOpcode::SetFilterType),
+            0x01 => Ok(ProxyConfigOpcode::AddAddresses),
+            0x02 => Ok(ProxyConfigOpcode::RemoveAddresses),
+            0x03 => Ok(ProxyConfigOpcode::FilterStatus),
+            _ => Err(ProxyConfigError::BadOpcode),
+        }
+    }
+}
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum FilterType {
+    /// Only destinations in the list pass.
+    WhiteList = 0x00,
+    /// Every destination not in the list passes.
+    BlackList = 0x01,
+}
+impl Default for FilterType {
+    fn default() -> Self {
+        FilterType::WhiteList
+    }
+}
+impl From<FilterType> for u8 {
+    fn from(filter_type: FilterType) -> Self {
+        filter_type as u8
+    }
+}
+impl TryFrom<u8> for FilterType {
+    type Error = ProxyConfigError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(FilterType::WhiteList),
+            0x01 => Ok(FilterType::BlackList),
+            _ => Err(ProxyConfigError::BadBytes),
+        }
+    }
+}
+const FILTER_STATUS_LEN: usize = 3;
+/// Sent by the Proxy Server after every change to its filter.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct FilterStatus {
+    pub filter_type: FilterType,
+    pub list_size: u16,
+}
+#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum ProxyConfigMessage {
+    SetFilterType(FilterType),
+    AddAddresses(Vec<Address>),
+    RemoveAddresses(Vec<Address>),
+    FilterStatus(FilterStatus),
+}
+impl ProxyConfigMessage {
+    pub fn opcode(&self) -> ProxyConfigOpcode {
+        match self {
+            ProxyConfigMessage::SetFilterType(_) => ProxyConfigOpcode::SetFilterType,
+            ProxyConfigMessage::AddAddresses(_) => ProxyConfigOpcode::AddAddresses,
+            ProxyConfigMessage::RemoveAddresses(_) => ProxyConfigOpcode::RemoveAddresses,
+            ProxyConfigMessage::FilterStatus(_) => ProxyConfigOpcode::FilterStatus,
+        }
+    }
+    /// Length of the opcode and parameters.
+    pub fn byte_len(&self) -> usize {
+        1 + match self {
+            ProxyConfigMessage::SetFilterType(_) => 1,
+            ProxyConfigMessage::AddAddresses(addresses)
+            | ProxyConfigMessage::RemoveAddresses(addresses) => addresses.len() * 2,
+            ProxyConfigMessage::FilterStatus(_) => FILTER_STATUS_LEN,
+        }
+    }
+    /// Unpacks a message from its opcode and parameters. Addresses are big endian.
+    pub fn unpack_from(buf: &[u8]) -> Result<Self, ProxyConfigError> {
+        let (&opcode, parameters) = buf.split_first().ok_or(ProxyConfigError::BadLength)?;
+        let addresses = || -> Result<Vec<Address>, ProxyConfigError> {
+            if parameters.len() % 2 != 0 {
+                return Err(ProxyConfigError::BadLength);
+            }
+            Ok(parameters
+                .chunks_exact(2)
+                .map(|address| Address::from_bytes_be(address).expect("2 byte chunks"))
+                .collect())
+        };
+        match ProxyConfigOpcode::try_from(opcode)? {
+            ProxyConfigOpcode::SetFilterType => match parameters {
+                [filter_type] => Ok(ProxyConfigMessage::SetFilterType(FilterType::try_from(
+                    *filter_type,
+                )?)),
+                _ => Err(ProxyConfigError::BadLength),
+            },
+            ProxyConfigOpcode::AddAddresses => Ok(ProxyConfigMessage::AddAddresses(addresses()?)),
+            ProxyConfigOpcode::RemoveAddresses => {
+                Ok(ProxyConfigMessage::RemoveAddresses(addresses()?))
+            }
+            ProxyConfigOpcode::FilterStatus => {
+                if parameters.len() != FILTER_STATUS_LEN {
+                    return Err(ProxyConfigError::BadLength);
+                }
+                Ok(ProxyConfigMessage::FilterStatus(FilterStatus {
+                    filter_type: FilterType::try_from(parameters[0])?,
+                    list_size: u16::from_bytes_be(&parameters[1..3]).expect("length checked"),
+                }))
+            }
+        }
+    }
+    /// Packs the opcode and parameters into `buf`.
+    pub fn pack_into(&self, buf: &mut [u8]) -> Result<(), ProxyConfigError> {
+        if buf.len() < self.byte_len() {
+            return Err(ProxyConfigError::BufferTooSmall);
+        }
+        buf[0] = self.opcode().into();
+        match self {
+            ProxyConfigMessage::SetFilterType(filter_type) => buf[1] = (*filter_type).into(),
+            ProxyConfigMessage::AddAddresses(addresses)
+            | ProxyConfigMessage::RemoveAddresses(addresses) => {
+                for (address, out) in addresses.iter().zip(buf[1..].chunks_exact_mut(2)) {
+                    out.copy_from_slice(&address.to_bytes_be());
+                }
+            }
+            ProxyConfigMessage::FilterStatus(status) => {
+                buf[1] = status.filter_type.into();
+                buf[2..4].copy_from_slice(&status.list_size.to_bytes_be());
+            }
+        }
+        Ok(())
+    }
+}
+/// Proxy filter of one Proxy Client connection. Addresses are compared by their 16-bit value so
+/// a virtual address matches by its hash.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ProxyFilter {
+    filter_type: FilterType,
+    addresses: BTreeSet<u16>,
+}
+impl ProxyFilter {
+    /// An empty white list (the filter of a new connection).
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn filter_type(&self) -> FilterType {
+        self.filter_type
+    }
+    /// Changes the filter type and clears the list.
+    pub fn set_filter_type(&mut self, filter_type: FilterType) {
+        self.filter_type = filter_type;
+        self.addresses.clear();
+    }
+    /// Adds `address` to the list. The unassigned address is ignored.
+    pub fn add(&mut self, address: &Address) {
+        if address.is_assigned() {
+            self.addresses.insert(address.value());
+        }
+    }
+    pub fn remove(&mut self, address: &Address) {
+        self.addresses.remove(&address.value());
+    }
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+    pub fn contains(&self, address: &Address) -> bool {
+        self.addresses.contains(&address.value())
+    }
+    /// Returns if a Network PDU to `dst` should be forwarded to the Proxy Client.
+    pub fn passes(&self, dst: &Address) -> bool {
+        match self.filter_type {
+            FilterType::WhiteList => self.contains(dst),
+            FilterType::BlackList => !self.contains(dst),
+        }
+    }
+    /// Returns the Network PDU to send to the Proxy Client (in a Proxy PDU) if `outgoing`'s
+    /// destination passes the filter. The counterpart of
+    /// [`IncomingEncryptedNetworkPDU::from_proxy_pdu`](crate::stack::bearer::IncomingEncryptedNetworkPDU::from_proxy_pdu).
+    pub fn forward<'a>(&self, outgoing: &'a OutgoingEncryptedNetworkPDU) -> Option<&'a [u8]> {
+        if self.passes(&outgoing.dst) {
+            Some(outgoing.pdu.as_ref())
+        } else {
+            None
+        }
+    }
+    /// Updates the filter for a Network PDU from the Proxy Client with source address `src`.
+    /// The client wants to receive messages to its own address so `src` gets added to a white
+    /// list and removed from a black list.
+    pub fn handle_client_source(&mut self, src: &Address) {
+        match self.filter_type {
+            FilterType::WhiteList => self.add(src),
+            FilterType::BlackList => self.remove(src),
+        }
+    }
+    pub fn status(&self) -> FilterStatus {
+        FilterStatus {
+            filter_type: self.filter_type,
+            list_size: u16::try_from(self.len()).unwrap_or(u16::max_value()),
+        }
+    }
+    /// Applies a Proxy Configuration message from the Proxy Client. Returns the Filter Status to
+    /// respond with or `None` if `msg` isn't a filter change.
+    pub fn handle_message(&mut self, msg: &ProxyConfigMessage) -> Option<FilterStatus> {
+        match msg {
+            ProxyConfigMessage::SetFilterType(filter_type) => self.set_filter_type(*filter_type),
+            ProxyConfigMessage::AddAddresses(addresses) => {
+                addresses.iter().for_each(|address| self.add(address))
+            }
+            ProxyConfigMessage::RemoveAddresses(addresses) => {
+                addresses.iter().for_each(|address| self.remove(address))
+            }
+            ProxyConfigMessage::FilterStatus(_) => return None,
+        }
+        Some(self.status())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::{GroupAddress, UnicastAddress, VirtualAddress};
+    use crate::mesh::TransmitInterval;
+    use crate::net;
+    use crate::uuid::UUID;
+
+    fn pack(msg: &ProxyConfigMessage) -> Vec<u8> {
+        let mut buf = vec![0_u8; msg.byte_len()];
+        msg.pack_into(&mut buf).expect("buffer is big enough");
+        buf
+    }
+    #[test]
+    fn test_pack_unpack() {
+        let add =
+            ProxyConfigMessage::AddAddresses(vec![Address::from(0x0001), Address::from(0xC001)]);
+        assert_eq!(pack(&add), vec![0x01, 0x00, 0x01, 0xC0, 0x01]);
+        let status = ProxyConfigMessage::FilterStatus(FilterStatus {
+            filter_type: FilterType::BlackList,
+            list_size: 0x0102,
+        });
+        assert_eq!(pack(&status), vec![0x03, 0x01, 0x01, 0x02]);
+        for msg in &[
+            ProxyConfigMessage::SetFilterType(FilterType::BlackList),
+            ProxyConfigMessage::RemoveAddresses(Vec::new()),
+            add,
+            status,
+        ] {
+            assert_eq!(
+                ProxyConfigMessage::unpack_from(&pack(msg)).as_ref(),
+                Ok(msg)
+            );
+        }
+        assert_eq!(
+            ProxyConfigMessage::unpack_from(&[0x00, 0x02]),
+            Err(ProxyConfigError::BadBytes)
+        );
+        assert_eq!(
+            ProxyConfigMessage::unpack_from(&[0x01, 0x00]),
+            Err(ProxyConfigError::BadLength)
+        );
+        assert_eq!(
+            ProxyConfigMessage::unpack_from(&[0x04]),
+            Err(ProxyConfigError::BadOpcode)
+        );
+        assert_eq!(
+            ProxyConfigMessage::unpack_from(&[]),
+            Err(ProxyConfigError::BadLength)
+        );
+    }
+    #[test]
+    fn test_filter() {
+        let unicast = Address::Unicast(UnicastAddress::new(0x0005));
+        let group = Address::Group(GroupAddress::new(0xC001));
+        let virtual_address = VirtualAddress::new(&UUID([0x5A; 16]));
+        let mut filter = ProxyFilter::new();
+        // New connections start with an empty white list.
+        assert_eq!(filter.filter_type(), FilterType::WhiteList);
+        assert!(!filter.passes(&group));
+
+        let status = filter.handle_message(&ProxyConfigMessage::AddAddresses(vec![
+            group,
+            Address::VirtualHash(virtual_address.hash()),
+            Address::Unassigned,
+        ]));
+        assert_eq!(
+            status,
+            Some(FilterStatus {
+                filter_type: FilterType::WhiteList,
+                list_size: 2
+            })
+        );
+        assert!(filter.passes(&group));
+        assert!(filter.passes(&Address::Virtual(virtual_address)));
+        assert!(!filter.passes(&unicast));
+        filter.handle_client_source(&unicast);
+        assert!(filter.passes(&unicast));
+        filter.handle_message(&ProxyConfigMessage::RemoveAddresses(vec![group]));
+        assert!(!filter.passes(&group));
+
+        // Changing the filter type clears the list.
+        let status =
+            filter.handle_message(&ProxyConfigMessage::SetFilterType(FilterType::BlackList));
+        assert_eq!(status.map(|status| status.list_size), Some(0));
+        assert!(filter.passes(&group));
+        filter.handle_message(&ProxyConfigMessage::AddAddresses(vec![group, unicast]));
+        assert!(!filter.passes(&group));
+        filter.handle_client_source(&unicast);
+        assert!(filter.passes(&unicast));
+        let outgoing = |dst| OutgoingEncryptedNetworkPDU {
+            transmit_parameters: TransmitInterval::from(0),
+            pdu: net::OwnedEncryptedPDU::new(&[0x5A; 20]).expect("valid length"),
+            dst,
+            relayed: false,
+        };
+        assert_eq!(filter.forward(&outgoing(unicast)), Some(&[0x5A_u8; 20][..]));
+        assert_eq!(filter.forward(&outgoing(group)), None);
+        assert_eq!(
+            filter.handle_message(&ProxyConfigMessage::FilterStatus(filter.status())),
+            None
+        );
+    }
+}
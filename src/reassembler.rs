@@ -13,6 +13,8 @@ pub enum ReassembleError {
     DataTooLong,
     SegmentOutOfBounds,
     Timeout,
+    /// The segment's AID/Opcode, SZMIC or SegO doesn't match the first segment.
+    HeaderMismatch,
 }
 
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
@@ -59,6 +61,21 @@ impl ContextHeader {
             block_ack: BlockAck::ZERO,
         }
     }
+    /// Checks that a segment's header fields match the ones of the first segment. Every segment
+    /// of a transfer carries the same AID/Opcode, SZMIC and SegO so a mismatch means the segment
+    /// is corrupted or spoofed.
+    pub fn check_segment(
+        &self,
+        lower_header: LowerHeader,
+        seg_o: SegO,
+        flag: bool,
+    ) -> Result<(), ReassembleError> {
+        if self.lower_header == lower_header && self.seg_o == seg_o && self.flag == flag {
+            Ok(())
+        } else {
+            Err(ReassembleError::HeaderMismatch)
+        }
+    }
     #[must_use]
     pub fn all_acked(&self) -> bool {
         self.block_ack.all_acked(self.seg_o)
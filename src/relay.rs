@@ -1,5 +1,5 @@
 //! Optional Relay Feature
-use crate::mesh::{IVIndex, NetKeyIndex};
+use crate::mesh::{IVIndex, NetKeyIndex, TTL};
 use crate::net;
 
 pub struct RelayPDU {
@@ -7,3 +7,107 @@ pub struct RelayPDU {
     pub iv_index: IVIndex,
     pub net_key_index: NetKeyIndex,
 }
+impl RelayPDU {
+    /// Returns the PDU to retransmit (with its TTL decremented by one) or `None` if its TTL is
+    /// too low to be relayed (see `TTL::should_relay`).
+    pub fn relayed_pdu(&self) -> Option<net::PDU> {
+        let ttl = self.pdu.header.ttl;
+        if !ttl.should_relay() {
+            return None;
+        }
+        let mut pdu = self.pdu;
+        pdu.header.ttl = TTL::new(u8::from(ttl) - 1);
+        Some(pdu)
+    }
+}
+/// Relays PDUs heard with a strong signal less often. A PDU received at `rssi_threshold` dBm
+/// or above came from a close neighbour so other relays most likely heard it too. Those PDUs are
+/// only relayed `relay_percent`% of the time. PDUs without an RSSI are always relayed.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct RelaySuppression {
+    pub rssi_threshold: i8,
+    pub relay_percent: u8,
+}
+impl RelaySuppression {
+    /// # Panics
+    /// Panics if `relay_percent > 100`.
+    pub fn new(rssi_threshold: i8, relay_percent: u8) -> Self {
+        assert!(relay_percent <= 100, "relay_percent over 100");
+        Self {
+            rssi_threshold,
+            relay_percent,
+        }
+    }
+    /// Returns if a PDU received with `rssi` should be relayed given a `roll` uniformly
+    /// distributed in `0..100`.
+    #[must_use]
+    pub fn should_relay_with(&self, rssi: Option<i8>, roll: u8) -> bool {
+        match rssi {
+            Some(rssi) if rssi >= self.rssi_threshold => roll < self.relay_percent,
+            _ => true,
+        }
+    }
+    /// Same as `should_relay_with` but rolls the dice itself.
+    #[must_use]
+    pub fn should_relay(&self, rssi: Option<i8>) -> bool {
+        use crate::random::Randomizable;
+        let roll = (u32::random() % 100) as u8;
+        self.should_relay_with(rssi, roll)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::{Address, UnicastAddress};
+    use crate::lower;
+    use crate::mesh::{KeyIndex, SequenceNumber, CTL, NID, U24};
+
+    fn relay_pdu(ttl: u8) -> RelayPDU {
+        RelayPDU {
+            pdu: net::PDU {
+                header: net::Header {
+                    ivi: IVIndex(0).ivi(),
+                    nid: NID::new(0),
+                    ctl: CTL(false),
+                    ttl: TTL::new(ttl),
+                    seq: SequenceNumber(U24::new(1)),
+                    src: UnicastAddress::new(0x0100),
+                    dst: Address::from(0x0200),
+                },
+                payload: lower::PDU::UnsegmentedAccess(lower::UnsegmentedAccessPDU::new(
+                    None, &[0_u8; 5],
+                )),
+            },
+            iv_index: IVIndex(0),
+            net_key_index: NetKeyIndex(KeyIndex::new(0)),
+        }
+    }
+    #[test]
+    fn test_relayed_pdu_ttl() {
+        let relayed = relay_pdu(5).relayed_pdu().expect("TTL 5 is relayed");
+        assert_eq!(relayed.header.ttl, TTL::new(4));
+        assert_eq!(relayed.header.seq, SequenceNumber(U24::new(1)));
+        assert_eq!(
+            relay_pdu(2).relayed_pdu().map(|pdu| pdu.header.ttl),
+            Some(TTL::new(1))
+        );
+        assert!(relay_pdu(1).relayed_pdu().is_none());
+        assert!(relay_pdu(0).relayed_pdu().is_none());
+    }
+    #[test]
+    fn test_relay_suppression() {
+        let suppression = RelaySuppression::new(-50, 25);
+        // Weak or unknown signals are always relayed.
+        for roll in 0..100 {
+            assert!(suppression.should_relay_with(Some(-70), roll));
+            assert!(suppression.should_relay_with(None, roll));
+        }
+        // Strong ones are relayed 25% of the time.
+        let relayed = (0..100)
+            .filter(|&roll| suppression.should_relay_with(Some(-50), roll))
+            .count();
+        assert_eq!(relayed, 25);
+        assert!(!RelaySuppression::new(-50, 0).should_relay(Some(-20)));
+        assert!(RelaySuppression::new(-50, 100).should_relay(Some(-20)));
+    }
+}
@@ -1,13 +1,23 @@
 //! Replay Cache based on a BTreeMap that keeps track of each ivi and seq per src address. Updating
 //! the IVIndex causes a 'Garbage Collection' like effect that will delete any cache entries for
 //! any 'too' old IVIndices.
+//!
+//! With `serde-1`, the `Cache` (the highest seq and IVI seen per src) can be saved and loaded
+//! again after a restart. Starting with an empty cache would accept replays of anything sent
+//! before the restart.
+//!
+//! The `Cache` holds at most `capacity` sources. Once full, the least recently updated source is
+//! evicted for each new one and replay protection for it is lost until it's heard again.
+//!
+//! Also has the `NetworkMessageCache` which only remembers the last few Network PDUs so the same
+//! PDU heard more than once (from different relays or bearers) is only handled and relayed once.
 use crate::address::UnicastAddress;
-use crate::mesh::{SequenceNumber, IVI};
+use crate::mesh::{IVIndex, SequenceNumber, IVI};
 
 use crate::lower::SeqZero;
 use crate::net::PrivateHeader;
 use alloc::collections::btree_map::Entry;
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, VecDeque};
 
 #[derive(Ord, PartialOrd, Eq, PartialEq, Copy, Clone, Hash, Debug)]
 #[cfg_attr(feature = "serde-1", derive(serde::Serialize, serde::Deserialize))]
@@ -15,6 +25,9 @@ pub struct CacheEntry {
     seq: SequenceNumber,
     ivi: IVI,
     seq_zero: Option<SeqZero>,
+    /// `Cache` tick of the last seq update. Lower is older.
+    #[cfg_attr(feature = "serde-1", serde(default))]
+    last_update: u64,
 }
 impl CacheEntry {
     /// Returns (if seq is old, if seq_zero is old).
@@ -41,17 +54,132 @@ impl From<PrivateHeader<'_>> for CacheEntry {
             seq: p.seq(),
             ivi: p.ivi(),
             seq_zero: None,
+            last_update: 0,
         }
     }
 }
-#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash, Default)]
+/// Default maximum number of source addresses in a `Cache`.
+pub const DEFAULT_CACHE_CAPACITY: usize = 256;
+#[cfg(feature = "serde-1")]
+fn default_cache_capacity() -> usize {
+    DEFAULT_CACHE_CAPACITY
+}
+#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
 #[cfg_attr(feature = "serde-1", derive(serde::Serialize, serde::Deserialize))]
 pub struct Cache {
     map: BTreeMap<UnicastAddress, CacheEntry>,
+    /// Not saved. A loaded cache gets `DEFAULT_CACHE_CAPACITY` (see `Cache::set_capacity`).
+    #[cfg_attr(feature = "serde-1", serde(skip, default = "default_cache_capacity"))]
+    capacity: usize,
+    /// Incremented for every seq update so entries can be ordered by how recently they changed.
+    #[cfg_attr(feature = "serde-1", serde(default))]
+    tick: u64,
+    #[cfg_attr(feature = "serde-1", serde(skip))]
+    evicted: Option<UnicastAddress>,
 }
 impl Cache {
-    pub fn new() -> Cache {
-        Cache::default()
+    /// Creates an empty cache holding at most `capacity` source addresses.
+    ///
+    /// # Panics
+    /// Panics if `capacity == 0`.
+    pub fn new(capacity: usize) -> Cache {
+        assert!(capacity > 0, "replay cache must hold at least one source");
+        Cache {
+            map: BTreeMap::new(),
+            capacity,
+            tick: 0,
+            evicted: None,
+        }
+    }
+    /// Creates a cache pre-seeded with known `(src, iv_index, seq, seq_zero)` peer state (from a
+    /// backup, etc) so replays of messages the node already saw are rejected. If a `src` shows up
+    /// more than once, the entry with the highest `(iv_index, seq)` is kept. The cache has
+    /// `DEFAULT_CACHE_CAPACITY` and if there are more sources than that, the lowest addresses are
+    /// evicted.
+    pub fn with_entries(
+        entries: impl IntoIterator<Item = (UnicastAddress, IVIndex, SequenceNumber, Option<SeqZero>)>,
+    ) -> Cache {
+        let mut newest: BTreeMap<UnicastAddress, (IVIndex, SequenceNumber, Option<SeqZero>)> =
+            BTreeMap::new();
+        for (src, iv_index, seq, seq_zero) in entries {
+            match newest.entry(src) {
+                Entry::Vacant(v) => {
+                    v.insert((iv_index, seq, seq_zero));
+                }
+                Entry::Occupied(mut o) => {
+                    let (old_iv_index, old_seq, _) = *o.get();
+                    if (iv_index, seq) > (old_iv_index, old_seq) {
+                        o.insert((iv_index, seq, seq_zero));
+                    }
+                }
+            }
+        }
+        let mut cache = Cache::default();
+        for (src, (iv_index, seq, seq_zero)) in newest {
+            let last_update = cache.next_tick();
+            cache.insert(
+                src,
+                CacheEntry {
+                    seq,
+                    ivi: iv_index.ivi(),
+                    seq_zero,
+                    last_update,
+                },
+            );
+        }
+        cache.evicted = None;
+        cache
+    }
+    /// Maximum number of source addresses before the least recently updated one is evicted.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+    /// Changes the capacity (after loading a saved cache, etc). Evicts the least recently updated
+    /// sources until there's at most `capacity` left.
+    ///
+    /// # Panics
+    /// Panics if `capacity == 0`.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        assert!(capacity > 0, "replay cache must hold at least one source");
+        self.capacity = capacity;
+        while self.map.len() > capacity {
+            self.evict_oldest();
+        }
+    }
+    /// Returns the last evicted source (if any) and clears it. Replays
+    /// from that source won't be caught until it's heard from again so callers should log it.
+    pub fn take_evicted(&mut self) -> Option<UnicastAddress> {
+        self.evicted.take()
+    }
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+    /// Removes the entry that was updated longest ago (the one with the oldest last seen seq).
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .map
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_update)
+            .map(|(&src, _)| src);
+        if let Some(oldest) = oldest {
+            self.map.remove(&oldest);
+            self.evicted = Some(oldest);
+        }
+    }
+    /// Inserts a new source, evicting the oldest one first if the cache is full.
+    fn insert(&mut self, src: UnicastAddress, entry: CacheEntry) {
+        if self.map.len() >= self.capacity {
+            self.evict_oldest();
+        }
+        self.map.insert(src, entry);
+    }
+    /// Returns the number of source addresses in the cache.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
     }
     pub fn get_entry(&self, address: UnicastAddress) -> Option<&CacheEntry> {
         self.map.get(&address)
@@ -85,26 +213,28 @@ impl Cache {
         ivi: IVI,
         seq_zero: Option<SeqZero>,
     ) -> (bool, bool) {
-        match self.map.entry(src) {
-            Entry::Vacant(v) => {
-                v.insert(CacheEntry {
-                    seq,
-                    ivi,
-                    seq_zero: None,
-                });
+        let tick = self.next_tick();
+        match self.map.get_mut(&src) {
+            None => {
+                self.insert(
+                    src,
+                    CacheEntry {
+                        seq,
+                        ivi,
+                        seq_zero: None,
+                        last_update: tick,
+                    },
+                );
                 (false, false)
             }
-            Entry::Occupied(mut o) => {
-                match o.get().is_old_header(ivi, seq, seq_zero) {
+            Some(entry) => {
+                match entry.is_old_header(ivi, seq, seq_zero) {
                     None => (false, false), // IVI doesn't match
                     Some((is_old_seq, is_old_seq_zero)) => {
-                        // If Seq is old, update it
-                        if is_old_seq {
-                            o.insert(CacheEntry {
-                                seq,
-                                ivi,
-                                seq_zero: None,
-                            });
+                        // If Seq is new, record it
+                        if !is_old_seq {
+                            entry.seq = seq;
+                            entry.last_update = tick;
                         }
                         (is_old_seq, is_old_seq_zero)
                     }
@@ -113,3 +243,179 @@ impl Cache {
         }
     }
 }
+impl Default for Cache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_CAPACITY)
+    }
+}
+/// Default number of Network PDUs remembered by a `NetworkMessageCache`.
+pub const NETWORK_MESSAGE_CACHE_LEN: usize = 32;
+/// Fixed size ring of the most recently seen Network PDUs keyed by `(src, seq, ivi)`. Unlike the
+/// replay `Cache`, which only tracks the highest seq per src, this also catches duplicates of
+/// older (reordered) PDUs and it's what relaying is deduplicated on. Once full, the oldest PDU is
+/// forgotten for every new one.
+#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct NetworkMessageCache {
+    entries: VecDeque<(UnicastAddress, SequenceNumber, IVI)>,
+    capacity: usize,
+}
+impl NetworkMessageCache {
+    /// # Panics
+    /// Panics if `capacity == 0`.
+    pub fn new(capacity: usize) -> NetworkMessageCache {
+        assert!(
+            capacity > 0,
+            "network message cache must hold at least one PDU"
+        );
+        NetworkMessageCache {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    pub fn contains(&self, src: UnicastAddress, seq: SequenceNumber, ivi: IVI) -> bool {
+        self.entries.contains(&(src, seq, ivi))
+    }
+    /// Records the PDU and returns `true` if it's new or `false` if it's already in the cache.
+    pub fn insert(&mut self, src: UnicastAddress, seq: SequenceNumber, ivi: IVI) -> bool {
+        if self.contains(src, seq, ivi) {
+            return false;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((src, seq, ivi));
+        true
+    }
+}
+impl Default for NetworkMessageCache {
+    fn default() -> Self {
+        Self::new(NETWORK_MESSAGE_CACHE_LEN)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::U24;
+
+    fn seq(seq: u32) -> SequenceNumber {
+        SequenceNumber(U24::new(seq))
+    }
+    #[test]
+    fn test_with_entries() {
+        let peer = UnicastAddress::new(0x0005);
+        let other = UnicastAddress::new(0x0006);
+        let iv_index = IVIndex(3);
+        let mut cache = Cache::with_entries(vec![
+            (peer, iv_index, seq(100), Some(SeqZero::new(90))),
+            // Older state for the same peer is ignored.
+            (peer, iv_index, seq(50), None),
+            (other, iv_index, seq(7), None),
+        ]);
+        assert_eq!(cache.len(), 2);
+        let ivi = iv_index.ivi();
+
+        // Anything up to the seeded seq is a replay.
+        assert_eq!(
+            cache.replay_net_check(peer, seq(100), ivi, None),
+            (true, false)
+        );
+        assert_eq!(
+            cache.replay_net_check(peer, seq(60), ivi, None),
+            (true, false)
+        );
+        // So is an already seen segmented transfer.
+        assert_eq!(
+            cache.replay_net_check(peer, seq(101), ivi, Some(SeqZero::new(90))),
+            (false, true)
+        );
+        assert_eq!(
+            cache.replay_net_check(peer, seq(102), ivi, None),
+            (false, false)
+        );
+        assert_eq!(
+            cache.replay_net_check(peer, seq(102), ivi, None),
+            (true, false)
+        );
+
+        assert_eq!(
+            cache.replay_net_check(other, seq(7), ivi, None),
+            (true, false)
+        );
+        assert_eq!(
+            cache.replay_net_check(other, seq(8), ivi, None),
+            (false, false)
+        );
+    }
+    #[test]
+    fn test_eviction() {
+        let ivi = IVIndex(0).ivi();
+        let src = |address| UnicastAddress::new(address);
+        let mut cache = Cache::new(2);
+        assert_eq!(
+            cache.replay_net_check(src(1), seq(10), ivi, None),
+            (false, false)
+        );
+        assert_eq!(
+            cache.replay_net_check(src(2), seq(10), ivi, None),
+            (false, false)
+        );
+        assert_eq!(cache.take_evicted(), None);
+        // 1 was updated last so 2 is the oldest.
+        assert_eq!(
+            cache.replay_net_check(src(1), seq(11), ivi, None),
+            (false, false)
+        );
+        assert_eq!(
+            cache.replay_net_check(src(3), seq(10), ivi, None),
+            (false, false)
+        );
+        assert_eq!(cache.take_evicted(), Some(src(2)));
+        assert_eq!(cache.take_evicted(), None);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get_entry(src(2)).is_none());
+        // Replays from an evicted source aren't caught anymore.
+        assert_eq!(
+            cache.replay_net_check(src(2), seq(10), ivi, None),
+            (false, false)
+        );
+        assert_eq!(cache.take_evicted(), Some(src(1)));
+        assert_eq!(
+            cache.replay_net_check(src(3), seq(10), ivi, None),
+            (true, false)
+        );
+
+        cache.set_capacity(1);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get_entry(src(2)).is_some());
+    }
+    #[test]
+    fn test_network_message_cache() {
+        let src = UnicastAddress::new(0x0005);
+        let ivi = IVIndex(0).ivi();
+        let mut cache = NetworkMessageCache::new(3);
+        assert!(cache.insert(src, seq(10), ivi));
+        // Heard again (another relay or bearer).
+        assert!(!cache.insert(src, seq(10), ivi));
+        // Reordered PDUs are still new.
+        assert!(cache.insert(src, seq(12), ivi));
+        assert!(cache.insert(src, seq(11), ivi));
+        assert!(!cache.insert(src, seq(11), ivi));
+        // Same seq from another src or IV Index.
+        assert!(cache.insert(UnicastAddress::new(0x0006), seq(11), ivi));
+        assert!(cache.insert(src, seq(11), IVIndex(1).ivi()));
+        assert_eq!(cache.len(), cache.capacity());
+        // The oldest ones were forgotten.
+        assert!(!cache.contains(src, seq(10), ivi));
+        assert!(!cache.contains(src, seq(12), ivi));
+        assert!(cache.insert(src, seq(10), ivi));
+    }
+}
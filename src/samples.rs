@@ -1,10 +1,16 @@
+//! Mesh Profile v1.0 Sample Data (section 8) as test vectors.
+use crate::access::{AccessPayload, Opcode, VendorOpcode};
 use crate::address::{Address, UnicastAddress, VirtualAddress};
+use crate::control::{self, ControlMessage};
 use crate::crypto::key::{AppKey, DevKey, Key, NetKey};
-use crate::crypto::nonce::AppNonceParts;
+use crate::crypto::materials::NetworkKeys;
+use crate::crypto::nonce::{AppNonceParts, DeviceNonceParts};
 use crate::crypto::{aes::MicSize, MIC};
-use crate::mesh::{IVIndex, SequenceNumber, U24};
+use crate::friend::{FriendCounter, FriendshipCredentials, LPNCounter};
+use crate::lower::{self, BlockAck, SegN, SegO, SegmentedAccessPDU, SeqZero};
+use crate::mesh::{CompanyID, IVIndex, SequenceNumber, CTL, TTL, U24};
 use crate::uuid::UUID;
-use crate::{mesh, upper};
+use crate::{mesh, net, upper};
 use core::str::FromStr;
 
 fn sample_app_key() -> AppKey {
@@ -16,10 +22,313 @@ fn sample_net_key() -> NetKey {
 fn sample_dev_key() -> DevKey {
     DevKey::new(Key::from_str("9d6dd0e96eb25dc19a40ed9914f8f03f").expect("from sample data"))
 }
+const SAMPLE_IV_INDEX: IVIndex = IVIndex(0x12345678);
+fn hex(s: &str) -> Vec<u8> {
+    assert_eq!(s.len() % 2, 0, "odd length hex string");
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).expect("from sample data"))
+        .collect()
+}
+/// Friendship between the Low Power node 0x1201 and the Friend 0x2345 (Messages #1 and #2).
+fn sample_friendship_keys() -> NetworkKeys {
+    FriendshipCredentials {
+        lpn_address: UnicastAddress::new(0x1201),
+        friend_address: UnicastAddress::new(0x2345),
+        lpn_counter: LPNCounter::new(0x0000),
+        friend_counter: FriendCounter::new(0x072F),
+    }
+    .network_keys(&sample_net_key())
+}
+fn sample_header(
+    keys: &NetworkKeys,
+    ctl: bool,
+    ttl: u8,
+    seq: u32,
+    src: u16,
+    dst: u16,
+) -> net::Header {
+    net::Header {
+        ivi: SAMPLE_IV_INDEX.ivi(),
+        nid: keys.nid(),
+        ctl: CTL(ctl),
+        ttl: TTL::new(ttl),
+        seq: SequenceNumber(U24::new(seq)),
+        src: UnicastAddress::new(src),
+        dst: Address::from(dst),
+    }
+}
+/// Encrypts `transport_pdu` and compares it to the sample `network_pdu`, then decrypts the sample
+/// `network_pdu` back into `header` and `transport_pdu`.
+fn check_network_pdu(
+    keys: &NetworkKeys,
+    header: net::Header,
+    transport_pdu: &str,
+    network_pdu: &str,
+) {
+    let transport_pdu = hex(transport_pdu);
+    let network_pdu = hex(network_pdu);
+    let encrypted = header
+        .encrypt_transport_pdu(&transport_pdu, keys, SAMPLE_IV_INDEX)
+        .expect("valid header");
+    assert_eq!(
+        AsRef::<[u8]>::as_ref(&encrypted),
+        &network_pdu[..],
+        "network pdu mismatch"
+    );
+    let decrypted = match net::EncryptedPDU::new(&network_pdu)
+        .expect("valid length")
+        .try_decrypt(keys, SAMPLE_IV_INDEX)
+    {
+        Ok(pdu) => pdu,
+        Err(_) => panic!("sample network pdu didn't decrypt"),
+    };
+    assert_eq!(decrypted.header, header, "header mismatch");
+    assert_eq!(
+        decrypted.decrypted_data().transport_pdu(),
+        &transport_pdu[..],
+        "transport pdu mismatch"
+    );
+}
+#[test]
+fn message1() {
+    let keys = NetworkKeys::from(&sample_net_key());
+    assert_eq!(u8::from(keys.nid()), 0x68);
+    check_network_pdu(
+        &keys,
+        sample_header(&keys, true, 0x00, 0x000001, 0x1201, 0xFFFD),
+        "034b50057e400000010000",
+        "68eca487516765b5e5bfdacbaf6cb7fb6bff871f035444ce83a670df",
+    );
+    let request = control::FriendRequest::unpack(&hex("4b50057e400000010000"))
+        .expect("from sample data")
+        .0;
+    assert_eq!(request.receive_delay.value(), 0x50);
+    assert_eq!(request.poll_timeout.steps(), U24::new(0x057E40));
+    assert_eq!(request.previous_address, None);
+    assert_eq!(request.num_elements, 1);
+    assert_eq!(request.lpn_counter, LPNCounter::new(0x0000));
+}
+#[test]
+fn message2() {
+    let keys = NetworkKeys::from(&sample_net_key());
+    check_network_pdu(
+        &keys,
+        sample_header(&keys, true, 0x00, 0x014820, 0x2345, 0x1201),
+        "04320308ba072f",
+        "68d4c826296d7979d7dbc0c9b4d43eebec129d20a620d01e",
+    );
+    let offer = control::FriendOffer::unpack(&hex("320308ba072f"))
+        .expect("from sample data")
+        .0;
+    assert_eq!(offer.receive_window, 0x32);
+    assert_eq!(offer.queue_size, 0x03);
+    assert_eq!(offer.subscription_list_size, 0x08);
+    assert_eq!(offer.rssi, -70);
+    assert_eq!(offer.friend_counter, FriendCounter::new(0x072F));
+}
+#[test]
+fn message3() {
+    let keys = NetworkKeys::from(&sample_net_key());
+    check_network_pdu(
+        &keys,
+        sample_header(&keys, true, 0x00, 0x2B3832, 0x2FE3, 0x1201),
+        "04fa0205a6000a",
+        "68da062bc96df253273086b8c5ee00bdd9cfcc62a2ddf572",
+    );
+}
+#[test]
+fn message4() {
+    // Friend Poll with the friendship credentials.
+    let keys = sample_friendship_keys();
+    assert_eq!(u8::from(keys.nid()), 0x5E);
+    check_network_pdu(
+        &keys,
+        sample_header(&keys, true, 0x00, 0x000002, 0x1201, 0x2345),
+        "0100",
+        "5e84eba092380fb0e5d0ad970d579a4e88051c",
+    );
+}
+#[test]
+fn message5() {
+    // Friend Update with the friendship credentials.
+    let keys = sample_friendship_keys();
+    check_network_pdu(
+        &keys,
+        sample_header(&keys, true, 0x00, 0x014834, 0x2345, 0x1201),
+        "02001234567800",
+        "5eafd6f53c43db5c39da1792b1fee9ec74b786c56d3a9dee",
+    );
+    let update = control::FriendUpdate::unpack(&hex("001234567800"))
+        .expect("from sample data")
+        .0;
+    assert_eq!(update.iv_index, SAMPLE_IV_INDEX);
+    assert!(!update.md.value());
+}
+#[test]
+fn message6() {
+    // Config AppKey Add sent with the DevKey in two segments.
+    let access = hex("0056341263964771734fbd76e3b40519d1d94a48");
+    let dev_key = sample_dev_key();
+    let nonce = DeviceNonceParts {
+        aszmic: false,
+        seq: SequenceNumber(U24::new(0x3129AB)),
+        src: UnicastAddress::new(0x0003),
+        dst: Address::from(0x1201),
+        iv_index: SAMPLE_IV_INDEX,
+    }
+    .to_nonce();
+    assert_eq!(
+        AsRef::<[u8]>::as_ref(&nonce),
+        &hex("02003129ab0003120112345678")[..],
+        "nonce mismatch"
+    );
+    let sm = upper::SecurityMaterials::Device(nonce, &dev_key);
+    let encrypted = upper::AppPayload::new(access.clone()).encrypt(&sm, MicSize::Small);
+    assert_eq!(
+        encrypted.data(),
+        &hex("ee9dddfd2169326d23f3afdfcfdc18c52fdef772")[..]
+    );
+    assert_eq!(encrypted.mic(), MIC::Small(0xE0E17308), "mic mismatch");
+    assert_eq!(encrypted.seg_o(), SegO::new(1));
+    assert_eq!(upper::calculate_seg_o(encrypted.len(), 12), SegO::new(1));
+    let mut upper_pdu = encrypted.data().to_vec();
+    upper_pdu.extend_from_slice(&hex("e0e17308"));
+
+    let keys = NetworkKeys::from(&sample_net_key());
+    let segments = [
+        (
+            0x3129AB,
+            "8026ac01ee9dddfd2169326d23f3afdf",
+            "68cab5c5348a230afba8c63d4e686364979deaf4fd40961145939cda0e",
+        ),
+        (
+            0x3129AC,
+            "8026ac21cfdc18c52fdef772e0e17308",
+            "681615b5dd4a846cae0c032bf0746f44f1b8cc8ce5edc57e55beed49c0",
+        ),
+    ];
+    let mut reassembled = Vec::new();
+    for (seg_n, &(seq, transport_pdu, network_pdu)) in segments.iter().enumerate() {
+        let segment = SegmentedAccessPDU::new(
+            None,
+            false.into(),
+            SeqZero::new(0x09AB),
+            SegO::new(1),
+            SegN::new(seg_n as u8),
+            &upper_pdu[seg_n * 12..(seg_n + 1) * 12],
+        );
+        let segment = lower::PDU::SegmentedAccess(segment);
+        let mut packed = [0_u8; 16];
+        segment.pack_into(&mut packed[..]);
+        assert_eq!(
+            &packed[..segment.len()],
+            &hex(transport_pdu)[..],
+            "segment mismatch"
+        );
+        assert_eq!(
+            lower::PDU::unpack_from(&packed[..segment.len()], CTL(false)),
+            Some(segment),
+            "segment unpack mismatch"
+        );
+        check_network_pdu(
+            &keys,
+            sample_header(&keys, false, 0x04, seq, 0x0003, 0x1201),
+            transport_pdu,
+            network_pdu,
+        );
+        reassembled.extend_from_slice(&hex(transport_pdu)[4..]);
+    }
+    let mic_start = reassembled.len() - MIC::small_size();
+    let mic = MIC::try_from_bytes_be(&reassembled[mic_start..]).expect("4 byte mic");
+    reassembled.truncate(mic_start);
+    let decrypted = upper::EncryptedAppPayload::new(reassembled, mic, None)
+        .decrypt(sm)
+        .expect("sample data should authenticate");
+    assert_eq!(decrypted.payload(), &access[..]);
+}
+#[test]
+fn message7() {
+    // Segment Acknowledgment sent by the Friend on behalf of the Low Power node.
+    let keys = NetworkKeys::from(&sample_net_key());
+    check_network_pdu(
+        &keys,
+        sample_header(&keys, true, 0x0B, 0x014835, 0x2345, 0x0003),
+        "00a6ac00000002",
+        "68e476b5579c980d0d730f94d7f3509df987bb417eb7c05f",
+    );
+    let ack = control::Ack {
+        obo: true,
+        seq_zero: SeqZero::new(0x09AB),
+        block_ack: BlockAck(0x00000002),
+    };
+    assert_eq!(control::Ack::unpack(&hex("a6ac00000002")), Ok(ack));
+    let mut packed = [0_u8; 6];
+    ack.pack(&mut packed[..]).expect("6 byte buffer");
+    assert_eq!(&packed[..], &hex("a6ac00000002")[..]);
+}
+#[test]
+fn message8() {
+    // Segment Acknowledgment for both segments.
+    let keys = NetworkKeys::from(&sample_net_key());
+    check_network_pdu(
+        &keys,
+        sample_header(&keys, true, 0x0B, 0x014836, 0x2345, 0x0003),
+        "00a6ac00000003",
+        "68aec467ed4901d85d806bbed248614f938067b0d983bb7b",
+    );
+    assert_eq!(
+        control::Ack::unpack(&hex("a6ac00000003")).map(|ack| ack.block_ack),
+        Ok(BlockAck::new_all_acked(SegO::new(1)))
+    );
+}
+#[test]
+fn stack_network_pdus() {
+    use crate::device_state::DeviceState;
+    use crate::mesh::{ElementCount, KeyIndex, NetKeyIndex};
+    use crate::stack::StackInternals;
+
+    let net_key_index = NetKeyIndex(KeyIndex::new(0));
+    let mut internals = StackInternals::new(DeviceState::new(
+        UnicastAddress::new(0x1201),
+        ElementCount(1),
+    ));
+    *internals.device_state_mut().iv_index_mut() = SAMPLE_IV_INDEX;
+    internals
+        .device_state_mut()
+        .security_materials_mut()
+        .net_key_map
+        .insert(net_key_index, &sample_net_key());
+    // Message #2
+    let network_pdu = hex("68d4c826296d7979d7dbc0c9b4d43eebec129d20a620d01e");
+    let (index, iv_index, pdu) = internals
+        .decrypt_network_pdu(net::EncryptedPDU::new(&network_pdu).expect("valid length"))
+        .expect("sample data should decrypt");
+    assert_eq!(index, net_key_index);
+    assert_eq!(iv_index, SAMPLE_IV_INDEX);
+    assert_eq!(pdu.header.src, UnicastAddress::new(0x2345));
+    assert_eq!(pdu.header.seq, SequenceNumber(U24::new(0x014820)));
+    let encrypted = internals
+        .encrypt_network_pdu(pdu, net_key_index, SAMPLE_IV_INDEX)
+        .expect("net key and iv index are known");
+    assert_eq!(AsRef::<[u8]>::as_ref(&encrypted.pdu), &network_pdu[..]);
+    match pdu.payload {
+        lower::PDU::UnsegmentedControl(offer) => {
+            assert_eq!(offer.opcode(), control::ControlOpcode::FriendOffer)
+        }
+        _ => panic!("expected a Friend Offer"),
+    }
+}
 #[test]
 fn message22() {
-    //let opcode = Opcode::Vendor(VendorOpcode::new(0x15), CompanyID(0x00a));
     let parameters = [0xd5_u8, 0x0a, 0x00, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+    let access = AccessPayload::new(
+        Opcode::Vendor(VendorOpcode::new(0x15), CompanyID(0x000a)),
+        b"Hello",
+    )
+    .expect("from sample data");
+    assert_eq!(&access.encode()[..], &parameters[..]);
+    assert_eq!(AccessPayload::decode(&parameters[..]), Ok(access));
     let payload = upper::AppPayload::new(parameters);
     let app_key = sample_app_key();
     let dst = VirtualAddress::new(&UUID(
@@ -56,3 +365,45 @@ fn message22() {
         "encrypted data mismatch"
     );
 }
+#[test]
+fn secure_network_beacon() {
+    use crate::beacon::{BeaconPDU, SecureNetworkBeacon};
+    use crate::crypto::key::BeaconKey;
+    use crate::crypto::NetworkID;
+    use crate::mesh::{IVUpdateFlag, KeyRefreshFlag};
+
+    let net_key = sample_net_key();
+    let beacon_key = BeaconKey::from(&net_key);
+    assert_eq!(
+        beacon_key,
+        BeaconKey::from_hex("5423d967da639a99cb02231a83f7d254").expect("from sample data")
+    );
+    assert_eq!(NetworkID::from(&net_key), NetworkID(0x3ecaff672f673370));
+    let beacon: [u8; 1 + SecureNetworkBeacon::BYTE_LEN] =
+        mesh::bytes_str_to_buf("01003ecaff672f673370123456788ea261582f364f6f")
+            .expect("from sample data");
+    let secure_network = match BeaconPDU::unpack_from(&beacon[..]).expect("valid beacon") {
+        BeaconPDU::SecureNetwork(secure_network) => secure_network,
+        BeaconPDU::Unprovisioned(_) => panic!("expected a secure network beacon"),
+    };
+    let verified = secure_network
+        .verify(&beacon_key)
+        .expect("authentication value mismatch");
+    assert_eq!(verified.iv_index, IVIndex(0x12345678));
+    assert_eq!(verified.key_refresh, KeyRefreshFlag(false));
+    assert_eq!(verified.iv_update, IVUpdateFlag(false));
+    assert!(secure_network
+        .verify(&BeaconKey::from_hex("9d6dd0e96eb25dc19a40ed9914f8f03f").expect("valid key"))
+        .is_none());
+    // Re-authenticating gives the same beacon back.
+    let mut packed = [0_u8; SecureNetworkBeacon::BYTE_LEN];
+    SecureNetworkBeacon::new(
+        secure_network.flags,
+        verified.network_id,
+        verified.iv_index,
+        &beacon_key,
+    )
+    .pack_into(&mut packed[..])
+    .expect("valid beacon");
+    assert_eq!(&packed[..], &beacon[1..]);
+}
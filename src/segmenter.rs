@@ -79,54 +79,61 @@ impl<'a, Storage: AsRef<[u8]>> Iterator for SegmentIterator<'a, Storage> {
 
     fn next(&mut self) -> Option<Self::Item> {
         // Skip acked segments.
-        while self.block_ack.get(self.seg_n) && self.seg_n < u8::from(self.segmenter.seg_o) {
-            self.seg_n += 1;
-        }
-        if self.seg_n > u8::from(self.segmenter.seg_o) {
-            None
-        } else {
-            let seg_n_out = SegN::new(self.seg_n);
-            let segment_data = self.segmenter.upper_pdu.seg_n_data(seg_n_out);
-            let header = self.segment_header();
-            match &self.segmenter.upper_pdu {
-                upper::PDU::Control(control) => {
-                    // ControlPDU
-                    let out = lower::SegmentedControlPDU::new(control.opcode, header, segment_data);
+        let first_unsent = self.seg_n;
+        self.seg_n = self
+            .block_ack
+            .missing(self.segmenter.seg_o)
+            .find(|&seg_n| seg_n >= first_unsent)?;
+        let seg_n_out = SegN::new(self.seg_n);
+        let segment_data = self.segmenter.upper_pdu.seg_n_data(seg_n_out);
+        let header = self.segment_header();
+        match &self.segmenter.upper_pdu {
+            upper::PDU::Control(control) => {
+                // ControlPDU
+                let out = lower::SegmentedControlPDU::new(control.opcode, header, segment_data);
+                self.seg_n += 1;
+                Some(lower::SegmentedPDU::Control(out))
+            }
+            upper::PDU::Access(access) => {
+                if segment_data.len() == SegmentedAccessPDU::max_seg_len() {
+                    let out = lower::SegmentedAccessPDU::new(
+                        access.aid(),
+                        access.mic().is_big().into(),
+                        self.segmenter.seq_auth.seq_zero(),
+                        self.segmenter.seg_o,
+                        seg_n_out,
+                        segment_data,
+                    );
                     self.seg_n += 1;
-                    Some(lower::SegmentedPDU::Control(out))
-                }
-                upper::PDU::Access(access) => {
-                    if segment_data.len() == SegmentedAccessPDU::max_seg_len() {
-                        let out = lower::SegmentedAccessPDU::new(
-                            access.aid(),
-                            access.mic().is_big().into(),
-                            self.segmenter.seq_auth.seq_zero(),
-                            self.segmenter.seg_o,
-                            seg_n_out,
-                            segment_data,
-                        );
-                        self.seg_n += 1;
-                        Some(lower::SegmentedPDU::Access(out))
-                    } else {
-                        let mic = access.mic();
-                        let seg_len = segment_data.len();
-                        let mut buf = [0_u8; SegmentedAccessPDU::max_seg_len() + MIC::big_size()];
-                        buf[..seg_len].copy_from_slice(segment_data);
-                        mic.be_pack_into(&mut buf[seg_len..seg_len + mic.byte_size()]);
-                        let out = lower::SegmentedAccessPDU::new(
-                            access.aid(),
-                            mic.is_big().into(),
-                            self.segmenter.seq_auth.first_seq.into(),
-                            self.segmenter.seg_o,
-                            seg_n_out,
-                            &buf[..min(
-                                seg_len + mic.byte_size(),
-                                SegmentedAccessPDU::max_seg_len(),
-                            )],
-                        );
-                        self.seg_n += 1;
-                        Some(lower::SegmentedPDU::Access(out))
-                    }
+                    Some(lower::SegmentedPDU::Access(out))
+                } else {
+                    // The TransMIC goes after the payload so it might be split over the last
+                    // two segments.
+                    let mic = access.mic();
+                    let mut mic_buf = [0_u8; MIC::big_size()];
+                    mic.be_pack_into(&mut mic_buf[..]);
+                    let payload_len = access.data_len();
+                    let seg_start = usize::from(self.seg_n) * SegmentedAccessPDU::max_seg_len();
+                    let seg_end = min(
+                        seg_start + SegmentedAccessPDU::max_seg_len(),
+                        payload_len + mic.byte_size(),
+                    );
+                    let mic_part =
+                        &mic_buf[seg_start.max(payload_len) - payload_len..seg_end - payload_len];
+                    let seg_len = segment_data.len();
+                    let mut buf = [0_u8; SegmentedAccessPDU::max_seg_len()];
+                    buf[..seg_len].copy_from_slice(segment_data);
+                    buf[seg_len..seg_len + mic_part.len()].copy_from_slice(mic_part);
+                    let out = lower::SegmentedAccessPDU::new(
+                        access.aid(),
+                        mic.is_big().into(),
+                        self.segmenter.seq_auth.first_seq.into(),
+                        self.segmenter.seg_o,
+                        seg_n_out,
+                        &buf[..seg_len + mic_part.len()],
+                    );
+                    self.seg_n += 1;
+                    Some(lower::SegmentedPDU::Access(out))
                 }
             }
         }
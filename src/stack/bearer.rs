@@ -1,10 +1,14 @@
 //! Bluetooth Mesh Bearers.
+use crate::address::Address;
 use crate::mesh::TransmitInterval;
 use crate::provisioning::pb_adv;
 use crate::{beacon, net};
-use btle::le::advertisement::{AdType, OutgoingAdvertisement};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use btle::le::advertisement::OutgoingAdvertisement;
 use btle::le::report::{EventType, ReportInfo};
-use btle::RSSI;
+use btle::{PackError, RSSI};
+use core::convert::TryFrom;
 
 #[derive(Debug)]
 pub enum BearerError {
@@ -16,28 +20,97 @@ pub struct IncomingEncryptedNetworkPDU {
     pub encrypted_pdu: net::OwnedEncryptedPDU,
     pub rssi: Option<RSSI>,
     pub dont_relay: bool,
+    /// Received from a Proxy Client over the GATT bearer instead of advertising. Proxied PDUs are
+    /// relayed to the advertising bearer if the Proxy feature is enabled (even if the Relay
+    /// feature isn't).
+    pub from_proxy: bool,
 }
 impl IncomingEncryptedNetworkPDU {
+    /// Wraps a Network PDU from a (reassembled) Proxy PDU received over the GATT bearer.
+    pub fn from_proxy_pdu(data: &[u8]) -> Option<IncomingEncryptedNetworkPDU> {
+        Some(IncomingEncryptedNetworkPDU {
+            encrypted_pdu: net::OwnedEncryptedPDU::new(data)?,
+            rssi: None,
+            dont_relay: false,
+            from_proxy: true,
+        })
+    }
     pub fn from_report_info(report_info: ReportInfo<&[u8]>) -> Option<IncomingEncryptedNetworkPDU> {
         if report_info.event_type == EventType::AdvInd {
-            if let Some(ad_struct) = report_info.data.iter().next() {
-                if ad_struct.ad_type == AdType::MeshPDU {
-                    return Some(IncomingEncryptedNetworkPDU {
-                        encrypted_pdu: net::OwnedEncryptedPDU::new(ad_struct.buf.as_ref())?,
-                        rssi: report_info.rssi,
-                        dont_relay: false,
-                    });
-                }
+            if let Some(IncomingMessage::Network(pdu)) =
+                IncomingMessage::from_adv_data(report_info.data.as_ref(), report_info.rssi)
+            {
+                return Some(pdu);
             }
         }
         None
     }
 }
+/// AD Type of PB-ADV PDUs.
+pub const PB_ADV_AD_TYPE: u8 = 0x29;
+/// AD Type of Mesh Message (Network) PDUs.
+pub const MESH_PDU_AD_TYPE: u8 = 0x2A;
+/// AD Type of Mesh Beacons.
+pub const MESH_BEACON_AD_TYPE: u8 = 0x2B;
+/// Iterates over the AD Structures (`length | ad_type | data`) in raw advertising data and
+/// yields each `(ad_type, data)`. A zero length (early termination) or a length pointing past
+/// the end of the data are treated as "no more AD Structures" so malformed data never panics.
+#[derive(Copy, Clone, Debug)]
+pub struct ADStructures<'a> {
+    data: &'a [u8],
+}
+impl<'a> ADStructures<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+}
+impl<'a> Iterator for ADStructures<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&len, rest) = self.data.split_first()?;
+        let len = usize::from(len);
+        if len == 0 || len > rest.len() {
+            self.data = &[];
+            return None;
+        }
+        let (ad_struct, rest) = rest.split_at(len);
+        self.data = rest;
+        Some((ad_struct[0], &ad_struct[1..]))
+    }
+}
 
 #[derive(Copy, Clone, Debug)]
 pub struct OutgoingEncryptedNetworkPDU {
     pub transmit_parameters: TransmitInterval,
     pub pdu: net::OwnedEncryptedPDU,
+    /// Destination of `pdu` (which is encrypted) for the proxy filter (see
+    /// [`ProxyFilter::forward`](crate::proxy::ProxyFilter::forward)).
+    pub dst: Address,
+    /// `pdu` is relayed for another node (see `Outgoing::encrypt_relay_pdu`) instead of
+    /// originating from this one.
+    pub relayed: bool,
+}
+impl OutgoingEncryptedNetworkPDU {
+    /// Advertising data (a single Mesh Message AD Structure) to advertise the PDU with.
+    pub fn adv_data(&self) -> Box<[u8]> {
+        ad_structure(MESH_PDU_AD_TYPE, self.pdu.as_ref())
+    }
+}
+/// Advertising data (a single PB-ADV AD Structure) to advertise `pdu` with.
+pub fn pb_adv_data(pdu: &pb_adv::PDU) -> Result<Box<[u8]>, PackError> {
+    let mut buf = Vec::new();
+    buf.resize(pdu.byte_len(), 0_u8);
+    pdu.pack_into(&mut buf)?;
+    Ok(ad_structure(PB_ADV_AD_TYPE, &buf))
+}
+/// `length | ad_type | data`. Mesh AD Structures are always shorter than 255 bytes.
+fn ad_structure(ad_type: u8, data: &[u8]) -> Box<[u8]> {
+    let mut ad = Vec::with_capacity(data.len() + 2);
+    ad.push(u8::try_from(data.len() + 1).expect("mesh AD Structures are less than 255 bytes"));
+    ad.push(ad_type);
+    ad.extend_from_slice(data);
+    ad.into_boxed_slice()
 }
 #[derive(Copy, Clone, Debug)]
 pub struct IncomingBeacon {
@@ -67,32 +140,125 @@ pub enum IncomingMessage {
 impl IncomingMessage {
     pub fn from_report_info(report_info: ReportInfo) -> Option<IncomingMessage> {
         if report_info.event_type == EventType::AdvNonconnInd {
-            dbg!(&report_info);
-            if let Some(ad_struct) = report_info.data.iter().next() {
-                dbg!(ad_struct.ad_type);
-                match ad_struct.ad_type {
-                    AdType::MeshPDU => {
-                        Some(IncomingMessage::Network(IncomingEncryptedNetworkPDU {
-                            encrypted_pdu: net::OwnedEncryptedPDU::new(ad_struct.buf.as_ref())?,
-                            rssi: report_info.rssi,
-                            dont_relay: false,
-                        }))
-                    }
-                    AdType::MeshBeacon => Some(IncomingMessage::Beacon(IncomingBeacon {
-                        beacon: beacon::BeaconPDU::unpack_from(ad_struct.buf.as_ref()).ok()?,
-                        rssi: report_info.rssi,
-                    })),
-                    AdType::PbAdv => Some(IncomingMessage::PBAdv(pb_adv::IncomingPDU {
-                        pdu: pb_adv::PDU::unpack_from(ad_struct.buf.as_ref()).ok()?,
-                        rssi: report_info.rssi,
-                    })),
-                    _ => None,
-                }
-            } else {
-                None
-            }
+            Self::from_adv_data(report_info.data.as_ref(), report_info.rssi)
         } else {
             None
         }
     }
+    /// Returns the first mesh AD Structure (PB-ADV, Mesh PDU or Mesh Beacon) in `data` that
+    /// parses. Every other AD Structure (flags, names, vendor data, etc) is skipped.
+    pub fn from_adv_data(data: &[u8], rssi: Option<RSSI>) -> Option<IncomingMessage> {
+        ADStructures::new(data).find_map(|(ad_type, buf)| match ad_type {
+            MESH_PDU_AD_TYPE => Some(IncomingMessage::Network(IncomingEncryptedNetworkPDU {
+                encrypted_pdu: net::OwnedEncryptedPDU::new(buf)?,
+                rssi,
+                dont_relay: false,
+                from_proxy: false,
+            })),
+            MESH_BEACON_AD_TYPE => Some(IncomingMessage::Beacon(IncomingBeacon {
+                beacon: beacon::BeaconPDU::unpack_from(buf).ok()?,
+                rssi,
+            })),
+            PB_ADV_AD_TYPE => Some(IncomingMessage::PBAdv(pb_adv::IncomingPDU {
+                pdu: pb_adv::PDU::unpack_from(buf).ok()?,
+                rssi,
+            })),
+            _ => None,
+        })
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLAGS: [u8; 3] = [0x02, 0x01, 0x06];
+
+    fn network_ad() -> Vec<u8> {
+        let mut ad = vec![21, MESH_PDU_AD_TYPE];
+        ad.extend_from_slice(&[0x5A; 20]);
+        ad
+    }
+    fn is_network(msg: Option<IncomingMessage>) -> bool {
+        match msg {
+            Some(IncomingMessage::Network(_)) => true,
+            _ => false,
+        }
+    }
+    #[test]
+    fn test_skips_unknown_ad_types() {
+        let mut data = FLAGS.to_vec();
+        // Vendor data that happens to contain the Mesh PDU AD Type.
+        data.extend_from_slice(&[0x05, 0xFF, 0x15, MESH_PDU_AD_TYPE, 0x00, 0x00]);
+        data.extend_from_slice(&network_ad());
+        assert!(is_network(IncomingMessage::from_adv_data(&data, None)));
+        assert_eq!(
+            ADStructures::new(&data)
+                .map(|(ad_type, _)| ad_type)
+                .collect::<Vec<_>>(),
+            vec![0x01, 0xFF, MESH_PDU_AD_TYPE]
+        );
+    }
+    #[test]
+    fn test_outgoing_adv_data() {
+        let outgoing = OutgoingEncryptedNetworkPDU {
+            transmit_parameters: TransmitInterval::from(0),
+            pdu: net::OwnedEncryptedPDU::new(&[0x5A; 20]).expect("valid length"),
+            dst: Address::Unassigned,
+            relayed: false,
+        };
+        let data = outgoing.adv_data();
+        assert_eq!(&data[..], &network_ad()[..]);
+        match IncomingMessage::from_adv_data(&data, None) {
+            Some(IncomingMessage::Network(incoming)) => {
+                assert_eq!(incoming.encrypted_pdu, outgoing.pdu)
+            }
+            _ => panic!("not a network PDU"),
+        }
+    }
+    #[test]
+    fn test_pb_adv_data() {
+        use crate::provisioning::bearer_control::{self, LinkAck};
+        use crate::provisioning::generic::{self, Control};
+        let pdu = pb_adv::PDU {
+            link_id: pb_adv::LinkID::new(0x0BAD_CAFE),
+            transaction_number: pb_adv::TransactionNumber::new(0),
+            generic_pdu: generic::PDU {
+                control: Control::BearerControl(bearer_control::PDU::LinkAck(LinkAck())),
+                payload: None,
+            },
+        };
+        let data = pb_adv_data(&pdu).expect("valid PDU");
+        assert_eq!(usize::from(data[0]), data.len() - 1);
+        assert_eq!(data[1], PB_ADV_AD_TYPE);
+        match IncomingMessage::from_adv_data(&data, None) {
+            Some(IncomingMessage::PBAdv(incoming)) => {
+                assert_eq!(incoming.pdu.link_id, pdu.link_id);
+                assert_eq!(incoming.pdu.transaction_number, pdu.transaction_number);
+            }
+            _ => panic!("not a PB-ADV PDU"),
+        }
+    }
+    #[test]
+    fn test_adversarial_lengths() {
+        // Length pointing past the end of the data.
+        let mut data = FLAGS.to_vec();
+        data.extend_from_slice(&[0xFE, MESH_PDU_AD_TYPE, 0x00, 0x00, 0x00]);
+        assert!(IncomingMessage::from_adv_data(&data, None).is_none());
+        assert_eq!(ADStructures::new(&data).count(), 1);
+        // Length one byte too long for the mesh AD Structure.
+        let mut data = network_ad();
+        data[0] += 1;
+        assert!(IncomingMessage::from_adv_data(&data, None).is_none());
+        // Zero length ends the data even if a mesh AD Structure follows.
+        let mut data = vec![0x00];
+        data.extend_from_slice(&network_ad());
+        assert!(IncomingMessage::from_adv_data(&data, None).is_none());
+        // A length byte with nothing after it.
+        assert!(IncomingMessage::from_adv_data(&[0x01], None).is_none());
+        assert!(IncomingMessage::from_adv_data(&[], None).is_none());
+        // A mesh AD Structure that doesn't parse is skipped.
+        let mut data = vec![0x02, MESH_BEACON_AD_TYPE, 0x07];
+        data.extend_from_slice(&network_ad());
+        assert!(is_network(IncomingMessage::from_adv_data(&data, None)));
+    }
 }
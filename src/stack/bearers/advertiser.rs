@@ -1 +1,186 @@
+//! Advertising bearer. Network PDUs are advertised as a Mesh Message AD Structure (see
+//! [`OutgoingEncryptedNetworkPDU::adv_data`]). Advertising is unreliable so every PDU is
+//! advertised `count + 1` times, `(steps + 1) * 10ms` apart plus a random 0-10ms delay (see
+//! [`TransmitInterval::network_interval`]).
+//!
+//! `AdvBearer` is an `OutputInterface`. It never waits between transmissions. Each one is handed
+//! to the `AdvertisingConnection` with the delay it should be advertised after instead.
+use crate::foundation::state::NetworkTransmit;
+use crate::interface::OutputInterface;
+use crate::mesh::TransmitInterval;
+use crate::random::Randomizable;
+use crate::stack::bearer::{BearerError, OutgoingEncryptedNetworkPDU};
+use core::time::Duration;
 
+/// Largest random delay (in milliseconds) added before each retransmission.
+pub const MAX_TRANSMIT_JITTER_MS: u8 = 10;
+
+/// Something that can advertise (like an HCI adapter).
+pub trait AdvertisingConnection {
+    /// Advertises `adv_data` once, `delay` after the PDU was handed to the bearer.
+    fn advertise(&mut self, adv_data: &[u8], delay: Duration) -> Result<(), BearerError>;
+}
+/// Delays (from the first transmission) of every transmission of a PDU sent with `transmit`.
+/// `jitter` is called for the random delay added before each retransmission.
+pub fn transmit_delays(
+    transmit: TransmitInterval,
+    mut jitter: impl FnMut() -> Duration,
+) -> impl Iterator<Item = Duration> {
+    let interval = transmit.network_interval();
+    let mut delay = Duration::default();
+    (0..transmit.transmissions()).map(move |i| {
+        if i > 0 {
+            delay += interval + jitter();
+        }
+        delay
+    })
+}
+/// Random delay between 0 and `MAX_TRANSMIT_JITTER_MS`.
+pub fn random_jitter() -> Duration {
+    Duration::from_millis(u64::from(u8::random() % (MAX_TRANSMIT_JITTER_MS + 1)))
+}
+pub struct AdvBearer<Connection: AdvertisingConnection> {
+    connection: Connection,
+    network_transmit: Option<NetworkTransmit>,
+}
+impl<Connection: AdvertisingConnection> AdvBearer<Connection> {
+    /// New bearer sending every PDU with its own `transmit_parameters`.
+    pub fn new(connection: Connection) -> Self {
+        Self {
+            connection,
+            network_transmit: None,
+        }
+    }
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+    pub fn connection_mut(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+    pub fn network_transmit(&self) -> Option<NetworkTransmit> {
+        self.network_transmit
+    }
+    /// Sends every PDU this node originates with `network_transmit` instead of the PDU's own
+    /// `transmit_parameters`. Relayed PDUs keep theirs (the Relay Retransmit state). `None` goes
+    /// back to the PDU's parameters.
+    pub fn set_network_transmit(&mut self, network_transmit: Option<NetworkTransmit>) {
+        self.network_transmit = network_transmit
+    }
+    /// Transmit parameters `pdu` is advertised with.
+    pub fn transmit_parameters(&self, pdu: &OutgoingEncryptedNetworkPDU) -> TransmitInterval {
+        match self.network_transmit {
+            Some(network_transmit) if !pdu.relayed => network_transmit.0,
+            _ => pdu.transmit_parameters,
+        }
+    }
+}
+impl<Connection: AdvertisingConnection> OutputInterface for AdvBearer<Connection> {
+    fn send_pdu(&mut self, pdu: &OutgoingEncryptedNetworkPDU) -> Result<(), BearerError> {
+        let adv_data = pdu.adv_data();
+        for delay in transmit_delays(self.transmit_parameters(pdu), random_jitter) {
+            self.connection.advertise(&adv_data, delay)?;
+        }
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Address;
+    use crate::interface::OutputInterfaces;
+    use crate::mesh::{TransmitCount, TransmitSteps};
+    use crate::net;
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    #[derive(Default)]
+    struct Advertised(Vec<(Box<[u8]>, Duration)>);
+    impl AdvertisingConnection for Advertised {
+        fn advertise(&mut self, adv_data: &[u8], delay: Duration) -> Result<(), BearerError> {
+            self.0.push((adv_data.into(), delay));
+            Ok(())
+        }
+    }
+    fn outgoing_pdu() -> OutgoingEncryptedNetworkPDU {
+        OutgoingEncryptedNetworkPDU {
+            transmit_parameters: TransmitInterval::from(0),
+            pdu: net::OwnedEncryptedPDU::new(&[0x5A; 20]).expect("valid length"),
+            dst: Address::Unassigned,
+            relayed: false,
+        }
+    }
+    #[test]
+    fn test_transmit_delays() {
+        let transmit = TransmitInterval::new(TransmitCount::new(2), TransmitSteps::new(1));
+        assert_eq!(transmit.transmissions(), 3);
+        assert_eq!(transmit.network_interval(), Duration::from_millis(20));
+        assert_eq!(
+            transmit_delays(transmit, Duration::default).collect::<Vec<_>>(),
+            vec![
+                Duration::from_millis(0),
+                Duration::from_millis(20),
+                Duration::from_millis(40)
+            ]
+        );
+        assert_eq!(
+            transmit_delays(transmit, || Duration::from_millis(3)).collect::<Vec<_>>(),
+            vec![
+                Duration::from_millis(0),
+                Duration::from_millis(23),
+                Duration::from_millis(46)
+            ]
+        );
+        assert_eq!(
+            transmit_delays(TransmitInterval::from(0), random_jitter).count(),
+            1
+        );
+    }
+    #[test]
+    fn test_adv_bearer() {
+        let mut bearer = AdvBearer::new(Advertised::default());
+        let outgoing = outgoing_pdu();
+        {
+            let mut outputs = OutputInterfaces::new();
+            outputs.add_interface(&mut bearer);
+            outputs.send_pdu(&outgoing).expect("sent");
+        }
+        assert_eq!(
+            bearer.connection().0,
+            vec![(outgoing.adv_data(), Duration::default())]
+        );
+
+        let network_transmit = NetworkTransmit(TransmitInterval::new(
+            TransmitCount::new(3),
+            TransmitSteps::new(4),
+        ));
+        bearer.connection_mut().0.clear();
+        bearer.set_network_transmit(Some(network_transmit));
+        assert_eq!(bearer.transmit_parameters(&outgoing), network_transmit.0);
+        bearer.send_pdu(&outgoing).expect("sent");
+        let advertised = &bearer.connection().0;
+        assert_eq!(advertised.len(), 4);
+        for (i, window) in advertised.windows(2).enumerate() {
+            assert_eq!(window[1].0, outgoing.adv_data(), "transmission {}", i + 1);
+            let spacing = window[1].1 - window[0].1;
+            assert!(spacing >= Duration::from_millis(50));
+            assert!(spacing <= Duration::from_millis(50 + u64::from(MAX_TRANSMIT_JITTER_MS)));
+        }
+
+        // Relayed PDUs keep their Relay Retransmit parameters.
+        let relayed = OutgoingEncryptedNetworkPDU {
+            transmit_parameters: TransmitInterval::new(
+                TransmitCount::new(1),
+                TransmitSteps::new(0),
+            ),
+            relayed: true,
+            ..outgoing
+        };
+        bearer.connection_mut().0.clear();
+        assert_eq!(
+            bearer.transmit_parameters(&relayed),
+            relayed.transmit_parameters
+        );
+        bearer.send_pdu(&relayed).expect("sent");
+        assert_eq!(bearer.connection().0.len(), 2);
+    }
+}
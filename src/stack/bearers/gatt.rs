@@ -0,0 +1,424 @@
+//! GATT bearer (a Proxy Client connection to this node). Everything over GATT is sent as Proxy
+//! PDUs: a 1-byte header (2-bit SAR and 6-bit message type) and the message (a Network PDU, Mesh
+//! Beacon, Proxy Configuration message or Provisioning PDU). Messages too long for one GATT
+//! write are split across several Proxy PDUs (SAR) and reassembled on the other side.
+//!
+//! `GattBearer` is an `InputInterface`/`OutputInterface` so a stack (like `FullStack`, see
+//! `InterfaceSink`) gets the same `IncomingEncryptedNetworkPDU`s and sends the same
+//! `OutgoingEncryptedNetworkPDU`s as over the advertising bearer.
+use crate::beacon;
+use crate::interface::{InputInterface, InterfaceError, InterfaceSink, OutputInterface};
+use crate::proxy::ProxyFilter;
+use crate::stack::bearer::{
+    BearerError, IncomingBeacon, IncomingEncryptedNetworkPDU, IncomingMessage,
+    OutgoingEncryptedNetworkPDU,
+};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+/// Smallest GATT write payload (the default ATT_MTU of 23 minus the 3 byte ATT header).
+pub const MIN_GATT_MTU: usize = 20;
+const SAR_SHIFT: u8 = 6;
+const TYPE_MASK: u8 = 0x3F;
+
+/// Segmentation And Reassembly state of a Proxy PDU.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum SAR {
+    Complete = 0b00,
+    First = 0b01,
+    Continuation = 0b10,
+    Last = 0b11,
+}
+impl From<SAR> for u8 {
+    fn from(sar: SAR) -> Self {
+        sar as u8
+    }
+}
+impl From<u8> for SAR {
+    /// Only the lower 2 bits are used.
+    fn from(b: u8) -> Self {
+        match b & 0b11 {
+            0b00 => SAR::Complete,
+            0b01 => SAR::First,
+            0b10 => SAR::Continuation,
+            0b11 => SAR::Last,
+            _ => unreachable!("only 2 bits"),
+        }
+    }
+}
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum ProxyPDUType {
+    Network = 0x00,
+    Beacon = 0x01,
+    ProxyConfiguration = 0x02,
+    Provisioning = 0x03,
+}
+impl From<ProxyPDUType> for u8 {
+    fn from(pdu_type: ProxyPDUType) -> Self {
+        pdu_type as u8
+    }
+}
+impl TryFrom<u8> for ProxyPDUType {
+    type Error = ProxySARError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(ProxyPDUType::Network),
+            0x01 => Ok(ProxyPDUType::Beacon),
+            0x02 => Ok(ProxyPDUType::ProxyConfiguration),
+            0x03 => Ok(ProxyPDUType::Provisioning),
+            _ => Err(ProxySARError::BadType),
+        }
+    }
+}
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum ProxySARError {
+    /// Empty Proxy PDU (not even a header).
+    Empty,
+    /// Reserved message type.
+    BadType,
+    /// A Continuation or Last segment without a First segment.
+    NotStarted,
+    /// A segment with a different message type than the First segment.
+    TypeMismatch,
+    /// The reassembled message is longer than `MAX_PROXY_MESSAGE_LEN`.
+    TooLong,
+}
+/// Longest reassembled message accepted (a Provisioning PDU is the longest at 65 bytes).
+pub const MAX_PROXY_MESSAGE_LEN: usize = 66;
+/// A whole (reassembled) message from a Proxy PDU.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct ProxyMessage {
+    pub pdu_type: ProxyPDUType,
+    pub data: Box<[u8]>,
+}
+impl ProxyMessage {
+    pub fn new(pdu_type: ProxyPDUType, data: &[u8]) -> Self {
+        Self {
+            pdu_type,
+            data: data.into(),
+        }
+    }
+    /// Returns the message as if it was received over the advertising bearer. Only Network PDUs
+    /// and Mesh Beacons have an `IncomingMessage`.
+    pub fn incoming_message(&self) -> Option<IncomingMessage> {
+        match self.pdu_type {
+            ProxyPDUType::Network => Some(IncomingMessage::Network(
+                IncomingEncryptedNetworkPDU::from_proxy_pdu(&self.data)?,
+            )),
+            ProxyPDUType::Beacon => Some(IncomingMessage::Beacon(IncomingBeacon {
+                beacon: beacon::BeaconPDU::unpack_from(&self.data).ok()?,
+                rssi: None,
+            })),
+            ProxyPDUType::ProxyConfiguration | ProxyPDUType::Provisioning => None,
+        }
+    }
+    /// Splits the message into Proxy PDUs of at most `mtu` bytes (the GATT write payload size).
+    ///
+    /// # Panics
+    /// Panics if `mtu < 2` (no room for the header and any data).
+    pub fn segments(&self, mtu: usize) -> Vec<Box<[u8]>> {
+        assert!(mtu >= 2, "mtu `{}` too small for a Proxy PDU", mtu);
+        let header = |sar: SAR| u8::from(sar) << SAR_SHIFT | u8::from(self.pdu_type);
+        let pdu = |sar: SAR, data: &[u8]| {
+            let mut pdu = Vec::with_capacity(data.len() + 1);
+            pdu.push(header(sar));
+            pdu.extend_from_slice(data);
+            pdu.into_boxed_slice()
+        };
+        if self.data.len() < mtu {
+            return alloc::vec![pdu(SAR::Complete, &self.data)];
+        }
+        let chunks = self.data.chunks(mtu - 1);
+        let last = chunks.len() - 1;
+        chunks
+            .enumerate()
+            .map(|(i, chunk)| {
+                let sar = match i {
+                    0 => SAR::First,
+                    i if i == last => SAR::Last,
+                    _ => SAR::Continuation,
+                };
+                pdu(sar, chunk)
+            })
+            .collect()
+    }
+}
+/// Reassembles segmented Proxy PDUs from one connection. A new First segment (or a Complete
+/// PDU) drops the message being reassembled.
+#[derive(Clone, Debug, Default)]
+pub struct ProxyReassembler {
+    in_progress: Option<(ProxyPDUType, Vec<u8>)>,
+}
+impl ProxyReassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn is_reassembling(&self) -> bool {
+        self.in_progress.is_some()
+    }
+    /// Feeds one Proxy PDU. Returns the whole message once it's complete. Any error drops the
+    /// message being reassembled.
+    pub fn feed(&mut self, proxy_pdu: &[u8]) -> Result<Option<ProxyMessage>, ProxySARError> {
+        let result = self.feed_inner(proxy_pdu);
+        if result.is_err() {
+            self.in_progress = None;
+        }
+        result
+    }
+    fn feed_inner(&mut self, proxy_pdu: &[u8]) -> Result<Option<ProxyMessage>, ProxySARError> {
+        let (&header, data) = proxy_pdu.split_first().ok_or(ProxySARError::Empty)?;
+        let pdu_type = ProxyPDUType::try_from(header & TYPE_MASK)?;
+        match SAR::from(header >> SAR_SHIFT) {
+            SAR::Complete => {
+                self.in_progress = None;
+                Ok(Some(ProxyMessage::new(pdu_type, data)))
+            }
+            SAR::First => {
+                self.in_progress = Some((pdu_type, data.to_vec()));
+                Ok(None)
+            }
+            sar => {
+                let (in_progress_type, buf) =
+                    self.in_progress.as_mut().ok_or(ProxySARError::NotStarted)?;
+                if *in_progress_type != pdu_type {
+                    return Err(ProxySARError::TypeMismatch);
+                }
+                if buf.len() + data.len() > MAX_PROXY_MESSAGE_LEN {
+                    return Err(ProxySARError::TooLong);
+                }
+                buf.extend_from_slice(data);
+                if sar == SAR::Last {
+                    let (pdu_type, buf) = self.in_progress.take().expect("checked above");
+                    Ok(Some(ProxyMessage::new(pdu_type, &buf)))
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+    }
+}
+/// The GATT side of a Proxy Client connection (writing to the Mesh Proxy Data Out
+/// characteristic).
+pub trait GattConnection {
+    /// Largest value that fits in one GATT notification (ATT_MTU minus 3).
+    fn mtu(&self) -> usize;
+    fn write(&mut self, proxy_pdu: &[u8]) -> Result<(), BearerError>;
+}
+/// GATT bearer for one Proxy Client connection. Outgoing Network PDUs are only forwarded if
+/// their destination passes the connection's `ProxyFilter`.
+pub struct GattBearer<Connection: GattConnection, Sink: InterfaceSink> {
+    connection: Connection,
+    sink: Option<Sink>,
+    reassembler: ProxyReassembler,
+    filter: ProxyFilter,
+}
+impl<Connection: GattConnection, Sink: InterfaceSink> GattBearer<Connection, Sink> {
+    /// A new connection with the default proxy filter (an empty white list).
+    pub fn new(connection: Connection) -> Self {
+        Self {
+            connection,
+            sink: None,
+            reassembler: ProxyReassembler::new(),
+            filter: ProxyFilter::new(),
+        }
+    }
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+    pub fn filter(&self) -> &ProxyFilter {
+        &self.filter
+    }
+    pub fn filter_mut(&mut self) -> &mut ProxyFilter {
+        &mut self.filter
+    }
+    /// Handles a Proxy PDU written by the Proxy Client (to the Mesh Proxy Data In
+    /// characteristic). Reassembled Network PDUs go to the sink. Every other message (beacons,
+    /// Proxy Configuration and Provisioning PDUs) is returned for the caller to handle.
+    ///
+    /// Network PDUs the sink doesn't take are dropped. Once the sink is closed (the stack shut
+    /// down) it's dropped and every later Network PDU is ignored.
+    pub fn handle_proxy_pdu(
+        &mut self,
+        proxy_pdu: &[u8],
+    ) -> Result<Option<ProxyMessage>, ProxySARError> {
+        let msg = match self.reassembler.feed(proxy_pdu)? {
+            Some(msg) => msg,
+            None => return Ok(None),
+        };
+        if let Some(IncomingMessage::Network(pdu)) = msg.incoming_message() {
+            if let Some(sink) = self.sink.as_mut() {
+                if sink.consume_pdu(&pdu) == Err(InterfaceError::Closed) {
+                    self.sink = None;
+                }
+            }
+            return Ok(None);
+        }
+        Ok(Some(msg))
+    }
+    /// Sends `msg` to the Proxy Client (split into as many Proxy PDUs as needed).
+    pub fn send_message(&mut self, msg: &ProxyMessage) -> Result<(), BearerError> {
+        for segment in msg.segments(self.connection.mtu()) {
+            self.connection.write(&segment)?;
+        }
+        Ok(())
+    }
+}
+impl<Connection: GattConnection, Sink: InterfaceSink> InputInterface<Sink>
+    for GattBearer<Connection, Sink>
+{
+    fn take_sink(&mut self, sink: Sink) {
+        self.sink = Some(sink)
+    }
+}
+impl<Connection: GattConnection, Sink: InterfaceSink> OutputInterface
+    for GattBearer<Connection, Sink>
+{
+    fn send_pdu(&mut self, pdu: &OutgoingEncryptedNetworkPDU) -> Result<(), BearerError> {
+        match self.filter.forward(pdu) {
+            Some(network_pdu) => {
+                self.send_message(&ProxyMessage::new(ProxyPDUType::Network, network_pdu))
+            }
+            None => Ok(()),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::{Address, GroupAddress};
+    use crate::interface::OutputInterfaces;
+    use crate::mesh::TransmitInterval;
+    use crate::net;
+
+    #[derive(Default)]
+    struct Written(Vec<Box<[u8]>>);
+    impl GattConnection for Written {
+        fn mtu(&self) -> usize {
+            MIN_GATT_MTU
+        }
+        fn write(&mut self, proxy_pdu: &[u8]) -> Result<(), BearerError> {
+            self.0.push(proxy_pdu.into());
+            Ok(())
+        }
+    }
+    #[derive(Default)]
+    struct Consumed(Vec<IncomingEncryptedNetworkPDU>);
+    impl InterfaceSink for Consumed {
+        fn consume_pdu(&mut self, pdu: &IncomingEncryptedNetworkPDU) -> Result<(), InterfaceError> {
+            self.0.push(*pdu);
+            Ok(())
+        }
+    }
+    /// A sink whose stack shut down.
+    struct Closed;
+    impl InterfaceSink for Closed {
+        fn consume_pdu(
+            &mut self,
+            _pdu: &IncomingEncryptedNetworkPDU,
+        ) -> Result<(), InterfaceError> {
+            Err(InterfaceError::Closed)
+        }
+    }
+    fn network_pdu() -> Vec<u8> {
+        (0..29).collect()
+    }
+    #[test]
+    fn test_segments() {
+        let msg = ProxyMessage::new(ProxyPDUType::Network, &network_pdu());
+        let segments = msg.segments(MIN_GATT_MTU);
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0][0], 0b01 << 6);
+        assert_eq!(segments[0].len(), MIN_GATT_MTU);
+        assert_eq!(segments[1][0], 0b11 << 6);
+        let mut reassembler = ProxyReassembler::new();
+        assert_eq!(reassembler.feed(&segments[0]), Ok(None));
+        assert_eq!(reassembler.feed(&segments[1]), Ok(Some(msg.clone())));
+
+        let short = ProxyMessage::new(ProxyPDUType::ProxyConfiguration, &[0x00, 0x01]);
+        let segments = short.segments(MIN_GATT_MTU);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(&segments[0][..], &[0x02_u8, 0x00, 0x01][..]);
+        assert_eq!(reassembler.feed(&segments[0]), Ok(Some(short)));
+        let three = msg.segments(12);
+        assert_eq!(three.len(), 3);
+        assert_eq!(three[1][0], 0b10 << 6);
+    }
+    #[test]
+    fn test_reassembly_errors() {
+        let msg = ProxyMessage::new(ProxyPDUType::Network, &network_pdu());
+        let segments = msg.segments(12);
+        let mut reassembler = ProxyReassembler::new();
+        assert_eq!(
+            reassembler.feed(&segments[1]),
+            Err(ProxySARError::NotStarted)
+        );
+        assert_eq!(reassembler.feed(&[]), Err(ProxySARError::Empty));
+        assert_eq!(reassembler.feed(&[0x04]), Err(ProxySARError::BadType));
+        // A new First segment starts over.
+        assert_eq!(reassembler.feed(&[0b01 << 6, 0xFF, 0xFF]), Ok(None));
+        assert_eq!(reassembler.feed(&segments[0]), Ok(None));
+        assert_eq!(reassembler.feed(&segments[1]), Ok(None));
+        assert_eq!(reassembler.feed(&segments[2]), Ok(Some(msg)));
+        // Segments of another type drop the message.
+        assert_eq!(reassembler.feed(&segments[0]), Ok(None));
+        assert_eq!(
+            reassembler.feed(&[0b10 << 6 | 0x01, 0x00]),
+            Err(ProxySARError::TypeMismatch)
+        );
+        assert!(!reassembler.is_reassembling());
+    }
+    #[test]
+    fn test_gatt_bearer() {
+        let mut bearer = GattBearer::new(Written::default());
+        bearer.take_sink(Consumed::default());
+        let msg = ProxyMessage::new(ProxyPDUType::Network, &network_pdu());
+        for segment in msg.segments(MIN_GATT_MTU) {
+            assert_eq!(bearer.handle_proxy_pdu(&segment), Ok(None));
+        }
+        let consumed = &bearer.sink.as_ref().expect("sink taken").0;
+        assert_eq!(consumed.len(), 1);
+        assert!(consumed[0].from_proxy);
+        assert_eq!(consumed[0].encrypted_pdu.as_ref(), &network_pdu()[..]);
+        let config = ProxyMessage::new(ProxyPDUType::ProxyConfiguration, &network_pdu());
+        assert_eq!(
+            bearer.handle_proxy_pdu(&config.segments(MIN_GATT_MTU)[0]),
+            Ok(None)
+        );
+        assert_eq!(
+            bearer.handle_proxy_pdu(&config.segments(MIN_GATT_MTU)[1]),
+            Ok(Some(config))
+        );
+
+        let group = Address::Group(GroupAddress::new(0xC001));
+        let outgoing = OutgoingEncryptedNetworkPDU {
+            transmit_parameters: TransmitInterval::from(0),
+            pdu: net::OwnedEncryptedPDU::new(&network_pdu()).expect("valid length"),
+            dst: group,
+            relayed: false,
+        };
+        {
+            let mut outputs = OutputInterfaces::new();
+            outputs.add_interface(&mut bearer);
+            // Nothing passes the empty white list.
+            outputs.send_pdu(&outgoing).expect("sent");
+        }
+        assert!(bearer.connection().0.is_empty());
+        bearer.filter_mut().add(&group);
+        bearer.send_pdu(&outgoing).expect("sent");
+        assert_eq!(bearer.connection().0, msg.segments(MIN_GATT_MTU));
+    }
+    #[test]
+    fn test_closed_sink() {
+        let mut bearer = GattBearer::new(Written::default());
+        bearer.take_sink(Closed);
+        let msg = ProxyMessage::new(ProxyPDUType::Network, &network_pdu());
+        for _ in 0..2 {
+            for segment in msg.segments(MIN_GATT_MTU) {
+                assert_eq!(bearer.handle_proxy_pdu(&segment), Ok(None));
+            }
+            assert!(bearer.sink.is_none());
+        }
+    }
+}
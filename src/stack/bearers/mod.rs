@@ -1 +1,2 @@
 pub mod advertiser;
+pub mod gatt;
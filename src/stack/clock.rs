@@ -0,0 +1,62 @@
+//! Where the stack gets the time from. Every timer in the stack (segment retransmits, acks,
+//! reassembly timeouts, Heartbeats, publications, etc) waits on a [`Clock`] given to it instead
+//! of the global clock so tests can drive time with a manual one (see
+//! `test_util::ManualClock`).
+use crate::asyncs::time;
+use crate::timestamp::{Timestamp, TimestampTrait};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::time::Duration;
+use futures_util::future::Either;
+
+/// Resolves once its `Clock` reaches the deadline it was made with.
+pub type Delay = Pin<Box<dyn Future<Output = ()> + Send>>;
+/// A `Clock` shared by the stack's tasks.
+pub type SharedClock = Arc<dyn Clock>;
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Timestamp;
+    /// Returns a `Delay` that resolves once `now()` is at or past `deadline`.
+    fn delay_until(&self, deadline: Timestamp) -> Delay;
+}
+/// Returned by `Clock::timeout` when the future didn't finish in time.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct Elapsed(());
+impl dyn Clock {
+    pub fn delay_for(&self, duration: Duration) -> Delay {
+        self.delay_until(self.now() + duration)
+    }
+    /// Runs `future` for up to `duration`. `future` wins if both are ready at once.
+    pub async fn timeout<F: Future>(
+        &self,
+        duration: Duration,
+        future: F,
+    ) -> Result<F::Output, Elapsed> {
+        let delay = self.delay_for(duration);
+        futures_util::pin_mut!(future);
+        match futures_util::future::select(future, delay).await {
+            Either::Left((output, _)) => Ok(output),
+            Either::Right(((), _)) => Err(Elapsed(())),
+        }
+    }
+}
+/// The real time (`Timestamp::now`) and the async runtime's timers. The default for the stack.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash, Default)]
+pub struct SystemClock;
+impl SystemClock {
+    pub fn shared() -> SharedClock {
+        Arc::new(SystemClock)
+    }
+}
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        Timestamp::now()
+    }
+
+    fn delay_until(&self, deadline: Timestamp) -> Delay {
+        Box::pin(time::delay_for(
+            Timestamp::now().until(deadline).unwrap_or_default(),
+        ))
+    }
+}
@@ -2,29 +2,252 @@
 //! care of all the stack layer between them.
 //use crate::interface::{InputInterfaces, InterfaceSink, OutputInterfaces};
 
+use crate::beacon::BeaconPDU;
+use crate::control::{self, ControlPDU};
+use crate::relay::RelaySuppression;
 use crate::replay;
-use crate::stack::{incoming, outgoing, RecvError, SendError, StackInternals};
+use crate::stack::{incoming, outgoing, segments, RecvError, SendError, StackInternals};
 
-use crate::asyncs::sync::{mpsc, Mutex, RwLock};
-use crate::stack::bearer::{IncomingEncryptedNetworkPDU, OutgoingMessage};
-use crate::stack::incoming::Incoming;
+use crate::access::ModelIdentifier;
+use crate::address::{Address, GroupAddress, UnicastAddress};
+use crate::asyncs::{
+    sync::{mpsc, oneshot, Mutex, RwLock},
+    task,
+};
+use crate::crypto::KeyRefreshPhases;
+use crate::foundation::{CompositionDataPage0, ProductInfo};
+use crate::friend::lpn::{self, LPNConfig, LPNError};
+use crate::friend::ReceiveDelay;
+use crate::mesh::{ElementIndex, IVIndex, IVUpdateFlag, NetKeyIndex, TTL};
+use crate::models::health::server::{self, AttentionCallback, HealthServer};
+use crate::stack::bearer::{
+    self, IncomingBeacon, IncomingEncryptedNetworkPDU, OutgoingEncryptedNetworkPDU,
+};
+use crate::stack::clock::{SharedClock, SystemClock};
+use crate::stack::incoming::{Incoming, IncomingHealth};
+use crate::stack::messages::{
+    IncomingControlMessage, IncomingMessage, IncomingNetworkPDU, MessageKeys, OutgoingMessage,
+};
 use crate::stack::outgoing::Outgoing;
+use crate::stack::publication::{DuePublication, Publications, Publisher};
+use crate::stack::stats::{Counter, StackStats, StatsCounters};
+use crate::stack::watchdog::TaskWatchdog;
+use crate::timestamp::{Timestamp, TimestampTrait};
+use crate::upper::AppPayload;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::ops::{Deref, DerefMut};
+use core::time::Duration;
 pub struct FullStack {
     pub replay_cache: Arc<Mutex<replay::Cache>>,
     pub internals: Arc<RwLock<StackInternals>>,
-    pub outgoing_bearer: mpsc::Receiver<OutgoingMessage>,
+    pub outgoing_bearer: mpsc::Receiver<bearer::OutgoingMessage>,
     pub incoming_bearer: mpsc::Sender<IncomingEncryptedNetworkPDU>,
     pub incoming: incoming::Incoming,
-    pub outgoing: outgoing::Outgoing,
+    pub outgoing: Arc<outgoing::Outgoing>,
+    /// Segmented messages waiting for their turn (see [`Outgoing::segments_loop`]).
+    /// `None` once [`FullStack::shutdown`] closed it.
+    segments_queue: Mutex<Option<mpsc::Sender<outgoing::QueuedSegments>>>,
+    /// The task running `Outgoing::segments_loop` until [`FullStack::shutdown`] joins it.
+    segments_task: Mutex<Option<task::JoinHandle<Result<(), SendError>>>>,
+    /// Every decrypted Access message for this node. Messages looped back by
+    /// [`FullStack::send_message`] show up here as well.
+    pub incoming_access: mpsc::Receiver<IncomingMessage<Box<[u8]>>>,
+    local_access: mpsc::Sender<IncomingMessage<Box<[u8]>>>,
+    stats: StatsCounters,
+    /// Every successfully decrypted Network PDU (before any upper transport handling) if
+    /// `FullStackOptions::monitor` was set. Useful for sniffers and network level debugging.
+    pub monitor: Option<mpsc::Receiver<IncomingNetworkPDU>>,
+    last_beacon: Mutex<Option<Timestamp>>,
+    /// Every timer and timestamp in the stack comes from this (see [`FullStack::with_clock`]).
+    clock: SharedClock,
+    /// Watches the task relaying PDUs handed off by `incoming` (see `Outgoing::relay_loop`).
+    relay_watchdog: TaskWatchdog,
+    /// Watches the task dispatching Transport Control messages (see `FullStack::handle_control`).
+    control_watchdog: TaskWatchdog,
+    /// Watches the task publishing Heartbeats (see `FullStack::heartbeat_loop`).
+    heartbeat_watchdog: TaskWatchdog,
+    /// Watches the task sending segmented messages (see `Outgoing::segments_loop`).
+    segments_watchdog: TaskWatchdog,
+    /// Watches the task sending the reassembler's acks (see `Outgoing::lower_transport_loop`).
+    transport_watchdog: TaskWatchdog,
+    /// Watches the periodic upkeep task (see `FullStack::maintenance_loop`).
+    maintenance_watchdog: TaskWatchdog,
+    /// The primary element's Health Server (see [`FullStack::set_health_server`]).
+    health_server: SharedHealthServer,
+    /// Friend Offers and Friend Updates for [`FullStack::establish_friendship`].
+    friend_messages: Mutex<mpsc::Receiver<IncomingControlMessage>>,
+    /// Friend Requests, Friend Polls and Friend Subscription List messages from Low Power nodes
+    /// for [`FullStack::friend_loop`].
+    lpn_messages: Mutex<mpsc::Receiver<IncomingControlMessage>>,
     _priv: (),
 }
+type SharedHealthServer = Arc<std::sync::Mutex<Option<HealthServer<AttentionCallback>>>>;
+fn lock_health_server(
+    health_server: &SharedHealthServer,
+) -> std::sync::MutexGuard<'_, Option<HealthServer<AttentionCallback>>> {
+    // The Health Server is only ever locked for a quick (non-panicking) update.
+    health_server
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+/// The Current Status of the stack's Health Server (see [`FullStack::health_publisher`]).
+struct HealthPublisher(SharedHealthServer);
+impl Publisher for HealthPublisher {
+    fn status(&mut self) -> Option<Box<[u8]>> {
+        lock_health_server(&self.0)
+            .as_ref()
+            .map(|health_server| server::pack_message(&health_server.current_status()))
+    }
+    fn publish_period(&mut self, period: Duration) -> Duration {
+        lock_health_server(&self.0)
+            .as_ref()
+            .map_or(period, |health_server| {
+                health_server.fault_publish_period(period)
+            })
+    }
+}
+/// Diagnostic snapshot of a `FullStack`. See [`FullStack::health`].
+#[derive(Clone, Debug)]
+pub struct StackHealth {
+    pub incoming: IncomingHealth,
+    pub relay_alive: bool,
+    pub control_alive: bool,
+    pub heartbeat_alive: bool,
+    pub segments_alive: bool,
+    pub transport_alive: bool,
+    pub maintenance_alive: bool,
+    pub replay_cache_len: usize,
+    pub reassembly_inflight: usize,
+    pub last_beacon: Option<Timestamp>,
+    pub iv_index: IVIndex,
+    pub iv_update_flag: IVUpdateFlag,
+    pub key_refresh_phases: Vec<(NetKeyIndex, KeyRefreshPhases)>,
+}
+impl StackHealth {
+    /// Returns if all the stack tasks are still running.
+    pub fn is_healthy(&self) -> bool {
+        self.incoming.all_alive()
+            && self.relay_alive
+            && self.control_alive
+            && self.heartbeat_alive
+            && self.segments_alive
+            && self.transport_alive
+            && self.maintenance_alive
+    }
+}
+/// Optional settings for `FullStack`. Extra features are off by default, the channel
+/// capacities default to `segments::REASSEMBLER_CHANNEL_LEN` and `segments::SEGMENTS_CHANNEL_LEN`
+/// the reassembly timeout to `segments::INCOMPLETE_TIMEOUT`, the concurrent reassemblies to
+/// `segments::MAX_REASSEMBLIES` and the network message cache to
+/// `replay::NETWORK_MESSAGE_CACHE_LEN` entries.
+///
+/// Larger channels absorb bursts of traffic instead of blocking the processing loops on
+/// `send().await` but every queued item is memory held until it's processed. The reassembler
+/// channel is allocated per reassembly context so it's multiplied by the number of segmented
+/// messages being received at once.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct FullStackOptions {
+    /// Emit decrypted Network PDUs on `FullStack::monitor`. The monitor never blocks the stack so
+    /// PDUs are dropped if the monitor channel is full.
+    pub monitor: bool,
+    /// Segments queued for each incoming segmented message being reassembled.
+    pub reassembler_channel_len: usize,
+    /// Segmented messages queued for sending (see `FullStack::send_message`) and acks queued for
+    /// the one in flight. A full queue blocks senders until a transfer finishes.
+    pub segments_channel_len: usize,
+    /// How long an incoming segmented message waits for its next segment before it's dropped
+    /// (the SAR receiver's incomplete timer). Shorter timeouts free up reassembly contexts
+    /// sooner but give slow senders less time to retransmit.
+    pub reassembly_timeout: Duration,
+    /// Segmented messages reassembled at once. A new one over the limit cancels the oldest.
+    pub max_reassemblies: usize,
+    /// What to do with Control PDUs with unknown opcodes. Drops them with a trace log by default.
+    pub unknown_control_policy: incoming::UnknownControlPolicy,
+    /// Recently received Network PDUs remembered so each one is only relayed once. Nodes with
+    /// many neighbouring relays (or several bearers) should use a bigger cache.
+    pub network_cache_len: usize,
+    /// Relay PDUs heard with a strong RSSI less often (see [`RelaySuppression`]). Every
+    /// relayable PDU is relayed by default.
+    pub relay_suppression: Option<RelaySuppression>,
+}
+impl Default for FullStackOptions {
+    fn default() -> Self {
+        Self {
+            monitor: false,
+            reassembler_channel_len: segments::REASSEMBLER_CHANNEL_LEN,
+            segments_channel_len: segments::SEGMENTS_CHANNEL_LEN,
+            reassembly_timeout: segments::INCOMPLETE_TIMEOUT,
+            max_reassemblies: segments::MAX_REASSEMBLIES,
+            unknown_control_policy: incoming::UnknownControlPolicy::default(),
+            network_cache_len: replay::NETWORK_MESSAGE_CACHE_LEN,
+            relay_suppression: None,
+        }
+    }
+}
+impl FullStackOptions {
+    pub fn monitor(mut self, monitor: bool) -> Self {
+        self.monitor = monitor;
+        self
+    }
+    /// # Panics
+    /// Panics if `channel_len == 0`.
+    pub fn reassembler_channel_len(mut self, channel_len: usize) -> Self {
+        assert_ne!(channel_len, 0, "zero reassembler_channel_len");
+        self.reassembler_channel_len = channel_len;
+        self
+    }
+    /// # Panics
+    /// Panics if `channel_len == 0`.
+    pub fn segments_channel_len(mut self, channel_len: usize) -> Self {
+        assert_ne!(channel_len, 0, "zero segments_channel_len");
+        self.segments_channel_len = channel_len;
+        self
+    }
+    /// # Panics
+    /// Panics if `timeout` is zero.
+    pub fn reassembly_timeout(mut self, timeout: Duration) -> Self {
+        assert_ne!(timeout, Duration::from_secs(0), "zero reassembly_timeout");
+        self.reassembly_timeout = timeout;
+        self
+    }
+    /// # Panics
+    /// Panics if `max_reassemblies == 0`.
+    pub fn max_reassemblies(mut self, max_reassemblies: usize) -> Self {
+        assert_ne!(max_reassemblies, 0, "zero max_reassemblies");
+        self.max_reassemblies = max_reassemblies;
+        self
+    }
+    pub fn unknown_control_policy(mut self, policy: incoming::UnknownControlPolicy) -> Self {
+        self.unknown_control_policy = policy;
+        self
+    }
+    /// # Panics
+    /// Panics if `len == 0`.
+    pub fn network_cache_len(mut self, len: usize) -> Self {
+        assert_ne!(len, 0, "zero network_cache_len");
+        self.network_cache_len = len;
+        self
+    }
+    pub fn relay_suppression(mut self, relay_suppression: Option<RelaySuppression>) -> Self {
+        self.relay_suppression = relay_suppression;
+        self
+    }
+}
 pub enum FullStackError {
     SendError(SendError),
     RecvError(RecvError),
 }
 pub const CONTROL_CHANNEL_SIZE: usize = 5;
+/// How often the Heartbeat publication is checked for due Heartbeats.
+pub const HEARTBEAT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How often `FullStack::publication_loop` checks for due publications (one Publish Retransmit
+/// Interval step).
+pub const PUBLICATION_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How often `FullStack::maintenance_loop` runs (the Attention Timer counts in seconds).
+pub const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+/// How often `FullStack::friend_loop` checks for friendships that timed out.
+pub const FRIEND_EXPIRE_INTERVAL: Duration = Duration::from_millis(500);
 impl FullStack {
     /// Create a new `FullStack` based on `StackInternals` and `replay::Cache`.
     /// `StackInternals` holds the `device_state::State` which should be save persistently for the
@@ -34,15 +257,126 @@ impl FullStack {
         internals: StackInternals,
         replay_cache: replay::Cache,
         channel_size: usize,
+    ) -> Self {
+        Self::with_options(
+            internals,
+            replay_cache,
+            channel_size,
+            FullStackOptions::default(),
+        )
+    }
+    /// Same as `FullStack::new` but with extra `FullStackOptions`.
+    pub fn with_options(
+        internals: StackInternals,
+        replay_cache: replay::Cache,
+        channel_size: usize,
+        options: FullStackOptions,
+    ) -> Self {
+        Self::with_logger(
+            internals,
+            replay_cache,
+            channel_size,
+            options,
+            slog::Logger::root(slog::Discard, slog::o!()),
+        )
+    }
+    /// Same as `FullStack::with_options` but logs to `logger`. Every dropped incoming PDU is
+    /// logged at `Trace` with its `incoming::DropReason`.
+    pub fn with_logger(
+        internals: StackInternals,
+        replay_cache: replay::Cache,
+        channel_size: usize,
+        options: FullStackOptions,
+        logger: slog::Logger,
+    ) -> Self {
+        Self::with_clock(
+            internals,
+            replay_cache,
+            channel_size,
+            options,
+            logger,
+            SystemClock::shared(),
+        )
+    }
+    /// Same as `FullStack::with_logger` but every timer and timestamp in the stack comes from
+    /// `clock` instead of the system clock.
+    pub fn with_clock(
+        internals: StackInternals,
+        replay_cache: replay::Cache,
+        channel_size: usize,
+        options: FullStackOptions,
+        logger: slog::Logger,
+        clock: SharedClock,
     ) -> Self {
         let (tx_bearer, rx_bearer) = mpsc::channel(2);
         let (tx_incoming_encrypted_net, rx_incoming_encrypted_net) = mpsc::channel(channel_size);
-        let (tx_outgoing_transport, _rx_outgoing_transport) = mpsc::channel(channel_size);
-        let (tx_control, _rx_control) = mpsc::channel(CONTROL_CHANNEL_SIZE);
-        let (tx_access, _rx_access) = mpsc::channel(channel_size);
-        let (tx_ack, rx_ack) = mpsc::channel(channel_size);
+        let (tx_outgoing_transport, rx_outgoing_transport) = mpsc::channel(channel_size);
+        let (tx_control, rx_control) = mpsc::channel(CONTROL_CHANNEL_SIZE);
+        let (tx_friend, rx_friend) = mpsc::channel(CONTROL_CHANNEL_SIZE);
+        let (tx_lpn, rx_lpn) = mpsc::channel(CONTROL_CHANNEL_SIZE);
+        let (tx_access, rx_access) = mpsc::channel(channel_size);
+        let (tx_ack, rx_ack) = mpsc::channel(options.segments_channel_len);
+        let (tx_relay, rx_relay) = mpsc::channel(channel_size);
+        let (tx_segments, rx_segments) = mpsc::channel(options.segments_channel_len);
+        let (tx_monitor, rx_monitor) = if options.monitor {
+            let (tx, rx) = mpsc::channel(channel_size);
+            (Some(tx), Some(rx))
+        } else {
+            (None, None)
+        };
         let internals = Arc::new(RwLock::new(internals));
         let replay_cache = Arc::new(Mutex::new(replay_cache));
+        let stats = StatsCounters::new();
+        // Relayed PDUs go straight to the bearer without going through `outgoing`.
+        let relay_watchdog = TaskWatchdog::new();
+        task::spawn(relay_watchdog.watch(Outgoing::relay_loop(
+            internals.clone(),
+            rx_relay,
+            tx_bearer.clone(),
+        )));
+        let heartbeat_watchdog = TaskWatchdog::new();
+        task::spawn(heartbeat_watchdog.watch(Self::heartbeat_loop(
+            internals.clone(),
+            tx_bearer.clone(),
+            clock.clone(),
+            logger.new(slog::o!("stack" => "heartbeat")),
+        )));
+        let control_watchdog = TaskWatchdog::new();
+        task::spawn(control_watchdog.watch(Self::control_loop(
+            internals.clone(),
+            rx_control,
+            tx_ack.clone(),
+            tx_friend,
+            tx_lpn,
+            stats.clone(),
+            clock.clone(),
+            logger.new(slog::o!("stack" => "control")),
+        )));
+        // Segmented messages are sent one at a time so acks always belong to the one in flight.
+        let outgoing = Arc::new(Outgoing::new(
+            internals.clone(),
+            rx_ack,
+            tx_bearer,
+            stats.clone(),
+            clock.clone(),
+        ));
+        let segments_watchdog = TaskWatchdog::new();
+        let segments_task = task::spawn(
+            segments_watchdog.watch(Outgoing::segments_loop(outgoing.clone(), rx_segments)),
+        );
+        let transport_watchdog = TaskWatchdog::new();
+        task::spawn(transport_watchdog.watch(Outgoing::lower_transport_loop(
+            outgoing.clone(),
+            rx_outgoing_transport,
+        )));
+        let health_server = SharedHealthServer::default();
+        let maintenance_watchdog = TaskWatchdog::new();
+        task::spawn(maintenance_watchdog.watch(Self::maintenance_loop(
+            internals.clone(),
+            health_server.clone(),
+            clock.clone(),
+            logger.new(slog::o!("stack" => "maintenance")),
+        )));
 
         // Encrypted Incoming Network PDU Handler.
 
@@ -53,15 +387,43 @@ impl FullStack {
             incoming: Incoming::new(
                 internals.clone(),
                 replay_cache.clone(),
+                options.network_cache_len,
                 rx_incoming_encrypted_net,
+                Some(tx_relay),
+                options.relay_suppression,
                 tx_outgoing_transport,
                 tx_ack,
-                tx_access,
+                tx_access.clone(),
                 tx_control,
+                tx_monitor,
                 channel_size,
+                options.reassembler_channel_len,
+                options.reassembly_timeout,
+                options.max_reassemblies,
+                options.unknown_control_policy,
+                stats.clone(),
+                clock.clone(),
+                logger.new(slog::o!("stack" => "incoming")),
             ),
             replay_cache,
-            outgoing: Outgoing::new(internals, rx_ack, tx_bearer),
+            relay_watchdog,
+            control_watchdog,
+            heartbeat_watchdog,
+            segments_watchdog,
+            transport_watchdog,
+            maintenance_watchdog,
+            health_server,
+            outgoing,
+            segments_queue: Mutex::new(Some(tx_segments)),
+            segments_task: Mutex::new(Some(segments_task)),
+            monitor: rx_monitor,
+            incoming_access: rx_access,
+            local_access: tx_access,
+            stats,
+            last_beacon: Mutex::new(None),
+            clock,
+            friend_messages: Mutex::new(rx_friend),
+            lpn_messages: Mutex::new(rx_lpn),
             _priv: (),
         }
     }
@@ -74,10 +436,1992 @@ impl FullStack {
             .await
             .map_err(|_| RecvError::ChannelClosed)
     }
+    /// Feeds a Network PDU received over the proxy (GATT) bearer (after Proxy SAR reassembly).
+    /// It's handled like any other Network PDU but relayed to the advertising bearer if the
+    /// Proxy feature is enabled.
+    pub async fn feed_proxy_pdu(&mut self, net_pdu: &[u8]) -> Result<(), RecvError> {
+        let pdu = IncomingEncryptedNetworkPDU::from_proxy_pdu(net_pdu)
+            .ok_or(RecvError::MalformedNetworkPDU)?;
+        self.feed_network_pdu(pdu).await
+    }
+    /// Encrypts and sends an Access message. Messages to this node's own addresses are looped
+    /// back to `FullStack::incoming_access` without going through the bearers (see
+    /// [`crate::stack::Loopback`]):
+    /// * A local element unicast address is only delivered locally.
+    /// * A group or virtual address this node is subscribed to is delivered locally and
+    /// transmitted (other nodes might be subscribed to it as well).
+    /// * Anything else is only transmitted.
+    ///
+    /// Segmented messages return once every segment is acked (see [`FullStack::queue_message`]).
+    pub async fn send_message(&self, msg: OutgoingMessage<Box<[u8]>>) -> Result<(), SendError> {
+        self.queue_message(msg)
+            .await?
+            .await
+            .unwrap_or(Err(SendError::ChannelClosed))
+    }
+    /// Same as [`FullStack::send_message`] but only waits for `msg` to be queued. The returned
+    /// `Receiver` gets `Ok(())` when a `BlockAck` acks every segment (right away for unsegmented
+    /// messages and after the last retransmission for group and virtual destinations) or the
+    /// `SendError` the transfer failed with.
+    pub async fn queue_message(
+        &self,
+        msg: OutgoingMessage<Box<[u8]>>,
+    ) -> Result<oneshot::Receiver<Result<(), SendError>>, SendError> {
+        let payload = msg.app_payload.0.clone();
+        let app_key_index = match msg.encryption_key {
+            MessageKeys::App(app_key_index) => Some(app_key_index),
+            MessageKeys::Device(_) => None,
+        };
+        let segmented = msg.should_segment();
+        // Only hold the read lock while encrypting.
+        let (loopback, upper) = {
+            let internals = self.internals.read().await;
+            let loopback = internals.loopback(&msg.dst);
+            (loopback, internals.app_encrypt(msg).map_err(|(e, _)| e)?)
+        };
+        if loopback.is_local() {
+            self.local_access
+                .clone()
+                .send(IncomingMessage {
+                    payload,
+                    src: upper.src,
+                    dst: upper.dst,
+                    seq: upper.seq.start(),
+                    iv_index: upper.iv_index,
+                    net_key_index: upper.net_key_index,
+                    app_key_index,
+                    ttl: upper.ttl,
+                    rssi: None,
+                })
+                .await
+                .ok()
+                .ok_or(SendError::ChannelClosed)?;
+        }
+        if !loopback.is_transmitted() {
+            return Ok(Self::finished(Ok(())));
+        }
+        if segmented {
+            self.queue_segments(upper.into_outgoing_segments()).await
+        } else {
+            let lower = upper
+                .as_unsegmented()
+                .expect("unsegmented access message fits in one PDU");
+            self.outgoing.send_unsegmented(lower).await?;
+            Ok(Self::finished(Ok(())))
+        }
+    }
+    /// A `Receiver` that already got `result`.
+    fn finished(result: Result<(), SendError>) -> oneshot::Receiver<Result<(), SendError>> {
+        let (result_tx, result_rx) = oneshot::channel();
+        // `result_rx` is still alive so this can't fail.
+        let _ = result_tx.send(result);
+        result_rx
+    }
+    /// Sends the Transport Control message `msg` from the primary element (see
+    /// [`StackInternals::control_upper`]). Messages that don't fit in an Unsegmented Control PDU
+    /// are segmented and, for a unicast `dst`, wait for the segments to be acked like segmented
+    /// Access messages do. A `None` `ttl` uses the Default TTL.
+    pub async fn send_control<M: control::ControlMessage>(
+        &self,
+        msg: &M,
+        dst: Address,
+        ttl: Option<TTL>,
+        net_key_index: NetKeyIndex,
+    ) -> Result<(), SendError> {
+        let upper = self
+            .internals
+            .read()
+            .await
+            .control_upper(msg, dst, ttl, net_key_index)?;
+        match upper.as_unsegmented() {
+            Some(lower) => self.outgoing.send_unsegmented(lower).await,
+            None => self.send_segments(upper.into_outgoing_segments()).await,
+        }
+    }
+    /// Queues `segments` behind the other segmented messages (see
+    /// [`Outgoing::segments_loop`]). The returned `Receiver` gets how the transfer ended.
+    async fn queue_segments(
+        &self,
+        segments: segments::OutgoingSegments<Box<[u8]>>,
+    ) -> Result<oneshot::Receiver<Result<(), SendError>>, SendError> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let mut segments_queue = self
+            .segments_queue
+            .lock()
+            .await
+            .clone()
+            .ok_or(SendError::ChannelClosed)?;
+        segments_queue
+            .send((segments, result_tx))
+            .await
+            .ok()
+            .ok_or(SendError::ChannelClosed)?;
+        Ok(result_rx)
+    }
+    /// Stops taking segmented messages (sending one fails with `SendError::ChannelClosed`) and
+    /// waits for the ones already queued to be sent, retransmissions included. Returns how the
+    /// segments task ended. If that takes longer than `deadline`, the one in flight and the rest
+    /// of the queue are aborted (see [`Outgoing::abort_segments`]) and
+    /// `SendError::ShutdownTimeout` is returned. Only the first call waits on anything.
+    pub async fn shutdown(&self, deadline: Duration) -> Result<(), SendError> {
+        // Closing the queue lets `Outgoing::segments_loop` return once it's empty.
+        drop(self.segments_queue.lock().await.take());
+        let mut segments_task = match self.segments_task.lock().await.take() {
+            Some(segments_task) => segments_task,
+            None => return Ok(()),
+        };
+        match self.clock.timeout(deadline, &mut segments_task).await {
+            // A panicked task dropped its channels along with it.
+            Ok(result) => result.unwrap_or(Err(SendError::ChannelClosed)),
+            Err(_) => {
+                self.outgoing.abort_segments().await;
+                let _ = segments_task.await;
+                Err(SendError::ShutdownTimeout)
+            }
+        }
+    }
+    /// Queues `segments` and waits until they're all acked or the transfer fails.
+    async fn send_segments(
+        &self,
+        segments: segments::OutgoingSegments<Box<[u8]>>,
+    ) -> Result<(), SendError> {
+        self.queue_segments(segments)
+            .await?
+            .await
+            .unwrap_or(Err(SendError::ChannelClosed))
+    }
+    /// Hands `msg` (from `FullStack::incoming_access`) to this node's Config Server (see
+    /// [`StackInternals::config_server`]) and sends its response. Composition Data is built from
+    /// the current `DeviceState` and `product`. Returns `Ok(false)` if `msg` isn't a Config
+    /// message secured with the DevKey (or doesn't get a response).
+    pub async fn handle_config_message(
+        &self,
+        msg: &IncomingMessage<Box<[u8]>>,
+        product: &ProductInfo,
+    ) -> Result<bool, SendError> {
+        let now = self.clock.now();
+        let response = self
+            .internals_with_mut(|internals| {
+                let composition_data =
+                    CompositionDataPage0::from_device_state(product, internals.device_state());
+                internals
+                    .config_server()
+                    .with_composition_data(&composition_data)
+                    .handle_message_at(msg, now)
+            })
+            .await;
+        match response {
+            Some(response) => self.send_message(response).await.map(|()| true),
+            None => Ok(false),
+        }
+    }
+    /// Hands `msg` (from `FullStack::incoming_access`) to this node's Config Server or Health
+    /// Server (see [`FullStack::handle_config_message`] and
+    /// [`FullStack::handle_health_message`]) and sends the response. Returns `Ok(false)` if
+    /// neither of them handles `msg`.
+    pub async fn handle_access_message(
+        &self,
+        msg: &IncomingMessage<Box<[u8]>>,
+        product: &ProductInfo,
+    ) -> Result<bool, SendError> {
+        if self.handle_config_message(msg, product).await? {
+            return Ok(true);
+        }
+        self.handle_health_message(msg).await
+    }
+    /// Hands `msg` (from `FullStack::incoming_access`) to the Health Server (see
+    /// [`FullStack::set_health_server`]) and sends its response. Returns `Ok(false)` if there's
+    /// no Health Server or `msg` isn't a Health message secured with an AppKey (or doesn't get a
+    /// response).
+    pub async fn handle_health_message(
+        &self,
+        msg: &IncomingMessage<Box<[u8]>>,
+    ) -> Result<bool, SendError> {
+        let tx_iv_index = self.internals.read().await.tx_iv_index();
+        let response = lock_health_server(&self.health_server)
+            .as_mut()
+            .and_then(|health_server| health_server.handle_message(msg, tx_iv_index));
+        match response {
+            Some(response) => self.send_message(response).await.map(|()| true),
+            None => Ok(false),
+        }
+    }
+    /// Gives the stack a Health Server for the primary element (or removes it with `None`). Its
+    /// Attention Timer is counted down by the maintenance task (see
+    /// `FullStack::maintenance_loop`) and its Current Status is published through
+    /// [`FullStack::health_publisher`].
+    pub fn set_health_server(&self, health_server: Option<HealthServer<AttentionCallback>>) {
+        *lock_health_server(&self.health_server) = health_server;
+    }
+    /// Runs `func` on the Health Server (to set or clear faults, etc). Returns `None` if there's
+    /// no Health Server. Publish right away with [`Publications::publish_now`] after a new
+    /// fault.
+    pub fn with_health_server<R>(
+        &self,
+        func: impl FnOnce(&mut HealthServer<AttentionCallback>) -> R,
+    ) -> Option<R> {
+        lock_health_server(&self.health_server).as_mut().map(func)
+    }
+    /// Publishes the Health Server's Current Status. Add it to the [`Publications`] given to
+    /// [`FullStack::publication_loop`] for the Health Server model on the primary element. It
+    /// publishes faster while there are current faults (see
+    /// [`HealthServer::fault_publish_period`]).
+    pub fn health_publisher(&self) -> Box<dyn Publisher> {
+        Box::new(HealthPublisher(self.health_server.clone()))
+    }
+    /// Sends `publication` with its model's publish settings (see
+    /// [`StackInternals::publish_message`]). Does nothing if the model stopped publishing.
+    pub async fn send_publication(&self, publication: DuePublication) -> Result<(), SendError> {
+        let msg = self
+            .internals_with(|internals| {
+                internals.publish_message(
+                    publication.element_index,
+                    &publication.model_identifier,
+                    AppPayload::new(publication.payload),
+                )
+            })
+            .await;
+        match msg {
+            Some(msg) => self.send_message(msg).await,
+            None => Ok(()),
+        }
+    }
+    /// Publishes and retransmits the status of every model in `publications` when it's due (see
+    /// [`Publications::poll`]). `publications` is checked every `PUBLICATION_POLL_INTERVAL` and
+    /// can be changed (or published right away with [`Publications::publish_now`]) while this
+    /// runs. A publication that can't be sent (an AppKey that doesn't exist, etc) is skipped.
+    /// Only returns if the stack channels are closed.
+    pub async fn publication_loop(&self, publications: &Mutex<Publications>) -> SendError {
+        loop {
+            self.clock.delay_for(PUBLICATION_POLL_INTERVAL).await;
+            let due = {
+                let internals = self.internals.read().await;
+                publications
+                    .lock()
+                    .await
+                    .poll(internals.device_state().models(), self.clock.now())
+            };
+            for publication in due {
+                match self.send_publication(publication).await {
+                    Err(SendError::ChannelClosed) => return SendError::ChannelClosed,
+                    Ok(()) | Err(_) => (),
+                }
+            }
+        }
+    }
+    /// Sends the Heartbeats published by `StackInternals::heartbeat_publication` straight to the
+    /// bearer. The publication is polled every `HEARTBEAT_POLL_INTERVAL`.
+    async fn heartbeat_loop(
+        internals: Arc<RwLock<StackInternals>>,
+        mut outgoing_network: mpsc::Sender<bearer::OutgoingMessage>,
+        clock: SharedClock,
+        logger: slog::Logger,
+    ) -> Result<(), SendError> {
+        loop {
+            clock.delay_for(HEARTBEAT_POLL_INTERVAL).await;
+            // The write lock is released before waiting on the bearer.
+            let outgoing_pdu = internals.write().await.heartbeat_pdu(clock.now());
+            match outgoing_pdu {
+                Ok(Some(outgoing_pdu)) => outgoing_network
+                    .send(bearer::OutgoingMessage::Network(outgoing_pdu))
+                    .await
+                    .ok()
+                    .ok_or(SendError::ChannelClosed)?,
+                Ok(None) => (),
+                Err(e) => slog::debug!(logger, "heartbeat_not_sent"; "error" => ?e),
+            }
+        }
+    }
+    /// Periodic upkeep every `MAINTENANCE_INTERVAL`:
+    /// * Counts the Health Server's Attention Timer down.
+    /// * Starts (and later finishes) an IV Update once an element runs out of Sequence Numbers
+    /// (see [`StackInternals::handle_seq_exhausted`]).
+    async fn maintenance_loop(
+        internals: Arc<RwLock<StackInternals>>,
+        health_server: SharedHealthServer,
+        clock: SharedClock,
+        logger: slog::Logger,
+    ) -> Result<(), SendError> {
+        loop {
+            clock.delay_for(MAINTENANCE_INTERVAL).await;
+            if let Some(health_server) = lock_health_server(&health_server).as_mut() {
+                health_server.tick();
+            }
+            // Only take the write lock if there's something to do.
+            if !internals.read().await.device_state().is_seq_exhausted() {
+                continue;
+            }
+            match internals.write().await.handle_seq_exhausted(clock.now()) {
+                Ok(false) => (),
+                Ok(true) => slog::info!(logger, "seq_exhausted_iv_update"),
+                Err(e) => slog::debug!(logger, "seq_exhausted_iv_update_failed"; "error" => ?e),
+            }
+        }
+    }
+    async fn control_loop(
+        internals: Arc<RwLock<StackInternals>>,
+        mut incoming: mpsc::Receiver<IncomingControlMessage>,
+        mut tx_ack: mpsc::Sender<segments::IncomingPDU<control::Ack>>,
+        mut tx_friend: mpsc::Sender<IncomingControlMessage>,
+        mut tx_lpn: mpsc::Sender<IncomingControlMessage>,
+        stats: StatsCounters,
+        clock: SharedClock,
+        logger: slog::Logger,
+    ) -> Result<(), RecvError> {
+        loop {
+            let next = incoming.recv().await.ok_or(RecvError::ChannelClosed)?;
+            match Self::handle_control(
+                &internals,
+                &mut tx_ack,
+                &mut tx_friend,
+                &mut tx_lpn,
+                next,
+                clock.now(),
+                &stats,
+                &logger,
+            )
+            .await
+            {
+                Ok(()) => (),
+                Err(RecvError::ChannelClosed) => return Err(RecvError::ChannelClosed),
+                Err(e) => slog::debug!(logger, "control_dropped"; "error" => ?e),
+            }
+        }
+    }
+    /// Dispatches a Transport Control message by its opcode:
+    /// * Segment Acknowledgements go to the outgoing segmented message in flight (see
+    /// [`Outgoing::send_segments`]). They're dropped if `tx_ack` is full (see
+    /// [`segments::forward_ack`]).
+    /// * Friend Offers and Friend Updates go to `tx_friend` (for
+    /// [`FullStack::establish_friendship`]). They're dropped if nobody is establishing a
+    /// friendship to take them.
+    /// * Friend Requests, Friend Polls and Friend Subscription List Adds and Removes go to
+    /// `tx_lpn` (for [`FullStack::friend_loop`]). They're dropped if the Friend feature isn't
+    /// running. The other Friend messages are ignored (logged at `Trace`).
+    /// * Heartbeats go to `FullStack::handle_heartbeat` (received at `now`).
+    ///
+    /// Control PDUs with unknown opcodes never get this far. They're dropped (or canceled) by
+    /// `incoming` according to `FullStackOptions::unknown_control_policy`.
+    pub async fn handle_control(
+        internals: &RwLock<StackInternals>,
+        tx_ack: &mut mpsc::Sender<segments::IncomingPDU<control::Ack>>,
+        tx_friend: &mut mpsc::Sender<IncomingControlMessage>,
+        tx_lpn: &mut mpsc::Sender<IncomingControlMessage>,
+        msg: IncomingControlMessage,
+        now: Timestamp,
+        stats: &StatsCounters,
+        logger: &slog::Logger,
+    ) -> Result<(), RecvError> {
+        match &msg.control_pdu {
+            ControlPDU::Ack(ack) => {
+                stats.count(Counter::AckReceived);
+                let forwarded = segments::forward_ack(
+                    tx_ack,
+                    segments::IncomingPDU {
+                        pdu: *ack,
+                        seq: msg.seq,
+                        iv_index: msg.iv_index,
+                        net_key_index: msg.net_key_index,
+                        src: msg.src,
+                        dst: msg.dst,
+                        ttl: msg.ttl.ok_or(RecvError::MalformedControlPDU)?,
+                    },
+                )?;
+                if !forwarded {
+                    slog::trace!(logger, "ack_dropped";
+                        "seq_zero" => ?ack.seq_zero, "src" => ?msg.src);
+                }
+                Ok(())
+            }
+            ControlPDU::FriendOffer(_) | ControlPDU::FriendUpdate(_) => {
+                let opcode = msg.control_pdu.opcode();
+                let src = msg.src;
+                if tx_friend.try_send(msg).is_err() {
+                    slog::trace!(logger, "friend_message_dropped";
+                        "opcode" => ?opcode, "src" => ?src);
+                }
+                Ok(())
+            }
+            ControlPDU::FriendPoll(_)
+            | ControlPDU::FriendRequest(_)
+            | ControlPDU::FriendSubscriptionListAdd(_)
+            | ControlPDU::FriendSubscriptionListRemove(_) => {
+                let opcode = msg.control_pdu.opcode();
+                let src = msg.src;
+                if tx_lpn.try_send(msg).is_err() {
+                    slog::trace!(logger, "lpn_message_dropped";
+                        "opcode" => ?opcode, "src" => ?src);
+                }
+                Ok(())
+            }
+            ControlPDU::FriendClear(_)
+            | ControlPDU::FriendClearConfirm(_)
+            | ControlPDU::FriendSubscriptionListConfirm(_) => {
+                slog::trace!(logger, "friend_message_ignored";
+                    "opcode" => ?msg.control_pdu.opcode(), "src" => ?msg.src);
+                Ok(())
+            }
+            ControlPDU::Heartbeat(heartbeat) => {
+                Self::handle_heartbeat(internals, heartbeat, &msg, now, logger).await;
+                Ok(())
+            }
+        }
+    }
+    /// Counts a Heartbeat received at `now` in `StackInternals::heartbeat_subscription` (if it
+    /// matches the subscription) and logs it at `Debug`.
+    pub async fn handle_heartbeat(
+        internals: &RwLock<StackInternals>,
+        heartbeat: &control::Heartbeat,
+        msg: &IncomingControlMessage,
+        now: Timestamp,
+        logger: &slog::Logger,
+    ) {
+        let counted = match msg.ttl {
+            Some(ttl) => internals
+                .write()
+                .await
+                .heartbeat_subscription_mut()
+                .handle(msg.src, &msg.dst, heartbeat, ttl, now),
+            None => false,
+        };
+        slog::debug!(logger, "heartbeat_received";
+            "src" => ?msg.src, "dst" => ?msg.dst, "init_ttl" => ?heartbeat.init_ttl,
+            "ttl" => ?msg.ttl, "features" => ?heartbeat.features, "counted" => counted);
+    }
+    /// Establishes a friendship with a Friend as a Low Power node on the NetKey `net_key_index`.
+    /// Broadcasts a Friend Request, collects Friend Offers for `lpn::OFFER_WINDOW`, accepts the
+    /// best one (see [`LowPowerNode::best_offer`](crate::friend::lpn::LowPowerNode::best_offer))
+    /// and polls the Friend until it answers with a Friend Update (up to `lpn::POLL_ATTEMPTS`
+    /// Friend Polls). Returns the Friend's address. The friendship is kept in
+    /// `StackInternals::low_power_node` and is cleared if it can't be established.
+    pub async fn establish_friendship(
+        &self,
+        config: &LPNConfig,
+        net_key_index: NetKeyIndex,
+    ) -> Result<UnicastAddress, LPNError> {
+        let mut friend_messages = self.friend_messages.lock().await;
+        // Drop anything left over from before this Friend Request.
+        while friend_messages.try_recv().is_ok() {}
+        let result = self
+            .try_establish_friendship(&mut friend_messages, config, net_key_index)
+            .await;
+        if result.is_err() {
+            let mut internals = self.internals.write().await;
+            internals.low_power_node_mut().clear();
+            internals.refresh_nid_index();
+        }
+        result
+    }
+    async fn try_establish_friendship(
+        &self,
+        friend_messages: &mut mpsc::Receiver<IncomingControlMessage>,
+        config: &LPNConfig,
+        net_key_index: NetKeyIndex,
+    ) -> Result<UnicastAddress, LPNError> {
+        let request = {
+            let mut internals = self.internals.write().await;
+            let num_elements = internals.device_state().element_count().0;
+            let request =
+                internals
+                    .low_power_node_mut()
+                    .friend_request(config, num_elements, net_key_index);
+            internals.control_pdu(
+                &control::FriendRequest(request),
+                Address::Group(GroupAddress::all_friends()),
+                TTL::new(0),
+                net_key_index,
+            )?
+        };
+        // The lock on StackInternals is released before waiting on the bearer.
+        self.outgoing.send_encrypted_network_pdu(request).await?;
+        let offers_end = self.clock.now() + lpn::OFFER_DELAY + lpn::OFFER_WINDOW;
+        while let Some(msg) = self
+            .next_friend_message(friend_messages, offers_end)
+            .await?
+        {
+            if let ControlPDU::FriendOffer(offer) = msg.control_pdu {
+                self.internals
+                    .write()
+                    .await
+                    .low_power_node_mut()
+                    .handle_offer(msg.src, msg.net_key_index, offer.0);
+            }
+        }
+        let (friend, poll_window) = {
+            let mut internals = self.internals.write().await;
+            let lpn_address = internals
+                .device_state()
+                .element_address(ElementIndex(0))
+                .expect("primary element always exists");
+            let low_power_node = internals.low_power_node_mut();
+            let offer = low_power_node.best_offer().ok_or(LPNError::NoOffer)?;
+            low_power_node.accept_offer(lpn_address, &offer, self.clock.now());
+            // The Friend's answers come with the new friendship credentials.
+            internals.refresh_nid_index();
+            (
+                offer.friend_address,
+                config.receive_delay.as_duration()
+                    + Duration::from_millis(offer.offer.receive_window.into()),
+            )
+        };
+        for _ in 0..lpn::POLL_ATTEMPTS {
+            let poll = {
+                let mut internals = self.internals.write().await;
+                let poll = internals
+                    .low_power_node_mut()
+                    .friend_poll(self.clock.now())
+                    .expect("offer was just accepted");
+                internals.control_pdu(
+                    &control::FriendPoll(poll),
+                    Address::Unicast(friend),
+                    TTL::new(0),
+                    net_key_index,
+                )?
+            };
+            self.outgoing.send_encrypted_network_pdu(poll).await?;
+            let poll_end = self.clock.now() + poll_window;
+            while let Some(msg) = self.next_friend_message(friend_messages, poll_end).await? {
+                if let ControlPDU::FriendUpdate(update) = msg.control_pdu {
+                    if self
+                        .internals
+                        .write()
+                        .await
+                        .low_power_node_mut()
+                        .handle_update(msg.src, &update.0)
+                    {
+                        return Ok(friend);
+                    }
+                }
+            }
+        }
+        Err(LPNError::NoFriendUpdate)
+    }
+    /// Waits for the next Friend Offer or Friend Update until `deadline`. Returns `Ok(None)` once
+    /// `deadline` passes.
+    async fn next_friend_message(
+        &self,
+        friend_messages: &mut mpsc::Receiver<IncomingControlMessage>,
+        deadline: Timestamp,
+    ) -> Result<Option<IncomingControlMessage>, LPNError> {
+        let remaining = match self.clock.now().until(deadline) {
+            Some(remaining) => remaining,
+            None => return Ok(None),
+        };
+        match self.clock.timeout(remaining, friend_messages.recv()).await {
+            Ok(Some(msg)) => Ok(Some(msg)),
+            Ok(None) => Err(LPNError::SendError(SendError::ChannelClosed)),
+            Err(_) => Ok(None),
+        }
+    }
+    /// Runs the Friend feature (see [`StackInternals::set_friend_node`]). Answers Friend
+    /// Requests, Friend Polls and Friend Subscription List messages from Low Power nodes once the
+    /// delay they expect has passed and ends the friendships that timed out (checked at least
+    /// every `FRIEND_EXPIRE_INTERVAL`). Messages are dropped if the Friend feature is disabled
+    /// and responses that can't be encrypted (an unknown NetKey, etc) are never sent. Only
+    /// returns if the stack channels are closed.
+    pub async fn friend_loop(&self) -> SendError {
+        let mut lpn_messages = self.lpn_messages.lock().await;
+        loop {
+            let msg = match self
+                .clock
+                .timeout(FRIEND_EXPIRE_INTERVAL, lpn_messages.recv())
+                .await
+            {
+                Ok(Some(msg)) => Some(msg),
+                Ok(None) => return SendError::ChannelClosed,
+                Err(_) => None,
+            };
+            let now = self.clock.now();
+            let response = {
+                let mut internals = self.internals.write().await;
+                internals.expire_friendships(now);
+                match msg {
+                    Some(msg) => Self::friend_response(&mut internals, &msg, now),
+                    None => Ok(None),
+                }
+            };
+            // The lock on StackInternals is released before waiting on the bearer.
+            if let Ok(Some((delay, pdu))) = response {
+                let mut outgoing_network = self.outgoing.outgoing_network.lock().await.clone();
+                let receive_delay = self.clock.delay_for(delay);
+                task::spawn(async move {
+                    receive_delay.await;
+                    let _ = outgoing_network
+                        .send(bearer::OutgoingMessage::Network(pdu))
+                        .await;
+                });
+            }
+        }
+    }
+    /// Returns the response to a message from a Low Power node and how long to wait before
+    /// sending it.
+    fn friend_response(
+        internals: &mut StackInternals,
+        msg: &IncomingControlMessage,
+        now: Timestamp,
+    ) -> Result<Option<(Duration, OutgoingEncryptedNetworkPDU)>, SendError> {
+        let with_delay = |response: Option<(ReceiveDelay, OutgoingEncryptedNetworkPDU)>| {
+            response.map(|(receive_delay, pdu)| (receive_delay.as_duration(), pdu))
+        };
+        match &msg.control_pdu {
+            ControlPDU::FriendRequest(request) => internals.friend_offer(
+                msg.src,
+                &request.0,
+                msg.rssi.map(i8::from),
+                msg.net_key_index,
+                now,
+            ),
+            ControlPDU::FriendPoll(poll) => internals
+                .friend_poll(msg.src, &poll.0, msg.net_key_index, now)
+                .map(with_delay),
+            ControlPDU::FriendSubscriptionListAdd(list) => internals
+                .friend_subscription_list(msg.src, &list.0, true, msg.net_key_index, now)
+                .map(with_delay),
+            ControlPDU::FriendSubscriptionListRemove(list) => internals
+                .friend_subscription_list(msg.src, &list.0, false, msg.net_key_index, now)
+                .map(with_delay),
+            _ => Ok(None),
+        }
+    }
+    /// Records when the last beacon was received (see [`FullStack::health`]). Secure Network
+    /// Beacons authenticated by one of our NetKeys update the IV Index (see
+    /// `StackInternals::update_iv_index`). Unauthenticated ones and IV Indexes the IV Update
+    /// procedure doesn't allow yet are silently ignored.
+    pub async fn feed_beacon(&self, beacon: &IncomingBeacon) {
+        self.stats.count(Counter::BeaconReceived);
+        let now = self.clock.now();
+        *self.last_beacon.lock().await = Some(now);
+        if let BeaconPDU::SecureNetwork(secure_network) = &beacon.beacon {
+            // Only take the write lock for beacons that are worth it.
+            let verified = self.internals.read().await.verify_beacon(secure_network);
+            if let Some((_, verified)) = verified {
+                let _ = self.internals.write().await.update_iv_index(&verified, now);
+            }
+        }
+    }
+    /// The clock every timer in the stack runs on (see [`FullStack::with_clock`]).
+    pub fn clock(&self) -> &SharedClock {
+        &self.clock
+    }
+    /// Returns a snapshot of the stack's counters (PDUs received, relayed, dropped, etc). Never
+    /// locks anything.
+    pub fn stats(&self) -> StackStats {
+        self.stats.snapshot()
+    }
+    /// Zeros every counter returned by [`FullStack::stats`].
+    pub fn reset_stats(&self) {
+        self.stats.reset()
+    }
+    /// Returns a diagnostic snapshot of the stack. Each lock is only held long enough to copy
+    /// out its part of the report and never more than one lock at a time.
+    pub async fn health(&self) -> StackHealth {
+        let replay_cache_len = self.replay_cache.lock().await.len();
+        let reassembly_inflight = self.incoming.reassembly_inflight();
+        let last_beacon = *self.last_beacon.lock().await;
+        let (iv_index, iv_update_flag, key_refresh_phases) = self
+            .internals_with(|internals| {
+                let device_state = internals.device_state();
+                (
+                    device_state.iv_index(),
+                    device_state.iv_update_flag(),
+                    internals
+                        .net_keys()
+                        .map
+                        .iter()
+                        .map(|(&index, phase)| (index, phase.phase()))
+                        .collect(),
+                )
+            })
+            .await;
+        StackHealth {
+            incoming: self.incoming.tasks_health(),
+            relay_alive: self.relay_watchdog.is_alive(),
+            control_alive: self.control_watchdog.is_alive(),
+            heartbeat_alive: self.heartbeat_watchdog.is_alive(),
+            segments_alive: self.segments_watchdog.is_alive(),
+            transport_alive: self.transport_watchdog.is_alive(),
+            maintenance_alive: self.maintenance_watchdog.is_alive(),
+            replay_cache_len,
+            reassembly_inflight,
+            last_beacon,
+            iv_index,
+            iv_update_flag,
+            key_refresh_phases,
+        }
+    }
     pub async fn internals_with<R>(&self, func: impl FnOnce(&StackInternals) -> R) -> R {
         func(self.internals.read().await.deref())
     }
+    /// Runs `func` under the write lock. Anything `func` changed in the keys or friendships is
+    /// picked up by the `NIDIndex` (see [`StackInternals::refresh_nid_index`]) and a changed
+    /// Friend state by the Friend feature (see [`StackInternals::sync_friend_node`]) before the
+    /// lock is released.
     pub async fn internals_with_mut<R>(&self, func: impl FnOnce(&mut StackInternals) -> R) -> R {
-        func(self.internals.write().await.deref_mut())
+        let mut internals = self.internals.write().await;
+        let result = func(internals.deref_mut());
+        internals.sync_friend_node();
+        internals.refresh_nid_index();
+        result
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::ModelIdentifier;
+    use crate::address::{Address, GroupAddress, UnicastAddress};
+    use crate::control::ControlMessage;
+    use crate::crypto::aes::MicSize;
+    use crate::crypto::key::{AppKey, NetKey};
+    use crate::device_state::{DeviceState, ModelInfo};
+    use crate::foundation::state::RelayState;
+    use crate::foundation::FeatureFlags;
+    use crate::mesh::{
+        AppKeyIndex, ElementCount, ElementIndex, KeyIndex, ModelID, SequenceNumber, CTL, TTL, U24,
+    };
+    use crate::random::Randomizable;
+    use crate::stack::bearer;
+    use crate::stack::clock::Clock;
+    use crate::test_util::ManualClock;
+    use crate::upper::AppPayload;
+    use crate::{lower, net};
+
+    const GROUP: u16 = 0xC001;
+    /// Timers the stack starts on its own (the Heartbeat and maintenance loops). Wait for them
+    /// (`ManualClock::wait_for_timers`) before advancing a new clock so they tick.
+    const STACK_TIMERS: u64 = 2;
+
+    fn app_key_index() -> AppKeyIndex {
+        AppKeyIndex(KeyIndex::new(1))
+    }
+    /// A stack on `clock`.
+    fn stack_with_clock(internals: StackInternals, clock: &ManualClock) -> FullStack {
+        FullStack::with_clock(
+            internals,
+            replay::Cache::default(),
+            4,
+            FullStackOptions::default(),
+            slog::Logger::root(slog::Discard, slog::o!()),
+            clock.shared(),
+        )
+    }
+    /// Two element node where a model on the second element subscribes to `GROUP`.
+    fn two_element_internals() -> StackInternals {
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        let mut device_state = DeviceState::new(UnicastAddress::new(0x0002), ElementCount(2));
+        device_state
+            .security_materials_mut()
+            .net_key_map
+            .insert(net_key_index, &NetKey::random_secure());
+        let mut subscriber = ModelInfo::default();
+        assert!(subscriber.add_subscription(Address::Group(GroupAddress::new(GROUP))));
+        device_state
+            .models_mut()
+            .insert(ModelIdentifier::new_sig(ModelID(0x1001)), subscriber);
+        let mut internals = StackInternals::new(device_state);
+        internals
+            .add_app_key(net_key_index, app_key_index(), AppKey::random_secure())
+            .expect("net key was just added");
+        internals
+    }
+    /// `two_element_internals` on a clock that never moves.
+    fn two_element_stack() -> FullStack {
+        stack_with_clock(two_element_internals(), &ManualClock::new())
+    }
+    fn message(dst: Address) -> OutgoingMessage<Box<[u8]>> {
+        OutgoingMessage {
+            app_payload: AppPayload(Box::from(&[0x82_u8, 0x04, 0x01][..])),
+            mic_size: MicSize::Small,
+            force_segment: false,
+            encryption_key: MessageKeys::App(app_key_index()),
+            iv_index: IVIndex(0),
+            source_element_index: ElementIndex(0),
+            dst,
+            ttl: None,
+        }
+    }
+    #[tokio::test]
+    async fn test_group_loopback() {
+        let mut stack = two_element_stack();
+        let group = Address::Group(GroupAddress::new(GROUP));
+        stack
+            .send_message(message(group))
+            .await
+            .expect("message sent");
+        // The subscribed second element gets it without going through a bearer...
+        let looped = stack
+            .incoming_access
+            .try_recv()
+            .expect("message looped back");
+        assert_eq!(looped.src, UnicastAddress::new(0x0002));
+        assert_eq!(looped.dst, group);
+        assert_eq!(&looped.payload[..], &[0x82_u8, 0x04, 0x01][..]);
+        assert_eq!(looped.app_key_index, Some(app_key_index()));
+        // ...and it still goes out for any other subscribers.
+        assert!(stack.outgoing_bearer.try_recv().is_ok());
+    }
+    #[tokio::test]
+    async fn test_config_composition_data_get() {
+        use crate::foundation::{ProductID, VersionID, CRPL};
+        use crate::models::config::messages::composition_data;
+        use crate::models::PackableMessage;
+
+        let mut stack = two_element_stack();
+        let product = ProductInfo {
+            cid: crate::mesh::CompanyID(0x05F1),
+            pid: ProductID(0x0001),
+            vid: VersionID(0x0002),
+            crpl: CRPL(32),
+        };
+        let mut payload = alloc::vec![0_u8; 3].into_boxed_slice();
+        composition_data::Get(0)
+            .pack_with_opcode(&mut payload[..])
+            .ok()
+            .expect("buffer is big enough");
+        // Sent from our own second element so the (segmented) status is looped back instead of
+        // waiting for acks.
+        let mut request = IncomingMessage {
+            payload,
+            src: UnicastAddress::new(0x0003),
+            dst: Address::Unicast(UnicastAddress::new(0x0002)),
+            seq: SequenceNumber(U24::new(1)),
+            iv_index: IVIndex(0),
+            net_key_index: NetKeyIndex(KeyIndex::new(0)),
+            app_key_index: None,
+            ttl: None,
+            rssi: None,
+        };
+        assert_eq!(
+            stack.handle_config_message(&request, &product).await,
+            Ok(true)
+        );
+        let status = stack
+            .incoming_access
+            .try_recv()
+            .expect("status looped back");
+        assert_eq!(status.dst, Address::Unicast(UnicastAddress::new(0x0003)));
+        assert_eq!(status.app_key_index, None);
+        let (opcode, parameters) =
+            crate::access::Opcode::split_from(&status.payload[..]).expect("status has an opcode");
+        assert_eq!(opcode, composition_data::Status::opcode());
+        match composition_data::Status::unpack_from(parameters) {
+            Ok(status) => assert_eq!(status.page.elements().elements().len(), 2),
+            Err(_) => panic!("status should unpack"),
+        }
+
+        // Config messages are only ever secured with the DevKey.
+        request.app_key_index = Some(app_key_index());
+        assert_eq!(
+            stack.handle_config_message(&request, &product).await,
+            Ok(false)
+        );
+    }
+    #[tokio::test]
+    async fn test_friend_set_disabled_ends_friendships() {
+        use crate::foundation::state::FriendState;
+        use crate::foundation::{ProductID, VersionID, CRPL};
+        use crate::friend::friend::{FriendConfig, FriendNode};
+        use crate::friend::{FriendCounter, FriendshipCredentials, LPNCounter, PollTimeout};
+        use crate::models::config::messages::friend;
+        use crate::models::PackableMessage;
+
+        let mut stack = two_element_stack();
+        let product = ProductInfo {
+            cid: crate::mesh::CompanyID(0x05F1),
+            pid: ProductID(0x0001),
+            vid: VersionID(0x0002),
+            crpl: CRPL(32),
+        };
+        let lpn = UnicastAddress::new(0x0100);
+        let now = stack.clock().now();
+        stack
+            .internals_with_mut(|internals| {
+                internals.set_friend_node(Some(FriendNode::new(FriendConfig::default())));
+                internals.friendships_mut().establish(
+                    FriendshipCredentials {
+                        lpn_address: lpn,
+                        friend_address: UnicastAddress::new(0x0002),
+                        lpn_counter: LPNCounter::new(0),
+                        friend_counter: FriendCounter::new(0),
+                    },
+                    PollTimeout::MIN,
+                    now,
+                );
+            })
+            .await;
+        assert_eq!(
+            stack
+                .internals_with(|internals| internals.security_credentials(&Address::Unicast(lpn)))
+                .await,
+            crate::stack::SecurityCredentials::Friendship(lpn)
+        );
+
+        let mut payload = alloc::vec![0_u8; 3].into_boxed_slice();
+        friend::Set(FriendState::Disabled)
+            .pack_with_opcode(&mut payload[..])
+            .ok()
+            .expect("buffer is big enough");
+        let request = IncomingMessage {
+            payload,
+            src: UnicastAddress::new(0x0003),
+            dst: Address::Unicast(UnicastAddress::new(0x0002)),
+            seq: SequenceNumber(U24::new(1)),
+            iv_index: IVIndex(0),
+            net_key_index: NetKeyIndex(KeyIndex::new(0)),
+            app_key_index: None,
+            ttl: None,
+            rssi: None,
+        };
+        assert_eq!(
+            stack.handle_config_message(&request, &product).await,
+            Ok(true)
+        );
+        stack
+            .internals_with(|internals| {
+                assert_eq!(
+                    internals.device_state().friend_state(),
+                    FriendState::Disabled
+                );
+                assert!(internals.friend_node().is_none());
+                assert!(internals.friendships().is_empty());
+                assert_eq!(
+                    internals.security_credentials(&Address::Unicast(lpn)),
+                    crate::stack::SecurityCredentials::ManagedFlooding
+                );
+            })
+            .await;
+    }
+    #[tokio::test]
+    async fn test_local_element_not_transmitted() {
+        let mut stack = two_element_stack();
+        let second_element = Address::Unicast(UnicastAddress::new(0x0003));
+        stack
+            .send_message(message(second_element))
+            .await
+            .expect("message sent");
+        assert_eq!(
+            stack
+                .incoming_access
+                .try_recv()
+                .expect("message looped back")
+                .dst,
+            second_element
+        );
+        assert!(stack.outgoing_bearer.try_recv().is_err());
+
+        // Unsubscribed groups are only transmitted.
+        stack
+            .send_message(message(Address::Group(GroupAddress::new(0xC002))))
+            .await
+            .expect("message sent");
+        assert!(stack.incoming_access.try_recv().is_err());
+        assert!(stack.outgoing_bearer.try_recv().is_ok());
+    }
+    #[tokio::test]
+    async fn test_not_for_us_dropped() {
+        use crate::stack::incoming::DropReason;
+
+        let mut stack = two_element_stack();
+        let net_keys = stack
+            .internals_with(|internals| {
+                *internals
+                    .net_keys()
+                    .get_keys(NetKeyIndex(KeyIndex::new(0)))
+                    .expect("key inserted above")
+                    .tx_key()
+                    .network_keys()
+            })
+            .await;
+        let pdu = net::PDU {
+            header: net::Header {
+                ivi: IVIndex(0).ivi(),
+                nid: net_keys.nid(),
+                ctl: CTL(false),
+                ttl: TTL::new(0),
+                seq: SequenceNumber(U24::new(1)),
+                src: UnicastAddress::new(0x0200),
+                dst: Address::Unicast(UnicastAddress::new(0x0100)),
+            },
+            payload: lower::PDU::UnsegmentedAccess(lower::UnsegmentedAccessPDU::new(
+                None, &[0_u8; 5],
+            )),
+        };
+        stack
+            .feed_network_pdu(IncomingEncryptedNetworkPDU {
+                encrypted_pdu: pdu.encrypt(&net_keys, IVIndex(0)).expect("valid PDU"),
+                rssi: None,
+                dont_relay: true,
+                from_proxy: false,
+            })
+            .await
+            .expect("stack running");
+        crate::test_util::wait_until(|| stack.stats().pdus_dropped.total() == 1).await;
+        let stats = stack.stats();
+        assert_eq!(stats.pdus_decrypted, 1);
+        assert_eq!(stats.pdus_dropped.get(DropReason::NotForUs), 1);
+        assert_eq!(stats.pdus_dropped.total(), 1);
+        assert!(stack.incoming_access.try_recv().is_err());
+    }
+    #[tokio::test]
+    async fn test_segmented_group_not_acked() {
+        let clock = ManualClock::new();
+        let mut stack = stack_with_clock(two_element_internals(), &clock);
+        clock.wait_for_timers(STACK_TIMERS).await;
+        let mut msg = message(Address::Group(GroupAddress::new(0xC002)));
+        msg.force_segment = true;
+        let interval = segments::segment_transmit_interval(
+            stack
+                .internals_with(|internals| internals.device_state().default_ttl())
+                .await,
+        );
+        let (_, placeholder) = mpsc::channel(1);
+        let mut bearer_rx = core::mem::replace(&mut stack.outgoing_bearer, placeholder);
+        let sending = stack.send_message(msg);
+        let receiving = async {
+            let mut sent = 0;
+            // Nothing acks segments to a group so the only segment is just sent again every
+            // time the transmission timer fires.
+            for _ in 0..=segments::SEGMENT_RETRANSMITS {
+                match bearer_rx.recv().await {
+                    Some(bearer::OutgoingMessage::Network(outgoing)) => {
+                        assert!(!outgoing.pdu.as_ref().is_empty())
+                    }
+                    None => panic!("bearer channel closed"),
+                }
+                sent += 1;
+                clock.advance(interval).await;
+            }
+            sent
+        };
+        let (result, sent) = futures_util::future::join(sending, receiving).await;
+        result.expect("message sent");
+        assert_eq!(sent, 1 + usize::from(segments::SEGMENT_RETRANSMITS));
+        clock.advance(interval).await;
+        assert!(bearer_rx.try_recv().is_err());
+        assert_eq!(stack.stats().segmented_sent, 1);
+    }
+    #[tokio::test]
+    async fn test_segmented_unicast_acked() {
+        let net_key = NetKey::random_secure();
+        let app_key = AppKey::random_secure();
+        let node = |address: u16| {
+            let net_key_index = NetKeyIndex(KeyIndex::new(0));
+            let mut device_state = DeviceState::new(UnicastAddress::new(address), ElementCount(1));
+            device_state
+                .security_materials_mut()
+                .net_key_map
+                .insert(net_key_index, &net_key);
+            let mut internals = StackInternals::new(device_state);
+            internals
+                .add_app_key(net_key_index, app_key_index(), app_key)
+                .expect("net key was just added");
+            // The clock never moves so nothing is retransmitted. The first ack has to do.
+            stack_with_clock(internals, &ManualClock::new())
+        };
+        // Every PDU one node transmits is heard by the other.
+        let link = |from: &mut FullStack, to: &FullStack| {
+            let (_, placeholder) = mpsc::channel(1);
+            let mut from_rx = core::mem::replace(&mut from.outgoing_bearer, placeholder);
+            let mut to_tx = to.incoming_bearer.clone();
+            task::spawn(async move {
+                while let Some(bearer::OutgoingMessage::Network(outgoing)) = from_rx.recv().await {
+                    let pdu = IncomingEncryptedNetworkPDU {
+                        encrypted_pdu: outgoing.pdu,
+                        rssi: None,
+                        dont_relay: true,
+                        from_proxy: false,
+                    };
+                    if to_tx.send(pdu).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        };
+        let mut sender = node(0x0002);
+        let mut receiver = node(0x0100);
+        link(&mut sender, &receiver);
+        link(&mut receiver, &sender);
+        let mut msg = message(Address::from(0x0100));
+        msg.force_segment = true;
+        // Only returns once the receiver's reassembler acks the segment.
+        sender.send_message(msg).await.expect("every segment acked");
+        let received = receiver
+            .incoming_access
+            .recv()
+            .await
+            .expect("message reassembled");
+        assert_eq!(received.src, UnicastAddress::new(0x0002));
+        assert_eq!(&received.payload[..], &[0x82, 0x04, 0x01][..]);
+        assert_eq!(receiver.stats().acks_sent, 1);
+        assert_eq!(sender.stats().acks_received, 1);
+        assert!(receiver.health().await.transport_alive);
+    }
+    #[tokio::test]
+    async fn test_shutdown() {
+        let clock = ManualClock::new();
+        let mut stack = stack_with_clock(two_element_internals(), &clock);
+        clock.wait_for_timers(STACK_TIMERS).await;
+        let mut msg = message(Address::Group(GroupAddress::new(0xC002)));
+        msg.force_segment = true;
+        let interval = segments::segment_transmit_interval(
+            stack
+                .internals_with(|internals| internals.device_state().default_ttl())
+                .await,
+        );
+        let (_, placeholder) = mpsc::channel(1);
+        let mut bearer_rx = core::mem::replace(&mut stack.outgoing_bearer, placeholder);
+        let delivered = stack.queue_message(msg).await.expect("stack running");
+        let shutdown = stack.shutdown(segments::SEGMENTS_SEND_TIMEOUT);
+        let receiving = async {
+            // The queued group message still gets all its retransmissions.
+            for _ in 0..=segments::SEGMENT_RETRANSMITS {
+                bearer_rx.recv().await.expect("segment sent");
+                clock.advance(interval).await;
+            }
+        };
+        let (result, ()) = futures_util::future::join(shutdown, receiving).await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(delivered.await.expect("result sent"), Ok(()));
+        assert!(bearer_rx.try_recv().is_err());
+        // Nothing else is taken.
+        let mut msg = message(Address::Group(GroupAddress::new(0xC002)));
+        msg.force_segment = true;
+        assert_eq!(
+            stack.queue_message(msg).await.err(),
+            Some(SendError::ChannelClosed)
+        );
+        assert_eq!(
+            stack.shutdown(segments::SEGMENTS_SEND_TIMEOUT).await,
+            Ok(())
+        );
+    }
+    #[tokio::test]
+    async fn test_shutdown_deadline() {
+        let clock = ManualClock::new();
+        let mut stack = stack_with_clock(two_element_internals(), &clock);
+        clock.wait_for_timers(STACK_TIMERS).await;
+        // Nobody is there to ack it.
+        let mut msg = message(Address::from(0x0100));
+        msg.force_segment = true;
+        let deadline = segments::segment_transmit_interval(
+            stack
+                .internals_with(|internals| internals.device_state().default_ttl())
+                .await,
+        ) / 2;
+        let (_, placeholder) = mpsc::channel(1);
+        let mut bearer_rx = core::mem::replace(&mut stack.outgoing_bearer, placeholder);
+        let delivered = stack.queue_message(msg).await.expect("stack running");
+        let shutdown = stack.shutdown(deadline);
+        let receiving = async {
+            bearer_rx.recv().await.expect("segment sent");
+            clock.advance(deadline).await;
+        };
+        let (result, ()) = futures_util::future::join(shutdown, receiving).await;
+        assert_eq!(result, Err(SendError::ShutdownTimeout));
+        // The unicast transfer was aborted before it got acked or gave up.
+        assert_eq!(
+            delivered.await.expect("result sent"),
+            Err(SendError::Aborted)
+        );
+        assert!(bearer_rx.try_recv().is_err());
+    }
+    #[tokio::test]
+    async fn test_health_reports_dead_task() {
+        let mut stack = two_element_stack();
+        let health = stack.health().await;
+        assert!(health.is_healthy());
+        assert_eq!(health.reassembly_inflight, 0);
+        // Without a bearer, the segmented message fails and takes the segments task down with it.
+        let (_, closed) = mpsc::channel(1);
+        drop(core::mem::replace(&mut stack.outgoing_bearer, closed));
+        let mut msg = message(Address::from(0x0100));
+        msg.force_segment = true;
+        assert_eq!(stack.send_message(msg).await, Err(SendError::ChannelClosed));
+        let health = stack.health().await;
+        assert!(!health.segments_alive);
+        assert!(health.relay_alive);
+        assert!(!health.is_healthy());
+    }
+    #[tokio::test]
+    async fn test_seq_exhausted_starts_iv_update() {
+        let clock = ManualClock::new();
+        let stack = stack_with_clock(two_element_internals(), &clock);
+        clock.wait_for_timers(STACK_TIMERS).await;
+        stack
+            .internals_with_mut(|internals| {
+                internals
+                    .device_state_mut()
+                    .seq_counter_mut(ElementIndex(0))
+                    .set_seq(SequenceNumber(U24::max_value()))
+            })
+            .await;
+        assert_eq!(
+            stack.send_message(message(Address::from(0x0100))).await,
+            Err(SendError::OutOfSeq)
+        );
+        assert_eq!(stack.health().await.iv_update_flag, IVUpdateFlag(false));
+        clock.advance(MAINTENANCE_INTERVAL).await;
+        let health = stack.health().await;
+        assert_eq!(health.iv_update_flag, IVUpdateFlag(true));
+        assert_eq!(health.iv_index, IVIndex(1));
+        assert!(health.maintenance_alive);
+    }
+    #[tokio::test]
+    async fn test_queued_segments_sent_in_order() {
+        let clock = ManualClock::new();
+        let mut stack = stack_with_clock(two_element_internals(), &clock);
+        clock.wait_for_timers(STACK_TIMERS).await;
+        let interval = segments::segment_transmit_interval(
+            stack
+                .internals_with(|internals| internals.device_state().default_ttl())
+                .await,
+        );
+        let (_, placeholder) = mpsc::channel(1);
+        let mut bearer_rx = core::mem::replace(&mut stack.outgoing_bearer, placeholder);
+        let mut sent = || {
+            let mut sent = 0;
+            while bearer_rx.try_recv().is_ok() {
+                sent += 1;
+            }
+            sent
+        };
+        let segmented = || {
+            let mut msg = message(Address::from(0x0100));
+            msg.force_segment = true;
+            msg
+        };
+        let since = clock.timers_started();
+        let mut first_rx = stack
+            .queue_message(segmented())
+            .await
+            .expect("message queued");
+        let mut second_rx = stack
+            .queue_message(segmented())
+            .await
+            .expect("message queued");
+        // Waiting for acks to the first message.
+        clock.wait_for_timers(since + 1).await;
+        // Only the first message is in flight. Nobody acks it so it's sent again on every timer.
+        assert_eq!(sent(), 1);
+        for _ in 0..segments::SEGMENT_RETRANSMITS {
+            clock.advance(interval).await;
+            assert_eq!(sent(), 1);
+        }
+        assert!(first_rx.try_recv().is_err());
+        // The first one gives up when the timer fires again and the second one starts.
+        clock.advance(interval).await;
+        assert_eq!(
+            first_rx.try_recv().ok(),
+            Some(Err(SendError::Unacknowledged))
+        );
+        assert_eq!(sent(), 1);
+        assert!(second_rx.try_recv().is_err());
+    }
+    #[tokio::test]
+    async fn test_relay_pdu() {
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        let mut device_state = DeviceState::new(UnicastAddress::new(0x0002), ElementCount(1));
+        device_state
+            .security_materials_mut()
+            .net_key_map
+            .insert(net_key_index, &NetKey::random_secure());
+        device_state.config_states_mut().relay_state = RelayState::Enabled;
+        let relay_retransmit = device_state.config_states().relay_retransmit;
+        let internals = StackInternals::new(device_state);
+        let net_keys = *internals
+            .net_keys()
+            .get_keys(net_key_index)
+            .expect("key inserted above")
+            .tx_key()
+            .network_keys();
+        let mut stack = FullStack::new(internals, replay::Cache::default(), 4);
+        let encrypted = |seq: u32, ttl: u8| IncomingEncryptedNetworkPDU {
+            encrypted_pdu: net::PDU {
+                header: net::Header {
+                    ivi: IVIndex(0).ivi(),
+                    nid: net_keys.nid(),
+                    ctl: CTL(false),
+                    ttl: TTL::new(ttl),
+                    seq: SequenceNumber(U24::new(seq)),
+                    src: UnicastAddress::new(0x0100),
+                    dst: Address::from(0x0200),
+                },
+                payload: lower::PDU::UnsegmentedAccess(lower::UnsegmentedAccessPDU::new(
+                    None, &[0_u8; 5],
+                )),
+            }
+            .encrypt(&net_keys, IVIndex(0))
+            .expect("valid PDU"),
+            rssi: None,
+            dont_relay: false,
+            from_proxy: false,
+        };
+        stack
+            .feed_network_pdu(encrypted(1, 5))
+            .await
+            .expect("stack running");
+        let bearer::OutgoingMessage::Network(relayed) =
+            stack.outgoing_bearer.recv().await.expect("PDU relayed");
+        assert_eq!(relayed.transmit_parameters, relay_retransmit.0);
+        assert!(relayed.relayed);
+        let pdu = relayed
+            .pdu
+            .as_ref()
+            .try_decrypt(&net_keys, IVIndex(0))
+            .ok()
+            .expect("relayed PDU decrypts");
+        assert_eq!(pdu.header.ttl, TTL::new(4));
+        assert_eq!(pdu.header.src, UnicastAddress::new(0x0100));
+        assert_eq!(pdu.header.seq, SequenceNumber(U24::new(1)));
+
+        // TTL 1 PDUs stay here.
+        stack
+            .feed_network_pdu(encrypted(2, 1))
+            .await
+            .expect("stack running");
+        stack
+            .feed_network_pdu(encrypted(3, 2))
+            .await
+            .expect("stack running");
+        let bearer::OutgoingMessage::Network(relayed) =
+            stack.outgoing_bearer.recv().await.expect("PDU relayed");
+        let pdu = relayed
+            .pdu
+            .as_ref()
+            .try_decrypt(&net_keys, IVIndex(0))
+            .ok()
+            .expect("relayed PDU decrypts");
+        assert_eq!(pdu.header.seq, SequenceNumber(U24::new(3)));
+        assert_eq!(pdu.header.ttl, TTL::new(1));
+        assert!(stack.health().await.relay_alive);
+    }
+    #[tokio::test]
+    async fn test_handle_control() {
+        let internals = RwLock::new(StackInternals::new(DeviceState::new(
+            UnicastAddress::new(0x0002),
+            ElementCount(1),
+        )));
+        let (mut tx_ack, mut rx_ack) = mpsc::channel(2);
+        let (mut tx_friend, mut rx_friend) = mpsc::channel(2);
+        let (mut tx_lpn, mut rx_lpn) = mpsc::channel(2);
+        let stats = StatsCounters::new();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let now = ManualClock::new().now();
+        let control_message = |control_pdu| IncomingControlMessage {
+            control_pdu,
+            src: UnicastAddress::new(0x0003),
+            dst: Address::from(0x0002),
+            seq: SequenceNumber(U24::new(7)),
+            iv_index: IVIndex(0),
+            net_key_index: NetKeyIndex(KeyIndex::new(0)),
+            rssi: None,
+            ttl: Some(TTL::new(5)),
+        };
+        let ack = control::Ack {
+            obo: false,
+            seq_zero: lower::SeqZero::new(0x10),
+            block_ack: lower::BlockAck(0b11),
+        };
+        FullStack::handle_control(
+            &internals,
+            &mut tx_ack,
+            &mut tx_friend,
+            &mut tx_lpn,
+            control_message(ControlPDU::Ack(ack)),
+            now,
+            &stats,
+            &logger,
+        )
+        .await
+        .expect("ack dispatched");
+        let fed = rx_ack.try_recv().expect("ack fed to the segments");
+        assert_eq!(fed.pdu, ack);
+        assert_eq!(fed.src, UnicastAddress::new(0x0003));
+        assert_eq!(fed.ttl, TTL::new(5));
+        assert_eq!(stats.get(Counter::AckReceived), 1);
+
+        // Friend messages and Heartbeats don't go to the segments.
+        assert!(internals.write().await.heartbeat_subscription_mut().set(
+            Address::from(0x0003),
+            Address::from(0x0002),
+            1,
+            now
+        ));
+        let offer = control::FriendOffer(crate::friend::FriendOffer {
+            receive_window: 50,
+            queue_size: 16,
+            subscription_list_size: 8,
+            rssi: -60,
+            friend_counter: crate::friend::FriendCounter::new(1),
+        });
+        let poll = control::FriendPoll(crate::friend::FriendPoll::new(crate::friend::FSN::new(
+            true,
+        )));
+        for control_pdu in vec![
+            ControlPDU::FriendClear(control::FriendClear(crate::friend::FriendClear {
+                lpn_address: UnicastAddress::new(0x0100),
+                lpn_counter: crate::friend::LPNCounter::new(2),
+            })),
+            ControlPDU::FriendOffer(offer),
+            ControlPDU::FriendPoll(poll),
+            ControlPDU::Heartbeat(control::Heartbeat {
+                init_ttl: TTL::new(7),
+                features: Default::default(),
+            }),
+        ] {
+            FullStack::handle_control(
+                &internals,
+                &mut tx_ack,
+                &mut tx_friend,
+                &mut tx_lpn,
+                control_message(control_pdu),
+                now,
+                &stats,
+                &logger,
+            )
+            .await
+            .expect("control message handled");
+        }
+        assert!(rx_ack.try_recv().is_err());
+        assert_eq!(stats.get(Counter::AckReceived), 1);
+        // Only the Friend Offer goes to the friend messages.
+        match rx_friend.try_recv().expect("offer forwarded").control_pdu {
+            ControlPDU::FriendOffer(forwarded) => assert_eq!(forwarded, offer),
+            _ => panic!("not the Friend Offer"),
+        }
+        assert!(rx_friend.try_recv().is_err());
+        // The Friend Poll goes to the Friend feature.
+        match rx_lpn.try_recv().expect("poll forwarded").control_pdu {
+            ControlPDU::FriendPoll(forwarded) => assert_eq!(forwarded, poll),
+            _ => panic!("not the Friend Poll"),
+        }
+        assert!(rx_lpn.try_recv().is_err());
+        // The Heartbeat took 3 hops (TTL 7 to 5).
+        let internals = internals.read().await;
+        let subscription = internals.heartbeat_subscription();
+        assert_eq!(subscription.count, 1);
+        assert_eq!((subscription.min_hops, subscription.max_hops), (3, 3));
+    }
+    #[tokio::test]
+    async fn test_stray_acks_dropped() {
+        let clock = ManualClock::new();
+        let mut stack = stack_with_clock(two_element_internals(), &clock);
+        let net_keys = stack
+            .internals_with_mut(|internals| {
+                assert!(internals.heartbeat_subscription_mut().set(
+                    Address::from(0x0200),
+                    Address::from(0x0002),
+                    1,
+                    clock.now()
+                ));
+                *internals
+                    .net_keys()
+                    .get_keys(NetKeyIndex(KeyIndex::new(0)))
+                    .expect("key inserted above")
+                    .tx_key()
+                    .network_keys()
+            })
+            .await;
+        let control = |seq: u32, pdu: lower::UnsegmentedControlPDU| IncomingEncryptedNetworkPDU {
+            encrypted_pdu: net::PDU {
+                header: net::Header {
+                    ivi: IVIndex(0).ivi(),
+                    nid: net_keys.nid(),
+                    ctl: CTL(true),
+                    ttl: TTL::new(5),
+                    seq: SequenceNumber(U24::new(seq)),
+                    src: UnicastAddress::new(0x0200),
+                    dst: Address::from(0x0002),
+                },
+                payload: lower::PDU::UnsegmentedControl(pdu),
+            }
+            .encrypt(&net_keys, IVIndex(0))
+            .expect("valid PDU"),
+            rssi: None,
+            dont_relay: true,
+            from_proxy: false,
+        };
+        // No segmented message is in flight to take these.
+        let acks = segments::SEGMENTS_CHANNEL_LEN as u32 + 2;
+        for seq in 0..acks {
+            let ack = control::Ack {
+                obo: false,
+                seq_zero: lower::SeqZero::new(0x10),
+                block_ack: lower::BlockAck(0b1),
+            };
+            stack
+                .feed_network_pdu(control(seq, ack.try_to_unseg().expect("ack fits")))
+                .await
+                .expect("stack running");
+        }
+        // The Heartbeat behind them still gets through.
+        let heartbeat = control::Heartbeat {
+            init_ttl: TTL::new(7),
+            features: Default::default(),
+        };
+        stack
+            .feed_network_pdu(control(
+                acks,
+                heartbeat.try_to_unseg().expect("heartbeat fits"),
+            ))
+            .await
+            .expect("stack running");
+        let heartbeats =
+            || stack.internals_with(|internals| internals.heartbeat_subscription().count);
+        for _ in 0..crate::test_util::MAX_SETTLE_YIELDS {
+            if heartbeats().await == 1 {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(heartbeats().await, 1);
+        assert_eq!(stack.stats().acks_received, acks as usize);
+    }
+    #[tokio::test]
+    async fn test_establish_friendship() {
+        use crate::friend::lpn::{OFFER_DELAY, OFFER_WINDOW};
+        use crate::friend::{
+            FriendCounter, FriendOffer, FriendUpdate, FriendshipCredentials, LPNCounter, MD,
+        };
+        use crate::mesh::KeyRefreshFlag;
+        let clock = ManualClock::new();
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        let net_key = NetKey::random_secure();
+        let internals = |address: u16| {
+            let mut device_state = DeviceState::new(UnicastAddress::new(address), ElementCount(1));
+            device_state
+                .security_materials_mut()
+                .net_key_map
+                .insert(net_key_index, &net_key);
+            StackInternals::new(device_state)
+        };
+        let lpn = UnicastAddress::new(0x0002);
+        let friend = UnicastAddress::new(0x0010);
+        let credentials = FriendshipCredentials {
+            lpn_address: lpn,
+            friend_address: friend,
+            lpn_counter: LPNCounter::new(0),
+            friend_counter: FriendCounter::new(7),
+        };
+        let mut friend_internals = internals(0x0010);
+        let mut stack = stack_with_clock(internals(0x0002), &clock);
+        clock.wait_for_timers(STACK_TIMERS).await;
+        let mut outgoing_bearer =
+            core::mem::replace(&mut stack.outgoing_bearer, mpsc::channel(1).1);
+        let mut incoming_bearer = stack.incoming_bearer.clone();
+        let incoming =
+            |outgoing: bearer::OutgoingEncryptedNetworkPDU| IncomingEncryptedNetworkPDU {
+                encrypted_pdu: outgoing.pdu,
+                rssi: None,
+                dont_relay: false,
+                from_proxy: false,
+            };
+        let config = LPNConfig::default();
+        let establishing = stack.establish_friendship(&config, net_key_index);
+        let friend_side = async {
+            let bearer::OutgoingMessage::Network(request) =
+                outgoing_bearer.recv().await.expect("Friend Request sent");
+            let (_, _, pdu) = friend_internals
+                .decrypt_network_pdu(request.pdu.as_ref())
+                .expect("managed flooding credentials");
+            assert_eq!(pdu.header.src, lpn);
+            assert_eq!(pdu.header.dst, Address::Group(GroupAddress::all_friends()));
+            assert_eq!(pdu.header.ttl, TTL::new(0));
+            let request = match &pdu.payload {
+                lower::PDU::UnsegmentedControl(unseg) => {
+                    control::FriendRequest::try_from_pdu(unseg).expect("Friend Request")
+                }
+                _ => panic!("not a Control PDU"),
+            };
+            assert_eq!(request.0.lpn_counter, credentials.lpn_counter);
+            assert_eq!(request.0.poll_timeout, config.poll_timeout);
+
+            let offer = FriendOffer {
+                receive_window: 100,
+                queue_size: 16,
+                subscription_list_size: 8,
+                rssi: -50,
+                friend_counter: credentials.friend_counter,
+            };
+            let offer = friend_internals
+                .control_pdu(
+                    &control::FriendOffer(offer),
+                    Address::Unicast(lpn),
+                    TTL::new(0),
+                    net_key_index,
+                )
+                .expect("offer encrypted");
+            // The LPN waits for more offers (a new timer) once it took this one.
+            let since = clock.timers_started();
+            incoming_bearer
+                .send(incoming(offer))
+                .await
+                .expect("stack running");
+            clock.wait_for_timers(since + 1).await;
+            clock.advance(OFFER_DELAY + OFFER_WINDOW).await;
+
+            // The Friend Poll is sent with the friendship credentials.
+            let bearer::OutgoingMessage::Network(poll) =
+                outgoing_bearer.try_recv().expect("Friend Poll sent");
+            assert!(friend_internals
+                .decrypt_network_pdu(poll.pdu.as_ref())
+                .is_err());
+            friend_internals.friendships_mut().establish(
+                credentials,
+                request.0.poll_timeout,
+                clock.now(),
+            );
+            let (_, _, pdu) = friend_internals
+                .decrypt_network_pdu(poll.pdu.as_ref())
+                .expect("friendship credentials");
+            assert_eq!(pdu.header.dst, Address::Unicast(friend));
+            match &pdu.payload {
+                lower::PDU::UnsegmentedControl(unseg) => {
+                    let poll = control::FriendPoll::try_from_pdu(unseg).expect("Friend Poll");
+                    assert!(!poll.0.fsn().value());
+                }
+                _ => panic!("not a Control PDU"),
+            }
+
+            let update = FriendUpdate {
+                key_refresh_flag: KeyRefreshFlag(false),
+                iv_update_flag: IVUpdateFlag(false),
+                iv_index: IVIndex(0),
+                md: MD::new(false),
+            };
+            let update = friend_internals
+                .control_pdu(
+                    &control::FriendUpdate(update),
+                    Address::Unicast(lpn),
+                    TTL::new(0),
+                    net_key_index,
+                )
+                .expect("update encrypted");
+            incoming_bearer
+                .send(incoming(update))
+                .await
+                .expect("stack running");
+            futures_util::future::pending::<()>().await
+        };
+        futures_util::pin_mut!(establishing, friend_side);
+        let result = match futures_util::future::select(establishing, friend_side).await {
+            futures_util::future::Either::Left((result, _)) => result,
+            futures_util::future::Either::Right(_) => panic!("friend side stopped"),
+        };
+        assert_eq!(result, Ok(friend));
+        let internals = stack.internals.read().await;
+        let friendship = internals
+            .low_power_node()
+            .friendship()
+            .expect("friendship established");
+        assert!(friendship.established);
+        assert_eq!(friendship.credentials, credentials);
+        assert_eq!(
+            internals.security_credentials(&Address::Unicast(friend)),
+            crate::stack::SecurityCredentials::Friend(friend)
+        );
+    }
+    #[tokio::test]
+    async fn test_heartbeat_publication() {
+        use crate::heartbeat::HeartbeatPublication;
+        let clock = ManualClock::new();
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        let mut device_state = DeviceState::new(UnicastAddress::new(0x0002), ElementCount(1));
+        device_state
+            .security_materials_mut()
+            .net_key_map
+            .insert(net_key_index, &NetKey::random_secure());
+        device_state.config_states_mut().relay_state = RelayState::Enabled;
+        let mut internals = StackInternals::new(device_state);
+        let net_keys = *internals
+            .net_keys()
+            .get_keys(net_key_index)
+            .expect("key inserted above")
+            .tx_key()
+            .network_keys();
+        // 2 Heartbeats, 2 seconds apart.
+        *internals.heartbeat_publication_mut() = HeartbeatPublication::new(
+            Address::Group(GroupAddress::new(GROUP)),
+            2,
+            2,
+            TTL::new(6),
+            Default::default(),
+            net_key_index,
+        );
+        let mut stack = stack_with_clock(internals, &clock);
+        clock.wait_for_timers(STACK_TIMERS).await;
+        let mut heartbeats = || {
+            let mut heartbeats = Vec::new();
+            while let Ok(bearer::OutgoingMessage::Network(outgoing)) =
+                stack.outgoing_bearer.try_recv()
+            {
+                let pdu = outgoing
+                    .pdu
+                    .as_ref()
+                    .try_decrypt(&net_keys, IVIndex(0))
+                    .ok()
+                    .expect("heartbeat decrypts");
+                assert_eq!(pdu.header.src, UnicastAddress::new(0x0002));
+                assert_eq!(pdu.header.dst, Address::Group(GroupAddress::new(GROUP)));
+                assert_eq!(pdu.header.ttl, TTL::new(6));
+                match pdu.payload {
+                    lower::PDU::UnsegmentedControl(control) => heartbeats.push(
+                        control::Heartbeat::unpack(control.data())
+                            .ok()
+                            .expect("heartbeat unpacks"),
+                    ),
+                    _ => panic!("heartbeats are unsegmented control PDUs"),
+                }
+            }
+            heartbeats
+        };
+        clock.advance(HEARTBEAT_POLL_INTERVAL).await;
+        let sent = heartbeats();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].init_ttl, TTL::new(6));
+        assert!(sent[0].features.get(FeatureFlags::Relay));
+        clock.advance(HEARTBEAT_POLL_INTERVAL).await;
+        assert!(heartbeats().is_empty());
+        clock.advance(HEARTBEAT_POLL_INTERVAL).await;
+        assert_eq!(heartbeats().len(), 1);
+        // Out of Heartbeats.
+        clock.advance(HEARTBEAT_POLL_INTERVAL * 4).await;
+        assert!(heartbeats().is_empty());
+        assert!(stack.health().await.heartbeat_alive);
+    }
+    #[tokio::test]
+    async fn test_publication_loop() {
+        use crate::foundation::publication::{
+            ModelPublishInfo, PublishPeriod, PublishRetransmit, StepResolution, Steps,
+        };
+        let clock = ManualClock::new();
+        let mut stack = stack_with_clock(two_element_internals(), &clock);
+        let model = ModelIdentifier::new_sig(ModelID(0x1001));
+        let group = Address::Group(GroupAddress::new(GROUP));
+        stack
+            .internals_with_mut(|internals| {
+                internals
+                    .device_state_mut()
+                    .models_mut()
+                    .get_mut(&model)
+                    .expect("model added by two_element_stack")
+                    .set_publish(ModelPublishInfo {
+                        address: group,
+                        app_key_index: app_key_index(),
+                        credential_flag: false,
+                        ttl: None,
+                        period: PublishPeriod::new(StepResolution::Second1, Steps::new(1)),
+                        // Retransmitted once 100ms later.
+                        retransmit: PublishRetransmit::from(1 | 1 << 3),
+                    })
+            })
+            .await;
+        let publications = Mutex::new(Publications::new());
+        let mut count = 0_u8;
+        publications.lock().await.add(
+            ElementIndex(1),
+            model,
+            Box::new(move || {
+                count += 1;
+                Some(Box::from(&[0x82_u8, 0x04, count][..]))
+            }),
+        );
+        // The first publication is a period after the loop first polls.
+        run_publications(&stack, &clock, &publications, 24).await;
+        for _ in 0..2 {
+            let looped = stack
+                .incoming_access
+                .try_recv()
+                .expect("publication looped back");
+            assert_eq!(looped.src, UnicastAddress::new(0x0003));
+            assert_eq!(looped.dst, group);
+            assert_eq!(&looped.payload[..], &[0x82_u8, 0x04, 1][..]);
+            assert!(stack.outgoing_bearer.try_recv().is_ok());
+        }
+        assert!(stack.incoming_access.try_recv().is_err());
+    }
+    /// Runs `FullStack::publication_loop` for `polls` rounds of `PUBLICATION_POLL_INTERVAL` on
+    /// `clock` (the stack's).
+    async fn run_publications(
+        stack: &FullStack,
+        clock: &ManualClock,
+        publications: &Mutex<Publications>,
+        polls: u32,
+    ) {
+        let publishing = stack.publication_loop(publications);
+        let advancing = async {
+            for _ in 0..polls {
+                clock.advance(PUBLICATION_POLL_INTERVAL).await;
+            }
+        };
+        futures_util::pin_mut!(publishing, advancing);
+        match futures_util::future::select(publishing, advancing).await {
+            futures_util::future::Either::Left((e, _)) => panic!("loop stopped: {:?}", e),
+            futures_util::future::Either::Right(_) => (),
+        }
+    }
+    /// Health `message` from our own second element to the primary element, secured with the
+    /// AppKey so the response is looped back.
+    fn health_request<M: crate::models::PackableMessage>(
+        message: &M,
+    ) -> IncomingMessage<Box<[u8]>> {
+        IncomingMessage {
+            payload: server::pack_message(message),
+            src: UnicastAddress::new(0x0003),
+            dst: Address::Unicast(UnicastAddress::new(0x0002)),
+            seq: SequenceNumber(U24::new(1)),
+            iv_index: IVIndex(0),
+            net_key_index: NetKeyIndex(KeyIndex::new(0)),
+            app_key_index: Some(app_key_index()),
+            ttl: None,
+            rssi: None,
+        }
+    }
+    #[tokio::test]
+    async fn test_health_messages_dispatched() {
+        use crate::foundation::health::FaultID;
+        use crate::foundation::state::AttentionTimer;
+        use crate::foundation::{ProductID, VersionID, CRPL};
+        use crate::mesh::CompanyID;
+        use crate::models::health::messages::{attention, fault};
+        use crate::models::health::server::AttentionEvent;
+        use crate::models::PackableMessage;
+
+        let clock = ManualClock::new();
+        let mut stack = stack_with_clock(two_element_internals(), &clock);
+        // Let the maintenance task start its first interval.
+        clock.wait_for_timers(STACK_TIMERS).await;
+        let company_id = CompanyID(0x05F1);
+        let product = ProductInfo {
+            cid: company_id,
+            pid: ProductID(0x0001),
+            vid: VersionID(0x0002),
+            crpl: CRPL(32),
+        };
+        let request = health_request(&fault::Get(company_id));
+        // Nothing answers Health messages without a Health Server.
+        assert_eq!(
+            stack.handle_access_message(&request, &product).await,
+            Ok(false)
+        );
+        let (mut events_tx, mut events) = mpsc::channel(8);
+        stack.set_health_server(Some(HealthServer::new(
+            company_id,
+            Box::new(move |event| {
+                let _ = events_tx.try_send(event);
+            }),
+        )));
+        assert_eq!(
+            stack.with_health_server(|health_server| {
+                health_server.set_fault(company_id, FaultID::BatteryLowWarning)
+            }),
+            Some(true)
+        );
+        assert_eq!(
+            stack.handle_access_message(&request, &product).await,
+            Ok(true)
+        );
+        let status = stack
+            .incoming_access
+            .try_recv()
+            .expect("status looped back");
+        assert_eq!(status.dst, Address::Unicast(UnicastAddress::new(0x0003)));
+        let (opcode, parameters) =
+            crate::access::Opcode::split_from(&status.payload[..]).expect("status has an opcode");
+        assert_eq!(opcode, fault::Status::opcode());
+        assert_eq!(
+            fault::Status::unpack_from(parameters).ok(),
+            Some(fault::Status {
+                test_id: server::STANDARD_TEST_ID,
+                company_id,
+                faults: alloc::vec![FaultID::BatteryLowWarning],
+            })
+        );
+
+        // The maintenance task counts the Attention Timer down once a second.
+        let request = health_request(&attention::SetUnacknowledged(AttentionTimer::new(2)));
+        assert_eq!(
+            stack.handle_access_message(&request, &product).await,
+            Ok(false)
+        );
+        assert!(stack.incoming_access.try_recv().is_err());
+        while events.try_recv().is_ok() {}
+        clock.advance(MAINTENANCE_INTERVAL).await;
+        assert_eq!(
+            events.try_recv().ok(),
+            Some(AttentionEvent::Attention(AttentionTimer::new(1)))
+        );
+        clock.advance(MAINTENANCE_INTERVAL).await;
+        assert_eq!(events.try_recv().ok(), Some(AttentionEvent::Stop));
+        assert!(stack.health().await.maintenance_alive);
+    }
+    #[tokio::test]
+    async fn test_health_current_status_published() {
+        use crate::foundation::health::FaultID;
+        use crate::foundation::publication::{
+            ModelPublishInfo, PublishPeriod, PublishRetransmit, StepResolution, Steps,
+        };
+        use crate::foundation::HEALTH_SERVER_MODEL_ID;
+        use crate::mesh::CompanyID;
+        use crate::models::health::messages::current;
+        use crate::models::PackableMessage;
+
+        let clock = ManualClock::new();
+        let mut stack = stack_with_clock(two_element_internals(), &clock);
+        let company_id = CompanyID(0x05F1);
+        let model = ModelIdentifier::new_sig(HEALTH_SERVER_MODEL_ID);
+        let group = Address::Group(GroupAddress::new(GROUP));
+        stack.set_health_server(Some(HealthServer::new(company_id, Box::new(|_| ()))));
+        stack
+            .internals_with_mut(|internals| {
+                let mut info = ModelInfo::default();
+                info.set_publish(ModelPublishInfo {
+                    address: group,
+                    app_key_index: app_key_index(),
+                    credential_flag: false,
+                    ttl: None,
+                    period: PublishPeriod::new(StepResolution::Second1, Steps::new(2)),
+                    retransmit: PublishRetransmit::from(0),
+                });
+                internals
+                    .device_state_mut()
+                    .models_mut()
+                    .insert(model, info)
+            })
+            .await;
+        let publications = Mutex::new(Publications::new());
+        publications
+            .lock()
+            .await
+            .add(ElementIndex(0), model, stack.health_publisher());
+        // Published every 2 seconds without faults.
+        run_publications(&stack, &clock, &publications, 50).await;
+        let published = |stack: &mut FullStack| {
+            let mut published = Vec::new();
+            while let Ok(msg) = stack.incoming_access.try_recv() {
+                assert!(stack.outgoing_bearer.try_recv().is_ok());
+                assert_eq!(msg.dst, group);
+                let (opcode, parameters) = crate::access::Opcode::split_from(&msg.payload[..])
+                    .expect("status has an opcode");
+                assert_eq!(opcode, current::Status::opcode());
+                published.push(
+                    current::Status::unpack_from(parameters)
+                        .ok()
+                        .expect("status should unpack")
+                        .faults,
+                );
+            }
+            published
+        };
+        assert_eq!(published(&mut stack), alloc::vec![Vec::new()]);
+        // Twice as often (a Fast Period Divisor of 1) with a current fault.
+        stack.with_health_server(|health_server| {
+            health_server.handle_period_set(crate::models::health::messages::period::Set(1));
+            health_server.set_fault(company_id, FaultID::BatteryLowWarning)
+        });
+        run_publications(&stack, &clock, &publications, 50).await;
+        assert_eq!(
+            published(&mut stack),
+            alloc::vec![alloc::vec![FaultID::BatteryLowWarning]; 2]
+        );
     }
 }
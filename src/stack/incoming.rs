@@ -1,21 +1,63 @@
 //! Incoming PDU message handler.
+use crate::address::{Address, UnicastAddress};
 use crate::asyncs::{
     sync::{mpsc, Mutex, RwLock},
     task,
 };
 use crate::control;
-use crate::relay::RelayPDU;
+use crate::control::ControlMessage;
+use crate::lower::BlockAck;
+use crate::mesh::{IVIndex, NetKeyIndex, SequenceNumber, TTL};
+use crate::relay::{RelayPDU, RelaySuppression};
 use crate::stack::bearer::IncomingEncryptedNetworkPDU;
+use crate::stack::clock::SharedClock;
 use crate::stack::messages::{
     EncryptedIncomingMessage, IncomingControlMessage, IncomingMessage, IncomingNetworkPDU,
-    OutgoingLowerTransportMessage,
+    IncomingTransportPDU, OutgoingLowerTransportMessage,
 };
 use crate::stack::segments::SegmentEvent;
+pub use crate::stack::stats::DropReason;
+use crate::stack::stats::{Counter, StatsCounters};
+use crate::stack::watchdog::TaskWatchdog;
 use crate::stack::{segments, RecvError, StackInternals};
-use crate::{lower, replay};
+use crate::{lower, net, replay, upper};
 use alloc::sync::Arc;
 use core::convert::TryFrom;
+use core::time::Duration;
 
+/// Logs and counts a dropped PDU. Errors that aren't drops (see
+/// [`DropReason::from_recv_error`]) are ignored.
+fn log_drop(
+    logger: &slog::Logger,
+    stats: &StatsCounters,
+    error: &RecvError,
+    src: Option<UnicastAddress>,
+    seq: Option<SequenceNumber>,
+) {
+    if let Some(reason) = DropReason::from_recv_error(error) {
+        stats.count_drop(reason);
+        slog::trace!(logger, "pdu_dropped"; "reason" => ?reason, "src" => ?src, "seq" => ?seq);
+    }
+}
+/// What to do with an authenticated Control PDU with an opcode the stack doesn't know (RFU or
+/// from a newer spec). Whatever the policy, the PDU is counted as a
+/// `DropReason::UnknownControlOpcode` drop and never reaches the rest of the stack.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum UnknownControlPolicy {
+    /// Drop it silently.
+    Drop,
+    /// Drop it and log it (at `Trace`).
+    DropWithLog,
+    /// Log it and, if it's a segment sent to one of our unicast addresses, cancel the whole
+    /// Segmented Control message with a cancel ack so the sender stops retransmitting.
+    /// Unsegmented ones are dropped.
+    CancelSegmented,
+}
+impl Default for UnknownControlPolicy {
+    fn default() -> Self {
+        UnknownControlPolicy::DropWithLog
+    }
+}
 /// Asynchronous incoming message handler stack. Input Encrypted Network PDUs and it Outputs Acks,
 /// Control and Encrypted Access PDUs. This will only mutate a `replay::Cache` state but it does
 /// not mutate `StackInternals`.
@@ -23,110 +65,266 @@ pub struct Incoming {
     net_handler: task::JoinHandle<Result<(), RecvError>>,
     encrypted_net_handler: task::JoinHandle<Result<(), RecvError>>,
     encrypted_access_handler: task::JoinHandle<Result<(), RecvError>>,
+    transport_handler: task::JoinHandle<Result<(), RecvError>>,
+    net_watchdog: TaskWatchdog,
+    encrypted_net_watchdog: TaskWatchdog,
+    encrypted_access_watchdog: TaskWatchdog,
+    transport_watchdog: TaskWatchdog,
+    reassembly_inflight: segments::InflightCounter,
+}
+/// Liveness of each `Incoming` task. See [`Incoming::tasks_health`].
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct IncomingHealth {
+    pub net_alive: bool,
+    pub encrypted_net_alive: bool,
+    pub encrypted_access_alive: bool,
+    pub transport_alive: bool,
+}
+impl IncomingHealth {
+    pub fn all_alive(&self) -> bool {
+        self.net_alive
+            && self.encrypted_net_alive
+            && self.encrypted_access_alive
+            && self.transport_alive
+    }
 }
 impl Incoming {
     pub fn new(
         internals: Arc<RwLock<StackInternals>>,
         replay_cache: Arc<Mutex<replay::Cache>>,
+        network_cache_len: usize,
         incoming_net: mpsc::Receiver<IncomingEncryptedNetworkPDU>,
+        outgoing_relay: Option<mpsc::Sender<RelayPDU>>,
+        relay_suppression: Option<RelaySuppression>,
         outgoing_transport: mpsc::Sender<OutgoingLowerTransportMessage>,
         tx_ack: mpsc::Sender<segments::IncomingPDU<control::Ack>>,
         tx_access: mpsc::Sender<IncomingMessage<Box<[u8]>>>,
         tx_control: mpsc::Sender<IncomingControlMessage>,
+        tx_monitor: Option<mpsc::Sender<IncomingNetworkPDU>>,
         channel_size: usize,
+        reassembler_channel_len: usize,
+        reassembly_timeout: Duration,
+        max_reassemblies: usize,
+        unknown_control: UnknownControlPolicy,
+        stats: StatsCounters,
+        clock: SharedClock,
+        logger: slog::Logger,
     ) -> Self {
         let (tx_incoming_net, rx_incoming_net) = mpsc::channel(channel_size);
         let (tx_encrypted_access, rx_encrypted_access) = mpsc::channel(channel_size);
-        let reassembler = Arc::new(Mutex::new(segments::Reassembler::new(outgoing_transport)));
+        let (tx_transport, rx_transport) = mpsc::channel(channel_size);
+        let mut reassembler = segments::Reassembler::with_channel_len(
+            outgoing_transport.clone(),
+            tx_transport,
+            reassembler_channel_len,
+        );
+        reassembler.set_incomplete_timeout(reassembly_timeout);
+        reassembler.set_max_inflight(max_reassemblies);
+        reassembler.set_stats(stats.clone());
+        reassembler.set_clock(clock);
+        let reassembly_inflight = reassembler.inflight_counter();
+        let net_watchdog = TaskWatchdog::new();
+        let encrypted_net_watchdog = TaskWatchdog::new();
+        let encrypted_access_watchdog = TaskWatchdog::new();
+        let transport_watchdog = TaskWatchdog::new();
         Self {
-            encrypted_net_handler: task::spawn(Self::handle_encrypted_net_pdu_loop(
-                internals.clone(),
-                replay_cache,
-                None,
-                incoming_net,
-                tx_incoming_net,
+            encrypted_net_handler: task::spawn(encrypted_net_watchdog.watch(
+                Self::handle_encrypted_net_pdu_loop(
+                    internals.clone(),
+                    replay_cache,
+                    Mutex::new(replay::NetworkMessageCache::new(network_cache_len)),
+                    outgoing_relay,
+                    relay_suppression,
+                    tx_monitor,
+                    incoming_net,
+                    tx_incoming_net,
+                    unknown_control,
+                    outgoing_transport,
+                    stats.clone(),
+                    logger.clone(),
+                ),
             )),
-            net_handler: task::spawn(Self::handle_net_loop(
+            transport_handler: task::spawn(transport_watchdog.watch(Self::handle_transport_loop(
+                tx_control.clone(),
+                tx_encrypted_access.clone(),
+                rx_transport,
+                stats.clone(),
+                logger.clone(),
+            ))),
+            net_handler: task::spawn(net_watchdog.watch(Self::handle_net_loop(
                 reassembler,
                 tx_ack,
                 tx_control,
                 tx_encrypted_access,
                 rx_incoming_net,
+                stats.clone(),
+                logger.clone(),
+            ))),
+            encrypted_access_handler: task::spawn(encrypted_access_watchdog.watch(
+                Self::handle_encrypted_access_loop(
+                    internals,
+                    rx_encrypted_access,
+                    tx_access,
+                    stats,
+                    logger,
+                ),
             )),
-            encrypted_access_handler: task::spawn(Self::handle_encrypted_access_loop(
-                internals,
-                rx_encrypted_access,
-                tx_access,
-            )),
+            net_watchdog,
+            encrypted_net_watchdog,
+            encrypted_access_watchdog,
+            transport_watchdog,
+            reassembly_inflight,
         }
     }
+    /// Returns if each incoming task is still running. Doesn't lock anything.
+    pub fn tasks_health(&self) -> IncomingHealth {
+        IncomingHealth {
+            net_alive: self.net_watchdog.is_alive(),
+            encrypted_net_alive: self.encrypted_net_watchdog.is_alive(),
+            encrypted_access_alive: self.encrypted_access_watchdog.is_alive(),
+            transport_alive: self.transport_watchdog.is_alive(),
+        }
+    }
+    /// Returns the number of segmented PDUs currently being reassembled. Doesn't lock anything.
+    pub fn reassembly_inflight(&self) -> usize {
+        self.reassembly_inflight.get()
+    }
     async fn handle_encrypted_access_loop(
         internals: Arc<RwLock<StackInternals>>,
         mut incoming_encrypted_access: mpsc::Receiver<EncryptedIncomingMessage<Box<[u8]>>>,
         mut outgoing_encrypted_access: mpsc::Sender<IncomingMessage<Box<[u8]>>>,
+        stats: StatsCounters,
+        logger: slog::Logger,
     ) -> Result<(), RecvError> {
         loop {
             let next = incoming_encrypted_access
                 .recv()
                 .await
                 .ok_or(RecvError::ChannelClosed)?;
-            if let Ok(decrypted) = internals.read().await.app_decrypt(next) {
-                outgoing_encrypted_access
+            let (src, seq) = (next.src, next.seq);
+            // Bind the result first so the internals read lock is released before the send.
+            let decrypted = internals.read().await.app_decrypt(next);
+            match decrypted {
+                Ok(decrypted) => outgoing_encrypted_access
                     .send(decrypted)
                     .await
                     .ok()
-                    .ok_or(RecvError::ChannelClosed)?;
+                    .ok_or(RecvError::ChannelClosed)?,
+                Err(e) => log_drop(&logger, &stats, &e, Some(src), Some(seq)),
+            }
+        }
+    }
+    async fn handle_transport_loop(
+        mut tx_control: mpsc::Sender<IncomingControlMessage>,
+        mut tx_access: mpsc::Sender<EncryptedIncomingMessage<Box<[u8]>>>,
+        mut incoming: mpsc::Receiver<IncomingTransportPDU<Box<[u8]>>>,
+        stats: StatsCounters,
+        logger: slog::Logger,
+    ) -> Result<(), RecvError> {
+        loop {
+            let next = incoming.recv().await.ok_or(RecvError::ChannelClosed)?;
+            let (src, seq) = (next.src, next.seq);
+            match Self::handle_transport(&mut tx_control, &mut tx_access, next).await {
+                Ok(()) => (),
+                Err(RecvError::ChannelClosed) => return Err(RecvError::ChannelClosed),
+                Err(e) => log_drop(&logger, &stats, &e, Some(src), Some(seq)),
             }
         }
     }
+    /// Dispatches a reassembled Transport PDU. Segmented Control PDUs are decoded with the
+    /// `ControlOpcode` carried through reassembly.
+    async fn handle_transport(
+        tx_control: &mut mpsc::Sender<IncomingControlMessage>,
+        tx_access: &mut mpsc::Sender<EncryptedIncomingMessage<Box<[u8]>>>,
+        incoming: IncomingTransportPDU<Box<[u8]>>,
+    ) -> Result<(), RecvError> {
+        match incoming.upper_pdu {
+            upper::PDU::Control(payload) => tx_control
+                .send(IncomingControlMessage {
+                    control_pdu: control::ControlPDU::try_from(&payload)
+                        .map_err(|_| RecvError::MalformedControlPDU)?,
+                    src: incoming.src,
+                    dst: incoming.dst,
+                    seq: incoming.seq,
+                    iv_index: incoming.iv_index,
+                    net_key_index: incoming.net_key_index,
+                    rssi: incoming.rssi,
+                    ttl: incoming.ttl,
+                })
+                .await
+                .ok()
+                .ok_or(RecvError::ChannelClosed),
+            upper::PDU::Access(encrypted_app_payload) => tx_access
+                .send(EncryptedIncomingMessage {
+                    encrypted_app_payload,
+                    seq: incoming.seq,
+                    seg_count: incoming.seg_count,
+                    iv_index: incoming.iv_index,
+                    net_key_index: incoming.net_key_index,
+                    dst: incoming.dst,
+                    src: incoming.src,
+                    ttl: incoming.ttl,
+                    rssi: incoming.rssi,
+                })
+                .await
+                .ok()
+                .ok_or(RecvError::ChannelClosed),
+        }
+    }
     async fn handle_net_loop(
-        reassembler: Arc<Mutex<segments::Reassembler>>,
+        mut reassembler: segments::Reassembler,
         mut tx_ack: mpsc::Sender<segments::IncomingPDU<control::Ack>>,
         mut tx_control: mpsc::Sender<IncomingControlMessage>,
         mut tx_access: mpsc::Sender<EncryptedIncomingMessage<Box<[u8]>>>,
         mut incoming: mpsc::Receiver<IncomingNetworkPDU>,
+        stats: StatsCounters,
+        logger: slog::Logger,
     ) -> Result<(), RecvError> {
         loop {
-            if let Err(RecvError::ChannelClosed) = Self::handle_net(
-                &reassembler,
+            let next = incoming.recv().await.ok_or(RecvError::ChannelClosed)?;
+            match Self::handle_net(
+                &mut reassembler,
                 &mut tx_ack,
                 &mut tx_control,
                 &mut tx_access,
-                incoming.recv().await.ok_or(RecvError::ChannelClosed)?,
+                &stats,
+                next,
             )
             .await
             {
-                return Err(RecvError::ChannelClosed);
+                Ok(()) => (),
+                Err(RecvError::ChannelClosed) => return Err(RecvError::ChannelClosed),
+                Err(e) => log_drop(
+                    &logger,
+                    &stats,
+                    &e,
+                    Some(next.pdu.header.src),
+                    Some(next.pdu.header.seq),
+                ),
             }
         }
     }
     async fn handle_net(
-        reassembler: &Mutex<segments::Reassembler>,
+        reassembler: &mut segments::Reassembler,
         tx_ack: &mut mpsc::Sender<segments::IncomingPDU<control::Ack>>,
         tx_control: &mut mpsc::Sender<IncomingControlMessage>,
         tx_access: &mut mpsc::Sender<EncryptedIncomingMessage<Box<[u8]>>>,
+        stats: &StatsCounters,
         incoming: IncomingNetworkPDU,
     ) -> Result<(), RecvError> {
         if let Ok(seg_event) = segments::SegmentEvent::try_from(&incoming) {
             match seg_event {
                 SegmentEvent::IncomingSegment(seg) => {
-                    match reassembler.lock().await.feed_pdu(seg).await {
-                        Ok(_) => {
-                            // ok seg
-                        }
-                        Err(_) => {
-                            // bad seg
-                            todo!("handle bad segment?")
-                        }
-                    }
+                    reassembler
+                        .feed_pdu(seg)
+                        .await
+                        .map_err(RecvError::ReassemblerError)?;
                     Some(())
                 }
                 SegmentEvent::IncomingAck(ack) => {
-                    tx_ack
-                        .send(ack)
-                        .await
-                        .ok()
-                        .ok_or(RecvError::ChannelClosed)?;
+                    stats.count(Counter::AckReceived);
+                    // Never waits so stray acks can't hold up the other PDUs.
+                    segments::forward_ack(tx_ack, ack)?;
                     Some(())
                 }
             }
@@ -158,6 +356,10 @@ impl Incoming {
                         }
                     },
                     src: incoming.pdu.header.src,
+                    dst: incoming.pdu.header.dst,
+                    seq: incoming.pdu.header.seq,
+                    iv_index: incoming.iv_index,
+                    net_key_index: incoming.net_key_index,
                     rssi: incoming.rssi,
                     ttl: Some(incoming.pdu.header.ttl),
                 })
@@ -173,86 +375,839 @@ impl Incoming {
     pub async fn handle_encrypted_net_pdu_loop(
         internals: Arc<RwLock<StackInternals>>,
         replay_cache: Arc<Mutex<replay::Cache>>,
+        network_cache: Mutex<replay::NetworkMessageCache>,
         mut outgoing_relay: Option<mpsc::Sender<RelayPDU>>,
+        relay_suppression: Option<RelaySuppression>,
+        mut monitor: Option<mpsc::Sender<IncomingNetworkPDU>>,
         mut incoming: mpsc::Receiver<IncomingEncryptedNetworkPDU>,
         mut outgoing: mpsc::Sender<IncomingNetworkPDU>,
+        unknown_control: UnknownControlPolicy,
+        mut outgoing_transport: mpsc::Sender<OutgoingLowerTransportMessage>,
+        stats: StatsCounters,
+        logger: slog::Logger,
     ) -> Result<(), RecvError> {
         loop {
             let next = incoming.recv().await.ok_or(RecvError::ChannelClosed)?;
             match Self::handle_encrypted_net_pdu(
                 &internals,
                 &replay_cache,
+                &network_cache,
                 outgoing_relay.as_mut(),
+                relay_suppression,
                 next,
+                &stats,
+                &logger,
             )
             .await
             {
-                Ok(pdu) => outgoing
-                    .send(pdu)
-                    .await
-                    .ok()
-                    .ok_or(RecvError::ChannelClosed)?,
-                Err(e) => {
-                    // Log the error, otherwise ignore it.
-                    #[cfg(debug_assertions)]
-                    eprintln!("recv error: {:?}", e);
+                Ok(pdu) => {
+                    if let Some(monitor_tx) = monitor.as_mut() {
+                        // Never wait on the monitor. If it's full or closed, drop the PDU.
+                        let _ = monitor_tx.try_send(pdu);
+                    }
+                    let queued =
+                        Self::queue_for_friend(&internals, &pdu, &mut outgoing_transport, &stats)
+                            .await;
+                    // Relayed PDUs and ones for other nodes end here. The monitor still gets
+                    // them.
+                    let header = pdu.pdu.header();
+                    if internals.read().await.matches(&header.dst).is_none() {
+                        if queued {
+                            // Stored for a Low Power node so it isn't a drop.
+                            continue;
+                        }
+                        log_drop(
+                            &logger,
+                            &stats,
+                            &RecvError::InvalidDestination,
+                            Some(header.src),
+                            Some(header.seq),
+                        );
+                        continue;
+                    }
+                    outgoing
+                        .send(pdu)
+                        .await
+                        .ok()
+                        .ok_or(RecvError::ChannelClosed)?
+                }
+                Err(RecvError::UnknownControlOpcode(net_key_index, iv_index, unknown)) => {
+                    Self::handle_unknown_control(
+                        unknown_control,
+                        &mut outgoing_transport,
+                        net_key_index,
+                        iv_index,
+                        &unknown,
+                        &stats,
+                        &logger,
+                    )
                 }
+                Err(_) => {
+                    // Dropped PDUs are already logged by `handle_encrypted_net_pdu`.
+                }
+            }
+        }
+    }
+    /// Stores `pdu` in the Friend Queues of the Low Power nodes it's for (if this node is their
+    /// Friend). Returns if it was stored. Only takes the `StackInternals` write lock if there's a
+    /// Friend Queue to store it in. Like cancel acks, the Segment Acknowledgements sent on behalf
+    /// of the Low Power node are never waited on.
+    async fn queue_for_friend(
+        internals: &RwLock<StackInternals>,
+        pdu: &IncomingNetworkPDU,
+        outgoing_transport: &mut mpsc::Sender<OutgoingLowerTransportMessage>,
+        stats: &StatsCounters,
+    ) -> bool {
+        let dst = pdu.pdu.header().dst;
+        let is_friend_of = internals
+            .read()
+            .await
+            .friend_node()
+            .map_or(false, |friend_node| friend_node.is_friend_of(&dst));
+        if !is_friend_of {
+            return false;
+        }
+        let ack =
+            internals
+                .write()
+                .await
+                .friend_queue_pdu(&pdu.pdu, pdu.net_key_index, pdu.iv_index);
+        if let Some(ack) = ack {
+            if outgoing_transport.try_send(ack).is_ok() {
+                stats.count(Counter::AckSent);
             }
         }
+        true
     }
+    /// Applies `policy` to an authenticated Control PDU with an unknown opcode (see
+    /// `RecvError::UnknownControlOpcode`). The cancel ack is never waited on so it's dropped if
+    /// `outgoing_transport` is full or closed.
+    pub fn handle_unknown_control(
+        policy: UnknownControlPolicy,
+        outgoing_transport: &mut mpsc::Sender<OutgoingLowerTransportMessage>,
+        net_key_index: NetKeyIndex,
+        iv_index: IVIndex,
+        unknown: &net::UnknownControlPDU,
+        stats: &StatsCounters,
+        logger: &slog::Logger,
+    ) {
+        stats.count_drop(DropReason::UnknownControlOpcode);
+        let header = &unknown.header;
+        if policy == UnknownControlPolicy::Drop {
+            return;
+        }
+        slog::trace!(logger, "unknown_control_opcode";
+            "opcode" => unknown.opcode,
+            "segmented" => unknown.segment_header.is_some(),
+            "src" => ?header.src,
+            "seq" => ?header.seq);
+        if policy != UnknownControlPolicy::CancelSegmented {
+            return;
+        }
+        // Only segments sent to one of our unicast addresses are acked (or canceled).
+        if let (Some(segment_header), Address::Unicast(dst)) = (unknown.segment_header, header.dst)
+        {
+            let ack = control::Ack {
+                obo: false,
+                seq_zero: segment_header.seq_zero,
+                block_ack: BlockAck::cancel(),
+            };
+            let sent = outgoing_transport.try_send(OutgoingLowerTransportMessage {
+                pdu: lower::PDU::UnsegmentedControl(
+                    ack.try_to_unseg().expect("correctly formatted PDU"),
+                ),
+                src: dst,
+                dst: Address::Unicast(header.src),
+                ttl: if u8::from(header.ttl) == 0_u8 {
+                    Some(TTL::new(0))
+                } else {
+                    None
+                },
+                seq: None,
+                iv_index,
+                net_key_index,
+            });
+            if sent.is_ok() {
+                stats.count(Counter::AckSent);
+            }
+        }
+    }
+    /// Decrypts, replay checks and (if enabled) relays an Encrypted Network PDU. PDUs from the
+    /// advertising bearer and the proxy (GATT) bearer both go through here. Proxied PDUs are
+    /// relayed if the Proxy feature is enabled and the rest if the Relay feature is enabled.
+    ///
+    /// PDUs already in `network_cache` are dropped before being relayed, so a PDU heard from
+    /// several bearers or relays is only relayed once. Replay protection runs after relaying.
+    /// With `relay_suppression`, PDUs heard with a strong RSSI are only sometimes relayed.
+    ///
+    /// The `StackInternals` read lock is only held while decrypting and reading the relay state.
+    /// It's released before the `replay::Cache` lock is taken and before anything is sent so a
+    /// slow relay or full channel never blocks writers to `StackInternals`.
+    ///
+    /// Dropped PDUs are logged to `logger` with their [`DropReason`] and counted in `stats`.
+    /// Control PDUs with unknown opcodes are returned (`RecvError::UnknownControlOpcode`) without
+    /// being logged or counted so the caller can apply its `UnknownControlPolicy`.
     pub async fn handle_encrypted_net_pdu(
         internals: &RwLock<StackInternals>,
         replay_cache: &Mutex<replay::Cache>,
+        network_cache: &Mutex<replay::NetworkMessageCache>,
         outgoing_relay: Option<&mut mpsc::Sender<RelayPDU>>,
+        relay_suppression: Option<RelaySuppression>,
         incoming: IncomingEncryptedNetworkPDU,
+        stats: &StatsCounters,
+        logger: &slog::Logger,
     ) -> Result<IncomingNetworkPDU, RecvError> {
-        let internals = internals.read().await;
-        if let Some((net_key_index, iv_index, pdu)) =
-            internals.decrypt_network_pdu(incoming.encrypted_pdu.as_ref())
+        stats.count(Counter::PDUReceived);
+        let (net_key_index, iv_index, pdu, relay_enabled) = {
+            let internals = internals.read().await;
+            let (net_key_index, iv_index, pdu) =
+                match internals.decrypt_network_pdu(incoming.encrypted_pdu.as_ref()) {
+                    Ok(decrypted) => decrypted,
+                    Err(e @ RecvError::UnknownControlOpcode(..)) => return Err(e),
+                    Err(e) => {
+                        // The src and seq are obfuscated so they can't be logged.
+                        log_drop(logger, stats, &e, None, None);
+                        return Err(e);
+                    }
+                };
+            let config_states = internals.device_state.config_states();
+            // Proxied PDUs go out on the advertising bearer through the Proxy feature while
+            // everything else needs the Relay feature.
+            let relay_enabled = if incoming.from_proxy {
+                config_states.gatt_proxy_state.is_enabled()
+            } else {
+                config_states.relay_state.is_enabled()
+            };
+            (net_key_index, iv_index, pdu, relay_enabled)
+        };
+        stats.count(Counter::PDUDecrypted);
+        let header = pdu.header();
+        if !network_cache
+            .lock()
+            .await
+            .insert(header.src, header.seq, header.ivi)
         {
-            let header = pdu.header();
-            let (is_old_seq, is_old_seq_zero) = replay_cache.lock().await.replay_net_check(
+            // We've already seen this exact PDU (maybe from another relay or bearer) so it's
+            // neither handled nor relayed again.
+            log_drop(
+                logger,
+                stats,
+                &RecvError::OldSeq,
+                Some(header.src),
+                Some(header.seq),
+            );
+            return Err(RecvError::OldSeq);
+        }
+        // Relaying only dedupes on the network message cache. An old seq (a reordered PDU) is
+        // still relayed to other nodes even though it isn't handled here.
+        if !incoming.dont_relay && pdu.header().ttl.should_relay() && relay_enabled {
+            let rssi = incoming.rssi.map(i8::from);
+            if relay_suppression.map_or(false, |suppression| !suppression.should_relay(rssi)) {
+                stats.count(Counter::RelaySuppressed);
+            } else if let Some(relay_tx) = outgoing_relay {
+                relay_tx
+                    .send(RelayPDU {
+                        pdu,
+                        iv_index,
+                        net_key_index,
+                    })
+                    .await
+                    .map_err(|_| RecvError::ChannelClosed)?;
+                stats.count(Counter::PDURelayed);
+            }
+        }
+        let (is_old_seq, is_old_seq_zero, evicted) = {
+            let mut replay_cache = replay_cache.lock().await;
+            let (is_old_seq, is_old_seq_zero) = replay_cache.replay_net_check(
                 header.src,
                 header.seq,
                 header.ivi,
                 pdu.payload.seq_zero(),
             );
-            if is_old_seq {
-                // We've already seen this PDU
-                return Err(RecvError::OldSeq);
-            }
-            // Seq isn't old but SeqZero might be. Even if SeqZero is old, we still relay it to other nodes.
-            if !incoming.dont_relay
-                && pdu.header().ttl.should_relay()
-                && internals
-                    .device_state
-                    .config_states()
-                    .relay_state
-                    .is_enabled()
-            {
-                if let Some(relay_tx) = outgoing_relay {
-                    relay_tx
-                        .send(RelayPDU {
-                            pdu,
-                            iv_index,
-                            net_key_index,
-                        })
+            (is_old_seq, is_old_seq_zero, replay_cache.take_evicted())
+        };
+        if let Some(evicted) = evicted {
+            slog::warn!(logger, "replay_cache_evicted";
+                "src" => ?evicted, "reason" => "replay cache full, replays from it won't be caught");
+        }
+        if is_old_seq {
+            // We've already seen this PDU
+            log_drop(
+                logger,
+                stats,
+                &RecvError::OldSeq,
+                Some(header.src),
+                Some(header.seq),
+            );
+            return Err(RecvError::OldSeq);
+        }
+        if is_old_seq_zero {
+            // We've already handle this PDU
+            log_drop(
+                logger,
+                stats,
+                &RecvError::OldSeqZero,
+                Some(header.src),
+                Some(header.seq),
+            );
+            return Err(RecvError::OldSeqZero);
+        }
+        Ok(IncomingNetworkPDU {
+            pdu,
+            net_key_index,
+            iv_index,
+            rssi: incoming.rssi,
+        })
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Address;
+    use crate::crypto::key::NetKey;
+    use crate::crypto::materials::NetworkKeys;
+    use crate::crypto::MIC;
+    use crate::device_state::DeviceState;
+    use crate::foundation::state::{GATTProxyState, RelayState};
+    use crate::mesh::{
+        ElementCount, IVIndex, IVUpdateFlag, KeyIndex, NetKeyIndex, CTL, NID, TTL, U24,
+    };
+    use crate::net;
+    use crate::random::Randomizable;
+    use crate::upper::EncryptedAppPayload;
+    use alloc::vec::Vec;
+
+    const FEEDERS: u16 = 8;
+    const PDUS_PER_FEEDER: u32 = 32;
+
+    fn logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+    fn net_key_index() -> NetKeyIndex {
+        NetKeyIndex(KeyIndex::new(0))
+    }
+    fn internals() -> StackInternals {
+        let mut device_state = DeviceState::new(UnicastAddress::new(0x0001), ElementCount(1));
+        device_state
+            .security_materials_mut()
+            .net_key_map
+            .insert(net_key_index(), &NetKey::random_secure());
+        StackInternals::new(device_state)
+    }
+    fn encrypted_pdu(
+        net_keys: &NetworkKeys,
+        src: UnicastAddress,
+        seq: u32,
+    ) -> IncomingEncryptedNetworkPDU {
+        let iv_index = IVIndex(0);
+        let pdu = net::PDU {
+            header: net::Header {
+                ivi: iv_index.ivi(),
+                nid: NID::new(0),
+                ctl: CTL(false),
+                ttl: TTL::new(0),
+                seq: SequenceNumber(U24::new(seq)),
+                src,
+                dst: Address::from(0x0001),
+            },
+            payload: lower::PDU::UnsegmentedAccess(lower::UnsegmentedAccessPDU::new(
+                None, &[0_u8; 5],
+            )),
+        };
+        IncomingEncryptedNetworkPDU {
+            encrypted_pdu: pdu.encrypt(net_keys, iv_index).expect("valid PDU"),
+            rssi: None,
+            dont_relay: true,
+            from_proxy: false,
+        }
+    }
+    #[tokio::test(threaded_scheduler)]
+    async fn test_concurrent_encrypted_net_pdus() {
+        let internals = Arc::new(RwLock::new(internals()));
+        let replay_cache = Arc::new(Mutex::new(replay::Cache::default()));
+        let network_cache = Arc::new(Mutex::new(replay::NetworkMessageCache::default()));
+        let net_keys = *internals
+            .read()
+            .await
+            .net_keys()
+            .get_keys(net_key_index())
+            .expect("key inserted above")
+            .tx_key()
+            .network_keys();
+        let stats = StatsCounters::new();
+        let feeders = (0..FEEDERS)
+            .map(|feeder| {
+                let internals = internals.clone();
+                let replay_cache = replay_cache.clone();
+                let network_cache = network_cache.clone();
+                let stats = stats.clone();
+                task::spawn(async move {
+                    let logger = logger();
+                    let src = UnicastAddress::new(0x0100 + feeder);
+                    let mut accepted = 0;
+                    for seq in 1..=PDUS_PER_FEEDER {
+                        if Incoming::handle_encrypted_net_pdu(
+                            &internals,
+                            &replay_cache,
+                            &network_cache,
+                            None,
+                            None,
+                            encrypted_pdu(&net_keys, src, seq),
+                            &stats,
+                            &logger,
+                        )
+                        .await
+                        .is_ok()
+                        {
+                            accepted += 1;
+                        }
+                        // The same PDU again must be caught by the network or replay cache.
+                        match Incoming::handle_encrypted_net_pdu(
+                            &internals,
+                            &replay_cache,
+                            &network_cache,
+                            None,
+                            None,
+                            encrypted_pdu(&net_keys, src, seq),
+                            &stats,
+                            &logger,
+                        )
                         .await
-                        .map_err(|_| RecvError::ChannelClosed)?;
+                        {
+                            Err(RecvError::OldSeq) => (),
+                            _ => panic!("replayed PDU wasn't rejected"),
+                        }
+                    }
+                    accepted
+                })
+            })
+            .collect::<Vec<_>>();
+        // Contend with the feeders for the write lock.
+        let writer = {
+            let internals = internals.clone();
+            task::spawn(async move {
+                for _ in 0..PDUS_PER_FEEDER {
+                    *internals
+                        .write()
+                        .await
+                        .device_state_mut()
+                        .iv_update_flag_mut() = IVUpdateFlag(false);
+                    tokio::task::yield_now().await;
                 }
+            })
+        };
+        for feeder in feeders {
+            assert_eq!(feeder.await.expect("feeder panicked"), PDUS_PER_FEEDER);
+        }
+        writer.await.expect("writer panicked");
+        assert_eq!(replay_cache.lock().await.len(), usize::from(FEEDERS));
+        let total = usize::from(FEEDERS) * PDUS_PER_FEEDER as usize;
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.pdus_received, 2 * total);
+        assert_eq!(snapshot.pdus_decrypted, 2 * total);
+        assert_eq!(snapshot.pdus_dropped.get(DropReason::Replayed), total);
+        assert_eq!(snapshot.pdus_dropped.total(), total);
+    }
+    #[test]
+    fn test_drop_reason() {
+        assert_eq!(
+            DropReason::from_recv_error(&RecvError::NoMatchingNetKey),
+            Some(DropReason::DecryptFailed)
+        );
+        assert_eq!(
+            DropReason::from_recv_error(&RecvError::NoMatchingAppKey),
+            Some(DropReason::AppDecryptFailed)
+        );
+        assert_eq!(
+            DropReason::from_recv_error(&RecvError::OldSeq),
+            Some(DropReason::Replayed)
+        );
+        assert_eq!(
+            DropReason::from_recv_error(&RecvError::MalformedControlPDU),
+            Some(DropReason::Unparseable)
+        );
+        assert_eq!(DropReason::from_recv_error(&RecvError::ChannelClosed), None);
+    }
+    #[tokio::test]
+    async fn test_malformed_control_dropped() {
+        let (tx_control, mut rx_control) = mpsc::channel(2);
+        let (tx_access, _rx_access) = mpsc::channel(2);
+        let (mut tx_transport, rx_transport) = mpsc::channel(2);
+        let stats = StatsCounters::new();
+        let handler = task::spawn(Incoming::handle_transport_loop(
+            tx_control,
+            tx_access,
+            rx_transport,
+            stats.clone(),
+            logger(),
+        ));
+        tx_transport
+            .send(IncomingTransportPDU {
+                // Too short for a Heartbeat.
+                upper_pdu: upper::PDU::Control(control::ControlPayload {
+                    opcode: control::ControlOpcode::Heartbeat,
+                    payload: Box::<[u8]>::from(&[0_u8][..]),
+                }),
+                iv_index: IVIndex(0),
+                seg_count: 0,
+                seq: SequenceNumber(U24::new(1)),
+                net_key_index: net_key_index(),
+                ttl: None,
+                rssi: None,
+                src: UnicastAddress::new(0x0002),
+                dst: Address::Unicast(UnicastAddress::new(0x0001)),
+            })
+            .await
+            .ok()
+            .expect("handler running");
+        drop(tx_transport);
+        match handler.await.expect("handler panicked") {
+            Err(RecvError::ChannelClosed) => (),
+            other => panic!("expected ChannelClosed, got {:?}", other),
+        }
+        assert!(rx_control.try_recv().is_err());
+        let dropped = stats.snapshot().pdus_dropped;
+        assert_eq!(dropped.get(DropReason::Unparseable), 1);
+        assert_eq!(dropped.total(), 1);
+    }
+    #[tokio::test]
+    async fn test_app_decrypt_failed_dropped() {
+        let (mut tx_encrypted, rx_encrypted) = mpsc::channel(2);
+        let (tx_access, mut rx_access) = mpsc::channel(2);
+        let stats = StatsCounters::new();
+        let handler = task::spawn(Incoming::handle_encrypted_access_loop(
+            Arc::new(RwLock::new(internals())),
+            rx_encrypted,
+            tx_access,
+            stats.clone(),
+            logger(),
+        ));
+        tx_encrypted
+            .send(EncryptedIncomingMessage {
+                // Not secured with our DevKey.
+                encrypted_app_payload: EncryptedAppPayload::new(
+                    Box::<[u8]>::from(&[0_u8; 4][..]),
+                    MIC::Small(0),
+                    None,
+                ),
+                seq: SequenceNumber(U24::new(1)),
+                seg_count: 0,
+                iv_index: IVIndex(0),
+                net_key_index: net_key_index(),
+                dst: Address::Unicast(UnicastAddress::new(0x0001)),
+                src: UnicastAddress::new(0x0002),
+                ttl: None,
+                rssi: None,
+            })
+            .await
+            .ok()
+            .expect("handler running");
+        drop(tx_encrypted);
+        match handler.await.expect("handler panicked") {
+            Err(RecvError::ChannelClosed) => (),
+            other => panic!("expected ChannelClosed, got {:?}", other),
+        }
+        assert!(rx_access.try_recv().is_err());
+        let dropped = stats.snapshot().pdus_dropped;
+        assert_eq!(dropped.get(DropReason::AppDecryptFailed), 1);
+        assert_eq!(dropped.total(), 1);
+    }
+    #[tokio::test(threaded_scheduler)]
+    async fn test_proxy_pdu_relayed() {
+        let mut internals = internals();
+        let config_states = internals.device_state_mut().config_states_mut();
+        config_states.gatt_proxy_state = GATTProxyState::Enabled;
+        config_states.relay_state = RelayState::Disabled;
+        let net_keys = *internals
+            .net_keys()
+            .get_keys(net_key_index())
+            .expect("key inserted above")
+            .tx_key()
+            .network_keys();
+        let internals = RwLock::new(internals);
+        let replay_cache = Mutex::new(replay::Cache::default());
+        let network_cache = Mutex::new(replay::NetworkMessageCache::default());
+        let (mut relay_tx, mut relay_rx) = mpsc::channel(2);
+        let stats = StatsCounters::new();
+        let src = UnicastAddress::new(0x0100);
+        let encrypt = |seq: u32| {
+            net::PDU {
+                header: net::Header {
+                    ivi: IVIndex(0).ivi(),
+                    nid: NID::new(0),
+                    ctl: CTL(false),
+                    ttl: TTL::new(5),
+                    seq: SequenceNumber(U24::new(seq)),
+                    src,
+                    dst: Address::from(0x0002),
+                },
+                payload: lower::PDU::UnsegmentedAccess(lower::UnsegmentedAccessPDU::new(
+                    None, &[0_u8; 5],
+                )),
             }
-            if is_old_seq_zero {
-                // We've already handle this PDU
-                return Err(RecvError::OldSeqZero);
+            .encrypt(&net_keys, IVIndex(0))
+            .expect("valid PDU")
+        };
+
+        // Proxied PDUs are relayed to the advertising bearer by the Proxy feature.
+        let encrypted = encrypt(1);
+        let proxied =
+            IncomingEncryptedNetworkPDU::from_proxy_pdu(AsRef::<[u8]>::as_ref(&encrypted))
+                .expect("valid network PDU");
+        assert!(proxied.from_proxy);
+        assert!(!proxied.dont_relay);
+        let decrypted = Incoming::handle_encrypted_net_pdu(
+            &internals,
+            &replay_cache,
+            &network_cache,
+            Some(&mut relay_tx),
+            None,
+            proxied,
+            &stats,
+            &logger(),
+        )
+        .await
+        .expect("proxied PDU should decrypt");
+        assert_eq!(decrypted.pdu.header.src, src);
+        assert_eq!(decrypted.pdu.header.seq, SequenceNumber(U24::new(1)));
+        let relayed = relay_rx.try_recv().expect("proxied PDU relayed");
+        assert_eq!(relayed.pdu.header.src, src);
+        assert_eq!(relayed.net_key_index, net_key_index());
+
+        // The same PDU from the advertising bearer needs the Relay feature.
+        let advertised = IncomingEncryptedNetworkPDU {
+            encrypted_pdu: encrypt(2),
+            rssi: None,
+            dont_relay: false,
+            from_proxy: false,
+        };
+        assert!(Incoming::handle_encrypted_net_pdu(
+            &internals,
+            &replay_cache,
+            &network_cache,
+            Some(&mut relay_tx),
+            None,
+            advertised,
+            &stats,
+            &logger(),
+        )
+        .await
+        .is_ok());
+        assert!(relay_rx.try_recv().is_err());
+        // Exactly one relay happened.
+        assert_eq!(stats.get(Counter::PDURelayed), 1);
+        assert_eq!(stats.get(Counter::PDUDecrypted), 2);
+    }
+    #[tokio::test(threaded_scheduler)]
+    async fn test_relayed_once() {
+        let mut internals = internals();
+        internals.device_state_mut().config_states_mut().relay_state = RelayState::Enabled;
+        let net_keys = *internals
+            .net_keys()
+            .get_keys(net_key_index())
+            .expect("key inserted above")
+            .tx_key()
+            .network_keys();
+        let internals = RwLock::new(internals);
+        let replay_cache = Mutex::new(replay::Cache::default());
+        let network_cache = Mutex::new(replay::NetworkMessageCache::default());
+        let (mut relay_tx, mut relay_rx) = mpsc::channel(4);
+        let stats = StatsCounters::new();
+        let relayable = |seq: u32| IncomingEncryptedNetworkPDU {
+            encrypted_pdu: net::PDU {
+                header: net::Header {
+                    ivi: IVIndex(0).ivi(),
+                    nid: NID::new(0),
+                    ctl: CTL(false),
+                    ttl: TTL::new(5),
+                    seq: SequenceNumber(U24::new(seq)),
+                    src: UnicastAddress::new(0x0100),
+                    dst: Address::from(0x0002),
+                },
+                payload: lower::PDU::UnsegmentedAccess(lower::UnsegmentedAccessPDU::new(
+                    None, &[0_u8; 5],
+                )),
             }
-            Ok(IncomingNetworkPDU {
-                pdu,
-                net_key_index,
-                iv_index,
-                rssi: incoming.rssi,
-            })
-        } else {
-            Err(RecvError::NoMatchingNetKey)
+            .encrypt(&net_keys, IVIndex(0))
+            .expect("valid PDU"),
+            rssi: None,
+            dont_relay: false,
+            from_proxy: false,
+        };
+        // The same PDU heard from three bearers/relays is handled and relayed once.
+        let mut results = Vec::new();
+        for _ in 0..3 {
+            results.push(
+                Incoming::handle_encrypted_net_pdu(
+                    &internals,
+                    &replay_cache,
+                    &network_cache,
+                    Some(&mut relay_tx),
+                    None,
+                    relayable(3),
+                    &stats,
+                    &logger(),
+                )
+                .await
+                .is_ok(),
+            );
+        }
+        assert_eq!(results, [true, false, false]);
+        assert_eq!(
+            relay_rx
+                .try_recv()
+                .expect("relayed")
+                .pdu
+                .header
+                .seq
+                .0
+                .value(),
+            3
+        );
+        assert!(relay_rx.try_recv().is_err());
+        assert_eq!(stats.get(Counter::PDURelayed), 1);
+
+        // A reordered older PDU isn't in the network cache so it's still relayed even though
+        // replay protection drops it here.
+        match Incoming::handle_encrypted_net_pdu(
+            &internals,
+            &replay_cache,
+            &network_cache,
+            Some(&mut relay_tx),
+            None,
+            relayable(2),
+            &stats,
+            &logger(),
+        )
+        .await
+        {
+            Err(RecvError::OldSeq) => (),
+            _ => panic!("reordered PDU wasn't replay protected"),
+        }
+        assert_eq!(
+            relay_rx
+                .try_recv()
+                .expect("relayed")
+                .pdu
+                .header
+                .seq
+                .0
+                .value(),
+            2
+        );
+        assert_eq!(stats.get(Counter::PDURelayed), 2);
+        assert_eq!(network_cache.lock().await.len(), 2);
+    }
+    #[tokio::test(threaded_scheduler)]
+    async fn test_unknown_control_policy() {
+        let internals = internals();
+        let net_keys = *internals
+            .net_keys()
+            .get_keys(net_key_index())
+            .expect("key inserted above")
+            .tx_key()
+            .network_keys();
+        let internals = RwLock::new(internals);
+        let replay_cache = Mutex::new(replay::Cache::default());
+        let network_cache = Mutex::new(replay::NetworkMessageCache::default());
+        let stats = StatsCounters::new();
+        // Segmented Control PDU with the RFU opcode 0x7F.
+        let seg_header = lower::SegmentHeader::new(
+            false,
+            lower::SeqZero::new(0x10),
+            lower::SegO::new(1),
+            lower::SegN::new(0),
+        );
+        let mut transport_pdu = [0xAA_u8; 12];
+        transport_pdu[0] = 0x80 | 0x7F;
+        transport_pdu[1..4].copy_from_slice(&seg_header.pack_into_u24().value().to_be_bytes()[1..]);
+        let encrypted_pdu = net::Header {
+            ivi: IVIndex(0).ivi(),
+            nid: NID::new(0),
+            ctl: CTL(true),
+            ttl: TTL::new(5),
+            seq: SequenceNumber(U24::new(1)),
+            src: UnicastAddress::new(0x0002),
+            dst: Address::from(0x0001),
+        }
+        .encrypt_transport_pdu(&transport_pdu, &net_keys, IVIndex(0))
+        .expect("valid PDU");
+        let received = Incoming::handle_encrypted_net_pdu(
+            &internals,
+            &replay_cache,
+            &network_cache,
+            None,
+            None,
+            IncomingEncryptedNetworkPDU {
+                encrypted_pdu,
+                rssi: None,
+                dont_relay: true,
+                from_proxy: false,
+            },
+            &stats,
+            &logger(),
+        )
+        .await;
+        let unknown = match received {
+            Err(RecvError::UnknownControlOpcode(index, iv_index, unknown)) => {
+                assert_eq!(index, net_key_index());
+                assert_eq!(iv_index, IVIndex(0));
+                unknown
+            }
+            _ => panic!("unknown control opcode wasn't reported"),
+        };
+        assert_eq!(unknown.opcode, 0x7F);
+        assert_eq!(unknown.segment_header, Some(seg_header));
+        // Left to the caller's policy.
+        assert_eq!(stats.snapshot().pdus_dropped.total(), 0);
+
+        let (mut tx, mut rx) = mpsc::channel(2);
+        Incoming::handle_unknown_control(
+            UnknownControlPolicy::Drop,
+            &mut tx,
+            net_key_index(),
+            IVIndex(0),
+            &unknown,
+            &stats,
+            &logger(),
+        );
+        assert!(rx.try_recv().is_err());
+        assert_eq!(
+            stats
+                .snapshot()
+                .pdus_dropped
+                .get(DropReason::UnknownControlOpcode),
+            1
+        );
+
+        Incoming::handle_unknown_control(
+            UnknownControlPolicy::CancelSegmented,
+            &mut tx,
+            net_key_index(),
+            IVIndex(0),
+            &unknown,
+            &stats,
+            &logger(),
+        );
+        let cancel = rx.try_recv().expect("cancel ack sent");
+        assert_eq!(cancel.src, UnicastAddress::new(0x0001));
+        assert_eq!(cancel.dst, Address::from(0x0002));
+        assert_eq!(cancel.net_key_index, net_key_index());
+        match cancel.pdu {
+            lower::PDU::UnsegmentedControl(pdu) => {
+                let ack = control::Ack::try_from_pdu(&pdu).expect("ack PDU");
+                assert_eq!(ack.seq_zero, seg_header.seq_zero);
+                assert_eq!(ack.block_ack, BlockAck::cancel());
+            }
+            _ => panic!("expected an unsegmented ack"),
         }
+        assert_eq!(stats.get(Counter::AckSent), 1);
+        assert_eq!(
+            stats
+                .snapshot()
+                .pdus_dropped
+                .get(DropReason::UnknownControlOpcode),
+            2
+        );
     }
 }
@@ -0,0 +1,290 @@
+//! IV Update procedure. A node is either in Normal Operation or has an IV Update in Progress
+//! (the IV Update flag in `DeviceState`). The IV Index only moves forward and each phase has to
+//! last at least `MIN_IV_UPDATE_PHASE` before it can change again. Every time the IV Index used
+//! for transmitting changes, the Sequence Numbers start over from 0.
+use crate::beacon::VerifiedBeacon;
+use crate::device_state::DeviceState;
+use crate::mesh::{IVIndex, IVUpdateFlag};
+use crate::stack::IV_INDEX_RECOVERY_LIMIT;
+use crate::timestamp::{Timestamp, TimestampTrait};
+use core::time::Duration;
+
+/// Minimum time spent in Normal Operation or IV Update in Progress before changing to the other
+/// (96 hours).
+pub const MIN_IV_UPDATE_PHASE: Duration = Duration::from_secs(96 * 60 * 60);
+/// Minimum time between two IV Index Recoveries (192 hours).
+pub const MIN_IV_RECOVERY_INTERVAL: Duration = Duration::from_secs(192 * 60 * 60);
+
+/// Why a beacon's IV Index or IV Update flag wasn't taken.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum IVUpdateError {
+    /// The beacon's IV Index is older than the current one.
+    OldIVIndex,
+    /// The beacon's IV Index is more than `IV_INDEX_RECOVERY_LIMIT` ahead.
+    TooFarAhead,
+    /// The current phase hasn't lasted `MIN_IV_UPDATE_PHASE` yet.
+    PhaseTooShort,
+    /// The last IV Index Recovery was less than `MIN_IV_RECOVERY_INTERVAL` ago.
+    RecoveryTooSoon,
+    /// An IV Update is already in progress (when starting one) or isn't (when finishing one).
+    WrongPhase,
+    /// The IV Index can't go any higher.
+    IVIndexExhausted,
+}
+/// Tracks when the IV Update phase last changed. The IV Index and IV Update flag themselves are
+/// stored in `DeviceState` so they get saved with the rest of it.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash, Default)]
+pub struct IVUpdateState {
+    /// `None` until the first change since start up. Nothing is known about how long the saved
+    /// phase has lasted so the first change is always allowed.
+    phase_start: Option<Timestamp>,
+    last_recovery: Option<Timestamp>,
+}
+impl IVUpdateState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// When the current phase started or `None` if it hasn't changed since start up.
+    pub fn phase_start(&self) -> Option<Timestamp> {
+        self.phase_start
+    }
+    fn has_elapsed(since: Option<Timestamp>, duration: Duration, now: Timestamp) -> bool {
+        since.map_or(true, |since| {
+            now.since(since)
+                .map_or(false, |elapsed| elapsed >= duration)
+        })
+    }
+    /// Applies an authenticated Secure Network Beacon to `device_state`'s IV Index and IV Update
+    /// flag:
+    /// * Same IV Index with a cleared flag finishes an IV Update in Progress.
+    /// * The next IV Index with the flag set starts an IV Update from Normal Operation.
+    /// * Any other newer IV Index (at most `IV_INDEX_RECOVERY_LIMIT` ahead) is an IV Index
+    /// Recovery and the beacon's IV Index and flag are taken as is.
+    ///
+    /// Returns `Ok(true)` if anything changed and `Ok(false)` if the beacon agrees with the
+    /// current state.
+    pub fn handle_beacon(
+        &mut self,
+        device_state: &mut DeviceState,
+        beacon: &VerifiedBeacon,
+        now: Timestamp,
+    ) -> Result<bool, IVUpdateError> {
+        let current = device_state.iv_index();
+        let in_progress = bool::from(device_state.iv_update_flag());
+        let beacon_in_progress = bool::from(beacon.iv_update);
+        if beacon.iv_index < current {
+            return Err(IVUpdateError::OldIVIndex);
+        }
+        if beacon.iv_index.0 - current.0 > IV_INDEX_RECOVERY_LIMIT {
+            return Err(IVUpdateError::TooFarAhead);
+        }
+        let next = current.next();
+        if beacon.iv_index == current || (Some(beacon.iv_index) == next && beacon_in_progress) {
+            let changes = if beacon.iv_index == current {
+                // A Normal Operation beacon for our IV Index finishes our IV Update. Beacons from
+                // nodes still in progress with our IV Index don't change anything.
+                in_progress && !beacon_in_progress
+            } else {
+                // A node already in progress for `current` has to finish first.
+                !in_progress
+            };
+            if !changes {
+                return Ok(false);
+            }
+            if !Self::has_elapsed(self.phase_start, MIN_IV_UPDATE_PHASE, now) {
+                return Err(IVUpdateError::PhaseTooShort);
+            }
+        } else {
+            if !Self::has_elapsed(self.last_recovery, MIN_IV_RECOVERY_INTERVAL, now) {
+                return Err(IVUpdateError::RecoveryTooSoon);
+            }
+            self.last_recovery = Some(now);
+        }
+        self.set_phase(
+            device_state,
+            beacon.iv_index,
+            IVUpdateFlag(beacon_in_progress),
+            now,
+        );
+        Ok(true)
+    }
+    /// Starts an IV Update from Normal Operation (when the node is running out of Sequence
+    /// Numbers, etc). Messages are still sent with the current IV Index until `finish`.
+    pub fn start(
+        &mut self,
+        device_state: &mut DeviceState,
+        now: Timestamp,
+    ) -> Result<(), IVUpdateError> {
+        if bool::from(device_state.iv_update_flag()) {
+            return Err(IVUpdateError::WrongPhase);
+        }
+        let next = device_state
+            .iv_index()
+            .next()
+            .ok_or(IVUpdateError::IVIndexExhausted)?;
+        if !Self::has_elapsed(self.phase_start, MIN_IV_UPDATE_PHASE, now) {
+            return Err(IVUpdateError::PhaseTooShort);
+        }
+        self.set_phase(device_state, next, IVUpdateFlag(true), now);
+        Ok(())
+    }
+    /// Finishes an IV Update in Progress and goes back to Normal Operation with the new IV Index
+    /// (which resets the Sequence Numbers).
+    pub fn finish(
+        &mut self,
+        device_state: &mut DeviceState,
+        now: Timestamp,
+    ) -> Result<(), IVUpdateError> {
+        if !bool::from(device_state.iv_update_flag()) {
+            return Err(IVUpdateError::WrongPhase);
+        }
+        if !Self::has_elapsed(self.phase_start, MIN_IV_UPDATE_PHASE, now) {
+            return Err(IVUpdateError::PhaseTooShort);
+        }
+        let iv_index = device_state.iv_index();
+        self.set_phase(device_state, iv_index, IVUpdateFlag(false), now);
+        Ok(())
+    }
+    fn set_phase(
+        &mut self,
+        device_state: &mut DeviceState,
+        iv_index: IVIndex,
+        iv_update: IVUpdateFlag,
+        now: Timestamp,
+    ) {
+        let tx_iv_index = device_state.tx_iv_index();
+        *device_state.iv_index_mut() = iv_index;
+        *device_state.iv_update_flag_mut() = iv_update;
+        if device_state.tx_iv_index() != tx_iv_index {
+            device_state.reset_seq_counters();
+        }
+        self.phase_start = Some(now);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::UnicastAddress;
+    use crate::crypto::NetworkID;
+    use crate::mesh::{ElementCount, ElementIndex, KeyRefreshFlag};
+
+    fn device_state() -> DeviceState {
+        DeviceState::new(UnicastAddress::new(1), ElementCount(1))
+    }
+
+    fn beacon(iv_index: u32, iv_update: bool) -> VerifiedBeacon {
+        VerifiedBeacon {
+            network_id: NetworkID(0),
+            iv_index: IVIndex(iv_index),
+            key_refresh: KeyRefreshFlag(false),
+            iv_update: IVUpdateFlag(iv_update),
+        }
+    }
+    #[test]
+    fn test_iv_update_phases() {
+        let mut device_state = device_state();
+        let mut state = IVUpdateState::new();
+        let start = Timestamp::now();
+        let hour = Duration::from_secs(60 * 60);
+        let flags =
+            |device_state: &DeviceState| (device_state.iv_index(), device_state.iv_update_flag());
+
+        // The saved phase has no known start so the IV Update starts right away.
+        assert_eq!(
+            state.handle_beacon(&mut device_state, &beacon(1, true), start),
+            Ok(true)
+        );
+        assert_eq!(flags(&device_state), (IVIndex(1), IVUpdateFlag(true)));
+        assert_eq!(device_state.tx_iv_index(), IVIndex(0));
+        // Agreeing beacons don't change anything.
+        assert_eq!(
+            state.handle_beacon(&mut device_state, &beacon(1, true), start),
+            Ok(false)
+        );
+        // IV Update in Progress has to last 96 hours.
+        assert_eq!(
+            state.handle_beacon(&mut device_state, &beacon(1, false), start + hour * 95),
+            Err(IVUpdateError::PhaseTooShort)
+        );
+        assert_eq!(
+            state.handle_beacon(&mut device_state, &beacon(1, false), start + hour * 96),
+            Ok(true)
+        );
+        assert_eq!(flags(&device_state), (IVIndex(1), IVUpdateFlag(false)));
+        assert_eq!(device_state.tx_iv_index(), IVIndex(1));
+        // So does Normal Operation.
+        assert_eq!(
+            state.handle_beacon(&mut device_state, &beacon(2, true), start + hour * 100),
+            Err(IVUpdateError::PhaseTooShort)
+        );
+        assert_eq!(
+            state.handle_beacon(&mut device_state, &beacon(0, false), start + hour * 200),
+            Err(IVUpdateError::OldIVIndex)
+        );
+        assert_eq!(flags(&device_state), (IVIndex(1), IVUpdateFlag(false)));
+    }
+    #[test]
+    fn test_iv_index_recovery() {
+        let mut device_state = device_state();
+        let mut state = IVUpdateState::new();
+        let start = Timestamp::now();
+        let hour = Duration::from_secs(60 * 60);
+
+        assert_eq!(
+            state.handle_beacon(
+                &mut device_state,
+                &beacon(IV_INDEX_RECOVERY_LIMIT + 1, false),
+                start
+            ),
+            Err(IVUpdateError::TooFarAhead)
+        );
+        assert_eq!(
+            state.handle_beacon(&mut device_state, &beacon(10, false), start),
+            Ok(true)
+        );
+        assert_eq!(device_state.iv_index(), IVIndex(10));
+        // Only one recovery every 192 hours.
+        assert_eq!(
+            state.handle_beacon(&mut device_state, &beacon(20, false), start + hour * 191),
+            Err(IVUpdateError::RecoveryTooSoon)
+        );
+        assert_eq!(
+            state.handle_beacon(&mut device_state, &beacon(20, true), start + hour * 192),
+            Ok(true)
+        );
+        assert_eq!(device_state.iv_index(), IVIndex(20));
+        assert_eq!(device_state.iv_update_flag(), IVUpdateFlag(true));
+    }
+    #[test]
+    fn test_start_and_finish() {
+        let mut device_state = device_state();
+        let mut state = IVUpdateState::new();
+        let start = Timestamp::now();
+        let seq = |device_state: &DeviceState| device_state.seq_counter(ElementIndex(0)).check();
+        assert_eq!(
+            state.finish(&mut device_state, start),
+            Err(IVUpdateError::WrongPhase)
+        );
+        device_state.next_seq(ElementIndex(0)).unwrap();
+
+        assert_eq!(state.start(&mut device_state, start), Ok(()));
+        assert_eq!(device_state.iv_index(), IVIndex(1));
+        assert_eq!(device_state.tx_iv_index(), IVIndex(0));
+        // Still sending with the old IV Index so the Sequence Numbers carry on.
+        assert_ne!(seq(&device_state).0.value(), 0);
+        assert_eq!(
+            state.start(&mut device_state, start + MIN_IV_UPDATE_PHASE),
+            Err(IVUpdateError::WrongPhase)
+        );
+        assert_eq!(
+            state.finish(&mut device_state, start),
+            Err(IVUpdateError::PhaseTooShort)
+        );
+        assert_eq!(
+            state.finish(&mut device_state, start + MIN_IV_UPDATE_PHASE),
+            Ok(())
+        );
+        assert_eq!(device_state.tx_iv_index(), IVIndex(1));
+        assert_eq!(seq(&device_state).0.value(), 0);
+    }
+}
@@ -91,6 +91,33 @@ impl<Storage: AsRef<[u8]>> OutgoingUpperTransportMessage<Storage> {
     pub fn should_segment(&self) -> bool {
         self.upper_pdu.should_segment()
     }
+    /// Returns the message as a single Unsegmented Lower Transport PDU (using the first `seq`)
+    /// or `None` if it's too long to fit in one PDU.
+    pub fn as_unsegmented(&self) -> Option<OutgoingLowerTransportMessage> {
+        Some(OutgoingLowerTransportMessage {
+            pdu: match &self.upper_pdu {
+                upper::PDU::Access(payload) => {
+                    lower::PDU::UnsegmentedAccess(payload.as_unsegmented()?)
+                }
+                upper::PDU::Control(payload) => {
+                    let parameters = payload.payload.as_ref();
+                    if parameters.len() > lower::UnsegmentedControlPDU::max_parameters_size() {
+                        return None;
+                    }
+                    lower::PDU::UnsegmentedControl(lower::UnsegmentedControlPDU::new(
+                        payload.opcode,
+                        parameters,
+                    ))
+                }
+            },
+            src: self.src,
+            dst: self.dst,
+            ttl: self.ttl,
+            seq: Some(self.seq.start()),
+            iv_index: self.iv_index,
+            net_key_index: self.net_key_index,
+        })
+    }
     pub fn into_outgoing_segments(self) -> segments::OutgoingSegments<Storage> {
         debug_assert_eq!(
             self.seq.seqs_lefts(),
@@ -154,6 +181,10 @@ impl<Storage: AsRef<[u8]>> EncryptedIncomingMessage<Storage> {
 pub struct IncomingControlMessage {
     pub control_pdu: control::ControlPDU,
     pub src: UnicastAddress,
+    pub dst: Address,
+    pub seq: SequenceNumber,
+    pub iv_index: IVIndex,
+    pub net_key_index: NetKeyIndex,
     pub rssi: Option<RSSI>,
     pub ttl: Option<TTL>,
 }
@@ -186,3 +217,13 @@ pub struct IncomingTransportPDU<Storage: AsRef<[u8]> + AsMut<[u8]>> {
     pub src: UnicastAddress,
     pub dst: Address,
 }
+impl<Storage: AsRef<[u8]> + AsMut<[u8]>> IncomingTransportPDU<Storage> {
+    /// Returns the `ControlOpcode` (carried by every segment of a Segmented Control PDU) or
+    /// `None` if the PDU is an Access PDU.
+    pub fn control_opcode(&self) -> Option<control::ControlOpcode> {
+        match &self.upper_pdu {
+            upper::PDU::Control(payload) => Some(payload.opcode),
+            upper::PDU::Access(_) => None,
+        }
+    }
+}
@@ -1,40 +1,86 @@
 //! Bluetooth Mesh Stack that connects all the layers together.
 //! See ['StackInternals'] for more.
+//!
+//! # Locking
+//! The full stack shares `StackInternals` behind an `RwLock` and the `replay::Cache` behind a
+//! `Mutex`. To keep PDU processing from serializing on those locks:
+//! * The `StackInternals` read lock is only held while encrypting/decrypting and copying out
+//! parameters. It's always released before sending on a channel or waiting on a bearer.
+//! * Never hold the `StackInternals` lock while locking the `replay::Cache` (or the other way).
+//! * Relayed PDUs are handed off already decrypted and get re-encrypted under a fresh read lock.
 
 pub mod bearer;
 pub mod bearers;
+#[cfg(feature = "full_stack")]
+pub mod clock;
 pub mod element;
 #[cfg(feature = "full_stack")]
 pub mod full;
 #[cfg(feature = "full_stack")]
 pub mod incoming;
+pub mod iv_update;
 pub mod messages;
 pub mod model;
+pub mod nid_index;
 #[cfg(feature = "full_stack")]
 pub mod outgoing;
+pub mod publication;
 #[cfg(feature = "std")]
 pub mod segments;
+pub mod stats;
+pub mod subscriptions;
+pub mod watchdog;
 
-use crate::address::{Address, UnicastAddress, VirtualAddress, VirtualAddressHash};
+use crate::access::ModelIdentifier;
+use crate::address::{Address, GroupAddress, UnicastAddress, VirtualAddress, VirtualAddressHash};
+use crate::beacon::{SecureNetworkBeacon, VerifiedBeacon};
+use crate::control;
+use crate::control::ControlMessage;
+use crate::crypto::aes::MicSize;
 
-use crate::crypto::materials::{ApplicationSecurityMaterials, NetKeyMap, NetworkSecurityMaterials};
+use crate::crypto::key::{AppKey, NetKey};
+use crate::crypto::materials::{
+    AppKeyError, ApplicationSecurityMaterials, NetKeyError, NetKeyMap, NetworkKeys,
+};
 use crate::crypto::nonce::{AppNonceParts, DeviceNonceParts};
-use crate::device_state::{DeviceState, SeqCounter};
+use crate::crypto::KeyRefreshPhases;
+use crate::device_state::{DeviceState, SeqCounter, SeqRange};
+use crate::foundation::state::FriendState;
+use crate::friend;
+use crate::friend::friend::{FriendConfig, FriendNode, QueueEntry};
+use crate::friend::lpn::LowPowerNode;
+use crate::friend::{
+    FriendPoll, FriendRequest, FriendSubscriptionList, FriendshipCredentials, Friendships,
+    PollTimeout, ReceiveDelay, MD,
+};
+use crate::heartbeat::{HeartbeatPublication, HeartbeatSubscription};
 use crate::lower::SegO;
 use crate::mesh::{
-    AppKeyIndex, ElementCount, ElementIndex, IVIndex, IVUpdateFlag, NetKeyIndex, TTL,
+    AppKeyIndex, ElementCount, ElementIndex, IVIndex, IVUpdateFlag, KeyRefreshFlag, NetKeyIndex,
+    TTL,
 };
-use crate::net::OwnedEncryptedPDU;
+use crate::models::config::server::ConfigServer;
 use crate::segmenter::EncryptedNetworkPDUIterator;
+use crate::stack::bearer::OutgoingEncryptedNetworkPDU;
 use crate::stack::element::ElementRef;
+use crate::stack::iv_update::{IVUpdateError, IVUpdateState};
 use crate::stack::messages::{
     EncryptedIncomingMessage, IncomingMessage, MessageKeys, OutgoingLowerTransportMessage,
     OutgoingMessage, OutgoingUpperTransportMessage,
 };
+use crate::stack::nid_index::NIDIndex;
 use crate::stack::segments::ReassemblyError;
+use crate::stack::subscriptions::SubscriptionList;
+use crate::timestamp::Timestamp;
 use crate::upper;
 use crate::upper::{AppPayload, SecurityMaterials, SecurityMaterialsIterator};
-use crate::{device_state, net};
+use crate::{device_state, lower, net};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::time::Duration;
+/// How far ahead of the current IV Index an authenticated beacon's IV Index is accepted (IV
+/// Index Recovery).
+pub const IV_INDEX_RECOVERY_LIMIT: u32 = 42;
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
 pub struct NetworkHeader {
     pub src: UnicastAddress,
@@ -59,6 +105,61 @@ pub struct NetworkHeader {
 /// The scheduling and input/output queues are handled by `FullStack`.
 pub struct StackInternals {
     device_state: device_state::DeviceState,
+    friendships: Friendships,
+    friend_node: Option<FriendNode>,
+    low_power_node: LowPowerNode,
+    iv_update: IVUpdateState,
+    subscriptions: SubscriptionList,
+    heartbeat_publication: HeartbeatPublication,
+    heartbeat_subscription: HeartbeatSubscription,
+    nid_index: NIDIndex,
+    seq_store: Option<Box<dyn SeqStore>>,
+}
+/// Saves the `DeviceState` whenever a new block of Sequence Numbers is reserved (see
+/// [`DeviceState::next_seqs`]) so they're never reused after a restart. It's called with the
+/// `StackInternals` lock held, once every `device_state::SEQ_BLOCK_LEN` Sequence Numbers.
+pub trait SeqStore: Send + Sync {
+    fn save(&self, device_state: &DeviceState) -> Result<(), SeqStoreError>;
+}
+/// Returned by a [`SeqStore`] that couldn't save the `DeviceState`.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub struct SeqStoreError;
+/// Which Network Layer security credentials a PDU is encrypted with.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum SecurityCredentials {
+    /// The NetKey's own NID, EncryptionKey and PrivacyKey. Used for everything by default.
+    ManagedFlooding,
+    /// Keys derived from the friendship with the Low Power node at the given address. Used
+    /// between a Friend and its Low Power node.
+    Friendship(UnicastAddress),
+    /// Keys derived from this Low Power node's friendship with the Friend at the given address.
+    Friend(UnicastAddress),
+}
+/// Where an outgoing Access message sent to `dst` ends up. See [`StackInternals::loopback`].
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum Loopback {
+    /// `dst` isn't local so the message only goes out on the bearers.
+    Transmit,
+    /// `dst` is one of this node's elements so the message is delivered locally and never
+    /// transmitted.
+    Local,
+    /// `dst` is a group or virtual address this node is subscribed to. Other nodes may be
+    /// subscribed to it as well so the message is delivered locally and transmitted.
+    LocalAndTransmit,
+}
+impl Loopback {
+    pub fn is_local(self) -> bool {
+        match self {
+            Loopback::Transmit => false,
+            Loopback::Local | Loopback::LocalAndTransmit => true,
+        }
+    }
+    pub fn is_transmitted(self) -> bool {
+        match self {
+            Loopback::Local => false,
+            Loopback::Transmit | Loopback::LocalAndTransmit => true,
+        }
+    }
 }
 /// Returned when an outgoing message can't be sent for some reason.
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
@@ -68,10 +169,25 @@ pub enum SendError {
     InvalidIVIndex,
     InvalidNetKeyIndex,
     InvalidDestination,
+    /// The source or destination is the unassigned address (`0x0000`).
+    InvalidAddress,
     InvalidSourceElement,
     NetEncryptError,
     OutOfSeq,
     AckTimeout,
+    /// The receiver canceled the segmented transfer with a `BlockAck::cancel()` ack.
+    Canceled,
+    /// The receiver didn't ack any new segments after `SEGMENT_RETRANSMITS` retransmissions in a
+    /// row.
+    Unacknowledged,
+    /// This node stopped the segmented transfer (`Outgoing::cancel`).
+    Aborted,
+    /// `Outgoing::cancel` was called for a segmented message that isn't the one being sent.
+    NotInFlight,
+    /// `FullStack::shutdown` aborted the segmented messages that weren't sent by its deadline.
+    ShutdownTimeout,
+    /// A new block of Sequence Numbers was reserved but the [`SeqStore`] couldn't save it.
+    SeqNotSaved,
 }
 /// Returned when an incoming message can't be received for some reason.
 #[derive(Debug)]
@@ -84,6 +200,9 @@ pub enum RecvError {
     InvalidDestination,
     MalformedNetworkPDU,
     MalformedControlPDU,
+    /// The Network PDU authenticated but it's a Control PDU with an unknown opcode. See
+    /// `incoming::UnknownControlPolicy`.
+    UnknownControlOpcode(NetKeyIndex, IVIndex, net::UnknownControlPDU),
     OldSeq,
     ChannelClosed,
     OldSeqZero,
@@ -91,7 +210,249 @@ pub enum RecvError {
 impl StackInternals {
     /// Wraps a `device_state::DeviceState` and lets you perform encrypt and decryption with it.
     pub fn new(device_state: device_state::DeviceState) -> Self {
-        Self { device_state }
+        let mut internals = Self {
+            device_state,
+            friendships: Friendships::new(),
+            friend_node: None,
+            low_power_node: LowPowerNode::new(),
+            iv_update: IVUpdateState::new(),
+            subscriptions: SubscriptionList::new(),
+            heartbeat_publication: HeartbeatPublication::default(),
+            heartbeat_subscription: HeartbeatSubscription::new(),
+            nid_index: NIDIndex::new(),
+            seq_store: None,
+        };
+        internals.sync_friend_node();
+        internals.rebuild_nid_index();
+        internals
+    }
+    /// The NetKeys and friendship keys by `NID` used by
+    /// [`StackInternals::decrypt_network_pdu`].
+    pub fn nid_index(&self) -> &NIDIndex {
+        &self.nid_index
+    }
+    fn rebuild_nid_index(&mut self) {
+        self.nid_index.rebuild(
+            &self.device_state.security_materials().net_key_map,
+            self.friendships
+                .iter()
+                .map(|friendship| friendship.credentials),
+            self.lpn_credentials(),
+        );
+    }
+    fn lpn_credentials(&self) -> Option<FriendshipCredentials> {
+        self.low_power_node
+            .friendship()
+            .map(|friendship| friendship.credentials)
+    }
+    /// Returns `false` if keys or friendships might have changed since the `NIDIndex` was built.
+    fn is_nid_index_current(&self) -> bool {
+        !self.nid_index.is_stale() && self.nid_index.lpn_credentials() == self.lpn_credentials()
+    }
+    /// Rebuilds the `NIDIndex` if keys or friendships might have changed through
+    /// [`StackInternals::device_state_mut`], [`StackInternals::friendships_mut`] or the Low Power
+    /// node accepting (or ending) a friendship. Until then, every key is tried on every PDU.
+    pub fn refresh_nid_index(&mut self) {
+        if !self.is_nid_index_current() {
+            self.rebuild_nid_index();
+        }
+    }
+    /// Group and virtual addresses the elements are subscribed to (on top of the model
+    /// subscriptions stored in `DeviceState`).
+    pub fn subscriptions(&self) -> &SubscriptionList {
+        &self.subscriptions
+    }
+    pub fn subscriptions_mut(&mut self) -> &mut SubscriptionList {
+        &mut self.subscriptions
+    }
+    /// Returns the element a PDU sent to `dst` is for or `None` if it isn't for this node:
+    /// * Unicast addresses match the element with that address.
+    /// * The all-nodes address always matches the primary element. The all-relays and
+    /// all-proxies addresses only match while the Relay or GATT Proxy feature is enabled.
+    /// * Group and virtual addresses match the lowest element subscribed to them. Addresses
+    /// only a model is subscribed to (see [`DeviceState::local_addresses`]) match the primary
+    /// element.
+    pub fn matches(&self, dst: &Address) -> Option<ElementIndex> {
+        let primary = Some(ElementIndex(0));
+        let config_states = self.device_state.config_states();
+        match dst {
+            Address::Unassigned => None,
+            Address::Unicast(unicast) => self.device_state.element_index(*unicast),
+            Address::Group(group) if *group == GroupAddress::all_nodes() => primary,
+            Address::Group(group) if *group == GroupAddress::all_relays() => {
+                primary.filter(|_| config_states.relay_state.is_enabled())
+            }
+            Address::Group(group) if *group == GroupAddress::all_proxies() => {
+                primary.filter(|_| config_states.gatt_proxy_state.is_enabled())
+            }
+            _ => self.subscriptions.matches(dst).or_else(|| {
+                if self.device_state.is_local_address(dst) {
+                    primary
+                } else {
+                    None
+                }
+            }),
+        }
+    }
+    /// The friendships with Low Power nodes if this node is a Friend.
+    pub fn friendships(&self) -> &Friendships {
+        &self.friendships
+    }
+    /// Call [`StackInternals::refresh_nid_index`] after changing the friendships.
+    pub fn friendships_mut(&mut self) -> &mut Friendships {
+        self.nid_index.mark_stale();
+        &mut self.friendships
+    }
+    /// The Friend feature (Friend Queues and Friend Subscription Lists) or `None` if this node
+    /// isn't a Friend.
+    pub fn friend_node(&self) -> Option<&FriendNode> {
+        self.friend_node.as_ref()
+    }
+    pub fn friend_node_mut(&mut self) -> Option<&mut FriendNode> {
+        self.friend_node.as_mut()
+    }
+    /// Enables (`Some`) or disables (`None`) the Friend feature (and the Friend state, see
+    /// [`DeviceState::set_friend`]). Every existing friendship with a Low Power node ends either
+    /// way.
+    pub fn set_friend_node(&mut self, friend_node: Option<FriendNode>) {
+        self.friendships = Friendships::new();
+        self.device_state.set_friend(if friend_node.is_some() {
+            FriendState::Enabled
+        } else {
+            FriendState::Disabled
+        });
+        self.friend_node = friend_node;
+        self.rebuild_nid_index();
+    }
+    /// Brings the Friend feature in line with the Friend state after it changed through
+    /// [`StackInternals::device_state_mut`] or [`StackInternals::config_server`] (a Config
+    /// Friend Set). Disabling it ends every friendship (see [`StackInternals::set_friend_node`])
+    /// and enabling it starts a `FriendNode` with the default `FriendConfig`.
+    pub fn sync_friend_node(&mut self) {
+        let enabled = self.device_state.friend_state().is_enabled();
+        if enabled && self.friend_node.is_none() {
+            self.friend_node = Some(FriendNode::new(FriendConfig::default()));
+        } else if !enabled && (self.friend_node.is_some() || !self.friendships.is_empty()) {
+            self.set_friend_node(None);
+        }
+    }
+    /// The friendship with a Friend if this node is a Low Power node.
+    pub fn low_power_node(&self) -> &LowPowerNode {
+        &self.low_power_node
+    }
+    pub fn low_power_node_mut(&mut self) -> &mut LowPowerNode {
+        &mut self.low_power_node
+    }
+    /// Returns the credentials a PDU to `dst` is sent with. PDUs to a Low Power node this node is
+    /// friends with (or to this Low Power node's Friend) use the friendship credentials and
+    /// everything else uses managed flooding.
+    pub fn security_credentials(&self, dst: &Address) -> SecurityCredentials {
+        match dst {
+            Address::Unicast(unicast) if self.friendships.get(*unicast).is_some() => {
+                SecurityCredentials::Friendship(*unicast)
+            }
+            Address::Unicast(unicast)
+                if self
+                    .low_power_node
+                    .friendship()
+                    .map_or(false, |friendship| friendship.friend_address() == *unicast) =>
+            {
+                SecurityCredentials::Friend(*unicast)
+            }
+            _ => SecurityCredentials::ManagedFlooding,
+        }
+    }
+    /// Returns the `NetworkKeys` for transmitting a PDU to `dst` with the NetKey under
+    /// `net_key_index` (see [`StackInternals::security_credentials`]). Friendship keys are
+    /// derived from the NetKey currently used for transmitting.
+    pub fn tx_network_keys(
+        &self,
+        net_key_index: NetKeyIndex,
+        dst: &Address,
+    ) -> Result<NetworkKeys, SendError> {
+        self.credentials_network_keys(net_key_index, self.security_credentials(dst))
+    }
+    /// Returns the `NetworkKeys` for transmitting with `credentials` and the NetKey under
+    /// `net_key_index`. Friendship keys come from the [`NIDIndex`] unless it's stale (see
+    /// [`StackInternals::refresh_nid_index`]). Returns `SendError::InvalidDestination` if the
+    /// friendship `credentials` refers to is gone.
+    pub fn credentials_network_keys(
+        &self,
+        net_key_index: NetKeyIndex,
+        credentials: SecurityCredentials,
+    ) -> Result<NetworkKeys, SendError> {
+        let net_sm = self
+            .net_keys()
+            .get_keys(net_key_index)
+            .ok_or(SendError::InvalidNetKeyIndex)?
+            .tx_key();
+        let friendship_credentials = match credentials {
+            SecurityCredentials::ManagedFlooding => return Ok(*net_sm.network_keys()),
+            SecurityCredentials::Friendship(lpn_address) => {
+                self.friendships
+                    .get(lpn_address)
+                    .ok_or(SendError::InvalidDestination)?
+                    .credentials
+            }
+            SecurityCredentials::Friend(friend_address) => {
+                self.low_power_node
+                    .friendship()
+                    .filter(|friendship| friendship.friend_address() == friend_address)
+                    .ok_or(SendError::InvalidDestination)?
+                    .credentials
+            }
+        };
+        // Reuse the keys derived by the last `refresh_nid_index` if they're still current.
+        let cached = if self.is_nid_index_current() {
+            self.nid_index
+                .friendship_tx_keys(&friendship_credentials, net_key_index)
+        } else {
+            None
+        };
+        Ok(match cached {
+            Some(keys) => *keys,
+            None => friendship_credentials.network_keys(net_sm.net_key()),
+        })
+    }
+    /// Saves the `DeviceState` to `seq_store` every time a new block of Sequence Numbers is
+    /// reserved. Without one, the caller has to save the `DeviceState` often enough itself.
+    pub fn set_seq_store(&mut self, seq_store: Option<Box<dyn SeqStore>>) {
+        self.seq_store = seq_store;
+    }
+    /// Hands out `count` consecutive Sequence Numbers for the element at `element_index` (see
+    /// [`DeviceState::next_seqs`]). A newly reserved block is saved to the [`SeqStore`] before
+    /// any of them are returned. Returns `SendError::OutOfSeq` once the element runs out (see
+    /// [`StackInternals::handle_seq_exhausted`]).
+    pub fn next_seqs(
+        &self,
+        element_index: ElementIndex,
+        count: u32,
+    ) -> Result<SeqRange, SendError> {
+        let allocation = self
+            .device_state
+            .next_seqs(element_index, count)
+            .map_err(|_| SendError::OutOfSeq)?;
+        if allocation.new_block {
+            if let Some(seq_store) = &self.seq_store {
+                seq_store
+                    .save(&self.device_state)
+                    .map_err(|_| SendError::SeqNotSaved)?;
+            }
+        }
+        Ok(allocation.seqs())
+    }
+    /// Starts an IV Update once an element ran out of Sequence Numbers and finishes it (which
+    /// resets them) as soon as `IVUpdateState` allows. Returns if anything had to be done.
+    pub fn handle_seq_exhausted(&mut self, now: Timestamp) -> Result<bool, IVUpdateError> {
+        if !self.device_state.is_seq_exhausted() {
+            return Ok(false);
+        }
+        if bool::from(self.device_state.iv_update_flag()) {
+            self.finish_iv_update(now)?;
+        } else {
+            self.start_iv_update(now)?;
+        }
+        Ok(true)
     }
     /// Returns a reference to the Atomic `SeqCounter` pertaining to the given element.
     /// # Panics
@@ -99,12 +460,27 @@ impl StackInternals {
     pub fn seq_counter(&self, element_index: ElementIndex) -> &SeqCounter {
         self.device_state.seq_counter(element_index)
     }
-    /// Returns all the virtual addresses owned by the stack with a hash matching `hash`.
+    /// Returns every subscribed virtual address (Label UUID) with a hash matching `hash`.
+    /// Different labels can have the same 14-bit hash so more than one label might match. Each
+    /// label is only returned once even if multiple models (or elements) subscribe to it.
     pub fn matching_virtual_addresses(
         &self,
-        _h: VirtualAddressHash,
+        hash: VirtualAddressHash,
     ) -> impl Iterator<Item = &'_ VirtualAddress> + Clone {
-        Option::<&'_ VirtualAddress>::None.into_iter()
+        let mut labels = self
+            .device_state
+            .models()
+            .iter()
+            .flat_map(|(_, info)| info.subscriptions.iter())
+            .chain(self.subscriptions.addresses())
+            .filter_map(|address| match address {
+                Address::Virtual(v) if v.hash() == hash => Some(v),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        labels.sort();
+        labels.dedup();
+        labels.into_iter()
     }
     /// Attempts to decrypt the application `msg`. Multiple keys may be used to try to decrypt the
     /// message so it will have to be cloned once so any decryption can be undone if the key wasn't
@@ -138,6 +514,9 @@ impl StackInternals {
                     Address::Unassigned => return Err(RecvError::InvalidDestination),
                     Address::Group(_) | Address::Unicast(_) => {
                         //Regular Address
+                        if self.matches(&msg.dst).is_none() {
+                            return Err(RecvError::InvalidDestination);
+                        }
                         SecurityMaterialsIterator::new_app(msg.app_nonce(), matching_aid)
                     }
                 };
@@ -160,7 +539,7 @@ impl StackInternals {
                         rssi: msg.rssi,
                     })
                 } else {
-                    Err(RecvError::NoMatchingNetKey)
+                    Err(RecvError::NoMatchingAppKey)
                 }
             }
             None => match msg.dst {
@@ -172,12 +551,11 @@ impl StackInternals {
                         let nonce = msg.device_nonce();
                         let mic = msg.encrypted_app_payload.mic();
                         let mut storage: Storage = msg.encrypted_app_payload.into_storage();
-                        if SecurityMaterials::Device(
-                            nonce,
-                            &self.device_state.security_materials().dev_key,
-                        )
-                        .decrypt(&mut storage.as_mut()[..], mic)
-                        .is_ok()
+                        // Statuses from a node we provisioned are secured with its DevKey and
+                        // requests from anyone else with ours.
+                        if SecurityMaterials::Device(nonce, self.device_state.dev_key_for(msg.src))
+                            .decrypt(&mut storage.as_mut()[..], mic)
+                            .is_ok()
                         {
                             Ok(IncomingMessage {
                                 payload: storage,
@@ -209,9 +587,8 @@ impl StackInternals {
         // If DST is a VirtualAddress, it must have the full Label UUID.
         let dst = msg.dst;
         match &dst {
-            Address::VirtualHash(_) | Address::Unassigned => {
-                return Err((SendError::InvalidDestination, msg))
-            }
+            Address::Unassigned => return Err((SendError::InvalidAddress, msg)),
+            Address::VirtualHash(_) => return Err((SendError::InvalidDestination, msg)),
             _ => (),
         }
         let iv_index = self.device_state.tx_iv_index();
@@ -219,7 +596,8 @@ impl StackInternals {
             None => return Err((SendError::InvalidSourceElement, msg)),
             Some(address) => address,
         };
-        let aszmic = msg.should_segment();
+        // ASZMIC is only set for segmented messages with a 64-bit TransMIC.
+        let aszmic = msg.should_segment() && msg.mic_size.is_big();
         let seg_count = u8::from(msg.seg_o().unwrap_or_else(|| SegO::new(0))) + 1;
         let (sm, net_key_index, seq) = match msg.encryption_key {
             MessageKeys::Device(net_key_index) => {
@@ -233,14 +611,16 @@ impl StackInternals {
                     None => return Err((SendError::InvalidNetKeyIndex, msg)),
                     Some(_) => (),
                 };
-                let seq_range = match self
-                    .seq_counter(msg.source_element_index)
-                    .inc_seq(seg_count.into())
-                {
-                    None => return Err((SendError::OutOfSeq, msg)),
-                    Some(seq) => seq,
+                let seq_range = match self.next_seqs(msg.source_element_index, seg_count.into()) {
+                    Err(e) => return Err((e, msg)),
+                    Ok(seqs) => seqs,
                 };
                 let seq = seq_range.start();
+                // Config messages to a node we provisioned use its DevKey.
+                let dev_key = match dst {
+                    Address::Unicast(unicast) => self.device_state.dev_key_for(unicast),
+                    _ => &self.device_state.security_materials().dev_key,
+                };
                 (
                     upper::SecurityMaterials::Device(
                         DeviceNonceParts {
@@ -251,7 +631,7 @@ impl StackInternals {
                             iv_index,
                         }
                         .to_nonce(),
-                        &self.device_state.security_materials().dev_key,
+                        dev_key,
                     ),
                     net_key_index,
                     seq_range,
@@ -278,12 +658,9 @@ impl StackInternals {
                     None => return Err((SendError::InvalidNetKeyIndex, msg)),
                     Some(_) => (),
                 };
-                let seq_range = match self
-                    .seq_counter(msg.source_element_index)
-                    .inc_seq(seg_count.into())
-                {
-                    None => return Err((SendError::OutOfSeq, msg)),
-                    Some(seq) => seq,
+                let seq_range = match self.next_seqs(msg.source_element_index, seg_count.into()) {
+                    Err(e) => return Err((e, msg)),
+                    Ok(seqs) => seqs,
                 };
                 let seq = seq_range.start();
                 let nonce = AppNonceParts {
@@ -317,7 +694,7 @@ impl StackInternals {
         Ok(OutgoingUpperTransportMessage {
             upper_pdu: upper::PDU::Access(encrypted),
             seq,
-            seg_count: SegO::new(seg_count),
+            seg_count: SegO::new(seg_count - 1),
             net_key_index,
             src,
             dst,
@@ -325,6 +702,94 @@ impl StackInternals {
             iv_index,
         })
     }
+    /// Assigns Sequence Numbers to the Transport Control message `msg` from the primary element
+    /// to `dst`. Messages too long for an Unsegmented Control PDU (like a long Friend
+    /// Subscription List) get a Sequence Number for each segment and have to be sent segmented
+    /// ([`OutgoingUpperTransportMessage::as_unsegmented`] returns `None`).
+    pub fn control_upper<M: ControlMessage>(
+        &self,
+        msg: &M,
+        dst: Address,
+        ttl: Option<TTL>,
+        net_key_index: NetKeyIndex,
+    ) -> Result<OutgoingUpperTransportMessage<Box<[u8]>>, SendError> {
+        match dst {
+            Address::Unassigned => return Err(SendError::InvalidAddress),
+            Address::VirtualHash(_) => return Err(SendError::InvalidDestination),
+            _ => (),
+        }
+        if self
+            .device_state
+            .security_materials()
+            .net_key_map
+            .get_keys(net_key_index)
+            .is_none()
+        {
+            return Err(SendError::InvalidNetKeyIndex);
+        }
+        let mut payload = control::ControlPayload {
+            opcode: M::OPCODE,
+            payload: vec![0_u8; msg.byte_len()].into_boxed_slice(),
+        };
+        msg.try_pack(&mut payload)
+            .expect("payload is sized by byte_len");
+        let segmented = msg.byte_len() > lower::UnsegmentedControlPDU::max_parameters_size();
+        let upper_pdu = upper::PDU::Control(payload);
+        let seg_o = if segmented {
+            upper_pdu.seg_o()
+        } else {
+            SegO::new(0)
+        };
+        let seq = self.next_seqs(ElementIndex(0), u32::from(u8::from(seg_o)) + 1)?;
+        Ok(OutgoingUpperTransportMessage {
+            upper_pdu,
+            iv_index: self.tx_iv_index(),
+            seq,
+            seg_count: seg_o,
+            net_key_index,
+            src: self.primary_element_address(),
+            dst,
+            ttl: Some(ttl.unwrap_or_else(|| self.default_ttl())),
+        })
+    }
+    /// Builds an `OutgoingMessage` from the model's publication settings. Returns `None` if the
+    /// model doesn't exist or isn't publishing (including publishing to the unassigned address).
+    pub fn publish_message<Storage: AsRef<[u8]>>(
+        &self,
+        source_element_index: ElementIndex,
+        model_identifier: &ModelIdentifier,
+        app_payload: AppPayload<Storage>,
+    ) -> Option<OutgoingMessage<Storage>> {
+        let publish = self
+            .device_state
+            .models()
+            .get(model_identifier)?
+            .publish
+            .as_ref()
+            .filter(|publish| publish.address.is_assigned())?;
+        Some(OutgoingMessage {
+            app_payload,
+            mic_size: MicSize::Small,
+            force_segment: false,
+            encryption_key: MessageKeys::App(publish.app_key_index),
+            iv_index: self.device_state.tx_iv_index(),
+            source_element_index,
+            dst: publish.address,
+            ttl: publish.ttl,
+        })
+    }
+    /// Returns where a message sent to `dst` is delivered (see [`Loopback`]). Messages to a
+    /// local element stay on this node while messages to a group or virtual address this node
+    /// is subscribed to are delivered locally and transmitted.
+    pub fn loopback(&self, dst: &Address) -> Loopback {
+        if self.matches(dst).is_none() {
+            Loopback::Transmit
+        } else if dst.is_unicast() {
+            Loopback::Local
+        } else {
+            Loopback::LocalAndTransmit
+        }
+    }
     /// Returns the default `TTL`.
     pub fn default_ttl(&self) -> TTL {
         self.device_state.default_ttl()
@@ -337,35 +802,519 @@ impl StackInternals {
             .app_key_map
             .get_key(app_key_index)
     }
+    /// Adds `app_key` under `app_key_index` bound to the NetKey under `net_key_index`. Adding
+    /// the same key again is allowed. See [`DeviceState::add_app_key`].
+    pub fn add_app_key(
+        &mut self,
+        net_key_index: NetKeyIndex,
+        app_key_index: AppKeyIndex,
+        app_key: AppKey,
+    ) -> Result<(), AppKeyError> {
+        self.device_state
+            .add_app_key(net_key_index, app_key_index, app_key)
+    }
+    /// Removes the AppKey under `app_key_index` and unbinds it from every model. See
+    /// [`DeviceState::remove_app_key`].
+    pub fn remove_app_key(
+        &mut self,
+        app_key_index: AppKeyIndex,
+    ) -> Result<ApplicationSecurityMaterials, AppKeyError> {
+        self.device_state.remove_app_key(app_key_index)
+    }
+    /// Starts a Key Refresh by installing `net_key` as the new NetKey under `net_key_index`.
+    /// See [`DeviceState::update_net_key`].
+    pub fn update_net_key(
+        &mut self,
+        net_key_index: NetKeyIndex,
+        net_key: &NetKey,
+    ) -> Result<(), NetKeyError> {
+        self.device_state.update_net_key(net_key_index, net_key)?;
+        self.rebuild_nid_index();
+        Ok(())
+    }
+    /// Moves the NetKey under `net_key_index` and its AppKeys to the Key Refresh `phase`. See
+    /// [`DeviceState::set_key_refresh_phase`].
+    pub fn set_key_refresh_phase(
+        &mut self,
+        net_key_index: NetKeyIndex,
+        phase: KeyRefreshPhases,
+    ) -> Result<KeyRefreshPhases, NetKeyError> {
+        let phase = self
+            .device_state
+            .set_key_refresh_phase(net_key_index, phase)?;
+        self.rebuild_nid_index();
+        self.queue_friend_updates();
+        Ok(phase)
+    }
+    /// Updates the AppKey under `app_key_index` to `app_key` during a Key Refresh. Until the
+    /// bound NetKey moves to Key Refresh Phase 2 (see [`StackInternals::set_key_refresh_phase`]),
+    /// messages are sent with the old AppKey while messages under either AppKey are received.
+    pub fn update_app_key(
+        &mut self,
+        net_key_index: NetKeyIndex,
+        app_key_index: AppKeyIndex,
+        app_key: AppKey,
+    ) -> Result<(), AppKeyError> {
+        if self.net_keys().get_keys(net_key_index).is_none() {
+            return Err(AppKeyError::InvalidNetKeyIndex);
+        }
+        self.device_state
+            .security_materials_mut()
+            .app_key_map
+            .update(net_key_index, app_key_index, app_key)
+    }
     pub fn net_keys(&self) -> &NetKeyMap {
         &self.device_state.security_materials().net_key_map
     }
+    /// Authenticates a Secure Network Beacon with the `BeaconKey` of the NetKey matching its
+    /// Network ID (old or new during a Key Refresh). Returns `None` if none of our NetKeys
+    /// match or the Authentication Value is wrong.
+    pub fn verify_beacon(
+        &self,
+        beacon: &SecureNetworkBeacon,
+    ) -> Option<(NetKeyIndex, VerifiedBeacon)> {
+        self.net_keys().map.iter().find_map(|(&index, phase)| {
+            let (key, other_key) = phase.rx_keys();
+            core::iter::once(key)
+                .chain(other_key)
+                .filter(|materials| materials.network_id() == beacon.network_id)
+                .find_map(|materials| beacon.verify(materials.beacon_key()))
+                .map(|verified| (index, verified))
+        })
+    }
+    /// Updates the IV Index from an authenticated beacon (see `StackInternals::verify_beacon`)
+    /// received at `now`. See [`IVUpdateState::handle_beacon`] for which beacons are accepted.
+    /// Returns if the IV Index or IV Update flag changed.
+    pub fn update_iv_index(
+        &mut self,
+        beacon: &VerifiedBeacon,
+        now: Timestamp,
+    ) -> Result<bool, IVUpdateError> {
+        let updated = self
+            .iv_update
+            .handle_beacon(&mut self.device_state, beacon, now)?;
+        if updated {
+            self.queue_friend_updates();
+        }
+        Ok(updated)
+    }
+    /// Starts an IV Update at `now` (once `DeviceState::next_seq` returns `SeqError::Exhausted`,
+    /// etc). See [`IVUpdateState::start`].
+    pub fn start_iv_update(&mut self, now: Timestamp) -> Result<(), IVUpdateError> {
+        self.iv_update.start(&mut self.device_state, now)?;
+        self.queue_friend_updates();
+        Ok(())
+    }
+    /// Finishes an IV Update started by `StackInternals::start_iv_update`. See
+    /// [`IVUpdateState::finish`].
+    pub fn finish_iv_update(&mut self, now: Timestamp) -> Result<(), IVUpdateError> {
+        self.iv_update.finish(&mut self.device_state, now)?;
+        self.queue_friend_updates();
+        Ok(())
+    }
+    /// The IV Update timers. The IV Index and IV Update flag are in `DeviceState`.
+    pub fn iv_update(&self) -> &IVUpdateState {
+        &self.iv_update
+    }
+    /// The IV Index outgoing PDUs are encrypted with. It's the previous IV Index while an IV
+    /// Update is in progress.
+    pub fn tx_iv_index(&self) -> IVIndex {
+        self.device_state.tx_iv_index()
+    }
     /// Returns a mutable reference to `device_state::DeviceState`. If you take a mutable reference,
     /// you essential lock out the rest of the stack from using `device_state::DeviceState` to
-    /// encrypt and decrypt messages.
+    /// encrypt and decrypt messages. Call [`StackInternals::refresh_nid_index`] after changing
+    /// the NetKeys.
     pub fn device_state_mut(&mut self) -> &mut DeviceState {
+        self.nid_index.mark_stale();
         &mut self.device_state
     }
     /// Returns an immutable reference to `device_state::DeviceState`.
     pub fn device_state(&self) -> &DeviceState {
         &self.device_state
     }
+    /// Returns the Config Server applying Config messages to this node's `DeviceState`. It
+    /// answers Low Power Node PollTimeout Gets from `StackInternals::friendships` and gets and
+    /// sets the Heartbeat states.
+    pub fn config_server(&mut self) -> ConfigServer<'_> {
+        // Config messages can add or remove NetKeys.
+        self.nid_index.mark_stale();
+        ConfigServer::new(&mut self.device_state)
+            .with_friendships(&self.friendships)
+            .with_heartbeat(
+                &mut self.heartbeat_publication,
+                &mut self.heartbeat_subscription,
+            )
+    }
+    pub fn heartbeat_publication(&self) -> &HeartbeatPublication {
+        &self.heartbeat_publication
+    }
+    pub fn heartbeat_publication_mut(&mut self) -> &mut HeartbeatPublication {
+        &mut self.heartbeat_publication
+    }
+    pub fn heartbeat_subscription(&self) -> &HeartbeatSubscription {
+        &self.heartbeat_subscription
+    }
+    pub fn heartbeat_subscription_mut(&mut self) -> &mut HeartbeatSubscription {
+        &mut self.heartbeat_subscription
+    }
+    /// Polls the Heartbeat publication at `now` (see [`HeartbeatPublication::poll`]) and
+    /// returns the encrypted Heartbeat to send from the primary element if one is due.
+    pub fn heartbeat_pdu(
+        &mut self,
+        now: Timestamp,
+    ) -> Result<Option<OutgoingEncryptedNetworkPDU>, SendError> {
+        let features = self.device_state.enabled_features();
+        let heartbeat = match self.heartbeat_publication.poll(now, features) {
+            Some(heartbeat) => heartbeat,
+            None => return Ok(None),
+        };
+        self.control_pdu(
+            &heartbeat,
+            self.heartbeat_publication.destination,
+            self.heartbeat_publication.ttl,
+            self.heartbeat_publication.net_key_index,
+        )
+        .map(Some)
+    }
+    /// Encrypts the unsegmented Transport Control message `msg` from the primary element to
+    /// `dst` with the credentials [`StackInternals::security_credentials`] picks.
+    /// # Panics
+    /// Panics if `msg` doesn't fit in an unsegmented Control PDU.
+    pub fn control_pdu<M: ControlMessage>(
+        &self,
+        msg: &M,
+        dst: Address,
+        ttl: TTL,
+        net_key_index: NetKeyIndex,
+    ) -> Result<OutgoingEncryptedNetworkPDU, SendError> {
+        let msg = OutgoingLowerTransportMessage {
+            pdu: lower::PDU::UnsegmentedControl(
+                msg.try_to_unseg().expect("correctly formatted PDU"),
+            ),
+            src: self
+                .device_state
+                .element_address(ElementIndex(0))
+                .expect("primary element always exists"),
+            dst,
+            ttl: Some(ttl),
+            seq: None,
+            iv_index: self.tx_iv_index(),
+            net_key_index,
+        };
+        self.encrypt_lower_transport(&msg)
+    }
+    fn primary_element_address(&self) -> UnicastAddress {
+        self.device_state
+            .element_address(ElementIndex(0))
+            .expect("primary element always exists")
+    }
+    /// Answers a Friend Request from `lpn_address` (received with `rssi`) if this node is a
+    /// Friend. Any old friendship with `lpn_address` ends and the new one (with its credentials)
+    /// starts as of `now`, but it's dropped by [`StackInternals::expire_friendships`] unless the
+    /// Low Power node polls within `friend::friend::OFFER_TIMEOUT`.
+    ///
+    /// Returns the encrypted Friend Offer and how long to wait before sending it (see
+    /// [`friend::friend::offer_delay`]) or `None` if there's no offer to make.
+    pub fn friend_offer(
+        &mut self,
+        lpn_address: UnicastAddress,
+        request: &FriendRequest,
+        rssi: Option<i8>,
+        net_key_index: NetKeyIndex,
+        now: Timestamp,
+    ) -> Result<Option<(Duration, OutgoingEncryptedNetworkPDU)>, SendError> {
+        if !self.device_state.friend_state().is_enabled() {
+            return Ok(None);
+        }
+        // The `FriendNode` only exists while the Friend feature is enabled (see
+        // `StackInternals::sync_friend_node`).
+        let offer = match self
+            .friend_node
+            .as_mut()
+            .and_then(|friend_node| friend_node.handle_request(lpn_address, request, rssi, now))
+        {
+            Some(offer) => offer,
+            None => return Ok(None),
+        };
+        // The Friend Offer still goes out with the managed flooding credentials.
+        self.friendships.remove(lpn_address);
+        let pdu = self.control_pdu(
+            &control::FriendOffer(offer),
+            Address::Unicast(lpn_address),
+            TTL::new(0),
+            net_key_index,
+        )?;
+        let credentials = FriendshipCredentials {
+            lpn_address,
+            friend_address: self.primary_element_address(),
+            lpn_counter: request.lpn_counter,
+            friend_counter: offer.friend_counter,
+        };
+        self.friendships
+            .establish(credentials, request.poll_timeout, now);
+        self.rebuild_nid_index();
+        Ok(Some((
+            friend::friend::offer_delay(&request.criteria, &offer),
+            pdu,
+        )))
+    }
+    /// Answers a Friend Poll from `lpn_address` with the next entry of its Friend Queue (see
+    /// [`friend::friend::FriendQueue::poll`]) and restarts its PollTimeout timer as of `now`.
+    /// Friend Updates carry the MD flag. Queued Network PDUs are re-encrypted with the
+    /// friendship credentials.
+    ///
+    /// Returns the PDU and the Low Power node's ReceiveDelay (to wait before sending it) or
+    /// `None` if `lpn_address` isn't friends with this node.
+    pub fn friend_poll(
+        &mut self,
+        lpn_address: UnicastAddress,
+        poll: &FriendPoll,
+        net_key_index: NetKeyIndex,
+        now: Timestamp,
+    ) -> Result<Option<(ReceiveDelay, OutgoingEncryptedNetworkPDU)>, SendError> {
+        let friend_node = match self.friend_node.as_mut() {
+            Some(friend_node) => friend_node,
+            None => return Ok(None),
+        };
+        if !self.friendships.poll(lpn_address, now) {
+            return Ok(None);
+        }
+        let (entry, more) = match friend_node.handle_poll(lpn_address, poll.fsn()) {
+            Some(polled) => polled,
+            None => return Ok(None),
+        };
+        let receive_delay = friend_node
+            .lpn(lpn_address)
+            .expect("lpn was just polled")
+            .receive_delay;
+        let pdu = match entry {
+            QueueEntry::Update => self.control_pdu(
+                &control::FriendUpdate(self.friend_update(net_key_index, more)),
+                Address::Unicast(lpn_address),
+                TTL::new(0),
+                net_key_index,
+            )?,
+            QueueEntry::PDU {
+                pdu,
+                net_key_index,
+                iv_index,
+            } => self.friendship_pdu(lpn_address, pdu, net_key_index, iv_index)?,
+        };
+        Ok(Some((receive_delay, pdu)))
+    }
+    /// Adds (`add`) or removes the addresses in `list` to the Friend Subscription List of
+    /// `lpn_address` and restarts its PollTimeout timer as of `now`. Returns the encrypted Friend
+    /// Subscription List Confirm and the Low Power node's ReceiveDelay (like
+    /// [`StackInternals::friend_poll`]).
+    pub fn friend_subscription_list(
+        &mut self,
+        lpn_address: UnicastAddress,
+        list: &FriendSubscriptionList,
+        add: bool,
+        net_key_index: NetKeyIndex,
+        now: Timestamp,
+    ) -> Result<Option<(ReceiveDelay, OutgoingEncryptedNetworkPDU)>, SendError> {
+        let friend_node = match self.friend_node.as_mut() {
+            Some(friend_node) => friend_node,
+            None => return Ok(None),
+        };
+        if !self.friendships.poll(lpn_address, now) {
+            return Ok(None);
+        }
+        let confirm = match friend_node.handle_subscription_list(lpn_address, list, add) {
+            Some(confirm) => confirm,
+            None => return Ok(None),
+        };
+        let receive_delay = friend_node
+            .lpn(lpn_address)
+            .expect("lpn subscription list was just updated")
+            .receive_delay;
+        let pdu = self.control_pdu(
+            &control::FriendSubscriptionListConfirm(confirm),
+            Address::Unicast(lpn_address),
+            TTL::new(0),
+            net_key_index,
+        )?;
+        Ok(Some((receive_delay, pdu)))
+    }
+    /// Stores `pdu` in the Friend Queue of every Low Power node it's for (see
+    /// [`FriendNode::queue_pdu`]). Returns the Segment Acknowledgement (with `obo` set) to send
+    /// from the primary element once every segment of a segmented message for a Low Power node
+    /// is in.
+    pub fn friend_queue_pdu(
+        &mut self,
+        pdu: &net::PDU,
+        net_key_index: NetKeyIndex,
+        iv_index: IVIndex,
+    ) -> Option<OutgoingLowerTransportMessage> {
+        let ack = self
+            .friend_node
+            .as_mut()?
+            .queue_pdu(pdu, net_key_index, iv_index)?;
+        Some(OutgoingLowerTransportMessage {
+            pdu: lower::PDU::UnsegmentedControl(
+                ack.try_to_unseg().expect("correctly formatted PDU"),
+            ),
+            src: self.primary_element_address(),
+            dst: Address::Unicast(pdu.header.src),
+            ttl: if u8::from(pdu.header.ttl) == 0_u8 {
+                Some(TTL::new(0))
+            } else {
+                None
+            },
+            seq: None,
+            iv_index,
+            net_key_index,
+        })
+    }
+    /// Ends the friendships whose PollTimeout expired as of `now` and drops the Friend Offers the
+    /// Low Power node didn't poll in time. Returns the Low Power nodes' addresses.
+    pub fn expire_friendships(&mut self, now: Timestamp) -> Vec<UnicastAddress> {
+        let friend_node = match self.friend_node.as_mut() {
+            Some(friend_node) => friend_node,
+            None => return Vec::new(),
+        };
+        let mut expired = friend_node.expire_offers(now);
+        expired.extend(
+            self.friendships
+                .iter()
+                .filter(|friendship| friendship.remaining_poll_timeout(now) == PollTimeout::ZERO)
+                .map(|friendship| friendship.lpn_address()),
+        );
+        expired.sort();
+        expired.dedup();
+        for &lpn_address in &expired {
+            friend_node.remove(lpn_address);
+            self.friendships.remove(lpn_address);
+        }
+        if !expired.is_empty() {
+            self.rebuild_nid_index();
+        }
+        expired
+    }
+    /// The security state sent in Friend Updates.
+    fn friend_update(&self, net_key_index: NetKeyIndex, more: bool) -> friend::FriendUpdate {
+        let key_refresh = self
+            .net_keys()
+            .get_keys(net_key_index)
+            .map_or(false, |keys| keys.phase() == KeyRefreshPhases::Second);
+        friend::FriendUpdate {
+            key_refresh_flag: KeyRefreshFlag(key_refresh),
+            iv_update_flag: self.device_state.iv_update_flag(),
+            iv_index: self.device_state.iv_index(),
+            md: MD::new(more),
+        }
+    }
+    /// Queues a Friend Update for every Low Power node after the IV Index, IV Update flag or a
+    /// Key Refresh Phase changed.
+    fn queue_friend_updates(&mut self) {
+        if let Some(friend_node) = self.friend_node.as_mut() {
+            friend_node.queue_update();
+        }
+    }
+    /// Re-encrypts `pdu` from a Friend Queue for `lpn_address` with their friendship
+    /// credentials.
+    fn friendship_pdu(
+        &self,
+        lpn_address: UnicastAddress,
+        mut pdu: net::PDU,
+        net_key_index: NetKeyIndex,
+        iv_index: IVIndex,
+    ) -> Result<OutgoingEncryptedNetworkPDU, SendError> {
+        if !self.is_valid_iv_index(iv_index) {
+            return Err(SendError::InvalidIVIndex);
+        }
+        let network_keys = self.credentials_network_keys(
+            net_key_index,
+            SecurityCredentials::Friendship(lpn_address),
+        )?;
+        pdu.header.nid = network_keys.nid();
+        Ok(OutgoingEncryptedNetworkPDU {
+            transmit_parameters: self.device_state.network_transmit().0,
+            pdu: pdu
+                .encrypt(&network_keys, iv_index)
+                .map_err(|_| SendError::NetEncryptError)?,
+            dst: pdu.header.dst,
+            relayed: false,
+        })
+    }
     /// Tries to find the matching `NetworkSecurityMaterials` from the device state manager. Once
     /// it finds a `NetworkSecurityMaterials` with a matching `NID`, it tries to decrypt the PDU.
     /// If the MIC is authenticated (the materials match), it'll return the decrypted PDU.
-    /// If no security materials match, it'll return `None`
+    /// PDUs from a friended Low Power node (or this Low Power node's Friend) are also tried with
+    /// the friendship credentials. Only the keys the [`NIDIndex`] lists under the PDU's `NID`
+    /// are tried unless the index is stale (see [`StackInternals::refresh_nid_index`]).
+    /// The PDU's IVI picks either the current or the previous IV Index so PDUs from nodes that
+    /// haven't finished an IV Update yet are still received.
+    ///
+    /// Returns `RecvError::MalformedNetworkPDU` (before trying any keys) if `pdu` is shorter or
+    /// longer than any Network PDU can be and `RecvError::NoMatchingNetKey` if no security
+    /// materials match.
     pub fn decrypt_network_pdu(
         &self,
         pdu: net::EncryptedPDU,
-    ) -> Option<(NetKeyIndex, IVIndex, net::PDU)> {
-        let iv_index = self.device_state.rx_iv_index(pdu.ivi())?;
+    ) -> Result<(NetKeyIndex, IVIndex, net::PDU), RecvError> {
+        if !pdu.is_valid_len() {
+            return Err(RecvError::MalformedNetworkPDU);
+        }
+        let iv_index = self
+            .device_state
+            .rx_iv_index(pdu.ivi())
+            .ok_or(RecvError::NoMatchingNetKey)?;
+        if self.is_nid_index_current() {
+            // Only the keys with the PDU's NID can decrypt it.
+            for (index, keys) in self.nid_index.candidates(pdu.nid()) {
+                match pdu.try_decrypt(keys, iv_index) {
+                    Ok(decrypted_pdu) => return Ok((*index, iv_index, decrypted_pdu)),
+                    // It authenticated so no other key would decrypt it.
+                    Err(net::NetworkDataError::UnknownControlOpcode(unknown)) => {
+                        return Err(RecvError::UnknownControlOpcode(*index, iv_index, unknown))
+                    }
+                    Err(_) => (),
+                }
+            }
+            return Err(RecvError::NoMatchingNetKey);
+        }
+        // The index might be out of date. Derive and try every key.
         for (index, sm) in self.net_keys().matching_nid(pdu.nid()) {
-            if let Ok(decrypted_pdu) = pdu.try_decrypt(sm.network_keys(), iv_index) {
-                return Some((index, iv_index, decrypted_pdu));
+            match pdu.try_decrypt(sm.network_keys(), iv_index) {
+                Ok(decrypted_pdu) => return Ok((index, iv_index, decrypted_pdu)),
+                // It authenticated so no other key would decrypt it.
+                Err(net::NetworkDataError::UnknownControlOpcode(unknown)) => {
+                    return Err(RecvError::UnknownControlOpcode(index, iv_index, unknown))
+                }
+                Err(_) => (),
             }
         }
-
-        None
+        let friendships = self
+            .friendships
+            .iter()
+            .map(|friendship| friendship.credentials)
+            .chain(
+                self.low_power_node
+                    .friendship()
+                    .map(|friendship| friendship.credentials),
+            );
+        for credentials in friendships {
+            for (&index, phase) in self.net_keys().map.iter() {
+                let (current, next) = phase.rx_keys();
+                for sm in core::iter::once(current).chain(next) {
+                    let keys = credentials.network_keys(sm.net_key());
+                    if keys.nid() != pdu.nid() {
+                        continue;
+                    }
+                    match pdu.try_decrypt(&keys, iv_index) {
+                        Ok(decrypted_pdu) => return Ok((index, iv_index, decrypted_pdu)),
+                        Err(net::NetworkDataError::UnknownControlOpcode(unknown)) => {
+                            return Err(RecvError::UnknownControlOpcode(index, iv_index, unknown))
+                        }
+                        Err(_) => (),
+                    }
+                }
+            }
+        }
+        Err(RecvError::NoMatchingNetKey)
     }
     /// Returns if the given `IVIndex` is a valid `IVIndex` (Based on IVI).
     fn is_valid_iv_index(&self, iv_index: IVIndex) -> bool {
@@ -394,10 +1343,15 @@ impl StackInternals {
             net_keys: net_sm.network_keys(),
         })
     }
+    /// Builds the Network PDU for `msg` and returns it with the `NetworkKeys` it has to be
+    /// encrypted with (see [`StackInternals::tx_network_keys`]).
     pub fn lower_to_net(
         &self,
         msg: &OutgoingLowerTransportMessage,
-    ) -> Result<(net::PDU, &NetworkSecurityMaterials), SendError> {
+    ) -> Result<(net::PDU, NetworkKeys), SendError> {
+        if msg.dst.is_unassigned() {
+            return Err(SendError::InvalidAddress);
+        }
         if !self.is_valid_iv_index(msg.iv_index) {
             return Err(SendError::InvalidIVIndex);
         }
@@ -405,49 +1359,60 @@ impl StackInternals {
             .device_state
             .element_index(msg.src)
             .ok_or(SendError::InvalidSourceElement)?;
-        let net_sm = self
-            .net_keys()
-            .get_keys(msg.net_key_index)
-            .ok_or(SendError::InvalidNetKeyIndex)?
-            .tx_key();
+        let network_keys = self.tx_network_keys(msg.net_key_index, &msg.dst)?;
         let seq = match msg.seq {
             Some(seq) => seq,
-            None => self
-                .device_state()
-                .seq_counter(index)
-                .inc_seq(1)
-                .ok_or(SendError::OutOfSeq)?
-                .start(),
+            None => self.next_seqs(index, 1)?.start(),
         };
         Ok((
             msg.net_pdu(
-                net_sm.network_keys().nid(),
+                network_keys.nid(),
                 seq,
                 msg.ttl.unwrap_or_else(|| self.device_state.default_ttl()),
             ),
-            net_sm,
+            network_keys,
         ))
     }
-    /// Encrypt a single [`net::PDU`]. Use `Self::encrypted_network_pdus` instead if you have
-    /// more than one Network PDU.
+    /// Encrypts a single [`net::PDU`] (the counterpart of [`StackInternals::decrypt_network_pdu`])
+    /// with the `NetworkKeys` for its destination (see [`StackInternals::tx_network_keys`]).
+    /// The payload is encrypted with the Network Nonce and the header is obfuscated so it's
+    /// ready for the bearers. It's sent with the node's `NetworkTransmit` parameters.
     pub fn encrypt_network_pdu(
         &self,
         pdu: net::PDU,
         net_key_index: NetKeyIndex,
         iv_index: IVIndex,
-    ) -> Result<OwnedEncryptedPDU, SendError> {
+    ) -> Result<OutgoingEncryptedNetworkPDU, SendError> {
         if !self.is_valid_iv_index(iv_index) {
             return Err(SendError::InvalidIVIndex);
         }
-        pdu.encrypt(
-            self.net_keys()
-                .get_keys(net_key_index)
-                .ok_or(SendError::InvalidNetKeyIndex)?
-                .tx_key()
-                .network_keys(),
-            iv_index,
-        )
-        .map_err(|_| SendError::NetEncryptError)
+        let network_keys = self.tx_network_keys(net_key_index, &pdu.header.dst)?;
+        self.encrypt_with_keys(pdu, &network_keys, iv_index)
+    }
+    /// Builds and encrypts the Network PDU for `msg` (see [`StackInternals::lower_to_net`]).
+    /// The `NetworkKeys` are only looked up once so sending every segment of a message to a
+    /// friend doesn't cost more than sending it to anyone else.
+    pub fn encrypt_lower_transport(
+        &self,
+        msg: &OutgoingLowerTransportMessage,
+    ) -> Result<OutgoingEncryptedNetworkPDU, SendError> {
+        let (pdu, network_keys) = self.lower_to_net(msg)?;
+        self.encrypt_with_keys(pdu, &network_keys, msg.iv_index)
+    }
+    fn encrypt_with_keys(
+        &self,
+        pdu: net::PDU,
+        network_keys: &NetworkKeys,
+        iv_index: IVIndex,
+    ) -> Result<OutgoingEncryptedNetworkPDU, SendError> {
+        Ok(OutgoingEncryptedNetworkPDU {
+            transmit_parameters: self.device_state.network_transmit().0,
+            pdu: pdu
+                .encrypt(network_keys, iv_index)
+                .map_err(|_| SendError::NetEncryptError)?,
+            dst: pdu.header.dst,
+            relayed: false,
+        })
     }
 }
 
@@ -466,3 +1431,1037 @@ pub trait Stack: Sized {
         payload: AppPayload<Storage>,
     ) -> Result<(), SendError>;
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::key::{BeaconKey, DevKey};
+    use crate::device_state::{ModelInfo, NodeInfo};
+    use crate::foundation::state::DefaultTTLState;
+    use crate::lower;
+    use crate::mesh::{KeyIndex, ModelID, SequenceNumber, U24};
+    use crate::random::Randomizable;
+    use crate::uuid::UUID;
+
+    fn internals() -> StackInternals {
+        StackInternals::new(DeviceState::new(UnicastAddress::new(1), ElementCount(1)))
+    }
+    #[test]
+    fn test_app_encrypt_rejects_unassigned() {
+        let internals = internals();
+        let msg = OutgoingMessage {
+            app_payload: AppPayload([0_u8; 4]),
+            mic_size: MicSize::Small,
+            force_segment: false,
+            encryption_key: MessageKeys::App(AppKeyIndex(KeyIndex::new(0))),
+            iv_index: IVIndex(0),
+            source_element_index: ElementIndex(0),
+            dst: Address::Unassigned,
+            ttl: None,
+        };
+        match internals.app_encrypt(msg) {
+            Err((e, _)) => assert_eq!(e, SendError::InvalidAddress),
+            Ok(_) => panic!("encrypted a message to the unassigned address"),
+        }
+    }
+    #[test]
+    fn test_lower_to_net_rejects_unassigned() {
+        let internals = internals();
+        let msg = OutgoingLowerTransportMessage {
+            pdu: lower::PDU::UnsegmentedAccess(lower::UnsegmentedAccessPDU::new(None, &[0_u8; 5])),
+            src: UnicastAddress::new(1),
+            dst: Address::Unassigned,
+            ttl: None,
+            seq: Some(SequenceNumber(U24::new(1))),
+            iv_index: IVIndex(0),
+            net_key_index: NetKeyIndex(KeyIndex::new(0)),
+        };
+        assert_eq!(
+            internals.lower_to_net(&msg).err(),
+            Some(SendError::InvalidAddress)
+        );
+    }
+    #[test]
+    fn test_lower_to_net_default_ttl() {
+        let mut internals = keyed_internals(&NetKey::random_secure(), AppKey::random_secure());
+        let msg = |ttl| OutgoingLowerTransportMessage {
+            pdu: lower::PDU::UnsegmentedAccess(lower::UnsegmentedAccessPDU::new(None, &[0_u8; 5])),
+            src: UnicastAddress::new(1),
+            dst: Address::from(0x0005),
+            ttl,
+            seq: Some(SequenceNumber(U24::new(1))),
+            iv_index: IVIndex(0),
+            net_key_index: NetKeyIndex(KeyIndex::new(0)),
+        };
+        let ttl = |internals: &StackInternals, ttl| {
+            internals
+                .lower_to_net(&msg(ttl))
+                .expect("valid message")
+                .0
+                .header
+                .ttl
+        };
+        internals
+            .device_state_mut()
+            .set_default_ttl(DefaultTTLState::new(0x0A));
+        assert_eq!(ttl(&internals, None), TTL::new(0x0A));
+        // A TTL of 0 (don't relay) is kept instead of being replaced by the Default TTL.
+        assert_eq!(ttl(&internals, Some(TTL::new(0))), TTL::new(0));
+        assert_eq!(ttl(&internals, Some(TTL::new(3))), TTL::new(3));
+    }
+    fn unsegmented_to(dst: u16, iv_index: IVIndex) -> OutgoingLowerTransportMessage {
+        OutgoingLowerTransportMessage {
+            pdu: lower::PDU::UnsegmentedAccess(lower::UnsegmentedAccessPDU::new(None, &[0_u8; 5])),
+            src: UnicastAddress::new(1),
+            dst: Address::from(dst),
+            ttl: None,
+            seq: None,
+            iv_index,
+            net_key_index: NetKeyIndex(KeyIndex::new(0)),
+        }
+    }
+    #[test]
+    fn test_seq_store() {
+        use crate::device_state::SEQ_BLOCK_LEN;
+        use alloc::sync::Arc;
+        use core::sync::atomic::{AtomicU32, Ordering};
+
+        /// Remembers the last saved watermark (plus one so 0 means nothing saved).
+        struct Watermark(Arc<AtomicU32>);
+        impl SeqStore for Watermark {
+            fn save(&self, device_state: &DeviceState) -> Result<(), SeqStoreError> {
+                let reserved = device_state.seq_counter(ElementIndex(0)).reserved();
+                self.0.store(reserved.0.value() + 1, Ordering::SeqCst);
+                Ok(())
+            }
+        }
+        struct Failing;
+        impl SeqStore for Failing {
+            fn save(&self, _device_state: &DeviceState) -> Result<(), SeqStoreError> {
+                Err(SeqStoreError)
+            }
+        }
+        let mut internals = keyed_internals(&NetKey::random_secure(), AppKey::random_secure());
+        let saved = Arc::new(AtomicU32::new(0));
+        internals.set_seq_store(Some(Box::new(Watermark(saved.clone()))));
+        let seq = |internals: &StackInternals| {
+            internals
+                .lower_to_net(&unsegmented_to(0x0005, IVIndex(0)))
+                .map(|(pdu, _)| pdu.header.seq.0.value())
+        };
+        // The block is saved before the first Sequence Number in it is used.
+        assert_eq!(seq(&internals), Ok(0));
+        assert_eq!(saved.load(Ordering::SeqCst), SEQ_BLOCK_LEN + 1);
+        for i in 1..SEQ_BLOCK_LEN {
+            assert_eq!(seq(&internals), Ok(i));
+        }
+        assert_eq!(saved.load(Ordering::SeqCst), SEQ_BLOCK_LEN + 1);
+        assert_eq!(seq(&internals), Ok(SEQ_BLOCK_LEN));
+        assert_eq!(saved.load(Ordering::SeqCst), 2 * SEQ_BLOCK_LEN + 1);
+        // Sequence Numbers from a block that couldn't be saved aren't used.
+        internals.set_seq_store(Some(Box::new(Failing)));
+        for _ in SEQ_BLOCK_LEN + 1..2 * SEQ_BLOCK_LEN {
+            assert!(seq(&internals).is_ok());
+        }
+        assert_eq!(seq(&internals), Err(SendError::SeqNotSaved));
+    }
+    #[test]
+    fn test_seq_exhausted_iv_update() {
+        use crate::stack::iv_update::MIN_IV_UPDATE_PHASE;
+        use crate::timestamp::TimestampTrait;
+
+        let mut internals = keyed_internals(&NetKey::random_secure(), AppKey::random_secure());
+        let now = Timestamp::now();
+        assert_eq!(internals.handle_seq_exhausted(now), Ok(false));
+        internals
+            .device_state_mut()
+            .seq_counter_mut(ElementIndex(0))
+            .set_seq(SequenceNumber(U24::new(U24::max_value().value() - 1)));
+        assert!(internals
+            .lower_to_net(&unsegmented_to(0x0005, IVIndex(0)))
+            .is_ok());
+        assert_eq!(
+            internals
+                .lower_to_net(&unsegmented_to(0x0005, IVIndex(0)))
+                .err(),
+            Some(SendError::OutOfSeq)
+        );
+        // Running out starts an IV Update but nothing can be sent until it's over.
+        assert_eq!(internals.handle_seq_exhausted(now), Ok(true));
+        assert_eq!(
+            internals.device_state().iv_update_flag(),
+            IVUpdateFlag(true)
+        );
+        assert_eq!(internals.tx_iv_index(), IVIndex(0));
+        assert_eq!(
+            internals
+                .lower_to_net(&unsegmented_to(0x0005, IVIndex(0)))
+                .err(),
+            Some(SendError::OutOfSeq)
+        );
+        assert_eq!(
+            internals.handle_seq_exhausted(now),
+            Err(IVUpdateError::PhaseTooShort)
+        );
+        // Finishing it moves to the new IV Index which resets the Sequence Numbers.
+        assert_eq!(
+            internals.handle_seq_exhausted(now + MIN_IV_UPDATE_PHASE),
+            Ok(true)
+        );
+        assert_eq!(internals.tx_iv_index(), IVIndex(1));
+        assert_eq!(
+            internals
+                .lower_to_net(&unsegmented_to(0x0005, IVIndex(1)))
+                .map(|(pdu, _)| pdu.header.seq.0.value()),
+            Ok(0)
+        );
+        assert_eq!(
+            internals.handle_seq_exhausted(now + MIN_IV_UPDATE_PHASE),
+            Ok(false)
+        );
+    }
+    fn keyed_internals(net_key: &NetKey, app_key: AppKey) -> StackInternals {
+        let mut internals = internals();
+        internals
+            .device_state_mut()
+            .security_materials_mut()
+            .net_key_map
+            .insert(NetKeyIndex(KeyIndex::new(0)), net_key);
+        internals
+            .add_app_key(
+                NetKeyIndex(KeyIndex::new(0)),
+                AppKeyIndex(KeyIndex::new(1)),
+                app_key,
+            )
+            .expect("net key was just added");
+        internals
+    }
+    fn encrypted_message(internals: &StackInternals) -> EncryptedIncomingMessage<[u8; 8]> {
+        encrypted_message_to(internals, Address::from(0x0001))
+    }
+    fn encrypted_message_to(
+        internals: &StackInternals,
+        dst: Address,
+    ) -> EncryptedIncomingMessage<[u8; 8]> {
+        let msg = OutgoingMessage {
+            app_payload: AppPayload([0xAB_u8; 8]),
+            mic_size: MicSize::Small,
+            force_segment: false,
+            encryption_key: MessageKeys::App(AppKeyIndex(KeyIndex::new(1))),
+            iv_index: IVIndex(0),
+            source_element_index: ElementIndex(0),
+            dst,
+            ttl: None,
+        };
+        match internals.app_encrypt(msg) {
+            Ok(upper) => incoming(upper),
+            Err((e, _)) => panic!("app_encrypt failed: {:?}", e),
+        }
+    }
+    /// What the receiver of `upper` gets after the lower transport layer.
+    fn incoming<Storage: AsRef<[u8]>>(
+        upper: OutgoingUpperTransportMessage<Storage>,
+    ) -> EncryptedIncomingMessage<Storage> {
+        match upper.upper_pdu {
+            upper::PDU::Access(encrypted_app_payload) => EncryptedIncomingMessage {
+                encrypted_app_payload,
+                seq: upper.seq.start(),
+                seg_count: 0,
+                iv_index: upper.iv_index,
+                net_key_index: upper.net_key_index,
+                // Only the hash of a virtual address is sent over the air.
+                dst: match upper.dst {
+                    Address::Virtual(v) => Address::VirtualHash(v.hash()),
+                    dst => dst,
+                },
+                src: upper.src,
+                ttl: None,
+                rssi: None,
+            },
+            upper::PDU::Control(_) => panic!("expected an access PDU"),
+        }
+    }
+    #[test]
+    fn test_remote_dev_key() {
+        let net_key = NetKey::random_secure();
+        let dev_key = DevKey::random_secure();
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        let mut provisioner = keyed_internals(&net_key, AppKey::random_secure());
+        provisioner.device_state_mut().nodes_mut().insert(
+            UnicastAddress::new(0x0005),
+            NodeInfo {
+                uuid: UUID([0x05; 16]),
+                element_count: ElementCount(2),
+                net_key_index,
+                dev_key,
+            },
+        );
+        let mut node = StackInternals::new(DeviceState::new(
+            UnicastAddress::new(0x0005),
+            ElementCount(2),
+        ));
+        node.device_state_mut()
+            .security_materials_mut()
+            .net_key_map
+            .insert(net_key_index, &net_key);
+        node.device_state_mut().security_materials_mut().dev_key = dev_key;
+        let msg = |dst: u16| OutgoingMessage {
+            app_payload: AppPayload([0x80_u8, 0x08, 0x00]),
+            mic_size: MicSize::Small,
+            force_segment: false,
+            encryption_key: MessageKeys::Device(net_key_index),
+            iv_index: IVIndex(0),
+            source_element_index: ElementIndex(0),
+            dst: Address::from(dst),
+            ttl: None,
+        };
+        let encrypt = |internals: &StackInternals, dst| match internals.app_encrypt(msg(dst)) {
+            Ok(upper) => incoming(upper),
+            Err((e, _)) => panic!("app_encrypt failed: {:?}", e),
+        };
+        // The provisioner secures requests with the node's DevKey...
+        let request = node
+            .app_decrypt(encrypt(&provisioner, 0x0005))
+            .expect("secured with the node's DevKey");
+        assert_eq!(request.app_key_index, None);
+        assert_eq!(&request.payload[..], &[0x80_u8, 0x08, 0x00][..]);
+        // ...and opens its statuses with it.
+        let status = provisioner
+            .app_decrypt(encrypt(&node, 0x0001))
+            .expect("opened with the node's DevKey");
+        assert_eq!(status.src, UnicastAddress::new(0x0005));
+        // Device messages only go to the primary element.
+        assert!(node.app_decrypt(encrypt(&provisioner, 0x0006)).is_err());
+    }
+    #[test]
+    fn test_virtual_address_collision() {
+        let net_key = NetKey::random_secure();
+        let app_key = AppKey::random_secure();
+        // Two labels with the same 14-bit hash (0xB95F).
+        let label = |i: u128| VirtualAddress::new(&UUID(i.to_be_bytes()));
+        let (first, second) = (label(128), label(272));
+        assert_eq!(first.hash(), second.hash());
+        let sender = keyed_internals(&net_key, app_key);
+        let mut internals = keyed_internals(&net_key, app_key);
+        // Not subscribed to either label yet.
+        assert!(internals
+            .app_decrypt(encrypted_message_to(&sender, Address::Virtual(first)))
+            .is_err());
+
+        let mut subscriber = ModelInfo::default();
+        assert!(subscriber.add_subscription(Address::Virtual(first)));
+        assert!(subscriber.add_subscription(Address::Virtual(second)));
+        let models = internals.device_state_mut().models_mut();
+        models.insert(
+            ModelIdentifier::new_sig(ModelID(0x1000)),
+            subscriber.clone(),
+        );
+        models.insert(ModelIdentifier::new_sig(ModelID(0x1001)), subscriber);
+        assert_eq!(
+            internals.matching_virtual_addresses(first.hash()).count(),
+            2
+        );
+        // Only the right label authenticates so each message ends up with its own label.
+        for &label in &[first, second] {
+            let decrypted = internals
+                .app_decrypt(encrypted_message_to(&sender, Address::Virtual(label)))
+                .ok()
+                .expect("decrypts with a subscribed label");
+            assert_eq!(decrypted.dst, Address::Virtual(label));
+            assert_eq!(decrypted.payload, [0xAB_u8; 8]);
+        }
+    }
+    #[test]
+    fn test_matches() {
+        use crate::foundation::state::RelayState;
+
+        let mut internals = StackInternals::new(DeviceState::new(
+            UnicastAddress::new(0x0010),
+            ElementCount(2),
+        ));
+        let group = Address::Group(GroupAddress::new(0xC001));
+        assert_eq!(internals.matches(&Address::Unassigned), None);
+        assert_eq!(
+            internals.matches(&Address::from(0x0010)),
+            Some(ElementIndex(0))
+        );
+        assert_eq!(
+            internals.matches(&Address::from(0x0011)),
+            Some(ElementIndex(1))
+        );
+        assert_eq!(internals.matches(&Address::from(0x0012)), None);
+        assert_eq!(
+            internals.matches(&Address::Group(GroupAddress::all_nodes())),
+            Some(ElementIndex(0))
+        );
+        // All-relays only while relaying.
+        let all_relays = Address::Group(GroupAddress::all_relays());
+        internals.device_state_mut().config_states_mut().relay_state = RelayState::Disabled;
+        assert_eq!(internals.matches(&all_relays), None);
+        internals.device_state_mut().config_states_mut().relay_state = RelayState::Enabled;
+        assert_eq!(internals.matches(&all_relays), Some(ElementIndex(0)));
+
+        assert_eq!(internals.matches(&group), None);
+        assert_eq!(internals.loopback(&group), Loopback::Transmit);
+        assert!(internals.subscriptions_mut().add(ElementIndex(1), group));
+        assert_eq!(internals.matches(&group), Some(ElementIndex(1)));
+        assert_eq!(internals.loopback(&group), Loopback::LocalAndTransmit);
+        assert!(internals
+            .subscriptions_mut()
+            .remove(ElementIndex(1), &group));
+        assert_eq!(internals.matches(&group), None);
+    }
+    #[test]
+    fn test_app_key_update_phase1() {
+        let net_key = NetKey::random_secure();
+        let old_key = AppKey::random_secure();
+        let new_key = AppKey::random_secure();
+        let old_internals = keyed_internals(&net_key, old_key);
+        let new_internals = keyed_internals(&net_key, new_key);
+
+        let mut internals = keyed_internals(&net_key, old_key);
+        internals
+            .update_app_key(
+                NetKeyIndex(KeyIndex::new(0)),
+                AppKeyIndex(KeyIndex::new(1)),
+                new_key,
+            )
+            .expect("app key exists");
+        // Phase 1 still transmits with the old key...
+        assert_eq!(
+            internals
+                .get_app_key(AppKeyIndex(KeyIndex::new(1)))
+                .map(|sm| sm.app_key),
+            Some(old_key)
+        );
+        // ...but receives with both.
+        for sender in &[old_internals, new_internals] {
+            let decrypted = internals
+                .app_decrypt(encrypted_message(sender))
+                .ok()
+                .expect("decrypts with the old or new key");
+            assert_eq!(decrypted.payload, [0xAB_u8; 8]);
+            assert_eq!(decrypted.app_key_index, Some(AppKeyIndex(KeyIndex::new(1))));
+        }
+        // Phase 2 switches transmitting to the new key.
+        internals
+            .device_state_mut()
+            .security_materials_mut()
+            .app_key_map
+            .set_phase(NetKeyIndex(KeyIndex::new(0)), KeyRefreshPhases::Second);
+        assert_eq!(
+            internals
+                .get_app_key(AppKeyIndex(KeyIndex::new(1)))
+                .map(|sm| sm.app_key),
+            Some(new_key)
+        );
+        assert_eq!(
+            internals.update_app_key(
+                NetKeyIndex(KeyIndex::new(0)),
+                AppKeyIndex(KeyIndex::new(1)),
+                AppKey::random_secure(),
+            ),
+            Err(AppKeyError::CannotUpdate)
+        );
+    }
+    #[test]
+    fn test_net_key_refresh() {
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        let old_key = NetKey::random_secure();
+        let new_key = NetKey::random_secure();
+        let app_key = AppKey::random_secure();
+        let old_internals = keyed_internals(&old_key, app_key);
+        let new_internals = keyed_internals(&new_key, app_key);
+        let encrypted = |sender: &StackInternals| {
+            let msg = OutgoingLowerTransportMessage {
+                pdu: lower::PDU::UnsegmentedAccess(lower::UnsegmentedAccessPDU::new(
+                    None, &[0_u8; 5],
+                )),
+                src: UnicastAddress::new(2),
+                dst: Address::from(0x0001),
+                ttl: None,
+                seq: Some(SequenceNumber(U24::new(0x10))),
+                iv_index: IVIndex(0),
+                net_key_index,
+            };
+            let (pdu, keys) = sender.lower_to_net(&msg).expect("valid message");
+            let encrypted = pdu.encrypt(&keys, IVIndex(0)).expect("valid PDU");
+            (pdu, encrypted)
+        };
+        let decrypts = |internals: &StackInternals, sender: &StackInternals| {
+            let (pdu, encrypted) = encrypted(sender);
+            internals
+                .decrypt_network_pdu(encrypted.as_ref())
+                .ok()
+                .map(|(_, _, decrypted)| decrypted)
+                == Some(pdu)
+        };
+        let tx_key = |internals: &StackInternals| {
+            *internals
+                .net_keys()
+                .get_keys(net_key_index)
+                .expect("key inserted above")
+                .tx_key()
+                .net_key()
+        };
+
+        let mut internals = keyed_internals(&old_key, app_key);
+        assert_eq!(
+            internals.set_key_refresh_phase(net_key_index, KeyRefreshPhases::Second),
+            Err(NetKeyError::CannotSet)
+        );
+        assert_eq!(
+            internals.update_net_key(NetKeyIndex(KeyIndex::new(1)), &new_key),
+            Err(NetKeyError::InvalidNetKeyIndex)
+        );
+        assert_eq!(internals.update_net_key(net_key_index, &new_key), Ok(()));
+        assert_eq!(
+            internals.update_net_key(net_key_index, &NetKey::random_secure()),
+            Err(NetKeyError::CannotUpdate)
+        );
+        // Phase 1 still transmits with the old key but receives with both.
+        assert_eq!(tx_key(&internals), old_key);
+        assert!(decrypts(&internals, &old_internals));
+        assert!(decrypts(&internals, &new_internals));
+        // Phase 2 switches transmitting to the new key.
+        assert_eq!(
+            internals.set_key_refresh_phase(net_key_index, KeyRefreshPhases::Second),
+            Ok(KeyRefreshPhases::Second)
+        );
+        assert_eq!(tx_key(&internals), new_key);
+        assert!(decrypts(&internals, &old_internals));
+        // Phase 3 revokes the old key.
+        assert_eq!(
+            internals.set_key_refresh_phase(net_key_index, KeyRefreshPhases::Third),
+            Ok(KeyRefreshPhases::Normal)
+        );
+        assert_eq!(tx_key(&internals), new_key);
+        assert!(decrypts(&internals, &new_internals));
+        assert!(!decrypts(&internals, &old_internals));
+    }
+    #[test]
+    fn test_friendship_credentials() {
+        use crate::friend::{FriendCounter, FriendshipCredentials, LPNCounter, PollTimeout};
+        use crate::timestamp::{Timestamp, TimestampTrait};
+
+        let net_key = NetKey::random_secure();
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        let mut internals = internals();
+        internals
+            .device_state_mut()
+            .security_materials_mut()
+            .net_key_map
+            .insert(net_key_index, &net_key);
+        let lpn = UnicastAddress::new(0x0100);
+        let credentials = FriendshipCredentials {
+            lpn_address: lpn,
+            friend_address: UnicastAddress::new(1),
+            lpn_counter: LPNCounter::new(0x0102),
+            friend_counter: FriendCounter::new(0x0304),
+        };
+        internals
+            .friendships_mut()
+            .establish(credentials, PollTimeout::MIN, Timestamp::now());
+        let message = |dst: UnicastAddress| OutgoingLowerTransportMessage {
+            pdu: lower::PDU::UnsegmentedAccess(lower::UnsegmentedAccessPDU::new(None, &[0_u8; 5])),
+            src: UnicastAddress::new(1),
+            dst: Address::Unicast(dst),
+            ttl: None,
+            seq: Some(SequenceNumber(U24::new(0x10))),
+            iv_index: IVIndex(0),
+            net_key_index,
+        };
+        let managed_flooding = *internals
+            .net_keys()
+            .get_keys(net_key_index)
+            .expect("key inserted above")
+            .tx_key()
+            .network_keys();
+
+        assert_eq!(
+            internals.security_credentials(&Address::Unicast(lpn)),
+            SecurityCredentials::Friendship(lpn)
+        );
+        let (pdu, keys) = internals
+            .lower_to_net(&message(lpn))
+            .expect("valid message");
+        assert_eq!(keys, credentials.network_keys(&net_key));
+        assert_ne!(keys, managed_flooding);
+        assert_eq!(pdu.header.nid, keys.nid());
+        let encrypted = pdu.encrypt(&keys, IVIndex(0)).expect("valid PDU");
+        assert_eq!(
+            encrypted.as_ref().try_decrypt(&keys, IVIndex(0)).ok(),
+            Some(pdu)
+        );
+        assert!(encrypted
+            .as_ref()
+            .try_decrypt(&managed_flooding, IVIndex(0))
+            .is_err());
+        // The stack tries the friendship credentials when receiving as well.
+        assert_eq!(
+            internals
+                .decrypt_network_pdu(encrypted.as_ref())
+                .ok()
+                .map(|(_, _, decrypted)| decrypted),
+            Some(pdu)
+        );
+
+        // Anyone else gets managed flooding.
+        let (_, keys) = internals
+            .lower_to_net(&message(UnicastAddress::new(0x0200)))
+            .expect("valid message");
+        assert_eq!(keys, managed_flooding);
+    }
+    #[test]
+    fn test_friend_node() {
+        use crate::friend::friend::{FriendConfig, MIN_OFFER_DELAY};
+        use crate::friend::{
+            Criteria, FriendCounter, LPNCounter, MinQueueSizeLog, RSSIFactor, ReceiveWindowFactor,
+            FSN,
+        };
+        use crate::mesh::CTL;
+        use crate::timestamp::TimestampTrait;
+
+        let net_key = NetKey::random_secure();
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        let mut internals = internals();
+        internals
+            .device_state_mut()
+            .security_materials_mut()
+            .net_key_map
+            .insert(net_key_index, &net_key);
+        let managed_flooding = *internals
+            .net_keys()
+            .get_keys(net_key_index)
+            .expect("key inserted above")
+            .tx_key()
+            .network_keys();
+        let lpn = UnicastAddress::new(0x0100);
+        let request = FriendRequest {
+            criteria: Criteria {
+                rssi_factor: RSSIFactor::Factor1,
+                receive_window_factor: ReceiveWindowFactor::Window1,
+                min_queue_size_log: MinQueueSizeLog::N2,
+            },
+            receive_delay: ReceiveDelay::new(100),
+            poll_timeout: PollTimeout::MIN,
+            previous_address: None,
+            num_elements: 1,
+            lpn_counter: LPNCounter::new(3),
+        };
+        let now = Timestamp::now();
+        // Only Friends make offers.
+        assert!(internals
+            .friend_offer(lpn, &request, Some(-40), net_key_index, now)
+            .expect("valid request")
+            .is_none());
+        internals.set_friend_node(Some(FriendNode::new(FriendConfig::default())));
+        assert!(internals.device_state().friend_state().is_enabled());
+        let (delay, offer) = internals
+            .friend_offer(lpn, &request, Some(-40), net_key_index, now)
+            .expect("valid request")
+            .expect("offer made");
+        assert!(delay >= MIN_OFFER_DELAY);
+        // The Friend Offer uses managed flooding.
+        let offer = offer
+            .pdu
+            .as_ref()
+            .try_decrypt(&managed_flooding, IVIndex(0))
+            .ok()
+            .expect("managed flooding credentials");
+        assert_eq!(offer.header.ttl, TTL::new(0));
+        match &offer.payload {
+            lower::PDU::UnsegmentedControl(unseg) => {
+                let offer = control::FriendOffer::try_from_pdu(unseg).expect("Friend Offer");
+                assert_eq!(offer.0.rssi, -40);
+                assert_eq!(offer.0.friend_counter, FriendCounter::new(0));
+            }
+            _ => panic!("not a Control PDU"),
+        }
+        let keys = FriendshipCredentials {
+            lpn_address: lpn,
+            friend_address: UnicastAddress::new(1),
+            lpn_counter: request.lpn_counter,
+            friend_counter: FriendCounter::new(0),
+        }
+        .network_keys(&net_key);
+
+        // The first Friend Poll gets a Friend Update with the friendship credentials.
+        let (receive_delay, update) = internals
+            .friend_poll(lpn, &FriendPoll::new(FSN::new(false)), net_key_index, now)
+            .expect("valid poll")
+            .expect("friends");
+        assert_eq!(receive_delay, request.receive_delay);
+        let update = update
+            .pdu
+            .as_ref()
+            .try_decrypt(&keys, IVIndex(0))
+            .ok()
+            .expect("friendship credentials");
+        match &update.payload {
+            lower::PDU::UnsegmentedControl(unseg) => {
+                let update = control::FriendUpdate::try_from_pdu(unseg).expect("Friend Update");
+                assert!(!update.0.md.value());
+                assert_eq!(update.0.iv_index, IVIndex(0));
+            }
+            _ => panic!("not a Control PDU"),
+        }
+
+        // PDUs for the Low Power node are queued and re-encrypted when polled.
+        let pdu = net::PDU {
+            header: net::Header {
+                ivi: IVIndex(0).ivi(),
+                nid: managed_flooding.nid(),
+                ctl: CTL(false),
+                ttl: TTL::new(5),
+                seq: SequenceNumber(U24::new(0x20)),
+                src: UnicastAddress::new(0x0200),
+                dst: Address::Unicast(lpn),
+            },
+            payload: lower::PDU::UnsegmentedAccess(lower::UnsegmentedAccessPDU::new(
+                None, &[1_u8; 5],
+            )),
+        };
+        assert!(internals
+            .friend_queue_pdu(&pdu, net_key_index, IVIndex(0))
+            .is_none());
+        let (_, queued) = internals
+            .friend_poll(lpn, &FriendPoll::new(FSN::new(true)), net_key_index, now)
+            .expect("valid poll")
+            .expect("friends");
+        let queued = queued
+            .pdu
+            .as_ref()
+            .try_decrypt(&keys, IVIndex(0))
+            .ok()
+            .expect("friendship credentials");
+        assert_eq!(queued.header.src, pdu.header.src);
+        assert_eq!(queued.header.ttl, TTL::new(4));
+        assert_eq!(queued.payload, pdu.payload);
+
+        // The friendship ends once the PollTimeout passes without a poll.
+        assert!(internals
+            .expire_friendships(now + Duration::from_millis(500))
+            .is_empty());
+        assert_eq!(
+            internals.expire_friendships(
+                now + PollTimeout::MIN.as_duration() + Duration::from_millis(100)
+            ),
+            vec![lpn]
+        );
+        assert_eq!(
+            internals.security_credentials(&Address::Unicast(lpn)),
+            SecurityCredentials::ManagedFlooding
+        );
+        assert!(internals
+            .friend_poll(lpn, &FriendPoll::new(FSN::new(false)), net_key_index, now)
+            .expect("valid poll")
+            .is_none());
+    }
+    #[test]
+    fn test_beacon_iv_index_update() {
+        use crate::beacon::SecureNetworkFlags;
+        use crate::mesh::KeyRefreshFlag;
+        use crate::stack::iv_update::MIN_IV_UPDATE_PHASE;
+        use crate::timestamp::TimestampTrait;
+
+        let net_key = NetKey::random_secure();
+        let mut internals = keyed_internals(&net_key, AppKey::random_secure());
+        let materials = *internals
+            .net_keys()
+            .get_keys(NetKeyIndex(KeyIndex::new(0)))
+            .expect("key inserted above")
+            .tx_key();
+        let beacon = |iv_index: u32, iv_update: bool| {
+            SecureNetworkBeacon::new(
+                SecureNetworkFlags::new(KeyRefreshFlag(false), IVUpdateFlag(iv_update)),
+                materials.network_id(),
+                IVIndex(iv_index),
+                materials.beacon_key(),
+            )
+        };
+        let verify = |internals: &StackInternals, beacon: &SecureNetworkBeacon| {
+            internals.verify_beacon(beacon).map(|(index, verified)| {
+                assert_eq!(index, NetKeyIndex(KeyIndex::new(0)));
+                verified
+            })
+        };
+
+        // Beacons from other networks or with a bad Authentication Value are ignored.
+        let mut forged = beacon(1, true);
+        forged.authentication_value.0[0] ^= 0x01;
+        assert!(verify(&internals, &forged).is_none());
+        let other_key = NetKey::random_secure();
+        let other_network = SecureNetworkBeacon::new(
+            SecureNetworkFlags::new(KeyRefreshFlag(false), IVUpdateFlag(true)),
+            materials.network_id(),
+            IVIndex(1),
+            &BeaconKey::from(&other_key),
+        );
+        assert!(verify(&internals, &other_network).is_none());
+
+        // IV Update started.
+        let start = Timestamp::now();
+        let verified = verify(&internals, &beacon(1, true)).expect("authentic beacon");
+        assert_eq!(verified.iv_update, IVUpdateFlag(true));
+        assert_eq!(internals.update_iv_index(&verified, start), Ok(true));
+        assert_eq!(internals.device_state().iv_index(), IVIndex(1));
+        assert_eq!(
+            internals.device_state().iv_update_flag(),
+            IVUpdateFlag(true)
+        );
+        // Still transmitting with the old IV Index but receiving with both.
+        assert_eq!(internals.tx_iv_index(), IVIndex(0));
+        assert!(internals.is_valid_iv_index(IVIndex(0)));
+        assert!(internals.is_valid_iv_index(IVIndex(1)));
+        // Old IV Indexes never go back.
+        let old = verify(&internals, &beacon(0, false)).expect("authentic beacon");
+        assert_eq!(
+            internals.update_iv_index(&old, start),
+            Err(IVUpdateError::OldIVIndex)
+        );
+        // IV Update finished.
+        let finished = verify(&internals, &beacon(1, false)).expect("authentic beacon");
+        assert_eq!(
+            internals.update_iv_index(&finished, start + MIN_IV_UPDATE_PHASE),
+            Ok(true)
+        );
+        assert_eq!(
+            internals.device_state().iv_update_flag(),
+            IVUpdateFlag(false)
+        );
+        assert_eq!(internals.tx_iv_index(), IVIndex(1));
+        // Too far ahead to recover from.
+        let far = verify(&internals, &beacon(1 + IV_INDEX_RECOVERY_LIMIT + 1, false))
+            .expect("authentic beacon");
+        assert_eq!(
+            internals.update_iv_index(&far, start + MIN_IV_UPDATE_PHASE),
+            Err(IVUpdateError::TooFarAhead)
+        );
+        let recover = verify(&internals, &beacon(1 + IV_INDEX_RECOVERY_LIMIT, false))
+            .expect("authentic beacon");
+        assert_eq!(
+            internals.update_iv_index(&recover, start + MIN_IV_UPDATE_PHASE),
+            Ok(true)
+        );
+        assert_eq!(
+            internals.device_state().iv_index(),
+            IVIndex(1 + IV_INDEX_RECOVERY_LIMIT)
+        );
+    }
+    #[test]
+    fn test_encrypt_network_pdu_round_trip() {
+        use crate::mesh::CTL;
+
+        let net_key = NetKey::random_secure();
+        let internals = keyed_internals(&net_key, AppKey::random_secure());
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        let nid = internals
+            .tx_network_keys(
+                net_key_index,
+                &Address::Unicast(UnicastAddress::new(0x0100)),
+            )
+            .expect("key inserted above")
+            .nid();
+        let pdu = net::PDU {
+            header: net::Header {
+                ivi: IVIndex(0).ivi(),
+                nid,
+                ctl: CTL(false),
+                ttl: TTL::new(5),
+                seq: SequenceNumber(U24::new(0x10)),
+                src: UnicastAddress::new(1),
+                dst: Address::Unicast(UnicastAddress::new(0x0100)),
+            },
+            payload: lower::PDU::UnsegmentedAccess(lower::UnsegmentedAccessPDU::new(
+                None,
+                &[0x01, 0x02, 0x03],
+            )),
+        };
+        let outgoing = internals
+            .encrypt_network_pdu(pdu, net_key_index, IVIndex(0))
+            .expect("valid PDU");
+        assert_eq!(
+            outgoing.transmit_parameters,
+            internals.device_state().config_states().network_transmit.0
+        );
+        assert_eq!(
+            internals
+                .decrypt_network_pdu(outgoing.pdu.as_ref())
+                .expect("same keys"),
+            (net_key_index, IVIndex(0), pdu)
+        );
+        assert_eq!(
+            internals
+                .encrypt_network_pdu(pdu, NetKeyIndex(KeyIndex::new(1)), IVIndex(0))
+                .err(),
+            Some(SendError::InvalidNetKeyIndex)
+        );
+    }
+    #[test]
+    fn test_nid_index() {
+        use crate::friend::{FriendCounter, FriendshipCredentials, LPNCounter, PollTimeout};
+        use crate::mesh::CTL;
+        use crate::timestamp::{Timestamp, TimestampTrait};
+
+        let net_key = NetKey::random_secure();
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        let mut internals = keyed_internals(&net_key, AppKey::random_secure());
+        // Keys added through `device_state_mut` aren't indexed until a refresh.
+        assert!(internals.nid_index().is_stale());
+        internals.refresh_nid_index();
+        assert!(!internals.nid_index().is_stale());
+        assert_eq!(internals.nid_index().len(), 1);
+        let lpn = UnicastAddress::new(0x0100);
+        let credentials = FriendshipCredentials {
+            lpn_address: lpn,
+            friend_address: UnicastAddress::new(1),
+            lpn_counter: LPNCounter::new(0x0102),
+            friend_counter: FriendCounter::new(0x0304),
+        };
+        internals
+            .friendships_mut()
+            .establish(credentials, PollTimeout::MIN, Timestamp::now());
+        let keys = internals
+            .tx_network_keys(net_key_index, &Address::Unicast(lpn))
+            .expect("friendship established above");
+        let pdu = net::PDU {
+            header: net::Header {
+                ivi: IVIndex(0).ivi(),
+                nid: keys.nid(),
+                ctl: CTL(false),
+                ttl: TTL::new(5),
+                seq: SequenceNumber(U24::new(0x10)),
+                src: UnicastAddress::new(1),
+                dst: Address::Unicast(lpn),
+            },
+            payload: lower::PDU::UnsegmentedAccess(lower::UnsegmentedAccessPDU::new(
+                None,
+                &[0x01, 0x02, 0x03],
+            )),
+        };
+        let encrypted = pdu.encrypt(&keys, IVIndex(0)).expect("valid PDU");
+        // A stale index falls back to trying every key.
+        assert!(internals.nid_index().is_stale());
+        assert_eq!(
+            internals.decrypt_network_pdu(encrypted.as_ref()).ok(),
+            Some((net_key_index, IVIndex(0), pdu))
+        );
+        internals.refresh_nid_index();
+        assert_eq!(internals.nid_index().len(), 2);
+        assert!(internals
+            .nid_index()
+            .candidates(keys.nid())
+            .contains(&(net_key_index, keys)));
+        assert_eq!(
+            internals.decrypt_network_pdu(encrypted.as_ref()).ok(),
+            Some((net_key_index, IVIndex(0), pdu))
+        );
+        // Removed keys are dropped from the index.
+        internals
+            .device_state_mut()
+            .security_materials_mut()
+            .net_key_map
+            .remove_keys(net_key_index);
+        internals.refresh_nid_index();
+        assert!(internals.nid_index().is_empty());
+        match internals.decrypt_network_pdu(encrypted.as_ref()) {
+            Err(RecvError::NoMatchingNetKey) => (),
+            other => panic!("expected NoMatchingNetKey, got {:?}", other),
+        }
+    }
+    #[test]
+    fn test_friendship_tx_keys_cached() {
+        use crate::friend::{FriendCounter, FriendshipCredentials, LPNCounter, PollTimeout};
+        use crate::timestamp::{Timestamp, TimestampTrait};
+
+        let net_key = NetKey::random_secure();
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        let mut internals = keyed_internals(&net_key, AppKey::random_secure());
+        let lpn = UnicastAddress::new(0x0100);
+        let credentials = FriendshipCredentials {
+            lpn_address: lpn,
+            friend_address: UnicastAddress::new(1),
+            lpn_counter: LPNCounter::new(0x0102),
+            friend_counter: FriendCounter::new(0x0304),
+        };
+        internals
+            .friendships_mut()
+            .establish(credentials, PollTimeout::MIN, Timestamp::now());
+        // Nothing is cached until the index is refreshed but the keys are still derived.
+        assert!(internals
+            .nid_index()
+            .friendship_tx_keys(&credentials, net_key_index)
+            .is_none());
+        assert_eq!(
+            internals.tx_network_keys(net_key_index, &Address::Unicast(lpn)),
+            Ok(credentials.network_keys(&net_key))
+        );
+        internals.refresh_nid_index();
+        assert_eq!(
+            internals
+                .nid_index()
+                .friendship_tx_keys(&credentials, net_key_index),
+            Some(&credentials.network_keys(&net_key))
+        );
+        assert_eq!(
+            internals.tx_network_keys(net_key_index, &Address::Unicast(lpn)),
+            Ok(credentials.network_keys(&net_key))
+        );
+        // A new NetKey makes the cached keys stale so they aren't used until the next refresh.
+        let new_net_key = NetKey::random_secure();
+        internals
+            .device_state_mut()
+            .security_materials_mut()
+            .net_key_map
+            .insert(net_key_index, &new_net_key);
+        assert_eq!(
+            internals.tx_network_keys(net_key_index, &Address::Unicast(lpn)),
+            Ok(credentials.network_keys(&new_net_key))
+        );
+        internals.refresh_nid_index();
+        assert_eq!(
+            internals
+                .nid_index()
+                .friendship_tx_keys(&credentials, net_key_index),
+            Some(&credentials.network_keys(&new_net_key))
+        );
+    }
+    #[test]
+    fn test_decrypt_previous_iv_index() {
+        use crate::mesh::CTL;
+
+        let net_key = NetKey::random_secure();
+        let mut internals = keyed_internals(&net_key, AppKey::random_secure());
+        *internals.device_state_mut().iv_index_mut() = IVIndex(1);
+        *internals.device_state_mut().iv_update_flag_mut() = IVUpdateFlag(true);
+        let net_keys = *internals
+            .net_keys()
+            .get_keys(NetKeyIndex(KeyIndex::new(0)))
+            .expect("key inserted above")
+            .tx_key()
+            .network_keys();
+        for &iv_index in &[IVIndex(0), IVIndex(1)] {
+            let pdu = net::PDU {
+                header: net::Header {
+                    ivi: iv_index.ivi(),
+                    nid: net_keys.nid(),
+                    ctl: CTL(false),
+                    ttl: TTL::new(5),
+                    seq: SequenceNumber(U24::new(1)),
+                    src: UnicastAddress::new(0x0100),
+                    dst: UnicastAddress::new(1).into(),
+                },
+                payload: lower::PDU::UnsegmentedAccess(lower::UnsegmentedAccessPDU::new(
+                    None,
+                    &[0x01, 0x02, 0x03],
+                )),
+            };
+            let encrypted = pdu.encrypt(&net_keys, iv_index).expect("valid PDU");
+            let (_, decrypted_iv_index, decrypted) = internals
+                .decrypt_network_pdu(encrypted.as_ref())
+                .expect("current or previous IV Index");
+            assert_eq!(decrypted_iv_index, iv_index);
+            assert_eq!(decrypted, pdu);
+        }
+    }
+}
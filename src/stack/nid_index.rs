@@ -0,0 +1,98 @@
+//! Network keys grouped by `NID` so an incoming Network PDU is only decrypted with the keys
+//! that could match it.
+use crate::crypto::materials::{NetKeyMap, NetworkKeys};
+use crate::friend::FriendshipCredentials;
+use crate::mesh::{NetKeyIndex, NID};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// Every `NetworkKeys` a Network PDU might be encrypted with (both keys during a Key Refresh,
+/// managed flooding and friendship credentials) by `NID`. Friendship keys cost a `k2` each to
+/// derive so they're derived once here instead of for every PDU, along with the friendship
+/// keys PDUs are sent with.
+///
+/// `NID` is only 7 bits so different keys can share one. Each `NID` keeps a short list of
+/// candidates and only trying the decryption tells which one (if any) is right.
+#[derive(Clone, Debug, Default)]
+pub struct NIDIndex {
+    map: BTreeMap<NID, Vec<(NetKeyIndex, NetworkKeys)>>,
+    tx_friendship_keys: BTreeMap<(FriendshipCredentials, NetKeyIndex), NetworkKeys>,
+    lpn_credentials: Option<FriendshipCredentials>,
+    stale: bool,
+}
+impl NIDIndex {
+    /// An empty index. Nothing matches it until it's rebuilt.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Rebuilds the index from `net_keys`, the friendships with Low Power nodes (`credentials`)
+    /// and this Low Power node's friendship (`lpn_credentials`). Managed flooding keys are
+    /// listed before friendship keys under the same `NID`.
+    pub fn rebuild(
+        &mut self,
+        net_keys: &NetKeyMap,
+        credentials: impl Iterator<Item = FriendshipCredentials>,
+        lpn_credentials: Option<FriendshipCredentials>,
+    ) {
+        self.map.clear();
+        self.tx_friendship_keys.clear();
+        for (&index, phase) in net_keys.map.iter() {
+            let (current, next) = phase.rx_keys();
+            for sm in core::iter::once(current).chain(next) {
+                self.insert(index, *sm.network_keys());
+            }
+        }
+        for credentials in credentials.chain(lpn_credentials) {
+            for (&index, phase) in net_keys.map.iter() {
+                let (current, next) = phase.rx_keys();
+                for sm in core::iter::once(current).chain(next) {
+                    self.insert(index, credentials.network_keys(sm.net_key()));
+                }
+                self.tx_friendship_keys.insert(
+                    (credentials, index),
+                    credentials.network_keys(phase.tx_key().net_key()),
+                );
+            }
+        }
+        self.lpn_credentials = lpn_credentials;
+        self.stale = false;
+    }
+    fn insert(&mut self, net_key_index: NetKeyIndex, keys: NetworkKeys) {
+        self.map
+            .entry(keys.nid())
+            .or_insert_with(Vec::new)
+            .push((net_key_index, keys));
+    }
+    /// Marks the index as out of date. Keys or friendships might have changed without going
+    /// through `rebuild`.
+    pub fn mark_stale(&mut self) {
+        self.stale = true;
+    }
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+    /// This Low Power node's friendship credentials the index was built with.
+    pub fn lpn_credentials(&self) -> Option<FriendshipCredentials> {
+        self.lpn_credentials
+    }
+    /// Returns every candidate key with the given `nid`.
+    pub fn candidates(&self, nid: NID) -> &[(NetKeyIndex, NetworkKeys)] {
+        self.map.get(&nid).map_or(&[][..], Vec::as_slice)
+    }
+    /// Returns the keys to send a PDU to (or from) a friend with `credentials` are encrypted
+    /// with, if the friendship was indexed.
+    pub fn friendship_tx_keys(
+        &self,
+        credentials: &FriendshipCredentials,
+        net_key_index: NetKeyIndex,
+    ) -> Option<&NetworkKeys> {
+        self.tx_friendship_keys.get(&(*credentials, net_key_index))
+    }
+    /// Returns how many keys are indexed in total.
+    pub fn len(&self) -> usize {
+        self.map.values().map(Vec::len).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
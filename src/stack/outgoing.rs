@@ -1,35 +1,76 @@
 //! Outgoing PDU handler.
-use crate::asyncs::{
-    sync::{mpsc, Mutex, RwLock},
-    time,
-};
+use crate::asyncs::sync::{mpsc, oneshot, Mutex, RwLock};
+use crate::control;
 use crate::device_state::SeqRange;
-use crate::mesh::{SequenceNumber, CTL};
-use crate::net::Header;
+use crate::lower::SeqZero;
+use crate::relay::RelayPDU;
 use crate::stack::bearer::{OutgoingEncryptedNetworkPDU, OutgoingMessage};
+use crate::stack::clock::SharedClock;
 use crate::stack::messages::{OutgoingLowerTransportMessage, OutgoingUpperTransportMessage};
-use crate::stack::segments::{IncomingPDU, OutgoingSegments};
+use crate::stack::segments::{
+    AckEvent, IncomingPDU, OutgoingSegments, MIN_SEGMENTS_TRANSMIT_SPACING, SEGMENTS_SEND_TIMEOUT,
+    SEGMENT_RETRANSMITS,
+};
+use crate::stack::stats::{Counter, StatsCounters};
 use crate::stack::{segments, SendError, StackInternals};
-use crate::{control, net};
+use crate::timestamp::TimestampTrait;
+use alloc::boxed::Box;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::time::Duration;
+use futures_util::future::Either;
 
 pub struct Outgoing {
     pub outgoing_network: Mutex<mpsc::Sender<OutgoingMessage>>,
     pub internals: Arc<RwLock<StackInternals>>,
     pub ack_rx: Mutex<mpsc::Receiver<IncomingPDU<control::Ack>>>,
+    cancel_tx: mpsc::Sender<SeqZero>,
+    cancel_rx: Mutex<mpsc::Receiver<SeqZero>>,
+    /// `SeqZero` of the segmented message `send_segments` is sending. Cancels are only queued
+    /// while it's set.
+    in_flight: Mutex<Option<SeqZero>>,
+    /// Set by `Outgoing::abort_segments`. No segmented message is sent anymore.
+    segments_aborted: AtomicBool,
+    pub stats: StatsCounters,
+    /// Times the segment transmissions (see [`Outgoing::send_segments`]).
+    clock: SharedClock,
+}
+/// What `Outgoing::send_segments` heard while waiting on a segmented transfer.
+enum SendEvent {
+    Ack(IncomingPDU<control::Ack>),
+    /// This node stopped the transfer with this `SeqZero` (see `Outgoing::cancel`).
+    Cancel(SeqZero),
+    /// The segment transmission timer fired.
+    TimerFired,
 }
-pub const SEND_TIMEOUT_SECS: u64 = 10;
+/// A segmented message waiting for [`Outgoing::segments_loop`] and where to report how its
+/// transfer ended.
+pub type QueuedSegments = (
+    OutgoingSegments<Box<[u8]>>,
+    oneshot::Sender<Result<(), SendError>>,
+);
+/// Cancels queued with `Outgoing::cancel`.
+const CANCEL_CHANNEL_LEN: usize = 2;
 impl Outgoing {
     pub fn new(
         internals: Arc<RwLock<StackInternals>>,
         ack_rx: mpsc::Receiver<IncomingPDU<control::Ack>>,
         outgoing: mpsc::Sender<OutgoingMessage>,
+        stats: StatsCounters,
+        clock: SharedClock,
     ) -> Self {
+        let (cancel_tx, cancel_rx) = mpsc::channel(CANCEL_CHANNEL_LEN);
         Self {
             outgoing_network: Mutex::new(outgoing),
             internals,
             ack_rx: Mutex::new(ack_rx),
+            cancel_tx,
+            cancel_rx: Mutex::new(cancel_rx),
+            in_flight: Mutex::new(None),
+            segments_aborted: AtomicBool::new(false),
+            stats,
+            clock,
         }
     }
     pub async fn send_upper_transport<Storage: AsRef<[u8]>>(
@@ -38,19 +79,71 @@ impl Outgoing {
     ) -> Result<(), SendError> {
         todo!("implement sending upper transport PDU")
     }
-    pub fn send_timeout(&self) -> Duration {
-        Duration::from_secs(SEND_TIMEOUT_SECS)
+    /// Stops sending the segmented message with `seq_zero` if it's the one in flight. Nothing
+    /// else is sent for it and its transfer ends with `SendError::Aborted`. Returns
+    /// `SendError::NotInFlight` (and nothing changes) if it isn't, even if it's still queued.
+    /// Never waits on the transfer.
+    ///
+    /// The receiver isn't told. There's no Control message for a sender to cancel a segmented
+    /// message so the receiver just times out (its incomplete timer).
+    pub async fn cancel(&self, seq_zero: SeqZero) -> Result<(), SendError> {
+        // Held while queuing so the transfer can't end (and another start) in between.
+        let in_flight = self.in_flight.lock().await;
+        if *in_flight != Some(seq_zero) {
+            return Err(SendError::NotInFlight);
+        }
+        match self.cancel_tx.clone().try_send(seq_zero) {
+            // A full queue already holds cancels for this transfer.
+            Ok(()) | Err(mpsc::error::TrySendError::Full(_)) => Ok(()),
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(SendError::ChannelClosed),
+        }
+    }
+    /// Aborts the segmented message in flight and every one sent after it with
+    /// `SendError::Aborted`. For shutting down (see `FullStack::shutdown`), there's no undoing it.
+    pub async fn abort_segments(&self) {
+        self.segments_aborted.store(true, Ordering::SeqCst);
+        if let Some(seq_zero) = *self.in_flight.lock().await {
+            // A full queue already holds cancels for this transfer.
+            let _ = self.cancel_tx.clone().try_send(seq_zero);
+        }
     }
-    pub async fn next_ack<Storage: AsRef<[u8]>>(
-        segments: &OutgoingSegments<Storage>,
+    /// Waits up to `timeout` for the next ack or cancel.
+    async fn next_event(
+        &self,
         ack_rx: &mut mpsc::Receiver<IncomingPDU<control::Ack>>,
-    ) -> Result<IncomingPDU<control::Ack>, SendError> {
+        cancel_rx: &mut mpsc::Receiver<SeqZero>,
+        timeout: Duration,
+    ) -> Result<SendEvent, SendError> {
+        let ack = ack_rx.recv();
+        let cancel = cancel_rx.recv();
+        futures_util::pin_mut!(ack, cancel);
+        match self
+            .clock
+            .timeout(timeout, futures_util::future::select(ack, cancel))
+            .await
+        {
+            Err(_) => Ok(SendEvent::TimerFired),
+            Ok(Either::Left((Some(ack), _))) => Ok(SendEvent::Ack(ack)),
+            Ok(Either::Right((Some(seq_zero), _))) => Ok(SendEvent::Cancel(seq_zero)),
+            Ok(_) => Err(SendError::ChannelClosed),
+        }
+    }
+    /// Waits `duration` unless `Outgoing::cancel` stops `seq_zero` first.
+    async fn wait_unless_canceled(
+        &self,
+        cancel_rx: &mut mpsc::Receiver<SeqZero>,
+        seq_zero: SeqZero,
+        duration: Duration,
+    ) -> Result<(), SendError> {
+        let deadline = self.clock.now() + duration;
         loop {
-            let next_ack = ack_rx.recv().await.ok_or(SendError::ChannelClosed)?;
-            match segments.is_new_ack(next_ack) {
-                Ok(is_new) if is_new => return Ok(next_ack),
-                _ => continue, // Ack doesn't match
-            };
+            let left = self.clock.now().until(deadline).unwrap_or_default();
+            match self.clock.timeout(left, cancel_rx.recv()).await {
+                Err(_) => return Ok(()),
+                Ok(None) => return Err(SendError::ChannelClosed),
+                Ok(Some(canceled)) if canceled == seq_zero => return Err(SendError::Aborted),
+                Ok(Some(_)) => (),
+            }
         }
     }
     pub async fn send_encrypted_network_pdu(
@@ -65,84 +158,599 @@ impl Outgoing {
             .ok()
             .ok_or(SendError::ChannelClosed)
     }
+    /// Re-encrypts `relay` (with its TTL decremented) under its NetKey for the advertising
+    /// bearer (see `StackInternals::encrypt_network_pdu`). It's sent with the node's
+    /// `RelayRetransmit` parameters instead of the `NetworkTransmit` ones. Returns `Ok(None)` if
+    /// the TTL is too low to relay.
+    pub fn encrypt_relay_pdu(
+        internals: &StackInternals,
+        relay: &RelayPDU,
+    ) -> Result<Option<OutgoingEncryptedNetworkPDU>, SendError> {
+        let pdu = match relay.relayed_pdu() {
+            Some(pdu) => pdu,
+            None => return Ok(None),
+        };
+        let mut outgoing_pdu =
+            internals.encrypt_network_pdu(pdu, relay.net_key_index, relay.iv_index)?;
+        outgoing_pdu.transmit_parameters = internals.device_state().relay_retransmit().0;
+        outgoing_pdu.relayed = true;
+        Ok(Some(outgoing_pdu))
+    }
+    /// Relays `relay` (see [`Outgoing::encrypt_relay_pdu`]).
+    pub async fn relay_pdu(&self, relay: RelayPDU) -> Result<(), SendError> {
+        let outgoing_pdu = Self::encrypt_relay_pdu(&*self.internals.read().await, &relay)?;
+        match outgoing_pdu {
+            Some(outgoing_pdu) => self.send_encrypted_network_pdu(outgoing_pdu).await,
+            None => Ok(()),
+        }
+    }
+    /// Relays every PDU from `relay_rx` until either channel closes. PDUs that can't be
+    /// re-encrypted (their NetKey was just deleted) are skipped.
+    pub async fn relay_loop(
+        internals: Arc<RwLock<StackInternals>>,
+        mut relay_rx: mpsc::Receiver<RelayPDU>,
+        mut outgoing_network: mpsc::Sender<OutgoingMessage>,
+    ) -> Result<(), SendError> {
+        while let Some(relay) = relay_rx.recv().await {
+            // The read lock is released before waiting on the bearer.
+            let outgoing_pdu = Self::encrypt_relay_pdu(&*internals.read().await, &relay);
+            if let Ok(Some(outgoing_pdu)) = outgoing_pdu {
+                outgoing_network
+                    .send(OutgoingMessage::Network(outgoing_pdu))
+                    .await
+                    .ok()
+                    .ok_or(SendError::ChannelClosed)?;
+            }
+        }
+        Err(SendError::ChannelClosed)
+    }
+    /// Sends every Lower Transport PDU from `transport_rx` (the reassembler's Segment
+    /// Acknowledgements) until either channel closes. PDUs that can't be encrypted (their NetKey
+    /// was just deleted) are skipped.
+    pub async fn lower_transport_loop(
+        outgoing: Arc<Outgoing>,
+        mut transport_rx: mpsc::Receiver<OutgoingLowerTransportMessage>,
+    ) -> Result<(), SendError> {
+        while let Some(msg) = transport_rx.recv().await {
+            if let Err(SendError::ChannelClosed) = outgoing.send_unsegmented(msg).await {
+                break;
+            }
+        }
+        Err(SendError::ChannelClosed)
+    }
     pub async fn send_unsegmented(
         &self,
         msg: OutgoingLowerTransportMessage,
     ) -> Result<(), SendError> {
-        let internals = self.internals.read().await;
-        let (pdu, net_sm) = internals.lower_to_net(&msg)?;
-        let transmit_parameters = internals.device_state.config_states().network_transmit.0;
-        // Release the lock on StackInternals.
-        self.send_encrypted_network_pdu(OutgoingEncryptedNetworkPDU {
-            transmit_parameters,
-            pdu: pdu
-                .encrypt(net_sm.network_keys(), msg.iv_index)
-                .map_err(|_| SendError::NetEncryptError)?,
-        })
-        .await
+        let outgoing_pdu = self.internals.read().await.encrypt_lower_transport(&msg)?;
+        // The lock on StackInternals is released before waiting on the bearer.
+        self.send_encrypted_network_pdu(outgoing_pdu).await
     }
+    /// Sends every segment of `msg` that isn't acked yet. The first transmission uses the
+    /// Sequence Numbers reserved for the message (starting at its `SeqAuth`) while
+    /// retransmissions get new ones.
+    async fn transmit_missing<Storage: AsRef<[u8]>>(
+        &self,
+        msg: &OutgoingSegments<Storage>,
+        mut seqs: Option<SeqRange>,
+    ) -> Result<(), SendError> {
+        // Encrypt every segment up front so the StackInternals lock isn't held while waiting on
+        // the bearer.
+        let outgoing_pdus = {
+            let internals = self.internals.read().await;
+            msg.segments_iter(|| seqs.as_mut().and_then(SeqRange::next))
+                .map(|segment| internals.encrypt_lower_transport(&segment))
+                .collect::<Result<Vec<_>, SendError>>()?
+        };
+        for outgoing_pdu in outgoing_pdus {
+            self.send_encrypted_network_pdu(outgoing_pdu).await?;
+        }
+        Ok(())
+    }
+    /// Sends every segment of `msg` and retransmits them until they're acked:
+    /// * Every unacked segment is sent again each time the segment transmission timer (see
+    /// [`segments::segment_transmit_interval`]) fires without a new ack. After
+    /// `SEGMENT_RETRANSMITS` of those in a row, the transfer fails with
+    /// `SendError::Unacknowledged`.
+    /// * A partial ack resends just the missing segments right away (but never sooner than
+    /// `MIN_SEGMENTS_TRANSMIT_SPACING` after the last transmission).
+    /// * A cancel ack from the receiver ends it with `SendError::Canceled` and
+    /// [`Outgoing::cancel`] with `SendError::Aborted`.
+    /// * Not everything acked within `SEGMENTS_SEND_TIMEOUT` is `SendError::AckTimeout`.
+    ///
+    /// Segments to group and virtual addresses aren't acked so they're just sent
+    /// `SEGMENT_RETRANSMITS` extra times. Only one transfer runs at a time.
     pub async fn send_segments<Storage: AsRef<[u8]>>(
         &self,
-        msg: segments::OutgoingSegments<Storage>,
+        mut msg: OutgoingSegments<Storage>,
     ) -> Result<(), SendError> {
-        //todo check element_index (src address?)
-        //todo Lock out SeqCounter
-        let seq = msg.segments.seq_auth().first_seq;
-        let internals = self.internals.read().await;
-        let iv_index = msg.segments.seq_auth().iv_index;
-        if !internals.is_valid_iv_index(iv_index) {
-            return Err(SendError::InvalidIVIndex);
-        }
-        let ivi = iv_index.ivi();
-        let net_sm = internals
-            .net_keys()
-            .get_keys(msg.net_key_index)
-            .ok_or(SendError::InvalidNetKeyIndex)?
-            .tx_key();
-        let nid = net_sm.network_keys().nid();
-        let ctl = CTL(msg.segments.upper_pdu.is_control());
-        let transmit_parameters = internals.device_state().config_states().network_transmit.0;
-        let ttl = msg.ttl.unwrap_or_else(|| internals.default_ttl());
-        let mut ack_rx = self.ack_rx.lock().await;
-        let make_net_header = |seq: SequenceNumber| Header {
-            ivi,
-            nid,
-            ctl,
-            ttl,
-            seq,
-            src: msg.src,
-            dst: msg.dst,
+        if msg.dst.is_unassigned() {
+            return Err(SendError::InvalidAddress);
+        }
+        let seg_o = msg.segments.seg_o();
+        let seq_zero = msg.segments.seq_auth().seq_zero();
+        let seqs = SeqRange::new_segs(msg.segments.seq_auth().first_seq, seg_o);
+        // The segments go out with the Default TTL if `msg` has none so time them with it too.
+        let ttl = match msg.ttl {
+            Some(ttl) => ttl,
+            None => self.internals.read().await.device_state().default_ttl(),
         };
-        // Immediately send out the PDUs with the acquired seq range.
-        for (seg, seq) in msg
-            .segments
-            .iter(msg.block_ack)
-            .zip(SeqRange::new_segs(seq, msg.segments.seg_o()))
+        let transmit_interval = segments::segment_transmit_interval(ttl);
+        let mut ack_rx = self.ack_rx.lock().await;
+        let mut cancel_rx = self.cancel_rx.lock().await;
+        // Acks queued while nothing was in flight are for earlier transfers.
+        while ack_rx.try_recv().is_ok() {}
         {
-            self.send_encrypted_network_pdu(OutgoingEncryptedNetworkPDU {
-                transmit_parameters,
-                pdu: net::PDU {
-                    header: make_net_header(seq),
-                    payload: seg.into(),
+            let mut in_flight = self.in_flight.lock().await;
+            // Checked under the lock so `abort_segments` either cancels this transfer or stops it
+            // here.
+            if self.segments_aborted.load(Ordering::SeqCst) {
+                return Err(SendError::Aborted);
+            }
+            *in_flight = Some(seq_zero);
+        }
+        let result = async {
+            self.transmit_missing(&msg, Some(seqs)).await?;
+            if !msg.dst.is_unicast() {
+                // Nobody acks segments sent to group or virtual addresses.
+                for _ in 0..SEGMENT_RETRANSMITS {
+                    self.wait_unless_canceled(&mut cancel_rx, seq_zero, transmit_interval)
+                        .await?;
+                    self.transmit_missing(&msg, None).await?;
                 }
-                .encrypt(net_sm.network_keys(), iv_index)
-                .map_err(|_| SendError::NetEncryptError)?,
-            })
-            .await?;
-        }
-        time::timeout(self.send_timeout(), async {
-            loop {
-                let _first_ack = Self::next_ack(&msg, &mut ack_rx).await?;
+                return Ok(());
+            }
+            let mut last_transmit = self.clock.now();
+            let mut retransmits_left = SEGMENT_RETRANSMITS;
+            self.clock
+                .timeout(SEGMENTS_SEND_TIMEOUT, async {
+                    loop {
+                        let ack = match self
+                            .next_event(&mut ack_rx, &mut cancel_rx, transmit_interval)
+                            .await?
+                        {
+                            SendEvent::Ack(ack) => ack,
+                            SendEvent::Cancel(canceled) if canceled == seq_zero => {
+                                return Err::<(), SendError>(SendError::Aborted)
+                            }
+                            SendEvent::Cancel(_) => continue,
+                            SendEvent::TimerFired => {
+                                if retransmits_left == 0 {
+                                    return Err(SendError::Unacknowledged);
+                                }
+                                retransmits_left -= 1;
+                                // Nothing new was heard. Resend everything that's still unacked.
+                                self.transmit_missing(&msg, None).await?;
+                                last_transmit = self.clock.now();
+                                continue;
+                            }
+                        };
+                        match msg.ack_event(ack) {
+                            Ok(AckEvent::New) => (),
+                            Ok(AckEvent::Canceled) => return Err(SendError::Canceled),
+                            Ok(AckEvent::Old) | Err(_) => continue,
+                        }
+                        msg.block_ack = ack.pdu.block_ack;
+                        retransmits_left = SEGMENT_RETRANSMITS;
+                        if msg.block_ack.all_acked(seg_o) {
+                            return Ok(());
+                        }
+                        // Some segments are missing (`BlockAck::missing_segments`). Resend just
+                        // those right away instead of waiting for the transmission timer.
+                        let since_last = self.clock.now().since(last_transmit).unwrap_or_default();
+                        if since_last < MIN_SEGMENTS_TRANSMIT_SPACING {
+                            self.clock
+                                .delay_for(MIN_SEGMENTS_TRANSMIT_SPACING - since_last)
+                                .await;
+                        }
+                        self.transmit_missing(&msg, None).await?;
+                        last_transmit = self.clock.now();
+                    }
+                })
+                .await
+                .unwrap_or(Err(SendError::AckTimeout))
+        }
+        .await;
+        *self.in_flight.lock().await = None;
+        // Cancels that came in too late to stop it.
+        while cancel_rx.try_recv().is_ok() {}
+        self.stats.count(match result {
+            Ok(()) => Counter::SegmentedSent,
+            Err(_) => Counter::SegmentedFailed,
+        });
+        result
+    }
+    /// Sends every segmented message from `queue_rx` one after another (see
+    /// [`Outgoing::send_segments`]) until every `Sender` to the queue is dropped. A failed
+    /// transfer doesn't stop the next one.
+    pub async fn segments_loop(
+        outgoing: Arc<Outgoing>,
+        mut queue_rx: mpsc::Receiver<QueuedSegments>,
+    ) -> Result<(), SendError> {
+        while let Some((msg, result_tx)) = queue_rx.recv().await {
+            let result = outgoing.send_segments(msg).await;
+            // Whoever queued `msg` might not be waiting for the result anymore.
+            let _ = result_tx.send(result);
+            if result == Err(SendError::ChannelClosed) {
+                return Err(SendError::ChannelClosed);
+            }
+        }
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::{Address, UnicastAddress};
+    use crate::asyncs::task;
+    use crate::control::FriendSubscriptionListAdd;
+    use crate::crypto::key::NetKey;
+    use crate::device_state::DeviceState;
+    use crate::friend::FriendSubscriptionList;
+    use crate::lower::{BlockAck, SegO};
+    use crate::mesh::{ElementCount, IVIndex, KeyIndex, NetKeyIndex, SequenceNumber, TTL, U24};
+    use crate::random::Randomizable;
+    use crate::test_util::ManualClock;
 
-                // Check for a valid ack
-                todo!()
+    const TTL_ZERO: TTL = TTL::new_with_flag(0).0;
+    struct Harness {
+        outgoing: Arc<Outgoing>,
+        ack_tx: mpsc::Sender<IncomingPDU<control::Ack>>,
+        bearer_rx: mpsc::Receiver<OutgoingMessage>,
+        stats: StatsCounters,
+        clock: ManualClock,
+    }
+    impl Harness {
+        fn new() -> Self {
+            let mut device_state = DeviceState::new(UnicastAddress::new(0x0001), ElementCount(1));
+            device_state
+                .security_materials_mut()
+                .net_key_map
+                .insert(NetKeyIndex(KeyIndex::new(0)), &NetKey::random_secure());
+            let internals = Arc::new(RwLock::new(StackInternals::new(device_state)));
+            let (ack_tx, ack_rx) = mpsc::channel(4);
+            let (bearer_tx, bearer_rx) = mpsc::channel(64);
+            let stats = StatsCounters::new();
+            let clock = ManualClock::new();
+            let outgoing =
+                Outgoing::new(internals, ack_rx, bearer_tx, stats.clone(), clock.shared());
+            Self {
+                outgoing: Arc::new(outgoing),
+                ack_tx,
+                bearer_rx,
+                stats,
+                clock,
+            }
+        }
+        /// 31 bytes of Friend Subscription List Add to `dst`. That's 4 segments.
+        async fn segments(&self, dst: Address) -> OutgoingSegments<Box<[u8]>> {
+            let msg = FriendSubscriptionListAdd(FriendSubscriptionList {
+                transaction_number: 7,
+                addresses: (0..15).map(|i| Address::from(0xC000 + i)).collect(),
+            });
+            let segments = self
+                .outgoing
+                .internals
+                .read()
+                .await
+                .control_upper(&msg, dst, Some(TTL_ZERO), NetKeyIndex(KeyIndex::new(0)))
+                .expect("valid control message")
+                .into_outgoing_segments();
+            assert_eq!(segments.segments.seg_o(), SegO::new(3));
+            segments
+        }
+        fn send(
+            &self,
+            segments: OutgoingSegments<Box<[u8]>>,
+        ) -> task::JoinHandle<Result<(), SendError>> {
+            let outgoing = self.outgoing.clone();
+            task::spawn(async move { outgoing.send_segments(segments).await })
+        }
+        async fn next_pdu(&mut self) -> OutgoingEncryptedNetworkPDU {
+            match self.bearer_rx.recv().await {
+                Some(OutgoingMessage::Network(outgoing)) => outgoing,
+                None => panic!("bearer channel closed"),
+            }
+        }
+        /// The SegN and Sequence Number of `outgoing`.
+        async fn segment(&self, outgoing: &OutgoingEncryptedNetworkPDU) -> (u8, u32) {
+            let internals = self.outgoing.internals.read().await;
+            let (_, _, pdu) = internals
+                .decrypt_network_pdu(outgoing.pdu.as_ref())
+                .expect("encrypted with our NetKey");
+            let segment = pdu.payload.segmented().expect("segmented PDU");
+            (
+                u8::from(segment.segment_header().seg_n),
+                pdu.header.seq.0.value(),
+            )
+        }
+        /// The SegN and Sequence Number of every segment sent since the last call.
+        async fn sent(&mut self) -> Vec<(u8, u32)> {
+            let mut sent = Vec::new();
+            while let Ok(OutgoingMessage::Network(outgoing)) = self.bearer_rx.try_recv() {
+                sent.push(self.segment(&outgoing).await);
             }
-            // Allow unreachable_code so we can annotate the async result type.
-            #[allow(unreachable_code)]
-            Ok::<(), SendError>(())
-        })
-        .await
-        .ok()
-        .ok_or(SendError::AckTimeout)?
+            sent
+        }
+        /// Waits for the first `count` segments of a transmission (see `Harness::sent`).
+        async fn wait_sent(&mut self, count: usize) -> Vec<(u8, u32)> {
+            let mut sent = Vec::new();
+            for _ in 0..count {
+                let outgoing = self.next_pdu().await;
+                sent.push(self.segment(&outgoing).await);
+            }
+            sent.extend(self.sent().await);
+            sent
+        }
+        /// Acks and waits until `send_segments` handled it (it's waiting on its next timer).
+        async fn ack(&mut self, seq_zero: SeqZero, block_ack: BlockAck) {
+            self.ack_from(UnicastAddress::new(0x0002), seq_zero, block_ack)
+                .await
+        }
+        /// Same as `Harness::ack` but the ack comes from `src`.
+        async fn ack_from(&mut self, src: UnicastAddress, seq_zero: SeqZero, block_ack: BlockAck) {
+            let since = self.clock.timers_started();
+            self.send_ack_from(src, seq_zero, block_ack).await;
+            self.clock.wait_for_timers(since + 1).await;
+        }
+        /// Acks without waiting, for acks that end the transfer.
+        async fn send_ack(&mut self, seq_zero: SeqZero, block_ack: BlockAck) {
+            self.send_ack_from(UnicastAddress::new(0x0002), seq_zero, block_ack)
+                .await
+        }
+        async fn send_ack_from(
+            &mut self,
+            src: UnicastAddress,
+            seq_zero: SeqZero,
+            block_ack: BlockAck,
+        ) {
+            self.ack_tx
+                .send(IncomingPDU {
+                    pdu: control::Ack {
+                        obo: false,
+                        seq_zero,
+                        block_ack,
+                    },
+                    seq: SequenceNumber(U24::new(0x30)),
+                    iv_index: IVIndex(0),
+                    net_key_index: NetKeyIndex(KeyIndex::new(0)),
+                    src,
+                    dst: Address::from(0x0001),
+                    ttl: TTL::new(5),
+                })
+                .await
+                .ok()
+                .expect("send_segments running");
+        }
+    }
+    /// Every segment of a 4 segment message starting at `seq`.
+    fn all_segments(seq: u32) -> Vec<(u8, u32)> {
+        (0..4)
+            .map(|seg_n| (seg_n, seq + u32::from(seg_n)))
+            .collect()
+    }
+    #[tokio::test]
+    async fn test_retransmit_missing_segment() {
+        let mut harness = Harness::new();
+        let segments = harness.segments(Address::from(0x0002)).await;
+        let seq_zero = segments.segments.seq_auth().seq_zero();
+        let first_seq = segments.segments.seq_auth().first_seq.0.value();
+        let send = harness.send(segments);
+        // The first transmission uses the Sequence Numbers reserved for the message.
+        assert_eq!(harness.wait_sent(4).await, all_segments(first_seq));
+        // Segment 2 was lost. It's resent (with a new Sequence Number) but not before the
+        // minimum spacing since the last transmission.
+        harness.ack(seq_zero, BlockAck(0b1011)).await;
+        assert!(harness.sent().await.is_empty());
+        harness.clock.advance(MIN_SEGMENTS_TRANSMIT_SPACING).await;
+        assert_eq!(harness.sent().await, vec![(2, first_seq + 4)]);
+        harness
+            .send_ack(seq_zero, BlockAck::new_all_acked(SegO::new(3)))
+            .await;
+        assert_eq!(send.await.expect("send task panicked"), Ok(()));
+        assert!(harness.sent().await.is_empty());
+        assert_eq!(harness.stats.snapshot().segmented_sent, 1);
+    }
+    #[tokio::test]
+    async fn test_retransmit_timer() {
+        let mut harness = Harness::new();
+        let segments = harness.segments(Address::from(0x0002)).await;
+        let seq_zero = segments.segments.seq_auth().seq_zero();
+        let first_seq = segments.segments.seq_auth().first_seq.0.value();
+        let send = harness.send(segments);
+        assert_eq!(harness.wait_sent(4).await, all_segments(first_seq));
+        // Nothing is resent until the transmission timer (200ms at TTL 0) fires.
+        let interval = segments::segment_transmit_interval(TTL_ZERO);
+        assert_eq!(interval, Duration::from_millis(200));
+        harness
+            .clock
+            .advance(interval - Duration::from_millis(1))
+            .await;
+        assert!(harness.sent().await.is_empty());
+        harness.clock.advance(Duration::from_millis(1)).await;
+        assert_eq!(harness.sent().await, all_segments(first_seq + 4));
+        harness
+            .send_ack(seq_zero, BlockAck::new_all_acked(SegO::new(3)))
+            .await;
+        assert_eq!(send.await.expect("send task panicked"), Ok(()));
+    }
+    #[tokio::test]
+    async fn test_unacknowledged_gives_up() {
+        let mut harness = Harness::new();
+        let send = harness.send(harness.segments(Address::from(0x0002)).await);
+        let interval = segments::segment_transmit_interval(TTL_ZERO);
+        assert_eq!(harness.wait_sent(4).await.len(), 4);
+        for _ in 0..SEGMENT_RETRANSMITS {
+            harness.clock.advance(interval).await;
+            assert_eq!(harness.sent().await.len(), 4);
+        }
+        // The timer fired once more without an ack so the transfer failed.
+        harness.clock.advance(interval).await;
+        assert!(harness.sent().await.is_empty());
+        assert_eq!(
+            send.await.expect("send task panicked"),
+            Err(SendError::Unacknowledged)
+        );
+        assert_eq!(harness.stats.snapshot().segmented_failed, 1);
+    }
+    #[tokio::test]
+    async fn test_canceled_mid_transfer() {
+        let mut harness = Harness::new();
+        let segments = harness.segments(Address::from(0x0002)).await;
+        let seq_zero = segments.segments.seq_auth().seq_zero();
+        let send = harness.send(segments);
+        assert_eq!(harness.wait_sent(4).await.len(), 4);
+        harness.ack(seq_zero, BlockAck(0b0001)).await;
+        harness.clock.advance(MIN_SEGMENTS_TRANSMIT_SPACING).await;
+        assert_eq!(harness.sent().await.len(), 3);
+        // An ack for another transfer doesn't cancel this one.
+        harness
+            .ack(SeqZero::new(u16::from(seq_zero) + 1), BlockAck::cancel())
+            .await;
+        assert!(harness.sent().await.is_empty());
+        // Neither does a cancel from a node the transfer isn't for.
+        harness
+            .ack_from(UnicastAddress::new(0x0003), seq_zero, BlockAck::cancel())
+            .await;
+        assert!(harness.sent().await.is_empty());
+        harness.send_ack(seq_zero, BlockAck::cancel()).await;
+        assert_eq!(
+            send.await.expect("send task panicked"),
+            Err(SendError::Canceled)
+        );
+        // Nothing is retransmitted after the cancel.
+        harness
+            .clock
+            .advance(segments::segment_transmit_interval(TTL_ZERO))
+            .await;
+        assert!(harness.sent().await.is_empty());
+        assert_eq!(harness.stats.snapshot().segmented_failed, 1);
+    }
+    #[tokio::test]
+    async fn test_cancel() {
+        let mut harness = Harness::new();
+        for dst in &[0x0002, 0xC000] {
+            let segments = harness.segments(Address::from(*dst)).await;
+            let seq_zero = segments.segments.seq_auth().seq_zero();
+            let send = harness.send(segments);
+            assert_eq!(harness.wait_sent(4).await.len(), 4);
+            // Other messages aren't affected.
+            assert_eq!(
+                harness
+                    .outgoing
+                    .cancel(SeqZero::new(u16::from(seq_zero) + 1))
+                    .await,
+                Err(SendError::NotInFlight)
+            );
+            harness
+                .outgoing
+                .cancel(seq_zero)
+                .await
+                .expect("cancel channel open");
+            assert_eq!(
+                send.await.expect("send task panicked"),
+                Err(SendError::Aborted)
+            );
+            // Nothing is retransmitted after the cancel.
+            harness
+                .clock
+                .advance(segments::segment_transmit_interval(TTL_ZERO))
+                .await;
+            assert!(harness.sent().await.is_empty());
+        }
+    }
+    #[tokio::test]
+    async fn test_cancel_idle() {
+        let mut harness = Harness::new();
+        let segments = harness.segments(Address::from(0x0002)).await;
+        let seq_zero = segments.segments.seq_auth().seq_zero();
+        // Nothing is sending so none of these wait or get queued for the next transfer.
+        for _ in 0..=CANCEL_CHANNEL_LEN {
+            assert_eq!(
+                harness.outgoing.cancel(seq_zero).await,
+                Err(SendError::NotInFlight)
+            );
+        }
+        let send = harness.send(segments);
+        assert_eq!(harness.wait_sent(4).await.len(), 4);
+        harness
+            .send_ack(seq_zero, BlockAck::new_all_acked(SegO::new(3)))
+            .await;
+        assert_eq!(send.await.expect("send task panicked"), Ok(()));
+        // It's over so it can't be canceled anymore.
+        assert_eq!(
+            harness.outgoing.cancel(seq_zero).await,
+            Err(SendError::NotInFlight)
+        );
+    }
+    #[tokio::test]
+    async fn test_group_segments_repeated() {
+        let mut harness = Harness::new();
+        // Nobody acks segments sent to a group.
+        let send = harness.send(harness.segments(Address::from(0xC000)).await);
+        let mut sent = harness.wait_sent(4).await.len();
+        for _ in 0..SEGMENT_RETRANSMITS {
+            harness
+                .clock
+                .advance(segments::segment_transmit_interval(TTL_ZERO))
+                .await;
+        }
+        assert_eq!(send.await.expect("send task panicked"), Ok(()));
+        sent += harness.sent().await.len();
+        assert_eq!(sent, 4 * (1 + usize::from(SEGMENT_RETRANSMITS)));
+        assert_eq!(harness.stats.snapshot().segmented_sent, 1);
+    }
+    #[tokio::test]
+    async fn test_segments_to_lpn_use_friendship_keys() {
+        use crate::friend::{FriendCounter, FriendshipCredentials, LPNCounter, PollTimeout};
+        use crate::stack::clock::Clock;
+
+        let mut harness = Harness::new();
+        let lpn = UnicastAddress::new(0x0002);
+        let credentials = FriendshipCredentials {
+            lpn_address: lpn,
+            friend_address: UnicastAddress::new(0x0001),
+            lpn_counter: LPNCounter::new(0x0102),
+            friend_counter: FriendCounter::new(0x0304),
+        };
+        let (friendship_keys, managed_flooding) = {
+            let mut internals = harness.outgoing.internals.write().await;
+            internals.friendships_mut().establish(
+                credentials,
+                PollTimeout::MIN,
+                harness.clock.now(),
+            );
+            internals.refresh_nid_index();
+            let net_sm = internals
+                .net_keys()
+                .get_keys(NetKeyIndex(KeyIndex::new(0)))
+                .expect("key inserted by the harness")
+                .tx_key();
+            (
+                credentials.network_keys(net_sm.net_key()),
+                *net_sm.network_keys(),
+            )
+        };
+        let segments = harness.segments(Address::Unicast(lpn)).await;
+        let seq_zero = segments.segments.seq_auth().seq_zero();
+        let send = harness.send(segments);
+        for _ in 0..4 {
+            let outgoing = harness.next_pdu().await;
+            assert!(outgoing
+                .pdu
+                .as_ref()
+                .try_decrypt(&friendship_keys, IVIndex(0))
+                .is_ok());
+            assert!(outgoing
+                .pdu
+                .as_ref()
+                .try_decrypt(&managed_flooding, IVIndex(0))
+                .is_err());
+        }
+        assert!(harness.sent().await.is_empty());
+        harness
+            .send_ack(seq_zero, BlockAck::new_all_acked(SegO::new(3)))
+            .await;
+        assert_eq!(send.await.expect("send task panicked"), Ok(()));
     }
 }
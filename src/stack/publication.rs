@@ -0,0 +1,336 @@
+//! Model publication. Models registered with a `Publisher` publish their current status every
+//! Publish Period of their `ModelPublishInfo` (stored in `DeviceState` and set through the
+//! Config Server) and each published message is sent again as many times as its Publish
+//! Retransmit says. Publishing to the unassigned address (no `ModelPublishInfo`) or with a
+//! disabled Publish Period stops periodic publishing.
+use crate::access::ModelIdentifier;
+use crate::device_state::Models;
+use crate::foundation::publication::ModelPublishInfo;
+use crate::mesh::ElementIndex;
+use crate::timestamp::Timestamp;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+/// A model that publishes its state.
+pub trait Publisher: Send {
+    /// Returns the current status of the model packed as an Access message (opcode and
+    /// parameters) or `None` to skip this publication.
+    fn status(&mut self) -> Option<Box<[u8]>>;
+    /// Time between periodic publications for the model's Publish Period (`period`). Models
+    /// like the Health Server publish faster while they have something to report.
+    fn publish_period(&mut self, period: Duration) -> Duration {
+        period
+    }
+}
+impl<F: FnMut() -> Option<Box<[u8]>> + Send> Publisher for F {
+    fn status(&mut self) -> Option<Box<[u8]>> {
+        self()
+    }
+}
+/// A message to send with the publish settings of `model_identifier` (see
+/// [`StackInternals::publish_message`](crate::stack::StackInternals::publish_message)).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DuePublication {
+    pub element_index: ElementIndex,
+    pub model_identifier: ModelIdentifier,
+    /// Packed Access message (opcode and parameters).
+    pub payload: Box<[u8]>,
+}
+struct Entry {
+    element_index: ElementIndex,
+    publisher: Box<dyn Publisher>,
+    /// Publish Period the next publication was scheduled with. A different period restarts the
+    /// schedule.
+    period: Option<Duration>,
+    next_publish: Option<Timestamp>,
+}
+struct Retransmit {
+    due: Timestamp,
+    interval: Duration,
+    remaining: u8,
+    publication: DuePublication,
+}
+/// Every registered `Publisher` and the retransmissions still to send.
+#[derive(Default)]
+pub struct Publications {
+    entries: BTreeMap<ModelIdentifier, Entry>,
+    retransmits: Vec<Retransmit>,
+}
+impl Publications {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Registers `publisher` for the model `model_identifier` on the element at `element_index`.
+    /// The first periodic publication is one Publish Period after it's first polled. Returns
+    /// `false` (and replaces the old one) if the model already had a `Publisher`.
+    pub fn add(
+        &mut self,
+        element_index: ElementIndex,
+        model_identifier: ModelIdentifier,
+        publisher: Box<dyn Publisher>,
+    ) -> bool {
+        self.retransmits
+            .retain(|r| r.publication.model_identifier != model_identifier);
+        self.entries
+            .insert(
+                model_identifier,
+                Entry {
+                    element_index,
+                    publisher,
+                    period: None,
+                    next_publish: None,
+                },
+            )
+            .is_none()
+    }
+    /// Unregisters the `Publisher` of `model_identifier` and drops its pending retransmissions.
+    /// Returns `false` if it wasn't registered.
+    pub fn remove(&mut self, model_identifier: &ModelIdentifier) -> bool {
+        self.retransmits
+            .retain(|r| &r.publication.model_identifier != model_identifier);
+        self.entries.remove(model_identifier).is_some()
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    fn publish_info<'a>(
+        models: &'a Models,
+        model_identifier: &ModelIdentifier,
+    ) -> Option<&'a ModelPublishInfo> {
+        models
+            .get(model_identifier)?
+            .publish
+            .as_ref()
+            .filter(|publish| publish.address.is_assigned())
+    }
+    fn publish(
+        retransmits: &mut Vec<Retransmit>,
+        model_identifier: ModelIdentifier,
+        entry: &mut Entry,
+        publish: &ModelPublishInfo,
+        now: Timestamp,
+    ) -> Option<DuePublication> {
+        let publication = DuePublication {
+            element_index: entry.element_index,
+            model_identifier,
+            payload: entry.publisher.status()?,
+        };
+        let remaining = publish.retransmit.count();
+        if remaining > 0 {
+            let interval = publish.retransmit.interval();
+            retransmits.push(Retransmit {
+                due: now + interval,
+                interval,
+                remaining,
+                publication: publication.clone(),
+            });
+        }
+        Some(publication)
+    }
+    /// Publishes the current status of `model_identifier` right away (after its state changed).
+    /// The periodic publications carry on as scheduled. Returns `None` if the model doesn't
+    /// have a `Publisher` or isn't publishing.
+    pub fn publish_now(
+        &mut self,
+        model_identifier: &ModelIdentifier,
+        models: &Models,
+        now: Timestamp,
+    ) -> Option<DuePublication> {
+        let publish = Self::publish_info(models, model_identifier)?;
+        let entry = self.entries.get_mut(model_identifier)?;
+        Self::publish(
+            &mut self.retransmits,
+            *model_identifier,
+            entry,
+            publish,
+            now,
+        )
+    }
+    /// Returns every publication and retransmission due at `now` with the publish settings in
+    /// `models`. Models that stopped publishing have their schedule and pending
+    /// retransmissions dropped.
+    pub fn poll(&mut self, models: &Models, now: Timestamp) -> Vec<DuePublication> {
+        let mut due = Vec::new();
+        self.retransmits
+            .retain(|r| Self::publish_info(models, &r.publication.model_identifier).is_some());
+        for retransmit in self.retransmits.iter_mut().filter(|r| now >= r.due) {
+            due.push(retransmit.publication.clone());
+            retransmit.remaining -= 1;
+            retransmit.due = now + retransmit.interval;
+        }
+        self.retransmits.retain(|r| r.remaining > 0);
+        for (model_identifier, entry) in self.entries.iter_mut() {
+            let publish = match Self::publish_info(models, model_identifier) {
+                Some(publish) if !publish.period.is_disabled() => publish,
+                _ => {
+                    entry.period = None;
+                    entry.next_publish = None;
+                    continue;
+                }
+            };
+            let period = entry.publisher.publish_period(publish.period.to_duration());
+            if entry.period != Some(period) {
+                entry.period = Some(period);
+                entry.next_publish = Some(now + period);
+                continue;
+            }
+            match entry.next_publish {
+                Some(next_publish) if now >= next_publish => (),
+                _ => continue,
+            }
+            entry.next_publish = Some(now + period);
+            due.extend(Self::publish(
+                &mut self.retransmits,
+                *model_identifier,
+                entry,
+                publish,
+                now,
+            ));
+        }
+        due
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Address;
+    use crate::device_state::ModelInfo;
+    use crate::foundation::publication::{PublishPeriod, PublishRetransmit, StepResolution, Steps};
+    use crate::mesh::{AppKeyIndex, KeyIndex, ModelID};
+    use crate::timestamp::TimestampTrait;
+
+    fn publish_info(address: Address, period_secs: u8, retransmit: u8) -> ModelPublishInfo {
+        ModelPublishInfo {
+            address,
+            app_key_index: AppKeyIndex(KeyIndex::new(0)),
+            credential_flag: false,
+            ttl: None,
+            period: PublishPeriod::new(StepResolution::Second1, Steps::new(period_secs)),
+            retransmit: PublishRetransmit::from(retransmit),
+        }
+    }
+    fn models(model_identifier: ModelIdentifier, publish: ModelPublishInfo) -> Models {
+        let mut models = Models::default();
+        let mut info = ModelInfo::default();
+        info.set_publish(publish);
+        models.insert(model_identifier, info);
+        models
+    }
+    fn counter() -> Box<dyn Publisher> {
+        let mut count = 0_u8;
+        Box::new(move || {
+            count += 1;
+            Some(Box::from(&[0x82_u8, 0x04, count][..]))
+        })
+    }
+    #[test]
+    fn test_periodic_publication() {
+        let model = ModelIdentifier::new_sig(ModelID(0x1000));
+        let mut publications = Publications::new();
+        assert!(publications.add(ElementIndex(0), model, counter()));
+        let second = Duration::from_secs(1);
+        let start = Timestamp::now();
+        let mut models = models(model, publish_info(Address::from(0xC001), 2, 0));
+
+        // The first publication is one period after the schedule starts.
+        assert!(publications.poll(&models, start).is_empty());
+        assert!(publications.poll(&models, start + second).is_empty());
+        let due = publications.poll(&models, start + second * 2);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].element_index, ElementIndex(0));
+        assert_eq!(due[0].model_identifier, model);
+        assert_eq!(&due[0].payload[..], &[0x82, 0x04, 1][..]);
+        assert!(publications.poll(&models, start + second * 3).is_empty());
+        assert_eq!(publications.poll(&models, start + second * 4).len(), 1);
+
+        // Publishing on a state change doesn't move the periodic schedule.
+        let now = publications
+            .publish_now(&model, &models, start + second * 5)
+            .expect("publishing");
+        assert_eq!(&now.payload[..], &[0x82, 0x04, 3][..]);
+        assert_eq!(publications.poll(&models, start + second * 6).len(), 1);
+
+        // The unassigned address disables publication.
+        models
+            .get_mut(&model)
+            .unwrap()
+            .set_publish(publish_info(Address::Unassigned, 2, 0));
+        assert!(publications.poll(&models, start + second * 8).is_empty());
+        assert_eq!(
+            publications.publish_now(&model, &models, start + second * 8),
+            None
+        );
+    }
+    #[test]
+    fn test_retransmit() {
+        let model = ModelIdentifier::new_sig(ModelID(0x1000));
+        let mut publications = Publications::new();
+        publications.add(ElementIndex(1), model, counter());
+        let start = Timestamp::now();
+        // Count 2 with an interval of 4 steps (250ms).
+        let publish = publish_info(Address::from(0xC001), 1, 2 | 4 << 3);
+        let interval = publish.retransmit.interval();
+        assert_eq!(interval, Duration::from_millis(250));
+        let models = models(model, publish);
+
+        let first = publications
+            .publish_now(&model, &models, start)
+            .expect("publishing");
+        assert!(publications
+            .poll(&models, start + interval - Duration::from_millis(1))
+            .is_empty());
+        assert_eq!(
+            publications.poll(&models, start + interval),
+            vec![first.clone()]
+        );
+        assert_eq!(
+            publications.poll(&models, start + interval * 2),
+            vec![first.clone()]
+        );
+        // Only retransmitted twice (the periodic publication is only due after a second).
+        assert!(publications.poll(&models, start + interval * 3).is_empty());
+
+        // Removing the publisher drops its retransmissions.
+        publications.publish_now(&model, &models, start + interval * 3);
+        assert!(publications.remove(&model));
+        assert!(!publications.remove(&model));
+        assert!(publications.poll(&models, start + interval * 4).is_empty());
+    }
+    #[test]
+    fn test_publisher_period() {
+        /// Publishes twice as often once `fast` is set.
+        struct Fast(alloc::sync::Arc<core::sync::atomic::AtomicBool>);
+        impl Publisher for Fast {
+            fn status(&mut self) -> Option<Box<[u8]>> {
+                Some(Box::from(&[0x04_u8][..]))
+            }
+            fn publish_period(&mut self, period: Duration) -> Duration {
+                if self.0.load(core::sync::atomic::Ordering::SeqCst) {
+                    period / 2
+                } else {
+                    period
+                }
+            }
+        }
+        let model = ModelIdentifier::new_sig(ModelID(0x1000));
+        let fast = alloc::sync::Arc::new(core::sync::atomic::AtomicBool::new(false));
+        let mut publications = Publications::new();
+        publications.add(ElementIndex(0), model, Box::new(Fast(fast.clone())));
+        let second = Duration::from_secs(1);
+        let start = Timestamp::now();
+        let models = models(model, publish_info(Address::from(0xC001), 2, 0));
+
+        assert!(publications.poll(&models, start).is_empty());
+        assert_eq!(publications.poll(&models, start + second * 2).len(), 1);
+        // A shorter period restarts the schedule with it.
+        fast.store(true, core::sync::atomic::Ordering::SeqCst);
+        assert!(publications.poll(&models, start + second * 3).is_empty());
+        assert_eq!(publications.poll(&models, start + second * 4).len(), 1);
+        assert_eq!(publications.poll(&models, start + second * 5).len(), 1);
+    }
+}
@@ -6,26 +6,45 @@ use crate::lower::{BlockAck, SegmentedPDU, SeqAuth, SeqZero};
 use crate::mesh::{IVIndex, NetKeyIndex, SequenceNumber, TTL};
 use crate::reassembler;
 use crate::reassembler::LowerHeader;
+use crate::stack::clock::{SharedClock, SystemClock};
 use crate::stack::messages::{
     IncomingNetworkPDU, IncomingTransportPDU, OutgoingLowerTransportMessage,
-    OutgoingUpperTransportMessage,
 };
+use crate::stack::stats::{Counter, StatsCounters};
+use crate::stack::watchdog::TaskWatchdog;
+use crate::stack::RecvError;
+use crate::timestamp::{Timestamp, TimestampTrait};
 use crate::{control, lower, segmenter};
 use alloc::collections::btree_map::Entry;
 use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::convert::{TryFrom, TryInto};
 use core::fmt::{Debug, Error, Formatter};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 #[derive(Copy, Clone, PartialOrd, PartialEq, Ord, Eq, Hash, Debug)]
 pub struct SegmentsConversionError(());
 
 #[derive(Copy, Clone, PartialOrd, PartialEq, Ord, Eq, Hash, Debug)]
 pub enum AckError {
+    /// The ack to a unicast transfer doesn't come from its destination.
+    BadSrc,
     BadDst,
     BadIVIndex,
     BadSeqZero,
     BadBlockAck,
 }
+/// What an `Ack` that matches an outgoing transfer means for it.
+#[derive(Copy, Clone, PartialOrd, PartialEq, Ord, Eq, Hash, Debug)]
+pub enum AckEvent {
+    /// The `BlockAck` acknowledges segments that weren't acknowledged before.
+    New,
+    /// The `BlockAck` is an old (or duplicate) ack and can be ignored.
+    Old,
+    /// The receiver canceled the transfer (`BlockAck::cancel()`).
+    Canceled,
+}
 
 pub struct OutgoingSegments<Storage: AsRef<[u8]>> {
     pub segments: segmenter::UpperSegmenter<Storage>,
@@ -36,7 +55,9 @@ pub struct OutgoingSegments<Storage: AsRef<[u8]>> {
     pub ttl: Option<TTL>,
 }
 impl<Storage: AsRef<[u8]>> OutgoingSegments<Storage> {
-    pub fn is_new_ack(&self, ack: IncomingPDU<control::Ack>) -> Result<bool, AckError> {
+    /// Checks if `ack` belongs to this transfer and returns if it's new, old or a cancel. Acks to
+    /// a unicast transfer only count if they come from its destination.
+    pub fn ack_event(&self, ack: IncomingPDU<control::Ack>) -> Result<AckEvent, AckError> {
         if ack.pdu.seq_zero != self.segments.seq_auth().seq_zero() {
             Err(AckError::BadSeqZero)
         } else if ack.iv_index != self.segments.seq_auth().iv_index {
@@ -45,10 +66,23 @@ impl<Storage: AsRef<[u8]>> OutgoingSegments<Storage> {
             Err(AckError::BadBlockAck)
         } else if !ack.dst.unicast().map_or(false, |u| u == self.src) {
             Err(AckError::BadDst)
+        } else if self.dst.unicast().map_or(false, |dst| dst != ack.src) {
+            // Otherwise anyone could cancel the transfer.
+            Err(AckError::BadSrc)
+        } else if ack.pdu.block_ack == BlockAck::cancel() {
+            Ok(AckEvent::Canceled)
+        } else if self.block_ack.is_new(ack.pdu.block_ack) {
+            Ok(AckEvent::New)
         } else {
-            Ok(self.block_ack.is_new(ack.pdu.block_ack))
+            Ok(AckEvent::Old)
         }
     }
+    pub fn is_new_ack(&self, ack: IncomingPDU<control::Ack>) -> Result<bool, AckError> {
+        Ok(self.ack_event(ack)? == AckEvent::New)
+    }
+    pub fn is_cancel_ack(&self, ack: IncomingPDU<control::Ack>) -> Result<bool, AckError> {
+        Ok(self.ack_event(ack)? == AckEvent::Canceled)
+    }
     pub fn seg_to_outgoing(
         &self,
         seg: SegmentedPDU,
@@ -67,6 +101,18 @@ impl<Storage: AsRef<[u8]>> OutgoingSegments<Storage> {
             net_key_index: self.net_key_index,
         }
     }
+    /// Returns every segment that isn't acked yet (every `seg_n` in `0..=seg_o` before the
+    /// first ack) as an `OutgoingLowerTransportMessage`. Each segment takes the next sequence
+    /// number from `next_seq`. If it returns `None`, the seq is allocated when the segment is
+    /// sent.
+    pub fn segments_iter<'a>(
+        &'a self,
+        mut next_seq: impl FnMut() -> Option<SequenceNumber> + 'a,
+    ) -> impl Iterator<Item = OutgoingLowerTransportMessage> + 'a {
+        self.segments
+            .iter(self.block_ack)
+            .map(move |seg| self.seg_to_outgoing(seg, next_seq()))
+    }
 }
 pub struct IncomingSegments {
     context: reassembler::Context,
@@ -75,21 +121,25 @@ pub struct IncomingSegments {
     segs_dst: Address,
     net_key_index: NetKeyIndex,
     ack_ttl: Option<TTL>,
+    /// TTL of the last segment inserted. Drives the ack timer.
+    ttl: TTL,
+    incomplete_timeout: time::Duration,
 }
 impl IncomingSegments {
     pub fn new(first_seg: IncomingPDU<lower::SegmentedPDU>) -> Option<Self> {
         let seg_header = first_seg.pdu.segment_header();
         if u8::from(seg_header.seg_n) == 0 {
-            let lower_header = match first_seg.pdu {
-                SegmentedPDU::Access(a) => LowerHeader::AID(a.aid()),
-                SegmentedPDU::Control(c) => LowerHeader::ControlOpcode(c.opcode()),
-            };
+            let lower_header = Self::lower_header(&first_seg.pdu);
+            let mut context = reassembler::Context::new(reassembler::ContextHeader::new(
+                lower_header,
+                seg_header.seg_o,
+                first_seg.pdu.szmic().unwrap_or(false),
+            ));
+            context
+                .insert_data(seg_header.seg_n, first_seg.pdu.seg_data())
+                .ok()?;
             Some(IncomingSegments {
-                context: reassembler::Context::new(reassembler::ContextHeader::new(
-                    lower_header,
-                    seg_header.seg_o,
-                    first_seg.pdu.szmic().unwrap_or(false),
-                )),
+                context,
                 segs_src: first_seg.src,
                 segs_dst: first_seg.dst,
                 seq_auth: SeqAuth::from_seq_zero(
@@ -103,15 +153,13 @@ impl IncomingSegments {
                 } else {
                     None
                 },
+                ttl: first_seg.ttl,
+                incomplete_timeout: INCOMPLETE_TIMEOUT,
             })
         } else {
             None
         }
     }
-    pub const fn recv_timeout(&self) -> time::Duration {
-        // As Per the Bluetooth Mesh Spec.
-        time::Duration::from_secs(10)
-    }
     pub fn is_control(&self) -> bool {
         !self.is_access()
     }
@@ -125,6 +173,61 @@ impl IncomingSegments {
     pub fn seq_auth(&self) -> SeqAuth {
         self.seq_auth
     }
+    /// Returns the `BlockAck` of the segments received so far.
+    pub fn block_ack(&self) -> BlockAck {
+        self.context.header().block_ack()
+    }
+    /// Acknowledgment timer (see [`ack_timeout`]) for the TTL of the last segment received.
+    /// Governs when the segments received so far get acked. It never aborts the reassembly.
+    pub fn ack_timeout(&self) -> time::Duration {
+        ack_timeout(self.ttl)
+    }
+    /// Incomplete timer. Governs when the reassembly is abandoned if no new segment arrives.
+    /// Doesn't depend on the TTL and defaults to `INCOMPLETE_TIMEOUT`.
+    pub fn incomplete_timeout(&self) -> time::Duration {
+        self.incomplete_timeout
+    }
+    /// # Panics
+    /// Panics if `incomplete_timeout` is zero.
+    pub fn set_incomplete_timeout(&mut self, incomplete_timeout: time::Duration) {
+        assert_ne!(
+            incomplete_timeout,
+            time::Duration::from_secs(0),
+            "zero incomplete_timeout"
+        );
+        self.incomplete_timeout = incomplete_timeout;
+    }
+    fn lower_header(pdu: &SegmentedPDU) -> LowerHeader {
+        match pdu {
+            SegmentedPDU::Access(a) => LowerHeader::AID(a.aid()),
+            SegmentedPDU::Control(c) => LowerHeader::ControlOpcode(c.opcode()),
+        }
+    }
+    /// Inserts the segment's data into the reassembly context. The segment's AID/Opcode, SZMIC
+    /// and SegO must match the first segment's (`ReassembleError::HeaderMismatch` otherwise).
+    pub fn insert(
+        &mut self,
+        seg: &IncomingPDU<lower::SegmentedPDU>,
+    ) -> Result<(), ReassemblyError> {
+        let seg_header = seg.pdu.segment_header();
+        self.context
+            .header()
+            .check_segment(
+                Self::lower_header(&seg.pdu),
+                seg_header.seg_o,
+                seg.pdu.szmic().unwrap_or(false),
+            )
+            .and_then(|()| {
+                self.context
+                    .insert_data(seg_header.seg_n, seg.pdu.seg_data())
+            })
+            .map_err(ReassemblyError::Reassemble)?;
+        self.ttl = seg.ttl;
+        Ok(())
+    }
+    /// Finishes reassembling the segments into an `IncomingTransportPDU`. For Segmented Control
+    /// PDUs, the `ControlOpcode` from the segments is kept in the `upper::PDU::Control` payload
+    /// (see [`IncomingTransportPDU::control_opcode`]).
     pub fn finish(self) -> Result<IncomingTransportPDU<Box<[u8]>>, Self> {
         if self.is_ready() {
             let seq_auth = self.seq_auth();
@@ -204,90 +307,119 @@ pub struct IncomingPDU<PDU: Copy + Clone> {
     pub dst: Address,
     pub ttl: TTL,
 }
-impl<PDU: Copy + Clone + Debug> Debug for &IncomingPDU<PDU> {
+impl<PDU: Copy + Clone + Debug> Debug for IncomingPDU<PDU> {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
         f.debug_struct("IncomingPDU")
             .field("pdu", &self.pdu)
+            .field("seq", &self.seq)
             .field("iv_index", &self.iv_index)
             .field("net_key_index", &self.net_key_index)
             .field("src", &self.src)
             .field("dst", &self.dst)
+            .field("ttl", &self.ttl)
             .finish()
     }
 }
+/// Hands `ack` to the segmented transfer in flight (see `Outgoing::send_segments`) without
+/// waiting. Acks are only read while a transfer is in flight so stray and late ones are dropped
+/// once `tx_ack` is full (`Outgoing::send_segments` drops the rest before it starts). Returns
+/// `false` if `ack` was dropped.
+pub fn forward_ack(
+    tx_ack: &mut mpsc::Sender<IncomingPDU<control::Ack>>,
+    ack: IncomingPDU<control::Ack>,
+) -> Result<bool, RecvError> {
+    match tx_ack.try_send(ack) {
+        Ok(()) => Ok(true),
+        Err(mpsc::error::TrySendError::Full(_)) => Ok(false),
+        Err(mpsc::error::TrySendError::Closed(_)) => Err(RecvError::ChannelClosed),
+    }
+}
 #[derive(Copy, Clone, Debug)]
 pub enum SegmentEvent {
     IncomingSegment(IncomingPDU<lower::SegmentedPDU>),
     IncomingAck(IncomingPDU<control::Ack>),
 }
-pub struct Segments<Storage: AsRef<[u8]> + AsMut<[u8]> + Send + 'static> {
-    send_task: task::JoinHandle<Result<(), SegmentError>>,
-    incoming_events_tx: mpsc::Sender<IncomingPDU<control::Ack>>,
-    outgoing_queue: mpsc::Sender<OutgoingUpperTransportMessage<Storage>>,
-}
-pub enum SegmentError {
-    ChannelClosed,
+/// How many times the segment transmission timer may resend the unacked segments without a new
+/// ack before the transfer fails. Messages to group or virtual addresses are never acked so
+/// their segments are just sent this many extra times.
+pub const SEGMENT_RETRANSMITS: u8 = 4;
+/// How long to wait for all the segments to be acked before giving up.
+pub const SEGMENTS_SEND_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+/// Minimum time between two transmissions of the same segmented message. Partial acks trigger a
+/// retransmit right away but never sooner than this after the last one.
+pub const MIN_SEGMENTS_TRANSMIT_SPACING: time::Duration = time::Duration::from_millis(50);
+/// Segment transmission timer (`200 + 50 * TTL` milliseconds). If no new ack arrives before it
+/// fires, every unacked segment is sent again.
+pub fn segment_transmit_interval(ttl: TTL) -> time::Duration {
+    time::Duration::from_millis(200 + 50 * u64::from(u8::from(ttl)))
 }
-impl<Storage: AsRef<[u8]> + AsMut<[u8]> + Send + 'static> Segments<Storage> {
-    pub async fn feed_ack(&mut self, ack: IncomingPDU<control::Ack>) -> Result<(), SegmentError> {
-        self.incoming_events_tx
-            .send(ack)
-            .await
-            .ok()
-            .ok_or(SegmentError::ChannelClosed)
-    }
-    pub fn new(
-        channel_capacity: usize,
-        outgoing_pdus: mpsc::Sender<OutgoingLowerTransportMessage>,
-    ) -> Self {
-        let (ack_tx, ack_rx) = mpsc::channel(channel_capacity);
-        let (queue_tx, queue_rx) = mpsc::channel(channel_capacity);
-        Self {
-            send_task: task::spawn(Self::send_loop(ack_rx, queue_rx, outgoing_pdus)),
-            incoming_events_tx: ack_tx,
-            outgoing_queue: queue_tx,
-        }
-    }
-    async fn send_loop(
-        mut ack_rx: mpsc::Receiver<IncomingPDU<control::Ack>>,
-        mut queue_rx: mpsc::Receiver<OutgoingUpperTransportMessage<Storage>>,
-        mut outgoing_tx: mpsc::Sender<OutgoingLowerTransportMessage>,
-    ) -> Result<(), SegmentError> {
-        loop {
-            let next = queue_rx.recv().await.ok_or(SegmentError::ChannelClosed)?;
-            // Try Sending the PDU
-            let _send_result = Self::send(next, &mut outgoing_tx, &mut ack_rx);
-        }
-    }
-    async fn send(
-        pdu: OutgoingUpperTransportMessage<Storage>,
-        _outgoing_tx: &mut mpsc::Sender<OutgoingLowerTransportMessage>,
-        _ack_rx: &mut mpsc::Receiver<IncomingPDU<control::Ack>>,
-    ) -> Result<(), SegmentError> {
-        let _segments = OutgoingSegments {
-            segments: segmenter::UpperSegmenter::new(
-                pdu.upper_pdu,
-                SeqAuth::new(pdu.seq.start(), pdu.iv_index),
-            ),
-            block_ack: BlockAck::default(),
-            net_key_index: pdu.net_key_index,
-            src: pdu.src,
-            dst: pdu.dst,
-            ttl: pdu.ttl,
-        };
-        todo!()
-    }
+/// Acknowledgment timer (`150 + 50 * TTL` milliseconds, `TTL` being the received segment's).
+/// Started by a new segment for a unicast destination. When it fires, the receiver acks every
+/// segment received so far so the sender only retransmits the missing ones.
+pub fn ack_timeout(ttl: TTL) -> time::Duration {
+    time::Duration::from_millis(150 + 50 * u64::from(u8::from(ttl)))
 }
-
 pub struct ReassemblerHandle {
     pub src: UnicastAddress,
     pub seq_zero: SeqZero,
     pub sender: mpsc::Sender<IncomingPDU<lower::SegmentedPDU>>,
-    pub handle: task::JoinHandle<Result<IncomingTransportPDU<Box<[u8]>>, ReassemblyError>>,
+    pub handle: task::JoinHandle<Result<(), ReassemblyError>>,
+    /// Counts up with every new context so the smallest one is the oldest.
+    pub context_id: u64,
+    /// Alive until the context is done (reassembled, timed out or canceled).
+    pub watchdog: TaskWatchdog,
+}
+/// Reports every `BlockAck` the reassembler sends (see `Reassembler::set_ack_observer`). Does
+/// nothing without the `test-util` feature.
+#[derive(Clone, Default)]
+struct AckObserver(#[cfg(any(test, feature = "test-util"))] Option<mpsc::Sender<BlockAck>>);
+impl AckObserver {
+    #[cfg(any(test, feature = "test-util"))]
+    fn observe(&mut self, ack: BlockAck) {
+        if let Some(observer) = self.0.as_mut() {
+            // Instrumentation never holds up reassembly. A full or closed observer misses acks.
+            let _ = observer.try_send(ack);
+        }
+    }
+    #[cfg(not(any(test, feature = "test-util")))]
+    fn observe(&mut self, _ack: BlockAck) {}
+}
+/// Number of reassembly contexts still running (see [`Reassembler::inflight_counter`]).
+#[derive(Clone, Debug, Default)]
+pub struct InflightCounter(Arc<AtomicUsize>);
+impl InflightCounter {
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+    /// Counts one more context until the returned `InflightGuard` is dropped.
+    fn enter(&self) -> InflightGuard {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        InflightGuard(self.0.clone())
+    }
+}
+/// Counts a running context in an `InflightCounter` until dropped.
+struct InflightGuard(Arc<AtomicUsize>);
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 pub struct Reassembler {
     incoming_channels: BTreeMap<(UnicastAddress, lower::SeqZero), ReassemblerHandle>,
     outgoing_pdus: mpsc::Sender<OutgoingLowerTransportMessage>,
+    finished_pdus: mpsc::Sender<IncomingTransportPDU<Box<[u8]>>>,
+    channel_len: usize,
+    incomplete_timeout: time::Duration,
+    stats: StatsCounters,
+    ack_observer: AckObserver,
+    max_inflight: usize,
+    next_context_id: u64,
+    inflight: InflightCounter,
+    /// Messages reassembled in the last `OBSOLETE_TIMEOUT` and when to forget them.
+    completed: BTreeMap<(UnicastAddress, lower::SeqZero), (SeqAuth, Timestamp)>,
+    completed_tx: mpsc::Sender<((UnicastAddress, lower::SeqZero), SeqAuth)>,
+    completed_rx: mpsc::Receiver<((UnicastAddress, lower::SeqZero), SeqAuth)>,
+    clock: SharedClock,
 }
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
 pub enum ReassemblyError {
@@ -297,19 +429,217 @@ pub enum ReassemblyError {
     ChannelClosed,
     Reassemble(reassembler::ReassembleError),
 }
+/// Default number of segments queued for each reassembly context. Each queued segment is an
+/// `IncomingPDU<SegmentedPDU>` (a few dozen bytes) so the worst case memory is about
+/// `channel_len * inflight contexts` of those.
 pub const REASSEMBLER_CHANNEL_LEN: usize = 8;
+/// Default capacity of the ack and message queues feeding outgoing segmented transfers (see
+/// `Outgoing::segments_loop`).
+pub const SEGMENTS_CHANNEL_LEN: usize = 8;
+/// Default incomplete timer (as per the Bluetooth Mesh Spec). A segmented message is dropped if
+/// no new segment for it is received for this long.
+pub const INCOMPLETE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+/// Default maximum number of segmented messages reassembled at once. Every context is a task
+/// and a channel so this bounds how much a peer can make us allocate by sending first segments.
+pub const MAX_REASSEMBLIES: usize = 16;
+/// How long a reassembled message is remembered. Segments of it arriving in that time (the
+/// sender missed our final ack) are acked again instead of starting a new reassembly.
+pub const OBSOLETE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
 impl Reassembler {
-    pub fn new(outgoing_pdus: mpsc::Sender<OutgoingLowerTransportMessage>) -> Self {
+    /// Creates a new `Reassembler`. Acks are sent out through `outgoing_pdus` and reassembled
+    /// PDUs are sent through `finished_pdus`. Uses `REASSEMBLER_CHANNEL_LEN` for each context
+    /// and the `INCOMPLETE_TIMEOUT`.
+    pub fn new(
+        outgoing_pdus: mpsc::Sender<OutgoingLowerTransportMessage>,
+        finished_pdus: mpsc::Sender<IncomingTransportPDU<Box<[u8]>>>,
+    ) -> Self {
+        Self::with_channel_len(outgoing_pdus, finished_pdus, REASSEMBLER_CHANNEL_LEN)
+    }
+    /// Same as `Reassembler::new` but queues up to `channel_len` segments for each reassembly
+    /// context. If a context's queue is full, `feed_pdu` waits (blocking the caller's loop)
+    /// until the context catches up.
+    ///
+    /// # Panics
+    /// Panics if `channel_len == 0`.
+    pub fn with_channel_len(
+        outgoing_pdus: mpsc::Sender<OutgoingLowerTransportMessage>,
+        finished_pdus: mpsc::Sender<IncomingTransportPDU<Box<[u8]>>>,
+        channel_len: usize,
+    ) -> Self {
+        assert_ne!(channel_len, 0, "zero reassembler channel_len");
+        let (completed_tx, completed_rx) = mpsc::channel(MAX_REASSEMBLIES);
         Self {
             incoming_channels: BTreeMap::new(),
             outgoing_pdus,
+            finished_pdus,
+            channel_len,
+            incomplete_timeout: INCOMPLETE_TIMEOUT,
+            stats: StatsCounters::new(),
+            ack_observer: AckObserver::default(),
+            max_inflight: MAX_REASSEMBLIES,
+            next_context_id: 0,
+            inflight: InflightCounter::default(),
+            completed: BTreeMap::new(),
+            completed_tx,
+            completed_rx,
+            clock: SystemClock::shared(),
+        }
+    }
+    /// Reports the `BlockAck` of every ack sent (progressive, final and cancel acks) through
+    /// `observer` so tests can check when and what the receiver acks. Acks are dropped if
+    /// `observer` is full. Only affects contexts started after this call.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn set_ack_observer(&mut self, observer: mpsc::Sender<BlockAck>) {
+        self.ack_observer = AckObserver(Some(observer));
+    }
+    /// Counts acks sent and segmented messages received/failed in `stats` instead of the
+    /// `Reassembler`'s own (unshared) counters.
+    pub fn set_stats(&mut self, stats: StatsCounters) {
+        self.stats = stats;
+    }
+    /// Times the ack and incomplete timers with `clock` instead of the system clock. Only
+    /// affects contexts started after this call.
+    pub fn set_clock(&mut self, clock: SharedClock) {
+        self.clock = clock;
+    }
+    /// Sets how long a reassembly context waits for its next segment before giving up on the
+    /// message (the incomplete timer). Only affects contexts started after this call.
+    ///
+    /// # Panics
+    /// Panics if `incomplete_timeout` is zero.
+    pub fn set_incomplete_timeout(&mut self, incomplete_timeout: time::Duration) {
+        assert_ne!(
+            incomplete_timeout,
+            time::Duration::from_secs(0),
+            "zero incomplete_timeout"
+        );
+        self.incomplete_timeout = incomplete_timeout;
+    }
+    /// Sets how many segmented messages can be reassembled at once. When a new message would go
+    /// over `max_inflight`, the oldest context is canceled (with a cancel ack for unicast
+    /// destinations) to make room.
+    ///
+    /// # Panics
+    /// Panics if `max_inflight == 0`.
+    pub fn set_max_inflight(&mut self, max_inflight: usize) {
+        assert_ne!(max_inflight, 0, "zero max_inflight");
+        self.max_inflight = max_inflight;
+    }
+    pub fn max_inflight(&self) -> usize {
+        self.max_inflight
+    }
+    pub fn channel_len(&self) -> usize {
+        self.channel_len
+    }
+    pub fn incomplete_timeout(&self) -> time::Duration {
+        self.incomplete_timeout
+    }
+    /// Returns the number of segmented messages currently being reassembled.
+    pub fn inflight(&self) -> usize {
+        self.inflight.get()
+    }
+    /// Returns a handle to read `Reassembler::inflight` from anywhere without access to the
+    /// `Reassembler`.
+    pub fn inflight_counter(&self) -> InflightCounter {
+        self.inflight.clone()
+    }
+    /// Drops the handles of every context that's done.
+    fn remove_finished(&mut self) {
+        let finished = self
+            .incoming_channels
+            .iter()
+            .filter(|(_, context)| !context.watchdog.is_alive())
+            .map(|(&key, _)| key)
+            .collect::<Vec<_>>();
+        for key in finished {
+            self.incoming_channels.remove(&key);
+        }
+    }
+    /// Remembers the messages reassembled since the last call and forgets the ones older than
+    /// `OBSOLETE_TIMEOUT`.
+    fn update_completed(&mut self) {
+        let now = self.clock.now();
+        while let Ok((key, seq_auth)) = self.completed_rx.try_recv() {
+            self.completed
+                .insert(key, (seq_auth, now + OBSOLETE_TIMEOUT));
+        }
+        self.completed.retain(|_, (_, expires)| *expires > now);
+    }
+    /// Returns if `pdu` is a segment of a message that's already been reassembled.
+    fn is_completed(&self, pdu: &IncomingPDU<lower::SegmentedPDU>) -> bool {
+        self.completed
+            .get(&(pdu.src, pdu.pdu.seq_zero()))
+            .map_or(false, |(seq_auth, _)| {
+                seq_auth.iv_index == pdu.iv_index && seq_auth.valid_seq(pdu.seq)
+            })
+    }
+    /// Acks every segment of the already reassembled message `pdu` belongs to again.
+    async fn ack_completed(
+        &mut self,
+        pdu: &IncomingPDU<lower::SegmentedPDU>,
+    ) -> Result<(), ReassemblyError> {
+        let src = match pdu.dst.unicast() {
+            Some(src) => src,
+            None => return Ok(()),
+        };
+        let block_ack = BlockAck::new_all_acked(pdu.pdu.segment_header().seg_o);
+        self.outgoing_pdus
+            .send(OutgoingLowerTransportMessage {
+                pdu: lower::PDU::UnsegmentedControl(
+                    control::Ack {
+                        obo: false,
+                        seq_zero: pdu.pdu.seq_zero(),
+                        block_ack,
+                    }
+                    .try_to_unseg()
+                    .expect("correctly formatted PDU"),
+                ),
+                src,
+                dst: Address::Unicast(pdu.src),
+                ttl: if u8::from(pdu.ttl) == 0_u8 {
+                    Some(TTL::new(0))
+                } else {
+                    None
+                },
+                seq: None,
+                iv_index: pdu.iv_index,
+                net_key_index: pdu.net_key_index,
+            })
+            .await
+            .ok()
+            .ok_or(ReassemblyError::ChannelClosed)?;
+        self.stats.count(Counter::AckSent);
+        self.ack_observer.observe(block_ack);
+        Ok(())
+    }
+    /// Cancels the oldest context. Dropping its sender closes its channel and the context's task
+    /// sends the cancel ack itself.
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .incoming_channels
+            .iter()
+            .min_by_key(|(_, context)| context.context_id)
+            .map(|(&key, _)| key);
+        if let Some(oldest) = oldest {
+            self.incoming_channels.remove(&oldest);
         }
     }
     pub async fn feed_pdu(
         &mut self,
         pdu: IncomingPDU<lower::SegmentedPDU>,
     ) -> Result<(), ReassemblyError> {
-        match self.incoming_channels.entry((pdu.src, pdu.pdu.seq_zero())) {
+        self.update_completed();
+        self.remove_finished();
+        let key = (pdu.src, pdu.pdu.seq_zero());
+        if self.is_completed(&pdu) {
+            return self.ack_completed(&pdu).await;
+        }
+        if !self.incoming_channels.contains_key(&key)
+            && self.incoming_channels.len() >= self.max_inflight
+        {
+            self.evict_oldest();
+        }
+        match self.incoming_channels.entry(key) {
             Entry::Occupied(mut o) => o
                 .get_mut()
                 .sender
@@ -317,15 +647,41 @@ impl Reassembler {
                 .await
                 .map_err(|_| ReassemblyError::ChannelClosed),
             Entry::Vacant(v) => {
-                let (tx, rx) = mpsc::channel(REASSEMBLER_CHANNEL_LEN);
-                let handle =
-                    task::spawn(Self::reassemble_segs(pdu, self.outgoing_pdus.clone(), rx));
+                let (tx, rx) = mpsc::channel(self.channel_len);
+                let watchdog = TaskWatchdog::new();
+                let seq_auth = SeqAuth::from_seq_zero(pdu.pdu.seq_zero(), pdu.seq, pdu.iv_index);
+                let mut completed = self.completed_tx.clone();
+                let inflight = self.inflight.enter();
+                let reassembly = Self::reassemble_segs(
+                    pdu,
+                    self.outgoing_pdus.clone(),
+                    self.finished_pdus.clone(),
+                    rx,
+                    self.incomplete_timeout,
+                    self.stats.clone(),
+                    self.ack_observer.clone(),
+                    self.clock.clone(),
+                );
+                let handle = task::spawn(watchdog.watch(async move {
+                    let _inflight = inflight;
+                    let result = reassembly.await;
+                    if result.is_ok() {
+                        // Reported before the watchdog goes down so `feed_pdu` never sees the
+                        // context gone without knowing it completed. If the channel is full,
+                        // late segments just start a new context.
+                        let _ = completed.try_send((key, seq_auth));
+                    }
+                    result
+                }));
                 v.insert(ReassemblerHandle {
                     src: pdu.src,
                     seq_zero: pdu.pdu.seq_zero(),
                     sender: tx,
                     handle,
+                    context_id: self.next_context_id,
+                    watchdog,
                 });
+                self.next_context_id += 1;
                 Ok(())
             }
         }
@@ -333,8 +689,15 @@ impl Reassembler {
     async fn send_ack(
         segs: &IncomingSegments,
         outgoing: &mut mpsc::Sender<OutgoingLowerTransportMessage>,
+        stats: &StatsCounters,
+        observer: &mut AckObserver,
         ack: BlockAck,
     ) -> Result<(), ReassemblyError> {
+        // Only messages to one of our unicast addresses are acked (from that address).
+        let src = match segs.segs_dst.unicast() {
+            Some(src) => src,
+            None => return Ok(()),
+        };
         outgoing
             .send(OutgoingLowerTransportMessage {
                 pdu: lower::PDU::UnsegmentedControl(
@@ -346,8 +709,8 @@ impl Reassembler {
                     .try_to_unseg()
                     .expect("correctly formatted PDU"),
                 ),
-                src: segs.segs_src,
-                dst: segs.segs_dst,
+                src,
+                dst: Address::Unicast(segs.segs_src),
                 ttl: segs.ack_ttl,
                 seq: None,
                 iv_index: segs.seq_auth.iv_index,
@@ -355,41 +718,754 @@ impl Reassembler {
             })
             .await
             .ok()
-            .ok_or(ReassemblyError::ChannelClosed)
+            .ok_or(ReassemblyError::ChannelClosed)?;
+        stats.count(Counter::AckSent);
+        observer.observe(ack);
+        Ok(())
     }
     async fn cancel_ack(
         segs: &IncomingSegments,
         outgoing: &mut mpsc::Sender<OutgoingLowerTransportMessage>,
+        stats: &StatsCounters,
+        observer: &mut AckObserver,
     ) -> Result<(), ReassemblyError> {
-        Self::send_ack(segs, outgoing, BlockAck::cancel()).await
+        Self::send_ack(segs, outgoing, stats, observer, BlockAck::cancel()).await
     }
     async fn reassemble_segs(
+        first_seg: IncomingPDU<lower::SegmentedPDU>,
+        outgoing: mpsc::Sender<OutgoingLowerTransportMessage>,
+        finished: mpsc::Sender<IncomingTransportPDU<Box<[u8]>>>,
+        rx: mpsc::Receiver<IncomingPDU<lower::SegmentedPDU>>,
+        incomplete_timeout: time::Duration,
+        stats: StatsCounters,
+        ack_observer: AckObserver,
+        clock: SharedClock,
+    ) -> Result<(), ReassemblyError> {
+        let result = Self::try_reassemble_segs(
+            first_seg,
+            outgoing,
+            finished,
+            rx,
+            incomplete_timeout,
+            &stats,
+            ack_observer,
+            &clock,
+        )
+        .await;
+        stats.count(match result {
+            Ok(()) => Counter::SegmentedReceived,
+            Err(_) => Counter::SegmentedFailed,
+        });
+        if result == Err(ReassemblyError::Timeout) {
+            stats.count(Counter::ReassemblyTimeout);
+        }
+        result
+    }
+    async fn try_reassemble_segs(
         first_seg: IncomingPDU<lower::SegmentedPDU>,
         mut outgoing: mpsc::Sender<OutgoingLowerTransportMessage>,
+        mut finished: mpsc::Sender<IncomingTransportPDU<Box<[u8]>>>,
         mut rx: mpsc::Receiver<IncomingPDU<lower::SegmentedPDU>>,
-    ) -> Result<IncomingTransportPDU<Box<[u8]>>, ReassemblyError> {
+        incomplete_timeout: time::Duration,
+        stats: &StatsCounters,
+        mut ack_observer: AckObserver,
+        clock: &SharedClock,
+    ) -> Result<(), ReassemblyError> {
         let mut segments =
             IncomingSegments::new(first_seg).ok_or(ReassemblyError::InvalidFirstSegment)?;
-
+        segments.set_incomplete_timeout(incomplete_timeout);
+        // Segments sent to group or virtual addresses are never acked.
+        let acked = segments.segs_dst.unicast().is_some();
+        let mut ack_at = if acked {
+            Some(clock.now() + segments.ack_timeout())
+        } else {
+            None
+        };
+        let mut last_segment = clock.now();
         while !segments.is_ready() {
-            let next = time::timeout(segments.recv_timeout(), rx.recv())
+            let incomplete_at = last_segment + segments.incomplete_timeout();
+            let wake_at = ack_at.map_or(incomplete_at, |ack_at| ack_at.min(incomplete_at));
+            let next = match clock
+                .timeout(clock.now().until(wake_at).unwrap_or_default(), rx.recv())
                 .await
-                .map_err(|_| ReassemblyError::Timeout)?
-                .ok_or(ReassemblyError::ChannelClosed)?;
+            {
+                Ok(Some(next)) => next,
+                Ok(None) => {
+                    // Evicted by the `Reassembler` to make room for a newer message (or the
+                    // `Reassembler` is gone).
+                    if acked {
+                        Self::cancel_ack(&segments, &mut outgoing, stats, &mut ack_observer)
+                            .await?;
+                    }
+                    return Err(ReassemblyError::Canceled);
+                }
+                Err(_) => match ack_at {
+                    Some(at) if at <= incomplete_at => {
+                        // Ack timer. Tell the sender which segments are still missing.
+                        Self::send_ack(
+                            &segments,
+                            &mut outgoing,
+                            stats,
+                            &mut ack_observer,
+                            segments.block_ack(),
+                        )
+                        .await?;
+                        ack_at = None;
+                        continue;
+                    }
+                    _ => return Err(ReassemblyError::Timeout),
+                },
+            };
             if !segments.seq_auth.valid_seq(next.seq) {
                 // bad sequence number for segment.
-                Self::cancel_ack(&segments, &mut outgoing).await?;
+                Self::cancel_ack(&segments, &mut outgoing, stats, &mut ack_observer).await?;
                 return Err(ReassemblyError::Canceled);
             }
-            let seg_header = next.pdu.segment_header();
-            segments
-                .context
-                .insert_data(seg_header.seg_n, next.pdu.seg_data())
-                .map_err(ReassemblyError::Reassemble)?;
+            if segments
+                .block_ack()
+                .get(u8::from(next.pdu.segment_header().seg_n))
+            {
+                // Retransmitted segment. The sender missed our ack so tell it again what we
+                // have. Duplicates don't restart the incomplete timer.
+                if acked {
+                    Self::send_ack(
+                        &segments,
+                        &mut outgoing,
+                        stats,
+                        &mut ack_observer,
+                        segments.block_ack(),
+                    )
+                    .await?;
+                }
+                continue;
+            }
+            last_segment = clock.now();
+            match segments.insert(&next) {
+                Ok(()) => (),
+                Err(ReassemblyError::Reassemble(reassembler::ReassembleError::HeaderMismatch)) => {
+                    // Corrupted or spoofed segment.
+                    Self::cancel_ack(&segments, &mut outgoing, stats, &mut ack_observer).await?;
+                    return Err(ReassemblyError::Canceled);
+                }
+                Err(e) => return Err(e),
+            }
+            if acked && ack_at.is_none() {
+                ack_at = Some(clock.now() + segments.ack_timeout());
+            }
+        }
+        if acked {
+            // Every segment is in. Ack them all right away instead of waiting for the timer.
+            Self::send_ack(
+                &segments,
+                &mut outgoing,
+                stats,
+                &mut ack_observer,
+                segments.block_ack(),
+            )
+            .await?;
         }
         match segments.finish() {
-            Ok(msg) => Ok(msg),
+            Ok(msg) => finished
+                .send(msg)
+                .await
+                .ok()
+                .ok_or(ReassemblyError::ChannelClosed),
             Err(_) => unreachable!("segments is ensured to be is_ready() by the loop above"),
         }
     }
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::ControlOpcode;
+    use crate::device_state::SeqRange;
+    use crate::lower::{SegN, SegO, SegmentHeader, SegmentedAccessPDU, SegmentedControlPDU, SZMIC};
+    use crate::mesh::{KeyIndex, U24};
+    use crate::test_util::ManualClock;
+    use crate::upper;
+
+    fn control_seg(seg_n: u8, data: &[u8]) -> IncomingPDU<SegmentedPDU> {
+        IncomingPDU {
+            pdu: SegmentedPDU::Control(SegmentedControlPDU::new(
+                ControlOpcode::FriendSubscriptionListAdd,
+                SegmentHeader::new(false, SeqZero::new(0x10), SegO::new(1), SegN::new(seg_n)),
+                data,
+            )),
+            seq: SequenceNumber(U24::new(0x10 + u32::from(seg_n))),
+            iv_index: IVIndex(0),
+            net_key_index: NetKeyIndex(KeyIndex::new(0)),
+            src: UnicastAddress::new(0x0002),
+            dst: Address::from(0x0001),
+            ttl: TTL::new(5),
+        }
+    }
+    fn access_seg(seg_n: u8, seg_o: u8, szmic: bool) -> IncomingPDU<SegmentedPDU> {
+        IncomingPDU {
+            pdu: SegmentedPDU::Access(SegmentedAccessPDU::new(
+                None,
+                SZMIC::from(szmic),
+                SeqZero::new(0x10),
+                SegO::new(seg_o),
+                SegN::new(seg_n),
+                &[0xAA; 12],
+            )),
+            seq: SequenceNumber(U24::new(0x10 + u32::from(seg_n))),
+            iv_index: IVIndex(0),
+            net_key_index: NetKeyIndex(KeyIndex::new(0)),
+            src: UnicastAddress::new(0x0002),
+            dst: Address::from(0x0001),
+            ttl: TTL::new(5),
+        }
+    }
+    /// Runs a reassembly with `segs` and returns its result and the acks it sent.
+    async fn reassemble(
+        segs: Vec<IncomingPDU<SegmentedPDU>>,
+    ) -> (
+        Result<(), ReassemblyError>,
+        Vec<OutgoingLowerTransportMessage>,
+    ) {
+        let (outgoing_tx, mut outgoing_rx) = mpsc::channel(4);
+        let (finished_tx, _finished_rx) = mpsc::channel(1);
+        let (mut seg_tx, seg_rx) = mpsc::channel(4);
+        let mut segs = segs.into_iter();
+        let first = segs.next().expect("at least one segment");
+        for seg in segs {
+            seg_tx.send(seg).await.ok().expect("channel open");
+        }
+        let result = Reassembler::reassemble_segs(
+            first,
+            outgoing_tx,
+            finished_tx,
+            seg_rx,
+            INCOMPLETE_TIMEOUT,
+            StatsCounters::new(),
+            AckObserver::default(),
+            ManualClock::new().shared(),
+        )
+        .await;
+        let mut acks = Vec::new();
+        while let Ok(ack) = outgoing_rx.try_recv() {
+            acks.push(ack);
+        }
+        (result, acks)
+    }
+    fn is_cancel(ack: &OutgoingLowerTransportMessage) -> bool {
+        match ack.pdu {
+            lower::PDU::UnsegmentedControl(pdu) => match control::Ack::try_from_pdu(&pdu) {
+                Ok(ack) => ack.block_ack == BlockAck::cancel(),
+                Err(_) => false,
+            },
+            _ => false,
+        }
+    }
+    #[test]
+    fn test_segment_header_mismatch() {
+        let mut segments =
+            IncomingSegments::new(access_seg(0, 2, false)).expect("valid first segment");
+        let mismatch = ReassemblyError::Reassemble(reassembler::ReassembleError::HeaderMismatch);
+        assert_eq!(segments.insert(&access_seg(1, 3, false)), Err(mismatch));
+        assert_eq!(segments.insert(&access_seg(1, 2, true)), Err(mismatch));
+        assert_eq!(segments.insert(&control_seg(1, &[0xBB; 8])), Err(mismatch));
+        assert_eq!(segments.insert(&access_seg(1, 2, false)), Ok(()));
+    }
+    #[test]
+    fn test_incoming_pdu_debug() {
+        let debug = format!("{:?}", access_seg(1, 2, false));
+        assert!(debug.contains("ttl: TTL(5)"), "{}", debug);
+        assert!(debug.contains("seq: SequenceNumber"), "{}", debug);
+    }
+    #[test]
+    fn test_segment_timers() {
+        let mut segments =
+            IncomingSegments::new(access_seg(0, 2, false)).expect("valid first segment");
+        // 150 + 50 * TTL 5
+        assert_eq!(segments.ack_timeout(), time::Duration::from_millis(400));
+        assert_eq!(segments.incomplete_timeout(), INCOMPLETE_TIMEOUT);
+        // The ack timer follows the TTL of the latest segment.
+        let mut seg = access_seg(1, 2, false);
+        seg.ttl = TTL::new(10);
+        segments.insert(&seg).expect("matching segment");
+        assert_eq!(segments.ack_timeout(), time::Duration::from_millis(650));
+        // The incomplete timer never depends on the TTL.
+        assert_eq!(segments.incomplete_timeout(), INCOMPLETE_TIMEOUT);
+        segments.set_incomplete_timeout(time::Duration::from_secs(2));
+        assert_eq!(segments.incomplete_timeout(), time::Duration::from_secs(2));
+    }
+    #[tokio::test]
+    async fn test_mismatched_seg_o_cancels() {
+        let (result, acks) =
+            reassemble(vec![access_seg(0, 2, false), access_seg(1, 3, false)]).await;
+        assert_eq!(result, Err(ReassemblyError::Canceled));
+        assert_eq!(acks.len(), 1);
+        assert!(is_cancel(&acks[0]));
+    }
+    #[tokio::test]
+    async fn test_mismatched_szmic_cancels() {
+        let (result, acks) =
+            reassemble(vec![access_seg(0, 2, false), access_seg(1, 2, true)]).await;
+        assert_eq!(result, Err(ReassemblyError::Canceled));
+        assert_eq!(acks.len(), 1);
+        assert!(is_cancel(&acks[0]));
+    }
+    #[tokio::test]
+    async fn test_ack_observer() {
+        let (outgoing_tx, mut outgoing_rx) = mpsc::channel(4);
+        let (finished_tx, _finished_rx) = mpsc::channel(1);
+        let (observer_tx, mut observer_rx) = mpsc::channel(4);
+        let mut reassembler = Reassembler::new(outgoing_tx, finished_tx);
+        reassembler.set_ack_observer(observer_tx);
+        reassembler.set_clock(ManualClock::new().shared());
+        reassembler
+            .feed_pdu(access_seg(0, 2, false))
+            .await
+            .expect("first segment");
+        reassembler
+            .feed_pdu(access_seg(1, 3, false))
+            .await
+            .expect("context still open");
+        // The observer sees exactly the acks that went out.
+        assert_eq!(observer_rx.recv().await, Some(BlockAck::cancel()));
+        assert!(observer_rx.try_recv().is_err());
+        let sent = outgoing_rx.try_recv().ok().expect("cancel ack sent");
+        assert!(is_cancel(&sent));
+        assert!(outgoing_rx.try_recv().is_err());
+    }
+    #[test]
+    fn test_segmented_control_opcode() {
+        let mut segments =
+            IncomingSegments::new(control_seg(0, &[0xAA; 8])).expect("valid first segment");
+        assert!(segments.is_control());
+        assert!(!segments.is_ready());
+        segments
+            .insert(&control_seg(1, &[0xBB; 3]))
+            .expect("valid second segment");
+        let pdu = match segments.finish() {
+            Ok(pdu) => pdu,
+            Err(_) => panic!("all segments were inserted"),
+        };
+        assert_eq!(
+            pdu.control_opcode(),
+            Some(ControlOpcode::FriendSubscriptionListAdd)
+        );
+        match pdu.upper_pdu {
+            upper::PDU::Control(payload) => {
+                assert_eq!(&payload.payload[..8], &[0xAA; 8]);
+                assert_eq!(&payload.payload[8..], &[0xBB; 3]);
+            }
+            upper::PDU::Access(_) => panic!("expected a control PDU"),
+        }
+    }
+    #[test]
+    fn test_segmented_control_round_trip() {
+        use crate::crypto::key::NetKey;
+        use crate::device_state::DeviceState;
+        use crate::friend::FriendSubscriptionList;
+        use crate::mesh::ElementCount;
+        use crate::random::Randomizable;
+        use crate::stack::StackInternals;
+
+        let net_key_index = NetKeyIndex(KeyIndex::new(0));
+        let mut internals =
+            StackInternals::new(DeviceState::new(UnicastAddress::new(1), ElementCount(1)));
+        internals
+            .device_state_mut()
+            .security_materials_mut()
+            .net_key_map
+            .insert(net_key_index, &NetKey::random_secure());
+        let list = |len| {
+            control::FriendSubscriptionListAdd(FriendSubscriptionList {
+                transaction_number: 7,
+                addresses: (0..len).map(|i| Address::from(0xC000 + i)).collect(),
+            })
+        };
+        let dst = Address::from(0x0002);
+        // 11 bytes still fit in an Unsegmented Control PDU.
+        let short = internals
+            .control_upper(&list(5), dst, None, net_key_index)
+            .expect("valid control message");
+        assert_eq!(short.seq.seqs_lefts(), 1);
+        assert!(short.as_unsegmented().is_some());
+        // 21 bytes are sent as 3 segments of up to 8 bytes.
+        let long = internals
+            .control_upper(&list(10), dst, None, net_key_index)
+            .expect("valid control message");
+        assert_eq!(long.seq.seqs_lefts(), 3);
+        assert!(long.as_unsegmented().is_none());
+        let outgoing = long.into_outgoing_segments();
+        let seqs = SeqRange::new_segs(
+            outgoing.segments.seq_auth().first_seq,
+            outgoing.segments.seg_o(),
+        );
+        let mut incoming =
+            outgoing
+                .segments
+                .iter(outgoing.block_ack)
+                .zip(seqs)
+                .map(|(pdu, seq)| IncomingPDU {
+                    pdu,
+                    seq,
+                    iv_index: outgoing.segments.seq_auth().iv_index,
+                    net_key_index,
+                    src: outgoing.src,
+                    dst: outgoing.dst,
+                    ttl: TTL::new(5),
+                });
+        let mut segments = IncomingSegments::new(incoming.next().expect("first segment"))
+            .expect("valid first segment");
+        assert!(segments.is_control());
+        for seg in incoming {
+            segments.insert(&seg).expect("matching segment");
+        }
+        let pdu = match segments.finish() {
+            Ok(pdu) => pdu,
+            Err(_) => panic!("all segments were inserted"),
+        };
+        assert_eq!(
+            pdu.control_opcode(),
+            Some(ControlOpcode::FriendSubscriptionListAdd)
+        );
+        match pdu.upper_pdu {
+            upper::PDU::Control(payload) => match control::ControlPDU::try_from(&payload) {
+                Ok(control::ControlPDU::FriendSubscriptionListAdd(add)) => {
+                    assert_eq!(add, list(10))
+                }
+                _ => panic!("expected a Friend Subscription List Add"),
+            },
+            upper::PDU::Access(_) => panic!("expected a control PDU"),
+        }
+    }
+    fn outgoing_segments() -> OutgoingSegments<Box<[u8]>> {
+        OutgoingSegments {
+            segments: segmenter::UpperSegmenter::new(
+                upper::PDU::Control(control::ControlPayload {
+                    opcode: ControlOpcode::FriendSubscriptionListAdd,
+                    payload: vec![0xAA; 16].into_boxed_slice(),
+                }),
+                SeqAuth::new(SequenceNumber(U24::new(0x10)), IVIndex(0)),
+            ),
+            block_ack: BlockAck::default(),
+            net_key_index: NetKeyIndex(KeyIndex::new(0)),
+            src: UnicastAddress::new(0x0001),
+            dst: Address::from(0x0002),
+            ttl: None,
+        }
+    }
+    fn ack(block_ack: BlockAck) -> IncomingPDU<control::Ack> {
+        IncomingPDU {
+            pdu: control::Ack {
+                obo: false,
+                seq_zero: SeqZero::new(0x10),
+                block_ack,
+            },
+            seq: SequenceNumber(U24::new(0x30)),
+            iv_index: IVIndex(0),
+            net_key_index: NetKeyIndex(KeyIndex::new(0)),
+            src: UnicastAddress::new(0x0002),
+            dst: Address::from(0x0001),
+            ttl: TTL::new(5),
+        }
+    }
+    #[test]
+    fn test_segments_iter() {
+        use crate::crypto::MIC;
+        // 22 bytes and a 32-bit TransMIC are 3 segments with the TransMIC split over the last two.
+        let payload = (0_u8..22).collect::<Vec<_>>().into_boxed_slice();
+        let mut outgoing = outgoing_segments();
+        outgoing.segments = segmenter::UpperSegmenter::new(
+            upper::PDU::Access(upper::EncryptedAppPayload::new(
+                payload.clone(),
+                MIC::Small(0xDEAD_BEEF),
+                None,
+            )),
+            SeqAuth::new(SequenceNumber(U24::new(0x10)), IVIndex(0)),
+        );
+        let mut next_seq = 0x10_u32;
+        let msgs = outgoing
+            .segments_iter(|| {
+                next_seq += 1;
+                Some(SequenceNumber(U24::new(next_seq - 1)))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(msgs.len(), 3);
+        let mut upper_pdu = Vec::new();
+        for (seg_n, msg) in msgs.iter().enumerate() {
+            assert_eq!(msg.seq, Some(SequenceNumber(U24::new(0x10 + seg_n as u32))));
+            assert_eq!((msg.src, msg.dst), (outgoing.src, outgoing.dst));
+            let seg = msg.pdu.segmented().expect("every message is a segment");
+            assert_eq!(seg.segment_header().seg_n, SegN::new(seg_n as u8));
+            assert_eq!(seg.segment_header().seg_o, SegO::new(2));
+            upper_pdu.extend_from_slice(seg.seg_data());
+        }
+        let mut expected = payload.to_vec();
+        expected.extend_from_slice(&0xDEAD_BEEF_u32.to_be_bytes());
+        assert_eq!(upper_pdu, expected);
+        // Acked segments are skipped.
+        outgoing.block_ack = BlockAck(0b101);
+        let resent = outgoing.segments_iter(|| None).collect::<Vec<_>>();
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].seq, None);
+        assert_eq!(
+            resent[0]
+                .pdu
+                .segmented()
+                .map(|seg| seg.segment_header().seg_n),
+            Some(SegN::new(1))
+        );
+    }
+    #[test]
+    fn test_ack_event() {
+        let mut segments = outgoing_segments();
+        assert_eq!(segments.ack_event(ack(BlockAck(0b001))), Ok(AckEvent::New));
+        segments.block_ack = BlockAck(0b001);
+        assert_eq!(segments.ack_event(ack(BlockAck(0b001))), Ok(AckEvent::Old));
+        assert_eq!(segments.ack_event(ack(BlockAck(0b011))), Ok(AckEvent::New));
+        assert_eq!(
+            segments.ack_event(ack(BlockAck::cancel())),
+            Ok(AckEvent::Canceled)
+        );
+        assert_eq!(segments.is_cancel_ack(ack(BlockAck::cancel())), Ok(true));
+        // A cancel for another transfer isn't ours to act on.
+        let mut other = ack(BlockAck::cancel());
+        other.pdu.seq_zero = SeqZero::new(0x11);
+        assert_eq!(segments.ack_event(other), Err(AckError::BadSeqZero));
+        // Neither is a cancel from a node the transfer isn't for.
+        let mut third = ack(BlockAck::cancel());
+        third.src = UnicastAddress::new(0x0003);
+        assert_eq!(segments.ack_event(third), Err(AckError::BadSrc));
+    }
+    #[tokio::test]
+    async fn test_incomplete_timeout() {
+        let clock = ManualClock::new();
+        let incomplete_timeout = time::Duration::from_secs(2);
+        let (outgoing_tx, _outgoing_rx) = mpsc::channel(4);
+        let (finished_tx, _finished_rx) = mpsc::channel(1);
+        // Keep the segment sender open so only the timer can end the reassembly.
+        let (_seg_tx, seg_rx) = mpsc::channel(4);
+        let (mut done_tx, mut done_rx) = mpsc::channel(1);
+        let stats = StatsCounters::new();
+        let task_stats = stats.clone();
+        let task_clock = clock.shared();
+        let since = clock.timers_started();
+        task::spawn(async move {
+            let result = Reassembler::reassemble_segs(
+                access_seg(0, 1, false),
+                outgoing_tx,
+                finished_tx,
+                seg_rx,
+                incomplete_timeout,
+                task_stats,
+                AckObserver::default(),
+                task_clock,
+            )
+            .await;
+            done_tx.send(result).await.ok().expect("channel open");
+        });
+        clock.wait_for_timers(since + 1).await;
+        clock
+            .advance(incomplete_timeout - time::Duration::from_millis(1))
+            .await;
+        assert!(done_rx.try_recv().is_err());
+        // The shorter timer fires well before the default 10 seconds.
+        clock.advance(time::Duration::from_millis(1)).await;
+        assert_eq!(done_rx.try_recv().ok(), Some(Err(ReassemblyError::Timeout)));
+        assert!(incomplete_timeout < INCOMPLETE_TIMEOUT);
+        assert_eq!(stats.snapshot().reassembly_timeouts, 1);
+        assert_eq!(stats.snapshot().segmented_failed, 1);
+    }
+    #[tokio::test]
+    async fn test_progressive_acks() {
+        let clock = ManualClock::new();
+        let (outgoing_tx, mut outgoing_rx) = mpsc::channel(4);
+        let (finished_tx, mut finished_rx) = mpsc::channel(1);
+        let (observer_tx, mut observer_rx) = mpsc::channel(4);
+        let mut reassembler = Reassembler::new(outgoing_tx, finished_tx);
+        reassembler.set_ack_observer(observer_tx);
+        reassembler.set_clock(clock.shared());
+        let since = clock.timers_started();
+        for seg_n in &[0, 2] {
+            reassembler
+                .feed_pdu(access_seg(*seg_n, 3, false))
+                .await
+                .expect("context open");
+        }
+        clock.wait_for_timers(since + 1).await;
+        assert!(observer_rx.try_recv().is_err());
+        // The ack timer acks what's been received so far (segment 1 and 3 are missing).
+        clock.advance(ack_timeout(TTL::new(5))).await;
+        assert_eq!(observer_rx.try_recv().ok(), Some(BlockAck(0b0101)));
+        // ...and sends it back to the source from the address the segments were sent to.
+        let progressive = outgoing_rx.try_recv().expect("progressive ack sent");
+        assert_eq!(progressive.src, UnicastAddress::new(0x0001));
+        assert_eq!(progressive.dst, Address::from(0x0002));
+        match progressive.pdu {
+            lower::PDU::UnsegmentedControl(unseg) => {
+                let ack = control::Ack::try_from_pdu(&unseg).expect("Segment Acknowledgement");
+                assert_eq!(ack.seq_zero, SeqZero::new(0x10));
+                assert_eq!(ack.block_ack, BlockAck(0b0101));
+            }
+            _ => panic!("not a Control PDU"),
+        }
+        // It only restarts on the next new segment.
+        clock.advance(ack_timeout(TTL::new(5))).await;
+        assert!(observer_rx.try_recv().is_err());
+        for seg_n in &[1, 3] {
+            reassembler
+                .feed_pdu(access_seg(*seg_n, 3, false))
+                .await
+                .expect("context open");
+        }
+        // The last segment is acked right away.
+        assert_eq!(
+            observer_rx.recv().await,
+            Some(BlockAck::new_all_acked(SegO::new(3)))
+        );
+        assert!(finished_rx.try_recv().is_ok());
+        assert!(outgoing_rx.try_recv().is_ok());
+        assert!(outgoing_rx.try_recv().is_err());
+    }
+    #[tokio::test]
+    async fn test_duplicate_segment_reacked() {
+        let clock = ManualClock::new();
+        let (outgoing_tx, _outgoing_rx) = mpsc::channel(4);
+        let (finished_tx, mut finished_rx) = mpsc::channel(1);
+        let (observer_tx, mut observer_rx) = mpsc::channel(4);
+        let mut reassembler = Reassembler::new(outgoing_tx, finished_tx);
+        reassembler.set_ack_observer(observer_tx);
+        reassembler.set_clock(clock.shared());
+        let since = clock.timers_started();
+        for seg_n in &[0, 1] {
+            reassembler
+                .feed_pdu(access_seg(*seg_n, 2, false))
+                .await
+                .expect("context open");
+        }
+        clock.wait_for_timers(since + 1).await;
+        assert!(observer_rx.try_recv().is_err());
+        // A duplicate is acked right away with what's been received so far.
+        reassembler
+            .feed_pdu(access_seg(1, 2, false))
+            .await
+            .expect("context open");
+        assert_eq!(observer_rx.recv().await, Some(BlockAck(0b011)));
+        assert!(finished_rx.try_recv().is_err());
+        reassembler
+            .feed_pdu(access_seg(2, 2, false))
+            .await
+            .expect("context open");
+        assert_eq!(
+            observer_rx.recv().await,
+            Some(BlockAck::new_all_acked(SegO::new(2)))
+        );
+        assert!(finished_rx.try_recv().is_ok());
+    }
+    #[tokio::test]
+    async fn test_late_segment_reacked() {
+        let clock = ManualClock::new();
+        let (outgoing_tx, _outgoing_rx) = mpsc::channel(4);
+        let (finished_tx, mut finished_rx) = mpsc::channel(2);
+        let (observer_tx, mut observer_rx) = mpsc::channel(4);
+        let mut reassembler = Reassembler::new(outgoing_tx, finished_tx);
+        reassembler.set_ack_observer(observer_tx);
+        reassembler.set_clock(clock.shared());
+        for seg_n in &[0, 1] {
+            reassembler
+                .feed_pdu(access_seg(*seg_n, 1, false))
+                .await
+                .expect("context open");
+        }
+        let all_acked = BlockAck::new_all_acked(SegO::new(1));
+        assert_eq!(observer_rx.recv().await, Some(all_acked));
+        assert!(finished_rx.try_recv().is_ok());
+        // The sender missed the final ack and retransmits. It gets acked again without the
+        // message being reassembled (and delivered) twice.
+        clock.advance(time::Duration::from_secs(1)).await;
+        reassembler
+            .feed_pdu(access_seg(1, 1, false))
+            .await
+            .expect("re-ack sent");
+        assert_eq!(observer_rx.try_recv().ok(), Some(all_acked));
+        assert!(finished_rx.try_recv().is_err());
+        assert!(reassembler.incoming_channels.is_empty());
+        // Once obsolete, the message is forgotten.
+        clock.advance(OBSOLETE_TIMEOUT).await;
+        reassembler
+            .feed_pdu(access_seg(1, 1, false))
+            .await
+            .expect("new context");
+        assert!(observer_rx.try_recv().is_err());
+        assert!(reassembler.completed.is_empty());
+    }
+    #[tokio::test]
+    async fn test_group_segments_not_acked() {
+        let clock = ManualClock::new();
+        let (outgoing_tx, mut outgoing_rx) = mpsc::channel(4);
+        let (finished_tx, mut finished_rx) = mpsc::channel(1);
+        let mut reassembler = Reassembler::new(outgoing_tx, finished_tx);
+        reassembler.set_clock(clock.shared());
+        let group_seg = |seg_n| IncomingPDU {
+            dst: Address::from(0xC001),
+            ..access_seg(seg_n, 1, false)
+        };
+        let since = clock.timers_started();
+        reassembler
+            .feed_pdu(group_seg(0))
+            .await
+            .expect("first segment");
+        clock.wait_for_timers(since + 1).await;
+        clock.advance(time::Duration::from_secs(1)).await;
+        reassembler
+            .feed_pdu(group_seg(1))
+            .await
+            .expect("context open");
+        assert!(finished_rx.recv().await.is_some());
+        assert!(outgoing_rx.try_recv().is_err());
+    }
+    #[tokio::test]
+    async fn test_oldest_reassembly_evicted() {
+        let (outgoing_tx, _outgoing_rx) = mpsc::channel(4);
+        let (finished_tx, mut finished_rx) = mpsc::channel(1);
+        let (observer_tx, mut observer_rx) = mpsc::channel(4);
+        let mut reassembler = Reassembler::new(outgoing_tx, finished_tx);
+        reassembler.set_ack_observer(observer_tx);
+        reassembler.set_clock(ManualClock::new().shared());
+        reassembler.set_max_inflight(2);
+        let first_seg = |src| IncomingPDU {
+            src: UnicastAddress::new(src),
+            ..access_seg(0, 1, false)
+        };
+        for src in &[0x0002, 0x0003] {
+            reassembler
+                .feed_pdu(first_seg(*src))
+                .await
+                .expect("new context");
+        }
+        assert_eq!(reassembler.inflight(), 2);
+        assert!(observer_rx.try_recv().is_err());
+        // A third message cancels the oldest one.
+        reassembler
+            .feed_pdu(first_seg(0x0004))
+            .await
+            .expect("new context");
+        assert_eq!(observer_rx.recv().await, Some(BlockAck::cancel()));
+        assert_eq!(reassembler.inflight(), 2);
+        assert!(!reassembler
+            .incoming_channels
+            .contains_key(&(UnicastAddress::new(0x0002), SeqZero::new(0x10))));
+
+        // Finished contexts are removed from the map.
+        reassembler
+            .feed_pdu(IncomingPDU {
+                src: UnicastAddress::new(0x0003),
+                ..access_seg(1, 1, false)
+            })
+            .await
+            .expect("context open");
+        assert!(finished_rx.recv().await.is_some());
+        assert_eq!(reassembler.inflight(), 1);
+        reassembler
+            .feed_pdu(first_seg(0x0005))
+            .await
+            .expect("new context");
+        assert_eq!(reassembler.incoming_channels.len(), 2);
+    }
+}
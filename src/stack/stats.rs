@@ -0,0 +1,186 @@
+//! Statistics counters for monitoring the stack. Every counter is a relaxed atomic so counting
+//! never locks or waits. See [`crate::stack::full::FullStack::stats`].
+use crate::stack::RecvError;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Why an incoming PDU was dropped. Every drop is logged once (at `Trace`) with its reason and,
+/// if the PDU could be decrypted, its src and seq. Unknown Control opcodes are logged as their
+/// `UnknownControlPolicy` says instead.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum DropReason {
+    /// No NetKey could decrypt and authenticate the Network PDU.
+    DecryptFailed = 0,
+    /// The seq was already seen from this src (replay protection).
+    Replayed = 1,
+    /// The segmented message (SeqZero) was already received.
+    AlreadyReceived = 2,
+    /// The PDU isn't addressed to this node.
+    NotForUs = 3,
+    /// The PDU decrypted but its payload couldn't be parsed.
+    Unparseable = 4,
+    /// The segment couldn't be handed to the reassembler.
+    BadSegment = 5,
+    /// A Control PDU with an opcode the stack doesn't know.
+    UnknownControlOpcode = 6,
+    /// No AppKey or DevKey could decrypt the Upper Transport Access PDU.
+    AppDecryptFailed = 7,
+}
+const DROP_REASONS: usize = 8;
+impl DropReason {
+    /// Returns the `DropReason` for a `RecvError` or `None` if the error isn't about the PDU
+    /// itself (closed channels, bearer errors, etc).
+    pub fn from_recv_error(error: &RecvError) -> Option<DropReason> {
+        match error {
+            RecvError::NoMatchingNetKey => Some(DropReason::DecryptFailed),
+            RecvError::NoMatchingAppKey | RecvError::InvalidDeviceKey => {
+                Some(DropReason::AppDecryptFailed)
+            }
+            RecvError::OldSeq => Some(DropReason::Replayed),
+            RecvError::OldSeqZero => Some(DropReason::AlreadyReceived),
+            RecvError::InvalidDestination => Some(DropReason::NotForUs),
+            RecvError::MalformedNetworkPDU | RecvError::MalformedControlPDU => {
+                Some(DropReason::Unparseable)
+            }
+            RecvError::ReassemblerError(_) => Some(DropReason::BadSegment),
+            RecvError::UnknownControlOpcode(..) => Some(DropReason::UnknownControlOpcode),
+            _ => None,
+        }
+    }
+}
+/// Everything counted by `StatsCounters` besides the dropped PDUs.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+pub enum Counter {
+    /// Encrypted Network PDUs fed to the stack.
+    PDUReceived = 0,
+    /// Network PDUs that decrypted with one of our NetKeys.
+    PDUDecrypted = 1,
+    /// Network PDUs relayed (by the Relay or Proxy feature).
+    PDURelayed = 2,
+    /// Outgoing segmented messages fully acked.
+    SegmentedSent = 3,
+    /// Incoming segmented messages fully reassembled.
+    SegmentedReceived = 4,
+    /// Outgoing or incoming segmented messages that timed out or were canceled.
+    SegmentedFailed = 5,
+    /// Segment acks sent by the reassembler.
+    AckSent = 6,
+    /// Segment acks received for outgoing segmented messages.
+    AckReceived = 7,
+    /// Beacons fed to the stack.
+    BeaconReceived = 8,
+    /// Incoming segmented messages dropped because the incomplete timer fired (also counted in
+    /// `SegmentedFailed`).
+    ReassemblyTimeout = 9,
+    /// Relayable Network PDUs not relayed because of `RelaySuppression`.
+    RelaySuppressed = 10,
+}
+const COUNTERS: usize = 11;
+/// Number of dropped PDUs for each `DropReason`.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash, Default)]
+pub struct DropCounts([usize; DROP_REASONS]);
+impl DropCounts {
+    pub fn get(&self, reason: DropReason) -> usize {
+        self.0[reason as usize]
+    }
+    /// Returns the number of dropped PDUs for every reason combined.
+    pub fn total(&self) -> usize {
+        self.0.iter().sum()
+    }
+}
+/// Snapshot of the stack's counters. See [`StatsCounters::snapshot`].
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash, Default)]
+pub struct StackStats {
+    pub pdus_received: usize,
+    pub pdus_decrypted: usize,
+    pub pdus_relayed: usize,
+    pub pdus_dropped: DropCounts,
+    pub segmented_sent: usize,
+    pub segmented_received: usize,
+    pub segmented_failed: usize,
+    pub acks_sent: usize,
+    pub acks_received: usize,
+    pub beacons_received: usize,
+    pub reassembly_timeouts: usize,
+    pub relays_suppressed: usize,
+}
+#[derive(Debug, Default)]
+struct Counters {
+    counters: [AtomicUsize; COUNTERS],
+    dropped: [AtomicUsize; DROP_REASONS],
+}
+/// Shared handle to the stack's counters. Clones count into the same counters.
+#[derive(Clone, Debug, Default)]
+pub struct StatsCounters(Arc<Counters>);
+impl StatsCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn count(&self, counter: Counter) {
+        self.0.counters[counter as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn count_drop(&self, reason: DropReason) {
+        self.0.dropped[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn get(&self, counter: Counter) -> usize {
+        self.0.counters[counter as usize].load(Ordering::Relaxed)
+    }
+    /// Copies out every counter. Counters are read one at a time so a snapshot taken while PDUs
+    /// are being processed might be off by the PDUs in flight.
+    pub fn snapshot(&self) -> StackStats {
+        let mut dropped = DropCounts::default();
+        for (count, counter) in dropped.0.iter_mut().zip(self.0.dropped.iter()) {
+            *count = counter.load(Ordering::Relaxed);
+        }
+        StackStats {
+            pdus_received: self.get(Counter::PDUReceived),
+            pdus_decrypted: self.get(Counter::PDUDecrypted),
+            pdus_relayed: self.get(Counter::PDURelayed),
+            pdus_dropped: dropped,
+            segmented_sent: self.get(Counter::SegmentedSent),
+            segmented_received: self.get(Counter::SegmentedReceived),
+            segmented_failed: self.get(Counter::SegmentedFailed),
+            acks_sent: self.get(Counter::AckSent),
+            acks_received: self.get(Counter::AckReceived),
+            beacons_received: self.get(Counter::BeaconReceived),
+            reassembly_timeouts: self.get(Counter::ReassemblyTimeout),
+            relays_suppressed: self.get(Counter::RelaySuppressed),
+        }
+    }
+    /// Zeros every counter.
+    pub fn reset(&self) {
+        for counter in self.0.counters.iter().chain(self.0.dropped.iter()) {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_and_reset() {
+        let stats = StatsCounters::new();
+        let shared = stats.clone();
+        stats.count(Counter::PDUReceived);
+        shared.count(Counter::PDUReceived);
+        shared.count(Counter::PDURelayed);
+        stats.count(Counter::ReassemblyTimeout);
+        stats.count_drop(DropReason::Replayed);
+        stats.count_drop(DropReason::NotForUs);
+        stats.count_drop(DropReason::NotForUs);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.pdus_received, 2);
+        assert_eq!(snapshot.pdus_relayed, 1);
+        assert_eq!(snapshot.pdus_decrypted, 0);
+        assert_eq!(snapshot.reassembly_timeouts, 1);
+        assert_eq!(snapshot.relays_suppressed, 0);
+        assert_eq!(snapshot.pdus_dropped.get(DropReason::NotForUs), 2);
+        assert_eq!(snapshot.pdus_dropped.get(DropReason::DecryptFailed), 0);
+        assert_eq!(snapshot.pdus_dropped.total(), 3);
+
+        shared.reset();
+        assert_eq!(stats.snapshot(), StackStats::default());
+    }
+}
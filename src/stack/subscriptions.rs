@@ -0,0 +1,124 @@
+//! Group and virtual addresses the elements of this node are subscribed to.
+use crate::address::{Address, VirtualAddress};
+use crate::mesh::ElementIndex;
+use alloc::collections::{BTreeMap, BTreeSet};
+
+/// Every group and virtual address (by full Label UUID) subscribed to and which elements
+/// subscribed to it. Unicast addresses don't need subscribing (see
+/// [`StackInternals::matches`](crate::stack::StackInternals::matches)).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SubscriptionList(BTreeMap<Address, BTreeSet<ElementIndex>>);
+impl SubscriptionList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Subscribes the element at `element_index` to `address`. Returns `false` if `address` can't
+    /// be subscribed to (unassigned, unicast or a virtual hash without its Label UUID) or if the
+    /// element is already subscribed.
+    pub fn add(&mut self, element_index: ElementIndex, address: Address) -> bool {
+        match address {
+            Address::Group(_) | Address::Virtual(_) => self
+                .0
+                .entry(address)
+                .or_insert_with(BTreeSet::new)
+                .insert(element_index),
+            Address::Unassigned | Address::Unicast(_) | Address::VirtualHash(_) => false,
+        }
+    }
+    /// Unsubscribes the element at `element_index` from `address`. Returns `false` if it wasn't
+    /// subscribed.
+    pub fn remove(&mut self, element_index: ElementIndex, address: &Address) -> bool {
+        let elements = match self.0.get_mut(address) {
+            Some(elements) => elements,
+            None => return false,
+        };
+        let removed = elements.remove(&element_index);
+        if elements.is_empty() {
+            self.0.remove(address);
+        }
+        removed
+    }
+    /// Returns the lowest element subscribed to `address` or `None` if no element is. A
+    /// `VirtualHash` matches any subscribed Label UUID with the same hash.
+    pub fn matches(&self, address: &Address) -> Option<ElementIndex> {
+        match address {
+            Address::VirtualHash(hash) => self
+                .0
+                .iter()
+                .filter(|(subscribed, _)| match subscribed {
+                    Address::Virtual(v) => v.hash() == *hash,
+                    _ => false,
+                })
+                .filter_map(|(_, elements)| elements.iter().next().copied())
+                .min(),
+            _ => self
+                .0
+                .get(address)
+                .and_then(|elements| elements.iter().next().copied()),
+        }
+    }
+    /// Every subscribed address (each only once).
+    pub fn addresses(&self) -> impl Iterator<Item = &'_ Address> + Clone {
+        self.0.keys()
+    }
+    /// Every subscribed Label UUID.
+    pub fn virtual_addresses(&self) -> impl Iterator<Item = &'_ VirtualAddress> + Clone {
+        self.addresses().filter_map(|address| match address {
+            Address::Virtual(v) => Some(v),
+            _ => None,
+        })
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::{GroupAddress, UnicastAddress};
+    use crate::uuid::UUID;
+
+    #[test]
+    fn test_add_remove() {
+        let mut list = SubscriptionList::new();
+        let group = Address::Group(GroupAddress::new(0xC001));
+        assert!(!list.add(ElementIndex(0), Address::Unassigned));
+        assert!(!list.add(ElementIndex(0), Address::Unicast(UnicastAddress::new(1))));
+        assert!(list.add(ElementIndex(2), group));
+        assert!(list.add(ElementIndex(1), group));
+        assert!(!list.add(ElementIndex(1), group));
+        assert_eq!(list.len(), 1);
+        assert_eq!(list.matches(&group), Some(ElementIndex(1)));
+
+        assert!(list.remove(ElementIndex(1), &group));
+        assert!(!list.remove(ElementIndex(1), &group));
+        assert_eq!(list.matches(&group), Some(ElementIndex(2)));
+        assert!(list.remove(ElementIndex(2), &group));
+        assert_eq!(list.matches(&group), None);
+        assert!(list.is_empty());
+    }
+    #[test]
+    fn test_virtual_matches() {
+        let mut list = SubscriptionList::new();
+        // Different Label UUIDs with the same hash (0xB95F).
+        let label = |i: u128| VirtualAddress::new(&UUID(i.to_be_bytes()));
+        let (first, second) = (label(128), label(272));
+        assert_eq!(first.hash(), second.hash());
+        assert!(!list.add(ElementIndex(0), Address::VirtualHash(first.hash())));
+        assert!(list.add(ElementIndex(3), Address::Virtual(first)));
+        assert!(list.add(ElementIndex(1), Address::Virtual(second)));
+
+        assert_eq!(
+            list.matches(&Address::VirtualHash(first.hash())),
+            Some(ElementIndex(1))
+        );
+        assert_eq!(
+            list.matches(&Address::Virtual(first)),
+            Some(ElementIndex(3))
+        );
+        assert_eq!(list.virtual_addresses().count(), 2);
+    }
+}
@@ -0,0 +1,81 @@
+//! Watchdog flags for checking if the stack's background tasks are still running.
+use alloc::sync::Arc;
+use core::future::Future;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Shared flag that is `true` while the watched task is running.
+#[derive(Clone, Debug, Default)]
+pub struct TaskWatchdog(Arc<AtomicBool>);
+impl TaskWatchdog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Returns if the watched task is still running.
+    pub fn is_alive(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+    /// Marks the task as alive until the returned `WatchdogGuard` is dropped.
+    pub fn guard(&self) -> WatchdogGuard {
+        self.0.store(true, Ordering::SeqCst);
+        WatchdogGuard(self.0.clone())
+    }
+    /// Wraps `future` so the watchdog is alive until `future` returns (or panics).
+    pub fn watch<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+        let guard = self.guard();
+        async move {
+            let _guard = guard;
+            future.await
+        }
+    }
+}
+/// Clears the `TaskWatchdog` when dropped.
+#[derive(Debug)]
+pub struct WatchdogGuard(Arc<AtomicBool>);
+impl Drop for WatchdogGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst)
+    }
+}
+#[cfg(all(test, feature = "full_stack"))]
+mod tests {
+    use super::*;
+    use crate::asyncs::{
+        sync::{mpsc, oneshot},
+        task,
+    };
+
+    #[tokio::test]
+    async fn test_watched_task_returns() {
+        let watchdog = TaskWatchdog::new();
+        assert!(!watchdog.is_alive());
+        let (mut stop_tx, mut stop_rx) = mpsc::channel::<()>(1);
+        let (started_tx, started_rx) = oneshot::channel();
+        let handle = task::spawn(watchdog.watch(async move {
+            let _ = started_tx.send(());
+            stop_rx.recv().await
+        }));
+        // Alive as soon as it's watched, even before the task first runs.
+        assert!(watchdog.is_alive());
+        started_rx.await.expect("task running");
+        assert!(watchdog.is_alive());
+        stop_tx.send(()).await.ok().expect("task running");
+        assert_eq!(handle.await.expect("task panicked"), Some(()));
+        assert!(!watchdog.is_alive());
+    }
+    #[tokio::test]
+    async fn test_watched_task_panics() {
+        let watchdog = TaskWatchdog::new();
+        let handle = task::spawn(watchdog.watch(async { panic!("watched task died") }));
+        assert!(handle.await.is_err());
+        assert!(!watchdog.is_alive());
+    }
+    #[test]
+    fn test_watched_future_dropped() {
+        let watchdog = TaskWatchdog::new();
+        let watched = watchdog.watch(futures_util::future::pending::<()>());
+        assert!(watchdog.is_alive());
+        // A future that's never polled again (its task was dropped) isn't running either.
+        drop(watched);
+        assert!(!watchdog.is_alive());
+    }
+}
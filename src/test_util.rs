@@ -0,0 +1,312 @@
+//! Deterministic time for testing the stack's timers (segment retransmits, acks and timeouts).
+//! Give the stack a [`ManualClock`] (see `FullStack::with_clock`) and step through time with
+//! [`ManualClock::advance`] instead of sleeping.
+//!
+//! Only works on a single threaded runtime (`#[tokio::test]`) so the tasks woken by `advance`
+//! run before it returns.
+//!
+//! [`provision_device`] plays the device being provisioned for provisioner tests.
+use crate::crypto::ecdh::PrivateKey;
+use crate::crypto::key::DevKey;
+use crate::provisioning::bearer::Link;
+use crate::provisioning::confirmation::{self, AuthValue, ConfirmationKey};
+use crate::provisioning::data::{ProvisioningData, SessionKeys};
+use crate::provisioning::protocol::{self, AuthenticationMethod, ErrorCode, PDU};
+use crate::stack::clock::{Clock, Delay, SharedClock};
+use crate::timestamp::{Timestamp, TimestampTrait};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// How many times a `ManualClock` yields to other tasks while waiting on them before giving up.
+/// A task only gets close if it's stuck on something else (a full channel, etc).
+pub const MAX_SETTLE_YIELDS: usize = 1000;
+
+/// A `Clock` that only moves with [`ManualClock::advance`]. Clones share the same time.
+#[derive(Clone, Debug)]
+pub struct ManualClock(Arc<Mutex<ManualTime>>);
+#[derive(Debug)]
+struct ManualTime {
+    now: Timestamp,
+    next_timer: u64,
+    /// Deadline of every pending delay and the task waiting on it.
+    timers: BTreeMap<u64, (Timestamp, Waker)>,
+}
+impl ManualClock {
+    /// Starts at the current system time.
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(ManualTime {
+            now: Timestamp::now(),
+            next_timer: 0,
+            timers: BTreeMap::new(),
+        })))
+    }
+    /// A `SharedClock` for the stack that follows this clock.
+    pub fn shared(&self) -> SharedClock {
+        Arc::new(self.clone())
+    }
+    fn time(&self) -> MutexGuard<'_, ManualTime> {
+        // Only ever locked for quick updates that can't panic.
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    /// Returns how many delays are waiting for their deadline.
+    pub fn pending_timers(&self) -> usize {
+        self.time().timers.len()
+    }
+    /// Returns how many delays started waiting so far (see [`ManualClock::wait_for_timers`]).
+    pub fn timers_started(&self) -> u64 {
+        self.time().next_timer
+    }
+    /// Yields until `count` delays started waiting in total. Use it after feeding a task
+    /// something to know it's done reacting (it's waiting on its next timer).
+    ///
+    /// # Panics
+    /// Panics if the delays don't start within `MAX_SETTLE_YIELDS` yields.
+    pub async fn wait_for_timers(&self, count: u64) {
+        yield_until(
+            || self.time().next_timer >= count,
+            "no task started a timer",
+        )
+        .await
+    }
+    /// Moves the clock forward by `duration`. The timers due on the way fire in deadline order.
+    /// The clock stops at each deadline until the tasks waiting on it ran (up to their next
+    /// `.await` that isn't ready) so a periodic task ticks once per period.
+    ///
+    /// # Panics
+    /// Panics if a task doesn't get to its due timer within `MAX_SETTLE_YIELDS` yields.
+    pub async fn advance(&self, duration: Duration) {
+        let target = self.now() + duration;
+        loop {
+            let wakers = {
+                let mut time = self.time();
+                let next_deadline = time
+                    .timers
+                    .values()
+                    .map(|(deadline, _)| *deadline)
+                    .filter(|deadline| *deadline <= target)
+                    .min();
+                match next_deadline {
+                    Some(deadline) => time.now = time.now.max(deadline),
+                    None => {
+                        time.now = target;
+                        return;
+                    }
+                }
+                let now = time.now;
+                time.timers
+                    .values()
+                    .filter(|(deadline, _)| *deadline <= now)
+                    .map(|(_, waker)| waker.clone())
+                    .collect::<Vec<_>>()
+            };
+            for waker in wakers {
+                waker.wake();
+            }
+            self.settle().await;
+        }
+    }
+    /// Yields until no delay that's due is left (they're removed once polled).
+    async fn settle(&self) {
+        let settled = || {
+            let time = self.time();
+            time.timers
+                .values()
+                .all(|(deadline, _)| *deadline > time.now)
+        };
+        yield_until(settled, "a task never got to its due timer").await
+    }
+}
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Clock for ManualClock {
+    fn now(&self) -> Timestamp {
+        self.time().now
+    }
+
+    fn delay_until(&self, deadline: Timestamp) -> Delay {
+        Box::pin(ManualDelay {
+            clock: self.clone(),
+            deadline,
+            timer: None,
+        })
+    }
+}
+/// A `Delay` of a `ManualClock`. It's only registered as a pending timer while a task waits on
+/// it.
+struct ManualDelay {
+    clock: ManualClock,
+    deadline: Timestamp,
+    timer: Option<u64>,
+}
+impl Future for ManualDelay {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let mut time = this.clock.time();
+        if time.now >= this.deadline {
+            if let Some(timer) = this.timer.take() {
+                time.timers.remove(&timer);
+            }
+            return Poll::Ready(());
+        }
+        let timer = match this.timer {
+            Some(timer) => timer,
+            None => {
+                let timer = time.next_timer;
+                time.next_timer += 1;
+                this.timer = Some(timer);
+                timer
+            }
+        };
+        time.timers
+            .insert(timer, (this.deadline, cx.waker().clone()));
+        Poll::Pending
+    }
+}
+impl Drop for ManualDelay {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            self.clock.time().timers.remove(&timer);
+        }
+    }
+}
+/// Yields until `done` returns `true`. For what other tasks do that can't be awaited (counters,
+/// etc).
+///
+/// # Panics
+/// Panics if `done` is still `false` after `MAX_SETTLE_YIELDS` yields.
+pub async fn wait_until(done: impl FnMut() -> bool) {
+    yield_until(done, "the condition never became true").await
+}
+async fn yield_until(mut done: impl FnMut() -> bool, failure: &str) {
+    for _ in 0..MAX_SETTLE_YIELDS {
+        if done() {
+            return;
+        }
+        YieldNow(false).await;
+    }
+    panic!("{}", failure);
+}
+/// Lets every other ready task run once.
+struct YieldNow(bool);
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+/// The device side of the Provisioning Protocol over an accepted `link`. Returns the decrypted
+/// Provisioning Data and the DevKey or the `ErrorCode` of the Provisioning Failed PDU sent or
+/// received instead. With `reflect`, the device sends the provisioner's own Confirmation back.
+/// With Input OOB, the user is done entering `auth_value` right after the Public Keys.
+///
+/// # Panics
+/// Panics if the link breaks or the provisioner sends something unexpected.
+pub async fn provision_device(
+    mut link: Link,
+    capabilities: protocol::Capabilities,
+    auth_value: AuthValue,
+    reflect: bool,
+) -> Result<(ProvisioningData, DevKey), ErrorCode> {
+    let mut inputs = confirmation::Inputs {
+        invite: None,
+        capabilities: None,
+        start: None,
+        provisioner_public_key: None,
+        device_public_key: None,
+    };
+    match recv_pdu(&mut link).await {
+        PDU::Invite(invite) => inputs.invite = Some(invite),
+        pdu => panic!("expected invite, got {:?}", pdu),
+    }
+    inputs.capabilities = Some(capabilities);
+    link.send(&PDU::Capabilities(capabilities))
+        .await
+        .expect("link open");
+    let start = match recv_pdu(&mut link).await {
+        PDU::Start(start) => start,
+        pdu => panic!("expected start, got {:?}", pdu),
+    };
+    inputs.start = Some(start);
+    let provisioner_public_key = match recv_pdu(&mut link).await {
+        PDU::PublicKey(public_key) => public_key,
+        pdu => panic!("expected public key, got {:?}", pdu),
+    };
+    inputs.provisioner_public_key = Some(provisioner_public_key);
+    let (private_key, public_key) = PrivateKey::generate().expect("system random");
+    inputs.device_public_key = Some(public_key);
+    link.send(&PDU::PublicKey(public_key))
+        .await
+        .expect("link open");
+    let secret = private_key
+        .agree(&provisioner_public_key)
+        .expect("valid public key");
+    let confirmation_salt = inputs.salt().expect("all inputs");
+    let confirmation_key = ConfirmationKey::new(&secret, &confirmation_salt);
+    if let AuthenticationMethod::InputOOB(_, _) = start.auth_method() {
+        link.send(&PDU::InputComplete(protocol::InputComplete()))
+            .await
+            .expect("link open");
+    }
+
+    let provisioner_confirmation = match recv_pdu(&mut link).await {
+        PDU::Confirm(confirmation) => confirmation,
+        pdu => panic!("expected confirmation, got {:?}", pdu),
+    };
+    let random = protocol::Random([0x77; 16]);
+    let confirmation = if reflect {
+        provisioner_confirmation
+    } else {
+        confirmation_key.confirmation(&random, &auth_value)
+    };
+    link.send(&PDU::Confirm(confirmation))
+        .await
+        .expect("link open");
+    let provisioner_random = match recv_pdu(&mut link).await {
+        PDU::Random(random) => random,
+        PDU::Failed(failed) => return Err(failed.0),
+        pdu => panic!("expected random, got {:?}", pdu),
+    };
+    if confirmation_key.confirmation(&provisioner_random, &auth_value) != provisioner_confirmation {
+        let failed = protocol::Failed(ErrorCode::ConfirmationFailed);
+        link.send(&PDU::Failed(failed)).await.expect("link open");
+        return Err(failed.0);
+    }
+    link.send(&PDU::Random(random)).await.expect("link open");
+    let encrypted = match recv_pdu(&mut link).await {
+        PDU::Data(encrypted) => encrypted,
+        PDU::Failed(failed) => return Err(failed.0),
+        pdu => panic!("expected data, got {:?}", pdu),
+    };
+    let provisioning_salt = confirmation_salt.provisioning_salt(&provisioner_random, &random);
+    let data = SessionKeys::new(&secret, &provisioning_salt)
+        .decrypt(&encrypted)
+        .expect("valid provisioning data");
+    link.send(&PDU::Complete(protocol::Complete()))
+        .await
+        .expect("link open");
+    Ok((
+        data,
+        DevKey::from_salt_and_secret(provisioning_salt, secret),
+    ))
+}
+async fn recv_pdu(link: &mut Link) -> PDU {
+    PDU::unpack_from(&link.recv().await.expect("link open")).expect("valid PDU")
+}
@@ -9,6 +9,7 @@ use crate::lower::{SegN, SegO, SegmentedAccessPDU, SegmentedControlPDU, Unsegmen
 use crate::mesh::AppKeyIndex;
 use crate::{control, lower};
 use alloc::boxed::Box;
+use core::cmp::min;
 use core::convert::TryFrom;
 use core::iter::Peekable;
 
@@ -34,16 +35,10 @@ impl<Storage: AsRef<[u8]>> PDU<Storage> {
             self.total_len() < ENCRYPTED_APP_PAYLOAD_MAX_LEN,
             "payload overflow"
         );
-        let l = self.total_len();
-        let n = l / self.max_seg_len();
-        SegO::new(
-            u8::try_from(if n * self.max_seg_len() == l {
-                n
-            } else {
-                n + 1
-            })
-            .expect("can't send this much data"),
-        )
+        // SegO is the last segment number so it's one less than the number of segments.
+        let max_seg_len = self.max_seg_len();
+        let seg_count = (self.total_len() + max_seg_len - 1) / max_seg_len;
+        SegO::new(u8::try_from(seg_count.saturating_sub(1)).expect("can't send this much data"))
     }
     /// Gets Segment N's data to be sent. !! THE MIC WON'T BE INCLUDED !!. Access Messages
     /// include a MIC and will have to be append to the end of the payload manually.
@@ -54,7 +49,9 @@ impl<Storage: AsRef<[u8]>> PDU<Storage> {
         assert!(seg_i <= u8::from(self.seg_o()));
         let seg_i = usize::from(seg_i);
         let max_seg = self.max_seg_len();
-        &self.payload()[seg_i * max_seg..(seg_i + 1) * max_seg]
+        let payload = self.payload();
+        // The last segment may be shorter (or empty if only the MIC is left).
+        &payload[min(seg_i * max_seg, payload.len())..min((seg_i + 1) * max_seg, payload.len())]
     }
     pub fn is_control(&self) -> bool {
         match self {
@@ -274,14 +271,17 @@ impl<'a, Storage: AsRef<[u8]>> AppPayload<Storage> {
     }
     #[must_use]
     pub fn should_segment(&self, mic_size: MicSize) -> bool {
-        self.0.as_ref().len() + mic_size.byte_size() > UnsegmentedAccessPDU::max_len()
+        self.0.as_ref().len() + mic_size.byte_size() > UnsegmentedAccessPDU::max_upper_pdu_len()
     }
 }
+/// Returns the last segment number (one less than the number of segments) for `data_len` bytes
+/// split into `pdu_size` byte segments.
 pub fn calculate_seg_o(data_len: usize, pdu_size: usize) -> SegO {
-    let l = data_len;
-    let n = data_len / pdu_size;
-    let n = if n * pdu_size * n == l { n } else { n + 1 };
-    SegO::new(u8::try_from(n).expect("data_len longer than ENCRYPTED_APP_PAYLOAD_MAX_LEN"))
+    let seg_count = (data_len + pdu_size - 1) / pdu_size;
+    SegO::new(
+        u8::try_from(seg_count.saturating_sub(1))
+            .expect("data_len longer than ENCRYPTED_APP_PAYLOAD_MAX_LEN"),
+    )
 }
 pub struct EncryptedAppPayload<Storage: AsRef<[u8]>> {
     pub data: Storage,
@@ -334,13 +334,19 @@ impl<Storage: AsRef<[u8]>> EncryptedAppPayload<Storage> {
         calculate_seg_o(self.len(), SegmentedAccessPDU::max_seg_len())
     }
     pub fn should_segment(&self) -> bool {
-        self.len() > UnsegmentedAccessPDU::max_len()
+        self.len() > UnsegmentedAccessPDU::max_upper_pdu_len()
     }
+    /// Returns the payload and TransMIC as a single `UnsegmentedAccessPDU` or `None` if it's too
+    /// long and has to be segmented.
     pub fn as_unsegmented(&self) -> Option<UnsegmentedAccessPDU> {
         if self.should_segment() {
-            Some(UnsegmentedAccessPDU::new(self.aid(), self.data()))
-        } else {
             None
+        } else {
+            let mut buf = [0_u8; UnsegmentedAccessPDU::max_upper_pdu_len()];
+            let data_len = self.data_len();
+            buf[..data_len].copy_from_slice(self.data());
+            self.mic.be_pack_into(&mut buf[data_len..]);
+            Some(UnsegmentedAccessPDU::new(self.aid(), &buf[..self.len()]))
         }
     }
     pub fn into_storage(self) -> Storage {